@@ -0,0 +1,235 @@
+//! Compression of stream data.
+//!
+//! Each [`CompressedPush`](crate::frame::OpCode::CompressedPush) frame is
+//! compressed on its own, so that frames can be retransmitted or dropped
+//! without affecting the others. Both ends must agree on the algorithm before
+//! creating their [`Multiplexor`](crate::Multiplexor)s, e.g. during the
+//! `WebSocket` handshake.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Largest payload that is compressed, and the largest a compressed payload
+/// may expand to. This stops a peer from making us allocate without bound.
+pub const MAX_UNCOMPRESSED_SIZE: usize = 1 << 20;
+
+/// Compression algorithms for stream data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// No compression
+    #[default]
+    None,
+    /// Raw DEFLATE (RFC 1951)
+    #[cfg(feature = "deflate")]
+    Deflate,
+    /// Zstandard (RFC 8878)
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Error returned when parsing an unknown or unsupported algorithm name
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Unsupported compression algorithm: {0}")]
+pub struct UnsupportedCompression(String);
+
+impl Compression {
+    /// Algorithms supported by this build, most preferred first
+    pub const SUPPORTED: &'static [Self] = &[
+        #[cfg(feature = "zstd")]
+        Self::Zstd,
+        #[cfg(feature = "deflate")]
+        Self::Deflate,
+    ];
+
+    /// Name of the algorithm used for negotiation
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            #[cfg(feature = "deflate")]
+            Self::Deflate => "deflate",
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Compress `data`.
+    /// Returns `None` if there is no algorithm or compressing does not make
+    /// the data smaller.
+    #[must_use]
+    pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed: Vec<u8> = match self {
+            Self::None => None,
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Write;
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::with_capacity(data.len()),
+                    flate2::Compression::fast(),
+                );
+                // Writing to a `Vec` does not fail
+                encoder.write_all(data).ok()?;
+                encoder.finish().ok()
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(data, 1).ok(),
+        }?;
+        (compressed.len() < data.len()).then_some(compressed)
+    }
+
+    /// Decompress `data`.
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::InvalidData`] if `data` is corrupt, expands to
+    /// more than [`MAX_UNCOMPRESSED_SIZE`] octets, or there is no algorithm.
+    pub(crate) fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "received {} compressed octets without compression enabled",
+                    data.len()
+                ),
+            )),
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Read;
+                let mut decompressed = Vec::new();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(MAX_UNCOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > MAX_UNCOMPRESSED_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "decompressed payload too large",
+                    ));
+                }
+                Ok(decompressed)
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::decompress(data, MAX_UNCOMPRESSED_SIZE)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = UnsupportedCompression;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("none") {
+            return Ok(Self::None);
+        }
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|algorithm| s.eq_ignore_ascii_case(algorithm.name()))
+            .ok_or_else(|| UnsupportedCompression(s.to_string()))
+    }
+}
+
+/// Compression settings of a [`MuxStream`](crate::MuxStream)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct StreamCompression {
+    /// Algorithm agreed with the peer
+    pub algorithm: Compression,
+    /// Payloads shorter than this are sent as they are
+    pub threshold: usize,
+    /// Whether this stream compresses its writes
+    pub enabled: bool,
+}
+
+impl StreamCompression {
+    pub const fn new(algorithm: Compression, threshold: usize) -> Self {
+        Self {
+            algorithm,
+            threshold,
+            enabled: !matches!(algorithm, Compression::None),
+        }
+    }
+
+    /// Compress `data` if it is worth it
+    #[must_use]
+    pub fn compress(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if !self.wants(data.len()) {
+            return None;
+        }
+        self.algorithm.compress(data)
+    }
+
+    /// Whether [`compress`](Self::compress) tries to compress `len` octets
+    #[must_use]
+    pub const fn wants(&self, len: usize) -> bool {
+        self.enabled && len >= self.threshold && len <= MAX_UNCOMPRESSED_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!("none".parse::<Compression>(), Ok(Compression::None));
+        assert!("lzma".parse::<Compression>().is_err());
+        for algorithm in Compression::SUPPORTED {
+            assert_eq!(
+                algorithm.name().to_uppercase().parse::<Compression>(),
+                Ok(*algorithm)
+            );
+            assert_eq!(algorithm.to_string(), algorithm.name());
+        }
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        crate::tests::setup_logging();
+        let data = b"SELECT * FROM penguins WHERE name = 'penguin';".repeat(64);
+        for algorithm in Compression::SUPPORTED {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(algorithm.decompress(&compressed).unwrap(), data);
+            assert!(algorithm.decompress(b"\xff\xff\xff\xff").is_err());
+            // Random data does not compress
+            let random: Vec<u8> = (0..256).map(|_| rand::random()).collect();
+            assert!(algorithm.compress(&random).is_none());
+        }
+        assert!(Compression::None.compress(&data).is_none());
+        assert!(Compression::None.decompress(&data).is_err());
+    }
+
+    #[test]
+    fn test_stream_compression_threshold() {
+        let data = vec![0; 64];
+        let Some(algorithm) = Compression::SUPPORTED.first().copied() else {
+            return;
+        };
+        let mut compression = StreamCompression::new(algorithm, 128);
+        assert!(compression.compress(&data).is_none());
+        compression.threshold = 32;
+        assert!(compression.compress(&data).is_some());
+        compression.enabled = false;
+        assert!(compression.compress(&data).is_none());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let data = vec![0; MAX_UNCOMPRESSED_SIZE + 1];
+        for algorithm in Compression::SUPPORTED {
+            let compressed = algorithm.compress(&data).unwrap();
+            assert!(algorithm.decompress(&compressed).is_err());
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Multiplexor configuration
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+/// Configuration parameters for the multiplexor.
+/// See each method for details on the parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub(crate) keepalive_interval: crate::timing::OptionalDuration,
+    pub(crate) keepalive_timeout: crate::timing::OptionalDuration,
+    pub(crate) datagram_buffer_size: usize,
+    pub(crate) stream_buffer_size: usize,
+    pub(crate) bind_buffer_size: usize,
+    pub(crate) max_flow_id_retries: usize,
+    pub(crate) rwnd: u32,
+    pub(crate) max_rwnd: u32,
+    pub(crate) default_rwnd_threshold: u32,
+    pub(crate) resume_timeout: std::time::Duration,
+    pub(crate) compression: crate::Compression,
+    pub(crate) compression_threshold: usize,
+    pub(crate) padding: bool,
+    pub(crate) coalesce: bool,
+    pub(crate) limit_up: crate::limit::Bandwidth,
+    pub(crate) limit_down: crate::limit::Bandwidth,
+    pub(crate) stream_limit_up: crate::limit::Bandwidth,
+    pub(crate) stream_limit_down: crate::limit::Bandwidth,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Options {
+    /// Create a new [`Options`] instance with default values.
+    #[must_use]
+    pub const fn new() -> Self {
+        const DATAGRAM_BUFFER_SIZE: usize = 1 << 9;
+        const STREAM_BUFFER_SIZE: usize = 1 << 4;
+        const MAX_FLOW_ID_RETRIES: usize = 3;
+        const RESUME_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+        const COMPRESSION_THRESHOLD: usize = 256;
+
+        #[cfg(not(test))]
+        const RWND: u32 = 1 << 9;
+        #[cfg(test)]
+        const RWND: u32 = 4;
+        #[cfg(not(test))]
+        const MAX_RWND: u32 = RWND << 3;
+        #[cfg(test)]
+        const MAX_RWND: u32 = RWND;
+        #[cfg(not(test))]
+        const DEFAULT_RWND_THRESHOLD: u32 = 1 << 8;
+        #[cfg(test)]
+        const DEFAULT_RWND_THRESHOLD: u32 = RWND;
+        Self {
+            keepalive_interval: crate::timing::OptionalDuration::NONE,
+            keepalive_timeout: crate::timing::OptionalDuration::NONE,
+            datagram_buffer_size: DATAGRAM_BUFFER_SIZE,
+            stream_buffer_size: STREAM_BUFFER_SIZE,
+            bind_buffer_size: 0,
+            max_flow_id_retries: MAX_FLOW_ID_RETRIES,
+            rwnd: RWND,
+            max_rwnd: MAX_RWND,
+            default_rwnd_threshold: DEFAULT_RWND_THRESHOLD,
+            resume_timeout: RESUME_TIMEOUT,
+            compression: crate::Compression::None,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            padding: false,
+            coalesce: false,
+            limit_up: crate::limit::Bandwidth::UNLIMITED,
+            limit_down: crate::limit::Bandwidth::UNLIMITED,
+            stream_limit_up: crate::limit::Bandwidth::UNLIMITED,
+            stream_limit_down: crate::limit::Bandwidth::UNLIMITED,
+        }
+    }
+
+    /// Sets the interval at which to send [`Ping`](crate::ws::Message::Ping) frames.
+    #[must_use]
+    pub const fn keepalive_interval(mut self, interval: crate::timing::OptionalDuration) -> Self {
+        self.keepalive_interval = interval;
+        self
+    }
+
+    /// How long to wait for the [`Pong`](crate::ws::Message::Pong) of a
+    /// `Ping` before giving up on the `WebSocket`. The multiplexor task then
+    /// exits with [`Error::PongTimeout`](crate::Error::PongTimeout), or waits
+    /// for a new `WebSocket` if it is resumable.
+    /// Only effective if `Ping`s are sent, e.g. with
+    /// [`Options::keepalive_interval`].
+    #[must_use]
+    pub const fn keepalive_timeout(mut self, timeout: crate::timing::OptionalDuration) -> Self {
+        self.keepalive_timeout = timeout;
+        self
+    }
+
+    /// Number of datagram frames to buffer in the channels on the receiving end.
+    /// If the buffer is not read fast enough, excess datagrams will be dropped.
+    ///
+    /// # Panics
+    /// Panics if the buffer size is not positive.
+    #[must_use]
+    pub const fn datagram_buffer_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "datagram_buffer_size must be greater than 0");
+        self.datagram_buffer_size = size;
+        self
+    }
+
+    /// Number of `MuxStream`s to buffer in the channels on the receiving end.
+    /// Since there is a handshake to obtain `MuxStream`s, there should be no
+    /// need to have a crazy high buffer size.
+    ///
+    /// # Panics
+    /// Panics if the buffer size is not positive.
+    #[must_use]
+    pub const fn stream_buffer_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "stream_buffer_size must be greater than 0");
+        self.stream_buffer_size = size;
+        self
+    }
+
+    /// Number of [`Bind`](crate::frame::OpCode::Bind) requests to buffer
+    /// in the channels on the receiving end.
+    /// Setting this to zero disallows the multiplexor from accepting any
+    /// `Bind` requests from the other end and
+    /// is the default. Make sure the security implications are understood
+    /// before enabling this.
+    #[must_use]
+    pub const fn bind_buffer_size(mut self, size: usize) -> Self {
+        self.bind_buffer_size = size;
+        self
+    }
+
+    /// Number of retries for establishing a connection if the other end rejects our `flow_id` selection.
+    ///
+    /// # Panics
+    /// Panics if the number of retries is not positive.
+    #[must_use]
+    pub const fn max_flow_id_retries(mut self, retries: usize) -> Self {
+        assert!(retries > 0, "max_flow_id_retries must be greater than 0");
+        self.max_flow_id_retries = retries;
+        self
+    }
+
+    /// Number of `StreamFrame`s to buffer in `MuxStream`'s channels before blocking.
+    ///
+    /// # Panics
+    /// Panics if the buffer size is not positive or does not fit in a `usize`.
+    #[must_use]
+    pub const fn rwnd(mut self, rwnd: u32) -> Self {
+        assert!(fits_in_usize(rwnd), "rwnd must fit in a usize");
+        assert!(rwnd > 0, "rwnd must be greater than 0");
+        self.rwnd = rwnd;
+        self
+    }
+
+    /// Largest number of `StreamFrame`s a stream may buffer when its window
+    /// grows. Streams start with a window of [`Options::rwnd`] frames and
+    /// grow it while they are read faster than the peer can send a window
+    /// per round trip, so that high-latency links are not limited by `rwnd`.
+    /// The round-trip time is measured with keepalive pings, so windows only
+    /// grow if [`Options::keepalive_interval`] is set.
+    /// Setting this to `rwnd` or lower keeps the window fixed.
+    ///
+    /// # Panics
+    /// Panics if the value does not fit in a `usize`.
+    #[must_use]
+    pub const fn max_rwnd(mut self, max_rwnd: u32) -> Self {
+        assert!(fits_in_usize(max_rwnd), "max_rwnd must fit in a usize");
+        self.max_rwnd = max_rwnd;
+        self
+    }
+
+    /// Number of [`Push`](crate::frame::OpCode::Push) frames between [`Acknowledge`](crate::frame::OpCode::Acknowledge)s:
+    /// If too low, `Acknowledge`s will consume too much bandwidth;
+    /// If too high, writers may block.
+    ///
+    /// Note that if the peer indicates a lower `rwnd` value in the handshake,
+    /// this value will be ignored for that connection.
+    ///
+    /// # Panics
+    /// Panics if the value is not positive.
+    #[must_use]
+    pub const fn default_rwnd_threshold(mut self, threshold: u32) -> Self {
+        assert!(
+            threshold > 0,
+            "default_rwnd_threshold must be greater than 0"
+        );
+        self.default_rwnd_threshold = threshold;
+        self
+    }
+
+    /// How long a resumable multiplexor waits for a new `WebSocket` after
+    /// losing the current one before closing all streams.
+    /// Only used by [`Multiplexor::new_resumable`](crate::Multiplexor::new_resumable).
+    #[must_use]
+    pub const fn resume_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.resume_timeout = timeout;
+        self
+    }
+
+    /// Algorithm to compress stream data with. The peer must be configured
+    /// with the same algorithm, since it is not negotiated by the multiplexor.
+    /// Compression can be turned off for individual streams with
+    /// [`MuxStream::set_compression`](crate::MuxStream::set_compression).
+    #[must_use]
+    pub const fn compression(mut self, compression: crate::Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Writes shorter than this many octets are sent uncompressed, since
+    /// they rarely get smaller.
+    #[must_use]
+    pub const fn compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Pad every frame to one of a few bucket sizes and send dummy frames
+    /// at random intervals to make traffic analysis harder. The peer must
+    /// support [`Padded`](crate::frame::OpCode::Padded) frames, since this
+    /// is not negotiated by the multiplexor.
+    #[must_use]
+    pub const fn padding(mut self, padding: bool) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Pack small frames that are queued at the same time into one
+    /// [`Batch`](crate::frame::OpCode::Batch) frame, which saves the
+    /// per-message overhead of the `WebSocket` for chatty traffic. The peer
+    /// must support `Batch` frames, since this is not negotiated by the
+    /// multiplexor.
+    #[must_use]
+    pub const fn coalesce(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce;
+        self
+    }
+
+    /// Limit the rate at which all streams together send data.
+    #[must_use]
+    pub const fn limit_up(mut self, bandwidth: crate::limit::Bandwidth) -> Self {
+        self.limit_up = bandwidth;
+        self
+    }
+
+    /// Limit the rate at which all streams together receive data.
+    /// This works by delaying [`Acknowledge`](crate::frame::OpCode::Acknowledge)s,
+    /// so the peer may send up to `rwnd` frames of each stream in a burst.
+    #[must_use]
+    pub const fn limit_down(mut self, bandwidth: crate::limit::Bandwidth) -> Self {
+        self.limit_down = bandwidth;
+        self
+    }
+
+    /// Limit the rate at which each stream sends data.
+    #[must_use]
+    pub const fn stream_limit_up(mut self, bandwidth: crate::limit::Bandwidth) -> Self {
+        self.stream_limit_up = bandwidth;
+        self
+    }
+
+    /// Limit the rate at which each stream receives data.
+    /// See [`Options::limit_down`] for caveats.
+    #[must_use]
+    pub const fn stream_limit_down(mut self, bandwidth: crate::limit::Bandwidth) -> Self {
+        self.stream_limit_down = bandwidth;
+        self
+    }
+}
+
+/// Whether `value` fits in a `usize` on this platform
+const fn fits_in_usize(value: u32) -> bool {
+    usize::BITS >= u32::BITS || value >> usize::BITS == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limit::Bandwidth;
+    use std::time::Duration;
+
+    #[test]
+    fn test_options() {
+        let options = Options::new()
+            .keepalive_interval(Duration::from_secs(100).into())
+            .keepalive_timeout(Duration::from_secs(10).into())
+            .datagram_buffer_size(33)
+            .stream_buffer_size(44)
+            .bind_buffer_size(55)
+            .max_flow_id_retries(66)
+            .rwnd(77)
+            .max_rwnd(770)
+            .default_rwnd_threshold(88)
+            .resume_timeout(Duration::from_secs(99))
+            .compression_threshold(111)
+            .padding(true)
+            .coalesce(true)
+            .limit_up(Bandwidth::from_bytes_per_sec(222))
+            .stream_limit_down(Bandwidth::from_bytes_per_sec(333));
+        assert_eq!(options.keepalive_interval, Duration::from_secs(100).into());
+        assert_eq!(options.keepalive_timeout, Duration::from_secs(10).into());
+        assert_eq!(options.datagram_buffer_size, 33);
+        assert_eq!(options.stream_buffer_size, 44);
+        assert_eq!(options.bind_buffer_size, 55);
+        assert_eq!(options.max_flow_id_retries, 66);
+        assert_eq!(options.rwnd, 77);
+        assert_eq!(options.max_rwnd, 770);
+        assert_eq!(options.default_rwnd_threshold, 88);
+        assert_eq!(options.resume_timeout, Duration::from_secs(99));
+        assert_eq!(options.compression, crate::Compression::None);
+        assert_eq!(options.compression_threshold, 111);
+        assert!(options.padding);
+        assert!(options.coalesce);
+        assert_eq!(options.limit_up, Bandwidth::from_bytes_per_sec(222));
+        assert_eq!(options.limit_down, Bandwidth::UNLIMITED);
+        assert_eq!(options.stream_limit_up, Bandwidth::UNLIMITED);
+        assert_eq!(
+            options.stream_limit_down,
+            Bandwidth::from_bytes_per_sec(333)
+        );
+    }
+}
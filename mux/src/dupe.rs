@@ -38,8 +38,6 @@ impl_dupe_as_clone! {
     http::uri::PathAndQuery => (),
     // `Uri` is the combination of the above.
     http::Uri => (),
-    // `Arc` is a reference-counted type.
-    std::sync::Arc<T> => (T),
     // `Sender` is designed to be cheaply cloned.
     tokio::sync::mpsc::Sender<T> => (T),
     // `UnboundedSender` is designed to be cheaply cloned.
@@ -48,6 +46,14 @@ impl_dupe_as_clone! {
     tokio::sync::broadcast::Sender<T> => (T),
 }
 
+// `Arc` is a reference-counted type, also of slices and `str`.
+impl<T: ?Sized> Dupe for std::sync::Arc<T> {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
 #[cfg(loom)]
 impl_dupe_as_clone! {
     loom::sync::Arc<T> => (T),
@@ -9,9 +9,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+use crate::pool::BufferPool;
 use crate::{Dupe, proto_version};
 use bytes::{Buf, BufMut, Bytes};
-use std::{fmt::Debug, mem::size_of};
+use std::{fmt::Debug, io::IoSlice, mem::size_of};
 use thiserror::Error;
 
 /// Errors that can occur when parsing a frame.
@@ -29,6 +30,12 @@ pub enum Error {
     /// Invalid type code in a `Bind` frame
     #[error("Invalid `Bind` type: {0}")]
     InvalidBindType(u8),
+    /// `Padded` frame inside another `Padded` frame
+    #[error("Nested `Padded` frame")]
+    NestedPadding,
+    /// `Batch` frame inside another `Batch` frame
+    #[error("Nested `Batch` frame")]
+    NestedBatch,
 }
 
 /// A special version of `std::borrow::Cow` using `Bytes`
@@ -114,6 +121,52 @@ impl TryFrom<u8> for BindType {
     }
 }
 
+/// Reasons carried by a [`OpCode::Reset`] frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Error)]
+#[repr(u8)]
+#[non_exhaustive]
+pub enum ResetReason {
+    /// No reason given
+    #[default]
+    #[error("reset by peer")]
+    Unspecified = 0,
+    /// The peer could not reach the destination
+    #[error("destination unreachable from peer")]
+    Unreachable = 1,
+    /// The peer's policy does not allow the destination
+    #[error("destination forbidden by peer policy")]
+    Forbidden = 2,
+    /// The peer limits how fast new connections may be opened
+    #[error("rate limited by peer")]
+    RateLimited = 3,
+}
+
+impl From<u8> for ResetReason {
+    /// Parse a `u8` into a `ResetReason`.
+    /// Unknown reasons from newer peers are treated as unspecified.
+    #[inline]
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Unreachable,
+            2 => Self::Forbidden,
+            3 => Self::RateLimited,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+impl ResetReason {
+    /// The kind of [`std::io::Error`] that best describes this reason
+    #[must_use]
+    pub const fn io_error_kind(self) -> std::io::ErrorKind {
+        match self {
+            Self::Unspecified | Self::RateLimited => std::io::ErrorKind::ConnectionReset,
+            Self::Unreachable => std::io::ErrorKind::ConnectionRefused,
+            Self::Forbidden => std::io::ErrorKind::PermissionDenied,
+        }
+    }
+}
+
 /// Operation codes
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
@@ -132,6 +185,14 @@ pub enum OpCode {
     Bind = 5,
     /// Sending datagram
     Datagram = 6,
+    /// Acknowledging frames of a resumable session
+    Session = 7,
+    /// Sending compressed stream data
+    CompressedPush = 8,
+    /// Padding another frame or carrying no data
+    Padded = 9,
+    /// Carrying several frames in one message
+    Batch = 10,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -148,6 +209,10 @@ impl TryFrom<u8> for OpCode {
             4 => Ok(Self::Push),
             5 => Ok(Self::Bind),
             6 => Ok(Self::Datagram),
+            7 => Ok(Self::Session),
+            8 => Ok(Self::CompressedPush),
+            9 => Ok(Self::Padded),
+            10 => Ok(Self::Batch),
             other => Err(Error::InvalidOpCode(other)),
         }
     }
@@ -196,8 +261,9 @@ pub(crate) enum Payload<'data> {
     /// the previous [`Acknowledge`] frame, or
     /// `rwnd`: Number of frames buffered in the receive buffer
     Acknowledge(u32),
-    /// `Reset` has no payload
-    Reset,
+    /// `Reset` payload
+    /// `reason`: Why the flow was reset. Omitted from the frame if unspecified.
+    Reset(ResetReason),
     /// `Finish` has no payload
     Finish,
     /// `Push` payload
@@ -206,24 +272,42 @@ pub(crate) enum Payload<'data> {
     Bind(BindPayload<'data>),
     /// `Datagram` payload. See [`DatagramPayload`]
     Datagram(DatagramPayload<'data>),
+    /// `Session` payload
+    /// `frames_received`: The number of non-`Session` frames received in
+    /// this session
+    Session(u64),
+    /// `CompressedPush` payload, compressed with the algorithm agreed
+    /// with the peer
+    CompressedPush(CowBytes<'data>),
+    /// An empty `Padded` frame. Non-empty ones are decoded into the frame
+    /// they contain
+    Padding,
+    /// `Batch` payload: the encoded frames it carries
+    Batch(Vec<Bytes>),
 }
 
 impl Payload<'_> {
     #[inline]
-    const fn len(&self) -> usize {
+    fn len(&self) -> usize {
         match self {
             Self::Connect(ConnectPayload { target_host, .. }) => {
                 size_of::<u32>() + size_of::<u16>() + target_host.len()
             }
-            Self::Acknowledge(_) => size_of::<u32>(),
-            Self::Reset | Self::Finish => 0,
-            Self::Push(data) => data.len(),
+            Self::Acknowledge(_) | Self::Padding => size_of::<u32>(),
+            Self::Reset(ResetReason::Unspecified) | Self::Finish => 0,
+            Self::Reset(_) => size_of::<u8>(),
+            Self::Push(data) | Self::CompressedPush(data) => data.len(),
             Self::Bind(BindPayload { target_host, .. }) => {
                 size_of::<u8>() + size_of::<u16>() + target_host.len()
             }
             Self::Datagram(DatagramPayload {
                 target_host, data, ..
             }) => size_of::<u8>() + size_of::<u16>() + target_host.len() + data.len(),
+            Self::Session(_) => size_of::<u64>(),
+            Self::Batch(frames) => frames
+                .iter()
+                .map(|frame| size_of::<u32>() + frame.len())
+                .sum(),
         }
     }
 }
@@ -234,11 +318,15 @@ impl<'data> From<&Payload<'data>> for OpCode {
         match payload {
             Payload::Connect { .. } => Self::Connect,
             Payload::Acknowledge(_) => Self::Acknowledge,
-            Payload::Reset => Self::Reset,
+            Payload::Reset(_) => Self::Reset,
             Payload::Finish => Self::Finish,
             Payload::Push(_) => Self::Push,
             Payload::Bind { .. } => Self::Bind,
             Payload::Datagram { .. } => Self::Datagram,
+            Payload::Session(_) => Self::Session,
+            Payload::CompressedPush(_) => Self::CompressedPush,
+            Payload::Padding => Self::Padded,
+            Payload::Batch(_) => Self::Batch,
         }
     }
 }
@@ -306,9 +394,19 @@ impl<'data> Frame<'data> {
     #[must_use]
     #[inline]
     pub const fn new_reset(id: u32) -> Self {
+        Self::new_reset_with_reason(id, ResetReason::Unspecified)
+    }
+    /// Create a new [`OpCode::Reset`] frame telling the peer why.
+    ///
+    /// # Arguments
+    /// * `id`: The flow ID of the offending frame.
+    /// * `reason`: Why the flow is reset.
+    #[must_use]
+    #[inline]
+    pub const fn new_reset_with_reason(id: u32, reason: ResetReason) -> Self {
         Self {
             id,
-            payload: Payload::Reset,
+            payload: Payload::Reset(reason),
         }
     }
     /// Create a new [`OpCode::Finish`] frame.
@@ -348,6 +446,20 @@ impl<'data> Frame<'data> {
         }
     }
 
+    /// Create a new [`OpCode::CompressedPush`] frame.
+    ///
+    /// # Arguments
+    /// * `id`: The flow ID of the connection to send data on.
+    /// * `data`: The compressed data to send.
+    #[must_use]
+    #[inline]
+    pub const fn new_compressed_push(id: u32, data: Bytes) -> Self {
+        Self {
+            id,
+            payload: Payload::CompressedPush(CowBytes::Owned(data)),
+        }
+    }
+
     /// Create a new [`OpCode::Bind`] frame.
     ///
     /// # Arguments
@@ -411,6 +523,33 @@ impl<'data> Frame<'data> {
         Self { id, payload }
     }
 
+    /// Create a new [`OpCode::Session`] frame.
+    ///
+    /// # Arguments
+    /// * `frames_received`: The number of non-`Session` frames received in
+    ///   this session.
+    #[must_use]
+    #[inline]
+    pub const fn new_session(frames_received: u64) -> Self {
+        Self {
+            id: 0,
+            payload: Payload::Session(frames_received),
+        }
+    }
+
+    /// Create a new [`OpCode::Batch`] frame.
+    ///
+    /// # Arguments
+    /// * `frames`: The encoded frames to carry, none of which may be a `Batch` frame.
+    #[must_use]
+    #[inline]
+    pub(crate) const fn new_batch(frames: Vec<Bytes>) -> Self {
+        Self {
+            id: 0,
+            payload: Payload::Batch(frames),
+        }
+    }
+
     /// Copy the frame into a [`FinalizedFrame`]
     #[must_use]
     #[inline]
@@ -469,9 +608,18 @@ impl TryFrom<Bytes> for Frame<'static> {
                 let psh_recvd_since = data.get_u32();
                 Payload::Acknowledge(psh_recvd_since)
             }
-            OpCode::Reset => Payload::Reset,
+            OpCode::Reset => {
+                // Peers that do not give a reason send no payload
+                let reason = if data.has_remaining() {
+                    ResetReason::from(data.get_u8())
+                } else {
+                    ResetReason::Unspecified
+                };
+                Payload::Reset(reason)
+            }
             OpCode::Finish => Payload::Finish,
             OpCode::Push => Payload::Push(CowBytes::Owned(data)),
+            OpCode::CompressedPush => Payload::CompressedPush(CowBytes::Owned(data)),
             OpCode::Bind => {
                 check_remaining!(data, size_of::<u8>() + size_of::<u16>());
                 let bind_type = BindType::try_from(data.get_u8())?;
@@ -494,27 +642,68 @@ impl TryFrom<Bytes> for Frame<'static> {
                     data: CowBytes::Owned(data),
                 })
             }
+            OpCode::Session => {
+                check_remaining!(data, size_of::<u64>());
+                Payload::Session(data.get_u64())
+            }
+            OpCode::Padded => {
+                check_remaining!(data, size_of::<u32>());
+                let inner_len = data.get_u32() as usize;
+                if inner_len == 0 {
+                    Payload::Padding
+                } else {
+                    check_remaining!(data, inner_len);
+                    let inner = data.split_to(inner_len);
+                    if inner
+                        .first()
+                        .is_some_and(|b| b & 0x0F == OpCode::Padded as u8)
+                    {
+                        return Err(Error::NestedPadding);
+                    }
+                    return Self::try_from(inner);
+                }
+            }
+            OpCode::Batch => {
+                let mut frames = Vec::new();
+                while data.has_remaining() {
+                    check_remaining!(data, size_of::<u32>());
+                    let len = data.get_u32() as usize;
+                    check_remaining!(data, len);
+                    let frame = data.split_to(len);
+                    if frame
+                        .first()
+                        .is_some_and(|b| b & 0x0F == OpCode::Batch as u8)
+                    {
+                        return Err(Error::NestedBatch);
+                    }
+                    frames.push(frame);
+                }
+                Payload::Batch(frames)
+            }
         };
         Ok(Self { id, payload })
     }
 }
 
-impl From<&Frame<'_>> for Vec<u8> {
-    /// Encode a [`Frame`] to bytes
+impl Frame<'_> {
+    /// Length of the encoded frame
+    #[inline]
+    fn encoded_len(&self) -> usize {
+        size_of::<u8>() + size_of::<u32>() + self.payload.len()
+    }
+
+    /// Encode the frame into `encoded`
     ///
     /// # Panics
     /// Panics when the frame has [`OpCode::Datagram`]
     /// but the `target_host` field is longer than 255 octets.
-    #[tracing::instrument(level = "trace")]
     #[inline]
-    fn from(frame: &Frame<'_>) -> Self {
-        let size = size_of::<u8>() + size_of::<u32>() + frame.payload.len();
-        let opcode = OpCode::from(&frame.payload) as u8;
+    fn encode_into<B: BufMut>(&self, encoded: &mut B) {
+        let opcode = OpCode::from(&self.payload) as u8;
         let firstbyte = opcode | (proto_version::PROTOCOL_VERSION_NUMBER << 4);
-        let mut encoded = Self::with_capacity(size);
         encoded.put_u8(firstbyte);
-        encoded.put_u32(frame.id);
-        match &frame.payload {
+        encoded.put_u32(self.id);
+        match &self.payload {
             Payload::Connect(ConnectPayload {
                 rwnd,
                 target_port,
@@ -522,14 +711,17 @@ impl From<&Frame<'_>> for Vec<u8> {
             }) => {
                 encoded.put_u32(*rwnd);
                 encoded.put_u16(*target_port);
-                encoded.extend(target_host.as_ref());
+                encoded.put_slice(target_host.as_ref());
             }
             Payload::Acknowledge(psh_recvd_since) => {
                 encoded.put_u32(*psh_recvd_since);
             }
-            Payload::Reset | Payload::Finish => {}
-            Payload::Push(data) => {
-                encoded.extend(data.as_ref());
+            Payload::Reset(ResetReason::Unspecified) | Payload::Finish => {}
+            Payload::Reset(reason) => {
+                encoded.put_u8(*reason as u8);
+            }
+            Payload::Push(data) | Payload::CompressedPush(data) => {
+                encoded.put_slice(data.as_ref());
             }
             Payload::Bind(BindPayload {
                 bind_type,
@@ -538,7 +730,7 @@ impl From<&Frame<'_>> for Vec<u8> {
             }) => {
                 encoded.put_u8(*bind_type as u8);
                 encoded.put_u16(*target_port);
-                encoded.extend(target_host.as_ref());
+                encoded.put_slice(target_host.as_ref());
             }
             Payload::Datagram(DatagramPayload {
                 target_port,
@@ -549,10 +741,49 @@ impl From<&Frame<'_>> for Vec<u8> {
                     u8::try_from(target_host.len()).expect("Datagram target host too long");
                 encoded.put_u8(len_u8);
                 encoded.put_u16(*target_port);
-                encoded.extend(target_host.as_ref());
-                encoded.extend(data.as_ref());
+                encoded.put_slice(target_host.as_ref());
+                encoded.put_slice(data.as_ref());
+            }
+            Payload::Session(frames_received) => {
+                encoded.put_u64(*frames_received);
+            }
+            Payload::Padding => {
+                encoded.put_u32(0);
+            }
+            Payload::Batch(frames) => {
+                for frame in frames {
+                    let len = u32::try_from(frame.len())
+                        .expect("Frame too long to batch (this is a bug)");
+                    encoded.put_u32(len);
+                    encoded.put_slice(frame);
+                }
             }
         }
+    }
+
+    /// Encode the frame into a buffer from `pool`
+    #[inline]
+    pub(crate) fn finalize_in(&self, pool: &BufferPool) -> FinalizedFrame {
+        let size = self.encoded_len();
+        let mut encoded = pool.get(size);
+        self.encode_into(&mut encoded);
+        debug_assert_eq!(size, encoded.len());
+        FinalizedFrame(encoded.freeze())
+    }
+}
+
+impl From<&Frame<'_>> for Vec<u8> {
+    /// Encode a [`Frame`] to bytes
+    ///
+    /// # Panics
+    /// Panics when the frame has [`OpCode::Datagram`]
+    /// but the `target_host` field is longer than 255 octets.
+    #[tracing::instrument(level = "trace")]
+    #[inline]
+    fn from(frame: &Frame<'_>) -> Self {
+        let size = frame.encoded_len();
+        let mut encoded = Self::with_capacity(size);
+        frame.encode_into(&mut encoded);
         // Make sure our estimated size is correct
         // so that no extra allocations are made
         debug_assert_eq!(size, encoded.len());
@@ -582,6 +813,20 @@ impl FinalizedFrame {
         let raw_opcode = firstbyte & 0x0F;
         OpCode::try_from(raw_opcode)
     }
+
+    /// Encode an [`OpCode::Push`] frame of all of `bufs` in one go,
+    /// without concatenating them first
+    #[inline]
+    pub fn new_push_vectored(id: u32, bufs: &[IoSlice<'_>], pool: &BufferPool) -> Self {
+        let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let mut encoded = pool.get(size_of::<u8>() + size_of::<u32>() + len);
+        encoded.put_u8(OpCode::Push as u8 | (proto_version::PROTOCOL_VERSION_NUMBER << 4));
+        encoded.put_u32(id);
+        for buf in bufs {
+            encoded.put_slice(buf);
+        }
+        Self(encoded.freeze())
+    }
 }
 
 impl Debug for FinalizedFrame {
@@ -722,6 +967,69 @@ mod tests {
         assert_eq!(frame, frame_back);
     }
 
+    #[test]
+    fn test_frame_repr_session() {
+        crate::tests::setup_logging();
+        let frame = Frame::new_session(0x0102_0304_0506);
+        let bytes = Vec::from(&frame);
+        assert_eq!(
+            bytes,
+            vec![
+                0x77, // ver | opcode (u8)
+                0x00, 0x00, 0x00, 0x00, // id (u32)
+                0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, // frames_received (u64)
+            ]
+        );
+        let frame_back = Frame::try_from(Bytes::from(bytes)).unwrap();
+        assert_eq!(frame, frame_back);
+    }
+
+    #[test]
+    fn test_frame_repr_batch() {
+        crate::tests::setup_logging();
+        let ack = Bytes::from(&Frame::new_acknowledge(0x0102_0304, 2));
+        let finish = Bytes::from(&Frame::new_finish(0x0506_0708));
+        let frame = Frame::new_batch(vec![ack.dupe(), finish.dupe()]);
+        let bytes = Vec::from(&frame);
+        assert_eq!(
+            bytes,
+            vec![
+                0x7a, // ver | opcode (u8)
+                0x00, 0x00, 0x00, 0x00, // id (u32)
+                0x00, 0x00, 0x00, 0x09, // len (u32)
+                0x71, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x02, // `Acknowledge`
+                0x00, 0x00, 0x00, 0x05, // len (u32)
+                0x73, 0x05, 0x06, 0x07, 0x08, // `Finish`
+            ]
+        );
+        let frame_back = Frame::try_from(Bytes::from(bytes.clone())).unwrap();
+        assert_eq!(frame, frame_back);
+        assert_eq!(frame_back.payload, Payload::Batch(vec![ack, finish]));
+        // Nested
+        let nested = Frame::new_batch(vec![Bytes::from(bytes)]);
+        assert!(matches!(
+            Frame::try_from(Bytes::from(&nested)),
+            Err(Error::NestedBatch)
+        ));
+    }
+
+    #[test]
+    fn test_frame_repr_compressed_push() {
+        crate::tests::setup_logging();
+        let frame = Frame::new_compressed_push(0x0102_0304, Bytes::from_static(b"\x4b\x04\x00"));
+        let bytes = Vec::from(&frame);
+        assert_eq!(
+            bytes,
+            vec![
+                0x78, // ver | opcode (u8)
+                0x01, 0x02, 0x03, 0x04, // id (u32)
+                0x4b, 0x04, 0x00, // data
+            ]
+        );
+        let frame_back = Frame::try_from(Bytes::from(bytes)).unwrap();
+        assert_eq!(frame, frame_back);
+    }
+
     #[test]
     fn test_frame_repr_reset() {
         crate::tests::setup_logging();
@@ -736,6 +1044,21 @@ mod tests {
         );
         let frame_back = Frame::try_from(Bytes::from(bytes)).unwrap();
         assert_eq!(frame, frame_back);
+        let frame = Frame::new_reset_with_reason(1291, ResetReason::Forbidden);
+        let bytes = Vec::from(&frame);
+        assert_eq!(
+            bytes,
+            vec![
+                0x72, // ver | opcode (u8)
+                0x00, 0x00, 0x05, 0x0b, // id (u32)
+                0x02, // reason (u8)
+            ]
+        );
+        let frame_back = Frame::try_from(Bytes::from(bytes)).unwrap();
+        assert_eq!(frame, frame_back);
+        // Unknown reasons are not an error
+        let frame_back = Frame::try_from(Bytes::from_static(&[0x72, 0, 0, 5, 0x0b, 0xff])).unwrap();
+        assert_eq!(frame_back, Frame::new_reset(1291));
     }
 
     #[test]
@@ -771,6 +1094,31 @@ mod tests {
         assert_eq!(frame, frame_back);
     }
 
+    #[test]
+    fn test_finalize_in_pool() {
+        crate::tests::setup_logging();
+        let pool = BufferPool::new();
+        let frame = Frame::new_datagram(2134, &[1, 2, 3, 4], 1234, &[5, 6, 7, 8]);
+        assert_eq!(frame.finalize_in(&pool), frame.finalize());
+        let frame = Frame::new_push(1, &[1, 2, 3, 4]);
+        assert_eq!(frame.finalize_in(&pool), frame.finalize());
+    }
+
+    #[test]
+    fn test_finalized_frame_push_vectored() {
+        crate::tests::setup_logging();
+        let bufs = [
+            IoSlice::new(&[1, 2]),
+            IoSlice::new(&[]),
+            IoSlice::new(&[3, 4]),
+        ];
+        let finalized = FinalizedFrame::new_push_vectored(0x75b_97bb, &bufs, &BufferPool::new());
+        assert_eq!(
+            finalized,
+            Frame::new_push(0x75b_97bb, &[1, 2, 3, 4]).finalize()
+        );
+    }
+
     #[test]
     fn test_frame_repr_bind() {
         crate::tests::setup_logging();
@@ -1,36 +1,56 @@
 //! Multiplexing streamed data and datagrams over a single WebSocket
 //! connection.
 //!
+//! The connection does not have to be a WebSocket: any byte stream can be
+//! used through [`ws::PlainStream`], and any transport carrying whole
+//! messages through [`ws::Framed`].
+//!
 //! This is not a general-purpose WebSocket multiplexing library.
 //! It is tailored to the needs of `penguin`.
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 #![deny(rust_2018_idioms, missing_docs, missing_debug_implementations)]
 #![deny(clippy::pedantic, clippy::cargo, clippy::nursery, clippy::unwrap_used)]
+// `loom` and the test dependencies pull in older versions of some crates
 
+pub mod compression;
 pub mod config;
 mod dupe;
 pub mod frame;
+pub mod limit;
 mod loom;
+mod padding;
+pub mod pool;
+mod priority;
 mod proto_version;
+pub mod stats;
 mod stream;
 mod task;
 #[cfg(test)]
 mod tests;
 pub mod timing;
+mod window;
 pub mod ws;
 
-use crate::frame::{BindPayload, BindType, FinalizedFrame, Frame};
-use crate::loom::{Arc, AtomicBool, AtomicU32, AtomicWaker, Mutex, Ordering, RwLock};
-use crate::task::{Task, TaskData};
+use crate::compression::StreamCompression;
+use crate::frame::{BindPayload, BindType, Frame};
+use crate::limit::SessionLimits;
+use crate::loom::{
+    Arc, AtomicBool, AtomicU8, AtomicU32, AtomicUsize, AtomicWaker, Mutex, Ordering, RwLock,
+};
+use crate::priority::FrameTx;
+use crate::stats::{FlowCounters, FlowStats, Stats};
+use crate::task::{Session, Task, TaskData};
+use crate::window::Rtt;
 use crate::ws::WebSocket;
 use bytes::Bytes;
 use rand::distr::uniform::SampleUniform;
 use std::future::poll_fn;
 use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc::error::TrySendError;
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{Notify, mpsc, oneshot};
 use tokio::task::JoinSet;
 use tracing::{error, trace, warn};
 
@@ -39,9 +59,16 @@ use nohash_hasher::IntMap;
 #[cfg(not(feature = "nohash"))]
 use std::collections::HashMap as IntMap;
 
+pub use crate::compression::Compression;
 pub use crate::dupe::Dupe;
-pub use crate::proto_version::{PROTOCOL_VERSION, PROTOCOL_VERSION_NUMBER};
-pub use crate::stream::MuxStream;
+pub use crate::frame::ResetReason;
+pub use crate::pool::BufferPool;
+pub use crate::priority::Priority;
+pub use crate::proto_version::{
+    PROTOCOL_VERSION, PROTOCOL_VERSION_NUMBER, SUPPORTED_PROTOCOL_VERSIONS,
+    negotiate_protocol_version,
+};
+pub use crate::stream::{MuxStream, OwnedReadHalf, OwnedWriteHalf, ReuniteError};
 
 /// Multiplexor error
 #[derive(Debug, Error)]
@@ -63,6 +90,15 @@ pub enum Error {
     /// Peer rejected the flow ID selection.
     #[error("Peer rejected flow ID selection")]
     FlowIdRejected,
+    /// No new `WebSocket` arrived in time to resume the session.
+    #[error("Session was not resumed in time")]
+    ResumeTimeout,
+    /// The peer cannot resume the session from where we are.
+    #[error("Peer cannot resume the session")]
+    ResumeMismatch,
+    /// No `Pong` arrived in time after a `Ping`.
+    #[error("No `Pong` received in time")]
+    PongTimeout,
 
     /// WebSocket errors
     #[error("WebSocket Error: {0}")]
@@ -96,7 +132,7 @@ pub struct Multiplexor {
     /// Open stream channels: `flow_id` -> `FlowSlot`
     flows: Arc<RwLock<IntMap<u32, FlowSlot>>>,
     /// Where tasks queue frames to be sent
-    tx_frame_tx: mpsc::UnboundedSender<FinalizedFrame>,
+    tx_frame_tx: FrameTx,
     /// We only use this to inform the task that the multiplexor is closed
     /// and it should stop processing.
     dropped_ports_tx: mpsc::UnboundedSender<u32>,
@@ -107,12 +143,18 @@ pub struct Multiplexor {
     con_recv_stream_rx: Mutex<mpsc::Receiver<MuxStream>>,
     /// Channel for `Bnd` requests.
     bnd_request_rx: Option<Mutex<mpsc::Receiver<BindRequest<'static>>>>,
+    /// Where to ask the task to send a `Ping`
+    ping_tx: mpsc::UnboundedSender<oneshot::Sender<Duration>>,
+    /// Round-trip time measured by the task
+    rtt: Arc<Rtt>,
     /// Number of retries to find a suitable flow ID
     /// See [`config::Options`] for more details.
     max_flow_id_retries: usize,
     /// Number of `StreamFrame`s to buffer in `MuxStream`'s channels before blocking
     /// See [`config::Options`] for more details.
     rwnd: u32,
+    /// Where frames are encoded
+    buffer_pool: BufferPool,
 }
 
 impl Multiplexor {
@@ -120,7 +162,8 @@ impl Multiplexor {
     ///
     /// # Arguments
     ///
-    /// * `ws`: The `WebSocket` connection to multiplex over.
+    /// * `ws`: The `WebSocket` connection to multiplex over, or another
+    ///   transport wrapped in [`ws::PlainStream`] or [`ws::Framed`].
     ///
     /// * `options`: Multiplexor options. See [`config::Options`] for more details.
     ///   If `None`, the default options will be used.
@@ -134,15 +177,48 @@ impl Multiplexor {
         options: Option<config::Options>,
         task_joinset: Option<&mut JoinSet<Result<()>>>,
     ) -> Self {
+        Self::new_inner(ws, options.unwrap_or_default(), None, task_joinset)
+    }
+
+    /// Create a new `Multiplexor` whose streams survive the loss of the
+    /// `WebSocket` connection.
+    ///
+    /// Both ends must use this constructor for the same session. When the
+    /// connection is lost, the multiplexor keeps all streams open for up to
+    /// [`resume_timeout`](config::Options::resume_timeout) while waiting for a
+    /// new `WebSocket` to be passed to [`Resumer::resume`]. Frames the peer
+    /// has not received are then retransmitted.
+    ///
+    /// See [`Multiplexor::new`] for the arguments.
+    #[tracing::instrument(skip_all, level = "debug")]
+    pub fn new_resumable<S: WebSocket>(
+        ws: S,
+        options: Option<config::Options>,
+        task_joinset: Option<&mut JoinSet<Result<()>>>,
+    ) -> (Self, Resumer<S>) {
         let options = options.unwrap_or_default();
+        let (ws_tx, ws_rx) = mpsc::channel(1);
+        let detached = Arc::new(Notify::new());
+        let session = Session::new(ws_rx, detached.dupe(), options.resume_timeout);
+        let mux = Self::new_inner(ws, options, Some(session), task_joinset);
+        (mux, Resumer { ws_tx, detached })
+    }
+
+    fn new_inner<S: WebSocket>(
+        ws: S,
+        options: config::Options,
+        session: Option<Session<S>>,
+        task_joinset: Option<&mut JoinSet<Result<()>>>,
+    ) -> Self {
         let (datagram_tx, datagram_rx) = mpsc::channel(options.datagram_buffer_size);
         let (con_recv_stream_tx, con_recv_stream_rx) = mpsc::channel(options.stream_buffer_size);
-        // This one is unbounded because the protocol provides its own flow control for `Push` frames
+        // These are unbounded because the protocol provides its own flow control for `Push` frames
         // and other frame types are to be immediately processed without any backpressure,
-        // so they are ok to be unbounded channels.
-        let (tx_frame_tx, tx_frame_rx) = mpsc::unbounded_channel();
+        // so they are ok to be unbounded channels. Stream data is queued by priority.
+        let (tx_frame_tx, tx_frame_rx) = priority::channel();
         // This one cannot be bounded because it needs to be used in Drop
         let (dropped_ports_tx, dropped_ports_rx) = mpsc::unbounded_channel();
+        let (ping_tx, ping_rx) = mpsc::unbounded_channel();
 
         let (bnd_request_tx, bnd_request_rx) = if options.bind_buffer_size > 0 {
             let (tx, rx) = mpsc::channel(options.bind_buffer_size);
@@ -151,6 +227,8 @@ impl Multiplexor {
             (None, None)
         };
         let flows = Arc::new(RwLock::new(IntMap::default()));
+        let buffer_pool = BufferPool::new();
+        let rtt = Arc::new(Rtt::default());
 
         let mux = Self {
             tx_frame_tx: tx_frame_tx.dupe(),
@@ -159,8 +237,11 @@ impl Multiplexor {
             datagram_rx: Mutex::new(datagram_rx),
             con_recv_stream_rx: Mutex::new(con_recv_stream_rx),
             bnd_request_rx: bnd_request_rx.map(Mutex::new),
+            ping_tx: ping_tx.dupe(),
+            rtt: rtt.dupe(),
             max_flow_id_retries: options.max_flow_id_retries,
             rwnd: options.rwnd,
+            buffer_pool: buffer_pool.dupe(),
         };
         let taskdata = TaskData {
             task: Task {
@@ -171,17 +252,40 @@ impl Multiplexor {
                 con_recv_stream_tx,
                 default_rwnd_threshold: options.default_rwnd_threshold,
                 rwnd: options.rwnd,
+                max_rwnd: options.max_rwnd,
+                rtt,
+                ping_tx,
+                pings: Mutex::new(Vec::new()),
                 datagram_tx,
                 bnd_request_tx,
                 keepalive_interval: options.keepalive_interval,
+                keepalive_timeout: options.keepalive_timeout,
+                compression: StreamCompression::new(
+                    options.compression,
+                    options.compression_threshold,
+                ),
+                limits: SessionLimits::new(&options),
+                padding: options.padding,
+                coalesce: options.coalesce,
+                session,
+                buffer_pool,
             },
             dropped_ports_rx,
             tx_frame_rx,
+            ping_rx,
         };
         taskdata.spawn(task_joinset);
         mux
     }
 
+    /// The pool the multiplexor encodes its frames in, which can be used for
+    /// the datagrams sent over it as well
+    #[inline]
+    #[must_use]
+    pub const fn buffer_pool(&self) -> &BufferPool {
+        &self.buffer_pool
+    }
+
     /// Request a channel for `host` and `port`.
     ///
     /// # Arguments
@@ -191,6 +295,11 @@ impl Multiplexor {
     ///   specifies that the host component of a URI is limited to 255 octets.
     /// * `port`: The port to forward to.
     ///
+    /// # Errors
+    /// * Returns [`Error::Closed`] if the connection is closed.
+    /// * Returns [`Error::FlowIdRejected`] if the peer keeps rejecting the
+    /// flow IDs we pick.
+    ///
     /// # Cancel safety
     /// This function is not cancel safe. If the task is cancelled while waiting
     /// for the channel to be established, that channel may be established but
@@ -208,7 +317,14 @@ impl Multiplexor {
                 // Allocate a new port
                 let flow_id = u32::next_available_key(&*streams);
                 trace!("flow_id = {flow_id:08x}");
-                streams.insert(flow_id, FlowSlot::Requested(stream_tx));
+                streams.insert(
+                    flow_id,
+                    FlowSlot::Requested {
+                        sender: stream_tx,
+                        dest_host: Bytes::copy_from_slice(host),
+                        dest_port: port,
+                    },
+                );
                 flow_id
             };
             trace!("sending `Connect`");
@@ -285,7 +401,7 @@ impl Multiplexor {
             datagram.data,
         );
         self.tx_frame_tx
-            .send(frame.finalize())
+            .send(frame.finalize_in(&self.buffer_pool))
             .or(Err(Error::Closed))?;
         Ok(())
     }
@@ -297,6 +413,9 @@ impl Multiplexor {
     /// not be supported by the remote peer.
     /// * `port`: The local port to bind to.
     ///
+    /// # Errors
+    /// Returns [`Error::Closed`] if the connection is closed.
+    ///
     /// # Cancel Safety
     /// This function is not cancel safe. If the task is cancelled while waiting
     /// for the peer to reply, the user will not be able to receive whether the
@@ -321,6 +440,11 @@ impl Multiplexor {
 
     /// Accept a `Bind` request from the remote peer.
     ///
+    /// # Errors
+    /// * Returns [`Error::Closed`] if the connection is closed.
+    /// * Returns [`Error::UnsupportedOperation`] if this multiplexor does not
+    /// accept `Bind` requests.
+    ///
     /// # Cancel Safety
     /// This function is cancel safe. If the task is cancelled while waiting
     /// for a `Bind` request, it is guaranteed that no request will be lost.
@@ -334,6 +458,56 @@ impl Multiplexor {
             Err(Error::UnsupportedOperation)
         }
     }
+
+    /// Send a `Ping` to the peer and wait for the `Pong`.
+    /// Returns the round-trip time, which is also used to adapt the receive
+    /// windows. If another `Ping`, e.g. a keepalive one, is already waiting
+    /// for its `Pong`, that one is measured instead of sending another.
+    ///
+    /// The `Pong` may never arrive if the peer does not answer `Ping`s,
+    /// so callers should apply a timeout.
+    ///
+    /// # Errors
+    /// Returns [`Error::Closed`] if the connection is closed.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe. If it is cancelled, the `Ping` may
+    /// still be sent.
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn ping(&self) -> Result<Duration> {
+        let (rtt_tx, rtt_rx) = oneshot::channel();
+        self.ping_tx.send(rtt_tx).or(Err(Error::Closed))?;
+        rtt_rx.await.or(Err(Error::Closed))
+    }
+
+    /// The smoothed round-trip time, if any `Pong` has been received.
+    /// It is measured with the `Ping`s sent every
+    /// [`keepalive_interval`](config::Options::keepalive_interval) and by
+    /// [`Multiplexor::ping`].
+    #[inline]
+    #[must_use]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.get()
+    }
+
+    /// What the multiplexor is doing at the moment: its streams with their
+    /// counters and windows, and the round-trip time.
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            rtt: self.rtt(),
+            ..Stats::default()
+        };
+        for (flow_id, slot) in self.flows.read().iter() {
+            match slot {
+                FlowSlot::Established(data) => stats.flows.push(data.stats(*flow_id)),
+                FlowSlot::Requested { .. } | FlowSlot::BindRequested(_) => {
+                    stats.pending_flows += 1;
+                }
+            }
+        }
+        stats
+    }
 }
 
 impl Drop for Multiplexor {
@@ -344,6 +518,59 @@ impl Drop for Multiplexor {
     }
 }
 
+/// Handle for passing a new `WebSocket` to a resumable [`Multiplexor`].
+/// See [`Multiplexor::new_resumable`].
+#[derive(Debug)]
+pub struct Resumer<S: WebSocket> {
+    /// Where the multiplexor task receives new `WebSocket`s
+    ws_tx: mpsc::Sender<S>,
+    /// Notified when the multiplexor task loses its `WebSocket`
+    detached: Arc<Notify>,
+}
+
+impl<S: WebSocket> Clone for Resumer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            ws_tx: self.ws_tx.clone(),
+            detached: self.detached.dupe(),
+        }
+    }
+}
+
+impl<S: WebSocket> Dupe for Resumer<S> {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl<S: WebSocket> Resumer<S> {
+    /// Continue the session over a new `WebSocket`.
+    /// The old `WebSocket` is dropped, even if the multiplexor has not
+    /// noticed that it was lost yet.
+    ///
+    /// # Errors
+    /// Returns [`Error::Closed`] if the multiplexor task has exited.
+    #[tracing::instrument(skip_all, level = "debug")]
+    pub async fn resume(&self, ws: S) -> Result<()> {
+        self.ws_tx.send(ws).await.or(Err(Error::Closed))
+    }
+
+    /// Wait until the multiplexor task loses its `WebSocket`.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn detached(&self) {
+        self.detached.notified().await;
+    }
+
+    /// Check if the multiplexor task has exited.
+    #[must_use]
+    pub fn is_closed(&self) -> bool {
+        self.ws_tx.is_closed()
+    }
+}
+
 #[derive(Debug)]
 struct EstablishedStreamData {
     /// Channel for sending data to `MuxStream`'s `AsyncRead`
@@ -362,6 +589,16 @@ struct EstablishedStreamData {
     /// Waker to wake up the task that sends frames because their `psh_send_remaining`
     /// has increased.
     writer_waker: Arc<AtomicWaker>,
+    /// [`ResetReason`] given by the peer when it reset the stream
+    reset_reason: Arc<AtomicU8>,
+    /// Forwarding destination
+    dest_host: Bytes,
+    /// Forwarding destination port
+    dest_port: u16,
+    /// Octets sent and received, shared with the `MuxStream`
+    counters: Arc<FlowCounters>,
+    /// Number of frames of the stream waiting to be sent
+    queued: Arc<AtomicUsize>,
 }
 
 impl EstablishedStreamData {
@@ -385,6 +622,30 @@ impl EstablishedStreamData {
         self.writer_waker.wake();
     }
 
+    /// Remember why the peer is resetting the stream so that the user sees
+    /// it instead of a plain EOF. Must be called before the stream is closed.
+    #[inline]
+    fn set_reset_reason(&self, reason: ResetReason) {
+        self.reset_reason.store(reason as u8, Ordering::Relaxed);
+    }
+
+    /// Counters of the stream for [`Multiplexor::stats`]
+    fn stats(&self, flow_id: u32) -> FlowStats {
+        FlowStats {
+            flow_id,
+            dest_host: self.dest_host.dupe(),
+            dest_port: self.dest_port,
+            bytes_sent: self.counters.sent(),
+            bytes_received: self.counters.received(),
+            queued_frames: self.queued.load(Ordering::Relaxed),
+            send_window: self.psh_send_remaining.load(Ordering::Relaxed),
+            unread_frames: self
+                .sender
+                .as_ref()
+                .map_or(0, |sender| sender.max_capacity() - sender.capacity()),
+        }
+    }
+
     /// Disallow any `AsyncWrite` operations.
     /// Note that this should not be used from inside the `MuxStream` itself
     #[inline]
@@ -407,7 +668,14 @@ impl EstablishedStreamData {
 #[derive(Debug)]
 enum FlowSlot {
     /// A `Connect` frame was sent and waiting for the peer to `Acknowledge`.
-    Requested(oneshot::Sender<Option<MuxStream>>),
+    Requested {
+        /// Where to send the stream once it is established
+        sender: oneshot::Sender<Option<MuxStream>>,
+        /// Forwarding destination
+        dest_host: Bytes,
+        /// Forwarding destination port
+        dest_port: u16,
+    },
     /// The stream is established.
     Established(EstablishedStreamData),
     /// A `Bind` request was sent and waiting for the peer to `Acknowledge` or `Reset`.
@@ -428,7 +696,7 @@ impl FlowSlot {
             return None;
         }
         let sender = match std::mem::replace(self, Self::Established(data)) {
-            Self::Requested(sender) => sender,
+            Self::Requested { sender, .. } => sender,
             Self::Established(_) | Self::BindRequested(_) => unreachable!(),
         };
         Some(sender)
@@ -438,10 +706,12 @@ impl FlowSlot {
     #[inline]
     fn dispatch(&self, data: Bytes) -> Option<std::result::Result<(), TrySendError<()>>> {
         if let Self::Established(stream_data) = self {
+            let len = data.len();
             let r = stream_data
                 .sender
                 .as_ref()
                 .map(|sender| sender.try_send(data))?
+                .map(|()| stream_data.counters.add_received(len))
                 .map_err(|e| match e {
                     TrySendError::Full(_) => TrySendError::Full(()),
                     TrySendError::Closed(_) => TrySendError::Closed(()),
@@ -474,7 +744,7 @@ pub struct BindRequest<'data> {
     /// Bind payload
     payload: BindPayload<'data>,
     /// Place to respond to the bind request
-    tx_frame_tx: mpsc::UnboundedSender<FinalizedFrame>,
+    tx_frame_tx: FrameTx,
 }
 
 impl BindRequest<'_> {
@@ -503,6 +773,9 @@ impl BindRequest<'_> {
     }
 
     /// Accept or reject the bind request
+    ///
+    /// # Errors
+    /// Returns [`Error::Closed`] if the connection is closed.
     #[tracing::instrument(skip(self), level = "debug")]
     pub fn reply(&self, accepted: bool) -> Result<()> {
         if accepted {
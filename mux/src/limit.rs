@@ -0,0 +1,287 @@
+//! Bandwidth limiting with token buckets.
+//!
+//! Each direction of a multiplexor can have a bucket shared by all its
+//! streams and a bucket for each stream. Data is let through as long as the
+//! buckets are not in debt, so a single write may exceed the rate, but the
+//! following ones wait until the debt is paid off. Limiting reads holds back
+//! the `Acknowledge`s of a stream, which makes the peer wait before sending
+//! more.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::Dupe;
+use crate::loom::{Arc, Mutex};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::{Instant, Sleep};
+
+/// A rate in octets per second.
+/// When parsed from a string, the suffixes `K`, `M` and `G` multiply the
+/// number by powers of 1000, and `0` means no limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bandwidth(u64);
+
+/// Error returned when parsing an invalid [`Bandwidth`]
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Invalid bandwidth: {0}")]
+pub struct InvalidBandwidth(String);
+
+impl Bandwidth {
+    /// No limit
+    pub const UNLIMITED: Self = Self(0);
+
+    /// Create a limit of `rate` octets per second. Zero means no limit.
+    #[must_use]
+    pub const fn from_bytes_per_sec(rate: u64) -> Self {
+        Self(rate)
+    }
+
+    /// The rate in octets per second, or `None` if there is no limit
+    #[must_use]
+    pub const fn bytes_per_sec(self) -> Option<u64> {
+        if self.0 == 0 { None } else { Some(self.0) }
+    }
+}
+
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = InvalidBandwidth;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidBandwidth(s.to_string());
+        let trimmed = s.trim();
+        let (number, multiplier) = match trimmed.as_bytes().last() {
+            Some(b'k' | b'K') => (&trimmed[..trimmed.len() - 1], 1_000),
+            Some(b'm' | b'M') => (&trimmed[..trimmed.len() - 1], 1_000_000),
+            Some(b'g' | b'G') => (&trimmed[..trimmed.len() - 1], 1_000_000_000),
+            _ => (trimmed, 1),
+        };
+        number
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(Self)
+            .ok_or_else(invalid)
+    }
+}
+
+/// A token bucket that holds up to one second worth of octets
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// Octets per second
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Octets that may be sent now. Negative when in debt.
+    tokens: i128,
+    /// When `tokens` was last refilled
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket, or `None` if there is no limit
+    pub fn new(bandwidth: Bandwidth) -> Option<Self> {
+        let rate = bandwidth.bytes_per_sec()?;
+        Some(Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: i128::from(rate),
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    /// Take `amount` octets out of the bucket, going into debt if needed.
+    pub fn consume(&self, amount: usize) {
+        let amount = i128::try_from(amount).unwrap_or(i128::MAX);
+        let mut state = self.state.lock();
+        state.tokens = state.tokens.saturating_sub(amount);
+    }
+
+    /// How long to wait until the bucket is out of debt, if it is in debt
+    pub fn delay(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_duration_since(state.refilled);
+        let refill = elapsed.as_nanos() * u128::from(self.rate) / 1_000_000_000;
+        let refill = i128::try_from(refill).unwrap_or(i128::MAX);
+        state.tokens = state
+            .tokens
+            .saturating_add(refill)
+            .min(i128::from(self.rate));
+        state.refilled = now;
+        let debt = u128::try_from(-state.tokens)
+            .ok()
+            .filter(|debt| *debt > 0)?;
+        drop(state);
+        let nanos = (debt * 1_000_000_000).div_ceil(u128::from(self.rate));
+        Some(Duration::from_nanos(
+            u64::try_from(nanos).unwrap_or(u64::MAX),
+        ))
+    }
+}
+
+/// Limits one direction of a [`MuxStream`](crate::MuxStream).
+/// Boxed because most streams are not limited.
+#[derive(Debug, Default)]
+pub(crate) struct Limiter(Option<Box<LimiterInner>>);
+
+#[derive(Debug)]
+struct LimiterInner {
+    /// Shared by all streams of the multiplexor
+    session: Option<Arc<TokenBucket>>,
+    /// Only used by this stream
+    stream: Option<TokenBucket>,
+    /// Waiting for the buckets to refill
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Limiter {
+    pub fn new(session: Option<&Arc<TokenBucket>>, stream: Bandwidth) -> Self {
+        let stream = TokenBucket::new(stream);
+        if session.is_none() && stream.is_none() {
+            return Self(None);
+        }
+        Self(Some(Box::new(LimiterInner {
+            session: session.map(Dupe::dupe),
+            stream,
+            sleep: None,
+        })))
+    }
+
+    /// Wait until data may pass.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(inner) = &mut self.0 else {
+            return Poll::Ready(());
+        };
+        loop {
+            if let Some(sleep) = &mut inner.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                inner.sleep = None;
+            }
+            let session_delay = inner.session.as_ref().and_then(|bucket| bucket.delay());
+            let stream_delay = inner.stream.as_ref().and_then(TokenBucket::delay);
+            let Some(delay) = session_delay.max(stream_delay) else {
+                return Poll::Ready(());
+            };
+            inner.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+
+    /// Account for `amount` octets that have passed.
+    pub fn consume(&self, amount: usize) {
+        let Some(inner) = &self.0 else {
+            return;
+        };
+        if let Some(bucket) = &inner.session {
+            bucket.consume(amount);
+        }
+        if let Some(bucket) = &inner.stream {
+            bucket.consume(amount);
+        }
+    }
+}
+
+/// Bandwidth limits of a [`MuxStream`](crate::MuxStream)
+#[derive(Debug, Default)]
+pub(crate) struct StreamLimits {
+    /// Applied to our writes
+    pub up: Limiter,
+    /// Applied to our reads
+    pub down: Limiter,
+}
+
+/// Bandwidth limits of a multiplexor
+#[derive(Debug, Default)]
+pub(crate) struct SessionLimits {
+    up: Option<Arc<TokenBucket>>,
+    down: Option<Arc<TokenBucket>>,
+    stream_up: Bandwidth,
+    stream_down: Bandwidth,
+}
+
+impl SessionLimits {
+    pub fn new(options: &crate::config::Options) -> Self {
+        Self {
+            up: TokenBucket::new(options.limit_up).map(Arc::new),
+            down: TokenBucket::new(options.limit_down).map(Arc::new),
+            stream_up: options.stream_limit_up,
+            stream_down: options.stream_limit_down,
+        }
+    }
+
+    /// Limits of a new stream
+    pub fn new_stream(&self) -> StreamLimits {
+        StreamLimits {
+            up: Limiter::new(self.up.as_ref(), self.stream_up),
+            down: Limiter::new(self.down.as_ref(), self.stream_down),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bandwidth() {
+        assert_eq!("0".parse(), Ok(Bandwidth::UNLIMITED));
+        assert_eq!("1500".parse(), Ok(Bandwidth(1500)));
+        assert_eq!("64k".parse(), Ok(Bandwidth(64_000)));
+        assert_eq!("10M".parse(), Ok(Bandwidth(10_000_000)));
+        assert_eq!("2G".parse(), Ok(Bandwidth(2_000_000_000)));
+        assert!("M".parse::<Bandwidth>().is_err());
+        assert!("1T".parse::<Bandwidth>().is_err());
+        assert!("-1".parse::<Bandwidth>().is_err());
+        assert!("99999999999999999999G".parse::<Bandwidth>().is_err());
+        assert_eq!(Bandwidth::UNLIMITED.bytes_per_sec(), None);
+        assert!(TokenBucket::new(Bandwidth::UNLIMITED).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        crate::tests::setup_logging();
+        let bucket = TokenBucket::new(Bandwidth(1000)).unwrap();
+        assert_eq!(bucket.delay(), None);
+        bucket.consume(1000);
+        assert_eq!(bucket.delay(), None);
+        bucket.consume(500);
+        assert_eq!(bucket.delay(), Some(Duration::from_millis(500)));
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert_eq!(bucket.delay(), Some(Duration::from_millis(300)));
+        // Does not fill up beyond one second worth of octets
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(bucket.delay(), None);
+        bucket.consume(1001);
+        assert_eq!(bucket.delay(), Some(Duration::from_millis(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limiter_waits_for_both_buckets() {
+        crate::tests::setup_logging();
+        let session = Arc::new(TokenBucket::new(Bandwidth(1000)).unwrap());
+        let mut limiter = Limiter::new(Some(&session), Bandwidth(100));
+        let start = Instant::now();
+        std::future::poll_fn(|cx| limiter.poll_ready(cx)).await;
+        limiter.consume(300);
+        std::future::poll_fn(|cx| limiter.poll_ready(cx)).await;
+        // The stream bucket is slower
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        session.consume(3000);
+        std::future::poll_fn(|cx| limiter.poll_ready(cx)).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(4));
+    }
+}
@@ -5,14 +5,14 @@ pub use futures_util::task::AtomicWaker;
 #[cfg(all(loom, test))]
 pub use loom::sync::{
     Arc,
-    atomic::{AtomicBool, AtomicU32, Ordering},
+    atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
 #[cfg(not(all(loom, test)))]
 pub use parking_lot::{Mutex, RwLock};
 #[cfg(not(all(loom, test)))]
 pub use std::sync::{
     Arc,
-    atomic::{AtomicBool, AtomicU32, Ordering},
+    atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering},
 };
 
 #[cfg(all(loom, test))]
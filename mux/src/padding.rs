@@ -0,0 +1,98 @@
+//! Traffic padding.
+//!
+//! With padding enabled, every frame is sent inside a
+//! [`Padded`](crate::frame::OpCode::Padded) frame whose length is rounded up
+//! to one of a few bucket sizes, and empty `Padded` frames are sent at
+//! random intervals, so that the sizes and timing of the `WebSocket`
+//! messages say less about the traffic in the tunnel.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::frame::OpCode;
+use crate::proto_version::PROTOCOL_VERSION_NUMBER;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::time::Duration;
+
+/// Smallest padded message
+const MIN_BUCKET: usize = 128;
+/// Messages longer than this are padded to a multiple of it instead of a
+/// power of two
+const MAX_BUCKET: usize = 16384;
+/// Size of the `Padded` frame header: opcode, flow ID and inner length
+const HEADER_LEN: usize = 1 + 4 + 4;
+/// Mean interval between dummy frames
+const DUMMY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size of the bucket a message of `len` octets is padded to
+const fn bucket(len: usize) -> usize {
+    if len <= MIN_BUCKET {
+        MIN_BUCKET
+    } else if len <= MAX_BUCKET {
+        len.next_power_of_two()
+    } else {
+        len.div_ceil(MAX_BUCKET) * MAX_BUCKET
+    }
+}
+
+/// Wrap an encoded frame in a `Padded` frame of a bucket size
+pub fn pad(frame: &[u8]) -> Bytes {
+    encode(frame, bucket(HEADER_LEN + frame.len()))
+}
+
+/// Make an empty `Padded` frame of a random bucket size
+pub fn dummy() -> Bytes {
+    let size = MIN_BUCKET << rand::random_range(0..4);
+    encode(&[], size)
+}
+
+/// Random delay before the next dummy frame
+pub fn dummy_interval() -> Duration {
+    DUMMY_INTERVAL.mul_f64(rand::random_range(0.0..2.0))
+}
+
+fn encode(frame: &[u8], size: usize) -> Bytes {
+    debug_assert!(size >= HEADER_LEN + frame.len());
+    let inner_len = u32::try_from(frame.len()).expect("Frame too long to pad (this is a bug)");
+    let mut buf = BytesMut::with_capacity(size);
+    buf.put_u8(OpCode::Padded as u8 | (PROTOCOL_VERSION_NUMBER << 4));
+    buf.put_u32(0);
+    buf.put_u32(inner_len);
+    buf.put_slice(frame);
+    // The padding does not need to be random because it should be encrypted
+    // by the transport anyway
+    buf.resize(size, 0);
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{Frame, Payload};
+
+    #[test]
+    fn test_bucket() {
+        crate::tests::setup_logging();
+        assert_eq!(bucket(0), 128);
+        assert_eq!(bucket(128), 128);
+        assert_eq!(bucket(129), 256);
+        assert_eq!(bucket(16384), 16384);
+        assert_eq!(bucket(16385), 32768);
+        assert_eq!(bucket(40000), 49152);
+    }
+
+    #[test]
+    fn test_pad() {
+        crate::tests::setup_logging();
+        let frame = Frame::new_push(42, b"hello");
+        let padded = pad(&Bytes::from(&frame));
+        assert_eq!(padded.len(), 128);
+        assert_eq!(Frame::try_from(padded).unwrap(), frame);
+        let dummy = dummy();
+        assert!([128, 256, 512, 1024].contains(&dummy.len()));
+        let frame = Frame::try_from(dummy).unwrap();
+        assert_eq!(frame.payload, Payload::Padding);
+        // Padded frames cannot be nested
+        let nested = pad(&pad(&Bytes::from(&Frame::new_finish(1))));
+        assert!(Frame::try_from(nested).is_err());
+    }
+}
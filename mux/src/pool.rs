@@ -0,0 +1,91 @@
+//! Pooled allocation of frame and packet buffers.
+//!
+//! Buffers are carved out of larger chunks so that small frames and UDP
+//! packets do not need an allocation each. A chunk is reused once all the
+//! buffers carved out of it have been dropped.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::Dupe;
+use crate::loom::{Arc, Mutex};
+use bytes::{Bytes, BytesMut};
+
+/// Size of the chunks buffers are carved out of
+const CHUNK_SIZE: usize = 256 << 10;
+/// Larger buffers get an allocation of their own
+const MAX_POOLED_SIZE: usize = CHUNK_SIZE / 4;
+
+/// A pool of buffers, cheap to clone and share between tasks
+#[derive(Clone, Debug, Default)]
+pub struct BufferPool(Arc<Mutex<BytesMut>>);
+
+impl Dupe for BufferPool {
+    #[inline]
+    fn dupe(&self) -> Self {
+        Self(self.0.dupe())
+    }
+}
+
+impl BufferPool {
+    /// Create an empty pool
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get an empty buffer with room for `len` octets
+    #[must_use]
+    pub fn get(&self, len: usize) -> BytesMut {
+        if len > MAX_POOLED_SIZE {
+            return BytesMut::with_capacity(len);
+        }
+        let mut chunk = self.0.lock();
+        if chunk.capacity() < len {
+            // This takes the old chunk back if nothing uses it anymore
+            chunk.reserve(CHUNK_SIZE);
+        }
+        let rest = chunk.split_off(len);
+        std::mem::replace(&mut *chunk, rest)
+    }
+
+    /// Copy `data` into a pooled buffer
+    #[must_use]
+    pub fn copy_from_slice(&self, data: &[u8]) -> Bytes {
+        let mut buf = self.get(data.len());
+        buf.extend_from_slice(data);
+        buf.freeze()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        crate::tests::setup_logging();
+        let pool = BufferPool::new();
+        let first = pool.copy_from_slice(b"hello");
+        let second = pool.copy_from_slice(b"world");
+        assert_eq!(first, "hello");
+        assert_eq!(second, "world");
+        // Carved out of the same chunk
+        assert_eq!(first.as_ptr().wrapping_add(5), second.as_ptr());
+        let big = pool.get(MAX_POOLED_SIZE + 1);
+        assert!(big.is_empty());
+        assert!(big.capacity() > MAX_POOLED_SIZE);
+    }
+
+    #[test]
+    fn test_buffer_pool_reuse() {
+        crate::tests::setup_logging();
+        let pool = BufferPool::new();
+        let bufs = (0..CHUNK_SIZE / MAX_POOLED_SIZE)
+            .map(|_| pool.get(MAX_POOLED_SIZE))
+            .collect::<Vec<_>>();
+        let start = bufs[0].as_ptr();
+        drop(bufs);
+        // The exhausted chunk is taken back once nothing refers to it
+        assert_eq!(pool.get(1).as_ptr(), start);
+    }
+}
@@ -0,0 +1,272 @@
+//! Scheduling of outgoing frames by stream priority.
+//!
+//! Frames generated by the multiplexor itself (e.g. `Connect`, `Acknowledge`
+//! and `Datagram`) are always sent first. The `Push` and `Finish` frames of
+//! streams wait in one queue per [`Priority`], and a queue is only served when
+//! all higher-priority queues are empty. The frames of each stream stay in
+//! order because a stream only moves to a higher-priority queue once all its
+//! frames in the old queue have been sent.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::Dupe;
+use crate::frame::FinalizedFrame;
+use crate::loom::{Arc, AtomicUsize, Ordering};
+use std::future::poll_fn;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+
+/// Priority of the data written to a [`MuxStream`](crate::MuxStream)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Sent before any other stream data, e.g. for interactive sessions
+    High,
+    /// The default priority
+    #[default]
+    Normal,
+    /// Only sent when no other stream has data to send, e.g. for bulk transfers
+    Low,
+}
+
+impl Priority {
+    /// Number of priority levels
+    const COUNT: usize = 3;
+
+    /// Index of the queue of this priority, highest priority first
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// A stream frame waiting to be sent
+#[derive(Debug)]
+struct QueuedFrame {
+    frame: FinalizedFrame,
+    /// Counter of queued frames of the stream that sent it
+    queued: Option<Arc<AtomicUsize>>,
+}
+
+/// Scheduling state of a [`MuxStream`](crate::MuxStream)
+#[derive(Debug, Default)]
+pub struct StreamSchedule {
+    /// Priority of the queue the stream is currently using
+    current: Priority,
+    /// Priority requested by the user
+    requested: Priority,
+    /// Number of frames of the stream that have not been dequeued yet
+    queued: Arc<AtomicUsize>,
+}
+
+impl StreamSchedule {
+    /// Priority requested by the user
+    pub const fn priority(&self) -> Priority {
+        self.requested
+    }
+
+    /// Number of frames of the stream that have not been dequeued yet
+    pub const fn queued(&self) -> &Arc<AtomicUsize> {
+        &self.queued
+    }
+
+    /// Request a new priority. It takes effect as soon as doing so cannot
+    /// reorder the frames of the stream.
+    pub const fn set_priority(&mut self, priority: Priority) {
+        self.requested = priority;
+    }
+
+    /// Priority of the queue to put the next frame in
+    fn next_queue(&mut self) -> Priority {
+        // Frames queued at a higher priority are always sent before ours, so
+        // lowering the priority is safe at any time.
+        if self.requested > self.current || self.queued.load(Ordering::Acquire) == 0 {
+            self.current = self.requested;
+        }
+        self.current
+    }
+}
+
+/// Sending ends of the frame queues
+#[derive(Clone, Debug)]
+pub struct FrameTx {
+    /// Frames generated by the multiplexor
+    control: mpsc::UnboundedSender<FinalizedFrame>,
+    /// Stream frames, one queue per priority
+    data: [mpsc::UnboundedSender<QueuedFrame>; Priority::COUNT],
+}
+
+impl Dupe for FrameTx {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl FrameTx {
+    /// Queue a frame ahead of all stream data.
+    #[inline]
+    pub fn send(&self, frame: FinalizedFrame) -> Result<(), SendError<FinalizedFrame>> {
+        self.control.send(frame)
+    }
+
+    /// Queue a frame of a stream according to its priority.
+    #[inline]
+    pub fn send_data(
+        &self,
+        schedule: &mut StreamSchedule,
+        frame: FinalizedFrame,
+    ) -> Result<(), SendError<FinalizedFrame>> {
+        let queue = &self.data[schedule.next_queue().index()];
+        schedule.queued.fetch_add(1, Ordering::AcqRel);
+        let queued = Some(schedule.queued.dupe());
+        queue.send(QueuedFrame { frame, queued }).map_err(|e| {
+            schedule.queued.fetch_sub(1, Ordering::AcqRel);
+            SendError(e.0.frame)
+        })
+    }
+
+    /// Queue a frame behind all stream data that is already queued,
+    /// e.g. a `Reset` that must not overtake the data of a dropped stream.
+    #[inline]
+    pub fn send_after_data(&self, frame: FinalizedFrame) -> Result<(), SendError<FinalizedFrame>> {
+        self.data[Priority::Low.index()]
+            .send(QueuedFrame {
+                frame,
+                queued: None,
+            })
+            .map_err(|e| SendError(e.0.frame))
+    }
+}
+
+/// Receiving ends of the frame queues
+#[derive(Debug)]
+pub struct FrameRx {
+    control: mpsc::UnboundedReceiver<FinalizedFrame>,
+    data: [mpsc::UnboundedReceiver<QueuedFrame>; Priority::COUNT],
+}
+
+impl FrameRx {
+    /// Poll for the next frame to send, highest priority first.
+    /// Returns `None` once all queues are closed and empty.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<FinalizedFrame>> {
+        let mut closed = match self.control.poll_recv(cx) {
+            Poll::Ready(Some(frame)) => return Poll::Ready(Some(frame)),
+            Poll::Ready(None) => true,
+            Poll::Pending => false,
+        };
+        for queue in &mut self.data {
+            match queue.poll_recv(cx) {
+                Poll::Ready(Some(QueuedFrame { frame, queued })) => {
+                    if let Some(queued) = queued {
+                        queued.fetch_sub(1, Ordering::AcqRel);
+                    }
+                    return Poll::Ready(Some(frame));
+                }
+                Poll::Ready(None) => {}
+                Poll::Pending => closed = false,
+            }
+        }
+        if closed {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Receive the next frame to send, highest priority first.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn recv(&mut self) -> Option<FinalizedFrame> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Close all queues. Frames already queued can still be received.
+    pub fn close(&mut self) {
+        self.control.close();
+        for queue in &mut self.data {
+            queue.close();
+        }
+    }
+}
+
+/// Create the frame queues of a multiplexor
+pub fn channel() -> (FrameTx, FrameRx) {
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let (high_tx, high_rx) = mpsc::unbounded_channel();
+    let (normal_tx, normal_rx) = mpsc::unbounded_channel();
+    let (low_tx, low_rx) = mpsc::unbounded_channel();
+    (
+        FrameTx {
+            control: control_tx,
+            data: [high_tx, normal_tx, low_tx],
+        },
+        FrameRx {
+            control: control_rx,
+            data: [high_rx, normal_rx, low_rx],
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use futures_util::FutureExt;
+
+    fn flow_id_of(frame: FinalizedFrame) -> u32 {
+        Frame::try_from(frame).unwrap().id
+    }
+
+    #[test]
+    fn test_frames_dequeued_by_priority() {
+        crate::tests::setup_logging();
+        let (tx, mut rx) = channel();
+        let mut low = StreamSchedule::default();
+        low.set_priority(Priority::Low);
+        let mut high = StreamSchedule::default();
+        high.set_priority(Priority::High);
+        let mut normal = StreamSchedule::default();
+        tx.send_data(&mut low, Frame::new_finish(1).finalize())
+            .unwrap();
+        tx.send_after_data(Frame::new_reset(2).finalize()).unwrap();
+        tx.send_data(&mut normal, Frame::new_finish(3).finalize())
+            .unwrap();
+        tx.send_data(&mut high, Frame::new_finish(4).finalize())
+            .unwrap();
+        tx.send(Frame::new_reset(5).finalize()).unwrap();
+        let order: Vec<u32> = std::iter::from_fn(|| rx.recv().now_or_never().flatten())
+            .map(flow_id_of)
+            .collect();
+        assert_eq!(order, [5, 4, 3, 1, 2]);
+        rx.close();
+        assert!(matches!(rx.recv().now_or_never(), Some(None)));
+    }
+
+    #[test]
+    fn test_raising_priority_keeps_order() {
+        crate::tests::setup_logging();
+        let (tx, mut rx) = channel();
+        let mut schedule = StreamSchedule::default();
+        tx.send_data(&mut schedule, Frame::new_push(1, b"1").finalize())
+            .unwrap();
+        schedule.set_priority(Priority::High);
+        assert_eq!(schedule.priority(), Priority::High);
+        // Still goes behind the first frame
+        tx.send_data(&mut schedule, Frame::new_push(1, b"2").finalize())
+            .unwrap();
+        assert_eq!(schedule.current, Priority::Normal);
+        rx.recv().now_or_never().unwrap().unwrap();
+        rx.recv().now_or_never().unwrap().unwrap();
+        // Now the queue is empty and the stream can move up
+        tx.send_data(&mut schedule, Frame::new_push(1, b"3").finalize())
+            .unwrap();
+        assert_eq!(schedule.current, Priority::High);
+        // Lowering takes effect immediately
+        schedule.set_priority(Priority::Low);
+        tx.send_data(&mut schedule, Frame::new_push(1, b"4").finalize())
+            .unwrap();
+        assert_eq!(schedule.current, Priority::Low);
+        assert_eq!(schedule.queued.load(Ordering::Relaxed), 2);
+    }
+}
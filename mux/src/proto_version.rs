@@ -0,0 +1,40 @@
+/// Current mux and Penguin protocol version
+pub const PROTOCOL_VERSION: &str = "penguin-v7";
+
+/// Current mux and Penguin protocol version, only the version number
+pub const PROTOCOL_VERSION_NUMBER: u8 = 7;
+
+/// Protocol versions we can speak, highest first
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[&str] = &[PROTOCOL_VERSION];
+
+/// Pick the highest version we support from the comma-separated list of
+/// versions the peer offered, as in `sec-websocket-protocol`.
+/// Returns `None` if there is none in common.
+#[must_use]
+pub fn negotiate_protocol_version(offered: &str) -> Option<&'static str> {
+    let offered: Vec<&str> = offered.split(',').map(str::trim).collect();
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .copied()
+        .find(|version| offered.contains(version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_protocol_version() {
+        crate::tests::setup_logging();
+        assert_eq!(
+            negotiate_protocol_version("penguin-v7"),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_protocol_version("penguin-v99,  penguin-v7 ,penguin-v6"),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_protocol_version("penguin-v6"), None);
+        assert_eq!(negotiate_protocol_version(""), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! Statistics of a [`Multiplexor`](crate::Multiplexor).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::loom::{AtomicU64, Ordering};
+use bytes::Bytes;
+use std::time::Duration;
+
+/// What a [`Multiplexor`](crate::Multiplexor) is doing at the moment,
+/// returned by [`Multiplexor::stats`](crate::Multiplexor::stats)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Established streams, in no particular order
+    pub flows: Vec<FlowStats>,
+    /// Streams and `Bind` requests waiting for the peer to accept them
+    pub pending_flows: usize,
+    /// Smoothed round-trip time, see [`Multiplexor::rtt`](crate::Multiplexor::rtt)
+    pub rtt: Option<Duration>,
+}
+
+impl Stats {
+    /// Number of established streams
+    #[must_use]
+    pub const fn active_flows(&self) -> usize {
+        self.flows.len()
+    }
+
+    /// Octets written to all established streams
+    #[must_use]
+    pub fn bytes_sent(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.bytes_sent).sum()
+    }
+
+    /// Octets received on all established streams
+    #[must_use]
+    pub fn bytes_received(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.bytes_received).sum()
+    }
+
+    /// Frames written to the streams that have not been sent yet
+    #[must_use]
+    pub fn queued_frames(&self) -> usize {
+        self.flows.iter().map(|flow| flow.queued_frames).sum()
+    }
+}
+
+/// Counters of an established stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FlowStats {
+    /// Flow ID
+    pub flow_id: u32,
+    /// Forwarding destination
+    pub dest_host: Bytes,
+    /// Forwarding destination port
+    pub dest_port: u16,
+    /// Octets written to the stream
+    pub bytes_sent: u64,
+    /// Octets received from the peer, including those not read yet
+    pub bytes_received: u64,
+    /// Frames written to the stream that have not been sent yet
+    pub queued_frames: usize,
+    /// Frames we may still send before the peer has to `Acknowledge`
+    pub send_window: u32,
+    /// Frames received from the peer that have not been read yet
+    pub unread_frames: usize,
+}
+
+/// Octet counters of a stream, shared by the mux task and the `MuxStream`
+#[derive(Debug)]
+pub(crate) struct FlowCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Default for FlowCounters {
+    /// Counters starting at zero
+    fn default() -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        }
+    }
+}
+
+impl FlowCounters {
+    /// Count octets written to the stream
+    #[inline]
+    pub fn add_sent(&self, len: usize) {
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count octets received from the peer
+    #[inline]
+    pub fn add_received(&self, len: usize) {
+        self.received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Octets written to the stream
+    #[inline]
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Octets received from the peer
+    #[inline]
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
@@ -2,46 +2,203 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use crate::frame::{FinalizedFrame, Frame};
-use crate::loom::{Arc, AtomicBool, AtomicU32, AtomicWaker, Ordering};
+use crate::Dupe;
+use crate::compression::StreamCompression;
+use crate::frame::{FinalizedFrame, Frame, ResetReason};
+use crate::limit::{Limiter, StreamLimits};
+use crate::loom::{Arc, AtomicBool, AtomicU8, AtomicU32, AtomicWaker, Ordering};
+use crate::pool::BufferPool;
+use crate::priority::{FrameTx, Priority, StreamSchedule};
+use crate::stats::FlowCounters;
+use crate::window::ReceiveWindow;
 use bytes::{Buf, Bytes};
 use std::io;
 use std::io::ErrorKind::BrokenPipe;
+use std::io::IoSlice;
 use std::pin::Pin;
 use std::task::{Context, Poll, ready};
+use thiserror::Error;
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, BufReader};
 use tokio::sync::mpsc;
 use tracing::{debug, trace, warn};
 
-/// All parameters of a stream channel
-pub struct MuxStream {
+/// All parameters of a new stream channel
+pub struct StreamParts {
     /// Receive stream frames
-    pub(super) frame_rx: mpsc::Receiver<Bytes>,
+    pub frame_rx: mpsc::Receiver<Bytes>,
     /// Flow ID
-    pub(super) flow_id: u32,
+    pub flow_id: u32,
     /// Forwarding destination
     pub dest_host: Bytes,
     /// Forwarding destination port
     pub dest_port: u16,
     /// Whether writes should not succeed
-    pub(super) finish_sent: Arc<AtomicBool>,
+    pub finish_sent: Arc<AtomicBool>,
     /// Number of frames we can still send before we need to wait for an `Acknowledge`
-    pub(super) psh_send_remaining: Arc<AtomicU32>,
-    /// Number of `Push` frames received after sending the previous `Acknowledge` frame
-    /// `rwnd - psh_recvd_since` is approximately the peer's `psh_send_remaining`
-    pub(super) psh_recvd_since: u32,
+    pub psh_send_remaining: Arc<AtomicU32>,
     /// Waker to wake up the task that sends frames
-    pub(super) writer_waker: Arc<AtomicWaker>,
-    /// Remaining bytes to be read
-    pub(super) buf: Bytes,
+    pub writer_waker: Arc<AtomicWaker>,
+    /// [`ResetReason`] given by the peer if it reset the stream
+    pub reset_reason: Arc<AtomicU8>,
+    /// Octets sent and received
+    pub counters: Arc<FlowCounters>,
     /// See `MultiplexorInner`.
-    pub(super) frame_tx: mpsc::UnboundedSender<FinalizedFrame>,
+    pub frame_tx: FrameTx,
     /// See `MultiplexorInner`.
-    pub(super) dropped_ports_tx: mpsc::UnboundedSender<u32>,
+    pub dropped_ports_tx: mpsc::UnboundedSender<u32>,
+    /// See [`OwnedReadHalf::rwnd_threshold`].
+    pub rwnd_threshold: u32,
+    /// See [`OwnedReadHalf::window`].
+    pub window: ReceiveWindow,
+    /// How to compress our `Push` frames
+    pub compression: StreamCompression,
+    /// Bandwidth limits of our writes and reads
+    pub limits: StreamLimits,
+    /// Where our frames are encoded
+    pub buffer_pool: BufferPool,
+}
+
+/// Frees the port of a stream once both of its halves are dropped
+#[derive(Debug)]
+struct PortGuard {
+    flow_id: u32,
+    dest_host: Bytes,
+    dest_port: u16,
+    dropped_ports_tx: mpsc::UnboundedSender<u32>,
+}
+
+impl Drop for PortGuard {
+    // Dropping the port should act like `close()` has been called.
+    // Since `drop` is not async, this is handled by the mux task.
+    /// Close the stream by instructing the mux task to send a [`Reset`](crate::frame::OpCode::Reset) frame if
+    /// the stream is still open. The associated port will be freed for reuse.
+    fn drop(&mut self) {
+        // Notify the task that this port is no longer in use
+        self.dropped_ports_tx
+            .send(self.flow_id)
+            // Maybe the task has already exited, who knows
+            .ok();
+    }
+}
+
+/// A stream channel of a [`Multiplexor`](crate::Multiplexor)
+pub struct MuxStream {
+    /// Flow ID
+    pub(super) flow_id: u32,
+    /// Forwarding destination
+    pub dest_host: Bytes,
+    /// Forwarding destination port
+    pub dest_port: u16,
+    /// Our reading state
+    pub(super) read: OwnedReadHalf,
+    /// Our writing state
+    pub(super) write: OwnedWriteHalf,
+}
+
+/// The read half of a [`MuxStream`], created by [`MuxStream::into_split`]
+pub struct OwnedReadHalf {
+    /// Receive stream frames
+    pub(super) frame_rx: mpsc::Receiver<Bytes>,
+    /// Flow ID
+    pub(super) flow_id: u32,
+    /// Number of `Push` frames received after sending the previous `Acknowledge` frame
+    /// `rwnd - psh_recvd_since` is approximately the peer's `psh_send_remaining`
+    pub(super) psh_recvd_since: u32,
     /// Number of `Push` frames between [`Acknowledge`](frame::OpCode::Acknowledge)s:
     /// If too low, `Acknowledge`s will consume too much bandwidth;
     /// If too high, writers may block.
     pub(super) rwnd_threshold: u32,
+    /// How many more frames than we read to `Acknowledge`, or fewer
+    pub(super) window: ReceiveWindow,
+    /// [`ResetReason`] given by the peer if it reset the stream
+    pub(super) reset_reason: Arc<AtomicU8>,
+    /// Remaining bytes to be read
+    pub(super) buf: Bytes,
+    /// See `MultiplexorInner`.
+    pub(super) frame_tx: FrameTx,
+    /// Bandwidth limit of our reads
+    pub(super) limit: Limiter,
+    /// Shared with the write half
+    port: Arc<PortGuard>,
+}
+
+/// The write half of a [`MuxStream`], created by [`MuxStream::into_split`]
+pub struct OwnedWriteHalf {
+    /// Flow ID
+    pub(super) flow_id: u32,
+    /// Whether writes should not succeed
+    pub(super) finish_sent: Arc<AtomicBool>,
+    /// Number of frames we can still send before we need to wait for an `Acknowledge`
+    pub(super) psh_send_remaining: Arc<AtomicU32>,
+    /// Waker to wake up the task that sends frames
+    pub(super) writer_waker: Arc<AtomicWaker>,
+    /// [`ResetReason`] given by the peer if it reset the stream
+    pub(super) reset_reason: Arc<AtomicU8>,
+    /// See `MultiplexorInner`.
+    pub(super) frame_tx: FrameTx,
+    /// How to compress our `Push` frames
+    pub(super) compression: StreamCompression,
+    /// Which queue our `Push` and `Finish` frames wait in
+    pub(super) schedule: StreamSchedule,
+    /// Bandwidth limit of our writes
+    pub(super) limit: Limiter,
+    /// Where our frames are encoded
+    pub(super) buffer_pool: BufferPool,
+    /// Octets written, shared with the mux task
+    pub(super) counters: Arc<FlowCounters>,
+    /// Shared with the read half
+    port: Arc<PortGuard>,
+}
+
+/// Error returned by [`OwnedReadHalf::reunite`] when the halves are not
+/// from the same stream
+#[derive(Debug, Error)]
+#[error("The halves are not from the same stream")]
+pub struct ReuniteError(pub OwnedReadHalf, pub OwnedWriteHalf);
+
+impl MuxStream {
+    /// Create a stream from its parts
+    pub(super) fn new(parts: StreamParts) -> Self {
+        let port = Arc::new(PortGuard {
+            flow_id: parts.flow_id,
+            dest_host: parts.dest_host.dupe(),
+            dest_port: parts.dest_port,
+            dropped_ports_tx: parts.dropped_ports_tx,
+        });
+        let read = OwnedReadHalf {
+            frame_rx: parts.frame_rx,
+            flow_id: parts.flow_id,
+            psh_recvd_since: 0,
+            rwnd_threshold: parts.rwnd_threshold,
+            window: parts.window,
+            reset_reason: parts.reset_reason.dupe(),
+            buf: Bytes::new(),
+            frame_tx: parts.frame_tx.dupe(),
+            limit: parts.limits.down,
+            port: port.dupe(),
+        };
+        let write = OwnedWriteHalf {
+            flow_id: parts.flow_id,
+            finish_sent: parts.finish_sent,
+            psh_send_remaining: parts.psh_send_remaining,
+            writer_waker: parts.writer_waker,
+            reset_reason: parts.reset_reason,
+            frame_tx: parts.frame_tx,
+            compression: parts.compression,
+            schedule: StreamSchedule::default(),
+            limit: parts.limits.up,
+            buffer_pool: parts.buffer_pool,
+            counters: parts.counters,
+            port,
+        };
+        Self {
+            flow_id: parts.flow_id,
+            dest_host: parts.dest_host,
+            dest_port: parts.dest_port,
+            read,
+            write,
+        }
+    }
 }
 
 impl std::fmt::Debug for MuxStream {
@@ -50,30 +207,104 @@ impl std::fmt::Debug for MuxStream {
             .field("flow_id", &format_args!("{:08x}", self.flow_id))
             .field("dest_host", &self.dest_host)
             .field("dest_port", &self.dest_port)
-            .field("finish_sent", &self.finish_sent)
-            .field("psh_send_remaining", &self.psh_send_remaining)
+            .field("read", &self.read)
+            .field("write", &self.write)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for OwnedReadHalf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedReadHalf")
+            .field("flow_id", &format_args!("{:08x}", self.flow_id))
             .field("psh_recvd_since", &self.psh_recvd_since)
             .field("rwnd_threshold", &self.rwnd_threshold)
+            .field("window", &self.window.size())
             .field("buf.len", &self.buf.len())
             .finish_non_exhaustive()
     }
 }
 
-impl Drop for MuxStream {
-    // Dropping the port should act like `close()` has been called.
-    // Since `drop` is not async, this is handled by the mux task.
-    /// Close the stream by instructing the mux task to send a [`Reset`](crate::frame::OpCode::Reset) frame if
-    /// the stream is still open. The associated port will be freed for reuse.
-    fn drop(&mut self) {
-        // Notify the task that this port is no longer in use
-        self.dropped_ports_tx
-            .send(self.flow_id)
-            // Maybe the task has already exited, who knows
-            .ok();
+impl std::fmt::Debug for OwnedWriteHalf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OwnedWriteHalf")
+            .field("flow_id", &format_args!("{:08x}", self.flow_id))
+            .field("finish_sent", &self.finish_sent)
+            .field("psh_send_remaining", &self.psh_send_remaining)
+            .field("compression", &self.compression)
+            .field("priority", &self.schedule.priority())
+            .finish_non_exhaustive()
     }
 }
 
 impl AsyncRead for MuxStream {
+    /// Read data from the stream.
+    /// See [`OwnedReadHalf::poll_read`].
+    #[inline]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    /// Write data to the stream.
+    /// See [`OwnedWriteHalf::poll_write`].
+    #[inline]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    /// Write data from several buffers to the stream.
+    /// See [`OwnedWriteHalf::poll_write_vectored`].
+    #[inline]
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.write.is_write_vectored()
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    /// Close the write end of the stream (`shutdown(SHUT_WR)`).
+    /// See [`OwnedWriteHalf::poll_shutdown`].
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.write).poll_shutdown(cx)
+    }
+}
+
+impl AsyncBufRead for MuxStream {
+    /// See [`OwnedReadHalf::poll_fill_buf`].
+    #[inline]
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().read).poll_fill_buf(cx)
+    }
+
+    #[inline]
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.read).consume(amt);
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
     /// Read data from the stream.
     /// There are two cases where this function gives EOF:
     /// 1. One `Frame` contains an empty payload.
@@ -93,7 +324,7 @@ impl AsyncRead for MuxStream {
     }
 }
 
-impl AsyncWrite for MuxStream {
+impl AsyncWrite for OwnedWriteHalf {
     /// Write data to the stream. Each invocation of this method will send a
     /// separate frame in a new [`Message`](crate::ws::Message), so it may be
     /// beneficial to wrap it in a [`BufWriter`](tokio::io::BufWriter) where
@@ -105,13 +336,52 @@ impl AsyncWrite for MuxStream {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        ready!(self.poll_obtain_write_permission(cx))?;
-        let frame = Frame::new_push(self.flow_id, buf).finalize();
-        self.frame_tx.send(frame).or(Err(BrokenPipe))?;
+        let this = self.get_mut();
+        ready!(this.limit.poll_ready(cx));
+        ready!(this.poll_obtain_write_permission(cx))?;
+        let frame = this.push_frame(buf);
+        this.frame_tx
+            .send_data(&mut this.schedule, frame)
+            .or(Err(BrokenPipe))?;
+        this.limit.consume(buf.len());
+        this.counters.add_sent(buf.len());
         trace!("sent a frame");
         Poll::Ready(Ok(buf.len()))
     }
 
+    /// Write data from several buffers to the stream. All of it is sent in
+    /// a single frame, like one [`poll_write`](Self::poll_write) of the
+    /// buffers concatenated.
+    #[tracing::instrument(skip_all, level = "trace", fields(flow_id = self.flow_id))]
+    #[inline]
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if len == 0 {
+            // An empty `Push` frame would tell the peer that we are done
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        ready!(this.limit.poll_ready(cx));
+        ready!(this.poll_obtain_write_permission(cx))?;
+        let frame = this.push_frame_vectored(bufs, len);
+        this.frame_tx
+            .send_data(&mut this.schedule, frame)
+            .or(Err(BrokenPipe))?;
+        this.limit.consume(len);
+        this.counters.add_sent(len);
+        trace!("sent a frame of {} buffers", bufs.len());
+        Poll::Ready(Ok(len))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
     #[tracing::instrument(skip(_cx), level = "trace", fields(flow_id = self.flow_id))]
     #[inline]
     fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -125,11 +395,11 @@ impl AsyncWrite for MuxStream {
     #[tracing::instrument(skip(_cx), level = "trace", fields(flow_id = self.flow_id))]
     #[inline]
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Poll::Ready(self.shutdown_inner())
+        Poll::Ready(self.get_mut().shutdown_inner())
     }
 }
 
-impl AsyncBufRead for MuxStream {
+impl AsyncBufRead for OwnedReadHalf {
     /// Poll for another `Push` frame to fill the internal buffer.
     /// Returns a reference to the internal buffer on success.
     /// See [`AsyncBufRead::poll_fill_buf`].
@@ -137,6 +407,7 @@ impl AsyncBufRead for MuxStream {
     #[inline]
     fn poll_fill_buf(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
         if self.buf.is_empty() {
+            ready!(self.limit.poll_ready(cx));
             trace!("polling the stream");
             let Some(next) = ready!(self.frame_rx.poll_recv(cx)) else {
                 trace!("stream has been closed");
@@ -148,11 +419,15 @@ impl AsyncBufRead for MuxStream {
                 // However, this is not an inconsistent state so we should not
                 // panic a production setup.
                 debug_assert!(self.frame_rx.try_recv().is_err());
+                if let Some(error) = peer_reset_error(&self.reset_reason) {
+                    return Poll::Ready(Err(error));
+                }
                 return Poll::Ready(Ok(&[]));
             };
             // Putting no data into the buffer is EOF, and other code should
             // already ensure that such frames are filtered out.
             debug_assert!(!next.is_empty());
+            self.limit.consume(next.len());
             self.buf = next;
             self.increment_psh_recvd_since();
         } else {
@@ -169,7 +444,137 @@ impl AsyncBufRead for MuxStream {
     }
 }
 
+/// The error to return if the peer reset the stream with a reason
+#[inline]
+fn peer_reset_error(reset_reason: &AtomicU8) -> Option<io::Error> {
+    match ResetReason::from(reset_reason.load(Ordering::Relaxed)) {
+        ResetReason::Unspecified => None,
+        reason => Some(io::Error::new(reason.io_error_kind(), reason)),
+    }
+}
+
 impl MuxStream {
+    /// Enable or disable compression of data written to this stream.
+    /// This has no effect if the multiplexor is not configured with a
+    /// [`Compression`](crate::Compression) algorithm. Compression is enabled
+    /// by default if it is configured.
+    #[inline]
+    pub const fn set_compression(&mut self, enabled: bool) {
+        self.write.set_compression(enabled);
+    }
+
+    /// Set the priority of data written to this stream. Data of
+    /// higher-priority streams on the same multiplexor is sent first.
+    /// [`Priority::Normal`] is the default.
+    ///
+    /// Raising the priority takes effect once the data already written has
+    /// been sent, so that the data stays in order.
+    #[inline]
+    pub const fn set_priority(&mut self, priority: Priority) {
+        self.write.set_priority(priority);
+    }
+
+    /// Get the priority of data written to this stream.
+    #[inline]
+    #[must_use]
+    pub const fn priority(&self) -> Priority {
+        self.write.priority()
+    }
+
+    /// Get the flow ID of this stream, which identifies it in the logs of
+    /// both peers.
+    #[inline]
+    #[must_use]
+    pub const fn flow_id(&self) -> u32 {
+        self.flow_id
+    }
+
+    /// Abort the stream, telling the peer why. Data already written is
+    /// still delivered before the [`Reset`](crate::frame::OpCode::Reset)
+    /// frame. Dropping the stream without calling `poll_shutdown` is the same
+    /// as resetting it with [`ResetReason::Unspecified`].
+    #[inline]
+    pub fn reset(self, reason: ResetReason) {
+        // Stop the mux task from sending a `Reset` of its own when we are
+        // dropped. If the peer has already closed the stream, the peer ignores
+        // our `Reset`.
+        self.write.finish_sent.store(true, Ordering::Relaxed);
+        self.write
+            .frame_tx
+            .send_after_data(Frame::new_reset_with_reason(self.flow_id, reason).finalize())
+            .ok();
+        // Ignore the error if the mux task has exited
+    }
+
+    /// Split the stream into halves that can be used in separate tasks,
+    /// like [`TcpStream::into_split`](tokio::net::TcpStream::into_split) but
+    /// without any locking between them.
+    ///
+    /// The stream is reset once both halves are dropped, unless the write
+    /// half has been shut down and the peer has finished as well.
+    #[inline]
+    #[must_use]
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        (self.read, self.write)
+    }
+
+    /// A specialized version of [`tokio::io::copy_bidirectional`] that
+    /// works better on a `MuxStream` because of the lack of an extra copy.
+    ///
+    /// The returned future will resolve to `io::Result<(u64, u64)>` where
+    /// the first value is the number of bytes read from `self` and
+    /// the second value is the number of bytes written to `self`.
+    ///
+    /// # Errors
+    /// Returns the underlying error if any of the IO operations fail. When
+    /// this happens, some data from the other side might be lost.
+    ///
+    /// # Cancel Safety
+    /// This function is not cancel safe. Cancelling the future might cause
+    /// data loss.
+    #[inline]
+    pub fn into_copy_bidirectional<RW>(self, other: RW) -> CopyBidirectional<BufReader<RW>>
+    where
+        RW: AsyncRead + AsyncWrite + Unpin,
+    {
+        let other_bufreader = BufReader::new(other);
+        self.into_copy_bidirectional_with_buf(other_bufreader)
+    }
+
+    /// See [`into_copy_bidirectional`]. This version allows you to
+    /// provide your own read buffer for the other side.
+    #[inline]
+    pub const fn into_copy_bidirectional_with_buf<BRW>(self, other: BRW) -> CopyBidirectional<BRW>
+    where
+        BRW: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        CopyBidirectional {
+            us: self,
+            other,
+            read_state: ReadState::Transferring(0),
+            write_state: WriteState::Transferring(0),
+        }
+    }
+}
+
+impl OwnedReadHalf {
+    /// Put the halves back together into a [`MuxStream`].
+    ///
+    /// # Errors
+    /// Returns both halves if they are not from the same stream.
+    pub fn reunite(self, write: OwnedWriteHalf) -> Result<MuxStream, Box<ReuniteError>> {
+        if !Arc::ptr_eq(&self.port, &write.port) {
+            return Err(Box::new(ReuniteError(self, write)));
+        }
+        Ok(MuxStream {
+            flow_id: self.flow_id,
+            dest_host: self.port.dest_host.dupe(),
+            dest_port: self.port.dest_port,
+            read: self,
+            write,
+        })
+    }
+
     /// Increment the number of `Push` frames received since the last `Acknowledge`
     /// and send an `Acknowledge` frame if the threshold is reached.
     #[tracing::instrument(skip_all, level = "trace", fields(count = self.psh_recvd_since + 1))]
@@ -181,10 +586,11 @@ impl MuxStream {
         if new >= self.rwnd_threshold {
             // Reset the counter
             self.psh_recvd_since = 0;
-            // Send an `Acknowledge` frame
-            trace!("sending `Acknowledge` of {new} frames");
+            // Send an `Acknowledge` frame, resizing the window
+            let acknowledged = self.window.acknowledge(new);
+            trace!("sending `Acknowledge` of {acknowledged} frames for {new} frames read");
             self.frame_tx
-                .send(Frame::new_acknowledge(self.flow_id, new).finalize())
+                .send(Frame::new_acknowledge(self.flow_id, acknowledged).finalize())
                 .ok();
             // If the previous line fails, the task has exited.
             // In this case, we don't care about the `Acknowledge` frame and the
@@ -192,6 +598,53 @@ impl MuxStream {
             // to EOF.
         }
     }
+}
+
+impl OwnedWriteHalf {
+    /// See [`MuxStream::set_compression`].
+    #[inline]
+    pub const fn set_compression(&mut self, enabled: bool) {
+        self.compression.enabled = enabled;
+    }
+
+    /// See [`MuxStream::set_priority`].
+    #[inline]
+    pub const fn set_priority(&mut self, priority: Priority) {
+        self.schedule.set_priority(priority);
+    }
+
+    /// See [`MuxStream::priority`].
+    #[inline]
+    #[must_use]
+    pub const fn priority(&self) -> Priority {
+        self.schedule.priority()
+    }
+
+    /// Create a `Push` frame of `data`, compressed if it is worth it.
+    #[inline]
+    fn push_frame(&self, data: &[u8]) -> FinalizedFrame {
+        self.compression.compress(data).map_or_else(
+            || Frame::new_push(self.flow_id, data).finalize_in(&self.buffer_pool),
+            |compressed| {
+                Frame::new_compressed_push(self.flow_id, Bytes::from(compressed)).finalize()
+            },
+        )
+    }
+
+    /// Create a `Push` frame of `bufs`, which are `len` octets in total.
+    /// They are only concatenated if the frame might be compressed.
+    #[inline]
+    fn push_frame_vectored(&self, bufs: &[IoSlice<'_>], len: usize) -> FinalizedFrame {
+        if self.compression.wants(len) {
+            let mut data = Vec::with_capacity(len);
+            for buf in bufs {
+                data.extend_from_slice(buf);
+            }
+            self.push_frame(&data)
+        } else {
+            FinalizedFrame::new_push_vectored(self.flow_id, bufs, &self.buffer_pool)
+        }
+    }
 
     /// Attempt to obtain permission to send a [`Push`](crate::frame::OpCode::Push) frame.
     /// If we need an `Acknowledge` frame to continue, the task will be woken up
@@ -207,7 +660,9 @@ impl MuxStream {
         if self.finish_sent.load(Ordering::Relaxed) {
             // The stream has been closed. Return an error
             debug!("stream has been closed, returning `BrokenPipe`");
-            return Poll::Ready(Err(BrokenPipe.into()));
+            return Poll::Ready(Err(
+                peer_reset_error(&self.reset_reason).unwrap_or_else(|| BrokenPipe.into())
+            ));
         }
         loop {
             // Atomic ordering: we don't really have a critical section here,
@@ -241,7 +696,7 @@ impl MuxStream {
     /// Send a [`Finish`](crate::frame::OpCode::Finish) frame to the remote peer
     /// and disallow further writes.
     #[inline]
-    fn shutdown_inner(&self) -> io::Result<()> {
+    fn shutdown_inner(&mut self) -> io::Result<()> {
         // There is no need to send a `Finish` frame if the mux task has already removed the stream
         // because either:
         // 1. `MuxStream` was dropped before `poll_shutdown` is completed and the mux task should
@@ -252,48 +707,13 @@ impl MuxStream {
             return Ok(());
         }
         self.frame_tx
-            .send(Frame::new_finish(self.flow_id).finalize())
+            .send_data(
+                &mut self.schedule,
+                Frame::new_finish(self.flow_id).finalize(),
+            )
             .or(Err(BrokenPipe))?;
         Ok(())
     }
-
-    /// A specialized version of [`tokio::io::copy_bidirectional`] that
-    /// works better on a `MuxStream` because of the lack of an extra copy.
-    ///
-    /// The returned future will resolve to `io::Result<(u64, u64)>` where
-    /// the first value is the number of bytes read from `self` and
-    /// the second value is the number of bytes written to `self`.
-    ///
-    /// # Errors
-    /// Returns the underlying error if any of the IO operations fail. When
-    /// this happens, some data from the other side might be lost.
-    ///
-    /// # Cancel Safety
-    /// This function is not cancel safe. Cancelling the future might cause
-    /// data loss.
-    #[inline]
-    pub fn into_copy_bidirectional<RW>(self, other: RW) -> CopyBidirectional<BufReader<RW>>
-    where
-        RW: AsyncRead + AsyncWrite + Unpin,
-    {
-        let other_bufreader = BufReader::new(other);
-        self.into_copy_bidirectional_with_buf(other_bufreader)
-    }
-
-    /// See [`into_copy_bidirectional`]. This version allows you to
-    /// provide your own read buffer for the other side.
-    #[inline]
-    pub const fn into_copy_bidirectional_with_buf<BRW>(self, other: BRW) -> CopyBidirectional<BRW>
-    where
-        BRW: AsyncBufRead + AsyncWrite + Unpin,
-    {
-        CopyBidirectional {
-            us: self,
-            other,
-            read_state: ReadState::Transferring(0),
-            write_state: WriteState::Transferring(0),
-        }
-    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -333,7 +753,7 @@ where
                 // Loop until we are done or that some of the polls return `Pending`
                 loop {
                     trace!("polling us");
-                    let new_buf = ready!(Pin::new(&mut self.us).poll_fill_buf(cx))?;
+                    let new_buf = ready!(Pin::new(&mut self.us.read).poll_fill_buf(cx))?;
                     if new_buf.is_empty() {
                         // Our side EOF
                         self.read_state = ReadState::ShuttingDown(read_amt);
@@ -345,7 +765,7 @@ where
                     // We either still have data or we got some new data. Try to
                     // write it to the other side.
                     let processed = ready!(Pin::new(&mut self.other).poll_write(cx, new_buf))?;
-                    Pin::new(&mut self.us).consume(processed);
+                    Pin::new(&mut self.us.read).consume(processed);
                     read_amt += processed as u64;
                     self.read_state = ReadState::Transferring(read_amt);
                     // If this write finished it, the next `poll_fill` will fetch
@@ -384,14 +804,19 @@ where
                     };
                     if new_buf.is_empty() {
                         // The other side is EOF'd
-                        self.us.shutdown_inner()?;
+                        self.us.write.shutdown_inner()?;
                         self.write_state = WriteState::Done(written_amt);
                         break Poll::Ready(Ok(written_amt));
                     }
-                    ready!(self.us.poll_obtain_write_permission(cx))?;
-                    let frame = Frame::new_push(self.us.flow_id, new_buf).finalize();
-                    self.us.frame_tx.send(frame).or(Err(BrokenPipe))?;
+                    let us = &mut self.us.write;
+                    ready!(us.limit.poll_ready(cx));
+                    ready!(us.poll_obtain_write_permission(cx))?;
+                    let frame = us.push_frame(new_buf);
+                    us.frame_tx
+                        .send_data(&mut us.schedule, frame)
+                        .or(Err(BrokenPipe))?;
                     let processed = new_buf.len();
+                    us.limit.consume(processed);
                     Pin::new(&mut self.other).consume(processed);
                     written_amt += processed as u64;
                     self.write_state = WriteState::Transferring(written_amt);
@@ -442,22 +867,26 @@ mod tests {
 
     async fn test_mux_stream_read_inner() {
         let (rx_frame_tx, rx_frame_rx) = mpsc::channel(10);
-        let (tx_frame_tx, mut tx_frame_rx) = mpsc::unbounded_channel();
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
         let (dropped_ports_tx, _) = mpsc::unbounded_channel();
-        let stream = MuxStream {
+        let stream = MuxStream::new(StreamParts {
             frame_rx: rx_frame_rx,
             flow_id: 1,
             dest_host: Bytes::new(),
             dest_port: 8080,
             finish_sent: Arc::new(AtomicBool::new(false)),
             psh_send_remaining: Arc::new(AtomicU32::new(2)),
-            psh_recvd_since: 0,
             writer_waker: Arc::new(AtomicWaker::new()),
+            reset_reason: Arc::new(AtomicU8::new(0)),
+            counters: Arc::new(FlowCounters::default()),
             frame_tx: tx_frame_tx,
-            buf: Bytes::new(),
             dropped_ports_tx,
             rwnd_threshold: 2,
-        };
+            window: ReceiveWindow::default(),
+            compression: StreamCompression::default(),
+            limits: StreamLimits::default(),
+            buffer_pool: BufferPool::new(),
+        });
         let mut stream = pin!(stream);
         let mut buf = vec![0u8; 5];
         let mut read_buf = tokio::io::ReadBuf::new(&mut buf);
@@ -533,22 +962,26 @@ mod tests {
 
     async fn test_mux_stream_write_inner() {
         let (_, rx_frame_rx) = mpsc::channel(DEFAULT_RWND_THRESHOLD as usize);
-        let (tx_frame_tx, mut tx_frame_rx) = mpsc::unbounded_channel();
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
         let (dropped_ports_tx, _) = mpsc::unbounded_channel();
-        let stream = MuxStream {
+        let stream = MuxStream::new(StreamParts {
             frame_rx: rx_frame_rx,
             flow_id: 1,
             dest_host: Bytes::new(),
             dest_port: 8080,
             finish_sent: Arc::new(AtomicBool::new(false)),
             psh_send_remaining: Arc::new(AtomicU32::new(2)),
-            psh_recvd_since: 0,
             writer_waker: Arc::new(AtomicWaker::new()),
+            reset_reason: Arc::new(AtomicU8::new(0)),
+            counters: Arc::new(FlowCounters::default()),
             frame_tx: tx_frame_tx,
-            buf: Bytes::new(),
             dropped_ports_tx,
             rwnd_threshold: DEFAULT_RWND_THRESHOLD,
-        };
+            window: ReceiveWindow::default(),
+            compression: StreamCompression::default(),
+            limits: StreamLimits::default(),
+            buffer_pool: BufferPool::new(),
+        });
         let mut stream = pin!(stream);
         let waker = futures_util::task::noop_waker();
         {
@@ -587,7 +1020,10 @@ mod tests {
         }
 
         // Simulate `Acknowledge`
-        stream.psh_send_remaining.fetch_add(1, Ordering::Release);
+        stream
+            .write
+            .psh_send_remaining
+            .fetch_add(1, Ordering::Release);
 
         {
             let mut cx = Context::from_waker(&waker);
@@ -606,6 +1042,54 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    #[cfg(not(loom))]
+    async fn test_mux_stream_write_vectored() {
+        setup_logging();
+        let (_, rx_frame_rx) = mpsc::channel(DEFAULT_RWND_THRESHOLD as usize);
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
+        let (dropped_ports_tx, _) = mpsc::unbounded_channel();
+        let mut stream = MuxStream::new(StreamParts {
+            frame_rx: rx_frame_rx,
+            flow_id: 1,
+            dest_host: Bytes::new(),
+            dest_port: 8080,
+            finish_sent: Arc::new(AtomicBool::new(false)),
+            psh_send_remaining: Arc::new(AtomicU32::new(2)),
+            writer_waker: Arc::new(AtomicWaker::new()),
+            reset_reason: Arc::new(AtomicU8::new(0)),
+            counters: Arc::new(FlowCounters::default()),
+            frame_tx: tx_frame_tx,
+            dropped_ports_tx,
+            rwnd_threshold: DEFAULT_RWND_THRESHOLD,
+            window: ReceiveWindow::default(),
+            compression: StreamCompression::default(),
+            limits: StreamLimits::default(),
+            buffer_pool: BufferPool::new(),
+        });
+        assert!(stream.is_write_vectored());
+        let bufs = [
+            IoSlice::new(b"hello"),
+            IoSlice::new(b" "),
+            IoSlice::new(b"world"),
+        ];
+        assert_eq!(stream.write_vectored(&bufs).await.unwrap(), 11);
+        // Nothing to write does not end the stream
+        assert_eq!(
+            stream.write_vectored(&[IoSlice::new(b"")]).await.unwrap(),
+            0
+        );
+        drop(stream);
+        let frame = Frame::try_from(tx_frame_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(frame.id, 1);
+        if let crate::frame::Payload::Push(push) = frame.payload {
+            assert_eq!(push.as_ref(), b"hello world");
+        } else {
+            panic!("Expected a `Push` frame");
+        }
+        assert!(tx_frame_rx.recv().await.is_none());
+    }
+
     #[tokio::test]
     #[cfg(not(loom))]
     async fn test_copy_bidirectional_normal() {
@@ -616,24 +1100,28 @@ mod tests {
         const RX3: Bytes = Bytes::from_static(b"stout");
         setup_logging();
         let (rx_frame_tx, rx_frame_rx) = mpsc::channel(DEFAULT_RWND_THRESHOLD as usize);
-        let (tx_frame_tx, mut tx_frame_rx) = mpsc::unbounded_channel();
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
         let (dropped_ports_tx, _) = mpsc::unbounded_channel();
         let (other_stream, mut check_side) = tokio::io::duplex(1024);
 
-        let mux_stream = MuxStream {
+        let mux_stream = MuxStream::new(StreamParts {
             frame_rx: rx_frame_rx,
             flow_id: 1,
             dest_host: Bytes::new(),
             dest_port: 8080,
             finish_sent: Arc::new(AtomicBool::new(false)),
             psh_send_remaining: Arc::new(AtomicU32::new(10)), // Allow more frames for this test
-            psh_recvd_since: 0,
             writer_waker: Arc::new(AtomicWaker::new()),
+            reset_reason: Arc::new(AtomicU8::new(0)),
+            counters: Arc::new(FlowCounters::default()),
             frame_tx: tx_frame_tx.clone(),
-            buf: Bytes::new(),
             dropped_ports_tx: dropped_ports_tx.clone(),
             rwnd_threshold: DEFAULT_RWND_THRESHOLD,
-        };
+            window: ReceiveWindow::default(),
+            compression: StreamCompression::default(),
+            limits: StreamLimits::default(),
+            buffer_pool: BufferPool::new(),
+        });
 
         let copy_task = tokio::spawn(mux_stream.into_copy_bidirectional(other_stream));
 
@@ -717,23 +1205,27 @@ mod tests {
         assert_eq!(TEST_ACK_THRESHOLD, TEST_ACK_THRESHOLD_U32 as usize);
         setup_logging();
         let (rx_frame_tx, rx_frame_rx) = mpsc::channel(TEST_ACK_THRESHOLD);
-        let (tx_frame_tx, mut tx_frame_rx) = mpsc::unbounded_channel();
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
         let (dropped_ports_tx, _) = mpsc::unbounded_channel();
         let (other_stream, mut check_side) = tokio::io::duplex(1024);
-        let mut mux_stream = MuxStream {
+        let mut mux_stream = MuxStream::new(StreamParts {
             frame_rx: rx_frame_rx,
             flow_id: 1,
             dest_host: Bytes::new(),
             dest_port: 8080,
             finish_sent: Arc::new(AtomicBool::new(false)),
             psh_send_remaining: Arc::new(AtomicU32::new(10)), // Allow more frames for this test
-            psh_recvd_since: 0,
             writer_waker: Arc::new(AtomicWaker::new()),
+            reset_reason: Arc::new(AtomicU8::new(0)),
+            counters: Arc::new(FlowCounters::default()),
             frame_tx: tx_frame_tx.clone(),
-            buf: Bytes::new(),
             dropped_ports_tx: dropped_ports_tx.clone(),
             rwnd_threshold: TEST_ACK_THRESHOLD_U32,
-        };
+            window: ReceiveWindow::default(),
+            compression: StreamCompression::default(),
+            limits: StreamLimits::default(),
+            buffer_pool: BufferPool::new(),
+        });
         // First clog the congestion window
         for i in 0..TEST_ACK_THRESHOLD {
             debug!("sending frame {i}");
@@ -746,7 +1238,7 @@ mod tests {
         // The point is to confirm that the reader processed the frames
         // so if we get `Acknowledge` even before we started reading, then
         // it is pointless.
-        tx_frame_rx.try_recv().unwrap_err();
+        assert!(futures_util::FutureExt::now_or_never(tx_frame_rx.recv()).is_none());
         // First test with just `AsyncRead`
         let mut buf = [0u8; 5 * TEST_ACK_THRESHOLD];
         let n = mux_stream.read_exact(&mut buf).await.unwrap();
@@ -821,6 +1313,70 @@ mod tests {
         assert_eq!(&buf[..], b"hello".repeat(TEST_ACK_THRESHOLD).as_slice());
     }
 
+    #[tokio::test]
+    #[cfg(not(loom))]
+    async fn test_mux_stream_split() {
+        setup_logging();
+        let (rx_frame_tx, rx_frame_rx) = mpsc::channel(10);
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
+        let (dropped_ports_tx, mut dropped_ports_rx) = mpsc::unbounded_channel();
+        let new_stream = |flow_id, frame_rx| {
+            MuxStream::new(StreamParts {
+                frame_rx,
+                flow_id,
+                dest_host: Bytes::from_static(b"example.com"),
+                dest_port: 443,
+                finish_sent: Arc::new(AtomicBool::new(false)),
+                psh_send_remaining: Arc::new(AtomicU32::new(2)),
+                writer_waker: Arc::new(AtomicWaker::new()),
+                reset_reason: Arc::new(AtomicU8::new(0)),
+                counters: Arc::new(FlowCounters::default()),
+                frame_tx: tx_frame_tx.dupe(),
+                dropped_ports_tx: dropped_ports_tx.clone(),
+                rwnd_threshold: DEFAULT_RWND_THRESHOLD,
+                window: ReceiveWindow::default(),
+                compression: StreamCompression::default(),
+                limits: StreamLimits::default(),
+                buffer_pool: BufferPool::new(),
+            })
+        };
+        let (mut read, mut write) = new_stream(1, rx_frame_rx).into_split();
+        let reader = tokio::spawn(async move {
+            let mut buf = [0; 5];
+            read.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            read
+        });
+        rx_frame_tx
+            .send(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+        write.write_all(b"world").await.unwrap();
+        let frame = Frame::try_from(tx_frame_rx.recv().await.unwrap()).unwrap();
+        assert_eq!(frame.id, 1);
+        assert!(
+            matches!(frame.payload, crate::frame::Payload::Push(push) if push.as_ref() == b"world")
+        );
+        let read = reader.await.unwrap();
+
+        // Halves of different streams stay apart
+        let (other_read, other_write) = new_stream(2, mpsc::channel(1).1).into_split();
+        let ReuniteError(read, other_write) = *read.reunite(other_write).unwrap_err();
+        drop(other_read);
+        drop(other_write);
+        assert_eq!(dropped_ports_rx.recv().await.unwrap(), 2);
+
+        // The port is only freed once both halves are dropped
+        let stream = read.reunite(write).unwrap();
+        assert_eq!(stream.dest_host, "example.com");
+        assert_eq!(stream.dest_port, 443);
+        let (read, write) = stream.into_split();
+        drop(write);
+        assert!(dropped_ports_rx.try_recv().is_err());
+        drop(read);
+        assert_eq!(dropped_ports_rx.recv().await.unwrap(), 1);
+    }
+
     #[tokio::test]
     #[cfg(not(loom))]
     async fn test_mux_stream_shutdown() {
@@ -838,27 +1394,32 @@ mod tests {
     async fn test_mux_stream_shutdown_inner() {
         setup_logging();
         let (_, rx_frame_rx) = mpsc::channel(10);
-        let (tx_frame_tx, mut tx_frame_rx) = mpsc::unbounded_channel();
+        let (tx_frame_tx, mut tx_frame_rx) = crate::priority::channel();
         let (dropped_ports_tx, mut dropped_ports_rx) = mpsc::unbounded_channel();
-        let mut stream = MuxStream {
+        let mut stream = MuxStream::new(StreamParts {
             frame_rx: rx_frame_rx,
             flow_id: 15,
             dest_host: Bytes::new(),
             dest_port: 8080,
             finish_sent: Arc::new(AtomicBool::new(false)),
             psh_send_remaining: Arc::new(AtomicU32::new(2)),
-            psh_recvd_since: 0,
             writer_waker: Arc::new(AtomicWaker::new()),
+            reset_reason: Arc::new(AtomicU8::new(0)),
+            counters: Arc::new(FlowCounters::default()),
             frame_tx: tx_frame_tx,
-            buf: Bytes::new(),
             dropped_ports_tx,
             rwnd_threshold: 2,
-        };
-        let waker = futures_util::task::noop_waker();
-        let mut cx = Context::from_waker(&waker);
-
-        let rs = Pin::new(&mut stream).as_mut().poll_shutdown(&mut cx);
-        assert!(matches!(rs, Poll::Ready(Ok(()))));
+            window: ReceiveWindow::default(),
+            compression: StreamCompression::default(),
+            limits: StreamLimits::default(),
+            buffer_pool: BufferPool::new(),
+        });
+        {
+            let waker = futures_util::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            let rs = Pin::new(&mut stream).as_mut().poll_shutdown(&mut cx);
+            assert!(matches!(rs, Poll::Ready(Ok(()))));
+        }
         // Check the frame sent
         let frame = tx_frame_rx.recv().await.unwrap();
         assert_eq!(frame.opcode().unwrap(), crate::frame::OpCode::Finish);
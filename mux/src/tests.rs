@@ -133,6 +133,37 @@ async fn connect_succeeds() {
     server_task.await.unwrap();
 }
 
+#[tokio::test]
+#[cfg(not(loom))]
+async fn connect_over_byte_stream() {
+    setup_logging();
+    let (client, server) = tokio::io::duplex(2048);
+
+    let client_mux = Multiplexor::new(ws::PlainStream::new(client), None, None);
+    let server_mux = Multiplexor::new(ws::PlainStream::new(server), None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut stream = server_mux.accept_stream_channel().await.unwrap();
+        assert_eq!(stream.dest_port, 443);
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.flush().await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let mut stream = client_mux
+        .new_stream_channel(b"example.com", 443)
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"hello");
+    server_task.await.unwrap();
+}
+
 #[tokio::test]
 #[cfg(not(loom))]
 async fn datagram_channel_passes_data_tiny_mtu() {
@@ -713,7 +744,7 @@ async fn test_close_port_behaviour() {
     };
     let frame = frame::Frame::try_from(payload).unwrap();
     let Frame {
-        payload: frame::Payload::Reset,
+        payload: frame::Payload::Reset(_),
         id: flow_id,
     } = frame
     else {
@@ -853,6 +884,7 @@ async fn test_flow_id_contention_can_succeed() {
     assert_eq!(stream1.flow_id, server_flow_id2);
     assert_eq!(
         stream1
+            .write
             .psh_send_remaining
             .load(std::sync::atomic::Ordering::Relaxed),
         10
@@ -920,3 +952,415 @@ async fn test_bind_request() {
     request.reply(false).unwrap();
     server_task.await.unwrap();
 }
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_session_resume_keeps_streams() {
+    setup_logging();
+    let (client, server) = get_pair(Some(1024)).await;
+
+    let (client_mux, client_resumer) = Multiplexor::new_resumable(client, None, None);
+    let (server_mux, server_resumer) = Multiplexor::new_resumable(server, None, None);
+
+    let input_bytes: Vec<u8> = (0..1024 * 256).map(|_| rand::random::<u8>()).collect();
+    let len = input_bytes.len();
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.accept_stream_channel().await.unwrap();
+        let mut output_bytes = Vec::with_capacity(len);
+        conn.read_to_end(&mut output_bytes).await.unwrap();
+        conn.shutdown().await.unwrap();
+        output_bytes
+    });
+
+    let mut conn = client_mux.new_stream_channel(&[], 0).await.unwrap();
+    conn.write_all(&input_bytes[..len / 2]).await.unwrap();
+    let (client, server) = get_pair(Some(1024)).await;
+    // Dropping the old client `WebSocket` makes the server lose its end
+    client_resumer.resume(client).await.unwrap();
+    conn.write_all(&input_bytes[len / 2..len * 3 / 4])
+        .await
+        .unwrap();
+    server_resumer.resume(server).await.unwrap();
+    conn.write_all(&input_bytes[len * 3 / 4..]).await.unwrap();
+    conn.shutdown().await.unwrap();
+    let output_bytes = server_task.await.unwrap();
+    assert_eq!(input_bytes, output_bytes);
+    let mut buf = [0u8; 1];
+    assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_session_resume_timeout() {
+    setup_logging();
+    let (client, server) = get_pair(None).await;
+
+    let options =
+        crate::config::Options::new().resume_timeout(std::time::Duration::from_millis(100));
+    let mut joinset = JoinSet::new();
+    let (_client_mux, resumer) =
+        Multiplexor::new_resumable(client, Some(options), Some(&mut joinset));
+    drop(server);
+    resumer.detached().await;
+    let result = joinset.join_next().await.unwrap().unwrap();
+    assert!(matches!(result, Err(Error::ResumeTimeout)));
+    assert!(resumer.is_closed());
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_ping() {
+    setup_logging();
+    let (client, server) = tokio::io::duplex(2048);
+
+    let client_mux = Multiplexor::new(ws::PlainStream::new(client), None, None);
+    let server_mux = Multiplexor::new(ws::PlainStream::new(server), None, None);
+
+    assert_eq!(client_mux.rtt(), None);
+    let (first, second) = tokio::join!(client_mux.ping(), client_mux.ping());
+    assert!(first.unwrap() > std::time::Duration::ZERO);
+    assert!(second.unwrap() > std::time::Duration::ZERO);
+    assert!(client_mux.rtt().is_some());
+    assert_eq!(server_mux.rtt(), None);
+    server_mux.ping().await.unwrap();
+    assert!(server_mux.rtt().is_some());
+    drop(server_mux);
+    // Cannot be answered after the peer is gone
+    let result = tokio::time::timeout(std::time::Duration::from_secs(5), client_mux.ping()).await;
+    assert!(matches!(result, Ok(Err(Error::Closed))));
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_pong_timeout() {
+    setup_logging();
+    let options = crate::config::Options::new()
+        .keepalive_interval(std::time::Duration::from_millis(50).into())
+        .keepalive_timeout(std::time::Duration::from_millis(200).into());
+    // A peer that answers
+    let (client, server) = tokio::io::duplex(2048);
+    let mut joinset = JoinSet::new();
+    let _client_mux = Multiplexor::new(
+        ws::PlainStream::new(client),
+        Some(options),
+        Some(&mut joinset),
+    );
+    let _server_mux = Multiplexor::new(ws::PlainStream::new(server), None, None);
+    let result = tokio::time::timeout(std::time::Duration::from_millis(500), joinset.join_next());
+    assert!(result.await.is_err());
+    // A peer that never answers
+    let (client, mut server) = tokio::io::duplex(2048);
+    let mut joinset = JoinSet::new();
+    let _client_mux = Multiplexor::new(
+        ws::PlainStream::new(client),
+        Some(options),
+        Some(&mut joinset),
+    );
+    let sink =
+        tokio::spawn(async move { tokio::io::copy(&mut server, &mut tokio::io::sink()).await });
+    let result = joinset.join_next().await.unwrap().unwrap();
+    assert!(matches!(result, Err(Error::PongTimeout)));
+    // A resumable multiplexor waits for a new `WebSocket` instead
+    let (client, mut server) = tokio::io::duplex(2048);
+    let (_client_mux, resumer) = Multiplexor::new_resumable(
+        ws::PlainStream::new(client),
+        Some(options),
+        Some(&mut joinset),
+    );
+    let sink2 =
+        tokio::spawn(async move { tokio::io::copy(&mut server, &mut tokio::io::sink()).await });
+    resumer.detached().await;
+    assert!(!resumer.is_closed());
+    sink.abort();
+    sink2.abort();
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_compressed_stream_passes_data() {
+    setup_logging();
+    let Some(compression) = crate::Compression::SUPPORTED.first().copied() else {
+        return;
+    };
+    let (client, server) = get_pair(None).await;
+
+    let options = crate::config::Options::new()
+        .compression(compression)
+        .compression_threshold(64);
+    let client_mux = Multiplexor::new(client, Some(options), None);
+    let server_mux = Multiplexor::new(server, Some(options), None);
+
+    let input_bytes = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4096);
+    let input_bytes_clone = input_bytes.clone();
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.accept_stream_channel().await.unwrap();
+        let mut output_bytes = Vec::new();
+        conn.read_to_end(&mut output_bytes).await.unwrap();
+        // Reply uncompressed
+        conn.set_compression(false);
+        conn.write_all(&output_bytes).await.unwrap();
+        conn.shutdown().await.unwrap();
+        output_bytes
+    });
+
+    let mut conn = client_mux.new_stream_channel(&[], 0).await.unwrap();
+    for chunk in input_bytes_clone.chunks(4096) {
+        conn.write_all(chunk).await.unwrap();
+    }
+    conn.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    conn.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(server_task.await.unwrap(), input_bytes);
+    assert_eq!(echoed, input_bytes);
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_padded_stream_passes_data() {
+    setup_logging();
+    let (client, server) = get_pair(None).await;
+
+    let options = crate::config::Options::new().padding(true);
+    let client_mux = Multiplexor::new(client, Some(options), None);
+    let server_mux = Multiplexor::new(server, Some(options), None);
+
+    let input_bytes: Vec<u8> = (0..1024 * 256).map(|_| rand::random::<u8>()).collect();
+    let input_bytes_clone = input_bytes.clone();
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.accept_stream_channel().await.unwrap();
+        let mut output_bytes = Vec::new();
+        conn.read_to_end(&mut output_bytes).await.unwrap();
+        conn.write_all(&output_bytes).await.unwrap();
+        conn.shutdown().await.unwrap();
+        output_bytes
+    });
+
+    let mut conn = client_mux.new_stream_channel(&[], 0).await.unwrap();
+    conn.write_all(&input_bytes_clone).await.unwrap();
+    conn.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    conn.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(server_task.await.unwrap(), input_bytes);
+    assert_eq!(echoed, input_bytes);
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_coalesced_streams_pass_data() {
+    setup_logging();
+    let (client, server) = get_pair(None).await;
+
+    let options = crate::config::Options::new().coalesce(true).padding(true);
+    let client_mux = Multiplexor::new(client, Some(options), None);
+    let server_mux = Multiplexor::new(server, Some(options), None);
+
+    let server_task = tokio::spawn(async move {
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let mut conn = server_mux.accept_stream_channel().await.unwrap();
+            tasks.push(tokio::spawn(async move {
+                let mut output_bytes = Vec::new();
+                conn.read_to_end(&mut output_bytes).await.unwrap();
+                conn.write_all(&output_bytes).await.unwrap();
+                conn.shutdown().await.unwrap();
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+    });
+
+    let mut tasks = Vec::new();
+    for i in 0..8u8 {
+        let mut conn = client_mux.new_stream_channel(&[], 0).await.unwrap();
+        tasks.push(tokio::spawn(async move {
+            // Many small writes so that frames queue up together
+            let input_bytes: Vec<u8> = (0..=255).cycle().take(4096).map(|j| i ^ j).collect();
+            for chunk in input_bytes.chunks(16) {
+                conn.write_all(chunk).await.unwrap();
+            }
+            conn.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            conn.read_to_end(&mut echoed).await.unwrap();
+            assert_eq!(echoed, input_bytes);
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_stream_priorities_pass_data() {
+    setup_logging();
+    let (client, server) = get_pair(None).await;
+    let client_mux = Multiplexor::new(client, None, None);
+    let server_mux = Multiplexor::new(server, None, None);
+
+    let bulk_bytes = (0..256 * 1024).map(|_| rand::random()).collect::<Vec<u8>>();
+    let interactive_bytes = b"ls -l\n".repeat(64);
+
+    let server_task = tokio::spawn(async move {
+        let mut outputs = Vec::new();
+        for _ in 0..2 {
+            let mut conn = server_mux.accept_stream_channel().await.unwrap();
+            outputs.push(tokio::spawn(async move {
+                let mut output_bytes = Vec::new();
+                conn.read_to_end(&mut output_bytes).await.unwrap();
+                (conn.dest_port, output_bytes)
+            }));
+        }
+        let mut results = Vec::new();
+        for output in outputs {
+            results.push(output.await.unwrap());
+        }
+        results.sort();
+        results
+    });
+
+    let mut bulk = client_mux.new_stream_channel(&[], 1).await.unwrap();
+    bulk.set_priority(Priority::Low);
+    assert_eq!(bulk.priority(), Priority::Low);
+    let mut interactive = client_mux.new_stream_channel(&[], 2).await.unwrap();
+    interactive.set_priority(Priority::High);
+    let bulk_bytes_clone = bulk_bytes.clone();
+    let bulk_task = tokio::spawn(async move {
+        for chunk in bulk_bytes_clone.chunks(4096) {
+            bulk.write_all(chunk).await.unwrap();
+        }
+        // Dropped without `shutdown`: the `Reset` must not overtake the data
+    });
+    for chunk in interactive_bytes.chunks(6) {
+        interactive.write_all(chunk).await.unwrap();
+    }
+    interactive.shutdown().await.unwrap();
+    bulk_task.await.unwrap();
+    assert_eq!(
+        server_task.await.unwrap(),
+        [(1, bulk_bytes), (2, interactive_bytes)]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stream_limit_up() {
+    setup_logging();
+    let (client, server) = get_pair(None).await;
+    let options = crate::config::Options::new()
+        .stream_limit_up(crate::limit::Bandwidth::from_bytes_per_sec(64 * 1024));
+    let client_mux = Multiplexor::new(client, Some(options), None);
+    let server_mux = Multiplexor::new(server, None, None);
+
+    let input_bytes = (0..192 * 1024).map(|_| rand::random()).collect::<Vec<u8>>();
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.accept_stream_channel().await.unwrap();
+        let mut output_bytes = Vec::new();
+        conn.read_to_end(&mut output_bytes).await.unwrap();
+        output_bytes
+    });
+
+    let start = tokio::time::Instant::now();
+    let mut conn = client_mux.new_stream_channel(&[], 0).await.unwrap();
+    for chunk in input_bytes.chunks(4096) {
+        conn.write_all(chunk).await.unwrap();
+    }
+    conn.shutdown().await.unwrap();
+    assert_eq!(server_task.await.unwrap(), input_bytes);
+    // One second worth of data goes through at once, and the rest takes
+    // about two seconds
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed > std::time::Duration::from_millis(1500),
+        "{elapsed:?}"
+    );
+    assert!(
+        elapsed < std::time::Duration::from_millis(2500),
+        "{elapsed:?}"
+    );
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_reset_reason() {
+    setup_logging();
+    let (client, server) = get_pair(None).await;
+    let client_mux = Multiplexor::new(client, None, None);
+    let server_mux = Multiplexor::new(server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.accept_stream_channel().await.unwrap();
+        conn.write_all(b"go away").await.unwrap();
+        conn.reset(ResetReason::Forbidden);
+        // A plain drop gives no reason
+        let conn = server_mux.accept_stream_channel().await.unwrap();
+        drop(conn);
+        server_mux
+    });
+
+    let mut conn = client_mux.new_stream_channel(&[], 1).await.unwrap();
+    let mut buf = [0; 7];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"go away");
+    let error = conn.read(&mut buf).await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+    assert_eq!(error.to_string(), ResetReason::Forbidden.to_string());
+    let error = conn.write_all(b"hello").await.unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+
+    let mut conn = client_mux.new_stream_channel(&[], 2).await.unwrap();
+    assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+    let _server_mux = server_task.await.unwrap();
+}
+
+#[tokio::test]
+#[cfg(not(loom))]
+async fn test_stats() {
+    setup_logging();
+    let (client, server) = tokio::io::duplex(2048);
+    let client_mux = Multiplexor::new(ws::PlainStream::new(client), None, None);
+    let server_mux = Multiplexor::new(ws::PlainStream::new(server), None, None);
+    assert_eq!(client_mux.stats(), stats::Stats::default());
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.accept_stream_channel().await.unwrap();
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        conn.write_all(b"abc").await.unwrap();
+        let stats = server_mux.stats();
+        assert_eq!(stats.active_flows(), 1);
+        assert_eq!(stats.bytes_sent(), 3);
+        assert_eq!(stats.bytes_received(), 5);
+        (server_mux, conn)
+    });
+
+    let mut conn = client_mux
+        .new_stream_channel(b"example.com", 80)
+        .await
+        .unwrap();
+    conn.write_all(b"hello").await.unwrap();
+    let (_server_mux, _server_conn) = server_task.await.unwrap();
+    // Wait for the data we do not read to arrive
+    let stats = loop {
+        let stats = client_mux.stats();
+        if stats.bytes_received() == 3 {
+            break stats;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    };
+    assert_eq!(stats.pending_flows, 0);
+    assert_eq!(stats.queued_frames(), 0);
+    let flow = &stats.flows[0];
+    assert_eq!(flow.flow_id, conn.flow_id);
+    assert_eq!(flow.dest_host.as_ref(), b"example.com");
+    assert_eq!(flow.dest_port, 80);
+    assert_eq!(flow.bytes_sent, 5);
+    assert_eq!(flow.unread_frames, 1);
+    assert!(flow.send_window > 0);
+    let mut buf = [0; 3];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(client_mux.stats().flows[0].unread_frames, 0);
+    drop(conn);
+}
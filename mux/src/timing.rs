@@ -25,6 +25,8 @@ pub struct Backoff {
     current: Duration,
     /// Current retry count.
     count: u32,
+    /// Maximum percentage by which each duration is randomly shortened.
+    jitter: u32,
 }
 
 impl Backoff {
@@ -38,9 +40,18 @@ impl Backoff {
             max_count,
             current: initial,
             count: 0,
+            jitter: 0,
         }
     }
 
+    /// Randomly shorten each backoff duration by up to `percent`% so that
+    /// many clients do not retry at the same time.
+    #[must_use]
+    pub const fn with_jitter(mut self, percent: u32) -> Self {
+        self.jitter = if percent > 100 { 100 } else { percent };
+        self
+    }
+
     /// Advance to the next backoff duration and return the previous duration.
     pub fn advance(&mut self) -> Option<Duration> {
         if self.max_count != 0 && self.count >= self.max_count {
@@ -50,7 +61,11 @@ impl Backoff {
 
         let old = self.current.min(self.max);
         self.current = old * self.mult;
-        Some(old)
+        if self.jitter == 0 {
+            return Some(old);
+        }
+        let shortened = rand::random_range(0..=self.jitter);
+        Some(old * (100 - shortened) / 100)
     }
 
     /// Reset the backoff generator.
@@ -130,6 +145,12 @@ impl From<Duration> for OptionalDuration {
     }
 }
 
+impl From<OptionalDuration> for Option<Duration> {
+    fn from(duration: OptionalDuration) -> Self {
+        duration.0
+    }
+}
+
 /// An optional interval
 #[derive(Debug, Default)]
 pub struct OptionalInterval(Option<tokio::time::Interval>);
@@ -188,6 +209,13 @@ mod tests {
         assert_eq!(backoff.advance(), Some(Duration::from_secs(1)));
         assert_eq!(backoff.advance(), Some(Duration::from_secs(1)));
         assert_eq!(backoff.advance(), Some(Duration::from_secs(1)));
+        let mut backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(4), 2, 0).with_jitter(25);
+        for max in [1000, 2000, 4000, 4000] {
+            let current = backoff.advance().unwrap();
+            assert!(current <= Duration::from_millis(max));
+            assert!(current >= Duration::from_millis(max * 3 / 4));
+        }
     }
 
     #[test]
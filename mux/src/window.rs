@@ -0,0 +1,229 @@
+//! Adaptive receive windows of streams.
+//!
+//! A stream starts with a window of `rwnd` frames. Every time it sends an
+//! [`Acknowledge`](crate::frame::OpCode::Acknowledge), it compares how long
+//! the peer would take to send a whole window at the rate the frames were
+//! read with the round-trip time measured with keepalive pings. If a window
+//! is read in less than two round trips, the peer is probably waiting for our
+//! `Acknowledge`s, so the window is doubled by acknowledging more frames than
+//! were read. If it takes more than eight, the window is shrunk by
+//! acknowledging fewer.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::loom::{Arc, AtomicU32, Mutex, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Smoothed round-trip time of a multiplexor, measured with keepalive pings
+#[derive(Debug)]
+pub struct Rtt {
+    /// Smoothed RTT in microseconds, or 0 if not measured yet
+    srtt_us: AtomicU32,
+    /// When the `Ping` we are waiting a `Pong` for was sent
+    ping_sent: Mutex<Option<Instant>>,
+}
+
+impl Default for Rtt {
+    /// An RTT that has not been measured yet
+    fn default() -> Self {
+        Self {
+            srtt_us: AtomicU32::new(0),
+            ping_sent: Mutex::new(None),
+        }
+    }
+}
+
+impl Rtt {
+    /// Record that a `Ping` was sent, unless we are still waiting for the
+    /// `Pong` of a previous one.
+    pub fn ping_sent(&self) {
+        self.ping_sent.lock().get_or_insert_with(Instant::now);
+    }
+
+    /// Whether a `Ping` is waiting for its `Pong`
+    pub fn is_waiting(&self) -> bool {
+        self.ping_sent.lock().is_some()
+    }
+
+    /// When the `Ping` waiting for its `Pong` was sent
+    pub fn waiting_since(&self) -> Option<Instant> {
+        *self.ping_sent.lock()
+    }
+
+    /// Take a sample if we are waiting for a `Pong`.
+    /// Returns the sample.
+    pub fn pong_received(&self) -> Option<Duration> {
+        let sent = self.ping_sent.lock().take()?;
+        let sample = sent.elapsed();
+        self.update(sample);
+        Some(sample)
+    }
+
+    /// Stop waiting for a `Pong`, e.g. because the `WebSocket` was replaced.
+    pub fn forget_ping(&self) {
+        self.ping_sent.lock().take();
+    }
+
+    /// Add a sample to the smoothed RTT like TCP does (RFC 6298).
+    fn update(&self, sample: Duration) {
+        let sample = u32::try_from(sample.as_micros()).unwrap_or(u32::MAX).max(1);
+        let old = self.srtt_us.load(Ordering::Relaxed);
+        let new = if old == 0 {
+            sample
+        } else {
+            old - old / 8 + sample / 8
+        };
+        self.srtt_us.store(new, Ordering::Relaxed);
+    }
+
+    /// The smoothed RTT, if any `Pong` has been received
+    pub fn get(&self) -> Option<Duration> {
+        match self.srtt_us.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(u64::from(us))),
+        }
+    }
+}
+
+/// Receive window of a stream
+#[derive(Debug)]
+pub struct ReceiveWindow {
+    /// Number of frames the peer may send before our next `Acknowledge`s
+    size: u32,
+    /// What `size` starts at and never goes below
+    min: u32,
+    /// What `size` never exceeds
+    max: u32,
+    /// When we last sent an `Acknowledge`
+    last_ack: Instant,
+    /// Round-trip time of the multiplexor
+    rtt: Arc<Rtt>,
+}
+
+impl Default for ReceiveWindow {
+    /// A window that never changes
+    fn default() -> Self {
+        Self::new(0, 0, Arc::new(Rtt::default()))
+    }
+}
+
+impl ReceiveWindow {
+    /// Create a window of `rwnd` frames that can grow up to `max_rwnd`
+    pub fn new(rwnd: u32, max_rwnd: u32, rtt: Arc<Rtt>) -> Self {
+        Self {
+            size: rwnd,
+            min: rwnd,
+            max: max_rwnd.max(rwnd),
+            last_ack: Instant::now(),
+            rtt,
+        }
+    }
+
+    /// Current size of the window
+    pub const fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// How many frames to `Acknowledge` after reading `consumed` frames
+    /// since the previous `Acknowledge`, resizing the window according to
+    /// how fast they were read.
+    pub fn acknowledge(&mut self, consumed: u32) -> u32 {
+        let now = Instant::now();
+        let elapsed = now - self.last_ack;
+        self.last_ack = now;
+        let Some(rtt) = self.rtt.get() else {
+            return consumed;
+        };
+        if consumed == 0 || self.min == self.max {
+            return consumed;
+        }
+        // How long reading a whole window takes at this rate
+        let window_time = elapsed.saturating_mul(self.size) / consumed;
+        if window_time < rtt.saturating_mul(2) && self.size < self.max {
+            let grow = self.size.min(self.max - self.size);
+            self.size += grow;
+            consumed + grow
+        } else if window_time > rtt.saturating_mul(8) && self.size > self.min {
+            let shrink = (self.size - self.min).min(self.size / 2).min(consumed / 2);
+            self.size -= shrink;
+            consumed - shrink
+        } else {
+            consumed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Dupe;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rtt() {
+        crate::tests::setup_logging();
+        let rtt = Rtt::default();
+        assert_eq!(rtt.get(), None);
+        assert_eq!(rtt.pong_received(), None);
+        assert_eq!(rtt.get(), None);
+        rtt.ping_sent();
+        assert!(rtt.is_waiting());
+        let sent = rtt.waiting_since().unwrap();
+        tokio::time::advance(Duration::from_millis(80)).await;
+        // Another `Ping` before the `Pong` does not restart the measurement
+        rtt.ping_sent();
+        assert_eq!(rtt.waiting_since(), Some(sent));
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert_eq!(rtt.pong_received(), Some(Duration::from_millis(100)));
+        assert!(!rtt.is_waiting());
+        assert_eq!(rtt.get(), Some(Duration::from_millis(100)));
+        rtt.ping_sent();
+        tokio::time::advance(Duration::from_millis(180)).await;
+        rtt.pong_received();
+        assert_eq!(rtt.get(), Some(Duration::from_millis(110)));
+        rtt.ping_sent();
+        rtt.forget_ping();
+        tokio::time::advance(Duration::from_secs(10)).await;
+        rtt.pong_received();
+        assert_eq!(rtt.get(), Some(Duration::from_millis(110)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receive_window() {
+        crate::tests::setup_logging();
+        let rtt = Arc::new(Rtt::default());
+        // Without an RTT, the window stays the same
+        let mut window = ReceiveWindow::new(16, 64, rtt.dupe());
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_eq!(window.acknowledge(8), 8);
+        rtt.ping_sent();
+        tokio::time::advance(Duration::from_millis(100)).await;
+        rtt.pong_received();
+        let mut window = ReceiveWindow::new(16, 64, rtt.dupe());
+        // 8 frames in 10 ms is a window in 20 ms: grow
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_eq!(window.acknowledge(8), 24);
+        assert_eq!(window.size(), 32);
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_eq!(window.acknowledge(8), 40);
+        assert_eq!(window.size(), 64);
+        // Capped at the maximum
+        tokio::time::advance(Duration::from_millis(10)).await;
+        assert_eq!(window.acknowledge(8), 8);
+        assert_eq!(window.size(), 64);
+        // 8 frames in 200 ms is a window in 1.6 s: shrink
+        tokio::time::advance(Duration::from_millis(200)).await;
+        assert_eq!(window.acknowledge(8), 4);
+        assert_eq!(window.size(), 60);
+        // Never below the initial size
+        for _ in 0..20 {
+            tokio::time::advance(Duration::from_secs(1)).await;
+            window.acknowledge(8);
+        }
+        assert_eq!(window.size(), 16);
+        // A fixed window never changes
+        let mut fixed = ReceiveWindow::new(16, 16, rtt);
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert_eq!(fixed.acknowledge(8), 8);
+    }
+}
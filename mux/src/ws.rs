@@ -0,0 +1,636 @@
+//! Generic WebSocket and the transports the multiplexor runs over
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{Sink, Stream};
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Types of messages we need
+#[derive(Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Binary message or any payload
+    Binary(Bytes),
+    /// Ping message. Note that the payload is discarded.
+    Ping,
+    /// Pong message. Note that the payload is discarded.
+    Pong,
+    /// Close message. Note that the payload is discarded.
+    Close,
+}
+
+impl std::fmt::Debug for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Binary(data) => f.debug_struct("Binary").field("len", &data.len()).finish(),
+            Self::Ping => f.debug_struct("Ping").finish(),
+            Self::Pong => f.debug_struct("Pong").finish(),
+            Self::Close => f.debug_struct("Close").finish(),
+        }
+    }
+}
+
+/// A generic WebSocket stream
+///
+/// This is what [`Multiplexor`](crate::Multiplexor) runs over. Besides
+/// `tokio_tungstenite::WebSocketStream`, it is implemented by [`PlainStream`]
+/// for byte streams and by [`Framed`] for transports that already carry
+/// whole messages.
+///
+/// Specialized for our [`Message`] type similar to [`futures_util::Stream`] and [`futures_util::Sink`].
+/// See [`futures_util::Stream`] and [`futures_util::Sink`] for more details on the required methods.
+pub trait WebSocket: Send + 'static {
+    /// Attempt to prepare the `Sink` to receive a value.
+    ///
+    /// # Errors
+    /// Indicates the underlying sink is permanently be unable to receive items.
+    fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>>;
+    /// Begin the process of sending a value to the sink.
+    ///
+    /// # Errors
+    /// Indicates the underlying sink is permanently be unable to receive items.
+    fn start_send_unpin(&mut self, item: Message) -> Result<(), crate::Error>;
+    /// Flush any remaining output from this sink.
+    ///
+    /// # Errors
+    /// Indicates the underlying sink is permanently be unable to receive items.
+    fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>>;
+    /// Flush any remaining output and close this sink, if necessary.
+    ///
+    /// # Errors
+    /// Indicates the underlying sink is unable to be closed properly but is nonetheless
+    /// permanently be unable to receive items.
+    fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>>;
+    /// Attempt to pull out the next value of this stream.
+    ///
+    /// # Errors
+    /// Indicates the underlying stream is otherwise unable to produce items.
+    fn poll_next_unpin(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message, crate::Error>>>;
+}
+
+impl std::fmt::Debug for dyn WebSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("dyn WebSocket")
+    }
+}
+
+impl<T: WebSocket + ?Sized> WebSocket for Box<T> {
+    #[inline]
+    fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        (**self).poll_ready_unpin(cx)
+    }
+
+    #[inline]
+    fn start_send_unpin(&mut self, item: Message) -> Result<(), crate::Error> {
+        (**self).start_send_unpin(item)
+    }
+
+    #[inline]
+    fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        (**self).poll_flush_unpin(cx)
+    }
+
+    #[inline]
+    fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        (**self).poll_close_unpin(cx)
+    }
+
+    #[inline]
+    fn poll_next_unpin(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message, crate::Error>>> {
+        (**self).poll_next_unpin(cx)
+    }
+}
+
+/// Largest message a [`PlainStream`] accepts
+const PLAIN_MAX_MESSAGE_SIZE: usize = 16 << 20;
+/// How much output a [`PlainStream`] buffers before writing it out
+const PLAIN_WRITE_BUFFER_SIZE: usize = 64 << 10;
+/// How much a [`PlainStream`] reads at a time
+const PLAIN_READ_SIZE: usize = 16 << 10;
+/// Type octet and length
+const PLAIN_HEADER_LEN: usize = 5;
+
+/// A [`WebSocket`] over a plain byte stream, such as TCP, TLS without the
+/// WebSocket layer, a Unix domain socket or a QUIC stream.
+///
+/// Each message is a type octet (0 for binary, 1 for ping, 2 for pong and 3
+/// for close), the length of the payload as a big-endian `u32`, and the
+/// payload. Only binary messages have a payload.
+///
+/// Like a WebSocket implementation, it answers received pings with pongs.
+#[derive(Debug)]
+pub struct PlainStream<RW> {
+    inner: RW,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    /// Whether a pong is waiting in `write_buf` to be flushed
+    pong_pending: bool,
+}
+
+impl<RW> PlainStream<RW> {
+    /// Wrap a byte stream
+    #[must_use]
+    pub fn new(inner: RW) -> Self {
+        Self {
+            inner,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            pong_pending: false,
+        }
+    }
+
+    /// Get the byte stream back, dropping any buffered data
+    pub fn into_inner(self) -> RW {
+        self.inner
+    }
+}
+
+/// An error on a [`PlainStream`]
+fn plain_error(kind: std::io::ErrorKind, msg: &'static str) -> crate::Error {
+    crate::Error::WebSocket(Box::new(std::io::Error::new(kind, msg)))
+}
+
+/// Take a complete message out of `buf`, if there is one
+fn plain_decode(buf: &mut BytesMut) -> Result<Option<Message>, crate::Error> {
+    if buf.len() < PLAIN_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+    let len = usize::try_from(len).unwrap_or(usize::MAX);
+    if len > PLAIN_MAX_MESSAGE_SIZE {
+        return Err(plain_error(
+            std::io::ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+    if buf.len() < PLAIN_HEADER_LEN + len {
+        buf.reserve(PLAIN_HEADER_LEN + len - buf.len());
+        return Ok(None);
+    }
+    let kind = buf[0];
+    buf.advance(PLAIN_HEADER_LEN);
+    let payload = buf.split_to(len).freeze();
+    match kind {
+        0 => Ok(Some(Message::Binary(payload))),
+        1 => Ok(Some(Message::Ping)),
+        2 => Ok(Some(Message::Pong)),
+        3 => Ok(Some(Message::Close)),
+        _ => Err(plain_error(
+            std::io::ErrorKind::InvalidData,
+            "unknown message type",
+        )),
+    }
+}
+
+impl<RW> PlainStream<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Write out all buffered output
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))
+                .map_err(|err| crate::Error::WebSocket(Box::new(err)))?;
+            if written == 0 {
+                return Poll::Ready(Err(plain_error(
+                    std::io::ErrorKind::WriteZero,
+                    "connection closed",
+                )));
+            }
+            self.write_buf.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<RW> WebSocket for PlainStream<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        if self.write_buf.len() >= PLAIN_WRITE_BUFFER_SIZE {
+            ready!(self.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send_unpin(&mut self, item: Message) -> Result<(), crate::Error> {
+        let (kind, payload) = match item {
+            Message::Binary(data) => (0, data),
+            Message::Ping => (1, Bytes::new()),
+            Message::Pong => (2, Bytes::new()),
+            Message::Close => (3, Bytes::new()),
+        };
+        let len = u32::try_from(payload.len())
+            .ok()
+            .filter(|_| payload.len() <= PLAIN_MAX_MESSAGE_SIZE)
+            .ok_or_else(|| plain_error(std::io::ErrorKind::InvalidInput, "message too large"))?;
+        self.write_buf.reserve(PLAIN_HEADER_LEN + payload.len());
+        self.write_buf.put_u8(kind);
+        self.write_buf.put_u32(len);
+        self.write_buf.put(payload);
+        Ok(())
+    }
+
+    fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(|err| crate::Error::WebSocket(Box::new(err)))
+    }
+
+    fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        ready!(self.poll_flush_unpin(cx))?;
+        Pin::new(&mut self.inner)
+            .poll_shutdown(cx)
+            .map_err(|err| crate::Error::WebSocket(Box::new(err)))
+    }
+
+    fn poll_next_unpin(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message, crate::Error>>> {
+        // Errors are left to the next write to report
+        if self.pong_pending && self.poll_flush_unpin(cx).is_ready() {
+            self.pong_pending = false;
+        }
+        loop {
+            match plain_decode(&mut self.read_buf) {
+                Ok(Some(Message::Ping)) => {
+                    if let Err(err) = self.start_send_unpin(Message::Pong) {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    self.pong_pending = self.poll_flush_unpin(cx).is_pending();
+                    return Poll::Ready(Some(Ok(Message::Ping)));
+                }
+                Ok(Some(msg)) => return Poll::Ready(Some(Ok(msg))),
+                Ok(None) => {}
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+            let old_len = self.read_buf.len();
+            self.read_buf.resize(old_len + PLAIN_READ_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut self.read_buf[old_len..]);
+            let result = Pin::new(&mut self.inner).poll_read(cx, &mut read_buf);
+            let read = read_buf.filled().len();
+            self.read_buf.truncate(old_len + read);
+            match result {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(err)) => {
+                    return Poll::Ready(Some(Err(crate::Error::WebSocket(Box::new(err)))));
+                }
+                Poll::Ready(Ok(())) if read == 0 && old_len == 0 => return Poll::Ready(None),
+                Poll::Ready(Ok(())) if read == 0 => {
+                    return Poll::Ready(Some(Err(plain_error(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "connection closed in the middle of a message",
+                    ))));
+                }
+                Poll::Ready(Ok(())) => {}
+            }
+        }
+    }
+}
+
+/// A [`WebSocket`] over a transport that already sends and receives whole
+/// [`Message`]s, for example a byte stream with a codec of its own.
+#[derive(Debug)]
+pub struct Framed<T> {
+    inner: T,
+}
+
+impl<T> Framed<T> {
+    /// Wrap a message transport
+    #[must_use]
+    pub const fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    /// Get the message transport back
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, E> WebSocket for Framed<T>
+where
+    T: Stream<Item = Result<Message, E>> + Sink<Message, Error = E> + Unpin + Send + 'static,
+    E: std::error::Error + Send + 'static,
+{
+    #[inline]
+    fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        Pin::new(&mut self.inner)
+            .poll_ready(cx)
+            .map_err(|err| crate::Error::WebSocket(Box::new(err)))
+    }
+
+    #[inline]
+    fn start_send_unpin(&mut self, item: Message) -> Result<(), crate::Error> {
+        Pin::new(&mut self.inner)
+            .start_send(item)
+            .map_err(|err| crate::Error::WebSocket(Box::new(err)))
+    }
+
+    #[inline]
+    fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(|err| crate::Error::WebSocket(Box::new(err)))
+    }
+
+    #[inline]
+    fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(|err| crate::Error::WebSocket(Box::new(err)))
+    }
+
+    #[inline]
+    fn poll_next_unpin(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message, crate::Error>>> {
+        Pin::new(&mut self.inner)
+            .poll_next(cx)
+            .map(|next| next.map(|res| res.map_err(|err| crate::Error::WebSocket(Box::new(err)))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::future::poll_fn;
+
+    async fn send<S: WebSocket>(ws: &mut S, msg: Message) {
+        poll_fn(|cx| ws.poll_ready_unpin(cx)).await.unwrap();
+        ws.start_send_unpin(msg).unwrap();
+        poll_fn(|cx| ws.poll_flush_unpin(cx)).await.unwrap();
+    }
+
+    async fn recv<S: WebSocket>(ws: &mut S) -> Option<Message> {
+        poll_fn(|cx| ws.poll_next_unpin(cx))
+            .await
+            .map(|res| res.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_plain_stream() {
+        crate::tests::setup_logging();
+        // A small buffer so that messages arrive in pieces
+        let (client, server) = tokio::io::duplex(7);
+        let mut client = PlainStream::new(client);
+        let mut server: Box<dyn WebSocket> = Box::new(PlainStream::new(server));
+        let big = Bytes::from(vec![42; 100_000]);
+        let sender = tokio::spawn(async move {
+            send(&mut client, Message::Binary(Bytes::from_static(b"hello"))).await;
+            send(&mut client, Message::Ping).await;
+            send(&mut client, Message::Binary(Bytes::new())).await;
+            send(&mut client, Message::Binary(big)).await;
+            send(&mut client, Message::Close).await;
+            poll_fn(|cx| client.poll_close_unpin(cx)).await.unwrap();
+            client
+        });
+        assert_eq!(
+            recv(&mut server).await,
+            Some(Message::Binary(Bytes::from_static(b"hello")))
+        );
+        assert_eq!(recv(&mut server).await, Some(Message::Ping));
+        assert_eq!(recv(&mut server).await, Some(Message::Binary(Bytes::new())));
+        assert_eq!(
+            recv(&mut server).await,
+            Some(Message::Binary(Bytes::from(vec![42; 100_000])))
+        );
+        assert_eq!(recv(&mut server).await, Some(Message::Close));
+        assert_eq!(recv(&mut server).await, None);
+        // The `Ping` was answered
+        let mut client = sender.await.unwrap();
+        assert_eq!(recv(&mut client).await, Some(Message::Pong));
+    }
+
+    /// Receives what it sends
+    #[derive(Debug, Default)]
+    struct Loopback(VecDeque<Message>);
+
+    impl Stream for Loopback {
+        type Item = Result<Message, std::io::Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.0.pop_front().map(Ok))
+        }
+    }
+
+    impl Sink<Message> for Loopback {
+        type Error = std::io::Error;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+            if item == Message::Close {
+                return Err(std::io::Error::other("closed"));
+            }
+            self.0.push_back(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_framed() {
+        crate::tests::setup_logging();
+        let mut ws = Framed::new(Loopback::default());
+        send(&mut ws, Message::Binary(Bytes::from_static(b"hello"))).await;
+        send(&mut ws, Message::Pong).await;
+        assert_eq!(
+            recv(&mut ws).await,
+            Some(Message::Binary(Bytes::from_static(b"hello")))
+        );
+        assert_eq!(recv(&mut ws).await, Some(Message::Pong));
+        assert_eq!(recv(&mut ws).await, None);
+        assert!(matches!(
+            ws.start_send_unpin(Message::Close),
+            Err(crate::Error::WebSocket(_))
+        ));
+        assert!(ws.into_inner().0.is_empty());
+    }
+
+    #[test]
+    fn test_plain_decode_errors() {
+        crate::tests::setup_logging();
+        let mut buf = BytesMut::from(&[0, 0, 0, 0][..]);
+        assert!(plain_decode(&mut buf).unwrap().is_none());
+        let mut buf = BytesMut::from(&[9, 0, 0, 0, 0][..]);
+        assert!(plain_decode(&mut buf).is_err());
+        let mut buf = BytesMut::from(&[0, 0xff, 0xff, 0xff, 0xff][..]);
+        assert!(plain_decode(&mut buf).is_err());
+    }
+}
+
+#[cfg(feature = "tungstenite")]
+mod tokio_tungstenite {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use bytes::Bytes;
+    use futures_util::{Sink, Stream};
+    use tokio_tungstenite::tungstenite;
+    use tracing::error;
+
+    use super::{Message, WebSocket};
+    impl From<tungstenite::Message> for Message {
+        #[inline]
+        fn from(msg: tungstenite::Message) -> Self {
+            match msg {
+                tungstenite::Message::Binary(data) => Self::Binary(data),
+                tungstenite::Message::Text(text) => {
+                    error!("Received text message: {text}");
+                    Self::Binary(Bytes::from(text))
+                }
+                tungstenite::Message::Ping(_) => Self::Ping,
+                tungstenite::Message::Pong(_) => Self::Pong,
+                tungstenite::Message::Close(_) => Self::Close,
+                tungstenite::Message::Frame(_) => {
+                    unreachable!("`Frame` message should not be received")
+                }
+            }
+        }
+    }
+
+    impl From<Message> for tungstenite::Message {
+        #[inline]
+        fn from(msg: Message) -> Self {
+            match msg {
+                Message::Binary(data) => Self::Binary(data),
+                Message::Ping => Self::Ping(Bytes::new()),
+                Message::Pong => Self::Pong(Bytes::new()),
+                Message::Close => Self::Close(None),
+            }
+        }
+    }
+
+    impl From<tungstenite::Error> for crate::Error {
+        #[inline]
+        fn from(e: tungstenite::Error) -> Self {
+            Self::WebSocket(Box::new(e))
+        }
+    }
+
+    impl<RW> WebSocket for tokio_tungstenite::WebSocketStream<RW>
+    where
+        RW: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+    {
+        #[inline]
+        fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+            Pin::new(self).poll_ready(cx).map_err(Into::into)
+        }
+
+        #[inline]
+        fn start_send_unpin(&mut self, item: Message) -> Result<(), crate::Error> {
+            let item: tungstenite::Message = item.into();
+            Pin::new(self).start_send(item).map_err(Into::into)
+        }
+
+        #[inline]
+        fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+            Pin::new(self).poll_flush(cx).map_err(Into::into)
+        }
+
+        #[inline]
+        fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), crate::Error>> {
+            let this = Pin::new(self);
+            futures_util::Sink::poll_close(this, cx).map_err(Into::into)
+        }
+
+        #[inline]
+        fn poll_next_unpin(
+            &mut self,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Message, crate::Error>>> {
+            Pin::new(self)
+                .poll_next(cx)
+                .map(|opt| opt.map(|res| res.map(Into::into).map_err(Into::into)))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+        #[test]
+        fn test_binary_message() {
+            let msg = tungstenite::Message::Binary(Bytes::from_static(b"Hello"));
+            let converted: Message = msg.clone().into();
+            assert_eq!(converted, Message::Binary(Bytes::from_static(b"Hello")));
+            assert_eq!(tungstenite::Message::from(converted), msg);
+        }
+
+        #[test]
+        fn test_text_message() {
+            let msg = tungstenite::Message::Text("Hello".into());
+            let converted: Message = msg.into();
+            assert_eq!(converted, Message::Binary(Bytes::from_static(b"Hello")));
+            assert_eq!(
+                tungstenite::Message::from(converted),
+                tungstenite::Message::Binary(Bytes::from_static(b"Hello"))
+            );
+        }
+
+        #[test]
+        fn test_ping_message() {
+            let msg = tungstenite::Message::Ping(Bytes::from_static(b"Ping"));
+            let converted: Message = msg.into();
+            assert_eq!(converted, Message::Ping);
+            assert_eq!(
+                tungstenite::Message::from(converted),
+                tungstenite::Message::Ping(Bytes::new())
+            );
+
+            let msg = tungstenite::Message::Pong(Bytes::from_static(b"Pong"));
+            let converted: Message = msg.into();
+            assert_eq!(converted, Message::Pong);
+            assert_eq!(
+                tungstenite::Message::from(converted),
+                tungstenite::Message::Pong(Bytes::new())
+            );
+        }
+
+        #[test]
+        fn test_close_message() {
+            let close_msg =
+                tungstenite::Message::Close(Some(tungstenite::protocol::frame::CloseFrame {
+                    code: CloseCode::Reserved(1000),
+                    reason: "Normal".into(),
+                }));
+            let converted: Message = close_msg.into();
+            assert_eq!(converted, Message::Close);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Allow and deny rules for forwarding destinations.
+//!
+//! A rule is `HOST[:PORTS]`, where `HOST` is one of
+//! - `*`, matching any host,
+//! - an IP address or a CIDR block, e.g. `10.0.0.0/8` or `[fd00::/8]`,
+//! - a hostname, e.g. `example.com`,
+//! - a domain wildcard, e.g. `*.example.com`, matching its subdomains,
+//!
+//! and `PORTS` is a port, a range such as `8000-8999`, or `*` (the default).
+//! IPv6 addresses must be in brackets when `PORTS` is given.
+//!
+//! Unix sockets are only reachable if their paths are listed separately.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::parse_remote::remove_brackets;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Error returned when parsing an invalid rule
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Invalid rule: {0}")]
+pub struct InvalidRule(String);
+
+/// A CIDR block, e.g. `10.0.0.0/8` or `[fd00::/8]`.
+/// A single address is a block of that address only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    /// Whether `ip` is in this block. IPv4-mapped addresses match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidRule(s.to_string());
+        let (addr, prefix) = match remove_brackets(s).split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (remove_brackets(s), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.addr {
+            IpAddr::V4(addr) => write!(f, "{addr}/{}", self.prefix),
+            IpAddr::V6(addr) => write!(f, "[{addr}/{}]", self.prefix),
+        }
+    }
+}
+
+/// Which hosts a rule matches
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Network(IpNetwork),
+    /// A lowercase hostname without the trailing dot
+    Name(String),
+    /// Subdomains of a lowercase domain, stored with the leading dot
+    Subdomains(String),
+}
+
+impl HostPattern {
+    fn parse(s: &str) -> Option<Self> {
+        if s == "*" {
+            return Some(Self::Any);
+        }
+        let s = remove_brackets(s);
+        if s.contains('/') || s.parse::<IpAddr>().is_ok() {
+            return s.parse().ok().map(Self::Network);
+        }
+        let name = normalize_name(s);
+        if let Some(domain) = name.strip_prefix("*.") {
+            let valid = !domain.is_empty() && !domain.contains('*');
+            return valid.then(|| Self::Subdomains(format!(".{domain}")));
+        }
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+        valid.then_some(Self::Name(name))
+    }
+
+    /// Check a hostname as given by the client
+    fn matches_name(&self, name: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Network(_) => false,
+            Self::Name(pattern) => *pattern == name,
+            Self::Subdomains(suffix) => name.ends_with(suffix.as_str()),
+        }
+    }
+
+    /// Check an address the destination resolves to
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Network(network) => network.contains(ip),
+            Self::Name(_) | Self::Subdomains(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => f.write_str("*"),
+            Self::Network(network) => network.fmt(f),
+            Self::Name(name) => f.write_str(name),
+            Self::Subdomains(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+/// Lowercase a hostname and remove its trailing dot
+fn normalize_name(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+/// An allow or deny rule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    host: HostPattern,
+    /// Inclusive range of ports
+    ports: (u16, u16),
+}
+
+impl Rule {
+    fn matches(&self, name: &str, ip: Option<IpAddr>, port: u16) -> bool {
+        (self.ports.0..=self.ports.1).contains(&port)
+            && (self.host.matches_name(name) || ip.is_some_and(|ip| self.host.matches_ip(ip)))
+    }
+
+    /// Check the destination `host` without resolving it, like
+    /// [`Acl::allows_unresolved`]
+    pub fn matches_unresolved(&self, host: &str, port: u16) -> bool {
+        let host = remove_brackets(host);
+        self.matches(&normalize_name(host), host.parse().ok(), port)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = InvalidRule;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidRule(s.to_string());
+        // Only split off the ports if the colon cannot be part of an IPv6 address
+        let (host, ports) = match s.rsplit_once(':') {
+            Some((host, ports))
+                if !host.contains(':') || (host.starts_with('[') && host.ends_with(']')) =>
+            {
+                (host, Some(ports))
+            }
+            _ => (s, None),
+        };
+        let ports = match ports {
+            None | Some("*") => (0, u16::MAX),
+            Some(ports) => {
+                let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+                let start = start.parse().map_err(|_| invalid())?;
+                let end = end.parse().map_err(|_| invalid())?;
+                if start > end {
+                    return Err(invalid());
+                }
+                (start, end)
+            }
+        };
+        let host = HostPattern::parse(host).ok_or_else(invalid)?;
+        Ok(Self { host, ports })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ports {
+            (0, u16::MAX) => write!(f, "{}", self.host),
+            (start, end) if start == end => write!(f, "{}:{start}", self.host),
+            (start, end) => write!(f, "{}:{start}-{end}", self.host),
+        }
+    }
+}
+
+/// A set of allow and deny rules.
+/// Deny rules take precedence, and if there are allow rules, a destination
+/// must match one of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+    /// Paths of the Unix sockets that may be connected to
+    unix_sockets: Vec<String>,
+}
+
+impl Acl {
+    pub const fn new(allow: Vec<Rule>, deny: Vec<Rule>) -> Self {
+        Self {
+            allow,
+            deny,
+            unix_sockets: Vec::new(),
+        }
+    }
+
+    /// Also allow connecting to the Unix sockets at `paths`
+    #[must_use]
+    pub fn with_unix_sockets(mut self, paths: Vec<String>) -> Self {
+        self.unix_sockets = paths;
+        self
+    }
+
+    /// Check a Unix socket destination, which must be listed exactly
+    pub fn allows_unix(&self, path: &str) -> bool {
+        self.unix_sockets.iter().any(|allowed| allowed == path)
+    }
+
+    /// Whether every destination is allowed
+    pub const fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check the destination `host` after it resolved to `addr`.
+    /// Both the name and the address are matched against the rules, so a
+    /// name cannot be used to reach a denied address and vice versa.
+    pub fn allows(&self, host: &str, addr: SocketAddr) -> bool {
+        self.check(host, Some(addr.ip()), addr.port())
+    }
+
+    /// Check the destination `host` without resolving it.
+    /// If `host` is not an IP address, only hostname rules can match it, so
+    /// CIDR rules deny nothing and allow nothing.
+    pub fn allows_unresolved(&self, host: &str, port: u16) -> bool {
+        let ip = remove_brackets(host).parse().ok();
+        self.check(host, ip, port)
+    }
+
+    fn check(&self, host: &str, ip: Option<IpAddr>, port: u16) -> bool {
+        let name = normalize_name(remove_brackets(host));
+        if self.deny.iter().any(|rule| rule.matches(&name, ip, port)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(&name, ip, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        crate::tests::setup_logging();
+        for rule in [
+            "*",
+            "*:22",
+            "10.0.0.0/8",
+            "10.0.0.1/32:1-1024",
+            "[fd00::/8]:443",
+            "example.com:8000-8999",
+            "*.example.com",
+        ] {
+            assert_eq!(rule.parse::<Rule>().unwrap().to_string(), rule);
+        }
+        assert_eq!(
+            "::1".parse::<Rule>().unwrap().to_string(),
+            "[::1/128]".to_string()
+        );
+        assert_eq!(
+            "Example.COM.:*".parse::<Rule>().unwrap().to_string(),
+            "example.com".to_string()
+        );
+        for rule in [
+            "",
+            "10.0.0.0/33",
+            "example.com:",
+            "example.com:99999",
+            "example.com:2-1",
+            "*.",
+            "exa mple.com",
+            "[::1]:",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn test_ip_network() {
+        crate::tests::setup_logging();
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.0.0.1".parse().unwrap()));
+        let network: IpNetwork = "fd00::/8".parse().unwrap();
+        assert_eq!(network.to_string(), "[fd00::/8]");
+        assert!(network.contains("fd12::1".parse().unwrap()));
+        let network: IpNetwork = "192.0.2.1".parse().unwrap();
+        assert_eq!(network.to_string(), "192.0.2.1/32");
+        assert!(!network.contains("192.0.2.2".parse().unwrap()));
+        for network in ["", "10.0.0.0/33", "example.com", "10.0.0.0/", "::1/129"] {
+            assert!(network.parse::<IpNetwork>().is_err(), "{network}");
+        }
+    }
+
+    #[test]
+    fn test_acl() {
+        crate::tests::setup_logging();
+        let acl = Acl::new(
+            vec!["*:80".parse().unwrap(), "*:443".parse().unwrap()],
+            vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "[fd00::/8]".parse().unwrap(),
+                "*.internal".parse().unwrap(),
+            ],
+        );
+        assert!(acl.allows("example.com", addr("192.0.2.1:443")));
+        assert!(!acl.allows("example.com", addr("192.0.2.1:22")));
+        assert!(!acl.allows("example.com", addr("10.1.2.3:80")));
+        assert!(!acl.allows("db.internal", addr("192.0.2.1:80")));
+        assert!(!acl.allows("DB.Internal.", addr("192.0.2.1:80")));
+        assert!(!acl.allows("[fd00::1]", addr("[fd00::1]:80")));
+        // IPv4-mapped addresses do not get around the rules
+        assert!(!acl.allows("::ffff:10.0.0.1", addr("[::ffff:10.0.0.1]:80")));
+        assert!(Acl::default().allows("db.internal", addr("10.0.0.1:22")));
+        // Without resolving, only literal addresses match CIDR rules
+        assert!(acl.allows_unresolved("db.example.com", 443));
+        assert!(!acl.allows_unresolved("10.0.0.1", 443));
+        assert!(!acl.allows_unresolved("[fd00::1]", 443));
+        assert!(!acl.allows_unresolved("db.internal", 443));
+        assert!(!acl.allows_unresolved("example.com", 22));
+        assert!(Acl::default().is_empty());
+        assert!(!acl.is_empty());
+    }
+
+    #[test]
+    fn test_acl_unix_sockets() {
+        crate::tests::setup_logging();
+        assert!(!Acl::default().allows_unix("/var/run/docker.sock"));
+        let acl = Acl::default().with_unix_sockets(vec!["/var/run/docker.sock".to_string()]);
+        assert!(acl.allows_unix("/var/run/docker.sock"));
+        assert!(!acl.allows_unix("/var/run/docker.sock/../other.sock"));
+        assert!(!acl.allows_unix("/var/run/other.sock"));
+        // Unix sockets do not open up other destinations
+        assert!(acl.is_empty());
+    }
+}
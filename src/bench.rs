@@ -0,0 +1,50 @@
+//! Throughput benchmark through the tunnel (`penguin bench`).
+//!
+//! A server started with `--bench` answers streams and datagrams to the
+//! host `penguin-bench` itself instead of forwarding them:
+//! - A stream starts with a mode octet and a length in octets (`u64`).
+//!   In [`UPLOAD`] mode, the client then sends that many octets and the
+//!   server replies with the number it received (`u64`) once it has all of
+//!   them. In [`DOWNLOAD`] mode, the server sends that many octets and
+//!   closes the stream.
+//! - Datagrams are sent back unchanged.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+/// Host of the streams and datagrams a `--bench` server answers itself
+pub const BENCH_HOST: &[u8] = b"penguin-bench";
+
+/// Mode of a stream where the client sends data
+pub const UPLOAD: u8 = b'u';
+
+/// Mode of a stream where the server sends data
+pub const DOWNLOAD: u8 = b'd';
+
+/// The request at the start of a benchmark stream
+#[cfg(feature = "client")]
+pub fn request(mode: u8, len: u64) -> [u8; 9] {
+    let mut request = [mode; 9];
+    request[1..].copy_from_slice(&len.to_be_bytes());
+    request
+}
+
+/// Answer a benchmark stream
+#[cfg(feature = "server")]
+pub async fn serve_stream(mut stream: penguin_mux::MuxStream) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mode = stream.read_u8().await?;
+    let len = stream.read_u64().await?;
+    match mode {
+        UPLOAD => {
+            let received =
+                tokio::io::copy(&mut (&mut stream).take(len), &mut tokio::io::sink()).await?;
+            stream.write_u64(received).await?;
+        }
+        DOWNLOAD => {
+            tokio::io::copy(&mut tokio::io::repeat(0).take(len), &mut stream).await?;
+        }
+        _ => return Err(std::io::ErrorKind::InvalidData.into()),
+    }
+    stream.shutdown().await
+}
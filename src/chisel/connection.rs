@@ -0,0 +1,647 @@
+//! The SSH connection protocol (RFC 4254): channels and global requests.
+//!
+//! A task owns the [`Transport`] and runs the protocol. [`Connection`]
+//! opens channels and sends requests through it, and what the peer opens
+//! or asks for comes out as [`Incoming`].
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::transport::{Transport, disconnect_error};
+use super::wire::{self, Reader};
+use super::{CHANNEL_TYPE, Error, msg};
+use bytes::{BufMut, Bytes, BytesMut};
+use penguin_mux::ws::WebSocket;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Semaphore, mpsc, oneshot};
+use tracing::debug;
+
+/// Our window of every channel, which is also what `chisel` uses
+const WINDOW_SIZE: u32 = 2 << 20;
+/// Largest data we accept in one packet
+const MAX_PACKET: u32 = 32768;
+/// Reason code of `SSH_MSG_CHANNEL_OPEN_FAILURE`
+const OPEN_ADMINISTRATIVELY_PROHIBITED: u32 = 1;
+const OPEN_UNKNOWN_CHANNEL_TYPE: u32 = 3;
+
+/// Reply to a global request: whether it succeeded and the data
+type RequestReply = (bool, Bytes);
+
+/// What the connection task is asked to do
+#[derive(Debug)]
+enum Command {
+    Open {
+        extra: Bytes,
+        reply: oneshot::Sender<Result<Channel, Error>>,
+    },
+    Request {
+        name: String,
+        data: Bytes,
+        reply: oneshot::Sender<RequestReply>,
+    },
+    Reply(RequestReply),
+    Data(u32, Bytes),
+    Eof(u32),
+    Close(u32),
+    /// Data of a channel was written out, so the peer may send more
+    Consumed(u32, u32),
+}
+
+/// Data for a channel handle
+#[derive(Debug)]
+enum Event {
+    Data(Bytes),
+    Eof,
+}
+
+/// What the peer opened or asked for
+#[derive(Debug)]
+pub enum Incoming {
+    /// A `chisel` channel, already confirmed, with its extra data
+    Channel { extra: Bytes, channel: Channel },
+    /// A global request. If `want_reply` is set, it must be answered with
+    /// [`Connection::reply`], in order.
+    Request {
+        name: String,
+        want_reply: bool,
+        data: Bytes,
+    },
+}
+
+/// A handle to a connection
+#[derive(Clone, Debug)]
+pub struct Connection {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Connection {
+    /// Open a `chisel` channel with `extra` as its extra data
+    pub async fn open(&self, extra: Bytes) -> Result<Channel, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Open { extra, reply })
+            .map_err(|_| Error::Closed)?;
+        rx.await.map_err(|_| Error::Closed)?
+    }
+
+    /// Send a global request and wait for the reply
+    pub async fn request(&self, name: &str, data: Bytes) -> Result<RequestReply, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.commands
+            .send(Command::Request {
+                name: name.to_string(),
+                data,
+                reply,
+            })
+            .map_err(|_| Error::Closed)?;
+        rx.await.map_err(|_| Error::Closed)
+    }
+
+    /// Answer the oldest global request that wants a reply
+    pub fn reply(&self, success: bool, data: Bytes) {
+        self.commands.send(Command::Reply((success, data))).ok();
+    }
+}
+
+/// A channel. Dropping it closes the channel.
+#[derive(Debug)]
+pub struct Channel {
+    id: u32,
+    /// Largest data the peer accepts in one packet
+    max_packet: usize,
+    /// The window of the peer
+    window: Arc<Semaphore>,
+    events: mpsc::UnboundedReceiver<Event>,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+fn broken_pipe() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "channel closed")
+}
+
+/// Send what `reader` reads through the channel
+async fn upload(
+    id: u32,
+    max_packet: usize,
+    window: &Semaphore,
+    commands: &mpsc::UnboundedSender<Command>,
+    reader: &mut (impl AsyncRead + Unpin),
+) -> std::io::Result<()> {
+    let mut buf = vec![0; max_packet];
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            commands.send(Command::Eof(id)).map_err(|_| broken_pipe())?;
+            return Ok(());
+        }
+        let mut data = &buf[..len];
+        while !data.is_empty() {
+            // Send as much as the window allows, but at least wait for some
+            window.acquire().await.map_err(|_| broken_pipe())?.forget();
+            let extra = window.available_permits().min(data.len() - 1);
+            let extra = u32::try_from(extra)
+                .ok()
+                .and_then(|extra| window.try_acquire_many(extra).ok())
+                .map_or(0, |permit| {
+                    let extra = permit.num_permits();
+                    permit.forget();
+                    extra
+                });
+            let (chunk, rest) = data.split_at(1 + extra);
+            commands
+                .send(Command::Data(id, Bytes::copy_from_slice(chunk)))
+                .map_err(|_| broken_pipe())?;
+            data = rest;
+        }
+    }
+}
+
+impl Channel {
+    /// Copy data between the channel and `stream` until both sides have
+    /// sent EOF or either closes
+    pub async fn pipe<RW: AsyncRead + AsyncWrite>(mut self, stream: RW) -> std::io::Result<()> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (eof_tx, eof_rx) = oneshot::channel();
+        let id = self.id;
+        let max_packet = self.max_packet;
+        let window = &self.window;
+        let events = &mut self.events;
+        let commands = &self.commands;
+        let download = async {
+            let mut eof_tx = Some(eof_tx);
+            while let Some(event) = events.recv().await {
+                match event {
+                    Event::Data(data) => {
+                        if eof_tx.is_some() {
+                            writer.write_all(&data).await?;
+                        }
+                        let len = u32::try_from(data.len())
+                            .expect("Data longer than the window (this is a bug)");
+                        commands.send(Command::Consumed(id, len)).ok();
+                    }
+                    Event::Eof => {
+                        writer.shutdown().await?;
+                        if let Some(eof_tx) = eof_tx.take() {
+                            eof_tx.send(()).ok();
+                        }
+                    }
+                }
+            }
+            // The peer closed the channel
+            Ok(())
+        };
+        let upload = async {
+            upload(id, max_packet, window, commands, &mut reader).await?;
+            eof_rx.await.ok();
+            Ok(())
+        };
+        tokio::select! {
+            result = download => result,
+            result = upload => result,
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.commands.send(Command::Close(self.id)).ok();
+    }
+}
+
+/// A channel as the connection task sees it
+#[derive(Debug)]
+struct ChannelState {
+    remote_id: u32,
+    /// `None` after the peer closed the channel
+    events: Option<mpsc::UnboundedSender<Event>>,
+    window: Arc<Semaphore>,
+    /// Data written out since the last window adjustment
+    consumed: u32,
+    sent_close: bool,
+}
+
+/// The connection task
+struct Task<S> {
+    transport: Transport<S>,
+    /// Weak so that the task ends when all handles are dropped
+    commands: mpsc::WeakUnboundedSender<Command>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+    channels: HashMap<u32, ChannelState>,
+    pending_opens: HashMap<u32, oneshot::Sender<Result<Channel, Error>>>,
+    pending_requests: VecDeque<oneshot::Sender<RequestReply>>,
+    next_id: u32,
+}
+
+/// Start the connection protocol on an authenticated transport.
+/// The returned future runs the connection and must be polled, usually in
+/// its own task. It ends when the transport fails or closes, or after all
+/// [`Connection`] and [`Channel`] handles are dropped.
+pub fn start<S: WebSocket>(
+    transport: Transport<S>,
+) -> (
+    Connection,
+    mpsc::UnboundedReceiver<Incoming>,
+    impl Future<Output = Result<(), Error>> + Send,
+) {
+    let (commands, commands_rx) = mpsc::unbounded_channel();
+    let (incoming, incoming_rx) = mpsc::unbounded_channel();
+    let task = Task {
+        transport,
+        commands: commands.downgrade(),
+        incoming,
+        channels: HashMap::new(),
+        pending_opens: HashMap::new(),
+        pending_requests: VecDeque::new(),
+        next_id: 0,
+    };
+    (Connection { commands }, incoming_rx, task.run(commands_rx))
+}
+
+/// `SSH_MSG_CHANNEL_OPEN_FAILURE`
+fn open_failure(recipient: u32, reason: u32, description: &str) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put_u8(msg::CHANNEL_OPEN_FAILURE);
+    buf.put_u32(recipient);
+    buf.put_u32(reason);
+    wire::put_string(&mut buf, description.as_bytes());
+    wire::put_string(&mut buf, b"");
+    buf
+}
+
+/// A channel message with only the recipient channel
+fn channel_message(number: u8, recipient: u32) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put_u8(number);
+    buf.put_u32(recipient);
+    buf
+}
+
+impl<S: WebSocket> Task<S> {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) -> Result<(), Error> {
+        let result = loop {
+            let result = tokio::select! {
+                payload = self.transport.recv_packet() => match payload {
+                    Ok(payload) => self.handle_packet(payload).await,
+                    Err(err) => Err(err),
+                },
+                command = commands.recv() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break self.transport.close().await,
+                },
+            };
+            if let Err(err) = result {
+                break Err(err);
+            }
+        };
+        // Wake up the channels waiting for the window
+        for state in self.channels.values() {
+            state.window.close();
+        }
+        result
+    }
+
+    fn alloc_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.channels.contains_key(&id) && !self.pending_opens.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Track a new channel and make its handle
+    fn new_channel(
+        &mut self,
+        id: u32,
+        remote_id: u32,
+        window: u32,
+        max_packet: u32,
+    ) -> Option<Channel> {
+        let commands = self.commands.upgrade()?;
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let window = Arc::new(Semaphore::new(window as usize));
+        let state = ChannelState {
+            remote_id,
+            events: Some(events),
+            window: window.clone(),
+            consumed: 0,
+            sent_close: false,
+        };
+        self.channels.insert(id, state);
+        Some(Channel {
+            id,
+            max_packet: max_packet.clamp(1, MAX_PACKET) as usize,
+            window,
+            events: events_rx,
+            commands,
+        })
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<(), Error> {
+        match command {
+            Command::Open { extra, reply } => {
+                let id = self.alloc_id();
+                let mut buf = BytesMut::new();
+                buf.put_u8(msg::CHANNEL_OPEN);
+                wire::put_string(&mut buf, CHANNEL_TYPE.as_bytes());
+                buf.put_u32(id);
+                buf.put_u32(WINDOW_SIZE);
+                buf.put_u32(MAX_PACKET);
+                buf.put_slice(&extra);
+                self.transport.send_packet(&buf).await?;
+                self.pending_opens.insert(id, reply);
+            }
+            Command::Request { name, data, reply } => {
+                let mut buf = BytesMut::new();
+                buf.put_u8(msg::GLOBAL_REQUEST);
+                wire::put_string(&mut buf, name.as_bytes());
+                wire::put_bool(&mut buf, true);
+                buf.put_slice(&data);
+                self.transport.send_packet(&buf).await?;
+                self.pending_requests.push_back(reply);
+            }
+            Command::Reply((success, data)) => {
+                let mut buf = BytesMut::new();
+                if success {
+                    buf.put_u8(msg::REQUEST_SUCCESS);
+                    buf.put_slice(&data);
+                } else {
+                    buf.put_u8(msg::REQUEST_FAILURE);
+                }
+                self.transport.send_packet(&buf).await?;
+            }
+            Command::Data(id, data) => {
+                if let Some(state) = self.channels.get(&id).filter(|state| !state.sent_close) {
+                    let mut buf = channel_message(msg::CHANNEL_DATA, state.remote_id);
+                    wire::put_string(&mut buf, &data);
+                    self.transport.send_packet(&buf).await?;
+                }
+            }
+            Command::Eof(id) => {
+                if let Some(state) = self.channels.get(&id).filter(|state| !state.sent_close) {
+                    let buf = channel_message(msg::CHANNEL_EOF, state.remote_id);
+                    self.transport.send_packet(&buf).await?;
+                }
+            }
+            Command::Close(id) => {
+                let Some(state) = self.channels.get_mut(&id) else {
+                    return Ok(());
+                };
+                if !state.sent_close {
+                    state.sent_close = true;
+                    let buf = channel_message(msg::CHANNEL_CLOSE, state.remote_id);
+                    self.transport.send_packet(&buf).await?;
+                }
+                if state.events.is_none() {
+                    self.channels.remove(&id);
+                }
+            }
+            Command::Consumed(id, len) => self.consume(id, len).await?,
+        }
+        Ok(())
+    }
+
+    /// Account for consumed data and adjust the window of the peer if
+    /// enough was consumed
+    async fn consume(&mut self, id: u32, len: u32) -> Result<(), Error> {
+        let Some(state) = self.channels.get_mut(&id) else {
+            return Ok(());
+        };
+        state.consumed = state.consumed.saturating_add(len);
+        if state.consumed >= WINDOW_SIZE / 2 && state.events.is_some() {
+            let mut buf = channel_message(msg::CHANNEL_WINDOW_ADJUST, state.remote_id);
+            buf.put_u32(state.consumed);
+            state.consumed = 0;
+            self.transport.send_packet(&buf).await?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_lines)]
+    async fn handle_packet(&mut self, payload: Bytes) -> Result<(), Error> {
+        let mut reader = Reader::new(&payload[1..]);
+        match payload[0] {
+            msg::KEXINIT => self.transport.key_exchange(Some(payload)).await?,
+            msg::DISCONNECT => return Err(disconnect_error(&payload)),
+            msg::IGNORE
+            | msg::DEBUG
+            | msg::UNIMPLEMENTED
+            | msg::CHANNEL_SUCCESS
+            | msg::CHANNEL_FAILURE => {}
+            msg::GLOBAL_REQUEST => {
+                let name = reader.str()?.to_string();
+                let want_reply = reader.bool()?;
+                let data = payload.slice_ref(reader.rest());
+                let request = Incoming::Request {
+                    name,
+                    want_reply,
+                    data,
+                };
+                if self.incoming.send(request).is_err() && want_reply {
+                    self.transport.send_packet(&[msg::REQUEST_FAILURE]).await?;
+                }
+            }
+            msg::REQUEST_SUCCESS | msg::REQUEST_FAILURE => {
+                let reply = self
+                    .pending_requests
+                    .pop_front()
+                    .ok_or(Error::Protocol("unexpected request reply"))?;
+                let success = payload[0] == msg::REQUEST_SUCCESS;
+                reply.send((success, payload.slice(1..))).ok();
+            }
+            msg::CHANNEL_OPEN => self.handle_open(&payload, reader).await?,
+            msg::CHANNEL_OPEN_CONFIRMATION => {
+                let id = reader.u32()?;
+                let remote_id = reader.u32()?;
+                let window = reader.u32()?;
+                let max_packet = reader.u32()?;
+                let reply = self
+                    .pending_opens
+                    .remove(&id)
+                    .ok_or(Error::Protocol("unexpected channel confirmation"))?;
+                if let Some(channel) = self.new_channel(id, remote_id, window, max_packet) {
+                    // Dropping the channel if nobody waits for it closes it
+                    reply.send(Ok(channel)).ok();
+                }
+            }
+            msg::CHANNEL_OPEN_FAILURE => {
+                let id = reader.u32()?;
+                let _reason = reader.u32()?;
+                let description = reader.str()?;
+                let reply = self
+                    .pending_opens
+                    .remove(&id)
+                    .ok_or(Error::Protocol("unexpected channel failure"))?;
+                reply
+                    .send(Err(Error::Rejected(description.to_string())))
+                    .ok();
+            }
+            msg::CHANNEL_WINDOW_ADJUST => {
+                let id = reader.u32()?;
+                let len = reader.u32()? as usize;
+                if let Some(state) = self.channels.get(&id) {
+                    if state.window.available_permits() + len > Semaphore::MAX_PERMITS {
+                        return Err(Error::Protocol("window too large"));
+                    }
+                    state.window.add_permits(len);
+                }
+            }
+            msg::CHANNEL_DATA => {
+                let id = reader.u32()?;
+                let data = reader.string()?;
+                if data.len() > MAX_PACKET as usize {
+                    return Err(Error::Protocol("channel data too long"));
+                }
+                let data = payload.slice_ref(data);
+                match self
+                    .channels
+                    .get(&id)
+                    .and_then(|state| state.events.as_ref())
+                {
+                    Some(events) if events.send(Event::Data(data.clone())).is_ok() => {}
+                    // Keep the window open if nobody reads
+                    _ => {
+                        let len = u32::try_from(data.len()).unwrap_or(u32::MAX);
+                        self.consume(id, len).await?;
+                    }
+                }
+            }
+            msg::CHANNEL_EXTENDED_DATA => {
+                let id = reader.u32()?;
+                let _type = reader.u32()?;
+                let len = reader.string()?.len();
+                self.consume(id, u32::try_from(len).unwrap_or(u32::MAX))
+                    .await?;
+            }
+            msg::CHANNEL_EOF => {
+                let id = reader.u32()?;
+                if let Some(events) = self
+                    .channels
+                    .get(&id)
+                    .and_then(|state| state.events.as_ref())
+                {
+                    events.send(Event::Eof).ok();
+                }
+            }
+            msg::CHANNEL_CLOSE => {
+                let id = reader.u32()?;
+                if let Some(state) = self.channels.get_mut(&id) {
+                    state.events = None;
+                    state.window.close();
+                    if state.sent_close {
+                        self.channels.remove(&id);
+                    }
+                }
+            }
+            msg::CHANNEL_REQUEST => {
+                let id = reader.u32()?;
+                let _type = reader.str()?;
+                if reader.bool()?
+                    && let Some(state) = self.channels.get(&id)
+                {
+                    let buf = channel_message(msg::CHANNEL_FAILURE, state.remote_id);
+                    self.transport.send_packet(&buf).await?;
+                }
+            }
+            number => debug!("ignoring SSH message {number}"),
+        }
+        Ok(())
+    }
+
+    async fn handle_open(&mut self, payload: &Bytes, mut reader: Reader<'_>) -> Result<(), Error> {
+        let kind = reader.str()?;
+        let remote_id = reader.u32()?;
+        let window = reader.u32()?;
+        let max_packet = reader.u32()?;
+        let extra = payload.slice_ref(reader.rest());
+        if kind != CHANNEL_TYPE {
+            let buf = open_failure(remote_id, OPEN_UNKNOWN_CHANNEL_TYPE, "unknown channel type");
+            return self.transport.send_packet(&buf).await;
+        }
+        let id = self.alloc_id();
+        let channel = if self.incoming.is_closed() {
+            None
+        } else {
+            self.new_channel(id, remote_id, window, max_packet)
+        };
+        let Some(channel) = channel else {
+            let buf = open_failure(remote_id, OPEN_ADMINISTRATIVELY_PROHIBITED, "not accepted");
+            return self.transport.send_packet(&buf).await;
+        };
+        let mut buf = channel_message(msg::CHANNEL_OPEN_CONFIRMATION, remote_id);
+        buf.put_u32(id);
+        buf.put_u32(WINDOW_SIZE);
+        buf.put_u32(MAX_PACKET);
+        self.transport.send_packet(&buf).await?;
+        self.incoming
+            .send(Incoming::Channel { extra, channel })
+            .ok();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chisel::HostKey;
+    use penguin_mux::ws::PlainStream;
+
+    #[tokio::test]
+    async fn test_connection() {
+        crate::tests::setup_logging();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let host_key = Arc::new(HostKey::generate());
+        let (client, server) = tokio::join!(
+            Transport::connect(PlainStream::new(client), None, "user", "pass"),
+            Transport::accept(PlainStream::new(server), host_key, |_, _| Some(()))
+        );
+        let (client, _, client_task) = start(client.unwrap());
+        let (server, mut incoming, server_task) = start(server.unwrap().0);
+        tokio::spawn(client_task);
+        tokio::spawn(server_task);
+        // An echo server
+        tokio::spawn(async move {
+            while let Some(incoming) = incoming.recv().await {
+                match incoming {
+                    Incoming::Channel { extra, channel } => {
+                        assert_eq!(extra, "echo");
+                        let (ours, theirs) = tokio::io::duplex(1024);
+                        tokio::spawn(channel.pipe(ours));
+                        tokio::spawn(async move {
+                            let (mut reader, mut writer) = tokio::io::split(theirs);
+                            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                            writer.shutdown().await.unwrap();
+                        });
+                    }
+                    Incoming::Request { name, data, .. } => {
+                        server.reply(name == "config", data);
+                    }
+                }
+            }
+        });
+        let reply = client.request("config", Bytes::from_static(b"{}")).await;
+        assert_eq!(reply.unwrap(), (true, Bytes::from_static(b"{}")));
+        let reply = client.request("other", Bytes::new()).await;
+        assert!(!reply.unwrap().0);
+        // More than a window in each direction
+        let data: Vec<u8> = (0..5 << 20)
+            .map(|i| u8::try_from(i % 251).unwrap())
+            .collect();
+        let channel = client.open(Bytes::from_static(b"echo")).await.unwrap();
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        let piped = tokio::spawn(channel.pipe(ours));
+        let (mut reader, mut writer) = tokio::io::split(theirs);
+        let expected = data.clone();
+        let write = tokio::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+        write.await.unwrap();
+        piped.await.unwrap().unwrap();
+    }
+}
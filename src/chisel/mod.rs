@@ -0,0 +1,207 @@
+//! Compatibility with the `chisel` wire protocol.
+//!
+//! `chisel` runs SSH over `WebSocket` (subprotocol `chisel-v3`): the binary
+//! messages carry the SSH byte stream, the client authenticates with a
+//! password, sends its remotes as JSON in a `config` global request, and
+//! opens a `chisel` channel whose extra data is `host:port` for every
+//! connection. This module implements the subset of SSH that `chisel`
+//! uses: `curve25519-sha256` key exchange, `chacha20-poly1305@openssh.com`,
+//! `ssh-ed25519` (and, for verifying servers, `ecdsa-sha2-nistp256`) host
+//! keys, password authentication and channels.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+pub mod connection;
+pub mod transport;
+mod wire;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use bytes::BytesMut;
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// `WebSocket` subprotocol of `chisel`
+pub const WS_PROTOCOL: &str = "chisel-v3";
+
+/// Type of the channels carrying connections
+pub const CHANNEL_TYPE: &str = "chisel";
+
+/// SSH message numbers (RFC 4250, section 4.1)
+mod msg {
+    pub const DISCONNECT: u8 = 1;
+    pub const IGNORE: u8 = 2;
+    pub const UNIMPLEMENTED: u8 = 3;
+    pub const DEBUG: u8 = 4;
+    pub const SERVICE_REQUEST: u8 = 5;
+    pub const SERVICE_ACCEPT: u8 = 6;
+    pub const EXT_INFO: u8 = 7;
+    pub const KEXINIT: u8 = 20;
+    pub const NEWKEYS: u8 = 21;
+    pub const KEX_ECDH_INIT: u8 = 30;
+    pub const KEX_ECDH_REPLY: u8 = 31;
+    pub const USERAUTH_REQUEST: u8 = 50;
+    pub const USERAUTH_FAILURE: u8 = 51;
+    pub const USERAUTH_SUCCESS: u8 = 52;
+    pub const USERAUTH_BANNER: u8 = 53;
+    pub const GLOBAL_REQUEST: u8 = 80;
+    pub const REQUEST_SUCCESS: u8 = 81;
+    pub const REQUEST_FAILURE: u8 = 82;
+    pub const CHANNEL_OPEN: u8 = 90;
+    pub const CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+    pub const CHANNEL_OPEN_FAILURE: u8 = 92;
+    pub const CHANNEL_WINDOW_ADJUST: u8 = 93;
+    pub const CHANNEL_DATA: u8 = 94;
+    pub const CHANNEL_EXTENDED_DATA: u8 = 95;
+    pub const CHANNEL_EOF: u8 = 96;
+    pub const CHANNEL_CLOSE: u8 = 97;
+    pub const CHANNEL_REQUEST: u8 = 98;
+    pub const CHANNEL_SUCCESS: u8 = 99;
+    pub const CHANNEL_FAILURE: u8 = 100;
+}
+
+/// `chisel` protocol errors
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("chisel connection failed: {0}")]
+    WebSocket(#[from] penguin_mux::Error),
+    #[error("chisel connection closed")]
+    Closed,
+    #[error("SSH protocol error: {0}")]
+    Protocol(&'static str),
+    #[error("SSH key exchange failed: no common {0} algorithm")]
+    Negotiation(&'static str),
+    #[error("SSH encryption failed: corrupted packet")]
+    Crypto,
+    #[error("SSH encryption failed: too many packets")]
+    SequenceExhausted,
+    #[error("SSH host key fingerprint mismatch: got {0}")]
+    Fingerprint(String),
+    #[error("chisel authentication failed")]
+    Auth,
+    #[error("chisel peer disconnected: {0}")]
+    Disconnected(String),
+    #[error("chisel channel rejected: {0}")]
+    Rejected(String),
+    #[error("chisel configuration rejected: {0}")]
+    Config(String),
+}
+
+impl From<ring::error::Unspecified> for Error {
+    fn from(_: ring::error::Unspecified) -> Self {
+        Self::Crypto
+    }
+}
+
+/// A remote as the `chisel` client sends it in its configuration
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct RemoteConfig {
+    pub local_host: String,
+    pub local_port: String,
+    pub local_proto: String,
+    pub remote_host: String,
+    pub remote_port: String,
+    pub remote_proto: String,
+    pub socks: bool,
+    pub reverse: bool,
+    pub stdio: bool,
+}
+
+/// The configuration the `chisel` client sends after authenticating
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase", default)]
+pub struct Config {
+    pub version: String,
+    pub remotes: Vec<RemoteConfig>,
+}
+
+/// The `SHA256` fingerprint of an SSH public key as `chisel` prints it
+pub fn fingerprint(public_key_blob: &[u8]) -> String {
+    B64_STANDARD_ENGINE.encode(digest(&SHA256, public_key_blob))
+}
+
+/// The `ssh-ed25519` host key of a server
+#[derive(Debug)]
+pub struct HostKey(Ed25519KeyPair);
+
+impl HostKey {
+    /// Generate a random key
+    pub fn generate() -> Self {
+        let seed: [u8; 32] = rand::random();
+        Self::from_seed_bytes(&seed)
+    }
+
+    /// Derive the key from a seed, so that it stays the same across restarts
+    pub fn from_seed(seed: &str) -> Self {
+        Self::from_seed_bytes(digest(&SHA256, seed.as_bytes()).as_ref())
+    }
+
+    fn from_seed_bytes(seed: &[u8]) -> Self {
+        Self(
+            Ed25519KeyPair::from_seed_unchecked(seed)
+                .expect("Ed25519 seed of the wrong length (this is a bug)"),
+        )
+    }
+
+    /// The public key in the SSH format
+    pub fn public_key_blob(&self) -> BytesMut {
+        let mut blob = BytesMut::new();
+        wire::put_string(&mut blob, b"ssh-ed25519");
+        wire::put_string(&mut blob, self.0.public_key().as_ref());
+        blob
+    }
+
+    /// Sign `data` and encode the signature in the SSH format
+    fn sign(&self, data: &[u8]) -> BytesMut {
+        let mut blob = BytesMut::new();
+        wire::put_string(&mut blob, b"ssh-ed25519");
+        wire::put_string(&mut blob, self.0.sign(data).as_ref());
+        blob
+    }
+
+    /// The fingerprint `chisel` clients check with `--fingerprint`
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key_blob())
+    }
+}
+
+/// Fill `buf` with random bytes
+fn fill_random(buf: &mut [u8]) -> Result<(), Error> {
+    ring::rand::SecureRandom::fill(&SystemRandom::new(), buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_json() {
+        crate::tests::setup_logging();
+        // As sent by `chisel` 1.10
+        let json = r#"{"Version":"1.10.1","Remotes":[{"LocalHost":"0.0.0.0","LocalPort":"3000",
+            "LocalProto":"tcp","RemoteHost":"example.com","RemotePort":"80","RemoteProto":"tcp",
+            "Socks":false,"Reverse":false,"Stdio":false}]}"#;
+        let config: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(config.version, "1.10.1");
+        assert_eq!(config.remotes[0].remote_host, "example.com");
+        assert_eq!(config.remotes[0].remote_port, "80");
+        let config: Config =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(config.remotes.len(), 1);
+    }
+
+    #[test]
+    fn test_host_key() {
+        crate::tests::setup_logging();
+        let key = HostKey::from_seed("seed");
+        assert_eq!(key.fingerprint(), HostKey::from_seed("seed").fingerprint());
+        assert_ne!(key.fingerprint(), HostKey::generate().fingerprint());
+        assert_eq!(key.fingerprint().len(), 44);
+    }
+}
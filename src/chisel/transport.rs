@@ -0,0 +1,757 @@
+//! The SSH transport layer (RFC 4253) and password authentication
+//! (RFC 4252).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::wire::{self, Reader};
+use super::{Error, HostKey, fill_random, fingerprint, msg};
+use bytes::{BufMut, Bytes, BytesMut};
+use penguin_mux::ws::{Message, WebSocket};
+use ring::aead::chacha20_poly1305_openssh::{
+    KEY_LEN, OpeningKey, PACKET_LENGTH_LEN, SealingKey, TAG_LEN,
+};
+use ring::agreement::{self, EphemeralPrivateKey, X25519};
+use ring::digest::{self, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature;
+use std::future::poll_fn;
+use std::sync::Arc;
+use tracing::{debug, trace};
+
+/// SSH version string of the `chisel` client
+pub const CLIENT_VERSION: &[u8] = b"SSH-chisel-v3-client";
+/// SSH version string of the `chisel` server
+pub const SERVER_VERSION: &[u8] = b"SSH-chisel-v3-server";
+
+const KEX_ALGORITHMS: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const ED25519: &str = "ssh-ed25519";
+const ECDSA_P256: &str = "ecdsa-sha2-nistp256";
+const CIPHER: &str = "chacha20-poly1305@openssh.com";
+/// Only offered because some peers insist on a MAC, which the AEAD cipher
+/// makes unnecessary
+const MAC: &str = "hmac-sha2-256";
+const COMPRESSION: &str = "none";
+/// Largest `packet_length` we accept
+const MAX_PACKET_LEN: usize = 256 * 1024;
+const BLOCK_SIZE: usize = 8;
+/// Lines the server may send before its version string
+const MAX_PRE_VERSION_LINES: usize = 64;
+/// Longest version string (RFC 4253, section 4.2)
+const MAX_VERSION_LEN: usize = 255;
+const MAX_AUTH_ATTEMPTS: usize = 10;
+
+/// Which side of the connection we are
+#[derive(Debug)]
+enum Role {
+    Client {
+        /// Prefix of the fingerprint the host key must have
+        fingerprint: Option<String>,
+    },
+    Server {
+        host_key: Arc<HostKey>,
+    },
+}
+
+/// An SSH connection over a [`WebSocket`] after the key exchange
+pub struct Transport<S> {
+    ws: S,
+    role: Role,
+    /// Received bytes that are not a complete packet yet
+    rbuf: BytesMut,
+    client_version: Bytes,
+    server_version: Bytes,
+    session_id: Option<Bytes>,
+    send_seq: u32,
+    recv_seq: u32,
+    sealing: Option<SealingKey>,
+    opening: Option<OpeningKey>,
+}
+
+impl<S> std::fmt::Debug for Transport<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Transport")
+            .field("role", &self.role)
+            .field("send_seq", &self.send_seq)
+            .field("recv_seq", &self.recv_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The next sequence number. They wrap around before the keys are set,
+/// but reusing one with the same key would reuse a nonce.
+fn next_seq(seq: u32, encrypted: bool) -> Result<u32, Error> {
+    match seq.checked_add(1) {
+        Some(next) => Ok(next),
+        None if !encrypted => Ok(0),
+        None => Err(Error::SequenceExhausted),
+    }
+}
+
+/// The error of a `SSH_MSG_DISCONNECT`
+pub(super) fn disconnect_error(payload: &[u8]) -> Error {
+    let mut reader = Reader::new(&payload[1..]);
+    let description = reader.u32().and_then(|_| reader.str()).unwrap_or_default();
+    Error::Disconnected(description.to_string())
+}
+
+/// The first algorithm of the client that the server also supports
+/// (RFC 4253, section 7.1)
+fn negotiate<'a>(
+    client: &[&'a str],
+    server: &[&'a str],
+    what: &'static str,
+) -> Result<&'a str, Error> {
+    client
+        .iter()
+        .find(|algorithm| server.contains(algorithm))
+        .copied()
+        .ok_or(Error::Negotiation(what))
+}
+
+/// The name-lists of a `SSH_MSG_KEXINIT`, after the message number and the
+/// cookie
+fn skip_cookie(kexinit: &[u8]) -> Result<Reader<'_>, Error> {
+    kexinit
+        .get(17..)
+        .map(Reader::new)
+        .ok_or(Error::Protocol("truncated message"))
+}
+
+/// Derive a `chacha20-poly1305@openssh.com` key (RFC 4253, section 7.2)
+fn derive_key(k: &[u8], h: &[u8], letter: u8, session_id: &[u8]) -> [u8; KEY_LEN] {
+    let mut ctx = digest::Context::new(&SHA256);
+    ctx.update(k);
+    ctx.update(h);
+    ctx.update(&[letter]);
+    ctx.update(session_id);
+    let first = ctx.finish();
+    let mut ctx = digest::Context::new(&SHA256);
+    ctx.update(k);
+    ctx.update(h);
+    ctx.update(first.as_ref());
+    let second = ctx.finish();
+    let mut key = [0; KEY_LEN];
+    key[..KEY_LEN / 2].copy_from_slice(first.as_ref());
+    key[KEY_LEN / 2..].copy_from_slice(second.as_ref());
+    key
+}
+
+/// Convert the `mpint` `r` and `s` of an ECDSA signature to the fixed
+/// format `ring` takes
+fn ecdsa_fixed_signature(blob: &[u8]) -> Result<[u8; 64], Error> {
+    let mut reader = Reader::new(blob);
+    let mut fixed = [0; 64];
+    for half in fixed.chunks_exact_mut(32) {
+        let int = reader.string()?;
+        let start = int.iter().position(|&b| b != 0).unwrap_or(int.len());
+        let int = &int[start..];
+        if int.len() > 32 {
+            return Err(Error::Protocol("invalid ECDSA signature"));
+        }
+        half[32 - int.len()..].copy_from_slice(int);
+    }
+    Ok(fixed)
+}
+
+/// Check the signature of the server on the exchange hash
+fn verify_host_key(
+    algorithm: &str,
+    key_blob: &[u8],
+    signature_blob: &[u8],
+    h: &[u8],
+) -> Result<(), Error> {
+    let mut key = Reader::new(key_blob);
+    let mut sig = Reader::new(signature_blob);
+    if key.str()? != algorithm || sig.str()? != algorithm {
+        return Err(Error::Protocol("host key of the wrong type"));
+    }
+    let sig = sig.string()?;
+    let result = match algorithm {
+        ED25519 => {
+            signature::UnparsedPublicKey::new(&signature::ED25519, key.string()?).verify(h, sig)
+        }
+        ECDSA_P256 => {
+            if key.str()? != "nistp256" {
+                return Err(Error::Protocol("host key of the wrong type"));
+            }
+            let sig = ecdsa_fixed_signature(sig)?;
+            signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, key.string()?)
+                .verify(h, &sig)
+        }
+        _ => return Err(Error::Negotiation("host key")),
+    };
+    result.map_err(|_| Error::Protocol("invalid host key signature"))
+}
+
+impl<S: WebSocket> Transport<S> {
+    fn new(ws: S, role: Role) -> Self {
+        Self {
+            ws,
+            role,
+            rbuf: BytesMut::new(),
+            client_version: Bytes::from_static(CLIENT_VERSION),
+            server_version: Bytes::from_static(SERVER_VERSION),
+            session_id: None,
+            send_seq: 0,
+            recv_seq: 0,
+            sealing: None,
+            opening: None,
+        }
+    }
+
+    /// Connect as a client and authenticate with a password.
+    /// If `fingerprint` is set, the host key must have a fingerprint that
+    /// starts with it.
+    pub async fn connect(
+        ws: S,
+        fingerprint: Option<String>,
+        user: &str,
+        password: &str,
+    ) -> Result<Self, Error> {
+        let mut transport = Self::new(ws, Role::Client { fingerprint });
+        transport.exchange_versions().await?;
+        transport.key_exchange(None).await?;
+        transport.authenticate(user, password).await?;
+        Ok(transport)
+    }
+
+    /// Accept a client. `check` is called with the user name and the
+    /// password (`None` for the `none` method) and returns `Some` to let the
+    /// client in.
+    pub async fn accept<R>(
+        ws: S,
+        host_key: Arc<HostKey>,
+        check: impl FnMut(&str, Option<&str>) -> Option<R>,
+    ) -> Result<(Self, R), Error> {
+        let mut transport = Self::new(ws, Role::Server { host_key });
+        transport.exchange_versions().await?;
+        transport.key_exchange(None).await?;
+        let result = transport.authenticate_client(check).await?;
+        Ok((transport, result))
+    }
+
+    async fn send_raw(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        poll_fn(|cx| self.ws.poll_ready_unpin(cx)).await?;
+        self.ws.start_send_unpin(Message::Binary(data.into()))?;
+        poll_fn(|cx| self.ws.poll_flush_unpin(cx)).await?;
+        Ok(())
+    }
+
+    /// Read more bytes into `rbuf`. This is cancel-safe.
+    async fn fill(&mut self) -> Result<(), Error> {
+        loop {
+            match poll_fn(|cx| self.ws.poll_next_unpin(cx))
+                .await
+                .transpose()?
+            {
+                Some(Message::Binary(data)) => {
+                    self.rbuf.extend_from_slice(&data);
+                    return Ok(());
+                }
+                Some(Message::Ping | Message::Pong) => {}
+                Some(Message::Close) | None => return Err(Error::Closed),
+            }
+        }
+    }
+
+    /// Close the `WebSocket`
+    pub async fn close(&mut self) -> Result<(), Error> {
+        poll_fn(|cx| self.ws.poll_close_unpin(cx)).await?;
+        Ok(())
+    }
+
+    async fn exchange_versions(&mut self) -> Result<(), Error> {
+        let ours = match self.role {
+            Role::Client { .. } => CLIENT_VERSION,
+            Role::Server { .. } => SERVER_VERSION,
+        };
+        let mut line = ours.to_vec();
+        line.extend_from_slice(b"\r\n");
+        self.send_raw(line).await?;
+        let mut lines = 0;
+        let theirs = loop {
+            if let Some(pos) = self.rbuf.iter().position(|&b| b == b'\n') {
+                let line = self.rbuf.split_to(pos + 1);
+                let line = &line[..pos];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                if line.starts_with(b"SSH-") {
+                    break Bytes::copy_from_slice(line);
+                }
+                // Other lines may come before the version (RFC 4253, section 4.2)
+                lines += 1;
+                if lines > MAX_PRE_VERSION_LINES {
+                    return Err(Error::Protocol("no version string"));
+                }
+                continue;
+            }
+            if self.rbuf.len() > MAX_VERSION_LEN {
+                return Err(Error::Protocol("invalid version string"));
+            }
+            self.fill().await?;
+        };
+        debug!("peer SSH version: {}", String::from_utf8_lossy(&theirs));
+        match self.role {
+            Role::Client { .. } => self.server_version = theirs,
+            Role::Server { .. } => self.client_version = theirs,
+        }
+        Ok(())
+    }
+
+    /// Send a packet with `payload`
+    pub async fn send_packet(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let encrypted = self.sealing.is_some();
+        // The length field is not aligned with this cipher
+        let aligned = if encrypted { 1 } else { 5 } + payload.len();
+        let mut padding = BLOCK_SIZE - aligned % BLOCK_SIZE;
+        if padding < 4 {
+            padding += BLOCK_SIZE;
+        }
+        let packet_len = 1 + payload.len() + padding;
+        let mut buf = Vec::with_capacity(PACKET_LENGTH_LEN + packet_len + TAG_LEN);
+        buf.put_u32(u32::try_from(packet_len).map_err(|_| Error::Protocol("packet too long"))?);
+        buf.put_u8(u8::try_from(padding).expect("Padding too long (this is a bug)"));
+        buf.put_slice(payload);
+        let start = buf.len();
+        buf.resize(start + padding, 0);
+        fill_random(&mut buf[start..])?;
+        if let Some(key) = &self.sealing {
+            let mut tag = [0; TAG_LEN];
+            key.seal_in_place(self.send_seq, &mut buf, &mut tag);
+            buf.extend_from_slice(&tag);
+        }
+        self.send_seq = next_seq(self.send_seq, encrypted)?;
+        self.send_raw(buf).await
+    }
+
+    /// Take a complete packet out of `rbuf`, if there is one, and return
+    /// its payload
+    fn try_decode(&mut self) -> Result<Option<Bytes>, Error> {
+        let Some(&len) = self.rbuf.first_chunk::<PACKET_LENGTH_LEN>() else {
+            return Ok(None);
+        };
+        let len = match &self.opening {
+            Some(key) => key.decrypt_packet_length(self.recv_seq, len),
+            None => len,
+        };
+        let packet_len = u32::from_be_bytes(len) as usize;
+        if !(5..=MAX_PACKET_LEN).contains(&packet_len) {
+            return Err(Error::Protocol("invalid packet length"));
+        }
+        let end = PACKET_LENGTH_LEN + packet_len;
+        let tag_len = if self.opening.is_some() { TAG_LEN } else { 0 };
+        if self.rbuf.len() < end + tag_len {
+            return Ok(None);
+        }
+        let mut packet = self.rbuf.split_to(end + tag_len);
+        if let Some(key) = &self.opening {
+            let tag: [u8; TAG_LEN] = packet[end..]
+                .try_into()
+                .expect("Slice of the wrong length (this is a bug)");
+            key.open_in_place(self.recv_seq, &mut packet[..end], &tag)?;
+        }
+        let payload_end = end
+            .checked_sub(usize::from(packet[PACKET_LENGTH_LEN]))
+            .filter(|&payload_end| payload_end > PACKET_LENGTH_LEN + 1)
+            .ok_or(Error::Protocol("invalid padding length"))?;
+        self.recv_seq = next_seq(self.recv_seq, self.opening.is_some())?;
+        Ok(Some(
+            packet.freeze().slice(PACKET_LENGTH_LEN + 1..payload_end),
+        ))
+    }
+
+    /// Receive the payload of the next packet, which is never empty.
+    /// This is cancel-safe.
+    pub async fn recv_packet(&mut self) -> Result<Bytes, Error> {
+        loop {
+            if let Some(payload) = self.try_decode()? {
+                trace!("received SSH message {}", payload[0]);
+                return Ok(payload);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Receive the next message that is not about the transport, and run
+    /// the key exchange if the peer starts one
+    async fn recv_message(&mut self) -> Result<Bytes, Error> {
+        loop {
+            let payload = self.recv_packet().await?;
+            match payload[0] {
+                msg::IGNORE | msg::DEBUG | msg::UNIMPLEMENTED | msg::EXT_INFO => {}
+                msg::DISCONNECT => return Err(disconnect_error(&payload)),
+                msg::KEXINIT => self.key_exchange(Some(payload)).await?,
+                _ => return Ok(payload),
+            }
+        }
+    }
+
+    /// Receive a message of the key exchange of type `expected`
+    async fn recv_kex_message(&mut self, expected: u8) -> Result<Bytes, Error> {
+        loop {
+            let payload = self.recv_packet().await?;
+            match payload[0] {
+                msg::IGNORE | msg::DEBUG | msg::UNIMPLEMENTED => {}
+                msg::DISCONNECT => return Err(disconnect_error(&payload)),
+                number if number == expected => return Ok(payload),
+                _ => return Err(Error::Protocol("unexpected message in key exchange")),
+            }
+        }
+    }
+
+    fn kexinit(&self) -> Result<Bytes, Error> {
+        let host_key_algorithms = match self.role {
+            Role::Client { .. } => &[ED25519, ECDSA_P256][..],
+            Role::Server { .. } => &[ED25519][..],
+        };
+        let mut cookie = [0; 16];
+        fill_random(&mut cookie)?;
+        let mut buf = BytesMut::new();
+        buf.put_u8(msg::KEXINIT);
+        buf.put_slice(&cookie);
+        let kex = KEX_ALGORITHMS.join(",");
+        let host_key = host_key_algorithms.join(",");
+        for list in [
+            &kex,
+            &host_key,
+            CIPHER,
+            CIPHER,
+            MAC,
+            MAC,
+            COMPRESSION,
+            COMPRESSION,
+            "",
+            "",
+        ] {
+            wire::put_string(&mut buf, list.as_bytes());
+        }
+        // first_kex_packet_follows and reserved
+        wire::put_bool(&mut buf, false);
+        buf.put_u32(0);
+        Ok(buf.freeze())
+    }
+
+    /// Run the key exchange. `peer_kexinit` is the `SSH_MSG_KEXINIT` of the
+    /// peer if it started the exchange.
+    pub async fn key_exchange(&mut self, peer_kexinit: Option<Bytes>) -> Result<(), Error> {
+        let ours = self.kexinit()?;
+        self.send_packet(&ours).await?;
+        let theirs = match peer_kexinit {
+            Some(theirs) => theirs,
+            None => self.recv_kex_message(msg::KEXINIT).await?,
+        };
+        let is_client = matches!(self.role, Role::Client { .. });
+        let (i_c, i_s) = if is_client {
+            (&ours, &theirs)
+        } else {
+            (&theirs, &ours)
+        };
+        let mut client = skip_cookie(i_c)?;
+        let mut server = skip_cookie(i_s)?;
+        let client_kex = client.name_list()?;
+        let server_kex = server.name_list()?;
+        let kex = negotiate(&client_kex, &server_kex, "key exchange")?;
+        let client_host_key = client.name_list()?;
+        let server_host_key = server.name_list()?;
+        let host_key_algorithm = negotiate(&client_host_key, &server_host_key, "host key")?;
+        for what in [
+            "cipher",
+            "cipher",
+            "MAC",
+            "MAC",
+            "compression",
+            "compression",
+        ] {
+            let client_list = client.name_list()?;
+            let server_list = server.name_list()?;
+            // No MAC is used with an AEAD cipher
+            if what != "MAC" {
+                negotiate(&client_list, &server_list, what)?;
+            }
+        }
+        for _ in 0..2 {
+            client.name_list()?;
+            server.name_list()?;
+        }
+        let mut peer = if is_client { server } else { client };
+        let peer_guessed = peer.bool()?;
+        let (peer_kex, peer_host_key) = if is_client {
+            (server_kex[0], server_host_key[0])
+        } else {
+            (client_kex[0], client_host_key[0])
+        };
+        if peer_guessed && (peer_kex != kex || peer_host_key != host_key_algorithm) {
+            // Ignore the packet sent with a wrong guess
+            self.recv_packet().await?;
+        }
+        debug!("SSH key exchange with {kex} and {host_key_algorithm}");
+        let (k, h) = match &self.role {
+            Role::Client { .. } => self.client_ecdh(i_c, i_s, host_key_algorithm).await?,
+            Role::Server { host_key } => {
+                let host_key = host_key.clone();
+                self.server_ecdh(i_c, i_s, &host_key).await?
+            }
+        };
+        let session_id = self.session_id.get_or_insert_with(|| h.clone()).clone();
+        let c2s_key = derive_key(&k, &h, b'C', &session_id);
+        let s2c_key = derive_key(&k, &h, b'D', &session_id);
+        let (sealing, opening) = if is_client {
+            (c2s_key, s2c_key)
+        } else {
+            (s2c_key, c2s_key)
+        };
+        self.send_packet(&[msg::NEWKEYS]).await?;
+        self.sealing = Some(SealingKey::new(&sealing));
+        self.recv_kex_message(msg::NEWKEYS).await?;
+        self.opening = Some(OpeningKey::new(&opening));
+        Ok(())
+    }
+
+    /// The exchange hash of `curve25519-sha256` (RFC 8731, section 3)
+    fn exchange_hash(
+        &self,
+        i_c: &[u8],
+        i_s: &[u8],
+        k_s: &[u8],
+        q_c: &[u8],
+        q_s: &[u8],
+        k: &[u8],
+    ) -> Bytes {
+        let mut buf = BytesMut::new();
+        for string in [
+            &self.client_version[..],
+            &self.server_version[..],
+            i_c,
+            i_s,
+            k_s,
+            q_c,
+            q_s,
+        ] {
+            wire::put_string(&mut buf, string);
+        }
+        buf.put_slice(k);
+        Bytes::copy_from_slice(digest::digest(&SHA256, &buf).as_ref())
+    }
+
+    /// Run the ECDH as the client and return the shared secret as an
+    /// `mpint` and the exchange hash
+    async fn client_ecdh(
+        &mut self,
+        i_c: &[u8],
+        i_s: &[u8],
+        host_key_algorithm: &str,
+    ) -> Result<(BytesMut, Bytes), Error> {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())?;
+        let q_c = private_key.compute_public_key()?;
+        let mut init = BytesMut::new();
+        init.put_u8(msg::KEX_ECDH_INIT);
+        wire::put_string(&mut init, q_c.as_ref());
+        self.send_packet(&init).await?;
+        let reply = self.recv_kex_message(msg::KEX_ECDH_REPLY).await?;
+        let mut reader = Reader::new(&reply[1..]);
+        let k_s = reader.string()?;
+        let q_s = reader.string()?;
+        let signature = reader.string()?;
+        let mut k = BytesMut::new();
+        agreement::agree_ephemeral(
+            private_key,
+            &agreement::UnparsedPublicKey::new(&X25519, q_s),
+            |shared| wire::put_mpint(&mut k, shared),
+        )?;
+        let h = self.exchange_hash(i_c, i_s, k_s, q_c.as_ref(), q_s, &k);
+        verify_host_key(host_key_algorithm, k_s, signature, &h)?;
+        let got = fingerprint(k_s);
+        if let Role::Client {
+            fingerprint: Some(expected),
+        } = &self.role
+        {
+            if !got.starts_with(expected.as_str()) {
+                return Err(Error::Fingerprint(got));
+            }
+        } else {
+            debug!("chisel server fingerprint: {got}");
+        }
+        Ok((k, h))
+    }
+
+    /// Run the ECDH as the server and return the shared secret as an
+    /// `mpint` and the exchange hash
+    async fn server_ecdh(
+        &mut self,
+        i_c: &[u8],
+        i_s: &[u8],
+        host_key: &HostKey,
+    ) -> Result<(BytesMut, Bytes), Error> {
+        let init = self.recv_kex_message(msg::KEX_ECDH_INIT).await?;
+        let q_c = Reader::new(&init[1..]).string()?;
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())?;
+        let q_s = private_key.compute_public_key()?;
+        let mut k = BytesMut::new();
+        agreement::agree_ephemeral(
+            private_key,
+            &agreement::UnparsedPublicKey::new(&X25519, q_c),
+            |shared| wire::put_mpint(&mut k, shared),
+        )?;
+        let k_s = host_key.public_key_blob();
+        let h = self.exchange_hash(i_c, i_s, &k_s, q_c, q_s.as_ref(), &k);
+        let mut reply = BytesMut::new();
+        reply.put_u8(msg::KEX_ECDH_REPLY);
+        wire::put_string(&mut reply, &k_s);
+        wire::put_string(&mut reply, q_s.as_ref());
+        wire::put_string(&mut reply, &host_key.sign(&h));
+        self.send_packet(&reply).await?;
+        Ok((k, h))
+    }
+
+    async fn authenticate(&mut self, user: &str, password: &str) -> Result<(), Error> {
+        let mut request = BytesMut::new();
+        request.put_u8(msg::SERVICE_REQUEST);
+        wire::put_string(&mut request, b"ssh-userauth");
+        self.send_packet(&request).await?;
+        if self.recv_message().await?[0] != msg::SERVICE_ACCEPT {
+            return Err(Error::Protocol("authentication service not accepted"));
+        }
+        let mut request = BytesMut::new();
+        request.put_u8(msg::USERAUTH_REQUEST);
+        wire::put_string(&mut request, user.as_bytes());
+        wire::put_string(&mut request, b"ssh-connection");
+        wire::put_string(&mut request, b"password");
+        wire::put_bool(&mut request, false);
+        wire::put_string(&mut request, password.as_bytes());
+        self.send_packet(&request).await?;
+        loop {
+            let reply = self.recv_message().await?;
+            match reply[0] {
+                msg::USERAUTH_SUCCESS => return Ok(()),
+                msg::USERAUTH_FAILURE => return Err(Error::Auth),
+                msg::USERAUTH_BANNER => {
+                    let banner = Reader::new(&reply[1..]).str().unwrap_or_default();
+                    debug!("chisel server banner: {banner}");
+                }
+                _ => return Err(Error::Protocol("unexpected authentication reply")),
+            }
+        }
+    }
+
+    async fn authenticate_client<R>(
+        &mut self,
+        mut check: impl FnMut(&str, Option<&str>) -> Option<R>,
+    ) -> Result<R, Error> {
+        let request = self.recv_message().await?;
+        if request[0] != msg::SERVICE_REQUEST
+            || Reader::new(&request[1..]).string()? != b"ssh-userauth"
+        {
+            return Err(Error::Protocol("expected authentication service request"));
+        }
+        let mut accept = BytesMut::new();
+        accept.put_u8(msg::SERVICE_ACCEPT);
+        wire::put_string(&mut accept, b"ssh-userauth");
+        self.send_packet(&accept).await?;
+        for _ in 0..MAX_AUTH_ATTEMPTS {
+            let request = self.recv_message().await?;
+            if request[0] != msg::USERAUTH_REQUEST {
+                return Err(Error::Protocol("expected authentication request"));
+            }
+            let mut reader = Reader::new(&request[1..]);
+            let user = reader.str()?;
+            let service = reader.str()?;
+            let method = reader.str()?;
+            let password = match method {
+                "password" => {
+                    // Whether it is a password change, which we do not support
+                    if reader.bool()? {
+                        None
+                    } else {
+                        Some(Some(reader.str()?))
+                    }
+                }
+                "none" => Some(None),
+                _ => None,
+            };
+            if service == "ssh-connection"
+                && let Some(password) = password
+                && let Some(result) = check(user, password)
+            {
+                self.send_packet(&[msg::USERAUTH_SUCCESS]).await?;
+                return Ok(result);
+            }
+            let mut failure = BytesMut::new();
+            failure.put_u8(msg::USERAUTH_FAILURE);
+            wire::put_string(&mut failure, b"password");
+            wire::put_bool(&mut failure, false);
+            self.send_packet(&failure).await?;
+        }
+        Err(Error::Auth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penguin_mux::ws::PlainStream;
+    use tokio::io::DuplexStream;
+
+    type TestTransport = Transport<PlainStream<DuplexStream>>;
+
+    async fn handshake(
+        fingerprint: Option<String>,
+        password: &str,
+    ) -> (Result<TestTransport, Error>, Result<TestTransport, Error>) {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let host_key = Arc::new(HostKey::from_seed("test"));
+        let (client, server) = tokio::join!(
+            Transport::connect(PlainStream::new(client), fingerprint, "user", password),
+            Transport::accept(PlainStream::new(server), host_key, |user, password| {
+                (user == "user" && password == Some("pass")).then_some(())
+            })
+        );
+        (client, server.map(|(server, ())| server))
+    }
+
+    #[tokio::test]
+    async fn test_transport() {
+        crate::tests::setup_logging();
+        let fingerprint = HostKey::from_seed("test").fingerprint();
+        let (client, server) = handshake(Some(fingerprint[..10].to_string()), "pass").await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        client.send_packet(b"\x80hello").await.unwrap();
+        assert_eq!(server.recv_packet().await.unwrap(), &b"\x80hello"[..]);
+        server.send_packet(&[0x80; 1000]).await.unwrap();
+        assert_eq!(client.recv_packet().await.unwrap(), &[0x80; 1000][..]);
+        // Re-keying keeps the session going
+        let session_id = client.session_id.clone();
+        let (client_rekeyed, server_rekeyed) = tokio::join!(client.key_exchange(None), async {
+            let kexinit = server.recv_packet().await.unwrap();
+            assert_eq!(kexinit[0], msg::KEXINIT);
+            server.key_exchange(Some(kexinit)).await
+        });
+        client_rekeyed.unwrap();
+        server_rekeyed.unwrap();
+        assert_eq!(client.session_id, session_id);
+        client.send_packet(b"\x80again").await.unwrap();
+        assert_eq!(server.recv_packet().await.unwrap(), &b"\x80again"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_transport_rejected() {
+        crate::tests::setup_logging();
+        let (client, _) = handshake(None, "wrong").await;
+        assert!(matches!(client, Err(Error::Auth)));
+        let (client, _) = handshake(Some("AAAA".to_string()), "pass").await;
+        assert!(matches!(client, Err(Error::Fingerprint(_))));
+    }
+
+    #[test]
+    fn test_ecdsa_fixed_signature() {
+        crate::tests::setup_logging();
+        let mut blob = BytesMut::new();
+        wire::put_mpint(&mut blob, &[0x80; 32]);
+        wire::put_mpint(&mut blob, &[0, 1]);
+        let fixed = ecdsa_fixed_signature(&blob).unwrap();
+        assert_eq!(fixed[..32], [0x80; 32]);
+        assert_eq!(fixed[32..63], [0; 31]);
+        assert_eq!(fixed[63], 1);
+        let mut blob = BytesMut::new();
+        wire::put_string(&mut blob, &[1; 33]);
+        assert!(ecdsa_fixed_signature(&blob).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Encoding of SSH data types (RFC 4251, section 5).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use bytes::{BufMut, BytesMut};
+
+/// Append a `string`
+pub fn put_string(buf: &mut BytesMut, data: &[u8]) {
+    let len = u32::try_from(data.len()).expect("SSH string too long (this is a bug)");
+    buf.put_u32(len);
+    buf.put_slice(data);
+}
+
+/// Append an unsigned big-endian integer as an `mpint`
+pub fn put_mpint(buf: &mut BytesMut, data: &[u8]) {
+    let start = data.iter().position(|&b| b != 0).unwrap_or(data.len());
+    let data = &data[start..];
+    if data.first().is_some_and(|&b| b & 0x80 != 0) {
+        let len = u32::try_from(data.len() + 1).expect("SSH mpint too long (this is a bug)");
+        buf.put_u32(len);
+        buf.put_u8(0);
+        buf.put_slice(data);
+    } else {
+        put_string(buf, data);
+    }
+}
+
+/// Append a `boolean`
+pub fn put_bool(buf: &mut BytesMut, value: bool) {
+    buf.put_u8(u8::from(value));
+}
+
+/// Reads SSH data types from a message
+#[derive(Debug)]
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let (head, rest) = self
+            .0
+            .split_at_checked(len)
+            .ok_or(Error::Protocol("truncated message"))?;
+        self.0 = rest;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(
+            bytes
+                .try_into()
+                .expect("Slice of the wrong length (this is a bug)"),
+        ))
+    }
+
+    pub fn string(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, Error> {
+        std::str::from_utf8(self.string()?).map_err(|_| Error::Protocol("invalid UTF-8 string"))
+    }
+
+    /// Read a `name-list`
+    pub fn name_list(&mut self) -> Result<Vec<&'a str>, Error> {
+        let names = self.str()?;
+        Ok(names.split(',').filter(|name| !name.is_empty()).collect())
+    }
+
+    /// The unread part of the message
+    pub const fn rest(&self) -> &'a [u8] {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire() {
+        crate::tests::setup_logging();
+        let mut buf = BytesMut::new();
+        put_string(&mut buf, b"abc");
+        put_mpint(&mut buf, &[0, 0, 0x80, 1]);
+        put_mpint(&mut buf, &[0, 0x7f]);
+        put_mpint(&mut buf, &[0, 0]);
+        put_bool(&mut buf, true);
+        put_string(&mut buf, b"a,b");
+        assert_eq!(
+            &buf[..],
+            b"\0\0\0\x03abc\0\0\0\x03\0\x80\x01\0\0\0\x01\x7f\0\0\0\0\x01\0\0\0\x03a,b"
+        );
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.str().unwrap(), "abc");
+        assert_eq!(reader.string().unwrap(), b"\0\x80\x01");
+        assert_eq!(reader.string().unwrap(), b"\x7f");
+        assert_eq!(reader.string().unwrap(), b"");
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.name_list().unwrap(), ["a", "b"]);
+        assert!(reader.rest().is_empty());
+        assert!(reader.u8().is_err());
+    }
+}
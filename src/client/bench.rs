@@ -0,0 +1,185 @@
+//! Measuring the throughput of the tunnel with `penguin bench`.
+//!
+//! See [`crate::bench`] for how the server answers.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use super::bond::mux_options;
+use super::ws_connect::handshake;
+use crate::arg::BenchArgs;
+use crate::bench::{BENCH_HOST, DOWNLOAD, UPLOAD, request};
+use bytes::Bytes;
+use penguin_mux::{Datagram, Multiplexor};
+use std::collections::HashSet;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Octets in a MiB
+const MIB: u64 = 1 << 20;
+/// Interval between two datagrams
+const DATAGRAM_INTERVAL: Duration = Duration::from_millis(1);
+/// How long to wait for the echoes after sending the last datagram
+const DATAGRAM_LINGER: Duration = Duration::from_secs(2);
+
+/// Results of a benchmark
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Report {
+    /// Octets sent and received in each direction
+    pub len: u64,
+    /// How long the upload took
+    pub upload: Duration,
+    /// How long the download took
+    pub download: Duration,
+    /// Number of datagrams sent
+    pub datagrams_sent: u32,
+    /// Number of distinct datagrams echoed by the server
+    pub datagrams_echoed: u32,
+}
+
+/// Describe the throughput of a transfer
+#[allow(clippy::cast_precision_loss)]
+fn throughput(len: u64, elapsed: Duration) -> String {
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+    format!(
+        "{:.2} MiB in {:.2} s, {:.2} Mbit/s",
+        len as f64 / MIB as f64,
+        elapsed.as_secs_f64(),
+        len as f64 * 8.0 / secs / 1e6
+    )
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Upload:    {}", throughput(self.len, self.upload))?;
+        write!(f, "Download:  {}", throughput(self.len, self.download))?;
+        if self.datagrams_sent > 0 {
+            let lost = self.datagrams_sent - self.datagrams_echoed;
+            write!(
+                f,
+                "\nDatagrams: {}/{} echoed, {:.1}% loss",
+                self.datagrams_echoed,
+                self.datagrams_sent,
+                f64::from(lost) * 100.0 / f64::from(self.datagrams_sent)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Send `len` octets to the server and wait for it to confirm them
+async fn upload(mux: &Multiplexor, len: u64) -> Result<Duration, Error> {
+    let mut stream = mux.new_stream_channel(BENCH_HOST, 0).await?;
+    let start = Instant::now();
+    async {
+        stream.write_all(&request(UPLOAD, len)).await?;
+        tokio::io::copy(&mut tokio::io::repeat(0).take(len), &mut stream).await?;
+        let received = stream.read_u64().await?;
+        if received != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("server received {received} octets instead of {len}"),
+            ));
+        }
+        Ok(start.elapsed())
+    }
+    .await
+    .map_err(Error::Bench)
+}
+
+/// Receive `len` octets from the server
+async fn download(mux: &Multiplexor, len: u64) -> Result<Duration, Error> {
+    let mut stream = mux.new_stream_channel(BENCH_HOST, 0).await?;
+    let start = Instant::now();
+    async {
+        stream.write_all(&request(DOWNLOAD, len)).await?;
+        let received = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await?;
+        if received != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("received {received} octets instead of {len}"),
+            ));
+        }
+        Ok(start.elapsed())
+    }
+    .await
+    .map_err(Error::Bench)
+}
+
+/// Send `count` numbered datagrams of `size` octets and count how many come
+/// back
+async fn datagrams(mux: &Multiplexor, count: u32, size: u16) -> Result<u32, Error> {
+    let flow_id = rand::random();
+    let mut echoed = HashSet::new();
+    let mut interval = tokio::time::interval(DATAGRAM_INTERVAL);
+    let mut sent = 0;
+    let deadline = tokio::time::sleep(DATAGRAM_INTERVAL * count + DATAGRAM_LINGER);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if sent < count => {
+                let mut data = vec![0; usize::from(size)];
+                data[..4].copy_from_slice(&sent.to_be_bytes());
+                mux.send_datagram(Datagram {
+                    flow_id,
+                    target_host: Bytes::from_static(BENCH_HOST),
+                    target_port: 0,
+                    data: data.into(),
+                })
+                .await?;
+                sent += 1;
+                if sent == count {
+                    deadline.as_mut().reset(Instant::now() + DATAGRAM_LINGER);
+                }
+            }
+            datagram = mux.get_datagram() => {
+                let datagram = datagram?;
+                if datagram.target_host != BENCH_HOST {
+                    continue;
+                }
+                if let Some(seq) = datagram.data.first_chunk::<4>() {
+                    echoed.insert(u32::from_be_bytes(*seq));
+                }
+                if echoed.len() >= count as usize {
+                    break;
+                }
+            }
+            () = &mut deadline => break,
+        }
+    }
+    Ok(u32::try_from(echoed.len()).unwrap_or(u32::MAX))
+}
+
+/// Connect to the server and run the benchmark
+pub async fn bench(args: &BenchArgs) -> Result<Report, Error> {
+    let (ws_stream, _, agreed) = handshake(&args.client, None).await?;
+    debug!("Connected to {}", args.client.server.0);
+    let mux = Multiplexor::new(ws_stream, Some(mux_options(&args.client, agreed)), None);
+    let len = args.size * MIB;
+    let upload = upload(&mux, len).await?;
+    debug!("upload finished in {upload:?}");
+    let download = download(&mux, len).await?;
+    debug!("download finished in {download:?}");
+    let datagrams_echoed = if args.datagrams > 0 {
+        datagrams(&mux, args.datagrams, args.datagram_size).await?
+    } else {
+        0
+    };
+    Ok(Report {
+        len,
+        upload,
+        download,
+        datagrams_sent: args.datagrams,
+        datagrams_echoed,
+    })
+}
+
+/// Run the benchmark and print the results
+pub async fn bench_main(args: &BenchArgs) -> Result<(), Error> {
+    let report = bench(args).await?;
+    println!("{report}");
+    Ok(())
+}
@@ -0,0 +1,312 @@
+//! Bonding multiple `WebSocket` connections to the server.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::ws_connect::{self, Agreed, SessionReply};
+use super::{Error, WsStream};
+use crate::arg::ClientArgs;
+use futures_util::future::select_all;
+use http::HeaderValue;
+use parking_lot::Mutex;
+use penguin_mux::timing::OptionalDuration;
+use penguin_mux::{Datagram, Dupe, Multiplexor, MuxStream, Resumer};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::task::{JoinError, JoinSet};
+
+/// A multiplexor and what is needed to keep it across reconnections
+#[derive(Debug)]
+struct Session {
+    mux: Arc<Multiplexor>,
+    /// Present if the server agreed to let us resume this session
+    resumer: Option<Resumer<WsStream>>,
+    /// Token that the server issued to identify the session
+    token: Option<HeaderValue>,
+    mux_task_joinset: Mutex<JoinSet<penguin_mux::Result<()>>>,
+    /// Set when the multiplexor task exits or loses its `WebSocket`
+    lost: AtomicBool,
+}
+
+/// Multiplexor options for a connection with the settings the server agreed to
+pub fn mux_options(args: &ClientArgs, agreed: Agreed) -> penguin_mux::config::Options {
+    args.mux.apply(
+        args.limits.apply(
+            penguin_mux::config::Options::new()
+                .keepalive_interval(args.keepalive)
+                .keepalive_timeout(args.keepalive_timeout)
+                .compression(agreed.compression)
+                .compression_threshold(args.compression_threshold)
+                .padding(agreed.padding)
+                .coalesce(agreed.coalesce),
+        ),
+    )
+}
+
+impl Session {
+    /// Multiplex a new connection to the server
+    fn new(ws_stream: WsStream, reply: SessionReply, agreed: Agreed, args: &ClientArgs) -> Self {
+        let mut mux_task_joinset = JoinSet::new();
+        let options = mux_options(args, agreed);
+        let resume_timeout = Option::<Duration>::from(args.resume_timeout);
+        let (mux, resumer, token) = match (reply, resume_timeout) {
+            (SessionReply::New(token), Some(resume_timeout)) => {
+                let (mux, resumer) = Multiplexor::new_resumable(
+                    ws_stream,
+                    Some(options.resume_timeout(resume_timeout)),
+                    Some(&mut mux_task_joinset),
+                );
+                (mux, Some(resumer), Some(token))
+            }
+            _ => (
+                Multiplexor::new(ws_stream, Some(options), Some(&mut mux_task_joinset)),
+                None,
+                None,
+            ),
+        };
+        Self {
+            mux: Arc::new(mux),
+            resumer,
+            token,
+            mux_task_joinset: Mutex::new(mux_task_joinset),
+            lost: AtomicBool::new(false),
+        }
+    }
+
+    /// Wait for the multiplexor task to exit.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    async fn join(&self) -> Option<Result<penguin_mux::Result<()>, JoinError>> {
+        let result =
+            std::future::poll_fn(|cx| self.mux_task_joinset.lock().poll_join_next(cx)).await;
+        self.lost.store(true, Ordering::Relaxed);
+        result
+    }
+
+    /// Wait until the multiplexor task loses its `WebSocket`, if the session
+    /// is resumable.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    async fn detached(&self) {
+        match &self.resumer {
+            Some(resumer) => resumer.detached().await,
+            None => std::future::pending().await,
+        }
+        self.lost.store(true, Ordering::Relaxed);
+    }
+
+    /// Check if the connection is still up
+    fn is_connected(&self) -> bool {
+        if self.lost.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut mux_task_joinset = self.mux_task_joinset.lock();
+        // A finished task means that the connection is gone, whatever its result
+        mux_task_joinset.try_join_next().is_none() && !mux_task_joinset.is_empty()
+    }
+
+    /// Drop the multiplexor and wait for it to flush and close gracefully
+    async fn close(self) -> Result<(), Error> {
+        let Self {
+            mux,
+            mut mux_task_joinset,
+            ..
+        } = self;
+        drop(mux);
+        while let Some(result) = mux_task_joinset.get_mut().join_next().await {
+            result.expect("Task panicked (this is a bug)")?;
+        }
+        Ok(())
+    }
+
+    /// Check if the session can be resumed on a new connection
+    fn is_resumable(&self) -> bool {
+        self.resumer
+            .as_ref()
+            .is_some_and(|resumer| !resumer.is_closed())
+    }
+
+    /// Connect to the server and resume `session` if possible, or replace it
+    /// with a new one.
+    /// Returns whether the session was resumed.
+    async fn connect(args: &ClientArgs, session: &mut Option<Self>) -> Result<bool, Error> {
+        let session_token = match session {
+            Some(current) if current.is_resumable() => current.token.clone(),
+            _ if args.resume_timeout != OptionalDuration::NONE => Some(SESSION_NEW.dupe()),
+            _ => None,
+        };
+        let (ws_stream, reply, agreed) =
+            ws_connect::handshake(args, session_token.as_ref()).await?;
+        match session.take() {
+            Some(current) if reply == SessionReply::Resumed && current.token == session_token => {
+                current
+                    .resumer
+                    .as_ref()
+                    .expect("Resumed a non-resumable session (this is a bug)")
+                    .resume(ws_stream)
+                    .await?;
+                current.lost.store(false, Ordering::Relaxed);
+                *session = Some(current);
+                Ok(true)
+            }
+            // Any previous session is dropped here
+            _ => {
+                *session = Some(Self::new(ws_stream, reply, agreed, args));
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Asks the server for a new resumable session
+static SESSION_NEW: HeaderValue = HeaderValue::from_static("new");
+
+/// Parallel connections to the server.
+/// New streams are spread over the connections in turn, and datagrams are
+/// sent on a connection chosen by their flow ID so that replies come back the
+/// same way. Reverse remotes are requested on the first connection.
+#[derive(Debug)]
+pub struct Bond {
+    sessions: Vec<Option<Session>>,
+    /// Index of the connection to open the next stream on
+    next_stream: AtomicUsize,
+}
+
+impl Bond {
+    /// Create a bond of `connections` connections. They are not connected
+    /// until [`Bond::connect`] is called.
+    pub fn new(connections: usize) -> Self {
+        Self {
+            sessions: (0..connections.max(1)).map(|_| None).collect(),
+            next_stream: AtomicUsize::new(0),
+        }
+    }
+
+    /// Connect the connections that are not connected or have been lost,
+    /// resuming their sessions where possible. Connections that are still up
+    /// are left alone with their streams.
+    /// Returns whether the first connection kept its session, either because
+    /// it was still up or because it was resumed.
+    pub async fn connect(&mut self, args: &ClientArgs) -> Result<bool, Error> {
+        let mut primary_kept = false;
+        for (idx, session) in self.sessions.iter_mut().enumerate() {
+            let kept = if session.as_ref().is_some_and(Session::is_connected) {
+                true
+            } else {
+                Session::connect(args, session).await?
+            };
+            if idx == 0 {
+                primary_kept = kept;
+            }
+        }
+        Ok(primary_kept)
+    }
+
+    /// Forget the connections that have been lost and cannot be resumed
+    /// after a failure. The others keep their streams.
+    pub fn retain_usable(&mut self) {
+        for session in &mut self.sessions {
+            if !session
+                .as_ref()
+                .is_some_and(|session| session.is_connected() || session.is_resumable())
+            {
+                *session = None;
+            }
+        }
+    }
+
+    /// Close all connections gracefully
+    pub async fn close(&mut self) -> Result<(), Error> {
+        for session in self.sessions.iter_mut().filter_map(Option::take) {
+            session.close().await?;
+        }
+        Ok(())
+    }
+
+    fn connected(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.iter().flatten()
+    }
+
+    /// The multiplexor of the first connection
+    pub fn primary(&self) -> &Multiplexor {
+        &self
+            .connected()
+            .next()
+            .expect("Bond is not connected (this is a bug)")
+            .mux
+    }
+
+    /// The multiplexors of the connections, for reporting their status
+    pub fn muxes(&self) -> Vec<Weak<Multiplexor>> {
+        self.connected()
+            .map(|session| Arc::downgrade(&session.mux))
+            .collect()
+    }
+
+    /// The multiplexor to open the next stream on
+    pub fn next_stream_mux(&self) -> &Multiplexor {
+        let idx = self.next_stream.fetch_add(1, Ordering::Relaxed) % self.sessions.len();
+        self.sessions[idx]
+            .as_ref()
+            .map_or_else(|| self.primary(), |session| &session.mux)
+    }
+
+    /// The multiplexor to send datagrams of `flow_id` on
+    pub fn datagram_mux(&self, flow_id: u32) -> &Multiplexor {
+        // `u32` always fits in a `usize` on supported platforms
+        let idx = flow_id as usize % self.sessions.len();
+        self.sessions[idx]
+            .as_ref()
+            .map_or_else(|| self.primary(), |session| &session.mux)
+    }
+
+    /// Wait for the first result of `f` on any connection.
+    async fn race<'a, T, F, Fut>(&'a self, f: F) -> T
+    where
+        F: Fn(&'a Session) -> Fut,
+        Fut: Future<Output = T> + 'a,
+    {
+        if let [Some(session)] = self.sessions.as_slice() {
+            // Avoid allocating for the common case
+            return f(session).await;
+        }
+        let futures = self.connected().map(|session| Box::pin(f(session)));
+        select_all(futures).await.0
+    }
+
+    /// Accept a new stream channel from the server on any connection.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn accept_stream_channel(&self) -> penguin_mux::Result<MuxStream> {
+        self.race(|session| session.mux.accept_stream_channel())
+            .await
+    }
+
+    /// Get the next datagram from any connection.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn get_datagram(&self) -> penguin_mux::Result<Datagram> {
+        self.race(|session| session.mux.get_datagram()).await
+    }
+
+    /// Wait for the multiplexor task of any connection to exit.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn join(&self) -> Option<Result<penguin_mux::Result<()>, JoinError>> {
+        self.race(Session::join).await
+    }
+
+    /// Wait until any resumable connection loses its `WebSocket`.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn detached(&self) {
+        self.race(Session::detached).await;
+    }
+}
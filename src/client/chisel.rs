@@ -0,0 +1,235 @@
+//! Client of the `chisel` compatibility mode (`--chisel`).
+//!
+//! Every remote gets a local listener, and every accepted connection opens
+//! a `chisel` channel to `host:port` on the current SSH connection. The
+//! remotes are sent to the server in the `config` request, which lets it
+//! check them against the user's permissions.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use super::handle_remote::FatalError;
+use super::maybe_retryable::MaybeRetryableError;
+use super::ws_connect::chisel_connect;
+use crate::arg::ClientArgs;
+use crate::chisel::connection::{self, Connection};
+use crate::chisel::transport::Transport;
+use crate::chisel::{Config, RemoteConfig};
+use crate::parse_remote::{LocalSpec, Protocol, Remote, RemoteSpec};
+use bytes::Bytes;
+use penguin_mux::timing::{Backoff, OptionalDuration, OptionalInterval};
+use std::convert::Infallible;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{debug, info, warn};
+
+/// Translate a remote to what the `chisel` server expects, if it can
+fn remote_config(remote: &Remote) -> Option<RemoteConfig> {
+    match (&remote.local_addr, &remote.remote_addr) {
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Inet((rhost, rport)))
+            if remote.protocol == Protocol::Tcp && !remote.reverse =>
+        {
+            Some(RemoteConfig {
+                local_host: lhost.clone(),
+                local_port: lport.to_string(),
+                local_proto: "tcp".to_string(),
+                remote_host: rhost.clone(),
+                remote_port: rport.to_string(),
+                remote_proto: "tcp".to_string(),
+                ..Default::default()
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The extra data of the channels to a remote
+fn channel_target(remote: &RemoteConfig) -> Bytes {
+    let host = &remote.remote_host;
+    if host.contains(':') {
+        format!("[{host}]:{}", remote.remote_port).into()
+    } else {
+        format!("{host}:{}", remote.remote_port).into()
+    }
+}
+
+/// Accept connections on `listener` and tunnel them to `target` through
+/// the current connection, waiting for one if we are reconnecting
+async fn serve_remote(
+    listener: TcpListener,
+    target: Bytes,
+    connection: watch::Receiver<Option<Connection>>,
+    channel_timeout: OptionalDuration,
+) -> std::io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let mut connection = connection.clone();
+        let target = target.clone();
+        tokio::spawn(async move {
+            let channel = channel_timeout.timeout(async {
+                let connection = connection.wait_for(Option::is_some).await.ok()?.clone()?;
+                connection
+                    .open(target)
+                    .await
+                    .inspect_err(|err| warn!("Cannot open a chisel channel for {peer}: {err}"))
+                    .ok()
+            });
+            match channel.await {
+                Ok(Some(channel)) => {
+                    if let Err(err) = channel.pipe(stream).await {
+                        debug!("chisel channel for {peer} failed: {err}");
+                    }
+                }
+                Ok(None) => {}
+                Err(_) => warn!("Timed out opening a chisel channel for {peer}"),
+            }
+        });
+    }
+}
+
+/// The error that ended the connection task
+fn task_error(result: Result<Result<(), crate::chisel::Error>, JoinError>) -> Error {
+    match result {
+        Ok(Err(err)) => err.into(),
+        _ => Error::RemoteDisconnected,
+    }
+}
+
+/// Connect, send the configuration and keep the connection alive until it
+/// fails
+async fn run_connection(
+    args: &ClientArgs,
+    config: &Bytes,
+    connection_tx: &watch::Sender<Option<Connection>>,
+    backoff: &mut Backoff,
+) -> Result<Infallible, Error> {
+    let ws = chisel_connect(args).await?;
+    let (user, password) = args
+        .auth
+        .as_ref()
+        .map_or(("", ""), |auth| (&auth.username, &auth.password));
+    let transport = args
+        .handshake_timeout
+        .timeout(Transport::connect(
+            ws,
+            args.fingerprint.clone(),
+            user,
+            password,
+        ))
+        .await
+        .map_err(|_| Error::HandshakeTimeout)??;
+    let (connection, _, task) = connection::start(transport);
+    let mut task = tokio::spawn(task);
+    let Ok((accepted, reply)) = connection.request("config", config.clone()).await else {
+        return Err(task_error(task.await));
+    };
+    if !accepted {
+        let reason = String::from_utf8_lossy(&reply).into_owned();
+        return Err(crate::chisel::Error::Config(reason).into());
+    }
+    info!("Connected to the chisel server");
+    backoff.reset();
+    connection_tx.send_replace(Some(connection.clone()));
+    let mut keepalive = OptionalInterval::from(args.keepalive);
+    // The first tick is immediate
+    keepalive.tick().await;
+    loop {
+        tokio::select! {
+            result = &mut task => return Err(task_error(result)),
+            _ = keepalive.tick() => {
+                let connection = connection.clone();
+                tokio::spawn(async move { connection.request("ping", Bytes::new()).await });
+            }
+        }
+    }
+}
+
+/// Run the client with `--chisel`
+pub async fn chisel_main(args: &ClientArgs) -> Result<(), Error> {
+    let mut remotes = Vec::new();
+    for remote in args.remotes() {
+        let Some(config) = remote_config(remote) else {
+            return Err(Error::ChiselRemote(remote.to_string()));
+        };
+        remotes.push(config);
+    }
+    let (connection_tx, connection_rx) = watch::channel(None);
+    let mut listeners = JoinSet::new();
+    for remote in &remotes {
+        let port = remote
+            .local_port
+            .parse::<u16>()
+            .expect("Port from a parsed remote (this is a bug)");
+        let listener = TcpListener::bind((remote.local_host.as_str(), port))
+            .await
+            .map_err(FatalError::ClientIo)?;
+        if let Ok(addr) = listener.local_addr() {
+            info!("Listening on {addr}");
+        }
+        let target = channel_target(remote);
+        listeners.spawn(serve_remote(
+            listener,
+            target,
+            connection_rx.clone(),
+            args.channel_timeout,
+        ));
+    }
+    let config = Config {
+        version: concat!("penguin-", env!("CARGO_PKG_VERSION")).to_string(),
+        remotes,
+    };
+    let config = Bytes::from(
+        serde_json::to_vec(&config).expect("Cannot serialize the configuration (this is a bug)"),
+    );
+    let main_future = async {
+        let mut backoff = super::reconnect_backoff(args);
+        loop {
+            let Err(err) = run_connection(args, &config, &connection_tx, &mut backoff).await;
+            connection_tx.send_replace(None);
+            if !err.retryable() {
+                return Err(err);
+            }
+            warn!("Connection failed: {err}");
+            let Some(interval) = backoff.advance() else {
+                warn!("Max retry count reached, giving up");
+                return Err(Error::MaxRetryCountReached(Box::new(err)));
+            };
+            warn!("Reconnecting in {interval:?}");
+            tokio::time::sleep(interval).await;
+        }
+    };
+    tokio::select! {
+        Some(result) = listeners.join_next() => {
+            let result = result.expect("Listener task panicked (this is a bug)");
+            let err = result.err().unwrap_or_else(|| std::io::Error::other("listener stopped"));
+            Err(FatalError::ClientIo(err).into())
+        }
+        result = main_future => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_remote_config() {
+        crate::tests::setup_logging();
+        let remote = Remote::from_str("127.0.0.1:3000:[::1]:80").unwrap();
+        let config = remote_config(&remote).unwrap();
+        assert_eq!(config.local_port, "3000");
+        assert_eq!(config.remote_host, "::1");
+        assert_eq!(channel_target(&config), "[::1]:80");
+        for remote in [
+            "socks",
+            "1.1.1.1:53/udp",
+            "R:2222:localhost:22",
+            "stdio:example.com:22",
+        ] {
+            let remote = Remote::from_str(remote).unwrap();
+            assert!(remote_config(&remote).is_none());
+        }
+    }
+}
@@ -0,0 +1,934 @@
+//! Runtime management of remotes.
+//!
+//! Forward remotes run as `handle_remote` tasks owned by [`RemoteTasks`], which
+//! makes them addressable by ID so that they can be listed, added, and removed
+//! while the client is running. With `--control`, the client accepts
+//! line-based commands on a Unix socket or a TCP address:
+//!
+//! - `list`: one line `<id> <remote>` for each running remote, followed by
+//!   ` paused` if it is paused
+//! - `add <remote>`: start a new remote and reply with its ID
+//! - `remove <id>`: stop a remote
+//! - `pause <name>`: close the listeners of the remotes named `<name>`
+//!   (e.g. `web=8080:localhost:80`) and reply with their number
+//! - `resume <name>`: listen again for the paused remotes named `<name>`
+//!
+//! Each response ends with a line that is either `ok`, optionally followed
+//! by a value, or `error: <reason>`.
+//!
+//! On SIGHUP, the remotes from the command line and the config file are
+//! reloaded: listeners of removed remotes are stopped and added remotes are
+//! started, while the WebSocket session stays alive. Remotes added with the
+//! control socket are left alone. Added remotes that fail to start are
+//! reported, and the client keeps running even if no remote is left.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::HandlerResources;
+use super::handle_remote::{FatalError, handle_remote};
+use super::traffic::RemoteTraffic;
+use crate::arg::{Commands, PenguinCli};
+use crate::config;
+use crate::parse_remote::{LocalSpec, Remote};
+use penguin_mux::Dupe;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{debug, error, info, warn};
+
+/// A running remote as listed by [`RemoteTasks::list`]: its ID, the remote,
+/// its traffic and whether it is paused
+pub(super) type ListedRemote = (u64, Arc<Remote>, Arc<RemoteTraffic>, bool);
+
+/// Commands sent from control connections to [`RemoteTasks::run`]
+#[derive(Debug)]
+pub(super) enum ControlCommand {
+    /// List the running remotes with their traffic
+    List(oneshot::Sender<Vec<ListedRemote>>),
+    /// Start a new remote and reply with its ID
+    Add(Remote, oneshot::Sender<u64>),
+    /// Stop a remote and reply whether it existed
+    Remove(u64, oneshot::Sender<bool>),
+    /// Pause or resume the remotes with a name and reply with their number
+    SetPaused(String, bool, oneshot::Sender<usize>),
+}
+
+/// Where a remote comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Origin {
+    /// Command line or config file at startup. The client exits if it fails.
+    Startup,
+    /// Command line or config file on SIGHUP
+    Reload,
+    /// Control socket
+    Control,
+}
+
+/// A `handle_remote` task in [`RemoteTasks`]
+#[derive(Debug)]
+struct RemoteTask {
+    abort_handle: AbortHandle,
+    /// Closed once the task has been dropped along with its listeners
+    dropped: oneshot::Receiver<()>,
+}
+
+impl RemoteTask {
+    /// Abort the task and wait until its listeners are closed
+    async fn stop(self) {
+        self.abort_handle.abort();
+        // Nothing is ever sent, so this always returns `Err`
+        self.dropped.await.ok();
+    }
+}
+
+/// A running `handle_remote` task
+#[derive(Debug)]
+struct RunningRemote {
+    remote: Arc<Remote>,
+    traffic: Arc<RemoteTraffic>,
+    handler_resources: Arc<HandlerResources>,
+    /// `None` once a paused remote has exited. Paused remotes keep their
+    /// aborted task until then so that resuming them can wait for it.
+    task: Option<RemoteTask>,
+    paused: bool,
+    /// Whether the remote has been resumed, so that it is paused again if
+    /// it fails
+    resumed: bool,
+    origin: Origin,
+}
+
+/// The set of running forward remotes
+#[derive(Debug)]
+pub(super) struct RemoteTasks {
+    handler_resources: Arc<HandlerResources>,
+    tasks: JoinSet<(u64, Result<(), FatalError>)>,
+    running: BTreeMap<u64, RunningRemote>,
+    /// Tasks that exited while `update_remotes` waited for new remotes
+    exited: VecDeque<(u64, Result<(), FatalError>)>,
+    next_id: u64,
+    /// Reverse remotes from startup, which cannot be reloaded
+    reverse: Vec<Remote>,
+}
+
+impl RemoteTasks {
+    /// Create an empty set of remotes
+    pub fn new(handler_resources: Arc<HandlerResources>) -> Self {
+        Self {
+            handler_resources,
+            tasks: JoinSet::new(),
+            running: BTreeMap::new(),
+            exited: VecDeque::new(),
+            next_id: 1,
+            reverse: Vec::new(),
+        }
+    }
+
+    /// Spawn the forward remotes from the command line. Reverse remotes are
+    /// handled in the main loop instead.
+    pub fn spawn_startup(&mut self, remotes: impl IntoIterator<Item = Remote>) {
+        for remote in remotes {
+            if remote.reverse {
+                self.reverse.push(remote);
+            } else {
+                self.spawn(remote, Origin::Startup);
+            }
+        }
+    }
+
+    /// Spawn a `handle_remote` task and return its ID.
+    fn spawn(&mut self, remote: Remote, origin: Origin) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let remote = Arc::new(remote);
+        let traffic = Arc::new(RemoteTraffic::default());
+        let handler_resources = Arc::new(self.handler_resources.for_remote(traffic.dupe()));
+        let task = start(&mut self.tasks, id, remote.dupe(), handler_resources.dupe());
+        self.running.insert(
+            id,
+            RunningRemote {
+                remote,
+                traffic,
+                handler_resources,
+                task: Some(task),
+                paused: false,
+                resumed: false,
+                origin,
+            },
+        );
+        id
+    }
+
+    /// Stop a remote and wait until its listeners are closed.
+    /// Returns `false` if there is no such remote.
+    pub async fn abort(&mut self, id: u64) -> bool {
+        let Some(running) = self.running.remove(&id) else {
+            return false;
+        };
+        if let Some(task) = running.task {
+            task.stop().await;
+        }
+        info!("Stopped remote {}", running.remote);
+        true
+    }
+
+    /// Pause or resume the remotes named `name`, keeping their IDs.
+    /// Returns the number of such remotes.
+    async fn set_paused(&mut self, name: &str, paused: bool) -> usize {
+        let mut count = 0;
+        for (id, running) in &mut self.running {
+            if running.remote.name.as_deref() != Some(name) {
+                continue;
+            }
+            count += 1;
+            if running.paused == paused {
+                continue;
+            }
+            running.paused = paused;
+            if paused {
+                if let Some(task) = &running.task {
+                    task.abort_handle.abort();
+                }
+                info!("Paused remote {}", running.remote);
+            } else {
+                // The old listeners must be closed before binding again
+                if let Some(task) = running.task.take() {
+                    task.stop().await;
+                }
+                // The client has been running fine without this remote
+                if running.origin == Origin::Startup {
+                    running.origin = Origin::Reload;
+                }
+                running.resumed = true;
+                running.task = Some(start(
+                    &mut self.tasks,
+                    *id,
+                    running.remote.dupe(),
+                    running.handler_resources.dupe(),
+                ));
+                info!("Resumed remote {}", running.remote);
+            }
+        }
+        count
+    }
+
+    /// List the running remotes with their traffic
+    pub fn list(&self) -> Vec<ListedRemote> {
+        self.running
+            .iter()
+            .map(|(id, running)| {
+                (
+                    *id,
+                    running.remote.dupe(),
+                    running.traffic.dupe(),
+                    running.paused,
+                )
+            })
+            .collect()
+    }
+
+    /// Whether no remote is running
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Wait for a task to exit, starting with those that `update_remotes`
+    /// has already seen. Returns `None` if no task is left.
+    async fn next_exited(&mut self) -> Option<(u64, Result<(), FatalError>)> {
+        if let Some(exited) = self.exited.pop_front() {
+            return Some(exited);
+        }
+        loop {
+            match self.tasks.join_next().await? {
+                Ok(exited) => return Some(exited),
+                // Aborted tasks have already been removed from `running`
+                Err(err) if err.is_cancelled() => {}
+                Err(err) => panic!("Remote handler panicked (this is a bug): {err}"),
+            }
+        }
+    }
+
+    /// Wait for a remote to exit. Returns `None` if no remote is running.
+    /// Errors are only returned for startup remotes; others are logged.
+    pub async fn join_next(&mut self) -> Option<Result<(), FatalError>> {
+        loop {
+            let (id, result) = self.next_exited().await?;
+            // The remote exited by itself right before it was stopped
+            let Some(running) = self.running.get_mut(&id) else {
+                continue;
+            };
+            // or right before it was paused
+            if running.paused {
+                running.task = None;
+                continue;
+            }
+            if running.resumed
+                && let Err(err) = &result
+            {
+                error!("Failed to resume remote {}: {err}", running.remote);
+                running.task = None;
+                running.paused = true;
+                continue;
+            }
+            let running = self
+                .running
+                .remove(&id)
+                .expect("Exited remote is not in `running` (this is a bug)");
+            match result {
+                Err(err) if running.origin != Origin::Startup => {
+                    error!("Remote {} failed: {err}", running.remote);
+                }
+                result => return Some(result),
+            }
+        }
+    }
+
+    /// Handle a command from a control connection
+    async fn handle_command(&mut self, command: ControlCommand) {
+        // `Err` from `send` means that the connection is gone, which is fine
+        match command {
+            ControlCommand::List(tx) => {
+                tx.send(self.list()).ok();
+            }
+            ControlCommand::Add(remote, tx) => {
+                info!("Adding remote {remote}");
+                tx.send(self.spawn(remote, Origin::Control)).ok();
+            }
+            ControlCommand::Remove(id, tx) => {
+                tx.send(self.abort(id).await).ok();
+            }
+            ControlCommand::SetPaused(name, paused, tx) => {
+                tx.send(self.set_paused(&name, paused).await).ok();
+            }
+        }
+    }
+
+    /// Re-read the remotes from the command line and the config file
+    async fn reload(&mut self) {
+        info!("Reloading remotes");
+        let args = match PenguinCli::try_parse_merged() {
+            Ok(PenguinCli {
+                subcommand: Commands::Client(args),
+                ..
+            }) => args,
+            Ok(_) => unreachable!("The client is running, so the subcommand is `client`"),
+            Err(err) => {
+                error!("Failed to reload remotes: {err}");
+                return;
+            }
+        };
+        let mut remotes = args.remotes().cloned().collect::<Vec<_>>();
+        remotes.extend(args.proxy_dns.map(super::proxy_dns::remote));
+        let failed = self.update_remotes(remotes).await;
+        if failed.is_empty() {
+            info!("Reloaded remotes");
+        }
+        for (remote, err) in failed {
+            error!("Failed to start reloaded remote {remote}: {err}");
+        }
+    }
+
+    /// Stop the remotes from the command line and the config file that are not
+    /// in `remotes` and start the ones that are new once the old listeners are
+    /// closed. Returns the new remotes that failed within
+    /// [`config::RELOAD_BIND_TIMEOUT`], e.g. because their port is in use.
+    async fn update_remotes(&mut self, remotes: Vec<Remote>) -> Vec<(Arc<Remote>, FatalError)> {
+        let (reverse, forward): (Vec<_>, Vec<_>) =
+            remotes.into_iter().partition(|remote| remote.reverse);
+        if reverse.len() != self.reverse.len()
+            || reverse.iter().any(|remote| !self.reverse.contains(remote))
+        {
+            warn!("Changes to reverse remotes require a restart");
+        }
+        let stale = self
+            .running
+            .iter()
+            .filter(|(_, running)| running.origin != Origin::Control)
+            .filter(|(_, running)| !forward.contains(&running.remote))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in stale {
+            self.abort(id).await;
+        }
+        let mut started = Vec::new();
+        for remote in forward {
+            let exists = self
+                .running
+                .values()
+                .any(|running| running.origin != Origin::Control && *running.remote == remote);
+            if !exists {
+                info!("Adding remote {remote}");
+                started.push(self.spawn(remote, Origin::Reload));
+            }
+        }
+        self.collect_failures(started).await
+    }
+
+    /// Wait up to [`config::RELOAD_BIND_TIMEOUT`] for the remotes with IDs in
+    /// `started` to fail. Other tasks that exit meanwhile are kept for
+    /// `join_next`.
+    async fn collect_failures(&mut self, mut started: Vec<u64>) -> Vec<(Arc<Remote>, FatalError)> {
+        let mut failed = Vec::new();
+        let mut timeout = std::pin::pin!(tokio::time::sleep(config::RELOAD_BIND_TIMEOUT));
+        while !started.is_empty() {
+            let exited = tokio::select! {
+                exited = self.next_exited() => exited,
+                () = &mut timeout => break,
+            };
+            let Some((id, result)) = exited else {
+                break;
+            };
+            let is_started = started.contains(&id);
+            started.retain(|started_id| *started_id != id);
+            match result {
+                Err(err) if is_started => {
+                    let running = self
+                        .running
+                        .remove(&id)
+                        .expect("Started remote is not in `running` (this is a bug)");
+                    failed.push((running.remote, err));
+                }
+                result => self.exited.push_back((id, result)),
+            }
+        }
+        failed
+    }
+
+    /// Supervise the remotes and handle control commands and SIGHUP.
+    /// Returns when all remotes have exited, unless `keep_alive` is set or
+    /// the remotes have been reloaded, or when a startup remote fails.
+    ///
+    /// # Errors
+    /// Returns the error of a failed startup remote.
+    pub async fn run(
+        mut self,
+        mut control_rx: mpsc::Receiver<ControlCommand>,
+        mut keep_alive: bool,
+    ) -> Result<(), FatalError> {
+        let mut hangup = Hangup::new();
+        loop {
+            if self.is_empty() && !keep_alive {
+                // Quit if there is no more listeners, which means we don't need
+                // to exist anymore
+                return Ok(());
+            }
+            tokio::select! {
+                Some(result) = self.join_next() => result?,
+                Some(command) = control_rx.recv() => self.handle_command(command).await,
+                () = hangup.recv() => {
+                    self.reload().await;
+                    // Another SIGHUP can bring remotes back
+                    keep_alive = true;
+                }
+            }
+        }
+    }
+}
+
+/// Run the `handle_remote` task of the remote with ID `id` in `tasks`
+fn start(
+    tasks: &mut JoinSet<(u64, Result<(), FatalError>)>,
+    id: u64,
+    remote: Arc<Remote>,
+    handler_resources: Arc<HandlerResources>,
+) -> RemoteTask {
+    let (dropped_tx, dropped) = oneshot::channel();
+    let abort_handle = tasks.spawn(async move {
+        // Dropped after the future of `handle_remote`, which owns the listeners
+        let _dropped_tx: oneshot::Sender<()> = dropped_tx;
+        (id, handle_remote(&remote, &handler_resources).await)
+    });
+    RemoteTask {
+        abort_handle,
+        dropped,
+    }
+}
+
+/// Listener for SIGHUP, which never fires on platforms without it
+#[derive(Debug)]
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+            // Reloading re-reads our command line, so a program embedding
+            // the client keeps its own SIGHUP handling
+            if crate::arg::ARGS.get().is_none() {
+                return Self { signal: None };
+            }
+            let signal = signal(SignalKind::hangup())
+                .inspect_err(|err| warn!("Cannot listen for SIGHUP: {err}"))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Wait for the next SIGHUP
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal
+            && signal.recv().await.is_some()
+        {
+            return;
+        }
+        std::future::pending::<()>().await;
+    }
+}
+
+/// A listener for control connections
+#[derive(Debug)]
+pub(super) enum ControlListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl ControlListener {
+    /// Listen on `addr`, which is either a loopback socket address or a Unix
+    /// socket path.
+    pub async fn bind(addr: &str) -> std::io::Result<Self> {
+        if let Ok(addr) = SocketAddr::from_str(addr) {
+            // Commands are not authenticated, so only local processes may send them
+            if !addr.ip().is_loopback() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "control address must be a loopback address",
+                ));
+            }
+            return Ok(Self::Tcp(TcpListener::bind(addr).await?));
+        }
+        #[cfg(unix)]
+        {
+            Ok(Self::Unix(bind_private_unix(std::path::Path::new(addr))?))
+        }
+        #[cfg(not(unix))]
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "control address must be a socket address",
+        ))
+    }
+
+    /// Accept control connections forever.
+    ///
+    /// # Errors
+    /// Returns an error if accepting a connection fails.
+    pub async fn serve(self, control_tx: mpsc::Sender<ControlCommand>) -> std::io::Result<()> {
+        loop {
+            let control_tx = control_tx.clone();
+            match &self {
+                Self::Tcp(listener) => {
+                    let (stream, peer) = listener.accept().await?;
+                    debug!("control connection from {peer}");
+                    tokio::spawn(handle_control_connection(stream, control_tx));
+                }
+                #[cfg(unix)]
+                Self::Unix(listener) => {
+                    let (stream, _) = listener.accept().await?;
+                    debug!("control connection");
+                    tokio::spawn(handle_control_connection(stream, control_tx));
+                }
+            }
+        }
+    }
+}
+
+/// Bind a Unix socket at `path` that only we can connect to.
+///
+/// Anyone who can connect can add remotes, so the socket is bound in a
+/// directory only we can enter and moved into place once its mode is 0600.
+#[cfg(unix)]
+fn bind_private_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    match std::fs::symlink_metadata(path) {
+        // Remove a stale socket from a previous run
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "control socket path exists and is not a socket",
+            ));
+        }
+        Err(_) => {}
+    }
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "control socket path has no file name",
+        )
+    })?;
+    let private_dir =
+        path.with_file_name(format!(".{}.{}", file_name.display(), std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+    let private_path = private_dir.join(file_name);
+    let result = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    if result.is_err() {
+        std::fs::remove_file(&private_path).ok();
+    }
+    std::fs::remove_dir(&private_dir)?;
+    result
+}
+
+/// Handle commands on a control connection until it is closed
+async fn handle_control_connection<S>(stream: S, control_tx: mpsc::Sender<ControlCommand>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match stream.read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(err) => {
+                warn!("Failed to read control command: {err}");
+                return;
+            }
+        }
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        let response = match execute_command(command, &control_tx).await {
+            Ok(response) => response,
+            Err(reason) => format!("error: {reason}\n"),
+        };
+        if let Err(err) = stream.get_mut().write_all(response.as_bytes()).await {
+            warn!("Failed to write control response: {err}");
+            return;
+        }
+    }
+}
+
+/// Execute a control command and format the response
+async fn execute_command(
+    command: &str,
+    control_tx: &mpsc::Sender<ControlCommand>,
+) -> Result<String, String> {
+    const EXITED: &str = "client is exiting";
+    let (verb, argument) = command.split_once(' ').unwrap_or((command, ""));
+    let argument = argument.trim();
+    match verb {
+        "list" => {
+            let (tx, rx) = oneshot::channel();
+            control_tx
+                .send(ControlCommand::List(tx))
+                .await
+                .or(Err(EXITED))?;
+            let remotes = rx.await.or(Err(EXITED))?;
+            let mut lines = remotes
+                .into_iter()
+                .map(|(id, remote, _, paused)| {
+                    if paused {
+                        format!("{id} {remote} paused")
+                    } else {
+                        format!("{id} {remote}")
+                    }
+                })
+                .collect::<Vec<_>>();
+            lines.push("ok\n".into());
+            Ok(lines.join("\n"))
+        }
+        "add" => {
+            let remote = Remote::from_str(argument).map_err(|err| err.to_string())?;
+            if remote.reverse {
+                return Err("reverse remotes cannot be added at runtime".into());
+            }
+            if remote.local_addr == LocalSpec::Stdio {
+                return Err("stdio remotes cannot be added at runtime".into());
+            }
+            let (tx, rx) = oneshot::channel();
+            control_tx
+                .send(ControlCommand::Add(remote, tx))
+                .await
+                .or(Err(EXITED))?;
+            let id = rx.await.or(Err(EXITED))?;
+            Ok(format!("ok {id}\n"))
+        }
+        "remove" => {
+            let id = argument.parse().or(Err("invalid remote ID"))?;
+            let (tx, rx) = oneshot::channel();
+            control_tx
+                .send(ControlCommand::Remove(id, tx))
+                .await
+                .or(Err(EXITED))?;
+            if rx.await.or(Err(EXITED))? {
+                Ok("ok\n".into())
+            } else {
+                Err("no such remote".into())
+            }
+        }
+        "pause" | "resume" => {
+            let (tx, rx) = oneshot::channel();
+            control_tx
+                .send(ControlCommand::SetPaused(
+                    argument.to_string(),
+                    verb == "pause",
+                    tx,
+                ))
+                .await
+                .or(Err(EXITED))?;
+            match rx.await.or(Err(EXITED))? {
+                0 => Err("no such remote".into()),
+                count => Ok(format!("ok {count}\n")),
+            }
+        }
+        _ => Err(format!("unknown command `{verb}`")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientIdMaps, RwLock, SocksPolicy};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, duplex};
+
+    fn make_handler_resources() -> Arc<HandlerResources> {
+        let (stream_command_tx, stream_command_rx) = mpsc::channel(1);
+        let (datagram_tx, datagram_rx) = mpsc::channel(1);
+        // Keep the main loop side alive
+        Box::leak(Box::new((stream_command_rx, datagram_rx)));
+        Arc::new(HandlerResources {
+            stream_command_tx,
+            datagram_tx,
+            udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
+            socks_policy: SocksPolicy::default(),
+            buffer_pool: penguin_mux::BufferPool::new(),
+            traffic: Arc::default(),
+            udp_timeout: crate::config::UDP_PRUNE_TIMEOUT,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_control_commands() {
+        crate::tests::setup_logging();
+        let mut remote_tasks = RemoteTasks::new(make_handler_resources());
+        remote_tasks.spawn_startup([Remote::from_str("127.0.0.1:0:example.com:80").unwrap()]);
+        let (control_tx, control_rx) = mpsc::channel(1);
+        tokio::spawn(remote_tasks.run(control_rx, true));
+        let (client, server) = duplex(1024);
+        tokio::spawn(handle_control_connection(server, control_tx));
+        let mut client = BufReader::new(client);
+        let mut request = async |command: &str| {
+            client
+                .get_mut()
+                .write_all(format!("{command}\n").as_bytes())
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            loop {
+                let mut line = String::new();
+                client.read_line(&mut line).await.unwrap();
+                let line = line.trim_end().to_string();
+                let done = line.starts_with("ok") || line.starts_with("error: ");
+                response.push(line);
+                if done {
+                    return response;
+                }
+            }
+        };
+        assert_eq!(
+            request("list").await,
+            ["1 127.0.0.1:0:example.com:80/tcp", "ok"]
+        );
+        assert_eq!(request("add 127.0.0.1:0:socks").await, ["ok 2"]);
+        assert_eq!(
+            request("list").await,
+            [
+                "1 127.0.0.1:0:example.com:80/tcp",
+                "2 127.0.0.1:0:socks/tcp",
+                "ok"
+            ]
+        );
+        assert_eq!(request("remove 1").await, ["ok"]);
+        assert_eq!(request("remove 1").await, ["error: no such remote"]);
+        assert_eq!(request("list").await, ["2 127.0.0.1:0:socks/tcp", "ok"]);
+        assert!(request("add R:2222:localhost:22").await[0].starts_with("error: "));
+        assert!(request("add stdio:localhost:22").await[0].starts_with("error: "));
+        assert!(request("frobnicate").await[0].starts_with("error: "));
+        // Named remotes can be paused and resumed
+        assert_eq!(
+            request("add web=127.0.0.1:0:example.com:80").await,
+            ["ok 3"]
+        );
+        assert_eq!(request("pause web").await, ["ok 1"]);
+        assert_eq!(
+            request("list").await,
+            [
+                "2 127.0.0.1:0:socks/tcp",
+                "3 web=127.0.0.1:0:example.com:80/tcp paused",
+                "ok"
+            ]
+        );
+        assert_eq!(request("resume web").await, ["ok 1"]);
+        assert_eq!(
+            request("list").await,
+            [
+                "2 127.0.0.1:0:socks/tcp",
+                "3 web=127.0.0.1:0:example.com:80/tcp",
+                "ok"
+            ]
+        );
+        assert_eq!(request("pause db").await, ["error: no such remote"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_socket_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        crate::tests::setup_logging();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("control.sock");
+        let path = path.to_str().unwrap();
+        let listener = ControlListener::bind(path).await.unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The private directory it was bound in is gone
+        assert_eq!(std::fs::read_dir(tmpdir.path()).unwrap().count(), 1);
+        tokio::net::UnixStream::connect(path).await.unwrap();
+        // A stale socket is replaced, but other files are not
+        drop(listener);
+        let _listener = ControlListener::bind(path).await.unwrap();
+        let file = tmpdir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let err = ControlListener::bind(file.to_str().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn test_control_tcp_loopback_only() {
+        crate::tests::setup_logging();
+        let err = ControlListener::bind("0.0.0.0:0").await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        ControlListener::bind("127.0.0.1:0").await.unwrap();
+        ControlListener::bind("[::1]:0").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_update_remotes() {
+        crate::tests::setup_logging();
+        let mut remote_tasks = RemoteTasks::new(make_handler_resources());
+        // A port that is free for now
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        remote_tasks.spawn_startup([
+            Remote::from_str(&format!("127.0.0.1:{port}:example.com:80")).unwrap(),
+            Remote::from_str("127.0.0.1:0:example.com:443").unwrap(),
+            Remote::from_str("R:2222:localhost:22").unwrap(),
+        ]);
+        let (tx, _rx) = oneshot::channel();
+        remote_tasks
+            .handle_command(ControlCommand::Add(
+                Remote::from_str("127.0.0.1:0:socks").unwrap(),
+                tx,
+            ))
+            .await;
+        // The new remote takes over the port of the removed one
+        let failed = remote_tasks
+            .update_remotes(vec![
+                Remote::from_str("127.0.0.1:0:example.com:443").unwrap(),
+                Remote::from_str(&format!("127.0.0.1:{port}:example.com:8080")).unwrap(),
+                Remote::from_str("R:2222:localhost:22").unwrap(),
+            ])
+            .await;
+        assert!(failed.is_empty());
+        let list = remote_tasks
+            .list()
+            .into_iter()
+            .map(|(id, remote, _, _)| format!("{id} {remote}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            list,
+            [
+                "2 127.0.0.1:0:example.com:443/tcp".to_string(),
+                "3 127.0.0.1:0:socks/tcp".to_string(),
+                format!("4 127.0.0.1:{port}:example.com:8080/tcp"),
+            ]
+        );
+        // Remotes that cannot bind are reported and not kept
+        let busy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        let failed = remote_tasks
+            .update_remotes(vec![
+                Remote::from_str(&format!("127.0.0.1:{busy_port}:example.com:80")).unwrap(),
+            ])
+            .await;
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            failed[0].0.to_string(),
+            format!("127.0.0.1:{busy_port}:example.com:80/tcp")
+        );
+        // Aborted tasks are not reported
+        assert!(remote_tasks.update_remotes(Vec::new()).await.is_empty());
+        assert_eq!(remote_tasks.list().len(), 1);
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), remote_tasks.join_next()).await;
+        assert!(
+            result.is_err(),
+            "the control remote should still be running"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        crate::tests::setup_logging();
+        let mut remote_tasks = RemoteTasks::new(make_handler_resources());
+        // A port that is free for now
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        remote_tasks
+            .spawn_startup([
+                Remote::from_str(&format!("web=127.0.0.1:{port}:example.com:80")).unwrap(),
+            ]);
+        let is_paused = |remote_tasks: &RemoteTasks| remote_tasks.list()[0].3;
+        // Resuming right away binds the port again
+        assert_eq!(remote_tasks.set_paused("web", true).await, 1);
+        assert!(is_paused(&remote_tasks));
+        assert_eq!(remote_tasks.set_paused("web", false).await, 1);
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), remote_tasks.join_next()).await;
+        assert!(result.is_err(), "the resumed remote should be running");
+        assert!(!is_paused(&remote_tasks));
+        // A remote that cannot bind stays paused, even from startup
+        assert_eq!(remote_tasks.set_paused("web", true).await, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let _busy = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        assert_eq!(remote_tasks.set_paused("web", false).await, 1);
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), remote_tasks.join_next()).await;
+        assert!(
+            !matches!(result, Ok(Some(Err(_)))),
+            "a failed resume should not be fatal"
+        );
+        assert!(is_paused(&remote_tasks));
+    }
+}
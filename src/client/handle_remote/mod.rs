@@ -10,17 +10,29 @@
 
 pub(super) mod socks;
 mod tcp;
+#[cfg(target_os = "linux")]
+mod transparent;
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub(super) mod tun;
 mod udp;
 
 use self::socks::{handle_socks, handle_socks_stdio};
+#[cfg(unix)]
+use self::tcp::handle_tcp_unix;
 use self::tcp::{handle_tcp, handle_tcp_stdio};
+#[cfg(target_os = "linux")]
+use self::transparent::{handle_transparent, handle_transparent_udp};
+#[cfg(all(feature = "tun", target_os = "linux"))]
+use self::tun::handle_tun;
 use self::udp::{handle_udp, handle_udp_stdio};
 use crate::client::HandlerResources;
+use crate::dns::{DNS_HOST, DNS_PORT};
 use crate::parse_remote::{LocalSpec, RemoteSpec};
 use crate::parse_remote::{Protocol, Remote};
+use std::sync::Arc;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{debug, error};
+use tracing::debug;
 
 /// Handler errors
 /// These are all fatal errors that will cause the client to exit.
@@ -52,8 +64,8 @@ pub enum FatalError {
 /// is alive. Individual connection tasks are spawned as connections appear.
 #[tracing::instrument(skip_all, fields(remote = %remote), level = "debug")]
 pub(super) async fn handle_remote(
-    remote: &'static Remote,
-    handler_resources: &'static HandlerResources,
+    remote: &Remote,
+    handler_resources: &Arc<HandlerResources>,
 ) -> Result<(), FatalError> {
     debug!("opening remote");
     match (&remote.local_addr, &remote.remote_addr, remote.protocol) {
@@ -69,6 +81,25 @@ pub(super) async fn handle_remote(
         (LocalSpec::Stdio, RemoteSpec::Inet((rhost, rport)), Protocol::Udp) => {
             handle_udp_stdio(rhost, *rport, handler_resources).await
         }
+        #[cfg(unix)]
+        (LocalSpec::Unix(path), RemoteSpec::Inet((rhost, rport)), _) => {
+            // The parser guarantees that the protocol is TCP
+            handle_tcp_unix(path, rhost, *rport, handler_resources).await
+        }
+        #[cfg(unix)]
+        (LocalSpec::Unix(lpath), RemoteSpec::Unix(path), _) => {
+            handle_tcp_unix(lpath, path, 0, handler_resources).await
+        }
+        (LocalSpec::Unix(_), _, _) => {
+            unreachable!("The parser rejects unsupported Unix socket remotes (this is a bug)")
+        }
+        // Unix sockets on the server are always TCP and use port 0
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Unix(path), _) => {
+            handle_tcp(lhost, *lport, path, 0, handler_resources).await
+        }
+        (LocalSpec::Stdio, RemoteSpec::Unix(path), _) => {
+            handle_tcp_stdio(path, 0, handler_resources).await
+        }
         (LocalSpec::Inet((lhost, lport)), RemoteSpec::Socks, _) => {
             // The parser guarantees that the protocol is TCP
             handle_socks(lhost, *lport, handler_resources).await
@@ -77,6 +108,34 @@ pub(super) async fn handle_remote(
             // The parser guarantees that the protocol is TCP
             handle_socks_stdio(handler_resources).await
         }
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Dns, _) => {
+            // Queries over TCP and UDP are taken on the same port
+            tokio::try_join!(
+                handle_tcp(lhost, *lport, DNS_HOST, DNS_PORT, handler_resources),
+                handle_udp(lhost, *lport, DNS_HOST, DNS_PORT, handler_resources),
+            )
+            .map(|_| ())
+        }
+        (LocalSpec::Stdio, RemoteSpec::Dns, _) => {
+            unreachable!("The parser rejects stdio dns remotes (this is a bug)")
+        }
+        #[cfg(target_os = "linux")]
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Transparent, Protocol::Tcp) => {
+            handle_transparent(lhost, *lport, handler_resources).await
+        }
+        #[cfg(target_os = "linux")]
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Transparent, Protocol::Udp) => {
+            handle_transparent_udp(lhost, *lport, handler_resources).await
+        }
+        #[cfg(all(feature = "tun", target_os = "linux"))]
+        (LocalSpec::Tun(name), _, _) => handle_tun(name, handler_resources).await,
+        #[cfg(not(all(feature = "tun", target_os = "linux")))]
+        (LocalSpec::Tun(_), _, _) => {
+            unreachable!("The parser rejects unsupported TUN remotes (this is a bug)")
+        }
+        (_, RemoteSpec::Transparent, _) => {
+            unreachable!("The parser rejects unsupported tproxy remotes (this is a bug)")
+        }
     }
 }
 
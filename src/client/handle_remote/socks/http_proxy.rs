@@ -0,0 +1,264 @@
+//! HTTP proxy server (RFC 9110 `CONNECT` and absolute-form requests).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::{Egress, Error, SocksPolicy, check_destination};
+use crate::arg::SocksAuth;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use bytes::Bytes;
+use http::Uri;
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Maximum size of a request head
+const MAX_HEAD_SIZE: usize = 1 << 14;
+
+/// Headers that only concern the connection to the proxy
+const HOP_BY_HOP_HEADERS: [&str; 4] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+/// An HTTP request line and headers
+#[derive(Debug, PartialEq, Eq)]
+struct RequestHead {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// Get the value of a header
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Check the `Proxy-Authorization` header
+    fn is_authorized(&self, auth: &SocksAuth) -> bool {
+        self.header("proxy-authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| B64_STANDARD_ENGINE.decode(encoded.trim()).ok())
+            .is_some_and(|decoded| {
+                decoded
+                    .iter()
+                    .position(|&c| c == b':')
+                    .is_some_and(|idx| auth.matches(&decoded[..idx], &decoded[idx + 1..]))
+            })
+    }
+
+    /// Rewrite an absolute-form request to be sent to the origin server
+    fn to_origin_form(&self, uri: &Uri) -> Vec<u8> {
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        let mut head = format!("{} {path} {}\r\n", self.method, self.version);
+        if self.header("host").is_none()
+            && let Some(authority) = uri.authority()
+        {
+            head.push_str("Host: ");
+            head.push_str(authority.as_str());
+            head.push_str("\r\n");
+        }
+        for (name, value) in &self.headers {
+            if !HOP_BY_HOP_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                head.push_str(name);
+                head.push_str(": ");
+                head.push_str(value);
+                head.push_str("\r\n");
+            }
+        }
+        // The next request on this connection may be for another server
+        head.push_str("Connection: close\r\n\r\n");
+        head.into_bytes()
+    }
+}
+
+/// Read an HTTP request head from the given reader.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context, or a malformed
+/// request.
+async fn read_request_head<R>(reader: &mut R) -> Result<RequestHead, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let len = reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| Error::ProcessSocksRequest("read HTTP request", e))?;
+        size += len;
+        if len == 0 || size > MAX_HEAD_SIZE {
+            return Err(Error::HttpRequest("incomplete or oversized request head"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line.to_string());
+    }
+    let mut lines = lines.into_iter();
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(Error::HttpRequest("malformed request line"));
+    };
+    let headers = lines
+        .map(|line| {
+            line.split_once(':')
+                .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+                .ok_or(Error::HttpRequest("malformed header"))
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(RequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+/// Write a response without a body to the given writer.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context.
+async fn write_response<W>(writer: &mut W, status: &str, extra_headers: &str) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = format!("HTTP/1.1 {status}\r\n{extra_headers}Content-Length: 0\r\n\r\n");
+    writer
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("write HTTP response", e))?;
+    writer
+        .flush()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("flush", e))?;
+    Ok(())
+}
+
+/// Get the host and port to connect to from a request target.
+fn target_host_port(uri: &Uri, default_port: u16) -> Option<(Bytes, u16)> {
+    let host = uri.host()?.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(default_port);
+    Some((Bytes::copy_from_slice(host.as_bytes()), port))
+}
+
+/// Handle an HTTP proxy request.
+/// `CONNECT` requests are relayed like SOCKS `CONNECT` requests. Other requests
+/// must be in absolute form for `http` URLs, and are rewritten to origin form.
+#[tracing::instrument(skip_all, fields(method, target), level = "debug")]
+pub(super) async fn http_proxy<RW>(
+    stream: &mut RW,
+    egress: Egress,
+    policy: &SocksPolicy,
+) -> Result<(), Error>
+where
+    RW: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let head = read_request_head(stream).await?;
+    tracing::Span::current().record("method", &head.method);
+    tracing::Span::current().record("target", &head.target);
+    debug!("HTTP proxy request");
+    if let Some(auth) = &policy.auth
+        && !head.is_authorized(auth)
+    {
+        write_response(
+            stream,
+            "407 Proxy Authentication Required",
+            "Proxy-Authenticate: Basic realm=\"penguin\"\r\n",
+        )
+        .await?;
+        return Err(Error::WrongPassword);
+    }
+    let is_connect = head.method == "CONNECT";
+    let uri = Uri::from_str(&head.target).ok();
+    let host_port = match &uri {
+        Some(uri) if is_connect && uri.scheme().is_none() => target_host_port(uri, 443),
+        Some(uri) if uri.scheme_str() == Some("http") => target_host_port(uri, 80),
+        _ => None,
+    };
+    let (Some(uri), Some((rhost, rport))) = (uri, host_port) else {
+        write_response(stream, "400 Bad Request", "").await?;
+        return Err(Error::HttpRequest("unsupported request target"));
+    };
+    if let Err(e) = check_destination(&policy.acl, &rhost, rport) {
+        write_response(stream, "403 Forbidden", "").await?;
+        return Err(e);
+    }
+    let mut target = match egress.connect(rhost, rport).await {
+        Ok(target) => target,
+        Err(e @ Error::Fatal(_)) => return Err(e),
+        Err(e) => {
+            write_response(stream, "502 Bad Gateway", "").await?;
+            return Err(e);
+        }
+    };
+    if is_connect {
+        write_response(stream, "200 Connection Established", "").await?;
+    } else {
+        target.write_all(&head.to_origin_form(&uri)).await?;
+    }
+    target.relay(stream).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn test_read_request_head() {
+        crate::tests::setup_logging();
+        let mut reader =
+            Cursor::new(b"GET http://example.com/a?b HTTP/1.1\r\nHost: example.com\r\nProxy-Connection: keep-alive\r\n\r\nbody".to_vec());
+        let head = read_request_head(&mut reader).await.unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.target, "http://example.com/a?b");
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.header("HOST"), Some("example.com"));
+        let uri = Uri::from_str(&head.target).unwrap();
+        assert_eq!(
+            head.to_origin_form(&uri),
+            b"GET /a?b HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(
+            target_host_port(&uri, 80),
+            Some((Bytes::from_static(b"example.com"), 80))
+        );
+        let mut reader = Cursor::new(b"GET / HTTP/1.1\r\nHost: example.com\r\n".to_vec());
+        assert!(read_request_head(&mut reader).await.is_err());
+        let mut reader = Cursor::new(b"GET /\r\n\r\n".to_vec());
+        assert!(read_request_head(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_target_and_auth() {
+        crate::tests::setup_logging();
+        let mut reader = Cursor::new(
+            b"CONNECT [::1]:8443 HTTP/1.1\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+                .to_vec(),
+        );
+        let head = read_request_head(&mut reader).await.unwrap();
+        let uri = Uri::from_str(&head.target).unwrap();
+        assert_eq!(
+            target_host_port(&uri, 443),
+            Some((Bytes::from_static(b"::1"), 8443))
+        );
+        assert!(head.is_authorized(&SocksAuth::from_str("user:pass").unwrap()));
+        assert!(!head.is_authorized(&SocksAuth::from_str("user:word").unwrap()));
+    }
+}
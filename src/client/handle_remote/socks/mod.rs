@@ -1,22 +1,26 @@
 //! SOCKS server.
+//!
+//! The same listener also works as an HTTP proxy: connections that start
+//! with an HTTP method instead of a SOCKS version are handled by [`http_proxy`].
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+mod http_proxy;
 mod v4;
 mod v5;
 
 use super::HandlerResources;
 use super::tcp::{open_tcp_listener, request_tcp_channel};
-use crate::client::StreamCommand;
+use crate::acl::Acl;
+use crate::arg::SocksAuth;
 use crate::config;
 use bytes::{Buf, Bytes};
-use penguin_mux::{Datagram, Dupe};
+use penguin_mux::{BufferPool, Datagram, Dupe, MuxStream};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncBufRead, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::task::JoinSet;
 use tracing::{debug, info, trace, warn};
 
@@ -37,22 +41,149 @@ pub enum Error {
     ProcessSocksRequest(&'static str, std::io::Error),
     #[error("Cannot parse SOCKS associate datagram")]
     ParseAssociate,
-    #[error("Client does not support NOAUTH")]
+    #[error("Client does not support any acceptable authentication method")]
     OtherAuth,
+    #[error("Invalid username/password authentication version: {0}")]
+    AuthVersion(u8),
+    #[error("Client presented wrong username or password")]
+    WrongPassword,
+    #[error("Invalid HTTP proxy request: {0}")]
+    HttpRequest(&'static str),
+    #[error("Destination not allowed: {0} port {1}")]
+    Forbidden(String, u16),
     /// Fatal error that we should propagate to main.
     #[error(transparent)]
     Fatal(#[from] super::FatalError),
 }
 
+/// What is required of the clients of SOCKS remotes and what they may ask for
+#[derive(Clone, Debug, Default)]
+pub struct SocksPolicy {
+    /// Credentials that SOCKS5 clients must present, if any
+    pub auth: Option<SocksAuth>,
+    /// Destinations that clients may connect or send datagrams to
+    pub acl: Acl,
+    /// Resolve domain names here and send the addresses to the server
+    /// instead of letting it resolve them
+    pub local_dns: bool,
+}
+
+/// Check the destination of a request against `acl`
+fn check_destination(acl: &Acl, rhost: &[u8], rport: u16) -> Result<(), Error> {
+    let rhost = String::from_utf8_lossy(rhost);
+    if acl.allows_unresolved(&rhost, rport) {
+        Ok(())
+    } else {
+        Err(Error::Forbidden(rhost.into_owned(), rport))
+    }
+}
+
+/// Resolve `rhost` with the local resolver, so that the server is given an
+/// address. Addresses are returned unchanged.
+async fn resolve_locally(rhost: Bytes, rport: u16) -> Result<Bytes, Error> {
+    let host = String::from_utf8_lossy(&rhost).into_owned();
+    if host.parse::<IpAddr>().is_ok() {
+        return Ok(rhost);
+    }
+    let addr = tokio::net::lookup_host((&*host, rport))
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("resolve target", e))?
+        .next()
+        .ok_or_else(|| {
+            Error::ProcessSocksRequest("resolve target", std::io::ErrorKind::NotFound.into())
+        })?;
+    trace!("resolved {host} to {}", addr.ip());
+    Ok(addr.ip().to_string().into())
+}
+
+/// Where the connections requested by SOCKS clients egress from.
+#[derive(Clone, Debug)]
+enum Egress {
+    /// Tunneled through the server, for normal `socks` remotes.
+    Tunnel(Arc<HandlerResources>),
+    /// Connected directly from the client, for `R:socks` remotes.
+    Direct,
+}
+
+impl Egress {
+    /// Connect to the target of a `CONNECT` request.
+    /// Only errors that are not fatal should be reported to the client.
+    async fn connect(self, rhost: Bytes, rport: u16) -> Result<Target, Error> {
+        match self {
+            Self::Tunnel(handler_resources) => {
+                let rhost = if handler_resources.socks_policy.local_dns {
+                    resolve_locally(rhost, rport).await?
+                } else {
+                    rhost
+                };
+                // This fails only if main has exited, which is a fatal error.
+                let stream_command_tx_permit = handler_resources
+                    .stream_command_tx
+                    .reserve()
+                    .await
+                    .or(Err(super::FatalError::RequestStream))?;
+                // Establish a connection to the remote host
+                let channel = request_tcp_channel(stream_command_tx_permit, rhost, rport)
+                    .await
+                    .or(Err(super::FatalError::MainLoopExitWithoutSendingStream))?;
+                Ok(Target::Tunnel(Box::new(channel)))
+            }
+            Self::Direct => {
+                let rhost = String::from_utf8_lossy(&rhost);
+                let tcp_stream = TcpStream::connect((&*rhost, rport))
+                    .await
+                    .map_err(|e| Error::ProcessSocksRequest("connect to target", e))?;
+                Ok(Target::Direct(tcp_stream))
+            }
+        }
+    }
+}
+
+/// A connection to the target of a `CONNECT` request.
+#[derive(Debug)]
+enum Target {
+    Tunnel(Box<MuxStream>),
+    Direct(TcpStream),
+}
+
+impl Target {
+    /// Send some data to the target before relaying.
+    async fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Tunnel(channel) => channel.write_all(data).await,
+            Self::Direct(tcp_stream) => tcp_stream.write_all(data).await,
+        }
+    }
+
+    /// Relay data between the client and the target until either side closes.
+    async fn relay<RW>(self, stream: &mut RW) -> std::io::Result<()>
+    where
+        RW: AsyncBufRead + AsyncWrite + Unpin,
+    {
+        trace!("SOCKS starting copy");
+        match self {
+            Self::Tunnel(channel) => {
+                channel.into_copy_bidirectional_with_buf(stream).await?;
+            }
+            Self::Direct(mut tcp_stream) => {
+                tokio::io::copy_bidirectional(stream, &mut tcp_stream).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
 pub(super) async fn handle_socks(
-    lhost: &'static str,
+    lhost: &str,
     lport: u16,
-    handler_resources: &'static HandlerResources,
+    handler_resources: &Arc<HandlerResources>,
 ) -> Result<(), super::FatalError> {
     // Failing to open the listener is a fatal error and should be propagated.
     let listener = open_tcp_listener(lhost, lport)
         .await
         .map_err(super::FatalError::ClientIo)?;
+    // Shared with the SOCKS jobs, which bind UDP ASSOCIATE sockets on it
+    let lhost: Arc<str> = lhost.into();
     let mut socks_jobs = JoinSet::new();
     loop {
         tokio::select! {
@@ -68,7 +199,14 @@ pub(super) async fn handle_socks(
             result = listener.accept() => {
                 // A failed accept() is a fatal error and should be propagated.
                 let (stream, _) = result.map_err(super::FatalError::ClientIo)?;
-                socks_jobs.spawn(on_socks_accept(stream, lhost, handler_resources));
+                handler_resources.traffic.add_connection();
+                let stream = handler_resources.traffic.count(stream);
+                let lhost = Arc::clone(&lhost);
+                let handler_resources = handler_resources.dupe();
+                socks_jobs.spawn(async move {
+                    let policy = handler_resources.socks_policy.clone();
+                    on_socks_accept(stream, &lhost, Egress::Tunnel(handler_resources), &policy).await
+                });
             }
         }
     }
@@ -76,9 +214,22 @@ pub(super) async fn handle_socks(
 
 #[inline]
 pub(super) async fn handle_socks_stdio(
-    handler_resources: &'static HandlerResources,
+    handler_resources: &Arc<HandlerResources>,
 ) -> Result<(), super::FatalError> {
-    if let Err(e) = on_socks_accept(super::Stdio::new(), "localhost", handler_resources).await {
+    // stdio is not reachable from the network, so no authentication is needed
+    let policy = SocksPolicy {
+        auth: None,
+        ..handler_resources.socks_policy.clone()
+    };
+    handler_resources.traffic.add_connection();
+    if let Err(e) = on_socks_accept(
+        handler_resources.traffic.count(super::Stdio::new()),
+        "localhost",
+        Egress::Tunnel(handler_resources.dupe()),
+        &policy,
+    )
+    .await
+    {
         if let Error::Fatal(e) = e {
             return Err(e);
         }
@@ -87,33 +238,60 @@ pub(super) async fn handle_socks_stdio(
     Ok(())
 }
 
+/// Handle a SOCKS connection tunneled back by the server for a `R:socks` remote.
+/// `CONNECT` requests are connected directly from the client.
+#[inline]
+pub(crate) async fn handle_socks_reverse(stream: MuxStream, policy: &SocksPolicy) {
+    if let Err(e) = on_socks_accept(stream, "localhost", Egress::Direct, policy).await {
+        info!("{e}");
+    }
+}
+
 /// Handle a SOCKS5 connection.
 /// Based on socksv5's example.
 /// We need to be able to request additional channels, so we need `handler_resources`
-#[tracing::instrument(skip(stream, handler_resources), level = "trace")]
-pub(super) async fn on_socks_accept<RW>(
+/// unless the connections egress directly.
+/// If `policy` has credentials, SOCKS5 clients must authenticate and SOCKS4
+/// clients are rejected.
+#[tracing::instrument(skip(stream, egress, policy), level = "trace")]
+async fn on_socks_accept<RW>(
     stream: RW,
     local_addr: &str,
-    handler_resources: &'static HandlerResources,
+    egress: Egress,
+    policy: &SocksPolicy,
 ) -> Result<(), Error>
 where
     RW: AsyncRead + AsyncWrite + Unpin,
 {
     let mut bufreader = BufReader::new(stream);
-    let version = bufreader
-        .read_u8()
+    // Only peek so that HTTP requests are left intact
+    let version = *bufreader
+        .fill_buf()
         .await
-        .map_err(|e| Error::ProcessSocksRequest("read version", e))?;
+        .map_err(|e| Error::ProcessSocksRequest("read version", e))?
+        .first()
+        .ok_or_else(|| {
+            Error::ProcessSocksRequest("read version", std::io::ErrorKind::UnexpectedEof.into())
+        })?;
+    if version.is_ascii_uppercase() {
+        return http_proxy::http_proxy(&mut bufreader, egress, policy).await;
+    }
+    bufreader.consume(1);
     match version {
-        4 => socks4(&mut bufreader, handler_resources).await,
-        5 => socks5(&mut bufreader, local_addr, handler_resources).await,
+        4 if policy.auth.is_some() => {
+            // SOCKSv4 has no way to authenticate
+            v4::write_response(&mut bufreader, 0x5b).await?;
+            Err(Error::OtherAuth)
+        }
+        4 => socks4(&mut bufreader, egress, &policy.acl).await,
+        5 => socks5(&mut bufreader, local_addr, egress, policy).await,
         version => Err(Error::SocksVersion(version)),
     }
 }
 
 #[inline]
 #[tracing::instrument(skip_all, fields(host, port, cmd))]
-async fn socks4<RW>(stream: &mut RW, handler_resources: &HandlerResources) -> Result<(), Error>
+async fn socks4<RW>(stream: &mut RW, egress: Egress, acl: &Acl) -> Result<(), Error>
 where
     RW: AsyncBufRead + AsyncWrite + Unpin,
 {
@@ -124,13 +302,7 @@ where
     debug!("SOCKSv4 request");
     if command == 0x01 {
         // CONNECT
-        // This fails only if main has exited, which is a fatal error.
-        let stream_command_tx_permit = handler_resources
-            .stream_command_tx
-            .reserve()
-            .await
-            .or(Err(super::FatalError::RequestStream))?;
-        handle_connect(stream, rhost, rport, stream_command_tx_permit, false).await
+        handle_connect(stream, (rhost, rport), egress, acl, false).await
     } else {
         v4::write_response(stream, 0x5b).await?;
         Err(Error::InvalidCommand(command))
@@ -142,41 +314,46 @@ where
 async fn socks5<RW>(
     stream: &mut RW,
     local_addr: &str,
-    handler_resources: &'static HandlerResources,
+    egress: Egress,
+    policy: &SocksPolicy,
 ) -> Result<(), Error>
 where
     RW: AsyncBufRead + AsyncWrite + Unpin,
 {
+    let auth = policy.auth.as_ref();
     // Complete the handshake
     let methods = v5::read_auth_methods(stream).await?;
-    if !methods.contains(&0x00) {
+    // USERNAME/PASSWORD if configured, otherwise NO AUTHENTICATION REQUIRED
+    let method = if auth.is_some() { 0x02 } else { 0x00 };
+    if !methods.contains(&method) {
         // Send back NO ACCEPTABLE METHODS
         // Note that we are not compliant with RFC 1928 here, as we MUST
-        // support GSSAPI and SHOULD support USERNAME/PASSWORD
+        // support GSSAPI
         v5::write_auth_method(stream, 0xff).await?;
         return Err(Error::OtherAuth);
     }
-    // Send back NO AUTHENTICATION REQUIRED
-    v5::write_auth_method(stream, 0x00).await?;
+    v5::write_auth_method(stream, method).await?;
+    if let Some(auth) = auth {
+        let (username, password) = v5::read_password_auth(stream).await?;
+        let success = auth.matches(&username, &password);
+        v5::write_password_auth_status(stream, success).await?;
+        if !success {
+            return Err(Error::WrongPassword);
+        }
+    }
     // Read the request
     let (command, rhost, rport) = v5::read_request(stream).await?;
     tracing::Span::current().record("host", format_args!("{}", String::from_utf8_lossy(&rhost)));
     tracing::Span::current().record("port", rport);
     tracing::Span::current().record("cmd", command);
     debug!("SOCKSv5 request");
-    match command {
-        0x01 => {
-            // CONNECT
-            // This fails only if main has exited, which is a fatal error.
-            let stream_command_tx_permit = handler_resources
-                .stream_command_tx
-                .reserve()
-                .await
-                .or(Err(super::FatalError::RequestStream))?;
-            handle_connect(stream, rhost, rport, stream_command_tx_permit, true).await
-        }
+    match (command, egress) {
+        // CONNECT
+        (0x01, egress) => handle_connect(stream, (rhost, rport), egress, &policy.acl, true).await,
         // UDP ASSOCIATE
-        0x03 => handle_associate(stream, local_addr, handler_resources).await,
+        (0x03, Egress::Tunnel(handler_resources)) => {
+            handle_associate(stream, local_addr, handler_resources).await
+        }
         // We don't support BIND because I can't ask the remote host to bind
         _ => {
             v5::write_response_unspecified(stream, 0x07).await?;
@@ -188,34 +365,70 @@ where
 #[tracing::instrument(skip_all, level = "trace")]
 async fn handle_connect<RW>(
     stream: &mut RW,
-    rhost: Bytes,
-    rport: u16,
-    stream_command_tx_permit: mpsc::Permit<'_, StreamCommand>,
+    (rhost, rport): (Bytes, u16),
+    egress: Egress,
+    acl: &Acl,
     version_is_5: bool,
 ) -> Result<(), Error>
 where
     RW: AsyncBufRead + AsyncWrite + Unpin,
 {
-    // Establish a connection to the remote host
-    let channel = request_tcp_channel(stream_command_tx_permit, rhost, rport)
-        .await
-        .or(Err(super::FatalError::MainLoopExitWithoutSendingStream))?;
-    // Send back a successful response
-    if version_is_5 {
-        v5::write_response_unspecified(stream, 0x00).await?;
-    } else {
-        v4::write_response(stream, 0x5a).await?;
-    }
-    trace!("SOCKS starting copy");
-    channel.into_copy_bidirectional_with_buf(stream).await?;
+    let connected = match check_destination(acl, &rhost, rport) {
+        Ok(()) => egress.connect(rhost, rport).await,
+        Err(e) => Err(e),
+    };
+    let target = match connected {
+        Ok(target) => target,
+        Err(e @ Error::Fatal(_)) => return Err(e),
+        Err(e) => {
+            let reply = if matches!(e, Error::Forbidden(..)) {
+                ConnectReply::Forbidden
+            } else {
+                ConnectReply::Unreachable
+            };
+            write_connect_response(stream, reply, version_is_5).await?;
+            return Err(e);
+        }
+    };
+    write_connect_response(stream, ConnectReply::Succeeded, version_is_5).await?;
+    target.relay(stream).await?;
     Ok(())
 }
 
+/// Outcome of a `CONNECT` request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ConnectReply {
+    Succeeded,
+    Unreachable,
+    Forbidden,
+}
+
+/// Send back the response to a `CONNECT` request.
+#[inline]
+async fn write_connect_response<W>(
+    stream: &mut W,
+    reply: ConnectReply,
+    version_is_5: bool,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    match (version_is_5, reply) {
+        (true, ConnectReply::Succeeded) => v5::write_response_unspecified(stream, 0x00).await,
+        // Connection not allowed by ruleset
+        (true, ConnectReply::Forbidden) => v5::write_response_unspecified(stream, 0x02).await,
+        // Host unreachable
+        (true, ConnectReply::Unreachable) => v5::write_response_unspecified(stream, 0x04).await,
+        (false, ConnectReply::Succeeded) => v4::write_response(stream, 0x5a).await,
+        (false, _) => v4::write_response(stream, 0x5b).await,
+    }
+}
+
 #[tracing::instrument(skip_all, level = "trace")]
 async fn handle_associate<RW>(
     stream: &mut RW,
     local_addr: &str,
-    handler_resources: &'static HandlerResources,
+    handler_resources: Arc<HandlerResources>,
 ) -> Result<(), Error>
 where
     RW: AsyncRead + AsyncWrite + Unpin,
@@ -235,7 +448,7 @@ where
         }
     };
     trace!("SOCKS relaying at {sock_local_addr}");
-    let relay_task = tokio::spawn(udp_relay(handler_resources, socket));
+    let relay_task = tokio::spawn(async move { udp_relay(&handler_resources, socket).await });
     // Send back a successful response
     v5::write_response(stream, 0x00, sock_local_addr).await?;
     // My crude way to detect when the client closes the connection
@@ -250,13 +463,38 @@ where
 #[tracing::instrument(skip_all, level = "trace")]
 async fn udp_relay(handler_resources: &HandlerResources, socket: UdpSocket) -> Result<(), Error> {
     let socket = Arc::new(socket);
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
     loop {
         let Some((target_host, target_port, data, src, sport)) =
-            handle_udp_relay_header(&socket).await?
+            handle_udp_relay_header(&socket, &mut buf, &handler_resources.buffer_pool).await?
         else {
             continue;
         };
-        let client_id = handler_resources.add_udp_client((src, sport).into(), socket.dupe(), true);
+        if let Err(e) = check_destination(
+            &handler_resources.socks_policy.acl,
+            &target_host,
+            target_port,
+        ) {
+            debug!("Dropping datagram: {e}");
+            continue;
+        }
+        let target_host = if handler_resources.socks_policy.local_dns {
+            match resolve_locally(target_host, target_port).await {
+                Ok(target_host) => target_host,
+                Err(e) => {
+                    debug!("Dropping datagram: {e}");
+                    continue;
+                }
+            }
+        } else {
+            target_host
+        };
+        let client_id = handler_resources.add_socks_udp_client(
+            (src, sport).into(),
+            socket.dupe(),
+            (target_host.dupe(), target_port),
+        );
+        let len = data.len();
         let datagram_frame = Datagram {
             target_host,
             target_port,
@@ -269,19 +507,20 @@ async fn udp_relay(handler_resources: &HandlerResources, socket: UdpSocket) -> R
             .send(datagram_frame)
             .await
             .or(Err(super::FatalError::SendDatagram))?;
+        handler_resources.traffic.add_sent(len);
     }
 }
 
-/// Parse a UDP relay request.
+/// Parse a UDP relay request received into `buf`.
 /// Returns (dst, dport, data, src, sport)
 async fn handle_udp_relay_header(
     socket: &UdpSocket,
+    buf: &mut [u8],
+    pool: &BufferPool,
 ) -> Result<Option<(Bytes, u16, Bytes, IpAddr, u16)>, Error> {
-    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
-    let (len, addr) = socket.recv_from(&mut buf).await?;
+    let (len, addr) = socket.recv_from(buf).await?;
     trace!("received {len} bytes from {addr}");
-    buf.truncate(len);
-    let mut buf = Bytes::from(buf);
+    let mut buf = pool.copy_from_slice(&buf[..len]);
     if buf.remaining() < 4 {
         return Err(Error::ParseAssociate);
     }
@@ -357,3 +596,127 @@ pub async fn send_udp_relay_response(
     content.extend(data);
     socket.send_to(&content, target).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use tokio::io::{AsyncWriteExt, duplex};
+    use tokio::net::TcpListener;
+
+    /// Run a SOCKS handshake with `auth` configured, sending `request` and
+    /// returning the response and the result of the server side.
+    async fn handshake(request: &[u8], response_len: usize) -> (Vec<u8>, Result<(), Error>) {
+        let policy = SocksPolicy {
+            auth: Some(SocksAuth::from_str("user:pass").unwrap()),
+            acl: Acl::default(),
+            local_dns: false,
+        };
+        handshake_with(&policy, request, response_len).await
+    }
+
+    async fn handshake_with(
+        policy: &SocksPolicy,
+        request: &[u8],
+        response_len: usize,
+    ) -> (Vec<u8>, Result<(), Error>) {
+        let (mut client, server) = duplex(1024);
+        let server_future = on_socks_accept(server, "localhost", Egress::Direct, policy);
+        let client_future = async {
+            client.write_all(request).await.unwrap();
+            let mut response = vec![0; response_len];
+            client.read_exact(&mut response).await.unwrap();
+            // Make the server side exit
+            drop(client);
+            response
+        };
+        tokio::join!(client_future, server_future)
+    }
+
+    #[tokio::test]
+    async fn test_socks5_password_auth() {
+        crate::tests::setup_logging();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port().to_be_bytes();
+        let mut request =
+            b"\x05\x02\x00\x02\x01\x04user\x04pass\x05\x01\x00\x01\x7f\x00\x00\x01".to_vec();
+        request.extend(port);
+        // Close the connection to the target so that copying finishes
+        let target_task = tokio::spawn(async move {
+            listener.accept().await.unwrap();
+        });
+        let (response, result) = handshake(&request, 2 + 2 + 10).await;
+        assert_eq!(&response[..7], b"\x05\x02\x01\x00\x05\x00\x00");
+        result.unwrap();
+        target_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks_auth_rejected() {
+        crate::tests::setup_logging();
+        // Wrong password
+        let (response, result) = handshake(b"\x05\x01\x02\x01\x04user\x04word", 4).await;
+        assert_eq!(response, b"\x05\x02\x01\x01");
+        assert!(matches!(result, Err(Error::WrongPassword)));
+        // Only NOAUTH
+        let (response, result) = handshake(b"\x05\x01\x00", 2).await;
+        assert_eq!(response, b"\x05\xff");
+        assert!(matches!(result, Err(Error::OtherAuth)));
+        // SOCKS4
+        let (response, result) = handshake(b"\x04", 2).await;
+        assert_eq!(response, b"\x00\x5b");
+        assert!(matches!(result, Err(Error::OtherAuth)));
+    }
+
+    #[tokio::test]
+    async fn test_socks_destination_forbidden() {
+        crate::tests::setup_logging();
+        let policy = SocksPolicy {
+            auth: None,
+            acl: Acl::new(Vec::new(), vec!["127.0.0.0/8".parse().unwrap()]),
+            local_dns: false,
+        };
+        // SOCKS5 `CONNECT` to 127.0.0.1:80
+        let (response, result) = handshake_with(
+            &policy,
+            b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50",
+            2 + 10,
+        )
+        .await;
+        assert_eq!(&response[..4], b"\x05\x00\x05\x02");
+        assert!(matches!(result, Err(Error::Forbidden(_, 80))));
+        // SOCKS4 `CONNECT` to 127.0.0.1:80
+        let (response, result) =
+            handshake_with(&policy, b"\x04\x01\x00\x50\x7f\x00\x00\x01\x00", 8).await;
+        assert_eq!(&response[..2], b"\x00\x5b");
+        assert!(matches!(result, Err(Error::Forbidden(_, 80))));
+        // HTTP proxy
+        let (response, result) = handshake_with(
+            &policy,
+            b"CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\n\r\n",
+            12,
+        )
+        .await;
+        assert_eq!(response, b"HTTP/1.1 403");
+        assert!(matches!(result, Err(Error::Forbidden(_, 443))));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_locally() {
+        crate::tests::setup_logging();
+        let resolved = resolve_locally(Bytes::from_static(b"localhost"), 80)
+            .await
+            .unwrap();
+        let resolved: IpAddr = std::str::from_utf8(&resolved).unwrap().parse().unwrap();
+        assert!(resolved.is_loopback());
+        // Addresses are not touched
+        let resolved = resolve_locally(Bytes::from_static(b"::1"), 80)
+            .await
+            .unwrap();
+        assert_eq!(resolved, Bytes::from_static(b"::1"));
+        assert!(matches!(
+            resolve_locally(Bytes::from_static(b"nonexistent.invalid"), 80).await,
+            Err(Error::ProcessSocksRequest("resolve target", _))
+        ));
+    }
+}
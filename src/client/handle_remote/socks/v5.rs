@@ -50,6 +50,66 @@ where
     Ok(())
 }
 
+/// Read a username/password authentication request (RFC 1929) from the given reader.
+/// Returns the username and password.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context, or an invalid
+/// subnegotiation version.
+#[inline]
+pub async fn read_password_auth<R>(reader: &mut R) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let version = reader
+        .read_u8()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("read auth version", e))?;
+    if version != 0x01 {
+        return Err(Error::AuthVersion(version));
+    }
+    let username_len = reader
+        .read_u8()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("read username length", e))?;
+    let mut username = vec![0; usize::from(username_len)];
+    reader
+        .read_exact(&mut username)
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("read username", e))?;
+    let password_len = reader
+        .read_u8()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("read password length", e))?;
+    let mut password = vec![0; usize::from(password_len)];
+    reader
+        .read_exact(&mut password)
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("read password", e))?;
+    Ok((username, password))
+}
+
+/// Write a username/password authentication status (RFC 1929) to the given writer.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context.
+#[inline]
+pub async fn write_password_auth_status<W>(writer: &mut W, success: bool) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer
+        // Status 0x00 is success and anything else is failure
+        .write_all(&[0x01, u8::from(!success)])
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("write auth status", e))?;
+    writer
+        .flush()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("flush", e))?;
+    Ok(())
+}
+
 /// Read a SOCKS5 request from the given reader. Returns the command, address, and port.
 /// Writes an `Address type not supported` response to the given writer if the address type is not
 /// valid.
@@ -232,6 +292,31 @@ mod tests {
         assert_eq!(writer.get_ref(), &[0x05, 0x00]);
     }
 
+    #[tokio::test]
+    async fn test_read_password_auth() {
+        crate::tests::setup_logging();
+        let mut reader = Cursor::new(b"\x01\x04user\x04pass".to_vec());
+        let (username, password) = read_password_auth(&mut reader).await.unwrap();
+        assert_eq!(username, b"user");
+        assert_eq!(password, b"pass");
+        let mut reader = Cursor::new(b"\x05\x04user\x04pass".to_vec());
+        assert!(matches!(
+            read_password_auth(&mut reader).await,
+            Err(Error::AuthVersion(0x05))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_write_password_auth_status() {
+        crate::tests::setup_logging();
+        let mut writer = Cursor::new(vec![]);
+        write_password_auth_status(&mut writer, true).await.unwrap();
+        write_password_auth_status(&mut writer, false)
+            .await
+            .unwrap();
+        assert_eq!(writer.get_ref(), &[0x01, 0x00, 0x01, 0x01]);
+    }
+
     #[tokio::test]
     async fn test_read_request_v4() {
         crate::tests::setup_logging();
@@ -7,11 +7,14 @@ use super::FatalError;
 use crate::client::HandlerResources;
 use crate::client::{MuxStream, StreamCommand};
 use bytes::Bytes;
+use penguin_mux::{Dupe, Priority};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::{
     net::TcpListener,
     sync::{mpsc, oneshot},
 };
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 /// Request a channel from the mux
 /// Returns an error if the main loop timed out waiting for a response.
@@ -51,7 +54,7 @@ pub(super) async fn open_tcp_listener(lhost: &str, lport: u16) -> std::io::Resul
 pub(super) async fn handle_tcp(
     lhost: &str,
     lport: u16,
-    rhost: &'static str,
+    rhost: &str,
     rport: u16,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
@@ -59,7 +62,7 @@ pub(super) async fn handle_tcp(
     let listener = open_tcp_listener(lhost, lport)
         .await
         .map_err(FatalError::ClientIo)?;
-    let rhost = rhost.as_bytes();
+    let rhost = Bytes::copy_from_slice(rhost.as_bytes());
     loop {
         // This fails only if main has exited, which is a fatal error.
         let stream_command_tx_permit = handler_resources
@@ -70,17 +73,74 @@ pub(super) async fn handle_tcp(
         // Only `accept` when we have a permit to send a request.
         // This way, the backpressure is propagated to the TCP listener.
         // Not being able to accept a TCP connection is a fatal error.
-        let (mut tcp_stream, _) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        let (tcp_stream, peer) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        handler_resources.traffic.add_connection();
+        let mut tcp_stream = handler_resources.traffic.count(tcp_stream);
         // A new channel is created for each incoming TCP connection.
         // It's already TCP, anyways.
-        let channel =
-            request_tcp_channel(stream_command_tx_permit, Bytes::from_static(rhost), rport)
-                .await
-                .or(Err(FatalError::MainLoopExitWithoutSendingStream))?;
+        let channel = request_tcp_channel(stream_command_tx_permit, rhost.dupe(), rport)
+            .await
+            .or(Err(FatalError::MainLoopExitWithoutSendingStream))?;
+        let flow_id = format!("{:08x}", channel.flow_id());
+        // Transient errors in the forwarder don't matter.
+        tokio::spawn(async move {
+            match channel.into_copy_bidirectional(&mut tcp_stream).await {
+                Ok((bytes_received, bytes_sent)) => {
+                    debug!(%flow_id, %peer, bytes_sent, bytes_received, "TCP forwarding finished");
+                }
+                Err(error) => warn!(%flow_id, %peer, "TCP forwarder failed: {error}"),
+            }
+        });
+    }
+}
+
+/// Open a Unix socket listener, replacing a stale socket file left behind
+/// by an earlier run.
+#[cfg(unix)]
+#[tracing::instrument(level = "trace")]
+pub(super) async fn open_unix_listener(path: &str) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.file_type().is_socket() => tokio::fs::remove_file(path).await?,
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening on {path}");
+    Ok(listener)
+}
+
+/// Handle a TCP Unix->Inet remote.
+#[cfg(unix)]
+#[tracing::instrument(skip(handler_resources), level = "debug")]
+pub(super) async fn handle_tcp_unix(
+    path: &str,
+    rhost: &str,
+    rport: u16,
+    handler_resources: &HandlerResources,
+) -> Result<(), FatalError> {
+    // Not being able to open a Unix listener is a fatal error.
+    let listener = open_unix_listener(path)
+        .await
+        .map_err(FatalError::ClientIo)?;
+    let rhost = Bytes::copy_from_slice(rhost.as_bytes());
+    loop {
+        // This fails only if main has exited, which is a fatal error.
+        let stream_command_tx_permit = handler_resources
+            .stream_command_tx
+            .reserve()
+            .await
+            .or(Err(FatalError::RequestStream))?;
+        // Same backpressure as in `handle_tcp`
+        let (unix_stream, _) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        handler_resources.traffic.add_connection();
+        let mut unix_stream = handler_resources.traffic.count(unix_stream);
+        let channel = request_tcp_channel(stream_command_tx_permit, rhost.dupe(), rport)
+            .await
+            .or(Err(FatalError::MainLoopExitWithoutSendingStream))?;
         // Transient errors in the forwarder don't matter.
         tokio::spawn(async move {
-            if let Err(error) = channel.into_copy_bidirectional(&mut tcp_stream).await {
-                warn!("TCP forwarder failed: {error}");
+            if let Err(error) = channel.into_copy_bidirectional(&mut unix_stream).await {
+                warn!("Unix socket forwarder failed: {error}");
             }
         });
     }
@@ -89,12 +149,12 @@ pub(super) async fn handle_tcp(
 /// Handle a TCP Stdio->Inet remote.
 #[tracing::instrument(skip(handler_resources))]
 pub(super) async fn handle_tcp_stdio(
-    rhost: &'static str,
+    rhost: &str,
     rport: u16,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
-    let mut stdio = super::Stdio::new();
-    let rhost = rhost.as_bytes();
+    let mut stdio = handler_resources.traffic.count(super::Stdio::new());
+    let rhost = Bytes::copy_from_slice(rhost.as_bytes());
     // We want `loop` to be able to continue after a connection failure
     loop {
         // This fails only if main has exited, which is a fatal error.
@@ -103,10 +163,13 @@ pub(super) async fn handle_tcp_stdio(
             .reserve()
             .await
             .or(Err(FatalError::RequestStream))?;
-        let channel =
-            request_tcp_channel(stream_command_tx_permit, Bytes::from_static(rhost), rport)
-                .await
-                .or(Err(FatalError::MainLoopExitWithoutSendingStream))?;
+        let mut channel = request_tcp_channel(stream_command_tx_permit, rhost.dupe(), rport)
+            .await
+            .or(Err(FatalError::MainLoopExitWithoutSendingStream))?;
+        // Stdio remotes are usually interactive, e.g. an SSH `ProxyCommand`,
+        // so keep them responsive during bulk transfers on other streams.
+        channel.set_priority(Priority::High);
+        handler_resources.traffic.add_connection();
         match channel.into_copy_bidirectional(&mut stdio).await {
             Ok(_) => {
                 info!("TCP stdio connection closed");
@@ -142,4 +205,28 @@ mod tests {
         stream.shutdown().await.unwrap();
         accept_task.await.unwrap();
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_open_unix_listener() {
+        crate::tests::setup_logging();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("app.sock");
+        let path = path.to_str().unwrap();
+        let listener = open_unix_listener(path).await.unwrap();
+        drop(listener);
+        // The socket file is still there, but can be bound again
+        let listener = open_unix_listener(path).await.unwrap();
+        let accept_task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let mut stream = tokio::net::UnixStream::connect(path).await.unwrap();
+        stream.shutdown().await.unwrap();
+        accept_task.await.unwrap();
+        // Regular files are not replaced
+        let file = tmpdir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(open_unix_listener(file.to_str().unwrap()).await.is_err());
+    }
 }
@@ -0,0 +1,384 @@
+//! Run a transparent proxy remote.
+//!
+//! Connections redirected to the listener by the firewall are tunneled to
+//! their original destination, so that clients do not need to be configured
+//! to use a proxy. Both `iptables` targets are supported:
+//! - `REDIRECT`, where the original destination is kept by `conntrack` and
+//!   read with `SO_ORIGINAL_DST`, and
+//! - `TPROXY`, where the listener is `IP_TRANSPARENT` and the original
+//!   destination is the local address of the connection. This requires
+//!   `CAP_NET_ADMIN`.
+//!
+//! UDP only works with `TPROXY`. The original destination of each datagram
+//! comes from `IP_RECVORIGDSTADDR`, and replies are sent from a socket bound
+//! to that destination and connected to the client, so they appear to come
+//! from the original destination. Later datagrams of the same flow arrive on
+//! that socket instead of the listener.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::FatalError;
+use super::tcp::request_tcp_channel;
+use crate::client::HandlerResources;
+use crate::config;
+use bytes::Bytes;
+use nix::sys::socket::{
+    ControlMessageOwned, MsgFlags, SockaddrIn, SockaddrIn6, SockaddrStorage, recvmsg, setsockopt,
+    sockopt,
+};
+use penguin_mux::{Datagram, Dupe};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::io::IoSliceMut;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsFd, AsRawFd};
+use std::sync::{Arc, Weak};
+use tokio::io::Interest;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, info, trace, warn};
+
+/// Resolve a local address to bind to.
+async fn resolve_local(lhost: &str, lport: u16) -> std::io::Result<SocketAddr> {
+    tokio::net::lookup_host((lhost, lport))
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))
+}
+
+/// Open a TCP listener that can accept `TPROXY`ed connections if permitted.
+#[tracing::instrument(level = "trace")]
+async fn open_transparent_listener(lhost: &str, lport: u16) -> std::io::Result<TcpListener> {
+    let addr = resolve_local(lhost, lport).await?;
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    if let Err(err) = socket.set_ip_transparent(true) {
+        // Without `CAP_NET_ADMIN`, only `REDIRECT` works
+        debug!("Cannot set IP_TRANSPARENT: {err}");
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(socket.into())?;
+    // `expect`: at this point `listener` should be bound. Otherwise, it's a bug.
+    let local_addr = listener
+        .local_addr()
+        .expect("Failed to get local address of TCP listener (this is a bug)");
+    info!("Listening on {local_addr} for redirected connections");
+    Ok(listener)
+}
+
+/// Get the original destination of a redirected connection.
+/// Returns `None` if the connection was made to the listener directly.
+fn original_destination(
+    stream: &TcpStream,
+    listen_port: u16,
+) -> std::io::Result<Option<SocketAddr>> {
+    let local_addr = stream.local_addr()?;
+    let socket = SockRef::from(stream);
+    let dest = socket
+        .original_dst()
+        .or_else(|_| socket.original_dst_ipv6())
+        .ok()
+        .and_then(|addr| addr.as_socket())
+        // `TPROXY`ed connections are not NAT'd
+        .unwrap_or(local_addr);
+    if dest == local_addr && dest.port() == listen_port {
+        // Tunneling this would connect to the server's own port instead
+        return Ok(None);
+    }
+    // Dual-stack listeners see IPv4 destinations as IPv4-mapped addresses
+    Ok(Some(SocketAddr::new(dest.ip().to_canonical(), dest.port())))
+}
+
+/// Handle a TCP Inet->original destination remote.
+#[tracing::instrument(skip(handler_resources), level = "debug")]
+pub(super) async fn handle_transparent(
+    lhost: &str,
+    lport: u16,
+    handler_resources: &HandlerResources,
+) -> Result<(), FatalError> {
+    // Not being able to open a TCP listener is a fatal error.
+    let listener = open_transparent_listener(lhost, lport)
+        .await
+        .map_err(FatalError::ClientIo)?;
+    let listen_port = listener.local_addr().map_err(FatalError::ClientIo)?.port();
+    loop {
+        // This fails only if main has exited, which is a fatal error.
+        let stream_command_tx_permit = handler_resources
+            .stream_command_tx
+            .reserve()
+            .await
+            .or(Err(FatalError::RequestStream))?;
+        // Same backpressure as `handle_tcp`
+        let (tcp_stream, peer) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        let dest = match original_destination(&tcp_stream, listen_port) {
+            Ok(Some(dest)) => dest,
+            Ok(None) => {
+                warn!("Connection from {peer} was not redirected by the firewall");
+                continue;
+            }
+            Err(error) => {
+                warn!("Cannot get the original destination of {peer}: {error}");
+                continue;
+            }
+        };
+        debug!("{peer} is connecting to {dest}");
+        let channel = request_tcp_channel(
+            stream_command_tx_permit,
+            Bytes::from(dest.ip().to_string()),
+            dest.port(),
+        )
+        .await
+        .or(Err(FatalError::MainLoopExitWithoutSendingStream))?;
+        handler_resources.traffic.add_connection();
+        let mut tcp_stream = handler_resources.traffic.count(tcp_stream);
+        // Transient errors in the forwarder don't matter.
+        tokio::spawn(async move {
+            if let Err(error) = channel.into_copy_bidirectional(&mut tcp_stream).await {
+                warn!("Transparent forwarder failed: {error}");
+            }
+        });
+    }
+}
+
+/// Create a UDP socket that can bind to foreign addresses.
+fn transparent_udp_socket(addr: SocketAddr) -> std::io::Result<Socket> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_ip_transparent(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Ask the kernel to report the original destination of received datagrams.
+fn set_recv_original_dst<F: AsFd>(socket: &F, addr: SocketAddr) -> std::io::Result<()> {
+    if addr.is_ipv6() {
+        setsockopt(socket, sockopt::Ipv6OrigDstAddr, &true)?;
+        // Dual-stack sockets report IPv4 destinations separately
+        setsockopt(socket, sockopt::Ipv4OrigDstAddr, &true).ok();
+    } else {
+        setsockopt(socket, sockopt::Ipv4OrigDstAddr, &true)?;
+    }
+    Ok(())
+}
+
+/// Open a UDP socket that receives `TPROXY`ed datagrams.
+#[tracing::instrument(level = "trace")]
+async fn open_transparent_udp_socket(lhost: &str, lport: u16) -> std::io::Result<UdpSocket> {
+    let addr = resolve_local(lhost, lport).await?;
+    let socket = transparent_udp_socket(addr)?;
+    set_recv_original_dst(&socket, addr)?;
+    socket.bind(&addr.into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    // `expect`: at this point `socket` should be bound. Otherwise, it's a bug.
+    let local_addr = socket
+        .local_addr()
+        .expect("Failed to get local address of UDP socket (this is a bug)");
+    info!("Bound on {local_addr} for redirected datagrams");
+    Ok(socket)
+}
+
+/// Open a socket to send replies to `peer` as `dest` and to receive the rest
+/// of the flow.
+fn open_flow_socket(dest: SocketAddr, peer: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = transparent_udp_socket(dest)?;
+    socket.bind(&dest.into())?;
+    socket.connect(&peer.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Convert an address from `nix` and unmap IPv4-mapped addresses.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Receive a datagram with its source and original destination.
+/// The destination is `None` if the kernel did not report it.
+fn recv_with_original_dst<F: AsRawFd>(
+    socket: &F,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg_buffer = nix::cmsg_space!(nix::libc::sockaddr_in6);
+    let msg = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::empty(),
+    )?;
+    let peer = msg
+        .address
+        .and_then(|addr| {
+            addr.as_sockaddr_in()
+                .map(|addr| SocketAddrV4::from(*addr).into())
+                .or_else(|| {
+                    addr.as_sockaddr_in6()
+                        .map(|addr| SocketAddrV6::from(*addr).into())
+                })
+        })
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+    let dest = msg.cmsgs()?.find_map(|cmsg| match cmsg {
+        ControlMessageOwned::Ipv4OrigDstAddr(addr) => {
+            Some(SocketAddrV4::from(SockaddrIn::from(addr)).into())
+        }
+        ControlMessageOwned::Ipv6OrigDstAddr(addr) => {
+            Some(SocketAddrV6::from(SockaddrIn6::from(addr)).into())
+        }
+        _ => None,
+    });
+    Ok((msg.bytes, canonical_addr(peer), dest.map(canonical_addr)))
+}
+
+/// Send a datagram of a flow to the main loop.
+async fn send_flow_datagram(
+    handler_resources: &HandlerResources,
+    flow: &Arc<UdpSocket>,
+    peer: SocketAddr,
+    dest: SocketAddr,
+    data: Vec<u8>,
+) -> Result<(), FatalError> {
+    trace!("received {} bytes from {peer} to {dest}", data.len());
+    // Replies are sent from `flow`, whose local address is `dest`
+    let client_id = handler_resources.add_udp_client(peer, flow.dupe());
+    let len = data.len();
+    let frame = Datagram {
+        target_host: Bytes::from(dest.ip().to_string()),
+        target_port: dest.port(),
+        flow_id: client_id,
+        data: Bytes::from(data),
+    };
+    // This fails only if main has exited, which is a fatal error.
+    handler_resources
+        .datagram_tx
+        .send(frame)
+        .await
+        .or(Err(FatalError::SendDatagram))?;
+    handler_resources.traffic.add_sent(len);
+    Ok(())
+}
+
+/// Forward the datagrams that arrive on a flow socket until the flow expires
+/// from the UDP client maps.
+async fn relay_flow(
+    flow: Arc<UdpSocket>,
+    peer: SocketAddr,
+    dest: SocketAddr,
+    handler_resources: &HandlerResources,
+) -> Result<(), FatalError> {
+    loop {
+        let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+        let len =
+            match tokio::time::timeout(handler_resources.udp_timeout, flow.recv(&mut buf)).await {
+                Ok(result) => result.map_err(FatalError::ClientIo)?,
+                // Only we are left, so the client maps have pruned the flow
+                Err(_) if Arc::strong_count(&flow) == 1 => return Ok(()),
+                Err(_) => continue,
+            };
+        buf.truncate(len);
+        send_flow_datagram(handler_resources, &flow, peer, dest, buf).await?;
+    }
+}
+
+/// Handle a UDP Inet->original destination remote.
+#[tracing::instrument(skip(handler_resources), level = "debug")]
+pub(super) async fn handle_transparent_udp(
+    lhost: &str,
+    lport: u16,
+    handler_resources: &Arc<HandlerResources>,
+) -> Result<(), FatalError> {
+    // Not being able to bind to the local port is a fatal error.
+    let socket = open_transparent_udp_socket(lhost, lport)
+        .await
+        .map_err(FatalError::ClientIo)?;
+    let mut flows: HashMap<(SocketAddr, SocketAddr), Weak<UdpSocket>> = HashMap::new();
+    loop {
+        let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+        // Failing to receive means that the socket is broken, which is fatal.
+        let (len, peer, dest) = socket
+            .async_io(Interest::READABLE, || {
+                recv_with_original_dst(&socket, &mut buf)
+            })
+            .await
+            .map_err(FatalError::ClientIo)?;
+        buf.truncate(len);
+        let Some(dest) = dest else {
+            warn!("Datagram from {peer} was not redirected by the firewall");
+            continue;
+        };
+        let flow = if let Some(flow) = flows.get(&(peer, dest)).and_then(Weak::upgrade) {
+            // Queued before the flow socket was opened
+            flow
+        } else {
+            let flow = match open_flow_socket(dest, peer) {
+                Ok(flow) => Arc::new(flow),
+                Err(error) => {
+                    warn!("Cannot reply to {peer} as {dest}: {error}");
+                    continue;
+                }
+            };
+            flows.retain(|_, flow| flow.strong_count() > 0);
+            flows.insert((peer, dest), Arc::downgrade(&flow));
+            let relayed_flow = flow.dupe();
+            let handler_resources = handler_resources.dupe();
+            tokio::spawn(async move {
+                if let Err(error) = relay_flow(relayed_flow, peer, dest, &handler_resources).await {
+                    debug!("Transparent UDP flow failed: {error}");
+                }
+            });
+            flow
+        };
+        send_flow_datagram(handler_resources, &flow, peer, dest, buf).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_direct_connection_is_not_redirected() {
+        crate::tests::setup_logging();
+        let listener = open_transparent_listener("127.0.0.1", 0).await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(local_addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        assert_eq!(
+            original_destination(&stream, local_addr.port()).unwrap(),
+            None
+        );
+        // As if it were `TPROXY`ed from another port
+        assert_eq!(
+            original_destination(&stream, local_addr.port() ^ 1).unwrap(),
+            Some(local_addr)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recv_with_original_dst() {
+        crate::tests::setup_logging();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        set_recv_original_dst(&socket, local_addr).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"hello", local_addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, peer, dest) = socket
+            .async_io(Interest::READABLE, || {
+                recv_with_original_dst(&socket, &mut buf)
+            })
+            .await
+            .unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(peer, client.local_addr().unwrap());
+        // Without `TPROXY`, the original destination is the socket itself
+        assert_eq!(dest, Some(local_addr));
+    }
+}
@@ -0,0 +1,669 @@
+//! Run a TUN device remote.
+//!
+//! IP packets from the TUN device are handled by a userspace TCP/IP stack
+//! (`smoltcp`) that accepts connections and datagrams to any address. Each
+//! TCP connection is tunneled as a stream to its destination, and each UDP
+//! datagram as a datagram to its destination, so traffic routed to the device
+//! leaves from the server's network.
+//!
+//! The addresses and routes of the device are left to the user, e.g.
+//! ```sh
+//! ip tuntap add mode tun dev penguin0
+//! ip link set penguin0 up
+//! ip route add 10.0.0.0/8 dev penguin0
+//! ```
+//!
+//! The stack runs on its own thread because `smoltcp` devices are not `Send`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::FatalError;
+use super::tcp::request_tcp_channel;
+use crate::client::HandlerResources;
+use crate::config;
+use bytes::{Buf, Bytes};
+use penguin_mux::{Datagram, Dupe};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium, TunTapInterface};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpProtocol, IpVersion, Ipv4Packet, Ipv6Packet,
+    TcpPacket, UdpPacket,
+};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+use tokio::sync::{Notify, mpsc, oneshot};
+use tracing::{debug, info, trace, warn};
+
+/// Address of the stack, which is only used as the gateway of its default
+/// route so that it accepts packets to any address.
+const STACK_IPV4: Ipv4Addr = Ipv4Addr::new(169, 254, 80, 1);
+/// See [`STACK_IPV4`]
+const STACK_IPV6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0x80, 1);
+/// How often to prune idle sockets
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A UDP reply to be written to the TUN device
+#[derive(Debug)]
+pub struct TunDatagram {
+    /// The client on the TUN side
+    pub peer_addr: SocketAddr,
+    /// The address that the client sent to, which is the source of the reply
+    pub our_addr: SocketAddr,
+    pub data: Bytes,
+}
+
+/// A TUN device with a receive queue, so that packets can be inspected before
+/// the stack processes them
+struct TunDevice {
+    tun: TunTapInterface,
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl TunDevice {
+    /// Read the available packets from the device
+    fn read_packets(&mut self) {
+        while let Some((rx, _)) = self.tun.receive(Instant::now()) {
+            let packet = phy::RxToken::consume(rx, <[u8]>::to_vec);
+            self.queue.push_back(packet);
+        }
+    }
+}
+
+/// A packet from the queue of [`TunDevice`]
+struct QueuedRxToken(Vec<u8>);
+
+impl phy::RxToken for QueuedRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl Device for TunDevice {
+    type RxToken<'a> = QueuedRxToken;
+    type TxToken<'a> = <TunTapInterface as Device>::TxToken<'a>;
+
+    fn receive(&mut self, timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        let tx = self.tun.transmit(timestamp)?;
+        let packet = self.queue.pop_front()?;
+        Some((QueuedRxToken(packet), tx))
+    }
+
+    fn transmit(&mut self, timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        self.tun.transmit(timestamp)
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.tun.capabilities()
+    }
+}
+
+/// The file descriptor of the TUN device, for readiness notifications
+struct TunFd(RawFd);
+
+impl AsRawFd for TunFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// A packet that needs a socket to be handled
+#[derive(Debug, PartialEq, Eq)]
+enum NewFlow {
+    /// A TCP SYN
+    Tcp(IpEndpoint, IpEndpoint),
+    /// A UDP datagram
+    Udp(IpEndpoint),
+}
+
+/// Find out whether a packet starts a TCP connection or is a UDP datagram.
+fn inspect_packet(packet: &[u8]) -> Option<NewFlow> {
+    let (protocol, src_addr, dst_addr, payload): (_, IpAddress, IpAddress, _) =
+        match IpVersion::of_packet(packet).ok()? {
+            IpVersion::Ipv4 => {
+                let packet = Ipv4Packet::new_checked(packet).ok()?;
+                if packet.frag_offset() != 0 {
+                    return None;
+                }
+                (
+                    packet.next_header(),
+                    packet.src_addr().into(),
+                    packet.dst_addr().into(),
+                    packet.payload(),
+                )
+            }
+            IpVersion::Ipv6 => {
+                let packet = Ipv6Packet::new_checked(packet).ok()?;
+                (
+                    packet.next_header(),
+                    packet.src_addr().into(),
+                    packet.dst_addr().into(),
+                    packet.payload(),
+                )
+            }
+        };
+    match protocol {
+        IpProtocol::Tcp => {
+            let segment = TcpPacket::new_checked(payload).ok()?;
+            (segment.syn() && !segment.ack()).then(|| {
+                NewFlow::Tcp(
+                    IpEndpoint::new(src_addr, segment.src_port()),
+                    IpEndpoint::new(dst_addr, segment.dst_port()),
+                )
+            })
+        }
+        IpProtocol::Udp => {
+            let datagram = UdpPacket::new_checked(payload).ok()?;
+            Some(NewFlow::Udp(IpEndpoint::new(dst_addr, datagram.dst_port())))
+        }
+        _ => None,
+    }
+}
+
+#[inline]
+fn to_socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(IpAddr::from(endpoint.addr), endpoint.port)
+}
+
+/// A TCP connection on the TUN device
+struct TcpFlow {
+    /// Client and destination
+    endpoints: (IpEndpoint, IpEndpoint),
+    /// Data from the client to the stream. `None` after the client's FIN.
+    to_mux: Option<mpsc::Sender<Bytes>>,
+    /// Data from the stream to the client
+    from_mux: mpsc::Receiver<Bytes>,
+    /// Part of a chunk from `from_mux` that did not fit in the socket
+    pending: Bytes,
+    /// Whether the stream has finished
+    mux_eof: bool,
+    created: std::time::Instant,
+}
+
+/// A UDP destination on the TUN device
+struct UdpBinding {
+    handle: SocketHandle,
+    last_used: std::time::Instant,
+}
+
+/// The userspace network stack
+struct Stack {
+    iface: Interface,
+    device: TunDevice,
+    sockets: SocketSet<'static>,
+    tcp_flows: HashMap<SocketHandle, TcpFlow>,
+    /// Handles of the TCP connections, to ignore retransmitted SYNs
+    tcp_endpoints: HashMap<(IpEndpoint, IpEndpoint), SocketHandle>,
+    udp_bindings: HashMap<IpEndpoint, UdpBinding>,
+    handler_resources: Arc<HandlerResources>,
+    udp_reply_tx: mpsc::Sender<TunDatagram>,
+    /// Notified by the stream tasks when the stack has work to do
+    wake: Rc<Notify>,
+    last_prune: std::time::Instant,
+}
+
+impl Stack {
+    fn new(
+        tun: TunTapInterface,
+        handler_resources: Arc<HandlerResources>,
+        udp_reply_tx: mpsc::Sender<TunDatagram>,
+    ) -> Self {
+        let mut device = TunDevice {
+            tun,
+            queue: VecDeque::new(),
+        };
+        let mut iface = Interface::new(
+            Config::new(HardwareAddress::Ip),
+            &mut device,
+            Instant::now(),
+        );
+        iface.set_any_ip(true);
+        iface.update_ip_addrs(|addrs| {
+            addrs
+                .push(IpCidr::new(STACK_IPV4.into(), 32))
+                .expect("Too many addresses (this is a bug)");
+            addrs
+                .push(IpCidr::new(STACK_IPV6.into(), 128))
+                .expect("Too many addresses (this is a bug)");
+        });
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(STACK_IPV4)
+            .expect("Too many routes (this is a bug)");
+        iface
+            .routes_mut()
+            .add_default_ipv6_route(STACK_IPV6)
+            .expect("Too many routes (this is a bug)");
+        Self {
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            tcp_flows: HashMap::new(),
+            tcp_endpoints: HashMap::new(),
+            udp_bindings: HashMap::new(),
+            handler_resources,
+            udp_reply_tx,
+            wake: Rc::new(Notify::new()),
+            last_prune: std::time::Instant::now(),
+        }
+    }
+
+    /// Read packets from the device and open sockets for new flows
+    fn receive(&mut self) {
+        let old_len = self.device.queue.len();
+        self.device.read_packets();
+        let new_flows = self
+            .device
+            .queue
+            .iter()
+            .skip(old_len)
+            .filter_map(|packet| inspect_packet(packet))
+            .collect::<Vec<_>>();
+        for new_flow in new_flows {
+            match new_flow {
+                NewFlow::Tcp(client, dest) => self.open_tcp_flow(client, dest),
+                NewFlow::Udp(dest) => {
+                    self.udp_binding(dest);
+                }
+            }
+        }
+    }
+
+    /// Listen for a new TCP connection and start requesting its stream
+    fn open_tcp_flow(&mut self, client: IpEndpoint, dest: IpEndpoint) {
+        if self.tcp_endpoints.contains_key(&(client, dest)) {
+            return;
+        }
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; config::TUN_TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; config::TUN_TCP_BUFFER_SIZE]),
+        );
+        if let Err(err) = socket.listen(dest) {
+            debug!("Cannot accept connections to {dest}: {err}");
+            return;
+        }
+        let handle = self.sockets.add(socket);
+        let (to_mux_tx, to_mux_rx) = mpsc::channel(config::TUN_FLOW_CHANNEL_SIZE);
+        let (from_mux_tx, from_mux_rx) = mpsc::channel(config::TUN_FLOW_CHANNEL_SIZE);
+        debug!("{client} is connecting to {dest}");
+        tokio::task::spawn_local(relay_tcp_flow(
+            to_socket_addr(dest),
+            self.handler_resources.dupe(),
+            to_mux_rx,
+            from_mux_tx,
+            self.wake.clone(),
+        ));
+        self.tcp_endpoints.insert((client, dest), handle);
+        self.tcp_flows.insert(
+            handle,
+            TcpFlow {
+                endpoints: (client, dest),
+                to_mux: Some(to_mux_tx),
+                from_mux: from_mux_rx,
+                pending: Bytes::new(),
+                mux_eof: false,
+                created: std::time::Instant::now(),
+            },
+        );
+    }
+
+    /// Get the socket bound to a UDP destination, opening one if needed
+    fn udp_binding(&mut self, dest: IpEndpoint) -> Option<SocketHandle> {
+        if let Some(binding) = self.udp_bindings.get_mut(&dest) {
+            binding.last_used = std::time::Instant::now();
+            return Some(binding.handle);
+        }
+        let buffer = || {
+            udp::PacketBuffer::new(
+                vec![udp::PacketMetadata::EMPTY; config::TUN_UDP_PACKET_COUNT],
+                vec![0; config::MAX_UDP_PACKET_SIZE],
+            )
+        };
+        let mut socket = udp::Socket::new(buffer(), buffer());
+        if let Err(err) = socket.bind(dest) {
+            debug!("Cannot receive datagrams to {dest}: {err}");
+            return None;
+        }
+        let handle = self.sockets.add(socket);
+        self.udp_bindings.insert(
+            dest,
+            UdpBinding {
+                handle,
+                last_used: std::time::Instant::now(),
+            },
+        );
+        Some(handle)
+    }
+
+    /// Process packets and move data between the sockets and the streams
+    fn poll(&mut self) -> Result<(), FatalError> {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.service_tcp();
+        self.service_udp()?;
+        // Send what the services have written
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        if self.last_prune.elapsed() >= PRUNE_INTERVAL {
+            self.prune();
+        }
+        Ok(())
+    }
+
+    /// Move data between the TCP sockets and their streams
+    fn service_tcp(&mut self) {
+        let mut closed = Vec::new();
+        for (&handle, flow) in &mut self.tcp_flows {
+            let socket = self.sockets.get_mut::<tcp::Socket<'_>>(handle);
+            // Client -> stream
+            if let Some(to_mux) = &flow.to_mux {
+                if to_mux.is_closed() {
+                    // The stream failed, and data cannot be delivered anymore
+                    socket.abort();
+                }
+                while socket.can_recv() {
+                    let Ok(permit) = to_mux.try_reserve() else {
+                        break;
+                    };
+                    let data = socket
+                        .recv(|buf| (buf.len(), Bytes::copy_from_slice(buf)))
+                        .unwrap_or_default();
+                    permit.send(data);
+                }
+                let handshaking =
+                    matches!(socket.state(), tcp::State::Listen | tcp::State::SynReceived);
+                if !socket.may_recv() && !socket.can_recv() && !handshaking {
+                    // The client has sent FIN
+                    flow.to_mux = None;
+                }
+            }
+            // Stream -> client
+            loop {
+                if flow.pending.is_empty() {
+                    match flow.from_mux.try_recv() {
+                        Ok(data) => flow.pending = data,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            if !flow.mux_eof {
+                                flow.mux_eof = true;
+                                socket.close();
+                            }
+                            break;
+                        }
+                    }
+                }
+                if !socket.can_send() {
+                    break;
+                }
+                match socket.send_slice(&flow.pending) {
+                    Ok(len) => flow.pending.advance(len),
+                    Err(_) => break,
+                }
+            }
+            if socket.state() == tcp::State::Closed {
+                closed.push(handle);
+            }
+        }
+        for handle in closed {
+            self.remove_tcp_flow(handle);
+        }
+    }
+
+    fn remove_tcp_flow(&mut self, handle: SocketHandle) {
+        self.sockets.remove(handle);
+        if let Some(flow) = self.tcp_flows.remove(&handle) {
+            self.tcp_endpoints.remove(&flow.endpoints);
+        }
+    }
+
+    /// Send the datagrams received on the UDP sockets to the main loop
+    fn service_udp(&mut self) -> Result<(), FatalError> {
+        for (dest, binding) in &mut self.udp_bindings {
+            let socket = self.sockets.get_mut::<udp::Socket<'_>>(binding.handle);
+            while let Ok((data, meta)) = socket.recv() {
+                binding.last_used = std::time::Instant::now();
+                let our_addr = to_socket_addr(*dest);
+                let peer_addr = to_socket_addr(meta.endpoint);
+                trace!(
+                    "received {} bytes from {peer_addr} to {our_addr}",
+                    data.len()
+                );
+                let client_id = self.handler_resources.add_tun_udp_client(
+                    peer_addr,
+                    our_addr,
+                    self.udp_reply_tx.clone(),
+                );
+                let frame = Datagram {
+                    target_host: Bytes::from(our_addr.ip().to_string()),
+                    target_port: our_addr.port(),
+                    flow_id: client_id,
+                    data: Bytes::copy_from_slice(data),
+                };
+                match self.handler_resources.datagram_tx.try_send(frame) {
+                    Ok(()) => self.handler_resources.traffic.add_sent(data.len()),
+                    // Like a full network queue
+                    Err(TrySendError::Full(_)) => trace!("dropping datagram from {peer_addr}"),
+                    // This fails only if main has exited, which is a fatal error.
+                    Err(TrySendError::Closed(_)) => return Err(FatalError::SendDatagram),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a UDP reply to the device
+    fn write_udp_reply(&mut self, datagram: &TunDatagram) {
+        let Some(handle) = self.udp_binding(datagram.our_addr.into()) else {
+            return;
+        };
+        let socket = self.sockets.get_mut::<udp::Socket<'_>>(handle);
+        if let Err(err) = socket.send_slice(&datagram.data, datagram.peer_addr) {
+            trace!("dropping datagram to {}: {err}", datagram.peer_addr);
+        }
+    }
+
+    /// Remove idle UDP sockets and TCP sockets that never completed the
+    /// handshake
+    fn prune(&mut self) {
+        let now = std::time::Instant::now();
+        self.last_prune = now;
+        let stale_udp = self
+            .udp_bindings
+            .iter()
+            .filter(|(_, binding)| now - binding.last_used > self.handler_resources.udp_timeout)
+            .map(|(dest, _)| *dest)
+            .collect::<Vec<_>>();
+        for dest in stale_udp {
+            if let Some(binding) = self.udp_bindings.remove(&dest) {
+                self.sockets.remove(binding.handle);
+            }
+        }
+        let stale_tcp = self
+            .tcp_flows
+            .iter()
+            .filter(|(handle, flow)| {
+                let state = self.sockets.get::<tcp::Socket<'_>>(**handle).state();
+                matches!(state, tcp::State::Listen | tcp::State::SynReceived)
+                    && now - flow.created > config::TUN_HANDSHAKE_TIMEOUT
+            })
+            .map(|(handle, _)| *handle)
+            .collect::<Vec<_>>();
+        for handle in stale_tcp {
+            self.remove_tcp_flow(handle);
+        }
+    }
+
+    /// How long to wait before the next poll if nothing happens
+    fn poll_delay(&mut self) -> Duration {
+        self.iface
+            .poll_delay(Instant::now(), &self.sockets)
+            .map_or(PRUNE_INTERVAL, |delay| {
+                Duration::from_micros(delay.total_micros()).min(PRUNE_INTERVAL)
+            })
+    }
+}
+
+/// Request a stream for a TCP connection and relay data between them
+async fn relay_tcp_flow(
+    dest: SocketAddr,
+    handler_resources: Arc<HandlerResources>,
+    mut to_mux: mpsc::Receiver<Bytes>,
+    from_mux: mpsc::Sender<Bytes>,
+    wake: Rc<Notify>,
+) {
+    // Dropping the channels tells the stack that the stream failed
+    let Ok(stream_command_tx_permit) = handler_resources.stream_command_tx.reserve().await else {
+        return;
+    };
+    let Ok(stream) = request_tcp_channel(
+        stream_command_tx_permit,
+        Bytes::from(dest.ip().to_string()),
+        dest.port(),
+    )
+    .await
+    else {
+        return;
+    };
+    let traffic = &handler_resources.traffic;
+    traffic.add_connection();
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mux_to_stack = async {
+        let mut buf = vec![0; config::TUN_TCP_BUFFER_SIZE];
+        while let Ok(len @ 1..) = reader.read(&mut buf).await {
+            traffic.add_received(len);
+            if from_mux
+                .send(Bytes::copy_from_slice(&buf[..len]))
+                .await
+                .is_err()
+            {
+                break;
+            }
+            wake.notify_one();
+        }
+        drop(from_mux);
+        wake.notify_one();
+    };
+    let stack_to_mux = async {
+        while let Some(data) = to_mux.recv().await {
+            // There is room in the channel now
+            wake.notify_one();
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+            traffic.add_sent(data.len());
+        }
+        writer.shutdown().await.ok();
+    };
+    tokio::join!(mux_to_stack, stack_to_mux);
+}
+
+/// Run the stack until `stop_rx` fires
+async fn run_stack(
+    name: &str,
+    handler_resources: Arc<HandlerResources>,
+    mut stop_rx: oneshot::Receiver<()>,
+) -> Result<(), FatalError> {
+    let tun = TunTapInterface::new(name, Medium::Ip).map_err(FatalError::ClientIo)?;
+    let (udp_reply_tx, mut udp_reply_rx) = mpsc::channel(config::INCOMING_DATAGRAM_BUFFER_SIZE);
+    let mut stack = Stack::new(tun, handler_resources, udp_reply_tx);
+    // Declared after `stack` so that it is deregistered before the device is closed
+    let tun_fd = AsyncFd::with_interest(TunFd(stack.device.tun.as_raw_fd()), Interest::READABLE)
+        .map_err(FatalError::ClientIo)?;
+    let wake = stack.wake.clone();
+    info!("Tunneling flows from TUN device {name}");
+    loop {
+        stack.receive();
+        stack.poll()?;
+        let delay = stack.poll_delay();
+        tokio::select! {
+            guard = tun_fd.readable() => {
+                // `receive` reads until the device would block
+                guard.map_err(FatalError::ClientIo)?.clear_ready();
+            }
+            () = wake.notified() => {}
+            Some(datagram) = udp_reply_rx.recv() => stack.write_udp_reply(&datagram),
+            () = tokio::time::sleep(delay) => {}
+            _ = &mut stop_rx => return Ok(()),
+        }
+    }
+}
+
+/// Handle a TUN->original destination remote.
+#[tracing::instrument(skip(handler_resources), level = "debug")]
+pub(super) async fn handle_tun(
+    name: &str,
+    handler_resources: &Arc<HandlerResources>,
+) -> Result<(), FatalError> {
+    let name = name.to_owned();
+    let handler_resources = handler_resources.dupe();
+    // Dropped when this task is aborted, which stops the stack
+    let (_stop_tx, stop_rx) = oneshot::channel();
+    let (result_tx, result_rx) = oneshot::channel();
+    std::thread::Builder::new()
+        .name(format!("tun {name}"))
+        .spawn(move || {
+            let result = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(FatalError::ClientIo)
+                .and_then(|runtime| {
+                    tokio::task::LocalSet::new()
+                        .block_on(&runtime, run_stack(&name, handler_resources, stop_rx))
+                });
+            if let Err(err) = &result {
+                warn!("TUN device {name} failed: {err}");
+            }
+            // Nobody is waiting if the remote has been stopped
+            result_tx.send(result).ok();
+        })
+        .map_err(FatalError::ClientIo)?;
+    result_rx
+        .await
+        .expect("TUN thread exited without a result (this is a bug)")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspect_packet() {
+        crate::tests::setup_logging();
+        // IPv4 TCP SYN from 10.0.0.2:40000 to 192.0.2.1:80
+        let mut packet = vec![
+            0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 2, 192, 0, 2, 1, // IPv4
+            0x9c, 0x40, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0,
+            0, // TCP
+        ];
+        let mut ipv4 = Ipv4Packet::new_unchecked(&mut packet);
+        ipv4.fill_checksum();
+        let client = IpEndpoint::new(Ipv4Addr::new(10, 0, 0, 2).into(), 40000);
+        let dest = IpEndpoint::new(Ipv4Addr::new(192, 0, 2, 1).into(), 80);
+        assert_eq!(inspect_packet(&packet), Some(NewFlow::Tcp(client, dest)));
+        // SYN-ACK does not start a connection
+        packet[33] = 0x12;
+        assert_eq!(inspect_packet(&packet), None);
+        // IPv6 UDP from [fd00::2]:5353 to [2001:db8::1]:53
+        let mut packet = vec![0x60, 0, 0, 0, 0, 8, 17, 64];
+        packet.extend_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        packet.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        packet.extend_from_slice(&[0x14, 0xe9, 0, 53, 0, 8, 0, 0]);
+        let dest = IpEndpoint::new(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(), 53);
+        assert_eq!(inspect_packet(&packet), Some(NewFlow::Udp(dest)));
+        assert_eq!(inspect_packet(&packet[..20]), None);
+    }
+}
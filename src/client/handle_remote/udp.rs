@@ -16,9 +16,9 @@ use tracing::{info, trace};
 #[inline]
 #[tracing::instrument(skip(handler_resources), level = "debug")]
 pub(super) async fn handle_udp(
-    lhost: &'static str,
+    lhost: &str,
     lport: u16,
-    rhost: &'static str,
+    rhost: &str,
     rport: u16,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
@@ -32,21 +32,21 @@ pub(super) async fn handle_udp(
         .local_addr()
         .expect("Failed to get local address of UDP socket (this is a bug)");
     info!("Bound on {local_addr}");
+    let rhost = Bytes::copy_from_slice(rhost.as_bytes());
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
     loop {
-        let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
         // `recv_from` can fail if the socket is closed, which is a fatal error.
         let (len, addr) = socket
             .recv_from(&mut buf)
             .await
             .map_err(FatalError::ClientIo)?;
-        buf.truncate(len);
         trace!("received {len} bytes from {addr}");
-        let client_id = handler_resources.add_udp_client(addr, socket.dupe(), false);
+        let client_id = handler_resources.add_udp_client(addr, socket.dupe());
         let frame = Datagram {
-            target_host: Bytes::from(rhost),
+            target_host: rhost.dupe(),
             target_port: rport,
             flow_id: client_id,
-            data: Bytes::from(buf),
+            data: handler_resources.buffer_pool.copy_from_slice(&buf[..len]),
         };
         // This fails only if main has exited, which is a fatal error.
         handler_resources
@@ -54,6 +54,7 @@ pub(super) async fn handle_udp(
             .send(frame)
             .await
             .or(Err(FatalError::SendDatagram))?;
+        handler_resources.traffic.add_sent(len);
     }
 }
 
@@ -61,10 +62,11 @@ pub(super) async fn handle_udp(
 #[inline]
 #[tracing::instrument(skip(handler_resources), level = "debug")]
 pub(super) async fn handle_udp_stdio(
-    rhost: &'static str,
+    rhost: &str,
     rport: u16,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
+    let rhost = Bytes::copy_from_slice(rhost.as_bytes());
     let mut stdin = BufReader::new(tokio::io::stdin());
     loop {
         let mut line = String::new();
@@ -73,8 +75,9 @@ pub(super) async fn handle_udp_stdio(
             .read_line(&mut line)
             .await
             .map_err(FatalError::ClientIo)?;
+        let len = line.len();
         let frame = Datagram {
-            target_host: Bytes::from_static(rhost.as_bytes()),
+            target_host: rhost.dupe(),
             target_port: rport,
             flow_id: 0,
             data: Bytes::from(line),
@@ -85,13 +88,14 @@ pub(super) async fn handle_udp_stdio(
             .send(frame)
             .await
             .or(Err(FatalError::SendDatagram))?;
+        handler_resources.traffic.add_sent(len);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::client::ClientIdMaps;
+    use crate::client::{ClientIdMaps, SocksPolicy};
     use parking_lot::RwLock;
 
     #[tokio::test]
@@ -106,6 +110,10 @@ mod tests {
             datagram_tx,
             stream_command_tx,
             udp_client_map: udp_client_map.dupe(),
+            socks_policy: SocksPolicy::default(),
+            buffer_pool: penguin_mux::BufferPool::new(),
+            traffic: Arc::default(),
+            udp_timeout: crate::config::UDP_PRUNE_TIMEOUT,
         };
         let forwarding_task =
             tokio::spawn(
@@ -122,7 +130,7 @@ mod tests {
         let client_id = *udp_client_map
             .read()
             .client_addr_map
-            .get(&(local_addr, ([127, 0, 0, 1], 14196).into()))
+            .get(&(local_addr, ([127, 0, 0, 1], 14196).into(), None))
             .unwrap();
         assert_eq!(frame.flow_id, client_id);
         forwarding_task.abort();
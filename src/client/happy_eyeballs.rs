@@ -0,0 +1,185 @@
+//! Racing connections to the addresses of the server, like Happy Eyeballs
+//! (RFC 8305).
+//!
+//! The addresses are tried alternating between IPv6 and IPv4, and each
+//! attempt gets `CONNECTION_ATTEMPT_DELAY` to finish before the next one
+//! starts alongside it. A failed attempt starts the next one right away.
+//! An attempt covers everything up to a usable stream, TLS included, so a
+//! path that accepts TCP but stalls the TLS handshake loses the race.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::debug;
+
+/// Order `addrs` so that the address families alternate, starting with the
+/// family of the first address. Addresses of a family keep their order.
+pub(super) fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first_is_ipv6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return addrs;
+    };
+    let mut result = Vec::with_capacity(addrs.len());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return result,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Run `connect` on each of `addrs`, starting the next attempt after `delay`
+/// or as soon as one fails, and return the first success. If every attempt
+/// fails, return the error of the last one to fail. `addrs` must not be
+/// empty.
+pub(super) async fn race<T, E, F, Fut>(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    mut connect: F,
+) -> Result<T, E>
+where
+    F: FnMut(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Display,
+{
+    let mut pending = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(tagged(addr, connect(addr))),
+                // `expect`: there was at least one attempt
+                None => return Err(last_err.expect("No addresses to race (this is a bug)")),
+            }
+        }
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(value) => return Ok(value),
+                Err(err) => {
+                    debug!("Cannot connect to {addr}: {err}");
+                    last_err = Some(err);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(tagged(addr, connect(addr)));
+                    }
+                }
+            },
+            () = tokio::time::sleep(delay), if pending.len() > 0 => {
+                // `expect`: `pending` is not empty
+                let addr = pending.next().expect("No address to try (this is a bug)");
+                debug!("Connection attempt timed out, also trying {addr}");
+                attempts.push(tagged(addr, connect(addr)));
+            }
+        }
+    }
+}
+
+/// One attempt, tagged with its address for logging
+async fn tagged<T, E>(
+    addr: SocketAddr,
+    attempt: impl Future<Output = Result<T, E>>,
+) -> (SocketAddr, Result<T, E>) {
+    (addr, attempt.await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::Instant;
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave() {
+        assert_eq!(
+            interleave(addrs(&[
+                "[2001:db8::1]:443",
+                "[2001:db8::2]:443",
+                "[2001:db8::3]:443",
+                "192.0.2.1:443",
+                "192.0.2.2:443",
+            ])),
+            addrs(&[
+                "[2001:db8::1]:443",
+                "192.0.2.1:443",
+                "[2001:db8::2]:443",
+                "192.0.2.2:443",
+                "[2001:db8::3]:443",
+            ])
+        );
+        assert_eq!(
+            interleave(addrs(&["192.0.2.1:80", "192.0.2.2:80", "[2001:db8::1]:80"])),
+            addrs(&["192.0.2.1:80", "[2001:db8::1]:80", "192.0.2.2:80"])
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_stalled_first() {
+        crate::tests::setup_logging();
+        let list = addrs(&["[2001:db8::1]:443", "192.0.2.1:443"]);
+        let start = Instant::now();
+        let winner = race(&list, Duration::from_millis(250), |addr| async move {
+            if addr.is_ipv6() {
+                // A black hole
+                std::future::pending::<()>().await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok::<_, io::Error>(addr)
+        })
+        .await
+        .unwrap();
+        assert_eq!(winner, list[1]);
+        assert_eq!(start.elapsed(), Duration::from_millis(260));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_failed_first() {
+        crate::tests::setup_logging();
+        let list = addrs(&["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443"]);
+        let refused = list[0];
+        let tried = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+        let winner = race(&list, Duration::from_millis(250), |addr| {
+            let tried = tried.clone();
+            async move {
+                tried.fetch_add(1, Ordering::Relaxed);
+                if addr == refused {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+                }
+                Ok(addr)
+            }
+        })
+        .await
+        .unwrap();
+        // The refusal starts the next attempt without waiting
+        assert_eq!(winner, list[1]);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(tried.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race_all_fail() {
+        crate::tests::setup_logging();
+        let list = addrs(&["192.0.2.1:443", "192.0.2.2:443"]);
+        let err = race(&list, Duration::from_millis(250), |addr| async move {
+            Err::<(), _>(io::Error::other(addr.to_string()))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "192.0.2.2:443");
+    }
+}
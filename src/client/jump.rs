@@ -0,0 +1,128 @@
+//! Chaining through intermediate servers with `--jump`.
+//!
+//! The connection to each server runs in a stream on the multiplexor of the
+//! hop before it, like SSH's `ProxyJump`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use super::bond::mux_options;
+use super::ws_connect::handshake;
+use crate::arg::ClientArgs;
+use penguin_mux::{Multiplexor, MuxStream};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+/// A stream through a hop, which keeps the multiplexor of the hop alive
+#[derive(Debug)]
+pub struct HopStream {
+    stream: MuxStream,
+    _mux: Multiplexor,
+}
+
+impl AsyncRead for HopStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HopStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// The arguments to connect to the last hop with, through the hops before it.
+/// Options that only concern the final server are cleared.
+fn hop_args(args: &ClientArgs) -> ClientArgs {
+    let (hop, before) = args
+        .jump
+        .split_last()
+        .expect("Jumping without a hop (this is a bug)");
+    ClientArgs {
+        server: hop.clone(),
+        jump: before.to_vec(),
+        resolve: None,
+        alt_port: Vec::new(),
+        sni: None,
+        hostname: None,
+        #[cfg(feature = "e2e")]
+        e2e_key: None,
+        ..args.clone()
+    }
+}
+
+/// Open a stream to `host:port` through the hops in `args.jump`.
+/// Each hop is connected through the ones before it.
+pub fn connect<'a>(
+    args: &'a ClientArgs,
+    host: &'a str,
+    port: u16,
+) -> Pin<Box<dyn Future<Output = Result<HopStream, Error>> + Send + 'a>> {
+    Box::pin(async move {
+        let hop_args = hop_args(args);
+        let (ws_stream, _, agreed) = handshake(&hop_args, None).await?;
+        debug!("Connected to hop {}", hop_args.server.0);
+        let mux = Multiplexor::new(ws_stream, Some(mux_options(&hop_args, agreed)), None);
+        let stream = mux.new_stream_channel(host.as_bytes(), port).await?;
+        Ok(HopStream { stream, _mux: mux })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arg::ServerUrl;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_hop_args() {
+        crate::tests::setup_logging();
+        let hops = ["wss://hop1.example.com", "wss://hop2.example.com"]
+            .map(|hop| ServerUrl::from_str(hop).unwrap());
+        let args = ClientArgs {
+            server: ServerUrl::from_str("wss://example.com").unwrap(),
+            jump: hops.to_vec(),
+            sni: Some("front.example.com".to_string()),
+            ..Default::default()
+        };
+        let last = hop_args(&args);
+        assert_eq!(last.server, hops[1]);
+        assert_eq!(last.jump, &hops[..1]);
+        assert_eq!(last.sni, None);
+        let first = hop_args(&last);
+        assert_eq!(first.server, hops[0]);
+        assert!(first.jump.is_empty());
+    }
+}
@@ -56,11 +56,43 @@ impl MaybeRetryableError for tokio_tungstenite::tungstenite::Error {
 impl MaybeRetryableError for penguin_mux::Error {
     fn retryable(&self) -> bool {
         match self {
-            Self::SendStreamToClient | Self::Closed => true,
+            Self::SendStreamToClient
+            | Self::Closed
+            | Self::ResumeTimeout
+            | Self::ResumeMismatch
+            | Self::PongTimeout => true,
             #[cfg(feature = "tungstenite")]
             Self::WebSocket(e) => e
                 .downcast_ref::<tokio_tungstenite::tungstenite::Error>()
-                .is_some_and(MaybeRetryableError::retryable),
+                .map(MaybeRetryableError::retryable)
+                // The plain transport reports I/O errors directly
+                .or_else(|| {
+                    e.downcast_ref::<std::io::Error>()
+                        .map(MaybeRetryableError::retryable)
+                })
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(feature = "e2e")]
+impl MaybeRetryableError for crate::e2e::Error {
+    fn retryable(&self) -> bool {
+        match self {
+            Self::WebSocket(e) => e.retryable(),
+            // The server closes the connection if we have the wrong key
+            Self::Closed | Self::Crypto | Self::NonceExhausted => false,
+        }
+    }
+}
+
+#[cfg(feature = "chisel")]
+impl MaybeRetryableError for crate::chisel::Error {
+    fn retryable(&self) -> bool {
+        match self {
+            Self::WebSocket(e) => e.retryable(),
+            Self::Closed | Self::Disconnected(_) | Self::SequenceExhausted => true,
             _ => false,
         }
     }
@@ -70,8 +102,16 @@ impl MaybeRetryableError for super::Error {
     fn retryable(&self) -> bool {
         match self {
             Self::Tungstenite(e) => e.retryable(),
+            Self::Connect(e) => e.retryable(),
+            #[cfg(feature = "e2e")]
+            Self::E2e(e) => e.retryable(),
+            #[cfg(feature = "chisel")]
+            Self::Chisel(e) => e.retryable(),
             Self::Mux(e) => e.retryable(),
-            Self::HandshakeTimeout | Self::StreamRequestTimeout | Self::RemoteDisconnected => true,
+            Self::HandshakeTimeout
+            | Self::StreamRequestTimeout
+            | Self::RemoteDisconnected
+            | Self::Detached => true,
             _ => false,
         }
     }
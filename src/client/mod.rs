@@ -2,31 +2,49 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+pub mod bench;
+mod bond;
+#[cfg(feature = "chisel")]
+mod chisel;
+mod control;
 mod handle_remote;
+mod happy_eyeballs;
+mod jump;
 mod maybe_retryable;
+pub mod ping;
+mod proxy;
+mod proxy_dns;
+mod reverse;
+mod status;
+mod traffic;
 pub mod ws_connect;
 
-use self::handle_remote::handle_remote;
+use self::bond::Bond;
+use self::control::{ControlListener, RemoteTasks};
+use self::handle_remote::socks::SocksPolicy;
 use self::maybe_retryable::MaybeRetryableError;
+use self::status::Status;
+use self::traffic::RemoteTraffic;
+use crate::acl::Acl;
 use crate::arg::ClientArgs;
 use crate::config;
+use crate::reverse_udp::REVERSE_UDP_HOST;
+use crate::udp_error::UDP_ERROR_HOST;
 use bytes::Bytes;
 use futures_util::TryFutureExt;
 use parking_lot::RwLock;
 use penguin_mux::timing::{Backoff, OptionalDuration};
-use penguin_mux::{Datagram, Dupe, IntKey, Multiplexor, MuxStream};
+use penguin_mux::{BufferPool, Datagram, Dupe, IntKey, Multiplexor, MuxStream};
 use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::{Arc, OnceLock};
+use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::io::AsyncWriteExt;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::{mpsc, oneshot};
-use tokio::task::JoinSet;
 use tokio::time;
-use tokio_tungstenite::MaybeTlsStream;
-use tracing::{error, info, trace, warn};
+use tracing::{debug, error, info, trace, warn};
 
 #[cfg(feature = "nohash")]
 use nohash_hasher::IntMap;
@@ -50,6 +68,21 @@ pub enum Error {
     Tls(#[from] crate::tls::Error),
     #[error(transparent)]
     Mux(#[from] penguin_mux::Error),
+    #[error("Cannot connect through the proxy: {0}")]
+    Proxy(#[from] proxy::Error),
+    #[error("Cannot connect to the server: {0}")]
+    Connect(std::io::Error),
+    #[cfg(not(target_os = "linux"))]
+    #[error("`--bind-iface` is only supported on Linux")]
+    BindIfaceUnsupported,
+    #[cfg(feature = "e2e")]
+    #[error(transparent)]
+    E2e(#[from] crate::e2e::Error),
+    #[cfg(feature = "quic")]
+    #[error("{0} is not supported with `wss+quic://`")]
+    QuicUnsupported(&'static str),
+    #[error("Server closed the connection during the handshake")]
+    HandshakeRejected,
     #[error("Initial WebSocket handshake timed out")]
     HandshakeTimeout,
     #[error("User cancelled initial WebSocket handshake")]
@@ -58,6 +91,20 @@ pub enum Error {
     StreamRequestTimeout,
     #[error("Remote disconnected normally")]
     RemoteDisconnected,
+    #[error("Lost connection to the server")]
+    Detached,
+    #[error("Control socket error: {0}")]
+    Control(std::io::Error),
+    #[error("Cannot listen for status requests: {0}")]
+    Status(std::io::Error),
+    #[error("Benchmark failed (is the server started with --bench?): {0}")]
+    Bench(std::io::Error),
+    #[cfg(feature = "chisel")]
+    #[error(transparent)]
+    Chisel(#[from] crate::chisel::Error),
+    #[cfg(feature = "chisel")]
+    #[error("Remote `{0}` is not supported with --chisel")]
+    ChiselRemote(String),
 }
 
 // Send the information about how to send the stream to the listener
@@ -79,6 +126,14 @@ pub struct HandlerResources {
     datagram_tx: mpsc::Sender<Datagram>,
     /// The map of client IDs to UDP sockets and the map of client addresses to client IDs
     udp_client_map: Arc<RwLock<ClientIdMaps>>,
+    /// Requirements and restrictions of SOCKS clients
+    socks_policy: SocksPolicy,
+    /// Where received UDP packets are kept
+    buffer_pool: BufferPool,
+    /// Traffic of the remote these resources are handed to
+    traffic: Arc<RemoteTraffic>,
+    /// How long local UDP clients live without traffic
+    udp_timeout: Duration,
 }
 
 impl HandlerResources {
@@ -100,38 +155,101 @@ impl HandlerResources {
                 stream_command_tx,
                 datagram_tx,
                 udp_client_map: udp_client_map.dupe(),
+                socks_policy: SocksPolicy::default(),
+                buffer_pool: BufferPool::new(),
+                traffic: Arc::default(),
+                udp_timeout: config::UDP_PRUNE_TIMEOUT,
             },
             stream_command_rx,
             datagram_rx,
         )
     }
 
+    /// The resources of a remote, which counts its traffic in `traffic`
+    fn for_remote(&self, traffic: Arc<RemoteTraffic>) -> Self {
+        Self {
+            traffic,
+            ..self.clone()
+        }
+    }
+
     /// Add a new UDP client to the maps, returns the new client ID
     #[must_use = "This function returns the new client ID, which should be used to mark the datagram"]
-    pub fn add_udp_client(&self, addr: SocketAddr, socket: Arc<UdpSocket>, socks5: bool) -> u32 {
-        // `expect`: at this point `socket` should be bound. Otherwise, it's a bug.
-        let our_addr = socket
-            .local_addr()
-            .expect("Failed to get local address of UDP socket (this is a bug)");
+    pub fn add_udp_client(&self, addr: SocketAddr, socket: Arc<UdpSocket>) -> u32 {
+        let our_addr = socket_addr(&socket);
+        let reply = UdpReply::Socket {
+            socket,
+            socks5: false,
+        };
+        self.add_udp_client_with_reply(addr, our_addr, None, reply)
+    }
+
+    /// Add a new UDP client of a SOCKS UDP association sending to
+    /// `destination` to the maps, returns the new client ID.
+    /// Each destination of the client gets its own client ID.
+    #[must_use = "This function returns the new client ID, which should be used to mark the datagram"]
+    pub fn add_socks_udp_client(
+        &self,
+        addr: SocketAddr,
+        socket: Arc<UdpSocket>,
+        destination: UdpDestination,
+    ) -> u32 {
+        let our_addr = socket_addr(&socket);
+        let reply = UdpReply::Socket {
+            socket,
+            socks5: true,
+        };
+        self.add_udp_client_with_reply(addr, our_addr, Some(destination), reply)
+    }
+
+    /// Add a new UDP client of a TUN device to the maps, returns the new client ID
+    #[cfg(all(feature = "tun", target_os = "linux"))]
+    #[must_use = "This function returns the new client ID, which should be used to mark the datagram"]
+    pub fn add_tun_udp_client(
+        &self,
+        addr: SocketAddr,
+        our_addr: SocketAddr,
+        tun_tx: mpsc::Sender<handle_remote::tun::TunDatagram>,
+    ) -> u32 {
+        self.add_udp_client_with_reply(addr, our_addr, None, UdpReply::Tun(tun_tx))
+    }
+
+    fn add_udp_client_with_reply(
+        &self,
+        addr: SocketAddr,
+        our_addr: SocketAddr,
+        destination: Option<UdpDestination>,
+        reply: UdpReply,
+    ) -> u32 {
         let ClientIdMaps {
             client_id_map,
             client_addr_map,
         } = &mut *self.udp_client_map.write();
-        if let Some(client_id) = client_addr_map.get(&(addr, our_addr)) {
+        let key = (addr, our_addr, destination);
+        if let Some(client_id) = client_addr_map.get(&key) {
             // The client already exists, just refresh the entry
             client_id_map
                 .get_mut(client_id)
                 .expect("`client_id_map` and `client_addr_map` are inconsistent (this is a bug)")
-                .refresh();
+                .refresh(self.udp_timeout);
             *client_id
         } else {
             // The client doesn't exist, add it to the maps
             let client_id = u32::next_available_key(client_id_map);
-            client_id_map.insert(
-                client_id,
-                ClientIdMapEntry::new(addr, our_addr, socket, socks5),
+            self.traffic.add_connection();
+            let (addr, our_addr, destination) = key;
+            // Not to keep the whole datagram it came from
+            let destination = destination.map(|(host, port)| (Bytes::copy_from_slice(&host), port));
+            let entry = ClientIdMapEntry::new(
+                addr,
+                our_addr,
+                destination,
+                reply,
+                self.traffic.dupe(),
+                self.udp_timeout,
             );
-            client_addr_map.insert((addr, our_addr), client_id);
+            client_addr_map.insert(entry.key(), client_id);
+            client_id_map.insert(client_id, entry);
             client_id
         }
     }
@@ -147,15 +265,33 @@ impl HandlerResources {
             if entry.expires > now {
                 true
             } else {
-                client_addr_map
-                    .remove(&(entry.peer_addr, entry.our_addr))
-                    .expect(
-                        "`client_id_map` and `client_addr_map` are inconsistent (this is a bug)",
-                    );
+                client_addr_map.remove(&entry.key()).expect(
+                    "`client_id_map` and `client_addr_map` are inconsistent (this is a bug)",
+                );
                 false
             }
         });
     }
+
+    /// Remove a UDP client whose flow has failed, so that its next datagram
+    /// starts a new one
+    fn remove_udp_client(&self, client_id: u32) {
+        let ClientIdMaps {
+            client_id_map,
+            client_addr_map,
+        } = &mut *self.udp_client_map.write();
+        if let Some(entry) = client_id_map.remove(&client_id) {
+            client_addr_map.remove(&entry.key());
+        }
+    }
+}
+
+/// Address of the socket of a UDP client
+fn socket_addr(socket: &UdpSocket) -> SocketAddr {
+    // `expect`: at this point `socket` should be bound. Otherwise, it's a bug.
+    socket
+        .local_addr()
+        .expect("Failed to get local address of UDP socket (this is a bug)")
 }
 
 /// Type of the two client ID maps
@@ -165,10 +301,10 @@ impl HandlerResources {
 pub struct ClientIdMaps {
     /// Client ID -> Client ID map entry
     client_id_map: IntMap<u32, ClientIdMapEntry>,
-    /// (client address, our address) -> client ID
+    /// (client address, our address, destination) -> client ID
     /// We need our address to make sure we send replies with the correct source address
     /// because different remotes and socks5 associations use different listeners
-    client_addr_map: HashMap<(SocketAddr, SocketAddr), u32>,
+    client_addr_map: HashMap<ClientKey, u32>,
 }
 
 impl ClientIdMaps {
@@ -180,28 +316,87 @@ impl ClientIdMaps {
         }
     }
 
-    /// Send a datagram to a client
+    /// Send a datagram from the server to its client, which stays in the maps
+    /// for another `timeout`
     async fn send_datagram_reply(
         lock_self: &RwLock<Self>,
-        client_id: u32,
-        data: &[u8],
+        frame: &Datagram,
+        timeout: Duration,
     ) -> Option<std::io::Result<()>> {
-        if client_id == 0 {
+        let data = frame.data.as_ref();
+        if frame.flow_id == 0 {
             // Used for stdio
             return Some(tokio::io::stdout().write_all(data).await);
         }
-        let info = lock_self.read().client_id_map.get(&client_id)?.dupe();
+        let info = {
+            let mut maps = lock_self.write();
+            let entry = maps.client_id_map.get_mut(&frame.flow_id)?;
+            entry.refresh(timeout);
+            entry.dupe()
+        };
 
-        let send_result = if info.socks5 {
-            handle_remote::socks::send_udp_relay_response(&info.socket, info.peer_addr, data).await
-        } else {
-            info.socket.send_to(data, info.peer_addr).await
+        let send_result = match &info.reply {
+            UdpReply::Socket {
+                socket,
+                socks5: true,
+            } => handle_remote::socks::send_udp_relay_response(socket, info.peer_addr, data)
+                .await
+                .map(|_| ()),
+            UdpReply::Socket {
+                socket,
+                socks5: false,
+            } => socket.send_to(data, info.peer_addr).await.map(|_| ()),
+            #[cfg(all(feature = "tun", target_os = "linux"))]
+            UdpReply::Tun(tun_tx) => tun_tx
+                .try_send(handle_remote::tun::TunDatagram {
+                    peer_addr: info.peer_addr,
+                    our_addr: info.our_addr,
+                    data: Bytes::copy_from_slice(data),
+                })
+                .or(Err(std::io::Error::other(
+                    "TUN device is not keeping up or has stopped",
+                ))),
+        };
+        if send_result.is_ok() {
+            info.traffic.add_received(data.len());
         }
-        .map(|_| ());
         Some(send_result)
     }
 }
 
+/// How replies are sent to a UDP client
+#[derive(Clone, Debug)]
+pub enum UdpReply {
+    /// From a UDP socket, with a SOCKS5 header if `socks5` is set
+    Socket {
+        socket: Arc<UdpSocket>,
+        socks5: bool,
+    },
+    /// Into a TUN device as IP packets
+    #[cfg(all(feature = "tun", target_os = "linux"))]
+    Tun(mpsc::Sender<handle_remote::tun::TunDatagram>),
+}
+
+impl Dupe for UdpReply {
+    fn dupe(&self) -> Self {
+        match self {
+            Self::Socket { socket, socks5 } => Self::Socket {
+                socket: socket.dupe(),
+                socks5: *socks5,
+            },
+            #[cfg(all(feature = "tun", target_os = "linux"))]
+            Self::Tun(tun_tx) => Self::Tun(tun_tx.clone()),
+        }
+    }
+}
+
+/// Destination host and port of the datagrams of a SOCKS UDP client
+pub type UdpDestination = (Bytes, u16);
+
+/// Key of the second client ID map: (client address, our address,
+/// destination if the client names one in each datagram)
+type ClientKey = (SocketAddr, SocketAddr, Option<UdpDestination>);
+
 /// Type stored in the first client ID map
 #[derive(Clone, Debug)]
 #[allow(clippy::module_name_repetitions)]
@@ -210,12 +405,14 @@ pub struct ClientIdMapEntry {
     pub peer_addr: SocketAddr,
     /// The address of our socket (redundant information, but makes it easier to remove entries)
     pub our_addr: SocketAddr,
-    /// The UDP socket used to communicate with the client
-    pub socket: Arc<UdpSocket>,
-    /// Whether responses should include a SOCKS5 header
-    pub socks5: bool,
+    /// Where the client sends, if it names the destination of each datagram
+    pub destination: Option<UdpDestination>,
+    /// How to send replies to the client
+    pub reply: UdpReply,
     /// When this entry should be removed
     pub expires: time::Instant,
+    /// Traffic of the remote the client came from
+    traffic: Arc<RemoteTraffic>,
 }
 
 impl Dupe for ClientIdMapEntry {
@@ -223,107 +420,160 @@ impl Dupe for ClientIdMapEntry {
         Self {
             peer_addr: self.peer_addr,
             our_addr: self.our_addr,
-            socket: self.socket.dupe(),
-            socks5: self.socks5,
+            destination: self.destination.clone(),
+            reply: self.reply.dupe(),
             expires: self.expires,
+            traffic: self.traffic.dupe(),
         }
     }
 }
 
 impl ClientIdMapEntry {
     #[must_use]
-    pub fn new(
+    fn new(
         peer_addr: SocketAddr,
         our_addr: SocketAddr,
-        socket: Arc<UdpSocket>,
-        socks5: bool,
+        destination: Option<UdpDestination>,
+        reply: UdpReply,
+        traffic: Arc<RemoteTraffic>,
+        timeout: Duration,
     ) -> Self {
         Self {
             peer_addr,
             our_addr,
-            socket,
-            socks5,
-            expires: time::Instant::now() + config::UDP_PRUNE_TIMEOUT,
+            destination,
+            reply,
+            expires: time::Instant::now() + timeout,
+            traffic,
         }
     }
 
-    pub fn refresh(&mut self) {
-        self.expires = time::Instant::now() + config::UDP_PRUNE_TIMEOUT;
+    pub fn refresh(&mut self, timeout: Duration) {
+        self.expires = time::Instant::now() + timeout;
+    }
+
+    fn key(&self) -> ClientKey {
+        (self.peer_addr, self.our_addr, self.destination.clone())
     }
 }
 
+/// `WebSocket` or plain transport connection to the server
+type WsStream = Box<dyn penguin_mux::ws::WebSocket>;
+
 #[tracing::instrument(level = "trace")]
-pub async fn client_main(args: &'static ClientArgs) -> Result<(), Error> {
-    static HANDLER_RESOURCES: OnceLock<HandlerResources> = OnceLock::new();
-    let (handler_resources, stream_command_rx, datagram_rx) = HandlerResources::create();
-    HANDLER_RESOURCES
-        .set(handler_resources)
-        .expect("HandlerResources should only be set once (this is a bug)");
-    client_main_inner(
-        args,
-        HANDLER_RESOURCES
-            .get()
-            .expect("HandlerResources should be set (this is a bug)"),
-        stream_command_rx,
-        datagram_rx,
+pub async fn client_main(args: &ClientArgs) -> Result<(), Error> {
+    #[cfg(feature = "chisel")]
+    if args.chisel {
+        return chisel::chisel_main(args).await;
+    }
+    let (mut handler_resources, stream_command_rx, datagram_rx) = HandlerResources::create();
+    handler_resources.socks_policy = SocksPolicy {
+        auth: args.socks_auth.clone(),
+        acl: Acl::new(args.socks_allow.clone(), args.socks_deny.clone()),
+        local_dns: args.socks_local_dns,
+    };
+    handler_resources.udp_timeout = args.udp_timeout.0;
+    // Not a static so that a program embedding us can run more than one client
+    let handler_resources = Arc::new(handler_resources);
+    client_main_inner(args, handler_resources, stream_command_rx, datagram_rx).await
+}
+
+/// How long to wait between attempts to connect to the server
+fn reconnect_backoff(args: &ClientArgs) -> Backoff {
+    // Initial retry interval is 200ms
+    Backoff::new(
+        Duration::from_millis(200),
+        Duration::from_millis(args.max_retry_interval),
+        2,
+        args.max_retry_count,
     )
-    .await
+    .with_jitter(args.retry_jitter)
 }
 
 pub async fn client_main_inner(
-    args: &'static ClientArgs,
-    handler_resources: &'static HandlerResources,
+    args: &ClientArgs,
+    handler_resources: Arc<HandlerResources>,
     mut stream_command_rx: mpsc::Receiver<StreamCommand>,
     mut datagram_rx: mpsc::Receiver<Datagram>,
 ) -> Result<(), Error> {
-    // TODO: Temporary, remove when implemented
-    // Blocked on `snapview/tungstenite-rs#177`
-    if args.proxy.is_some() {
-        warn!("Proxy not implemented yet");
-    }
-    let mut jobs = JoinSet::new();
+    let mut remote_tasks = RemoteTasks::new(handler_resources.dupe());
     // Spawn listeners. See `handle_remote.rs` for the implementation considerations.
-    for remote in &args.remote {
-        jobs.spawn(handle_remote(remote, handler_resources));
-    }
-    // Check if any listener has failed. If so, quit immediately.
-    let check_listeners_future = async move {
-        while let Some(result) = jobs.join_next().await {
-            // Quit immediately if any handler fails
-            // so maybe `systemd` can restart it
-            result.expect("JoinSet panicked (this is a bug)")?;
+    // If any of them fails, quit immediately so maybe `systemd` can restart it.
+    remote_tasks.spawn_startup(args.remotes().cloned());
+    // Kept until we exit, when it puts back the system's resolv.conf
+    let _resolv_conf = args
+        .proxy_dns
+        .and_then(|addr| proxy_dns::start(&mut remote_tasks, addr));
+    // Reverse remotes and the control socket keep us alive even without local listeners
+    let keep_alive = args.control.is_some() || args.remotes().any(|remote| remote.reverse);
+    let (control_tx, control_rx) = mpsc::channel(config::CONTROL_COMMAND_SIZE);
+    let control_listener = match &args.control {
+        Some(addr) => Some(ControlListener::bind(addr).await.map_err(Error::Control)?),
+        None => None,
+    };
+    let status = Status::default();
+    let status_listener = match args.status_listen {
+        Some(addr) => Some(TcpListener::bind(addr).await.map_err(Error::Status)?),
+        None => None,
+    };
+    let status_future = status::run(
+        status_listener,
+        args.status_file.as_deref(),
+        status.dupe(),
+        control_tx.clone(),
+    );
+    let control_future = async move {
+        if let Some(control_listener) = control_listener {
+            control_listener
+                .serve(control_tx)
+                .await
+                .map_err(Error::Control)
+        } else {
+            // Let `RemoteTasks::run` know that there will be no commands
+            drop(control_tx);
+            std::future::pending().await
         }
-        // Quit if there is no more listeners, which means we don't need
-        // to exist anymore
-        Ok::<(), Error>(())
     };
+    let handler_resources = &handler_resources;
+    // Check if any listener has failed. If so, quit immediately.
+    let check_listeners_future = remote_tasks.run(control_rx, keep_alive).err_into::<Error>();
     let main_future = async move {
-        // Initial retry interval is 200ms
-        let mut backoff = Backoff::new(
-            Duration::from_millis(200),
-            Duration::from_millis(args.max_retry_interval),
-            2,
-            args.max_retry_count,
-        );
+        let mut backoff = reconnect_backoff(args);
         // Place to park one failed stream request so that it can be retried
         let mut failed_stream_request: Option<StreamCommand> = None;
+        // Flows of reverse UDP remotes, kept if the session is resumed
+        let mut reverse_udp_flows = reverse::ReverseUdpFlows::default();
+        // Connections to the server, kept across reconnections while they are up
+        // or resumable
+        let mut bond = Bond::new(usize::from(args.connections));
         // Retry loop
         loop {
-            let r = ws_connect::handshake(args)
-                .and_then(|ws_stream| {
+            let r = match bond.connect(args).await {
+                Ok(resumed) => {
+                    status.connected(bond.muxes());
                     on_connected(
-                        ws_stream,
+                        &bond,
+                        resumed,
                         &mut stream_command_rx,
                         &mut failed_stream_request,
                         &mut datagram_rx,
-                        &handler_resources.udp_client_map,
-                        args.keepalive,
-                        args.channel_timeout,
+                        &mut reverse_udp_flows,
+                        handler_resources,
+                        args,
                     )
                     // Since we once connected, reset the retry count
                     .inspect_err(|_| backoff.reset())
-                })
-                .await;
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if r.is_ok() {
+                bond.close().await?;
+            } else {
+                // Keep the streams of the connections that are still up or
+                // can be continued on a new connection
+                bond.retain_usable();
+            }
             match r {
                 // Will get `Ok` only if the user wants to quit
                 Ok(()) => return Ok(()),
@@ -331,6 +581,7 @@ pub async fn client_main_inner(
                 // else, retry
                 Err(e) => {
                     warn!("Connection failed: {e}");
+                    status.reconnecting(&e);
                     let Some(current_retry_interval) = backoff.advance() else {
                         warn!("Max retry count reached, giving up");
                         return Err(Error::MaxRetryCountReached(Box::new(e)));
@@ -350,8 +601,10 @@ pub async fn client_main_inner(
     tokio::select! {
         biased;
         result = check_listeners_future => result,
+        result = control_future => result,
         // This future never resolves
         () = prune_client_id_map_task(handler_resources) => unreachable!("prune_client_id_map_task should never return"),
+        () = status_future => unreachable!("status::run should never return"),
         result = main_future => result,
     }
 }
@@ -360,45 +613,85 @@ pub async fn client_main_inner(
 /// local listeners, establishes them, and sends them back to the listeners.
 /// Datagrams are simply dropped if we fail to send them.
 ///
+/// Returns `Ok(())` if the user wants to quit. The caller should then drop
+/// the multiplexor and wait for its task to finish.
+///
 /// # Errors
 /// This function returns when the connection is lost, and the caller should
 /// retry based on the error.
 #[tracing::instrument(skip_all, level = "debug")]
+#[allow(clippy::too_many_arguments)]
 async fn on_connected(
-    ws_stream: tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>,
+    bond: &Bond,
+    resumed: bool,
     stream_command_rx: &mut mpsc::Receiver<StreamCommand>,
     failed_stream_request: &mut Option<StreamCommand>,
     datagram_rx: &mut mpsc::Receiver<Datagram>,
-    udp_client_map: &RwLock<ClientIdMaps>,
-    keepalive: OptionalDuration,
-    channel_timeout: OptionalDuration,
+    reverse_udp_flows: &mut reverse::ReverseUdpFlows,
+    handler_resources: &Arc<HandlerResources>,
+    args: &ClientArgs,
 ) -> Result<(), Error> {
-    let mut mux_task_joinset = JoinSet::new();
-    let options = penguin_mux::config::Options::new().keepalive_interval(keepalive);
-    let mux = Multiplexor::new(ws_stream, Some(options), Some(&mut mux_task_joinset));
-    info!("Connected to server");
+    let channel_timeout = args.channel_timeout;
+    if resumed {
+        // The server still has our reverse remotes
+        info!("Resumed session with server");
+    } else {
+        info!("Connected to server");
+        // Ask the server to listen for our reverse remotes on each new connection
+        reverse::request_reverse_binds(bond.primary(), args.remotes()).await?;
+    }
     // If we have a failed stream request, try it first
     if let Some(sender) = failed_stream_request.take() {
-        get_send_stream_chan(&mux, sender, failed_stream_request, channel_timeout).await?;
+        get_send_stream_chan(
+            bond.next_stream_mux(),
+            sender,
+            failed_stream_request,
+            channel_timeout,
+        )
+        .await?;
     }
     // Main loop
     loop {
         tokio::select! {
-            Some(mux_task_joinset_result) = mux_task_joinset.join_next() => {
+            Some(mux_task_joinset_result) = bond.join() => {
                 mux_task_joinset_result.expect("Task panicked (this is a bug)")?;
+                // Only the lost connection is reconnected; the others keep their streams
+                return Err(Error::RemoteDisconnected);
+            }
+            () = bond.detached() => {
+                warn!("Lost connection to the server, streams are kept open while reconnecting");
+                return Err(Error::Detached);
             }
             Some(sender) = stream_command_rx.recv() => {
-                get_send_stream_chan(&mux, sender, failed_stream_request, channel_timeout).await?;
+                get_send_stream_chan(bond.next_stream_mux(), sender, failed_stream_request, channel_timeout).await?;
+            }
+            Ok(stream) = bond.accept_stream_channel() => {
+                if let Some(remote) = reverse::find_reverse_remote(args.remotes(), &stream) {
+                    tokio::spawn(reverse::handle_reverse_stream(stream, remote.clone(), handler_resources.dupe()));
+                } else {
+                    warn!("Server opened a stream that matches no reverse remote");
+                }
             }
             Some(datagram) = datagram_rx.recv() => {
+                // Reverse remotes are bound on the first connection
+                let mux = if datagram.target_host == REVERSE_UDP_HOST {
+                    bond.primary()
+                } else {
+                    bond.datagram_mux(datagram.flow_id)
+                };
                 if let Err(e) = mux.send_datagram(datagram).await {
                     error!("{e}");
                 }
             }
-            Ok(dgram_frame) = mux.get_datagram() => {
+            Ok(dgram_frame) = bond.get_datagram() => {
                 let client_id = dgram_frame.flow_id;
-                let data = dgram_frame.data;
-                match ClientIdMaps::send_datagram_reply(udp_client_map, client_id, data.as_ref()).await {
+                if dgram_frame.target_host == UDP_ERROR_HOST {
+                    let reason = String::from_utf8_lossy(&dgram_frame.data);
+                    debug!("UDP flow of client {client_id:08x} failed: {reason}");
+                    handler_resources.remove_udp_client(client_id);
+                    continue;
+                }
+                match ClientIdMaps::send_datagram_reply(&handler_resources.udp_client_map, &dgram_frame, handler_resources.udp_timeout).await {
                     Some(Ok(())) => {
                         trace!("sent datagram to client {client_id:08x}");
                     }
@@ -406,18 +699,18 @@ async fn on_connected(
                         warn!("Failed to send datagram to client {client_id:08x}: {e}");
                     }
                     None => {
-                        // Just drop the datagram
-                        info!("Received datagram for unknown client ID: {client_id:08x}");
+                        if let Some(remote) = reverse::find_reverse_udp_remote(args.remotes(), &dgram_frame) {
+                            reverse_udp_flows.forward(dgram_frame, remote, handler_resources);
+                        } else {
+                            // Just drop the datagram
+                            info!("Received datagram for unknown client ID: {client_id:08x}");
+                        }
                     }
                 }
             }
             Ok(()) = tokio::signal::ctrl_c() => {
                 // `Err` means unable to listen for Ctrl-C, which we will ignore
                 info!("Received Ctrl-C, exiting once all streams are closed");
-                drop(mux);
-                while let Some(result) = mux_task_joinset.join_next().await {
-                    result.expect("Task panicked (this is a bug)")?;
-                }
                 return Ok(());
             }
             else => {
@@ -429,7 +722,7 @@ async fn on_connected(
 }
 
 /// Get a new channel from the multiplexor and send it to the handler.
-/// If we fail, put the request back in the failed_stream_request slot.
+/// If we fail, put the request back in the `failed_stream_request` slot.
 #[tracing::instrument(skip_all, level = "trace")]
 async fn get_send_stream_chan(
     mux: &Multiplexor,
@@ -464,7 +757,7 @@ async fn get_send_stream_chan(
 /// Prune the client ID map of entries that have not been used for a while.
 #[tracing::instrument(skip_all, level = "trace")]
 async fn prune_client_id_map_task(handler_resources: &HandlerResources) {
-    let mut interval = time::interval(config::UDP_PRUNE_TIMEOUT);
+    let mut interval = time::interval(handler_resources.udp_timeout);
     interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
     loop {
         interval.tick().await;
@@ -485,32 +778,30 @@ mod tests {
             stream_command_tx: stub_stream_tx,
             datagram_tx: stub_datagram_tx,
             udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
+            socks_policy: SocksPolicy::default(),
+            buffer_pool: BufferPool::new(),
+            traffic: Arc::default(),
+            udp_timeout: config::UDP_PRUNE_TIMEOUT,
         };
         let stub_socket = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
         let client_id = handler_resources.add_udp_client(
             (IpAddr::from([127, 0, 0, 1]), 1234).into(),
             stub_socket.dupe(),
-            false,
         );
         let client_id2 = handler_resources.add_udp_client(
             (IpAddr::from([127, 0, 0, 1]), 1234).into(),
             stub_socket.dupe(),
-            false,
         );
         // We should get the same client ID for the same client address and socket
         assert_eq!(client_id, client_id2);
         let stub_socket_2 = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
-        let client_id2 = handler_resources.add_udp_client(
-            (IpAddr::from([127, 0, 0, 1]), 1234).into(),
-            stub_socket_2,
-            false,
-        );
+        let client_id2 = handler_resources
+            .add_udp_client((IpAddr::from([127, 0, 0, 1]), 1234).into(), stub_socket_2);
         // We should get a different client ID for a different socket
         assert_ne!(client_id, client_id2);
         let client_id2 = handler_resources.add_udp_client(
             (IpAddr::from([127, 0, 0, 1]), 1235).into(),
             stub_socket.dupe(),
-            false,
         );
         // We should get a different client ID for a different client address
         assert_ne!(client_id, client_id2);
@@ -525,14 +816,17 @@ mod tests {
             stream_command_tx: stub_stream_tx,
             datagram_tx: stub_datagram_tx,
             udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
+            socks_policy: SocksPolicy::default(),
+            buffer_pool: BufferPool::new(),
+            traffic: Arc::default(),
+            udp_timeout: Duration::from_millis(100),
         };
         let stub_socket = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
         let _ = handler_resources.add_udp_client(
             (IpAddr::from([127, 0, 0, 1]), 1234).into(),
             stub_socket.dupe(),
-            false,
         );
-        tokio::time::sleep(config::UDP_PRUNE_TIMEOUT).await;
+        tokio::time::sleep(handler_resources.udp_timeout).await;
         handler_resources.prune_udp_clients();
         assert!(
             handler_resources
@@ -542,4 +836,77 @@ mod tests {
                 .is_empty()
         );
     }
+    #[tokio::test]
+    async fn test_client_map_remove_failed_client() {
+        crate::tests::setup_logging();
+        let (stub_stream_tx, _stub_stream_rx) = mpsc::channel(1);
+        let (stub_datagram_tx, _stub_datagram_rx) = mpsc::channel(1);
+        let handler_resources = HandlerResources {
+            stream_command_tx: stub_stream_tx,
+            datagram_tx: stub_datagram_tx,
+            udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
+            socks_policy: SocksPolicy::default(),
+            buffer_pool: BufferPool::new(),
+            traffic: Arc::default(),
+            udp_timeout: config::UDP_PRUNE_TIMEOUT,
+        };
+        let stub_socket = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
+        let peer = (IpAddr::from([127, 0, 0, 1]), 1234).into();
+        let client_id = handler_resources.add_udp_client(peer, stub_socket.dupe());
+        handler_resources.remove_udp_client(client_id);
+        let maps = handler_resources.udp_client_map.read();
+        assert!(maps.client_id_map.is_empty());
+        assert!(maps.client_addr_map.is_empty());
+    }
+    #[tokio::test]
+    async fn test_client_map_socks_destinations() {
+        crate::tests::setup_logging();
+        let (stub_stream_tx, _stub_stream_rx) = mpsc::channel(1);
+        let (stub_datagram_tx, _stub_datagram_rx) = mpsc::channel(1);
+        let handler_resources = HandlerResources {
+            stream_command_tx: stub_stream_tx,
+            datagram_tx: stub_datagram_tx,
+            udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
+            socks_policy: SocksPolicy::default(),
+            buffer_pool: BufferPool::new(),
+            traffic: Arc::default(),
+            udp_timeout: Duration::from_millis(500),
+        };
+        let stub_socket = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
+        let peer = (IpAddr::from([127, 0, 0, 1]), 1234).into();
+        let dns = (Bytes::from_static(b"192.0.2.53"), 53);
+        let client_id =
+            handler_resources.add_socks_udp_client(peer, stub_socket.dupe(), dns.clone());
+        let client_id2 = handler_resources.add_socks_udp_client(peer, stub_socket.dupe(), dns);
+        // The same destination keeps its client ID
+        assert_eq!(client_id, client_id2);
+        let client_id2 = handler_resources.add_socks_udp_client(
+            peer,
+            stub_socket.dupe(),
+            (Bytes::from_static(b"example.com"), 443),
+        );
+        // Another destination gets its own
+        assert_ne!(client_id, client_id2);
+        // Replies keep the client alive
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let reply = Datagram {
+            target_host: Bytes::from_static(b"192.0.2.53"),
+            target_port: 53,
+            flow_id: client_id,
+            data: Bytes::from_static(b"answer"),
+        };
+        let result = ClientIdMaps::send_datagram_reply(
+            &handler_resources.udp_client_map,
+            &reply,
+            handler_resources.udp_timeout,
+        )
+        .await;
+        assert!(matches!(result, Some(Ok(()))));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        handler_resources.prune_udp_clients();
+        let maps = handler_resources.udp_client_map.read();
+        assert!(maps.client_id_map.contains_key(&client_id));
+        assert!(!maps.client_id_map.contains_key(&client_id2));
+        assert_eq!(maps.client_addr_map.len(), 1);
+    }
 }
@@ -0,0 +1,125 @@
+//! Measuring the round-trip time of the tunnel with `penguin ping`.
+//!
+//! The probes are `Ping`s of the multiplexor, so any server answers them.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use super::bond::mux_options;
+use super::ws_connect::handshake;
+use crate::arg::PingArgs;
+use penguin_mux::Multiplexor;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+
+/// Results of pinging a server
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// Number of probes sent
+    pub sent: u32,
+    /// Round-trip times of the probes answered in time, in order
+    pub rtts: Vec<Duration>,
+}
+
+impl Report {
+    /// Smallest round-trip time
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    /// Largest round-trip time
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    /// Mean round-trip time
+    pub fn avg(&self) -> Option<Duration> {
+        let count = u32::try_from(self.rtts.len()).ok().filter(|n| *n > 0)?;
+        Some(self.rtts.iter().sum::<Duration>() / count)
+    }
+
+    /// Mean difference between consecutive round-trip times
+    pub fn jitter(&self) -> Option<Duration> {
+        let count = u32::try_from(self.rtts.len().checked_sub(1)?)
+            .ok()
+            .filter(|n| *n > 0)?;
+        let total = self
+            .rtts
+            .windows(2)
+            .map(|pair| pair[0].abs_diff(pair[1]))
+            .sum::<Duration>();
+        Some(total / count)
+    }
+}
+
+/// Format a duration in milliseconds
+fn ms(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let received = u32::try_from(self.rtts.len()).unwrap_or(u32::MAX);
+        let lost = self.sent.saturating_sub(received);
+        write!(
+            f,
+            "{} probes sent, {received} answered, {:.1}% loss",
+            self.sent,
+            f64::from(lost) * 100.0 / f64::from(self.sent.max(1))
+        )?;
+        if let (Some(min), Some(avg), Some(max)) = (self.min(), self.avg(), self.max()) {
+            write!(
+                f,
+                "\nrtt min/avg/max/jitter = {}/{}/{}/{} ms",
+                ms(min),
+                ms(avg),
+                ms(max),
+                ms(self.jitter().unwrap_or_default())
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Connect to the server and send the probes, calling `on_probe` with the
+/// sequence number and round-trip time of each probe, or `None` if it was
+/// not answered in time
+pub async fn ping<F>(args: &PingArgs, mut on_probe: F) -> Result<Report, Error>
+where
+    F: FnMut(u32, Option<Duration>),
+{
+    let (ws_stream, _, agreed) = handshake(&args.client, None).await?;
+    debug!("Connected to {}", args.client.server.0);
+    let mux = Multiplexor::new(ws_stream, Some(mux_options(&args.client, agreed)), None);
+    let timeout = Duration::from_millis(args.timeout);
+    let mut interval = tokio::time::interval(Duration::from_millis(args.interval));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut rtts = Vec::new();
+    for seq in 1..=args.count {
+        interval.tick().await;
+        let rtt = match tokio::time::timeout(timeout, mux.ping()).await {
+            Ok(rtt) => Some(rtt?),
+            Err(_) => None,
+        };
+        on_probe(seq, rtt);
+        rtts.extend(rtt);
+    }
+    Ok(Report {
+        sent: args.count,
+        rtts,
+    })
+}
+
+/// Ping the server and print the results
+pub async fn ping_main(args: &PingArgs) -> Result<(), Error> {
+    println!("PING {}", args.client.server.0);
+    let report = ping(args, |seq, rtt| match rtt {
+        Some(rtt) => println!("seq={seq} time={} ms", ms(rtt)),
+        None => println!("seq={seq} timed out"),
+    })
+    .await?;
+    println!("--- {} ping statistics ---", args.client.server.0);
+    println!("{report}");
+    Ok(())
+}
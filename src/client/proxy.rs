@@ -0,0 +1,342 @@
+//! Upstream HTTP `CONNECT` (RFC 9110) and SOCKS5 (RFC 1928 and RFC 1929)
+//! proxy client.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::{ProxyProtocol, ProxyUrl, SocksAuth};
+use base64::Engine;
+use std::net::IpAddr;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot {0} proxy: {1}")]
+    Io(&'static str, std::io::Error),
+    #[error("cannot resolve server host {0}")]
+    Resolve(String),
+    #[error("SOCKS5 proxy replied with version {0}")]
+    Version(u8),
+    #[error("SOCKS5 proxy accepted none of our authentication methods")]
+    NoAcceptableMethod,
+    #[error("SOCKS5 proxy rejected our credentials")]
+    Auth,
+    #[error("SOCKS5 proxy refused to connect (reply code {0})")]
+    Refused(u8),
+    #[error("SOCKS5 proxy replied with address type {0}")]
+    AddressType(u8),
+    #[error("HTTP proxy refused to connect: {0}")]
+    HttpStatus(String),
+    #[error("HTTP proxy sent an invalid response")]
+    HttpResponse,
+}
+
+/// Longest response head that we accept from an HTTP proxy
+const MAX_HTTP_RESPONSE_HEAD: usize = 8192;
+
+/// Destination of a SOCKS5 `CONNECT` request
+#[derive(Debug, PartialEq, Eq)]
+enum Target<'a> {
+    Ip(IpAddr),
+    Domain(&'a str),
+}
+
+/// Connect to `host:port` through the proxy.
+///
+/// # Errors
+/// Failure to reach the proxy or a rejected request.
+pub async fn connect(proxy: &ProxyUrl, host: &str, port: u16) -> Result<TcpStream, Error> {
+    let target = match host.parse::<IpAddr>() {
+        Ok(ip) => Target::Ip(ip),
+        Err(_) if proxy.remote_dns => Target::Domain(host),
+        Err(_) => {
+            let addr = tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|e| Error::Io("resolve server host for", e))?
+                .next()
+                .ok_or_else(|| Error::Resolve(host.to_string()))?;
+            Target::Ip(addr.ip())
+        }
+    };
+    let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port))
+        .await
+        .map_err(|e| Error::Io("connect to", e))?;
+    match proxy.protocol {
+        ProxyProtocol::Http => {
+            http_connect(&mut stream, proxy.auth.as_ref(), &target, port).await?;
+        }
+        ProxyProtocol::Socks5 => handshake(&mut stream, proxy.auth.as_ref(), &target, port).await?,
+    }
+    debug!("connected to {host}:{port} through the proxy");
+    Ok(stream)
+}
+
+/// Perform the SOCKS5 handshake and `CONNECT` request on the given stream.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context, or a rejected request.
+async fn handshake<RW>(
+    stream: &mut RW,
+    auth: Option<&SocksAuth>,
+    target: &Target<'_>,
+    port: u16,
+) -> Result<(), Error>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    let greeting: &[u8] = if auth.is_some() {
+        &[0x05, 0x02, 0x00, 0x02]
+    } else {
+        &[0x05, 0x01, 0x00]
+    };
+    write_flush(stream, greeting).await?;
+    let mut reply = [0; 2];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(Error::Version(reply[0]));
+    }
+    match (reply[1], auth) {
+        (0x00, _) => {}
+        (0x02, Some(auth)) => password_auth(stream, auth).await?,
+        _ => return Err(Error::NoAcceptableMethod),
+    }
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target {
+        Target::Ip(IpAddr::V4(ip)) => {
+            request.push(0x01);
+            request.extend(ip.octets());
+        }
+        Target::Ip(IpAddr::V6(ip)) => {
+            request.push(0x04);
+            request.extend(ip.octets());
+        }
+        Target::Domain(domain) => {
+            // `Uri` does not accept hostnames this long anyways
+            let len = u8::try_from(domain.len()).map_err(|_| Error::Resolve(domain.to_string()))?;
+            request.push(0x03);
+            request.push(len);
+            request.extend(domain.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    write_flush(stream, &request).await?;
+    let mut reply = [0; 4];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != 0x05 {
+        return Err(Error::Version(reply[0]));
+    }
+    if reply[1] != 0x00 {
+        return Err(Error::Refused(reply[1]));
+    }
+    // Skip the bound address and port
+    let addr_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0; 1];
+            read_exact(stream, &mut len).await?;
+            usize::from(len[0])
+        }
+        atyp => return Err(Error::AddressType(atyp)),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    read_exact(stream, &mut bound).await?;
+    Ok(())
+}
+
+/// Send an HTTP `CONNECT` request and read the response head.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context, or a rejected request.
+async fn http_connect<RW>(
+    stream: &mut RW,
+    auth: Option<&SocksAuth>,
+    target: &Target<'_>,
+    port: u16,
+) -> Result<(), Error>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = match target {
+        Target::Ip(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+        Target::Ip(ip) => format!("{ip}:{port}"),
+        Target::Domain(domain) => format!("{domain}:{port}"),
+    };
+    let proxy_authorization = auth
+        .map(|auth| {
+            let credentials = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", auth.username, auth.password));
+            format!("Proxy-Authorization: Basic {credentials}\r\n")
+        })
+        .unwrap_or_default();
+    let request =
+        format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n{proxy_authorization}\r\n");
+    write_flush(stream, request.as_bytes()).await?;
+    // Read byte by byte so that nothing after the head is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HTTP_RESPONSE_HEAD {
+            return Err(Error::HttpResponse);
+        }
+        let mut byte = [0; 1];
+        read_exact(stream, &mut byte).await?;
+        head.push(byte[0]);
+    }
+    let status_line = head
+        .split(|&byte| byte == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or(Error::HttpResponse)?;
+    let mut parts = status_line.split(' ');
+    if !parts
+        .next()
+        .is_some_and(|version| version.starts_with("HTTP/1."))
+    {
+        return Err(Error::HttpResponse);
+    }
+    match parts.next() {
+        Some(status) if status.starts_with('2') && status.len() == 3 => Ok(()),
+        Some(_) => Err(Error::HttpStatus(status_line.to_string())),
+        None => Err(Error::HttpResponse),
+    }
+}
+
+/// Perform username/password authentication (RFC 1929).
+async fn password_auth<RW>(stream: &mut RW, auth: &SocksAuth) -> Result<(), Error>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    // `SocksAuth` guarantees that both fit in a byte
+    let mut request = vec![0x01];
+    for field in [&auth.username, &auth.password] {
+        request.push(u8::try_from(field.len()).map_err(|_| Error::Auth)?);
+        request.extend(field.as_bytes());
+    }
+    write_flush(stream, &request).await?;
+    let mut reply = [0; 2];
+    read_exact(stream, &mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(Error::Auth);
+    }
+    Ok(())
+}
+
+async fn write_flush<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> Result<(), Error> {
+    writer
+        .write_all(data)
+        .await
+        .map_err(|e| Error::Io("write to", e))?;
+    writer.flush().await.map_err(|e| Error::Io("flush", e))
+}
+
+async fn read_exact<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    reader
+        .read_exact(buf)
+        .await
+        .map_err(|e| Error::Io("read from", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_handshake_with_auth() {
+        crate::tests::setup_logging();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let auth = SocksAuth::from_str("user:pass").unwrap();
+        let proxy = tokio::spawn(async move {
+            let mut buf = [0; 4];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [0x05, 0x02, 0x00, 0x02]);
+            server.write_all(&[0x05, 0x02]).await.unwrap();
+            let mut buf = [0; 11];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x01\x04user\x04pass");
+            server.write_all(&[0x01, 0x00]).await.unwrap();
+            let mut buf = [0; 18];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+            server
+                .write_all(&[0x05, 0x00, 0x00, 0x03, 0x01, b'a', 0x00, 0x00])
+                .await
+                .unwrap();
+            server.write_all(b"data").await.unwrap();
+        });
+        handshake(
+            &mut client,
+            Some(&auth),
+            &Target::Domain("example.com"),
+            443,
+        )
+        .await
+        .unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        crate::tests::setup_logging();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let auth = SocksAuth::from_str("us@er:pass").unwrap();
+        let proxy = tokio::spawn(async move {
+            let expected = b"CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\
+                Proxy-Authorization: Basic dXNAZXI6cGFzcw==\r\n\r\n";
+            let mut buf = vec![0; expected.len()];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, expected);
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\ndata")
+                .await
+                .unwrap();
+        });
+        let target = Target::Ip("::1".parse().unwrap());
+        http_connect(&mut client, Some(&auth), &target, 443)
+            .await
+            .unwrap();
+        let mut buf = [0; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+        proxy.await.unwrap();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        server
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(matches!(
+            http_connect(&mut client, None, &Target::Domain("example.com"), 443).await,
+            Err(Error::HttpStatus(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_refused() {
+        crate::tests::setup_logging();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let target = Target::Ip("::1".parse().unwrap());
+        let proxy = tokio::spawn(async move {
+            let mut buf = [0; 3];
+            server.read_exact(&mut buf).await.unwrap();
+            server.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut buf = [0; 22];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[3], 0x04);
+            server
+                .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+        assert!(matches!(
+            handshake(&mut client, None, &target, 80).await,
+            Err(Error::Refused(0x05))
+        ));
+        proxy.await.unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! Sending the DNS queries of the whole system through the tunnel, enabled
+//! with `--proxy-dns`.
+//!
+//! The client runs a `dns` remote on the given address, so queries over UDP
+//! go to the server as datagrams and queries over TCP as streams, and points
+//! `/etc/resolv.conf` at it while running. Programs that use a "socks"
+//! remote or a TUN device then do not leak their lookups to the local
+//! network. The original file is moved next to it as `resolv.conf.penguin`
+//! and moved back when the client exits. If the file cannot be rewritten,
+//! such as when we are not root or the address is not on port 53, the
+//! client logs how to point the system at it by hand.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::control::RemoteTasks;
+use crate::dns::DNS_PORT;
+use crate::parse_remote::{LocalSpec, Protocol, Remote, RemoteSpec};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Where the system takes its nameservers from
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// The `dns` remote listening on `addr`
+pub(super) fn remote(addr: SocketAddr) -> Remote {
+    Remote {
+        local_addr: LocalSpec::Inet((addr.ip().to_string(), addr.port())),
+        remote_addr: RemoteSpec::Dns,
+        protocol: Protocol::Tcp,
+        reverse: false,
+        name: None,
+    }
+}
+
+/// Start the `dns` remote on `addr` and point the system at it, or tell the
+/// user how to. The returned guard restores the system configuration when
+/// dropped.
+pub(super) fn start(remote_tasks: &mut RemoteTasks, addr: SocketAddr) -> Option<ResolvConf> {
+    remote_tasks.spawn_startup([remote(addr)]);
+    if addr.port() != DNS_PORT {
+        // resolv.conf cannot name a port
+        info!("Set the DNS server of the system to {addr} to send all queries through the tunnel");
+        return None;
+    }
+    match ResolvConf::point_to(Path::new(RESOLV_CONF), addr) {
+        Ok(resolv_conf) => {
+            info!("Pointed {RESOLV_CONF} at {}", addr.ip());
+            Some(resolv_conf)
+        }
+        Err(err) => {
+            warn!(
+                "Cannot rewrite {RESOLV_CONF}: {err}; replace its nameservers with \
+                 \"nameserver {}\" to send all queries through the tunnel",
+                addr.ip()
+            );
+            None
+        }
+    }
+}
+
+/// A rewritten `resolv.conf`, put back when dropped
+#[derive(Debug)]
+pub(super) struct ResolvConf {
+    path: PathBuf,
+    backup: PathBuf,
+}
+
+impl ResolvConf {
+    /// Move the file at `path` aside and write one that only has `addr` as
+    /// nameserver
+    fn point_to(path: &Path, addr: SocketAddr) -> io::Result<Self> {
+        let mut backup = OsString::from(path);
+        backup.push(".penguin");
+        let backup = PathBuf::from(backup);
+        // A backup that is already there was left by a client that did not
+        // exit cleanly, and it is the original
+        if backup.exists() {
+            warn!(
+                "Found {}, which will be restored when we exit",
+                backup.display()
+            );
+        } else {
+            // Renaming rather than copying keeps a symlink to the file of
+            // `systemd-resolved` or the like as it was
+            fs::rename(path, &backup)?;
+        }
+        let contents = format!(
+            "# Written by penguin --proxy-dns, which restores {} on exit\nnameserver {}\n",
+            backup.display(),
+            addr.ip()
+        );
+        if let Err(err) = fs::write(path, contents) {
+            fs::rename(&backup, path).ok();
+            return Err(err);
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            backup,
+        })
+    }
+}
+
+impl Drop for ResolvConf {
+    fn drop(&mut self) {
+        match fs::rename(&self.backup, &self.path) {
+            Ok(()) => info!("Restored {}", self.path.display()),
+            Err(err) => warn!(
+                "Cannot restore {} from {}: {err}",
+                self.path.display(),
+                self.backup.display()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolv_conf() {
+        crate::tests::setup_logging();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("resolv.conf");
+        fs::write(&path, "nameserver 192.0.2.53\n").unwrap();
+        let resolv_conf = ResolvConf::point_to(&path, "127.0.0.1:53".parse().unwrap()).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.ends_with("\nnameserver 127.0.0.1\n"));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("resolv.conf.penguin")).unwrap(),
+            "nameserver 192.0.2.53\n"
+        );
+        drop(resolv_conf);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "nameserver 192.0.2.53\n"
+        );
+        assert!(!dir.path().join("resolv.conf.penguin").exists());
+    }
+}
@@ -0,0 +1,258 @@
+//! Reverse remotes.
+//!
+//! For each reverse remote, the client asks the server to listen on its
+//! local address with a `Bind` request. The server then opens a stream for each
+//! incoming connection, carrying the host and port of the `Bind` request, and
+//! the client connects the stream to the remote address. For `R:socks`
+//! remotes, the client runs the SOCKS server on the stream itself so that
+//! connections egress from the client's network.
+//!
+//! For `R:…/udp` remotes, the server binds a UDP socket instead and sends
+//! the datagrams of each of its peers with a flow ID of its own. The client
+//! forwards each flow to the remote address from its own socket and sends
+//! the replies back with the same flow ID.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::HandlerResources;
+use super::handle_remote::socks::handle_socks_reverse;
+use crate::config;
+use crate::parse_remote::{LocalSpec, Protocol, Remote, RemoteSpec};
+use crate::reverse_udp::REVERSE_UDP_HOST;
+use bytes::Bytes;
+use penguin_mux::{Datagram, Dupe, Multiplexor, MuxStream, frame::BindType};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{TcpStream, UdpSocket, lookup_host};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, trace, warn};
+
+#[cfg(feature = "nohash")]
+use nohash_hasher::IntMap;
+#[cfg(not(feature = "nohash"))]
+use std::collections::HashMap as IntMap;
+
+/// Request the server to listen for all reverse remotes.
+///
+/// # Errors
+/// Only returns an error if the mux is closed. Rejected requests are logged.
+#[tracing::instrument(skip_all, level = "debug")]
+pub(super) async fn request_reverse_binds(
+    mux: &Multiplexor,
+    remotes: impl IntoIterator<Item = &Remote>,
+) -> Result<(), penguin_mux::Error> {
+    for remote in remotes.into_iter().filter(|remote| remote.reverse) {
+        // The parser guarantees that reverse remotes have an Inet local address
+        let LocalSpec::Inet((lhost, lport)) = &remote.local_addr else {
+            continue;
+        };
+        let bind_type = match remote.protocol {
+            Protocol::Tcp => BindType::Stream,
+            Protocol::Udp => BindType::Datagram,
+        };
+        if mux
+            .request_bind(lhost.as_bytes(), *lport, bind_type)
+            .await?
+        {
+            info!("Server listening for reverse remote {remote}");
+        } else {
+            error!("Server rejected reverse remote {remote} (is `--reverse` enabled?)");
+        }
+    }
+    Ok(())
+}
+
+/// Find the reverse remote of `protocol` that was bound on `host` and `port`.
+fn find_bound_remote<'a>(
+    remotes: impl IntoIterator<Item = &'a Remote>,
+    protocol: Protocol,
+    host: &[u8],
+    port: u16,
+) -> Option<&'a Remote> {
+    remotes
+        .into_iter()
+        .filter(|remote| remote.reverse && remote.protocol == protocol)
+        .find(|remote| {
+            matches!(
+                &remote.local_addr,
+                LocalSpec::Inet((lhost, lport)) if lhost.as_bytes() == host && *lport == port
+            )
+        })
+}
+
+/// Find the reverse remote that a stream opened by the server belongs to.
+pub(super) fn find_reverse_remote<'a>(
+    remotes: impl IntoIterator<Item = &'a Remote>,
+    stream: &MuxStream,
+) -> Option<&'a Remote> {
+    find_bound_remote(remotes, Protocol::Tcp, &stream.dest_host, stream.dest_port)
+}
+
+/// Find the reverse UDP remote that a datagram from the server belongs to.
+pub(super) fn find_reverse_udp_remote<'a>(
+    remotes: impl IntoIterator<Item = &'a Remote>,
+    datagram: &Datagram,
+) -> Option<&'a Remote> {
+    find_bound_remote(
+        remotes,
+        Protocol::Udp,
+        &datagram.target_host,
+        datagram.target_port,
+    )
+}
+
+/// Handle a stream opened by the server for a reverse remote.
+/// `socks_policy` applies to the clients of `R:socks` remotes.
+#[tracing::instrument(skip(stream, handler_resources), fields(remote = %remote), level = "debug")]
+pub(super) async fn handle_reverse_stream(
+    stream: MuxStream,
+    remote: Remote,
+    handler_resources: Arc<HandlerResources>,
+) {
+    match &remote.remote_addr {
+        RemoteSpec::Inet((rhost, rport)) => handle_reverse_tcp(stream, rhost, *rport).await,
+        RemoteSpec::Socks => handle_socks_reverse(stream, &handler_resources.socks_policy).await,
+        RemoteSpec::Unix(_) | RemoteSpec::Transparent | RemoteSpec::Dns => {
+            unreachable!(
+                "The parser rejects reverse tproxy, dns and Unix socket remotes (this is a bug)"
+            )
+        }
+    }
+}
+
+/// Connect a stream opened by the server to the target of its reverse remote.
+async fn handle_reverse_tcp(stream: MuxStream, rhost: &str, rport: u16) {
+    trace!("attempting TCP connect to {rhost} port={rport}");
+    let mut tcp_stream = match TcpStream::connect((rhost, rport)).await {
+        Ok(tcp_stream) => tcp_stream,
+        Err(error) => {
+            warn!("Failed to connect to reverse remote target: {error}");
+            return;
+        }
+    };
+    debug!("reverse TCP forwarding to {rhost}:{rport}");
+    // Transient errors in the forwarder don't matter.
+    if let Err(error) = stream.into_copy_bidirectional(&mut tcp_stream).await {
+        warn!("Reverse TCP forwarder failed: {error}");
+    }
+}
+
+/// Flows of reverse UDP remotes by the flow IDs the server chose, each
+/// relayed by a task that owns a socket to the target of its remote
+#[derive(Debug, Default)]
+pub(super) struct ReverseUdpFlows(IntMap<u32, mpsc::Sender<Bytes>>);
+
+impl ReverseUdpFlows {
+    /// Forward a datagram from a peer of `remote` to its target, starting a
+    /// new flow if needed
+    pub fn forward(
+        &mut self,
+        datagram: Datagram,
+        remote: &Remote,
+        handler_resources: &Arc<HandlerResources>,
+    ) {
+        let flow_id = datagram.flow_id;
+        let data = match self.0.get(&flow_id) {
+            None => datagram.data,
+            Some(sender) => match sender.try_send(datagram.data) {
+                Ok(()) => return,
+                Err(TrySendError::Full(_)) => {
+                    trace!("reverse UDP flow {flow_id:08x} has a full channel");
+                    return;
+                }
+                // The flow has expired, so start a new one
+                Err(TrySendError::Closed(data)) => data,
+            },
+        };
+        let RemoteSpec::Inet((rhost, rport)) = &remote.remote_addr else {
+            unreachable!(
+                "The parser only allows host targets for reverse UDP remotes (this is a bug)"
+            );
+        };
+        // Forget the other flows that have expired too
+        self.0.retain(|_, sender| !sender.is_closed());
+        let (sender, receiver) = mpsc::channel(config::INCOMING_DATAGRAM_BUFFER_SIZE);
+        self.0.insert(flow_id, sender);
+        tokio::spawn(relay_reverse_udp(
+            flow_id,
+            data,
+            receiver,
+            (rhost.clone(), *rport),
+            handler_resources.dupe(),
+        ));
+    }
+}
+
+/// Send the datagrams of a reverse UDP flow to `target` and its replies to
+/// the server, until the flow sees no traffic for `--udp-timeout`.
+#[tracing::instrument(skip(first_data, receiver, handler_resources), fields(flow_id = %format_args!("{flow_id:08x}")), level = "debug")]
+async fn relay_reverse_udp(
+    flow_id: u32,
+    first_data: Bytes,
+    mut receiver: mpsc::Receiver<Bytes>,
+    target: (String, u16),
+    handler_resources: Arc<HandlerResources>,
+) {
+    let socket = match connect_udp((target.0.as_str(), target.1)).await {
+        Ok(socket) => socket,
+        Err(error) => {
+            warn!("Failed to reach reverse remote target: {error}");
+            return;
+        }
+    };
+    if let Err(error) = socket.send(&first_data).await {
+        debug!("cannot send to reverse remote target: {error}");
+    }
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    loop {
+        // Reset this timeout each time we see traffic
+        let this_round_timeout = tokio::time::sleep(handler_resources.udp_timeout);
+        tokio::select! {
+            Some(data) = receiver.recv() => {
+                if let Err(error) = socket.send(&data).await {
+                    debug!("cannot send to reverse remote target: {error}");
+                }
+            }
+            result = socket.recv(&mut buf) => {
+                let len = match result {
+                    Ok(len) => len,
+                    Err(error) => {
+                        // Such as an ICMP error from the target
+                        trace!("ignoring UDP receive error: {error}");
+                        continue;
+                    }
+                };
+                let datagram = Datagram {
+                    flow_id,
+                    target_host: Bytes::from_static(REVERSE_UDP_HOST),
+                    target_port: 0,
+                    data: handler_resources.buffer_pool.copy_from_slice(&buf[..len]),
+                };
+                if handler_resources.datagram_tx.send(datagram).await.is_err() {
+                    // The main loop has exited
+                    return;
+                }
+            }
+            () = this_round_timeout => {
+                trace!("reverse UDP flow expired");
+                return;
+            }
+        }
+    }
+}
+
+/// Bind a UDP socket and connect it to `target`.
+async fn connect_udp(target: (&str, u16)) -> std::io::Result<UdpSocket> {
+    let target = lookup_host(target)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other("target resolved to no addresses"))?;
+    let bind_addr: SocketAddr = if target.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
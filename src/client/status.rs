@@ -0,0 +1,469 @@
+//! Status of the client for dashboards and health checks.
+//!
+//! With `--status-listen`, the client answers every HTTP request on that
+//! address with the status as JSON: `200 OK` while connected to the server
+//! and `503 Service Unavailable` otherwise, so that a health check can look
+//! at the status code alone. With `--status-file`, the same JSON is written
+//! to a file every few seconds.
+//!
+//! The status has the connection state, the number of reconnections, and
+//! the running forward remotes with the streams and octets of their open
+//! connections. Streams of `socks` and transparent remotes go to varying
+//! destinations, so they only count in the totals. Each remote also has the
+//! `total` connections and octets since it started, which are summed up in
+//! the log every few minutes whether or not the status is enabled.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use super::control::{ControlCommand, ListedRemote};
+use super::traffic::Totals;
+use crate::config;
+use crate::dns::{DNS_HOST, DNS_PORT};
+use crate::parse_remote::{Remote, RemoteSpec};
+use parking_lot::Mutex;
+use penguin_mux::{Dupe, Multiplexor};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use tracing::{debug, error, info, warn};
+
+/// Longest HTTP request head we read before answering
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+/// State of the connection to the server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum State {
+    /// Connecting for the first time
+    #[default]
+    Connecting,
+    Connected,
+    /// Waiting to retry after the connection failed
+    Reconnecting,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    state: State,
+    connected_at: Option<SystemTime>,
+    /// Number of times the connection was established
+    connects: u64,
+    last_error: Option<String>,
+    /// Multiplexors of the current connections
+    muxes: Vec<Weak<Multiplexor>>,
+}
+
+/// Connection state shared by the main loop and the status reporters
+#[derive(Clone, Debug, Default)]
+pub(super) struct Status {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Dupe for Status {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl Status {
+    /// Record that the client is connected with `muxes`
+    pub fn connected(&self, muxes: Vec<Weak<Multiplexor>>) {
+        let mut inner = self.inner.lock();
+        inner.state = State::Connected;
+        inner.connected_at = Some(SystemTime::now());
+        inner.connects += 1;
+        inner.muxes = muxes;
+    }
+
+    /// Record that the connection failed with `error` and will be retried
+    pub fn reconnecting(&self, error: &Error) {
+        let mut inner = self.inner.lock();
+        inner.state = State::Reconnecting;
+        inner.connected_at = None;
+        inner.last_error = Some(error.to_string());
+        inner.muxes.clear();
+    }
+
+    /// Take a snapshot of the status with the running `remotes`
+    fn report(&self, remotes: &[ListedRemote]) -> Report {
+        let (state, connected_at, connects, last_error, muxes) = {
+            let inner = self.inner.lock();
+            (
+                inner.state,
+                inner.connected_at,
+                inner.connects,
+                inner.last_error.clone(),
+                inner.muxes.clone(),
+            )
+        };
+        let mux_stats = muxes
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|mux| mux.stats())
+            .collect::<Vec<_>>();
+        let mut report = Report {
+            state,
+            connected_at: connected_at.map(|connected_at| {
+                connected_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            reconnects: connects.saturating_sub(1),
+            last_error,
+            rtt_ms: mux_stats
+                .first()
+                .and_then(|stats| stats.rtt)
+                .map(|rtt| rtt.as_secs_f64() * 1000.0),
+            streams: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            remotes: remotes
+                .iter()
+                .map(|(id, remote, traffic, paused)| RemoteInfo {
+                    id: *id,
+                    remote: remote.to_string(),
+                    paused: *paused,
+                    streams: 0,
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                    total: traffic.totals(),
+                })
+                .collect(),
+        };
+        let destinations = remotes
+            .iter()
+            .map(|(_, remote, _, _)| destination(remote))
+            .collect::<Vec<_>>();
+        for flow in mux_stats.iter().flat_map(|stats| &stats.flows) {
+            report.streams += 1;
+            report.bytes_sent += flow.bytes_sent;
+            report.bytes_received += flow.bytes_received;
+            let dest = Some((flow.dest_host.as_ref(), flow.dest_port));
+            if let Some(idx) = destinations.iter().position(|d| *d == dest) {
+                let remote = &mut report.remotes[idx];
+                remote.streams += 1;
+                remote.bytes_sent += flow.bytes_sent;
+                remote.bytes_received += flow.bytes_received;
+            }
+        }
+        report
+    }
+}
+
+/// Where the streams of a remote go, if it is fixed
+fn destination(remote: &Remote) -> Option<(&[u8], u16)> {
+    match &remote.remote_addr {
+        RemoteSpec::Inet((host, port)) => Some((host.as_bytes(), *port)),
+        // Unix sockets on the server are sent with port 0
+        RemoteSpec::Unix(path) => Some((path.as_bytes(), 0)),
+        RemoteSpec::Dns => Some((DNS_HOST.as_bytes(), DNS_PORT)),
+        RemoteSpec::Socks | RemoteSpec::Transparent => None,
+    }
+}
+
+/// The status as JSON
+#[derive(Debug, Serialize)]
+struct Report {
+    state: State,
+    /// Seconds since the Unix epoch
+    connected_at: Option<u64>,
+    reconnects: u64,
+    /// Why the connection last failed
+    last_error: Option<String>,
+    rtt_ms: Option<f64>,
+    /// Open streams of all remotes
+    streams: usize,
+    bytes_sent: u64,
+    bytes_received: u64,
+    remotes: Vec<RemoteInfo>,
+}
+
+/// A running forward remote
+#[derive(Debug, Serialize)]
+struct RemoteInfo {
+    /// ID as in the control socket
+    id: u64,
+    remote: String,
+    /// Whether the remote is paused with the control socket
+    paused: bool,
+    /// Open streams to the destination of the remote
+    streams: usize,
+    /// Octets of the open streams
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Connections and octets since the remote started
+    total: Totals,
+}
+
+/// List the remotes that `RemoteTasks` runs
+async fn list_remotes(control_tx: &mpsc::Sender<ControlCommand>) -> Vec<ListedRemote> {
+    let (tx, rx) = oneshot::channel();
+    // If the remote supervisor is gone, the client is exiting anyway
+    match control_tx.send(ControlCommand::List(tx)).await {
+        Ok(()) => rx.await.unwrap_or_default(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Take a snapshot of the status with the remotes that `RemoteTasks` runs
+async fn report(status: &Status, control_tx: &mpsc::Sender<ControlCommand>) -> Report {
+    status.report(&list_remotes(control_tx).await)
+}
+
+/// Serve the status on `listener` and write it to `file`, whichever is given,
+/// and log the traffic of the remotes periodically.
+/// Errors are logged, so this never returns.
+pub(super) async fn run(
+    listener: Option<TcpListener>,
+    file: Option<&Path>,
+    status: Status,
+    control_tx: mpsc::Sender<ControlCommand>,
+) {
+    let serve = async {
+        match listener {
+            Some(listener) => serve(listener, &status, &control_tx).await,
+            None => std::future::pending().await,
+        }
+    };
+    let write = async {
+        match file {
+            Some(path) => write_file(path, &status, &control_tx).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::join!(serve, write, log_traffic(&control_tx));
+}
+
+/// Log the traffic of the remotes that had any since the last time, every
+/// `TRAFFIC_SUMMARY_INTERVAL`
+async fn log_traffic(control_tx: &mpsc::Sender<ControlCommand>) {
+    let mut logged = HashMap::new();
+    let mut interval = time::interval(config::TRAFFIC_SUMMARY_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    // The first tick completes immediately, when there is nothing to log
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        logged = log_traffic_since(&list_remotes(control_tx).await, &logged);
+    }
+}
+
+/// Log the traffic of `remotes` that changed since `logged`.
+/// Returns the totals to compare with next time.
+fn log_traffic_since(
+    remotes: &[ListedRemote],
+    logged: &HashMap<u64, Totals>,
+) -> HashMap<u64, Totals> {
+    remotes
+        .iter()
+        .map(|(id, remote, traffic, _)| {
+            let totals = traffic.totals();
+            if logged.get(id).copied().unwrap_or_default() != totals {
+                info!(
+                    connections = totals.connections,
+                    bytes_sent = totals.bytes_sent,
+                    bytes_received = totals.bytes_received,
+                    "Traffic of remote {id} `{remote}`"
+                );
+            }
+            (*id, totals)
+        })
+        .collect()
+}
+
+/// Accept status requests forever
+async fn serve(listener: TcpListener, status: &Status, control_tx: &mpsc::Sender<ControlCommand>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Status endpoint accept error: {err}");
+                continue;
+            }
+        };
+        debug!("status request from {peer}");
+        tokio::spawn(handle_status_connection(
+            stream,
+            status.dupe(),
+            control_tx.clone(),
+        ));
+    }
+}
+
+/// Answer one HTTP request with the status and close the connection
+async fn handle_status_connection<S>(
+    stream: S,
+    status: Status,
+    control_tx: mpsc::Sender<ControlCommand>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream.take(MAX_REQUEST_HEAD));
+    let mut line = String::new();
+    // The response does not depend on the request, but wait for all of it
+    // so that the client does not see a reset
+    loop {
+        line.clear();
+        match stream.read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(_) if line.trim_end().is_empty() => break,
+            Ok(_) => {}
+            Err(err) => {
+                warn!("Failed to read status request: {err}");
+                return;
+            }
+        }
+    }
+    let report = report(&status, &control_tx).await;
+    let code = if report.state == State::Connected {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    // `expect`: the report only has strings and numbers
+    let body = serde_json::to_string(&report).expect("Failed to serialize status (this is a bug)");
+    let response = format!(
+        "HTTP/1.1 {code}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let stream = stream.get_mut().get_mut();
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        warn!("Failed to write status response: {err}");
+        return;
+    }
+    stream.shutdown().await.ok();
+}
+
+/// Write the status to `path` periodically. The file is replaced atomically
+/// so that readers never see a partial status.
+async fn write_file(path: &Path, status: &Status, control_tx: &mpsc::Sender<ControlCommand>) {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut interval = time::interval(config::STATUS_FILE_INTERVAL);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let report = report(status, control_tx).await;
+        // `expect`: the report only has strings and numbers
+        let json =
+            serde_json::to_vec_pretty(&report).expect("Failed to serialize status (this is a bug)");
+        let result = async {
+            tokio::fs::write(&temp_path, json).await?;
+            tokio::fs::rename(&temp_path, path).await
+        };
+        if let Err(err) = result.await {
+            warn!("Failed to write status file {}: {err}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::traffic::RemoteTraffic;
+    use penguin_mux::ws::PlainStream;
+    use std::str::FromStr;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_status_endpoint() {
+        crate::tests::setup_logging();
+        let (client, server) = duplex(2048);
+        let client_mux = Arc::new(Multiplexor::new(PlainStream::new(client), None, None));
+        let server_mux = Multiplexor::new(PlainStream::new(server), None, None);
+        let remotes = [
+            Arc::new(Remote::from_str("127.0.0.1:0:example.com:443").unwrap()),
+            Arc::new(Remote::from_str("127.0.0.1:0:socks").unwrap()),
+        ];
+        let traffic = Arc::new(RemoteTraffic::default());
+        traffic.add_connection();
+        traffic.add_sent(100);
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            while let Some(command) = control_rx.recv().await {
+                if let ControlCommand::List(tx) = command {
+                    tx.send(vec![
+                        (1, remotes[0].dupe(), traffic.dupe(), false),
+                        (2, remotes[1].dupe(), Arc::default(), true),
+                    ])
+                    .ok();
+                }
+            }
+        });
+        let status = Status::default();
+        let request = async |status: &Status| {
+            let (mut client, server) = duplex(4096);
+            tokio::spawn(handle_status_connection(
+                server,
+                status.dupe(),
+                control_tx.clone(),
+            ));
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            let code = head.split(' ').nth(1).unwrap().to_string();
+            (
+                code,
+                serde_json::from_str::<serde_json::Value>(body).unwrap(),
+            )
+        };
+
+        let (code, report) = request(&status).await;
+        assert_eq!(code, "503");
+        assert_eq!(report["state"], "connecting");
+        assert_eq!(
+            report["remotes"][0]["remote"],
+            "127.0.0.1:0:example.com:443/tcp"
+        );
+        assert_eq!(report["remotes"][1]["paused"], true);
+
+        status.connected(vec![Arc::downgrade(&client_mux)]);
+        let accept = tokio::spawn(async move {
+            let mut stream = server_mux.accept_stream_channel().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.flush().await.unwrap();
+            (server_mux, stream)
+        });
+        let mut stream = client_mux
+            .new_stream_channel(b"example.com", 443)
+            .await
+            .unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(b"hi").await.unwrap();
+        let _server = accept.await.unwrap();
+        let (code, report) = request(&status).await;
+        assert_eq!(code, "200");
+        assert_eq!(report["state"], "connected");
+        assert_eq!(report["reconnects"], 0);
+        assert_eq!(report["streams"], 1);
+        assert_eq!(report["remotes"][0]["streams"], 1);
+        assert_eq!(report["remotes"][0]["bytes_sent"], 2);
+        assert_eq!(report["remotes"][0]["bytes_received"], 5);
+        assert_eq!(report["remotes"][1]["streams"], 0);
+        assert_eq!(report["remotes"][0]["total"]["connections"], 1);
+        assert_eq!(report["remotes"][0]["total"]["bytes_sent"], 100);
+        assert_eq!(report["remotes"][1]["total"]["connections"], 0);
+
+        status.reconnecting(&Error::RemoteDisconnected);
+        status.connected(Vec::new());
+        let (code, report) = request(&status).await;
+        assert_eq!(code, "200");
+        assert_eq!(report["reconnects"], 1);
+        assert_eq!(report["last_error"], "Remote disconnected normally");
+        assert_eq!(report["streams"], 0);
+    }
+}
@@ -0,0 +1,142 @@
+//! Traffic of each forward remote since it started.
+//!
+//! Every `handle_remote` task counts the connections it accepts, or the new
+//! clients for UDP remotes, and the octets they exchange with the server.
+//! The counts are reported in the status and summed up in the log every
+//! few minutes for the remotes that had any traffic since.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use penguin_mux::Dupe;
+use serde::Serialize;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Counters of a remote
+#[derive(Debug, Default)]
+pub(super) struct RemoteTraffic {
+    connections: AtomicU64,
+    /// To the server
+    bytes_sent: AtomicU64,
+    /// From the server
+    bytes_received: AtomicU64,
+}
+
+impl RemoteTraffic {
+    /// Count a new connection or UDP client
+    pub fn add_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count octets sent to the server
+    pub fn add_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count octets received from the server
+    pub fn add_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Wrap a local connection so that what goes through it is counted:
+    /// what we read from it is sent to the server, and what we write to it
+    /// was received from the server.
+    pub fn count<S>(self: &Arc<Self>, stream: S) -> Counted<S> {
+        Counted {
+            inner: stream,
+            traffic: self.dupe(),
+        }
+    }
+
+    /// The counts so far
+    pub fn totals(&self) -> Totals {
+        Totals {
+            connections: self.connections.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of [`RemoteTraffic`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub(super) struct Totals {
+    pub connections: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// A local connection of a remote that counts its octets
+#[derive(Debug)]
+pub(super) struct Counted<S> {
+    inner: S,
+    traffic: Arc<RemoteTraffic>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let len = buf.filled().len() - before;
+        self.traffic.add_sent(len);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            self.traffic.add_received(len);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_counted() {
+        crate::tests::setup_logging();
+        let traffic = Arc::new(RemoteTraffic::default());
+        traffic.add_connection();
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut counted = traffic.count(ours);
+        counted.write_all(b"hello").await.unwrap();
+        theirs.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        counted.read_exact(&mut buf).await.unwrap();
+        traffic.add_sent(10);
+        assert_eq!(
+            traffic.totals(),
+            Totals {
+                connections: 1,
+                bytes_sent: 12,
+                bytes_received: 5,
+            }
+        );
+    }
+}
@@ -2,78 +2,514 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+use super::WsStream;
+use super::happy_eyeballs::{interleave, race};
 use crate::arg::ClientArgs;
+use crate::config::CONNECTION_ATTEMPT_DELAY;
+use crate::raw_transport::{PROTOCOL_HEADER, recv_headers, send_headers};
 use crate::tls::make_tls_connector;
-use http::header::HeaderValue;
-use penguin_mux::{Dupe, PROTOCOL_VERSION};
-use tokio::net::TcpStream;
+use crate::udp_error::UDP_ERRORS_HEADER;
+use crate::ws_deflate::DeflateStream;
+use http::HeaderMap;
+use http::header::{AUTHORIZATION, HeaderValue};
+use penguin_mux::ws::PlainStream;
+use penguin_mux::{Compression, Dupe, PROTOCOL_VERSION, SUPPORTED_PROTOCOL_VERSIONS};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
 use tokio_tungstenite::tungstenite::{client::IntoClientRequest, handshake::client::Request};
-use tokio_tungstenite::{
-    Connector, MaybeTlsStream, WebSocketStream, connect_async_tls_with_config,
-};
+use tokio_tungstenite::{Connector, MaybeTlsStream, client_async_with_config};
 use tracing::{debug, warn};
 
-/// Perform a `WebSocket` handshake.
-#[tracing::instrument(skip_all, fields(server = %args.server.0), level = "debug")]
-pub async fn handshake(
+/// Header carrying the session token in requests and the outcome in responses
+pub const SESSION_HEADER: &str = "x-penguin-session";
+
+/// Header carrying the compression algorithm we want in requests and the
+/// one the server agreed to in responses
+pub const COMPRESSION_HEADER: &str = "x-penguin-compression";
+
+/// Header asking for traffic padding in requests and agreeing to it in
+/// responses
+pub const PADDING_HEADER: &str = "x-penguin-padding";
+
+/// Header saying that we can receive frames packed into one message in
+/// requests and that the server can too in responses
+pub const COALESCE_HEADER: &str = "x-penguin-coalesce";
+
+/// A connection to the server before TLS: TCP, or a stream through a hop
+pub trait RawStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> RawStream for T {}
+
+/// Multiplexor settings agreed with the server in the handshake
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Agreed {
+    pub compression: Compression,
+    pub padding: bool,
+    pub coalesce: bool,
+}
+
+/// What the server did with our session token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionReply {
+    /// No token was sent or the server does not support resumption
+    Unsupported,
+    /// The server started a new resumable session with this token
+    New(HeaderValue),
+    /// The server continues the session of the token
+    Resumed,
+}
+
+/// Open a connection to the server, directly, through the proxy or through
+/// the `--jump` hops, and set up TLS if `connector` asks for it.
+/// `host` is the host in the server URL. `--resolve` overrides the address
+/// we connect to, and `--sni` the name we send and verify in TLS.
+async fn connect_stream(
     args: &ClientArgs,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, super::Error> {
-    // We already sanitized https URLs to wss
-    let is_tls = args
-        .server
-        .scheme()
-        .expect("URL scheme should be present (this is a bug)")
-        .as_str()
-        == "wss";
+    host: &str,
+    port: u16,
+    connector: Connector,
+) -> Result<MaybeTlsStream<Box<dyn RawStream>>, super::Error> {
+    let resolved = args.resolve.map(|ip| ip.to_string());
+    let connect_host = resolved.as_deref().unwrap_or(host);
+    let tls_name = args.sni.as_deref().unwrap_or(host);
+    let stream: Box<dyn RawStream> = if !args.jump.is_empty() {
+        Box::new(super::jump::connect(args, connect_host, port).await?)
+    } else if let Some(proxy) = &args.proxy {
+        Box::new(super::proxy::connect(proxy, connect_host, port).await?)
+    } else {
+        return connect_direct(args, connect_host, port, tls_name, connector).await;
+    };
+    wrap_tls(stream, tls_name, connector).await
+}
 
+/// Resolve `connect_host` ourselves and race TCP and TLS to each of its
+/// addresses on `port` and the `--alt-port`s. With `--bind-addr`, only the
+/// addresses of its family are tried.
+async fn connect_direct(
+    args: &ClientArgs,
+    connect_host: &str,
+    port: u16,
+    tls_name: &str,
+    connector: Connector,
+) -> Result<MaybeTlsStream<Box<dyn RawStream>>, super::Error> {
+    let resolved = interleave(
+        lookup_host((connect_host, port))
+            .await
+            .map_err(super::Error::Connect)?
+            .filter(|addr| {
+                args.bind_addr
+                    .is_none_or(|bind_addr| bind_addr.is_ipv4() == addr.is_ipv4())
+            })
+            .collect(),
+    );
+    let addrs = std::iter::once(port)
+        .chain(args.alt_port.iter().copied())
+        .flat_map(|port| {
+            resolved
+                .iter()
+                .map(move |addr| SocketAddr::new(addr.ip(), port))
+        })
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        let reason = if args.bind_addr.is_some() {
+            format!("{connect_host} has no addresses of the --bind-addr family")
+        } else {
+            format!("{connect_host} has no addresses")
+        };
+        return Err(super::Error::Connect(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            reason,
+        )));
+    }
+    race(&addrs, CONNECTION_ATTEMPT_DELAY, |addr| {
+        let connector = connector.clone();
+        async move {
+            let stream = connect_tcp(args, addr).await?;
+            let stream = wrap_tls(Box::new(stream), tls_name, connector).await?;
+            debug!("Connected to {addr}");
+            Ok(stream)
+        }
+    })
+    .await
+}
+
+/// Open a TCP connection to `addr` from `--bind-addr` and through
+/// `--bind-iface` if given
+async fn connect_tcp(args: &ClientArgs, addr: SocketAddr) -> Result<TcpStream, super::Error> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+    .map_err(super::Error::Connect)?;
+    #[cfg(target_os = "linux")]
+    if let Some(iface) = &args.bind_iface {
+        socket
+            .bind_device(Some(iface.as_bytes()))
+            .map_err(super::Error::Connect)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.bind_iface.is_some() {
+        return Err(super::Error::BindIfaceUnsupported);
+    }
+    if let Some(bind_addr) = args.bind_addr {
+        socket
+            .bind(SocketAddr::new(bind_addr, 0))
+            .map_err(super::Error::Connect)?;
+    }
+    socket.connect(addr).await.map_err(super::Error::Connect)
+}
+
+/// Set up TLS on `stream` if `connector` asks for it, verifying the server
+/// as `tls_name`
+async fn wrap_tls(
+    stream: Box<dyn RawStream>,
+    tls_name: &str,
+    connector: Connector,
+) -> Result<MaybeTlsStream<Box<dyn RawStream>>, super::Error> {
+    match connector {
+        #[cfg(feature = "__rustls")]
+        Connector::Rustls(config) => {
+            let name =
+                rustls::pki_types::ServerName::try_from(tls_name.to_string()).map_err(|err| {
+                    super::Error::Connect(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        err,
+                    ))
+                })?;
+            let stream = tokio_rustls::TlsConnector::from(config)
+                .connect(name, stream)
+                .await
+                .map_err(super::Error::Connect)?;
+            Ok(MaybeTlsStream::Rustls(stream))
+        }
+        #[cfg(feature = "nativetls")]
+        Connector::NativeTls(connector) => {
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(tls_name, stream)
+                .await
+                .map_err(|err| super::Error::Connect(std::io::Error::other(err)))?;
+            Ok(MaybeTlsStream::NativeTls(stream))
+        }
+        _ => Ok(MaybeTlsStream::Plain(stream)),
+    }
+}
+
+/// Connect to the server and upgrade to `WebSocket` with `headers`.
+/// Returns the connection and the headers of the response.
+async fn websocket_connect(
+    args: &ClientArgs,
+    host: &str,
+    port: u16,
+    connector: Connector,
+    headers: HeaderMap,
+) -> Result<(WsStream, HeaderMap), super::Error> {
     // Use a request to allow additional headers
     let mut req: Request = args.server.0.dupe().into_client_request()?;
-    let req_headers = req.headers_mut();
-    // Add protocol version
+    req.headers_mut().extend(headers);
+    let stream = connect_stream(args, host, port, connector).await?;
+    if args.ws_deflate.enabled {
+        req.headers_mut().insert(
+            http::header::SEC_WEBSOCKET_EXTENSIONS,
+            crate::ws_deflate::offer(args.ws_deflate),
+        );
+        let stream = DeflateStream::client(stream);
+        let (ws_stream, response) = client_async_with_config(req, stream, None).await?;
+        Ok((Box::new(ws_stream), response.into_parts().0.headers))
+    } else {
+        let (ws_stream, response) = client_async_with_config(req, stream, None).await?;
+        Ok((Box::new(ws_stream), response.into_parts().0.headers))
+    }
+}
+
+/// Connect to the server without the `WebSocket` layer and exchange headers.
+/// Returns the connection and the headers the server replied with.
+async fn raw_connect(
+    args: &ClientArgs,
+    host: &str,
+    port: u16,
+    connector: Connector,
+    headers: &HeaderMap,
+) -> Result<(WsStream, HeaderMap), super::Error> {
+    let stream = connect_stream(args, host, port, connector).await?;
+    let mut ws: WsStream = Box::new(PlainStream::new(stream));
+    send_headers(&mut ws, headers).await?;
+    // The server closes the connection if it does not accept us
+    let reply = recv_headers(&mut ws)
+        .await?
+        .ok_or(super::Error::HandshakeRejected)?;
+    Ok((ws, reply))
+}
+
+/// Connect to the server over QUIC and exchange headers on the control lane.
+/// Returns the connection and the headers the server replied with.
+#[cfg(feature = "quic")]
+async fn quic_connect(
+    args: &ClientArgs,
+    host: &str,
+    port: u16,
+    connector: Connector,
+    headers: &HeaderMap,
+) -> Result<(WsStream, HeaderMap), super::Error> {
+    if !args.jump.is_empty() {
+        return Err(super::Error::QuicUnsupported("--jump"));
+    }
+    if args.proxy.is_some() {
+        return Err(super::Error::QuicUnsupported("--proxy"));
+    }
+    let Connector::Rustls(tls_config) = connector else {
+        unreachable!("`wss+quic://` URLs always use rustls (this is a bug)");
+    };
+    let config = crate::quic::client_config(&tls_config).map_err(super::Error::Connect)?;
+    let resolved = args.resolve.map(|ip| ip.to_string());
+    let connect_host = resolved.as_deref().unwrap_or(host);
+    let addr = lookup_host((connect_host, port))
+        .await
+        .map_err(super::Error::Connect)?
+        .find(|addr| {
+            args.bind_addr
+                .is_none_or(|bind_addr| bind_addr.is_ipv4() == addr.is_ipv4())
+        })
+        .ok_or_else(|| {
+            super::Error::Connect(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{connect_host} has no usable addresses"),
+            ))
+        })?;
+    let tls_name = args.sni.as_deref().unwrap_or(host);
+    let mut ws = crate::quic::connect(config, addr, tls_name, args.bind_addr)
+        .await
+        .map_err(super::Error::Connect)?;
+    debug!("Connected to {addr} over QUIC");
+    send_headers(&mut ws, headers).await?;
+    // The server closes the connection if it does not accept us
+    let reply = recv_headers(&mut ws)
+        .await?
+        .ok_or(super::Error::HandshakeRejected)?;
+    #[cfg(feature = "e2e")]
+    let encrypted = args.e2e_key.is_some();
+    #[cfg(not(feature = "e2e"))]
+    let encrypted = false;
+    let padding = args.obfs_padding && reply.contains_key(PADDING_HEADER);
+    let coalesce = args.coalesce && reply.contains_key(COALESCE_HEADER);
+    // Frames can only be told apart when they are not encrypted, padded or packed
+    ws.start_lanes(!encrypted && !padding && !coalesce);
+    Ok((Box::new(ws), reply))
+}
+
+/// Connect with the transport of the server URL and exchange `headers`.
+/// Returns the connection and the headers the server replied with.
+async fn connect_transport(
+    args: &ClientArgs,
+    host: &str,
+    port: u16,
+    connector: Connector,
+    headers: HeaderMap,
+) -> Result<(WsStream, HeaderMap), super::Error> {
+    #[cfg(feature = "quic")]
+    if args.server.is_quic() {
+        return quic_connect(args, host, port, connector, &headers).await;
+    }
+    if args.server.is_raw() {
+        raw_connect(args, host, port, connector, &headers).await
+    } else {
+        websocket_connect(args, host, port, connector, headers).await
+    }
+}
+
+/// The TLS connector for the server URL
+async fn make_connector(args: &ClientArgs) -> Result<Connector, super::Error> {
+    if args.server.is_tls() {
+        Ok(make_tls_connector(
+            args.tls_cert.as_deref(),
+            args.tls_key.as_deref(),
+            args.tls_ca.as_deref(),
+            args.tls_skip_verify,
+            &args.tls,
+            &args.tls_pin,
+        )
+        .await?)
+    } else {
+        // No TLS
+        warn!("Using insecure connection");
+        Ok(Connector::Plain)
+    }
+}
+
+/// The host and port of the server URL
+fn server_host_port(args: &ClientArgs) -> (&str, u16) {
+    let host = args
+        .server
+        .host()
+        .expect("Server URL should have a host (this is a bug)")
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = args
+        .server
+        .port_u16()
+        .unwrap_or(if args.server.is_tls() { 443 } else { 80 });
+    (host, port)
+}
+
+/// Connect to a `chisel` server and upgrade to `WebSocket` with its
+/// subprotocol. Authentication happens later in SSH.
+#[cfg(feature = "chisel")]
+pub async fn chisel_connect(args: &ClientArgs) -> Result<WsStream, super::Error> {
+    let mut req_headers = HeaderMap::new();
     req_headers.insert(
         "sec-websocket-protocol",
-        HeaderValue::from_static(PROTOCOL_VERSION),
+        HeaderValue::from_static(crate::chisel::WS_PROTOCOL),
     );
+    if let Some(ref hostname) = args.hostname {
+        req_headers.insert("host", hostname.dupe());
+    }
+    for header in &args.header {
+        req_headers.insert(&header.name, header.value.dupe());
+    }
+    let connector = make_connector(args).await?;
+    let (host, port) = server_host_port(args);
+    let connect = websocket_connect(args, host, port, connector, req_headers);
+    tokio::select! {
+        result = connect => Ok(result?.0),
+        () = args.handshake_timeout.sleep() => Err(super::Error::HandshakeTimeout),
+        Ok(()) = tokio::signal::ctrl_c() => Err(super::Error::HandshakeCancelled),
+    }
+}
+
+/// Perform a `WebSocket` handshake, or the handshake of the plain transport
+/// for `tls://` and `tcp://` URLs.
+/// If `session_token` is set, ask the server to resume the session, or to
+/// start one if it is `new`.
+/// Also returns the settings the server agreed to.
+#[tracing::instrument(skip_all, fields(server = %args.server.0), level = "debug")]
+pub async fn handshake(
+    args: &ClientArgs,
+    session_token: Option<&HeaderValue>,
+) -> Result<(WsStream, SessionReply, Agreed), super::Error> {
+    let is_raw = args.server.is_raw();
+
+    let mut req_headers = HeaderMap::new();
+    // Offer the protocol versions we support
+    // `expect`: the versions are valid header values
+    let versions = HeaderValue::try_from(SUPPORTED_PROTOCOL_VERSIONS.join(", "))
+        .expect("Invalid protocol versions (this is a bug)");
+    if is_raw {
+        req_headers.insert(PROTOCOL_HEADER, versions);
+    } else {
+        req_headers.insert("sec-websocket-protocol", versions);
+    }
     // Add PSK
     if let Some(ref ws_psk) = args.ws_psk {
         req_headers.insert("x-penguin-psk", ws_psk.dupe());
     }
+    if let Some(ref auth) = args.auth {
+        req_headers.insert(AUTHORIZATION, auth.header_value());
+    }
+    if let Some(session_token) = session_token {
+        req_headers.insert(SESSION_HEADER, session_token.dupe());
+    }
+    if args.compression != Compression::None {
+        req_headers.insert(
+            COMPRESSION_HEADER,
+            HeaderValue::from_static(args.compression.name()),
+        );
+    }
+    if args.obfs_padding {
+        req_headers.insert(PADDING_HEADER, HeaderValue::from_static("1"));
+    }
+    if args.coalesce {
+        req_headers.insert(COALESCE_HEADER, HeaderValue::from_static("1"));
+    }
+    req_headers.insert(UDP_ERRORS_HEADER, HeaderValue::from_static("1"));
     // Add potentially custom hostname
     if let Some(ref hostname) = args.hostname {
         req_headers.insert("host", hostname.dupe());
     }
+    // Let the server continue our trace
+    #[cfg(feature = "otel")]
+    crate::otel::inject(&mut req_headers);
     // Now add custom headers
     for header in &args.header {
         req_headers.insert(&header.name, header.value.dupe());
     }
 
-    let connector = if is_tls {
-        make_tls_connector(
-            args.tls_cert.as_deref(),
-            args.tls_key.as_deref(),
-            args.tls_ca.as_deref(),
-            args.tls_skip_verify,
-        )
-        .await?
-    } else {
-        // No TLS
-        warn!("Using insecure WebSocket connection");
-        Connector::Plain
-    };
-    let handshake = Box::pin(connect_async_tls_with_config(
-        req,
-        None,
-        false,
-        Some(connector),
-    ));
+    let connector = make_connector(args).await?;
+    let (host, port) = server_host_port(args);
+    let handshake = Box::pin(async {
+        let (ws_stream, response_headers) =
+            connect_transport(args, host, port, connector, req_headers).await?;
+        #[cfg(feature = "e2e")]
+        let ws_stream: WsStream = match &args.e2e_key {
+            Some(key) => Box::new(crate::e2e::connect(ws_stream, key).await?),
+            None => ws_stream,
+        };
+        Ok::<_, super::Error>((ws_stream, response_headers))
+    });
     tokio::select! {
         result = handshake => {
-            let (ws_stream, _response) = result?;
-            // We don't need to check the response now...
-            debug!("WebSocket handshake succeeded");
-            Ok(ws_stream)
+            let (ws_stream, response_headers) = result?;
+            // Servers from before the negotiation only spoke this version
+            let version = response_headers
+                .get(if is_raw { PROTOCOL_HEADER } else { "sec-websocket-protocol" })
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or(PROTOCOL_VERSION);
+            debug!("Handshake succeeded with protocol {version}");
+            let session_reply = match response_headers.get(SESSION_HEADER) {
+                _ if session_token.is_none() => SessionReply::Unsupported,
+                Some(value) if value == "resumed" => SessionReply::Resumed,
+                // Older servers echo `new` instead of issuing a token
+                Some(value) if value == "new" => SessionReply::Unsupported,
+                Some(token) => SessionReply::New(token.dupe()),
+                None => SessionReply::Unsupported,
+            };
+            let compression = match response_headers.get(COMPRESSION_HEADER) {
+                Some(value) if value == args.compression.name() => args.compression,
+                _ => Compression::None,
+            };
+            if compression != args.compression {
+                warn!("Server does not support {} compression", args.compression);
+            }
+            let padding = args.obfs_padding && response_headers.contains_key(PADDING_HEADER);
+            if padding != args.obfs_padding {
+                warn!("Server does not support traffic padding");
+            }
+            let coalesce = args.coalesce && response_headers.contains_key(COALESCE_HEADER);
+            if coalesce != args.coalesce {
+                warn!("Server does not support frame coalescing");
+            }
+            let agreed = Agreed {
+                compression,
+                padding,
+                coalesce,
+            };
+            Ok((ws_stream, session_reply, agreed))
         }
         () = args.handshake_timeout.sleep() => Err(super::Error::HandshakeTimeout),
         Ok(()) = tokio::signal::ctrl_c() => Err(super::Error::HandshakeCancelled),
     }
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connect_tcp_bind_addr() {
+        crate::tests::setup_logging();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let args = ClientArgs {
+            // Linux routes all of 127.0.0.0/8 to the loopback interface
+            bind_addr: Some("127.0.0.2".parse().unwrap()),
+            bind_iface: None,
+            ..Default::default()
+        };
+        let stream = connect_tcp(&args, listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        assert_eq!(peer.ip(), args.bind_addr.unwrap());
+    }
+}
@@ -9,6 +9,19 @@ pub const UDP_PRUNE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
 /// Client side: Number of stream requests to buffer in the channels for the main
 /// loop to read from.
 pub const STREAM_REQUEST_COMMAND_SIZE: usize = 1 << 6;
+/// Client side: Number of control commands to buffer in the channel for the
+/// remote supervisor to read from.
+pub const CONTROL_COMMAND_SIZE: usize = 1 << 4;
+/// Client side: how long the remotes started on SIGHUP get to fail, e.g.
+/// because their port is in use, before the reload is considered done
+pub const RELOAD_BIND_TIMEOUT: time::Duration = time::Duration::from_millis(200);
+/// Client side: how long a connection attempt to the server gets before the
+/// next address is tried alongside it
+pub const CONNECTION_ATTEMPT_DELAY: time::Duration = time::Duration::from_millis(250);
+/// Client side: how often to write the status file with `--status-file`
+pub const STATUS_FILE_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// Client side: how often to log the traffic of the remotes
+pub const TRAFFIC_SUMMARY_INTERVAL: time::Duration = time::Duration::from_mins(5);
 /// Both: Number of datagrams to buffer in the channels for the main loop
 /// to read from.
 pub const INCOMING_DATAGRAM_BUFFER_SIZE: usize = 1 << 6;
@@ -16,3 +29,36 @@ pub const INCOMING_DATAGRAM_BUFFER_SIZE: usize = 1 << 6;
 pub const MAX_UDP_PACKET_SIZE: usize = 1 << 16;
 /// Server side: Bind request buffer size
 pub const BIND_BUFFER_SIZE: usize = 1 << 4;
+/// Server side: how long to wait for the end-to-end encryption handshake
+#[cfg(feature = "e2e")]
+pub const E2E_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Server side: how long to wait for the SSH handshake of a `chisel` client
+#[cfg(feature = "chisel")]
+pub const CHISEL_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Server side: how long to wait for one `--resolver-nameserver` before
+/// asking the next
+pub const RESOLVER_NAMESERVER_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+/// Server side: how many hosts to keep in the cache of `--resolver-cache-ttl`
+pub const RESOLVER_CACHE_SIZE: usize = 1 << 12;
+/// Server side: how often to fetch the keys at the JWKS URL again
+pub const JWKS_REFRESH_INTERVAL: time::Duration = time::Duration::from_hours(1);
+/// Server side: how often to load the stapled OCSP response again
+pub const OCSP_REFRESH_INTERVAL: time::Duration = time::Duration::from_hours(12);
+/// Server side: how often to check whether the TLS certificate files changed
+/// with `--tls-watch`
+pub const TLS_WATCH_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// Client side: Size of the send and receive buffers of each TCP connection
+/// on a TUN device.
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub const TUN_TCP_BUFFER_SIZE: usize = 1 << 16;
+/// Client side: Number of datagrams to buffer for each UDP destination on a
+/// TUN device.
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub const TUN_UDP_PACKET_COUNT: usize = 1 << 5;
+/// Client side: Number of chunks to buffer between a TUN TCP connection and
+/// its stream.
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub const TUN_FLOW_CHANNEL_SIZE: usize = 1 << 4;
+/// Client side: how long to wait for a TUN TCP handshake to complete
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub const TUN_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
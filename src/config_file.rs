@@ -0,0 +1,389 @@
+//! Configuration file support.
+//!
+//! A configuration file is a TOML table whose keys are the long names of the
+//! options of a subcommand, or the names of its positional arguments:
+//!
+//! ```toml
+//! server = "wss://example.com"
+//! remote = ["1080:socks", "R:2222:localhost:22"]
+//! ws-psk = "some-secret"
+//! keepalive = 30
+//! tls-skip-verify = true
+//! ```
+//!
+//! The file is converted to command line arguments placed before the actual
+//! command line, so `clap` validates the result with the same rules, and
+//! options on the command line override those in the file. Options that can
+//! be given multiple times are combined instead. Positional arguments in the
+//! file are only used if they are not given on the command line.
+//!
+//! Files ending in `.yaml` or `.yml` are read as YAML with the same keys:
+//!
+//! ```yaml
+//! server: wss://example.com
+//! remote: ["1080:socks", "R:2222:localhost:22"]
+//! keepalive: 30
+//! ```
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use clap::{Arg, Command};
+use std::ffi::OsString;
+use thiserror::Error;
+
+/// Errors reading the configuration file
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("cannot read config file `{0}`: {1}")]
+    Read(String, std::io::Error),
+    #[error("cannot parse config file `{0}`: {1}")]
+    Parse(String, toml::de::Error),
+    #[error("cannot parse config file `{0}`: {1}")]
+    ParseYaml(String, serde_yaml::Error),
+    #[error("unknown option `{0}` in config file")]
+    UnknownKey(String),
+    #[error("invalid value for `{0}` in config file")]
+    Value(String),
+}
+
+/// What we need to know about the command line after the subcommand.
+#[derive(Debug, Default)]
+struct CliScan {
+    /// The value of `--config`, if any
+    config: Option<OsString>,
+    /// Number of positional arguments
+    positionals: usize,
+    /// Whether `--` is present
+    has_separator: bool,
+}
+
+#[inline]
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+/// Find `--config` and count the positional arguments of a subcommand.
+fn scan_cli(subcommand: &Command, args: &[OsString]) -> CliScan {
+    let mut scan = CliScan::default();
+    let mut iter = args.iter();
+    while let Some(token) = iter.next() {
+        let Some(token) = token.to_str() else {
+            // Non-UTF-8 options are not ours
+            scan.positionals += 1;
+            continue;
+        };
+        if scan.has_separator {
+            scan.positionals += 1;
+        } else if token == "--" {
+            scan.has_separator = true;
+        } else if let Some(long) = token.strip_prefix("--") {
+            let (name, inline_value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(OsString::from(value))),
+                None => (long, None),
+            };
+            let wants_value = subcommand
+                .get_arguments()
+                .any(|arg| arg.get_long() == Some(name) && takes_value(arg));
+            let value = if wants_value && inline_value.is_none() {
+                iter.next().cloned()
+            } else {
+                inline_value
+            };
+            if name == "config" {
+                scan.config = value;
+            }
+        } else if let Some(shorts) = token.strip_prefix('-')
+            && !shorts.is_empty()
+        {
+            // Clustered short flags: only the last one may take the next token
+            for (idx, short) in shorts.char_indices() {
+                let wants_value = subcommand
+                    .get_arguments()
+                    .any(|arg| arg.get_short() == Some(short) && takes_value(arg));
+                if wants_value {
+                    if idx + short.len_utf8() == shorts.len() {
+                        iter.next();
+                    }
+                    break;
+                }
+            }
+        } else {
+            scan.positionals += 1;
+        }
+    }
+    scan
+}
+
+/// Convert a scalar TOML value to an argument.
+fn scalar_to_arg(key: &str, value: &toml::Value) -> Result<OsString, Error> {
+    match value {
+        toml::Value::String(s) => Ok(s.into()),
+        toml::Value::Integer(i) => Ok(i.to_string().into()),
+        toml::Value::Float(f) => Ok(f.to_string().into()),
+        toml::Value::Boolean(b) => Ok(b.to_string().into()),
+        _ => Err(Error::Value(key.to_string())),
+    }
+}
+
+/// Convert a TOML value to a list of arguments.
+fn value_to_args(key: &str, value: &toml::Value) -> Result<Vec<OsString>, Error> {
+    match value {
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| scalar_to_arg(key, value))
+            .collect(),
+        value => Ok(vec![scalar_to_arg(key, value)?]),
+    }
+}
+
+/// Convert the configuration file to command line arguments for `subcommand`.
+/// Returns the options and the positional arguments separately.
+fn table_to_args(
+    subcommand: &Command,
+    table: &toml::Table,
+    cli_positionals: usize,
+) -> Result<(Vec<OsString>, Vec<OsString>), Error> {
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    let positional_args = subcommand
+        .get_positionals()
+        .map(|arg| arg.get_id().as_str())
+        .collect::<Vec<_>>();
+    for (key, value) in table {
+        // Allow `snake_case` keys as well
+        let name = key.replace('_', "-");
+        if let Some(idx) = positional_args.iter().position(|id| *id == name) {
+            // Only used if the command line does not have this one
+            if idx >= cli_positionals {
+                positionals.push((idx, value_to_args(key, value)?));
+            }
+            continue;
+        }
+        let Some(arg) = subcommand
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(&name) && name != "config")
+        else {
+            return Err(Error::UnknownKey(key.clone()));
+        };
+        let flag = OsString::from(format!("--{name}"));
+        if takes_value(arg) {
+            for value in value_to_args(key, value)? {
+                options.push(flag.clone());
+                options.push(value);
+            }
+        } else {
+            match value {
+                toml::Value::Boolean(true) => options.push(flag),
+                toml::Value::Boolean(false) => {}
+                _ => return Err(Error::Value(key.clone())),
+            }
+        }
+    }
+    positionals.sort_by_key(|(idx, _)| *idx);
+    let positionals = positionals
+        .into_iter()
+        .flat_map(|(_, values)| values)
+        .collect();
+    Ok((options, positionals))
+}
+
+/// Parse the configuration file as YAML or TOML depending on its extension.
+fn parse_table(path: String, content: &str) -> Result<toml::Table, Error> {
+    let is_yaml = std::path::Path::new(&path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"));
+    if is_yaml {
+        serde_yaml::from_str(content).map_err(|err| Error::ParseYaml(path, err))
+    } else {
+        content.parse().map_err(|err| Error::Parse(path, err))
+    }
+}
+
+/// Merge the configuration file given with `--config`, if any, into the
+/// command line arguments.
+///
+/// # Errors
+/// Returns an error if the file cannot be read or does not describe valid
+/// options. Whether the options are valid together is left to `clap`.
+pub fn merge_args(command: &Command, args: Vec<OsString>) -> Result<Vec<OsString>, Error> {
+    // Global options are all flags, so the subcommand is the first non-option
+    let Some(subcommand_idx) = args
+        .iter()
+        .skip(1)
+        .position(|arg| !arg.to_string_lossy().starts_with('-'))
+        .map(|idx| idx + 1)
+    else {
+        return Ok(args);
+    };
+    let Some(subcommand) = args[subcommand_idx]
+        .to_str()
+        .and_then(|name| command.find_subcommand(name))
+    else {
+        return Ok(args);
+    };
+    let scan = scan_cli(subcommand, &args[subcommand_idx + 1..]);
+    let Some(path) = scan.config else {
+        return Ok(args);
+    };
+    let path = path.to_string_lossy().into_owned();
+    let content = std::fs::read_to_string(&path).map_err(|err| Error::Read(path.clone(), err))?;
+    let table = parse_table(path, &content)?;
+    let (options, positionals) = table_to_args(subcommand, &table, scan.positionals)?;
+    let mut merged = Vec::with_capacity(args.len() + options.len() + positionals.len() + 1);
+    let mut args = args.into_iter();
+    merged.extend(args.by_ref().take(subcommand_idx + 1));
+    merged.extend(options);
+    merged.extend(args);
+    if !positionals.is_empty() {
+        if !scan.has_separator {
+            merged.push("--".into());
+        }
+        merged.extend(positionals);
+    }
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arg::{Commands, PenguinCli};
+    use clap::{CommandFactory, Parser};
+
+    fn parse_with_config(config: &str, args: &[&str]) -> Result<PenguinCli, String> {
+        parse_with_config_file("penguin.toml", config, args)
+    }
+
+    fn parse_with_config_file(
+        name: &str,
+        config: &str,
+        args: &[&str],
+    ) -> Result<PenguinCli, String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, config).unwrap();
+        let mut full_args: Vec<OsString> = vec!["penguin".into()];
+        full_args.extend(args.iter().map(OsString::from));
+        full_args.push("--config".into());
+        full_args.push(path.into_os_string());
+        let merged =
+            merge_args(&PenguinCli::command(), full_args).map_err(|err| err.to_string())?;
+        PenguinCli::try_parse_from(merged).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_client_config() {
+        crate::tests::setup_logging();
+        let config = r#"
+            server = "wss://example.com"
+            remote = ["1080:socks", "R:2222:localhost:22"]
+            ws-psk = "secret"
+            keepalive = 30
+            tls_skip_verify = true
+            header = ["X-A: 1", "X-B: 2"]
+        "#;
+        let cli = parse_with_config(config, &["client"]).unwrap();
+        let Commands::Client(args) = cli.subcommand else {
+            panic!("expected client");
+        };
+        assert_eq!(args.server.to_string(), "wss://example.com/");
+        assert_eq!(args.remote.len(), 2);
+        assert!(args.remote[1].0[0].reverse);
+        assert_eq!(args.ws_psk.unwrap(), "secret");
+        assert_eq!(args.keepalive.to_string(), "30s");
+        assert!(args.tls_skip_verify);
+        assert_eq!(args.header.len(), 2);
+    }
+
+    #[test]
+    fn test_client_config_cli_overrides() {
+        crate::tests::setup_logging();
+        let config = r#"
+            server = "wss://example.com"
+            remote = ["1080:socks"]
+            keepalive = 30
+            header = ["X-A: 1"]
+        "#;
+        let cli = parse_with_config(
+            config,
+            &[
+                "-v",
+                "client",
+                "--keepalive",
+                "5",
+                "-H",
+                "X-B: 2",
+                "ws://other.example.com",
+            ],
+        )
+        .unwrap();
+        assert_eq!(cli.verbose, 1);
+        let Commands::Client(args) = cli.subcommand else {
+            panic!("expected client");
+        };
+        assert_eq!(args.server.to_string(), "ws://other.example.com/");
+        // Remotes come from the file since there is none on the command line
+        assert_eq!(args.remote.len(), 1);
+        assert_eq!(args.keepalive.to_string(), "5s");
+        assert_eq!(args.header.len(), 2);
+        let cli = parse_with_config(config, &["client", "ws://other.example.com", "3000"]).unwrap();
+        let Commands::Client(args) = cli.subcommand else {
+            panic!("expected client");
+        };
+        assert_eq!(args.remote, ["3000".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_server_config() {
+        crate::tests::setup_logging();
+        let config = r#"
+            host = ["127.0.0.1", "::1"]
+            port = 443
+            listen-plain = ["127.0.0.1:8080"]
+            reverse = true
+            timeout = 0
+        "#;
+        let cli = parse_with_config(config, &["server"]).unwrap();
+        let Commands::Server(args) = cli.subcommand else {
+            panic!("expected server");
+        };
+        assert_eq!(args.host, ["127.0.0.1", "::1"]);
+        assert_eq!(args.port, [443]);
+        assert_eq!(args.listen_plain, ["127.0.0.1:8080".parse().unwrap()]);
+        assert!(args.reverse);
+        assert_eq!(args.timeout, penguin_mux::timing::OptionalDuration::NONE);
+    }
+
+    #[test]
+    fn test_yaml_config() {
+        crate::tests::setup_logging();
+        let config = r#"
+server: wss://example.com
+remote:
+  - "1080:socks"
+  - "R:2222:localhost:22"
+keepalive: 30
+tls_skip_verify: true
+"#;
+        let cli = parse_with_config_file("penguin.yaml", config, &["client"]).unwrap();
+        let Commands::Client(args) = cli.subcommand else {
+            panic!("expected client");
+        };
+        assert_eq!(args.server.to_string(), "wss://example.com/");
+        assert_eq!(args.remote.len(), 2);
+        assert_eq!(args.keepalive.to_string(), "30s");
+        assert!(args.tls_skip_verify);
+        parse_with_config_file("penguin.yml", "host: [", &["server"]).unwrap_err();
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        crate::tests::setup_logging();
+        let err = parse_with_config("no-such-option = 1", &["server"]).unwrap_err();
+        assert!(err.contains("no-such-option"));
+        let err = parse_with_config("reverse = \"yes\"", &["server"]).unwrap_err();
+        assert!(err.contains("reverse"));
+        parse_with_config("host = [", &["server"]).unwrap_err();
+        // `clap` still validates the combined options
+        parse_with_config("tls-key = \"key.pem\"", &["server"]).unwrap_err();
+    }
+}
@@ -0,0 +1,14 @@
+//! `dns` remotes, which forward DNS queries to the server's resolver.
+//!
+//! The client listens for queries over both UDP and TCP and sends them to the
+//! host `penguin-dns` port 53: queries over UDP as datagrams and those over
+//! TCP as streams, with the usual length prefixes. The server forwards them
+//! to its resolver instead of connecting to that host.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+/// Host of the streams and datagrams the server sends to its resolver
+pub const DNS_HOST: &str = "penguin-dns";
+
+/// Port of the streams and datagrams to [`DNS_HOST`]
+pub const DNS_PORT: u16 = 53;
@@ -0,0 +1,412 @@
+//! End-to-end encryption inside the tunnel.
+//!
+//! With `--e2e-key`, the client and the server run a
+//! `Noise_NNpsk0_25519_ChaChaPoly_SHA256` handshake right after connecting,
+//! keyed with the SHA-256 digest of the key, and then encrypt the payload of
+//! every binary message. This keeps the traffic confidential even if TLS
+//! ends at an intermediary such as a CDN, which cannot impersonate either
+//! side without the key.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::E2eKey;
+use bytes::{Bytes, BytesMut};
+use penguin_mux::PROTOCOL_VERSION;
+use penguin_mux::ws::{Message, WebSocket};
+use ring::aead::{self, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{SHA256, digest};
+use ring::hmac;
+use ring::rand::SystemRandom;
+use std::future::poll_fn;
+use std::task::{Context, Poll};
+use thiserror::Error;
+
+const PROTOCOL_NAME: &[u8] = b"Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const HASH_LEN: usize = 32;
+const DH_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+
+/// End-to-end encryption errors
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("E2E handshake failed: {0}")]
+    WebSocket(#[from] penguin_mux::Error),
+    #[error("E2E handshake failed: connection closed")]
+    Closed,
+    #[error("E2E encryption failed: wrong key or corrupted message")]
+    Crypto,
+    #[error("E2E encryption failed: too many messages")]
+    NonceExhausted,
+}
+
+impl From<ring::error::Unspecified> for Error {
+    fn from(_: ring::error::Unspecified) -> Self {
+        Self::Crypto
+    }
+}
+
+/// `HKDF` as defined by Noise, with three outputs
+fn hkdf(chaining_key: &[u8; HASH_LEN], ikm: &[u8]) -> [[u8; HASH_LEN]; 3] {
+    let temp_key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, chaining_key), ikm);
+    let temp_key = hmac::Key::new(hmac::HMAC_SHA256, temp_key.as_ref());
+    let mut outputs = [[0; HASH_LEN]; 3];
+    let mut previous: &[u8] = &[];
+    for (i, output) in (1u8..).zip(outputs.iter_mut()) {
+        let mut ctx = hmac::Context::with_key(&temp_key);
+        ctx.update(previous);
+        ctx.update(&[i]);
+        output.copy_from_slice(ctx.sign().as_ref());
+        previous = output;
+    }
+    outputs
+}
+
+/// A key and its nonce counter
+#[derive(Debug)]
+struct CipherState {
+    key: LessSafeKey,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: &[u8; HASH_LEN]) -> Self {
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, key)
+            .expect("ChaCha20-Poly1305 key of the wrong length (this is a bug)");
+        Self {
+            key: LessSafeKey::new(key),
+            nonce: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<Nonce, Error> {
+        // The maximum is reserved
+        if self.nonce == u64::MAX {
+            return Err(Error::NonceExhausted);
+        }
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce()?;
+        let mut buf = Vec::with_capacity(plaintext.len() + TAG_LEN);
+        buf.extend_from_slice(plaintext);
+        self.key
+            .seal_in_place_append_tag(nonce, aead::Aad::from(ad), &mut buf)?;
+        Ok(buf)
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<BytesMut, Error> {
+        let nonce = self.next_nonce()?;
+        let mut buf = BytesMut::from(ciphertext);
+        let len = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(ad), &mut buf)?
+            .len();
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+/// The handshake state shared by both sides
+struct SymmetricState {
+    chaining_key: [u8; HASH_LEN],
+    hash: [u8; HASH_LEN],
+    cipher: Option<CipherState>,
+}
+
+impl SymmetricState {
+    /// Start the handshake and process the `psk` token
+    fn new(key: &E2eKey) -> Self {
+        let mut hash = [0; HASH_LEN];
+        hash.copy_from_slice(digest(&SHA256, PROTOCOL_NAME).as_ref());
+        let mut state = Self {
+            chaining_key: hash,
+            hash,
+            cipher: None,
+        };
+        state.mix_hash(PROTOCOL_VERSION.as_bytes());
+        let [chaining_key, temp_hash, temp_key] = hkdf(
+            &state.chaining_key,
+            digest(&SHA256, key.0.as_bytes()).as_ref(),
+        );
+        state.chaining_key = chaining_key;
+        state.mix_hash(&temp_hash);
+        state.cipher = Some(CipherState::new(&temp_key));
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut ctx = ring::digest::Context::new(&SHA256);
+        ctx.update(&self.hash);
+        ctx.update(data);
+        self.hash.copy_from_slice(ctx.finish().as_ref());
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let [chaining_key, temp_key, _] = hkdf(&self.chaining_key, ikm);
+        self.chaining_key = chaining_key;
+        self.cipher = Some(CipherState::new(&temp_key));
+    }
+
+    /// Process an ephemeral public key, which also goes into the key with
+    /// a PSK
+    fn mix_ephemeral(&mut self, public_key: &[u8]) {
+        self.mix_hash(public_key);
+        self.mix_key(public_key);
+    }
+
+    fn cipher(&mut self) -> &mut CipherState {
+        self.cipher
+            .as_mut()
+            .expect("No key after the `psk` token (this is a bug)")
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let hash = self.hash;
+        let ciphertext = self.cipher().encrypt(&hash, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<BytesMut, Error> {
+        let hash = self.hash;
+        let plaintext = self.cipher().decrypt(&hash, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// The keys of the initiator and the responder
+    fn split(&self) -> (CipherState, CipherState) {
+        let [initiator, responder, _] = hkdf(&self.chaining_key, &[]);
+        (CipherState::new(&initiator), CipherState::new(&responder))
+    }
+}
+
+/// Generate an ephemeral key and process it
+fn write_ephemeral(
+    state: &mut SymmetricState,
+    rng: &SystemRandom,
+) -> Result<(EphemeralPrivateKey, Vec<u8>), Error> {
+    let private_key = EphemeralPrivateKey::generate(&X25519, rng)?;
+    let public_key = private_key.compute_public_key()?;
+    state.mix_ephemeral(public_key.as_ref());
+    let mut message = public_key.as_ref().to_vec();
+    message.extend(state.encrypt_and_hash(&[])?);
+    Ok((private_key, message))
+}
+
+/// Process the ephemeral key of the peer and check the rest of `message`
+fn read_ephemeral<'a>(state: &mut SymmetricState, message: &'a [u8]) -> Result<&'a [u8], Error> {
+    let (public_key, rest) = message.split_at_checked(DH_LEN).ok_or(Error::Crypto)?;
+    state.mix_ephemeral(public_key);
+    state.decrypt_and_hash(rest)?;
+    Ok(public_key)
+}
+
+/// Mix in the shared secret of the ephemeral keys
+fn mix_dh(
+    state: &mut SymmetricState,
+    private_key: EphemeralPrivateKey,
+    peer_public_key: &[u8],
+) -> Result<(), Error> {
+    let peer_public_key = UnparsedPublicKey::new(&X25519, peer_public_key);
+    agreement::agree_ephemeral(private_key, &peer_public_key, |shared| {
+        state.mix_key(shared);
+    })?;
+    Ok(())
+}
+
+async fn send<S: WebSocket>(ws: &mut S, message: Vec<u8>) -> Result<(), Error> {
+    poll_fn(|cx| ws.poll_ready_unpin(cx)).await?;
+    ws.start_send_unpin(Message::Binary(message.into()))?;
+    poll_fn(|cx| ws.poll_flush_unpin(cx)).await?;
+    Ok(())
+}
+
+async fn recv<S: WebSocket>(ws: &mut S) -> Result<Bytes, Error> {
+    loop {
+        match poll_fn(|cx| ws.poll_next_unpin(cx)).await.transpose()? {
+            Some(Message::Binary(data)) => return Ok(data),
+            Some(Message::Ping | Message::Pong) => {}
+            Some(Message::Close) | None => return Err(Error::Closed),
+        }
+    }
+}
+
+/// Run the handshake as the client
+pub async fn connect<S: WebSocket>(mut ws: S, key: &E2eKey) -> Result<E2eStream<S>, Error> {
+    let rng = SystemRandom::new();
+    let mut state = SymmetricState::new(key);
+    // -> psk, e
+    let (private_key, message) = write_ephemeral(&mut state, &rng)?;
+    send(&mut ws, message).await?;
+    // <- e, ee
+    let message = recv(&mut ws).await?;
+    let (peer_public_key, rest) = message.split_at_checked(DH_LEN).ok_or(Error::Crypto)?;
+    state.mix_ephemeral(peer_public_key);
+    mix_dh(&mut state, private_key, peer_public_key)?;
+    state.decrypt_and_hash(rest)?;
+    let (send, recv) = state.split();
+    Ok(E2eStream {
+        inner: ws,
+        send,
+        recv,
+    })
+}
+
+/// Run the handshake as the server
+pub async fn accept<S: WebSocket>(mut ws: S, key: &E2eKey) -> Result<E2eStream<S>, Error> {
+    let rng = SystemRandom::new();
+    let mut state = SymmetricState::new(key);
+    // -> psk, e
+    let message = recv(&mut ws).await?;
+    let peer_public_key = read_ephemeral(&mut state, &message)?;
+    // <- e, ee
+    let private_key = EphemeralPrivateKey::generate(&X25519, &rng)?;
+    let public_key = private_key.compute_public_key()?;
+    state.mix_ephemeral(public_key.as_ref());
+    mix_dh(&mut state, private_key, peer_public_key)?;
+    let mut message = public_key.as_ref().to_vec();
+    message.extend(state.encrypt_and_hash(&[])?);
+    send(&mut ws, message).await?;
+    let (recv, send) = state.split();
+    Ok(E2eStream {
+        inner: ws,
+        send,
+        recv,
+    })
+}
+
+/// A [`WebSocket`] that encrypts the payload of binary messages
+#[derive(Debug)]
+pub struct E2eStream<S> {
+    inner: S,
+    send: CipherState,
+    recv: CipherState,
+}
+
+impl<S: WebSocket> WebSocket for E2eStream<S> {
+    fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), penguin_mux::Error>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send_unpin(&mut self, item: Message) -> Result<(), penguin_mux::Error> {
+        let item = match item {
+            Message::Binary(data) => Message::Binary(
+                self.send
+                    .encrypt(&[], &data)
+                    .map_err(|err| penguin_mux::Error::WebSocket(Box::new(err)))?
+                    .into(),
+            ),
+            item => item,
+        };
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), penguin_mux::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), penguin_mux::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+
+    fn poll_next_unpin(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message, penguin_mux::Error>>> {
+        self.inner.poll_next_unpin(cx).map(|item| {
+            item.map(|result| match result? {
+                Message::Binary(data) => self
+                    .recv
+                    .decrypt(&[], &data)
+                    .map(|data| Message::Binary(data.freeze()))
+                    .map_err(|err| penguin_mux::Error::WebSocket(Box::new(err))),
+                message => Ok(message),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penguin_mux::ws::PlainStream;
+    use std::str::FromStr;
+    use tokio::io::DuplexStream;
+
+    async fn handshake(
+        client_key: &str,
+        server_key: &str,
+    ) -> (
+        Result<E2eStream<PlainStream<DuplexStream>>, Error>,
+        Result<E2eStream<PlainStream<DuplexStream>>, Error>,
+    ) {
+        let (client, server) = tokio::io::duplex(1024);
+        let client_key = E2eKey::from_str(client_key).unwrap();
+        let server_key = E2eKey::from_str(server_key).unwrap();
+        tokio::join!(
+            connect(PlainStream::new(client), &client_key),
+            accept(PlainStream::new(server), &server_key)
+        )
+    }
+
+    async fn roundtrip<A: WebSocket, B: WebSocket>(from: &mut A, to: &mut B, message: Message) {
+        poll_fn(|cx| from.poll_ready_unpin(cx)).await.unwrap();
+        from.start_send_unpin(message.clone()).unwrap();
+        poll_fn(|cx| from.poll_flush_unpin(cx)).await.unwrap();
+        let received = poll_fn(|cx| to.poll_next_unpin(cx)).await.unwrap().unwrap();
+        assert_eq!(received, message);
+    }
+
+    #[tokio::test]
+    async fn test_e2e_handshake() {
+        crate::tests::setup_logging();
+        let (client, server) = handshake("secret", "secret").await;
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        for _ in 0..3 {
+            let data = Bytes::from_static(b"hello");
+            roundtrip(&mut client, &mut server, Message::Binary(data.clone())).await;
+            roundtrip(&mut server, &mut client, Message::Binary(data)).await;
+        }
+        roundtrip(&mut client, &mut server, Message::Binary(Bytes::new())).await;
+        roundtrip(&mut server, &mut client, Message::Ping).await;
+        // `PlainStream` answers it
+        let pong = poll_fn(|cx| server.poll_next_unpin(cx)).await.unwrap();
+        assert_eq!(pong.unwrap(), Message::Pong);
+        // The payload is not sent in the clear
+        client
+            .start_send_unpin(Message::Binary(Bytes::from_static(b"hello")))
+            .unwrap();
+        poll_fn(|cx| client.poll_flush_unpin(cx)).await.unwrap();
+        let Some(Ok(Message::Binary(data))) = poll_fn(|cx| server.inner.poll_next_unpin(cx)).await
+        else {
+            panic!("Expected a binary message");
+        };
+        assert_eq!(data.len(), 5 + TAG_LEN);
+        assert_ne!(&data[..5], b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_e2e_wrong_key() {
+        crate::tests::setup_logging();
+        let (client, server) = handshake("secret", "other").await;
+        assert!(matches!(server, Err(Error::Crypto)));
+        assert!(matches!(client, Err(Error::Closed | Error::WebSocket(_))));
+    }
+
+    #[test]
+    fn test_hkdf() {
+        crate::tests::setup_logging();
+        let [first, second, third] = hkdf(&[0; HASH_LEN], b"ikm");
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+        let temp_key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &[0; HASH_LEN]), b"ikm");
+        let temp_key = hmac::Key::new(hmac::HMAC_SHA256, temp_key.as_ref());
+        assert_eq!(hmac::sign(&temp_key, &[1]).as_ref(), first);
+    }
+}
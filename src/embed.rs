@@ -0,0 +1,433 @@
+//! Running the client and the server from another program.
+//!
+//! The configurations wrap the command line arguments, so every option of
+//! `penguin client` and `penguin server` is available through `from_args`,
+//! and the common ones have typed `with_*` methods.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+#[cfg(feature = "client")]
+use crate::arg::ClientArgs;
+#[cfg(feature = "server")]
+use crate::arg::ServerArgs;
+use crate::arg::{BasicAuth, Commands, PenguinCli};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::str::FromStr;
+#[cfg(feature = "server")]
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::task::{AbortHandle, JoinHandle};
+
+/// An invalid client or server configuration
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    /// The options are invalid, as they would be on the command line
+    #[error(transparent)]
+    Args(#[from] clap::Error),
+    /// The value of an option is invalid
+    #[error("Invalid {0}: {1}")]
+    Value(&'static str, String),
+}
+
+/// What stopped a client or a server
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct Error(ErrorKind);
+
+#[derive(Debug, Error)]
+enum ErrorKind {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Run(#[from] crate::RunError),
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        Self(err.into())
+    }
+}
+
+/// Parse the value of an option
+fn parse<T: FromStr>(what: &'static str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|err: T::Err| ConfigError::Value(what, err.to_string()))
+}
+
+/// Parse `args` as the options of `subcommand`, merged with the config file
+/// they name, if any
+fn parse_subcommand<I, T>(subcommand: &str, args: I) -> Result<Commands, ConfigError>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let args = ["penguin", subcommand]
+        .into_iter()
+        .map(OsString::from)
+        .chain(args.into_iter().map(Into::into))
+        .collect();
+    Ok(PenguinCli::try_parse_merged_from(args)?.subcommand)
+}
+
+/// Configuration of a client
+#[cfg(feature = "client")]
+#[derive(Clone, Debug)]
+pub struct ClientConfig(ClientArgs);
+
+#[cfg(feature = "client")]
+impl ClientConfig {
+    /// Connect to `server`, such as `wss://example.com/ws`, and serve
+    /// `remotes`, written as on the command line (e.g. `1080:socks`).
+    /// Other options have their default values.
+    ///
+    /// # Errors
+    /// Returns an error if the URL or a remote is invalid.
+    pub fn new<I, T>(server: &str, remotes: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        Self::from_args(
+            std::iter::once(OsString::from(server)).chain(remotes.into_iter().map(Into::into)),
+        )
+    }
+
+    /// Parse the arguments of `penguin client`, without the program and
+    /// subcommand names
+    ///
+    /// # Errors
+    /// Returns an error if the arguments are invalid.
+    pub fn from_args<I, T>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        match parse_subcommand("client", args)? {
+            Commands::Client(args) => Ok(Self(args)),
+            Commands::Bench(_) => unreachable!("Parsed `client` as `bench`"),
+            Commands::Ping(_) => unreachable!("Parsed `client` as `ping`"),
+            #[cfg(feature = "server")]
+            Commands::Server(_) => unreachable!("Parsed `client` as `server`"),
+        }
+    }
+
+    /// Send this PSK to the server
+    ///
+    /// # Errors
+    /// Returns an error if the PSK is not a valid header value.
+    pub fn with_ws_psk(mut self, psk: &str) -> Result<Self, ConfigError> {
+        self.0.ws_psk = Some(parse("PSK", psk)?);
+        Ok(self)
+    }
+
+    /// Authenticate to the server with a username and a password
+    #[must_use]
+    pub fn with_auth(mut self, username: &str, password: &str) -> Self {
+        self.0.auth = Some(BasicAuth {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    /// Send a header in the handshake with the server
+    ///
+    /// # Errors
+    /// Returns an error if the name or the value is invalid.
+    pub fn with_header(mut self, name: &str, value: &str) -> Result<Self, ConfigError> {
+        self.0
+            .header
+            .push(parse("header", &format!("{name}: {value}"))?);
+        Ok(self)
+    }
+
+    /// Verify the server certificate with the CA bundle at `path` instead of
+    /// the system roots
+    #[must_use]
+    pub fn with_tls_ca(mut self, path: &str) -> Self {
+        self.0.tls_ca = Some(path.to_string());
+        self
+    }
+
+    /// Accept any server certificate. This is insecure.
+    #[must_use]
+    pub const fn with_tls_skip_verify(mut self, skip: bool) -> Self {
+        self.0.tls_skip_verify = skip;
+        self
+    }
+}
+
+/// Configuration of a server
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub struct ServerConfig(ServerArgs);
+
+#[cfg(feature = "server")]
+impl ServerConfig {
+    /// Listen on `host:port`. Other options have their default values.
+    ///
+    /// # Errors
+    /// Returns an error if the host is invalid.
+    pub fn new(host: &str, port: u16) -> Result<Self, ConfigError> {
+        Self::from_args(["--host", host, "--port", &port.to_string()])
+    }
+
+    /// Parse the arguments of `penguin server`, without the program and
+    /// subcommand names
+    ///
+    /// # Errors
+    /// Returns an error if the arguments are invalid.
+    pub fn from_args<I, T>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        match parse_subcommand("server", args)? {
+            Commands::Server(args) => Ok(Self(args)),
+            #[cfg(feature = "client")]
+            Commands::Client(_) => unreachable!("Parsed `server` as `client`"),
+            #[cfg(feature = "client")]
+            Commands::Bench(_) => unreachable!("Parsed `server` as `bench`"),
+            #[cfg(feature = "client")]
+            Commands::Ping(_) => unreachable!("Parsed `server` as `ping`"),
+        }
+    }
+
+    /// Only accept clients with this PSK. Can be called several times to
+    /// accept any of them.
+    ///
+    /// # Errors
+    /// Returns an error if the PSK is not a valid header value.
+    pub fn with_ws_psk(mut self, psk: &str) -> Result<Self, ConfigError> {
+        self.0.ws_psk.push(parse("PSK", psk)?);
+        Ok(self)
+    }
+
+    /// Only accept clients that authenticate with this username and password
+    #[must_use]
+    pub fn with_auth(mut self, username: &str, password: &str) -> Self {
+        self.0.auth = Some(BasicAuth {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    /// Proxy requests that are not for the WebSocket endpoint to `url`
+    ///
+    /// # Errors
+    /// Returns an error if the URL is invalid.
+    pub fn with_backend(mut self, url: &str) -> Result<Self, ConfigError> {
+        self.0.backend = Some(parse("backend URL", url)?);
+        Ok(self)
+    }
+
+    /// Serve TLS with the certificate chain and the private key in these
+    /// PEM files
+    #[must_use]
+    pub fn with_tls(mut self, cert: &str, key: &str) -> Self {
+        self.0.tls_cert = Some(cert.to_string());
+        self.0.tls_key = Some(key.to_string());
+        self
+    }
+
+    /// Whether clients may use reverse remotes
+    #[must_use]
+    pub const fn with_reverse(mut self, reverse: bool) -> Self {
+        self.0.reverse = reverse;
+        self
+    }
+}
+
+/// A client or a server running in the background
+#[derive(Debug)]
+pub struct Handle(JoinHandle<Result<(), crate::RunError>>);
+
+/// Stops a running client or server
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle(AbortHandle);
+
+impl Handle {
+    /// A handle to stop it from elsewhere
+    #[must_use]
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.0.abort_handle())
+    }
+
+    /// Wait for it to stop. After a shutdown, this returns `Ok`.
+    ///
+    /// # Errors
+    /// Returns the error that stopped it.
+    ///
+    /// # Panics
+    /// Resumes the panic of the client or the server, if any.
+    pub async fn wait(self) -> Result<(), Error> {
+        match self.0.await {
+            Ok(result) => result.map_err(|err| Error(err.into())),
+            Err(err) if err.is_cancelled() => Ok(()),
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Stop the client or the server, closing its connections and the
+    /// streams on them
+    pub fn shutdown(&self) {
+        self.0.abort();
+    }
+}
+
+/// Start a client on the current `tokio` runtime.
+///
+/// # Panics
+/// Panics if called outside of a `tokio` runtime.
+#[cfg(feature = "client")]
+#[must_use]
+pub fn run_client(config: ClientConfig) -> Handle {
+    Handle(tokio::spawn(async move {
+        crate::client::client_main(&config.0)
+            .await
+            .map_err(Into::into)
+    }))
+}
+
+/// Start a server on the current `tokio` runtime.
+///
+/// # Panics
+/// Panics if called outside of a `tokio` runtime.
+#[cfg(feature = "server")]
+#[must_use]
+pub fn run_server(config: ServerConfig) -> Handle {
+    Handle(tokio::spawn(async move {
+        crate::server::server_main(Arc::new(config.0))
+            .await
+            .map_err(Into::into)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_remote::Remote;
+    use http::HeaderValue;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_client_config() {
+        crate::tests::setup_logging();
+        let config = ClientConfig::new("wss://example.com/ws", ["1080:socks", "8080:localhost:80"])
+            .unwrap()
+            .with_ws_psk("secret")
+            .unwrap()
+            .with_auth("user", "pass")
+            .with_header("X-Test", "1")
+            .unwrap();
+        assert_eq!(config.0.server.0.host(), Some("example.com"));
+        assert_eq!(
+            config.0.remote[0].0,
+            [Remote::from_str("1080:socks").unwrap()]
+        );
+        assert_eq!(config.0.remote.len(), 2);
+        assert_eq!(config.0.ws_psk, Some(HeaderValue::from_static("secret")));
+        assert_eq!(config.0.header[0].name, "x-test");
+        // Defaults are those of the command line
+        assert_eq!(config.0.max_retry_interval, 300_000);
+        assert!(ClientConfig::new("ftp://example.com", ["1080:socks"]).is_err());
+        assert!(ClientConfig::from_args(["wss://example.com", "--no-such-option"]).is_err());
+    }
+
+    #[test]
+    fn test_server_config() {
+        crate::tests::setup_logging();
+        let config = ServerConfig::new("127.0.0.1", 8443)
+            .unwrap()
+            .with_ws_psk("a")
+            .unwrap()
+            .with_ws_psk("b")
+            .unwrap()
+            .with_backend("http://127.0.0.1:3000")
+            .unwrap()
+            .with_reverse(true);
+        assert_eq!(config.0.host, ["127.0.0.1"]);
+        assert_eq!(config.0.port, [8443]);
+        assert_eq!(config.0.ws_psk.len(), 2);
+        assert!(config.0.reverse);
+        assert!(
+            ServerConfig::new("127.0.0.1", 8443)
+                .unwrap()
+                .with_backend("ftp://example.com")
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_and_shutdown() {
+        crate::tests::setup_logging();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        let server = run_server(
+            ServerConfig::new("127.0.0.1", 31874)
+                .unwrap()
+                .with_ws_psk("secret")
+                .unwrap(),
+        );
+        let client = run_client(
+            ClientConfig::new(
+                "ws://127.0.0.1:31874/ws",
+                [format!("127.0.0.1:21873:127.0.0.1:{target_port}")],
+            )
+            .unwrap()
+            .with_ws_psk("secret")
+            .unwrap(),
+        );
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut sock = TcpStream::connect("127.0.0.1:21873").await.unwrap();
+        sock.write_all(b"hello").await.unwrap();
+        let (mut accepted, _) = target.accept().await.unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        client.shutdown_handle().shutdown();
+        client.wait().await.unwrap();
+        server.shutdown_handle().shutdown();
+        server.wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_streams() {
+        crate::tests::setup_logging();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        let server = run_server(ServerConfig::new("127.0.0.1", 31875).unwrap());
+        let client = run_client(
+            ClientConfig::new(
+                "ws://127.0.0.1:31875/ws",
+                [format!("127.0.0.1:21874:127.0.0.1:{target_port}")],
+            )
+            .unwrap(),
+        );
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut sock = TcpStream::connect("127.0.0.1:21874").await.unwrap();
+        sock.write_all(b"hello").await.unwrap();
+        let (mut accepted, _) = target.accept().await.unwrap();
+        let mut buf = [0; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        // The session outlives the listener, but not the server
+        server.shutdown_handle().shutdown();
+        server.wait().await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(5), accepted.read(&mut buf))
+            .await
+            .expect("Stream still open after shutdown");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        client.shutdown_handle().shutdown();
+        client.wait().await.unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+//! A fast TCP/UDP tunnel, transported over HTTP WebSocket.
+//!
+//! This library runs the `penguin` client or server inside another program.
+//! Build a [`ClientConfig`] or a [`ServerConfig`], start it with
+//! [`run_client`] or [`run_server`] on a `tokio` runtime, and stop it with
+//! the [`ShutdownHandle`] of the returned [`Handle`]:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), penguin::Error> {
+//! let config = penguin::ClientConfig::new("wss://example.com/ws", ["1080:socks"])?
+//!     .with_ws_psk("some-secret")?;
+//! let client = penguin::run_client(config);
+//! let shutdown = client.shutdown_handle();
+//! // Later, maybe from another task
+//! shutdown.shutdown();
+//! client.wait().await
+//! # }
+//! ```
+//!
+//! The multiplexor itself is the `penguin-mux` crate.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#![warn(rust_2018_idioms, missing_docs, missing_debug_implementations)]
+#![warn(clippy::pedantic, clippy::cargo, clippy::unwrap_used)]
+#![deny(unsafe_code)]
+#![cfg_attr(not(all(feature = "client", feature = "server")), allow(dead_code))]
+// Without them, there is no client or server to run
+#![cfg(feature = "penguin-binary-common")]
+
+mod acl;
+mod arg;
+mod bench;
+#[cfg(feature = "chisel")]
+mod chisel;
+#[cfg(feature = "client")]
+mod client;
+mod config;
+mod config_file;
+mod dns;
+#[cfg(feature = "e2e")]
+mod e2e;
+mod embed;
+#[cfg(feature = "otel")]
+mod otel;
+mod parse_remote;
+#[cfg(feature = "quic")]
+mod quic;
+mod raw_transport;
+mod reverse_udp;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(windows)]
+mod service;
+#[cfg(unix)]
+mod syslog;
+#[cfg(test)]
+mod tests;
+mod tls;
+mod udp_error;
+mod ws_deflate;
+
+#[cfg(feature = "client")]
+pub use embed::{ClientConfig, run_client};
+pub use embed::{ConfigError, Error, Handle, ShutdownHandle};
+#[cfg(feature = "server")]
+pub use embed::{ServerConfig, run_server};
+use std::process::ExitCode;
+use thiserror::Error;
+use tracing::trace;
+use tracing_subscriber::{Layer, filter, fmt, prelude::*, registry};
+
+/// Errors of the subcommands
+#[derive(Debug, Error)]
+enum RunError {
+    #[cfg(feature = "client")]
+    #[error(transparent)]
+    Client(#[from] client::Error),
+    #[cfg(feature = "server")]
+    #[error(transparent)]
+    Server(#[from] server::Error),
+    #[cfg(windows)]
+    #[error(transparent)]
+    Service(#[from] service::Error),
+}
+
+/// Exit status when the client gives up reconnecting
+const RETRIES_EXHAUSTED_EXIT_CODE: u8 = 3;
+
+impl RunError {
+    /// Exit status to report for this error
+    fn exit_code(&self) -> ExitCode {
+        match self {
+            #[cfg(feature = "client")]
+            Self::Client(client::Error::MaxRetryCountReached(_)) => {
+                ExitCode::from(RETRIES_EXHAUSTED_EXIT_CODE)
+            }
+            _ => ExitCode::FAILURE,
+        }
+    }
+}
+
+const QUIET_QUIET_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::ERROR;
+const QUIET_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::WARN;
+const DEFAULT_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::INFO;
+const VERBOSE_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::DEBUG;
+const VERBOSE_VERBOSE_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::TRACE;
+
+#[cfg(feature = "deadlock-detection")]
+fn spawn_deadlock_detection() {
+    use std::thread;
+    use tracing::error;
+
+    // Create a background thread which checks for deadlocks every 10s
+    thread::spawn(move || {
+        loop {
+            thread::sleep(std::time::Duration::from_secs(10));
+            let deadlocks = parking_lot::deadlock::check_deadlock();
+            if deadlocks.is_empty() {
+                continue;
+            }
+
+            error!("{} deadlocks detected", deadlocks.len());
+            for (i, threads) in deadlocks.iter().enumerate() {
+                error!("Deadlock #{}", i);
+                for t in threads {
+                    error!("Thread Id {:#?}", t.thread_id());
+                    error!("{:#?}", t.backtrace());
+                }
+            }
+        }
+    });
+}
+
+/// Log level for the `-v` and `-q` flags, which cannot be given together
+const fn log_level(cli_args: &arg::PenguinCli) -> filter::LevelFilter {
+    match (cli_args.verbose, cli_args.quiet) {
+        (0, 0) => DEFAULT_LOG_LEVEL,
+        (0, 1) => QUIET_LOG_LEVEL,
+        (0, _) => QUIET_QUIET_LOG_LEVEL,
+        (1, _) => VERBOSE_LOG_LEVEL,
+        _ => VERBOSE_VERBOSE_LOG_LEVEL,
+    }
+}
+
+/// Format messages of `layer` as given by `--log-format`
+fn with_log_format<S, T, W>(
+    layer: fmt::Layer<S, fmt::format::DefaultFields, fmt::format::Format<fmt::format::Full, T>, W>,
+    format: arg::LogFormat,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> registry::LookupSpan<'a>,
+    T: fmt::time::FormatTime + Send + Sync + 'static,
+    W: for<'w> fmt::MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        arg::LogFormat::Compact => layer.compact().boxed(),
+        // Event fields at the top level and span fields in a list, so that
+        // the names of the fields do not depend on how spans are nested
+        arg::LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Log to the target, in the format and at the level from the command line
+fn setup_logging(cli_args: &arg::PenguinCli) {
+    let stderr_layer = || {
+        with_log_format(
+            fmt::Layer::default()
+                .with_timer(fmt::time::time())
+                .with_writer(std::io::stderr),
+            cli_args.log_format,
+        )
+    };
+    let fmt_layer = match cli_args.log_target {
+        arg::LogTarget::Stderr => stderr_layer(),
+        // The daemon adds the timestamp
+        #[cfg(unix)]
+        arg::LogTarget::Syslog => match syslog::Syslog::connect() {
+            Ok(syslog) => with_log_format(
+                fmt::Layer::default()
+                    .without_time()
+                    .with_ansi(false)
+                    .with_writer(syslog),
+                cli_args.log_format,
+            ),
+            Err(err) => {
+                eprintln!("Cannot connect to syslog, logging to stderr: {err}");
+                stderr_layer()
+            }
+        },
+        // Fields without a prefix, so that they are named as in JSON logs
+        #[cfg(target_os = "linux")]
+        arg::LogTarget::Journald => match tracing_journald::layer() {
+            Ok(layer) => layer
+                .with_field_prefix(None)
+                .with_syslog_identifier("penguin".into())
+                .boxed(),
+            Err(err) => {
+                eprintln!("Cannot connect to journald, logging to stderr: {err}");
+                stderr_layer()
+            }
+        },
+    }
+    .with_filter(log_level(cli_args));
+    let registry = tracing_subscriber::registry();
+    #[cfg(feature = "tokio-console")]
+    let registry = registry.with(console_subscriber::spawn());
+    // All of our spans, whatever their level, but only the events we log
+    #[cfg(feature = "otel")]
+    let registry = registry.with(cli_args.otlp_endpoint.as_ref().map(|_| {
+        let level = log_level(cli_args);
+        otel::layer().with_filter(filter::filter_fn(move |meta| {
+            (meta.is_span() && meta.target().starts_with("penguin")) || level >= *meta.level()
+        }))
+    }));
+    registry.with(fmt_layer).init();
+}
+
+/// Entry point of the `penguin` executable
+#[doc(hidden)]
+pub async fn cli_main() -> ExitCode {
+    arg::PenguinCli::parse_global();
+    let cli_args = arg::PenguinCli::get_global();
+    // Before logging, so that the layer gets the tracer, and kept until we
+    // exit to export the last spans
+    #[cfg(feature = "otel")]
+    let _otel = cli_args.otlp_endpoint.as_deref().and_then(|endpoint| {
+        otel::Otel::init(endpoint)
+            .inspect_err(|err| eprintln!("Cannot export to {endpoint}: {err}"))
+            .ok()
+    });
+    setup_logging(cli_args);
+    trace!("cli_args = {cli_args:#?}");
+    #[cfg(feature = "deadlock-detection")]
+    spawn_deadlock_detection();
+    #[cfg(windows)]
+    let result = match cli_args.service {
+        Some(action) => service::service_main(action, cli_args)
+            .await
+            .map_err(Into::into),
+        None => run(cli_args).await,
+    };
+    #[cfg(not(windows))]
+    let result = run(cli_args).await;
+    if let Err(e) = result {
+        eprintln!("Error: {e}");
+        return e.exit_code();
+    }
+    ExitCode::SUCCESS
+}
+
+/// Run the subcommand
+async fn run(cli_args: &'static arg::PenguinCli) -> Result<(), RunError> {
+    match &cli_args.subcommand {
+        #[cfg(feature = "client")]
+        arg::Commands::Client(args) => client::client_main(args).await.map_err(Into::into),
+        #[cfg(feature = "client")]
+        arg::Commands::Bench(args) => client::bench::bench_main(args).await.map_err(Into::into),
+        #[cfg(feature = "client")]
+        arg::Commands::Ping(args) => client::ping::ping_main(args).await.map_err(Into::into),
+        #[cfg(feature = "server")]
+        arg::Commands::Server(args) => server::server_main(std::sync::Arc::new(args.clone()))
+            .await
+            .map_err(Into::into),
+    }
+}
+
+#[cfg(all(feature = "rustls-native-roots", feature = "rustls-webpki-roots"))]
+compile_error!("Only one of rustls-native-roots and rustls-webpki-roots can be enabled at a time");
+#[cfg(all(feature = "__rustls", feature = "nativetls"))]
+compile_error!(
+    "Only one of rustls-native-roots, rustls-webpki-roots, and nativetls can be enabled at a time"
+);
+#[cfg(all(feature = "tokio-console", feature = "remove-logging"))]
+compile_error!("tokio-console without trace-level logging is likely not desired");
@@ -1,5 +0,0 @@
-/// Current mux and Penguin protocol version
-pub const PROTOCOL_VERSION: &str = "penguin-v7";
-
-/// Current mux and Penguin protocol version, only the version number
-pub const PROTOCOL_VERSION_NUMBER: u8 = 7;
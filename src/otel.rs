@@ -0,0 +1,151 @@
+//! Exporting traces and metrics with OTLP, built with the `otel` feature and
+//! enabled with `--otlp-endpoint`.
+//!
+//! The spans of the handshakes, sessions, streams and forwarding connections
+//! go to the collector, along with counters of the handshakes, streams and
+//! octets. The client sends the context of its handshake as a W3C
+//! `traceparent` header, and the server continues the trace of any request
+//! that has one.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use http::HeaderMap;
+use opentelemetry::global::BoxedTracer;
+use opentelemetry::metrics::Counter;
+use opentelemetry::{KeyValue, global};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_otlp::{ExporterBuildError, MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::sync::LazyLock;
+use tracing::Span;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+
+/// Name of the instrumentation scope and the service
+const NAME: &str = "penguin";
+
+/// Exporters of traces and metrics, flushed when dropped
+#[derive(Debug)]
+pub struct Otel {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Otel {
+    /// Export to the OTLP/HTTP collector at `endpoint`, such as
+    /// `http://localhost:4318`
+    pub fn init(endpoint: &str) -> Result<Self, ExporterBuildError> {
+        let endpoint = endpoint.trim_end_matches('/');
+        let resource = Resource::builder().with_service_name(NAME).build();
+        let span_exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{endpoint}/v1/traces"))
+            .build()?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_batch_exporter(span_exporter)
+            .with_resource(resource.clone())
+            .build();
+        let metric_exporter = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{endpoint}/v1/metrics"))
+            .build()?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_periodic_exporter(metric_exporter)
+            .with_resource(resource)
+            .build();
+        global::set_tracer_provider(tracer_provider.clone());
+        global::set_meter_provider(meter_provider.clone());
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        Ok(Self {
+            tracer_provider,
+            meter_provider,
+        })
+    }
+}
+
+impl Drop for Otel {
+    fn drop(&mut self) {
+        // Logging is about to go away with us
+        if let Err(err) = self.tracer_provider.shutdown() {
+            eprintln!("Cannot export the remaining spans: {err}");
+        }
+        if let Err(err) = self.meter_provider.shutdown() {
+            eprintln!("Cannot export the remaining metrics: {err}");
+        }
+    }
+}
+
+/// A `tracing` layer that exports the spans once [`Otel::init`] has set up
+/// the exporter
+pub fn layer<S>() -> OpenTelemetryLayer<S, BoxedTracer>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(global::tracer(NAME))
+}
+
+/// Continue the trace in the `traceparent` of `headers`, if any, in `span`
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    // Fails only if the span is disabled, and then nothing is exported
+    span.set_parent(parent).ok();
+}
+
+/// Add the `traceparent` of the current span to `headers`
+pub fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers));
+    });
+}
+
+/// Counters exported as metrics
+struct Metrics {
+    handshakes: Counter<u64>,
+    streams: Counter<u64>,
+    bytes_sent: Counter<u64>,
+    bytes_received: Counter<u64>,
+}
+
+// Not exported until `Otel::init` sets the meter provider
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let meter = global::meter(NAME);
+    Metrics {
+        handshakes: meter
+            .u64_counter("penguin.handshakes")
+            .with_description("Tunnel handshakes received by the server")
+            .build(),
+        streams: meter
+            .u64_counter("penguin.streams")
+            .with_description("Streams and UDP flows forwarded by the server")
+            .build(),
+        bytes_sent: meter
+            .u64_counter("penguin.bytes_sent")
+            .with_description("Octets sent to clients")
+            .with_unit("By")
+            .build(),
+        bytes_received: meter
+            .u64_counter("penguin.bytes_received")
+            .with_description("Octets received from clients")
+            .with_unit("By")
+            .build(),
+    }
+});
+
+/// Count a handshake, `"accepted"` or `"rejected"`
+pub fn handshake(result: &'static str) {
+    METRICS
+        .handshakes
+        .add(1, &[KeyValue::new("result", result)]);
+}
+
+/// Count a stream or UDP flow of `protocol` that has finished
+pub fn stream_finished(protocol: &'static str, bytes_sent: u64, bytes_received: u64) {
+    let attributes = [KeyValue::new("protocol", protocol)];
+    METRICS.streams.add(1, &attributes);
+    METRICS.bytes_sent.add(bytes_sent, &attributes);
+    METRICS.bytes_received.add(bytes_received, &attributes);
+}
@@ -27,6 +27,7 @@ macro_rules! default_host {
 }
 
 // Export this macro for use in `arg.rs`.
+#[cfg(test)]
 pub(crate) use default_host;
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
@@ -35,20 +36,41 @@ pub struct Remote {
     #[allow(clippy::struct_field_names)]
     pub remote_addr: RemoteSpec,
     pub protocol: Protocol,
+    /// Whether this is a reverse remote (`R:` prefix), i.e. the server
+    /// listens on `local_addr` and connections are tunneled back to the
+    /// client, which connects to `remote_addr`.
+    pub reverse: bool,
+    /// Name given with a `NAME=` prefix, which the control socket uses to
+    /// pause and resume the remote. Several remotes may share a name.
+    pub name: Option<String>,
 }
 
-/// The local side can be either IP+port or "stdio".
+/// The local side can be either IP+port, "stdio", a Unix socket, or a TUN device.
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum LocalSpec {
     Inet((String, u16)),
     Stdio,
+    /// Absolute path of a Unix domain socket to listen on
+    Unix(String),
+    /// Name of a TUN device whose TCP and UDP flows are tunneled to their
+    /// destinations. Always paired with `RemoteSpec::Transparent`.
+    Tun(String),
 }
 
-/// The remote side can be either IP+port or "socks".
+/// The remote side can be either IP+port, a Unix socket, "socks", "tproxy",
+/// or "dns".
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum RemoteSpec {
     Inet((String, u16)),
+    /// Absolute path of a Unix domain socket on the server. It is sent as
+    /// the host of the stream with port 0.
+    Unix(String),
     Socks,
+    /// The original destination of connections redirected to the listener
+    /// by the firewall (`iptables` `REDIRECT` or `TPROXY`).
+    Transparent,
+    /// DNS queries over both UDP and TCP, answered by the server's resolver
+    Dns,
 }
 
 /// Protocol can be either "tcp" or "udp".
@@ -71,6 +93,37 @@ pub enum Error {
     Port(#[from] std::num::ParseIntError),
     #[error("socks remote must be TCP")]
     UdpSocks,
+    #[error("reverse remote cannot use stdio")]
+    ReverseStdio,
+    #[error("tproxy remote cannot use stdio or be reversed")]
+    TransparentLocal,
+    #[error("dns remote cannot use stdio or be reversed")]
+    DnsLocal,
+    #[error("dns remote listens on both TCP and UDP")]
+    UdpDns,
+    #[cfg(not(target_os = "linux"))]
+    #[error("tproxy remotes are only supported on Linux")]
+    TransparentUnsupported,
+    #[error("reverse remote cannot use a Unix socket")]
+    ReverseUnix,
+    #[error("Unix socket remote must be TCP")]
+    UdpUnix,
+    #[cfg(not(unix))]
+    #[error("Unix socket remotes are only supported on Unix")]
+    UnixUnsupported,
+    #[error("reverse remote cannot use a TUN device")]
+    ReverseTun,
+    #[cfg(not(all(feature = "tun", target_os = "linux")))]
+    #[error("tun remotes require the `tun` feature on Linux")]
+    TunUnsupported,
+    #[error("Invalid remote name")]
+    Name,
+    #[error("Invalid port range: ranges must be ascending and of the same length")]
+    PortRange,
+    #[error("Cannot read remote file `{0}`: {1}")]
+    ReadFile(String, String),
+    #[error("Invalid remote on line {1} of `{0}`: {2}")]
+    FileLine(String, usize, Box<Error>),
 }
 
 impl Display for Protocol {
@@ -94,6 +147,22 @@ impl FromStr for Protocol {
     }
 }
 
+/// Split the `NAME=` prefix of a remote, if any. Names consist of ASCII
+/// letters, digits, `-` and `_`.
+fn split_name(s: &str) -> (Option<&str>, &str) {
+    match s.split_once('=') {
+        Some((name, rest))
+            if !name.is_empty()
+                && name
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') =>
+        {
+            (Some(name), rest)
+        }
+        _ => (None, s),
+    }
+}
+
 /// Tokenize a remote string by splitting on `:`.
 /// We don't need RE!
 fn tokenize_remote(s: &str) -> Result<Vec<&str>, Error> {
@@ -121,6 +190,16 @@ fn tokenize_remote(s: &str) -> Result<Vec<&str>, Error> {
 
 impl Display for Remote {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{name}=")?;
+        }
+        if self.reverse {
+            f.write_str("R:")?;
+        }
+        if let LocalSpec::Tun(name) = &self.local_addr {
+            // Both protocols are always tunneled
+            return write!(f, "tun://{name}");
+        }
         match &self.local_addr {
             LocalSpec::Inet((host, port)) => {
                 if host.contains(':') {
@@ -130,6 +209,8 @@ impl Display for Remote {
                 }
             }
             LocalSpec::Stdio => f.write_str("stdio")?,
+            LocalSpec::Unix(path) => f.write_str(path)?,
+            LocalSpec::Tun(_) => unreachable!("handled above"),
         }
         match &self.remote_addr {
             RemoteSpec::Inet((host, port)) => {
@@ -139,7 +220,12 @@ impl Display for Remote {
                     write!(f, ":{host}:{port}")?;
                 }
             }
+            // Always TCP, and a suffix would be taken as part of the path
+            RemoteSpec::Unix(path) => return write!(f, ":{path}"),
             RemoteSpec::Socks => f.write_str(":socks")?,
+            RemoteSpec::Transparent => f.write_str(":tproxy")?,
+            // Both protocols are always listened on
+            RemoteSpec::Dns => return f.write_str(":dns"),
         }
         write!(f, "/{}", self.protocol)?;
         Ok(())
@@ -150,48 +236,134 @@ impl FromStr for Remote {
     type Err = Error;
 
     /// Parse a remote specification.
+    #[allow(clippy::too_many_lines)]
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let (Some(name), spec) = split_name(s) {
+            let remote = Self::from_str(spec)?;
+            if remote.name.is_some() {
+                return Err(Error::Name);
+            }
+            return Ok(Self {
+                name: Some(name.to_string()),
+                ..remote
+            });
+        }
+        // Reverse remotes are prefixed with `R:` like in `chisel`
+        let (reverse, s) = match s.strip_prefix("R:") {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        if let Some(name) = s.strip_prefix("tun://") {
+            if name.is_empty() || name.contains(['/', ':']) {
+                return Err(Error::Format);
+            }
+            return Self {
+                local_addr: LocalSpec::Tun(name.to_string()),
+                remote_addr: RemoteSpec::Transparent,
+                protocol: Protocol::Tcp,
+                reverse,
+                name: None,
+            }
+            .validated();
+        }
+        if s.starts_with('/') {
+            return Self::parse_unix(s, reverse);
+        }
+        if let Some((local, path)) = s.split_once(":/") {
+            // Like with "socks", listen on localhost by default
+            let local_addr = match tokenize_remote(local)?[..] {
+                ["stdio"] => LocalSpec::Stdio,
+                [port] => LocalSpec::Inet((default_host!(local), port.parse()?)),
+                [host, port] => LocalSpec::Inet((remove_brackets(host).to_string(), port.parse()?)),
+                _ => return Err(Error::Format),
+            };
+            return Self {
+                local_addr,
+                remote_addr: RemoteSpec::Unix(format!("/{path}")),
+                protocol: Protocol::Tcp,
+                reverse,
+                name: None,
+            }
+            .validated();
+        }
         let (rest, proto) = match s.rsplit_once('/') {
             Some((rest, proto)) => (rest, proto.parse()?),
             None => (s, Protocol::Tcp),
         };
         let tokens = tokenize_remote(rest)?;
         let result = match tokens[..] {
-            // One element: either "socks" or a port number.
+            // One element: either "socks", "dns" or a port number.
             ["socks"] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(local), 1080)),
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                reverse,
+                name: None,
+            }),
+            ["dns"] => Ok(Self {
+                local_addr: LocalSpec::Inet((default_host!(local), 53)),
+                remote_addr: RemoteSpec::Dns,
+                protocol: proto,
+                reverse,
+                name: None,
             }),
             [port] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(unspec), port.parse()?)),
                 remote_addr: RemoteSpec::Inet((default_host!(local), port.parse()?)),
                 protocol: proto,
+                reverse,
+                name: None,
             }),
-            // Two elements: either "socks" and local port number, or remote host and port number.
+            // Two elements: either local port number and "socks", "tproxy" or "dns",
+            // or remote host and port number.
             ["stdio", "socks"] => Ok(Self {
                 local_addr: LocalSpec::Stdio,
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                reverse,
+                name: None,
             }),
             [port, "socks"] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(local), port.parse()?)),
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                reverse,
+                name: None,
+            }),
+            ["stdio", "tproxy"] => Err(Error::TransparentLocal),
+            ["stdio", "dns"] => Err(Error::DnsLocal),
+            [port, "dns"] => Ok(Self {
+                local_addr: LocalSpec::Inet((default_host!(local), port.parse()?)),
+                remote_addr: RemoteSpec::Dns,
+                protocol: proto,
+                reverse,
+                name: None,
+            }),
+            // Redirected connections usually come from other hosts
+            [port, "tproxy"] => Ok(Self {
+                local_addr: LocalSpec::Inet((default_host!(unspec), port.parse()?)),
+                remote_addr: RemoteSpec::Transparent,
+                protocol: proto,
+                reverse,
+                name: None,
             }),
             ["stdio", port] => Ok(Self {
                 local_addr: LocalSpec::Stdio,
                 remote_addr: RemoteSpec::Inet((default_host!(local), port.parse()?)),
                 protocol: proto,
+                reverse,
+                name: None,
             }),
             [host, port] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(unspec), port.parse()?)),
                 remote_addr: RemoteSpec::Inet((remove_brackets(host).to_string(), port.parse()?)),
                 protocol: proto,
+                reverse,
+                name: None,
             }),
             // Three elements:
             // - "stdio", remote host, and port number,
-            // - local host, local port number, and "socks", or
+            // - local host, local port number, and "socks", "tproxy" or "dns", or
             // - local port number, remote host, and port number.
             ["stdio", remote_host, remote_port] => Ok(Self {
                 local_addr: LocalSpec::Stdio,
@@ -200,6 +372,8 @@ impl FromStr for Remote {
                     remote_port.parse()?,
                 )),
                 protocol: proto,
+                reverse,
+                name: None,
             }),
             [local_host, local_port, "socks"] => Ok(Self {
                 local_addr: LocalSpec::Inet((
@@ -208,6 +382,28 @@ impl FromStr for Remote {
                 )),
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                reverse,
+                name: None,
+            }),
+            [local_host, local_port, "dns"] => Ok(Self {
+                local_addr: LocalSpec::Inet((
+                    remove_brackets(local_host).to_string(),
+                    local_port.parse()?,
+                )),
+                remote_addr: RemoteSpec::Dns,
+                protocol: proto,
+                reverse,
+                name: None,
+            }),
+            [local_host, local_port, "tproxy"] => Ok(Self {
+                local_addr: LocalSpec::Inet((
+                    remove_brackets(local_host).to_string(),
+                    local_port.parse()?,
+                )),
+                remote_addr: RemoteSpec::Transparent,
+                protocol: proto,
+                reverse,
+                name: None,
             }),
             [local_port, remote_host, remote_port] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(unspec), local_port.parse()?)),
@@ -216,6 +412,8 @@ impl FromStr for Remote {
                     remote_port.parse()?,
                 )),
                 protocol: proto,
+                reverse,
+                name: None,
             }),
             [local_host, local_port, remote_host, remote_port] => Ok(Self {
                 local_addr: LocalSpec::Inet((
@@ -227,21 +425,248 @@ impl FromStr for Remote {
                     remote_port.parse()?,
                 )),
                 protocol: proto,
+                reverse,
+                name: None,
             }),
             _ => Err(Error::Format),
         };
+        result?.validated()
+    }
+}
+
+impl Remote {
+    /// Parse a remote listening on a Unix socket, i.e. `PATH:[HOST:]PORT`
+    /// or `PATH:REMOTE_PATH`.
+    /// The path is absolute and cannot contain `:`.
+    fn parse_unix(s: &str, reverse: bool) -> Result<Self, Error> {
+        let (path, rest) = s.split_once(':').ok_or(Error::Format)?;
+        if rest.starts_with('/') {
+            return Self {
+                local_addr: LocalSpec::Unix(path.to_string()),
+                remote_addr: RemoteSpec::Unix(rest.to_string()),
+                protocol: Protocol::Tcp,
+                reverse,
+                name: None,
+            }
+            .validated();
+        }
+        let (rest, proto) = match rest.rsplit_once('/') {
+            Some((rest, proto)) => (rest, proto.parse()?),
+            None => (rest, Protocol::Tcp),
+        };
+        let remote_addr = match tokenize_remote(rest)?[..] {
+            [port] => RemoteSpec::Inet((default_host!(local), port.parse()?)),
+            [host, port] => RemoteSpec::Inet((remove_brackets(host).to_string(), port.parse()?)),
+            _ => return Err(Error::Format),
+        };
+        Self {
+            local_addr: LocalSpec::Unix(path.to_string()),
+            remote_addr,
+            protocol: proto,
+            reverse,
+            name: None,
+        }
+        .validated()
+    }
+
+    /// Reject remotes that are syntactically valid but not supported.
+    fn validated(self) -> Result<Self, Error> {
         // I love Rust's pattern matching
         // (this sentence is written by GitHub Copilot)
-        if let Ok(Self {
-            remote_addr: RemoteSpec::Socks,
-            protocol: Protocol::Udp,
-            ..
-        }) = &result
-        {
-            Err(Error::UdpSocks)
-        } else {
-            result
+        match self {
+            Self {
+                remote_addr: RemoteSpec::Socks,
+                protocol: Protocol::Udp,
+                ..
+            } => Err(Error::UdpSocks),
+            Self {
+                remote_addr: RemoteSpec::Dns,
+                reverse: true,
+                ..
+            } => Err(Error::DnsLocal),
+            Self {
+                remote_addr: RemoteSpec::Dns,
+                protocol: Protocol::Udp,
+                ..
+            } => Err(Error::UdpDns),
+            Self {
+                local_addr: LocalSpec::Stdio,
+                reverse: true,
+                ..
+            } => Err(Error::ReverseStdio),
+            Self {
+                local_addr: LocalSpec::Tun(_),
+                reverse: true,
+                ..
+            } => Err(Error::ReverseTun),
+            Self {
+                local_addr: LocalSpec::Unix(_),
+                reverse: true,
+                ..
+            }
+            | Self {
+                remote_addr: RemoteSpec::Unix(_),
+                reverse: true,
+                ..
+            } => Err(Error::ReverseUnix),
+            Self {
+                local_addr: LocalSpec::Unix(_),
+                protocol: Protocol::Udp,
+                ..
+            } => Err(Error::UdpUnix),
+            #[cfg(not(unix))]
+            Self {
+                local_addr: LocalSpec::Unix(_),
+                ..
+            } => Err(Error::UnixUnsupported),
+            #[cfg(not(all(feature = "tun", target_os = "linux")))]
+            Self {
+                local_addr: LocalSpec::Tun(_),
+                ..
+            } => Err(Error::TunUnsupported),
+            Self {
+                remote_addr: RemoteSpec::Transparent,
+                reverse: true,
+                ..
+            } => Err(Error::TransparentLocal),
+            #[cfg(not(target_os = "linux"))]
+            Self {
+                remote_addr: RemoteSpec::Transparent,
+                ..
+            } => Err(Error::TransparentUnsupported),
+            _ => Ok(self),
+        }
+    }
+}
+
+/// A remote on the command line, whose ports may be ranges such as
+/// `5000-5010`. It stands for one remote per port of the ranges, or for all
+/// the remotes in a file if written as `@path`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RemoteArg(pub Vec<Remote>);
+
+impl From<Remote> for RemoteArg {
+    fn from(remote: Remote) -> Self {
+        Self(vec![remote])
+    }
+}
+
+/// Parse a port range token, keeping what follows it (e.g. `/udp`).
+/// Returns the first port, the number of ports, and the rest.
+fn port_range(token: &str) -> Option<Result<(u16, u16, &str), Error>> {
+    let end_idx = token.find('/').unwrap_or(token.len());
+    let (start, end) = token[..end_idx].split_once('-')?;
+    let start = start.parse::<u16>().ok()?;
+    let end = end.parse::<u16>().ok()?;
+    if start > end {
+        return Some(Err(Error::PortRange));
+    }
+    Some(Ok((start, end - start, &token[end_idx..])))
+}
+
+/// Whether the token at `idx` of `tokens` is the local or the remote port,
+/// the only fields that may be ranges. The remote port is the last token
+/// and the local port comes before the remote host, or before a keyword
+/// such as `socks`.
+fn is_port_field(tokens: &[&str], idx: usize) -> bool {
+    let last = tokens.len() - 1;
+    let last_is_port = port_range(tokens[last]).is_some()
+        || tokens[last]
+            .split('/')
+            .next()
+            .is_some_and(|port| port.parse::<u16>().is_ok());
+    if last_is_port {
+        idx == last || idx + 2 == last
+    } else {
+        idx + 1 == last
+    }
+}
+
+impl FromStr for RemoteArg {
+    type Err = Error;
+
+    /// Parse a remote, expanding its port ranges. `5000-5010:host:6000-6010`
+    /// maps local port 5000 to remote port 6000 and so on, and a single port
+    /// on one side takes all the ports of the other.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix('@') {
+            return Self::load(path);
+        }
+        if let (Some(name), spec) = split_name(s) {
+            // Files have their own names
+            if spec.starts_with('@') {
+                return Err(Error::Name);
+            }
+            let mut arg = Self::from_str(spec)?;
+            for remote in &mut arg.0 {
+                if remote.name.is_some() {
+                    return Err(Error::Name);
+                }
+                remote.name = Some(name.to_string());
+            }
+            return Ok(arg);
+        }
+        // Anything that is a valid remote as is, such as a host with `-`,
+        // is not a range
+        let err = match Remote::from_str(s) {
+            Ok(remote) => return Ok(remote.into()),
+            Err(err) => err,
+        };
+        let tokens = tokenize_remote(s)?;
+        let mut ranges = Vec::new();
+        for (idx, token) in tokens.iter().enumerate() {
+            if !is_port_field(&tokens, idx) {
+                continue;
+            }
+            if let Some(range) = port_range(token) {
+                let (start, len, rest) = range?;
+                ranges.push((idx, start, len, rest));
+            }
+        }
+        let Some(&(_, _, len, _)) = ranges.first() else {
+            return Err(err);
+        };
+        if ranges.iter().any(|range| range.2 != len) {
+            return Err(Error::PortRange);
+        }
+        (0..=len)
+            .map(|offset| {
+                let mut tokens = tokens.iter().map(ToString::to_string).collect::<Vec<_>>();
+                for (idx, start, _, rest) in &ranges {
+                    tokens[*idx] = format!("{}{rest}", start + offset);
+                }
+                Remote::from_str(&tokens.join(":"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl RemoteArg {
+    /// Load a file with one remote per line. Empty lines and everything
+    /// after `#` are ignored.
+    fn load(path: &str) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| Error::ReadFile(path.to_string(), err.to_string()))?;
+        Self::parse_file(path, &content)
+    }
+
+    /// Parse the content of a remote file named `path`
+    fn parse_file(path: &str, content: &str) -> Result<Self, Error> {
+        let mut remotes = Vec::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line_error = |err| Error::FileLine(path.to_string(), idx + 1, Box::new(err));
+            let spec = line.split_once('#').map_or(line, |(spec, _)| spec).trim();
+            if spec.is_empty() {
+                continue;
+            }
+            // Files do not include other files
+            if spec.starts_with('@') {
+                return Err(line_error(Error::Format));
+            }
+            remotes.extend(Self::from_str(spec).map_err(line_error)?.0);
         }
+        Ok(Self(remotes))
     }
 }
 
@@ -297,6 +722,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 3000)),
                     remote_addr: RemoteSpec::Inet((default_host!(local), 3000)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -305,6 +732,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 4000)),
                     remote_addr: RemoteSpec::Inet((default_host!(local), 4000)),
                     protocol: Protocol::Udp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -313,6 +742,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 80)),
                     remote_addr: RemoteSpec::Inet((String::from("google.com"), 80)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -321,6 +752,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 80)),
                     remote_addr: RemoteSpec::Inet((String::from("示例網站.com"), 80)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -329,6 +762,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 8080)),
                     remote_addr: RemoteSpec::Inet((String::from("example.com"), 80)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -337,6 +772,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(local), 1080)),
                     remote_addr: RemoteSpec::Socks,
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -345,6 +782,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((String::from("127.0.0.1"), 1081)),
                     remote_addr: RemoteSpec::Socks,
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -353,6 +792,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(local), 9050)),
                     remote_addr: RemoteSpec::Socks,
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -361,6 +802,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 53)),
                     remote_addr: RemoteSpec::Inet((String::from("1.1.1.1"), 53)),
                     protocol: Protocol::Udp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -369,6 +812,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((String::from("localhost"), 5353)),
                     remote_addr: RemoteSpec::Inet((String::from("1.1.1.1"), 53)),
                     protocol: Protocol::Udp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -377,6 +822,8 @@ mod tests {
                     local_addr: LocalSpec::Inet((String::from("::1"), 8080)),
                     remote_addr: RemoteSpec::Inet((String::from("google.com"), 80)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -388,6 +835,8 @@ mod tests {
                         53,
                     )),
                     protocol: Protocol::Udp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -396,6 +845,8 @@ mod tests {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Inet((String::from("google.com"), 80)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -404,6 +855,8 @@ mod tests {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Socks,
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -412,6 +865,8 @@ mod tests {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Inet((default_host!(local), 443)),
                     protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
             (
@@ -420,6 +875,206 @@ mod tests {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Inet((default_host!(local), 5353)),
                     protocol: Protocol::Udp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "R:8080:example.com:80",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 8080)),
+                    remote_addr: RemoteSpec::Inet((String::from("example.com"), 80)),
+                    protocol: Protocol::Tcp,
+                    reverse: true,
+                    name: None,
+                },
+            ),
+            (
+                "R:[::1]:2222:localhost:22/tcp",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("::1"), 2222)),
+                    remote_addr: RemoteSpec::Inet((String::from("localhost"), 22)),
+                    protocol: Protocol::Tcp,
+                    reverse: true,
+                    name: None,
+                },
+            ),
+            (
+                "dns",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(local), 53)),
+                    remote_addr: RemoteSpec::Dns,
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "5353:dns",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(local), 5353)),
+                    remote_addr: RemoteSpec::Dns,
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "[::1]:5353:dns",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("::1"), 5353)),
+                    remote_addr: RemoteSpec::Dns,
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "R:5353:127.0.0.1:53/udp",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 5353)),
+                    remote_addr: RemoteSpec::Inet((String::from("127.0.0.1"), 53)),
+                    protocol: Protocol::Udp,
+                    reverse: true,
+                    name: None,
+                },
+            ),
+            (
+                "R:socks",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(local), 1080)),
+                    remote_addr: RemoteSpec::Socks,
+                    protocol: Protocol::Tcp,
+                    reverse: true,
+                    name: None,
+                },
+            ),
+            (
+                "R:0.0.0.0:9050:socks",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("0.0.0.0"), 9050)),
+                    remote_addr: RemoteSpec::Socks,
+                    protocol: Protocol::Tcp,
+                    reverse: true,
+                    name: None,
+                },
+            ),
+            #[cfg(target_os = "linux")]
+            (
+                "12345:tproxy",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 12345)),
+                    remote_addr: RemoteSpec::Transparent,
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(target_os = "linux")]
+            (
+                "12345:tproxy/udp",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 12345)),
+                    remote_addr: RemoteSpec::Transparent,
+                    protocol: Protocol::Udp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(target_os = "linux")]
+            (
+                "[::]:12345:tproxy/tcp",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("::"), 12345)),
+                    remote_addr: RemoteSpec::Transparent,
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(unix)]
+            (
+                "/tmp/app.sock:example.com:80",
+                Remote {
+                    local_addr: LocalSpec::Unix(String::from("/tmp/app.sock")),
+                    remote_addr: RemoteSpec::Inet((String::from("example.com"), 80)),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(unix)]
+            (
+                "/run/dir.d/app.sock:[::1]:5432/tcp",
+                Remote {
+                    local_addr: LocalSpec::Unix(String::from("/run/dir.d/app.sock")),
+                    remote_addr: RemoteSpec::Inet((String::from("::1"), 5432)),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(unix)]
+            (
+                "/tmp/app.sock:8080",
+                Remote {
+                    local_addr: LocalSpec::Unix(String::from("/tmp/app.sock")),
+                    remote_addr: RemoteSpec::Inet((default_host!(local), 8080)),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "2375:/var/run/docker.sock",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(local), 2375)),
+                    remote_addr: RemoteSpec::Unix(String::from("/var/run/docker.sock")),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "[::]:5432:/run/postgresql/.s.PGSQL.5432",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("::"), 5432)),
+                    remote_addr: RemoteSpec::Unix(String::from("/run/postgresql/.s.PGSQL.5432")),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            (
+                "stdio:/var/run/docker.sock",
+                Remote {
+                    local_addr: LocalSpec::Stdio,
+                    remote_addr: RemoteSpec::Unix(String::from("/var/run/docker.sock")),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(unix)]
+            (
+                "/tmp/docker.sock:/var/run/docker.sock",
+                Remote {
+                    local_addr: LocalSpec::Unix(String::from("/tmp/docker.sock")),
+                    remote_addr: RemoteSpec::Unix(String::from("/var/run/docker.sock")),
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
+                },
+            ),
+            #[cfg(all(feature = "tun", target_os = "linux"))]
+            (
+                "tun://penguin0",
+                Remote {
+                    local_addr: LocalSpec::Tun(String::from("penguin0")),
+                    remote_addr: RemoteSpec::Transparent,
+                    protocol: Protocol::Tcp,
+                    reverse: false,
+                    name: None,
                 },
             ),
         ];
@@ -433,5 +1088,199 @@ mod tests {
         }
         "just_a_hostname".parse::<Remote>().unwrap_err();
         "socks/udp".parse::<Remote>().unwrap_err();
+        assert_eq!(
+            "R:stdio:example.com:22".parse::<Remote>(),
+            Err(Error::ReverseStdio)
+        );
+        assert_eq!("R:socks/udp".parse::<Remote>(), Err(Error::UdpSocks));
+        assert_eq!("R:5353:dns".parse::<Remote>(), Err(Error::DnsLocal));
+        assert_eq!("stdio:dns".parse::<Remote>(), Err(Error::DnsLocal));
+        assert_eq!("5353:dns/udp".parse::<Remote>(), Err(Error::UdpDns));
+        assert_eq!(
+            "stdio:tproxy".parse::<Remote>(),
+            Err(Error::TransparentLocal)
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            "R:12345:tproxy".parse::<Remote>(),
+            Err(Error::TransparentLocal)
+        );
+        assert_eq!("R:tun://tun0".parse::<Remote>(), Err(Error::ReverseTun));
+        assert_eq!("tun://".parse::<Remote>(), Err(Error::Format));
+        assert_eq!("tun://tun0/udp".parse::<Remote>(), Err(Error::Format));
+        assert_eq!(
+            "R:/tmp/app.sock:80".parse::<Remote>(),
+            Err(Error::ReverseUnix)
+        );
+        assert_eq!(
+            "/tmp/app.sock:53/udp".parse::<Remote>(),
+            Err(Error::UdpUnix)
+        );
+        assert_eq!("/tmp/app.sock".parse::<Remote>(), Err(Error::Format));
+        assert_eq!(
+            "R:2375:/var/run/docker.sock".parse::<Remote>(),
+            Err(Error::ReverseUnix)
+        );
+        assert_eq!("a:b:c:/x.sock".parse::<Remote>(), Err(Error::Format));
+        assert_eq!(
+            "/tmp/app.sock:socks".parse::<Remote>().map_err(|_| ()),
+            Err(())
+        );
+    }
+
+    #[test]
+    fn test_parse_remote_range() {
+        crate::tests::setup_logging();
+        let remotes = "5000-5002:example.com:6000-6002/udp"
+            .parse::<RemoteArg>()
+            .unwrap();
+        assert_eq!(
+            remotes,
+            RemoteArg(
+                [
+                    "5000:example.com:6000/udp",
+                    "5001:example.com:6001/udp",
+                    "5002:example.com:6002/udp"
+                ]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect()
+            )
+        );
+        let remotes = "R:[::1]:7000-7001:[::1]:7000-7001"
+            .parse::<RemoteArg>()
+            .unwrap();
+        assert_eq!(
+            remotes.0,
+            [
+                "R:[::1]:7000:[::1]:7000".parse().unwrap(),
+                "R:[::1]:7001:[::1]:7001".parse().unwrap()
+            ]
+        );
+        // One port on one side takes all the ports of the other
+        assert_eq!(
+            "8000-8009:localhost:80"
+                .parse::<RemoteArg>()
+                .unwrap()
+                .0
+                .len(),
+            10
+        );
+        assert_eq!("2000-2001".parse::<RemoteArg>().unwrap().0.len(), 2);
+        // Hosts with `-` are not ranges
+        assert_eq!(
+            "3000:1-2:80".parse::<RemoteArg>().unwrap(),
+            RemoteArg::from("3000:1-2:80".parse::<Remote>().unwrap())
+        );
+        // Only the ports are expanded
+        assert_eq!(
+            "5000-5001:1-2:80".parse::<RemoteArg>().unwrap().0,
+            [
+                "5000:1-2:80".parse().unwrap(),
+                "5001:1-2:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            "127.0.0.1:5000-5001:socks".parse::<RemoteArg>().unwrap().0,
+            [
+                "127.0.0.1:5000:socks".parse().unwrap(),
+                "127.0.0.1:5001:socks".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            "5000-5010:host:6000-6005".parse::<RemoteArg>(),
+            Err(Error::PortRange)
+        );
+        assert_eq!("5010-5000".parse::<RemoteArg>(), Err(Error::PortRange));
+        assert_eq!("stdio:socks".parse::<RemoteArg>().unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_remote_name() {
+        crate::tests::setup_logging();
+        let remote = "web=8080:example.com:80".parse::<Remote>().unwrap();
+        assert_eq!(remote.name.as_deref(), Some("web"));
+        assert_eq!(
+            Remote {
+                name: None,
+                ..remote.clone()
+            },
+            "8080:example.com:80".parse().unwrap()
+        );
+        assert!(remote.to_string().starts_with("web="));
+        assert_eq!(remote.to_string().parse::<Remote>().unwrap(), remote);
+        let remote = "ssh_2=R:2222:localhost:22".parse::<Remote>().unwrap();
+        assert_eq!(remote.name.as_deref(), Some("ssh_2"));
+        assert!(remote.reverse);
+        // Paths are not names
+        assert_eq!(
+            "/tmp/a=b.sock:80".parse::<Remote>().unwrap().local_addr,
+            LocalSpec::Unix("/tmp/a=b.sock".to_string())
+        );
+        assert_eq!("a=b=8080".parse::<Remote>(), Err(Error::Name));
+        // All the remotes of a range share the name
+        let remotes = "rtp=6000-6001:media:6000-6001/udp"
+            .parse::<RemoteArg>()
+            .unwrap();
+        assert_eq!(remotes.0.len(), 2);
+        assert!(
+            remotes
+                .0
+                .iter()
+                .all(|remote| remote.name.as_deref() == Some("rtp"))
+        );
+        assert_eq!(
+            "web=5000-5001:host:6000-6001"
+                .parse::<RemoteArg>()
+                .unwrap()
+                .0,
+            [
+                "web=5000:host:6000".parse().unwrap(),
+                "web=5001:host:6001".parse().unwrap()
+            ]
+        );
+        assert_eq!("web=@remotes.txt".parse::<RemoteArg>(), Err(Error::Name));
+    }
+
+    #[test]
+    fn test_parse_remote_file() {
+        crate::tests::setup_logging();
+        let content = "# Web servers
+            8080:web:80
+            8443:web:443 # TLS
+
+            6000-6001:rtp:6000-6001/udp
+            ";
+        let remotes = RemoteArg::parse_file("remotes.txt", content).unwrap();
+        assert_eq!(
+            remotes.0,
+            [
+                "8080:web:80".parse().unwrap(),
+                "8443:web:443".parse().unwrap(),
+                "6000:rtp:6000/udp".parse().unwrap(),
+                "6001:rtp:6001/udp".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            RemoteArg::parse_file("remotes.txt", "8080:web:80\nsocks/udp"),
+            Err(Error::FileLine(
+                "remotes.txt".to_string(),
+                2,
+                Box::new(Error::UdpSocks)
+            ))
+        );
+        assert!(matches!(
+            RemoteArg::parse_file("remotes.txt", "@other.txt"),
+            Err(Error::FileLine(_, 1, _))
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("remotes.txt");
+        std::fs::write(&path, content).unwrap();
+        let arg = format!("@{}", path.display());
+        assert_eq!(arg.parse::<RemoteArg>().unwrap(), remotes);
+        assert!(matches!(
+            "@/nonexistent/remotes.txt".parse::<RemoteArg>(),
+            Err(Error::ReadFile(..))
+        ));
     }
 }
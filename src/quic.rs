@@ -0,0 +1,705 @@
+//! Experimental QUIC transport (`wss+quic://`).
+//!
+//! The client opens a bidirectional stream, the control lane, and exchanges
+//! headers on it like the plain transport (see [`crate::raw_transport`]).
+//! Afterwards, [`QuicSocket`] carries the messages of the multiplexor: the
+//! frames of each flow go on one of [`LANES`] unidirectional streams picked
+//! by the flow ID, so that a lost packet only stalls the flows sharing its
+//! lane, and `Datagram` frames go in QUIC datagrams when they fit. Every
+//! other message, and all messages when the frames cannot be told apart
+//! (end-to-end encryption, padding or coalescing), goes on the control lane.
+//! On each lane, messages are framed like [`PlainStream`](penguin_mux::ws::PlainStream).
+//!
+//! Since the lanes are not ordered with each other, a closing end finishes
+//! its lanes first and then sends `Close` with the number of lanes it opened
+//! on the control lane. The other end only passes the `Close` on once it has
+//! read that many lanes to the end, so that no data is overtaken by it.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::task::AtomicWaker;
+use penguin_mux::Dupe;
+use penguin_mux::ws::{Message, WebSocket};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{
+    Connection, ConnectionError, Endpoint, ReadExactError, RecvStream, SendDatagramError,
+    SendStream, TransportConfig, VarInt,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+/// ALPN protocol of the transport
+pub const ALPN: &[u8] = b"penguin";
+
+/// Number of unidirectional lanes the flows are spread over
+pub const LANES: u32 = 32;
+
+/// Largest message on a lane
+const MAX_MESSAGE_SIZE: usize = 16 << 20;
+
+/// Bytes we queue for the lanes before `poll_ready` waits
+const MAX_QUEUED: usize = 4 << 20;
+
+/// Type octet and length
+const HEADER_LEN: usize = 5;
+
+/// How often to send QUIC keep-alives so that idle connections stay open
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// `Datagram` opcode of the multiplexor
+const OPCODE_DATAGRAM: u8 = 6;
+
+/// Opcodes from `Connect` to `CompressedPush`, which carry a flow ID
+const OPCODE_LAST_FLOW: u8 = 8;
+
+/// Transport settings of both ends
+fn transport_config() -> Arc<TransportConfig> {
+    let mut config = TransportConfig::default();
+    config
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_concurrent_uni_streams(VarInt::from_u32(LANES));
+    Arc::new(config)
+}
+
+/// Make the QUIC configuration of a client from its TLS configuration
+pub fn client_config(tls: &rustls::ClientConfig) -> io::Result<quinn::ClientConfig> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(tls)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Make the QUIC configuration of a server from its TLS configuration
+pub fn server_config(tls: &rustls::ServerConfig) -> io::Result<quinn::ServerConfig> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// Connect to `addr`, verifying the server as `tls_name`, from `bind_addr`
+/// if given. Returns the socket before the handshake.
+pub async fn connect(
+    config: quinn::ClientConfig,
+    addr: SocketAddr,
+    tls_name: &str,
+    bind_addr: Option<std::net::IpAddr>,
+) -> io::Result<QuicSocket> {
+    let bind_addr = bind_addr.unwrap_or(if addr.is_ipv4() {
+        std::net::Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        std::net::Ipv6Addr::UNSPECIFIED.into()
+    });
+    let mut endpoint = Endpoint::client(SocketAddr::new(bind_addr, 0))?;
+    endpoint.set_default_client_config(config);
+    let conn = endpoint
+        .connect(addr, tls_name)
+        .map_err(io::Error::other)?
+        .await?;
+    let (send, recv) = conn.open_bi().await?;
+    Ok(QuicSocket::new(conn, Some(endpoint), send, recv))
+}
+
+/// Accept the control lane of a connection to a server.
+/// Returns the socket before the handshake.
+pub async fn accept(conn: Connection) -> io::Result<QuicSocket> {
+    let (send, recv) = conn.accept_bi().await?;
+    Ok(QuicSocket::new(conn, None, send, recv))
+}
+
+/// What the readers of the lanes pass to the socket
+#[derive(Debug)]
+enum Incoming {
+    Message(Message),
+    /// The peer is closing after finishing this many lanes
+    Close(u32),
+    /// A lane of the peer was read to the end
+    LaneFinished,
+}
+
+/// Read a message from a lane. Returns `None` when the lane is finished.
+async fn read_message(recv: &mut RecvStream) -> io::Result<Option<Incoming>> {
+    let mut header = [0; HEADER_LEN];
+    match recv.read_exact(&mut header).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(ReadExactError::FinishedEarly(_)) => {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Err(ReadExactError::ReadError(err)) => return Err(err.into()),
+    }
+    let mut header = &header[..];
+    let kind = header.get_u8();
+    let len = header.get_u32() as usize;
+    // Only `Binary` and `Close` (with the number of lanes) have data
+    let valid_len = match kind {
+        0 => len <= MAX_MESSAGE_SIZE,
+        3 => len == 4,
+        _ => len == 0,
+    };
+    if !valid_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid QUIC lane message",
+        ));
+    }
+    let mut data = vec![0; len];
+    recv.read_exact(&mut data).await.map_err(|err| match err {
+        ReadExactError::FinishedEarly(_) => io::ErrorKind::UnexpectedEof.into(),
+        ReadExactError::ReadError(err) => io::Error::from(err),
+    })?;
+    Ok(Some(match kind {
+        0 => Incoming::Message(Message::Binary(data.into())),
+        1 => Incoming::Message(Message::Ping),
+        2 => Incoming::Message(Message::Pong),
+        3 => Incoming::Close(data.as_slice().get_u32()),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid QUIC lane message type",
+            ));
+        }
+    }))
+}
+
+/// Write a message on a lane. `Close` carries the number of `lanes` we opened.
+async fn write_message(send: &mut SendStream, message: &Message, lanes: u32) -> io::Result<()> {
+    let (kind, data) = match message {
+        Message::Binary(data) => (0, data.dupe()),
+        Message::Ping => (1, Bytes::new()),
+        Message::Pong => (2, Bytes::new()),
+        Message::Close => (3, Bytes::copy_from_slice(&lanes.to_be_bytes())),
+    };
+    let mut header = BytesMut::with_capacity(HEADER_LEN);
+    header.put_u8(kind);
+    header.put_u32(u32::try_from(data.len()).map_err(|_| io::ErrorKind::InvalidInput)?);
+    send.write_all_chunks(&mut [header.freeze(), data])
+        .await
+        .map_err(io::Error::from)
+}
+
+/// Where to send a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Route {
+    Control,
+    Lane(usize),
+    Datagram,
+}
+
+/// Pick the route of a message of the multiplexor
+fn route(message: &Message, split_flows: bool) -> Route {
+    let Message::Binary(data) = message else {
+        return Route::Control;
+    };
+    if !split_flows || data.len() < HEADER_LEN {
+        return Route::Control;
+    }
+    match data[0] & 0x0f {
+        OPCODE_DATAGRAM => Route::Datagram,
+        opcode if opcode <= OPCODE_LAST_FLOW => {
+            let flow_id = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+            Route::Lane((flow_id % LANES) as usize)
+        }
+        _ => Route::Control,
+    }
+}
+
+/// Bytes queued for the lanes and the number of lanes opened, shared with
+/// their writers
+#[derive(Debug, Default)]
+struct Queued {
+    bytes: AtomicUsize,
+    waker: AtomicWaker,
+    lanes: AtomicU32,
+}
+
+impl Queued {
+    /// Account for `message` having been written or dropped
+    fn done(&self, message: &Message) {
+        self.bytes.fetch_sub(queued_len(message), Ordering::AcqRel);
+        self.waker.wake();
+    }
+
+    /// Poll until `bytes` is below `limit`
+    fn poll_below(&self, limit: usize, cx: &mut Context<'_>) -> Poll<()> {
+        if self.bytes.load(Ordering::Acquire) < limit {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // Check again in case a writer finished before we registered
+        if self.bytes.load(Ordering::Acquire) < limit {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Size of a message in [`Queued`]. Every message counts so that flushing
+/// waits for all of them.
+const fn queued_len(message: &Message) -> usize {
+    match message {
+        Message::Binary(data) => HEADER_LEN + data.len(),
+        _ => HEADER_LEN,
+    }
+}
+
+/// Write the messages of a lane until it is closed
+async fn write_lane(
+    mut send: SendStream,
+    mut rx: mpsc::UnboundedReceiver<Message>,
+    queued: Arc<Queued>,
+) {
+    while let Some(message) = rx.recv().await {
+        let lanes = queued.lanes.load(Ordering::Acquire);
+        let result = write_message(&mut send, &message, lanes).await;
+        queued.done(&message);
+        if let Err(err) = result {
+            debug!("Failed to write to a QUIC lane: {err}");
+            discard_lane(rx, &queued);
+            return;
+        }
+    }
+    // Wait for the peer to receive everything before we close the connection
+    if send.finish().is_ok() {
+        send.stopped().await.ok();
+    }
+}
+
+/// Drop the messages queued for a lane that cannot be written
+fn discard_lane(mut rx: mpsc::UnboundedReceiver<Message>, queued: &Queued) {
+    rx.close();
+    while let Ok(message) = rx.try_recv() {
+        queued.done(&message);
+    }
+}
+
+/// Pass the messages of a lane to the socket.
+/// Returns whether the lane was read to the end.
+async fn read_lane(
+    conn: Connection,
+    mut recv: RecvStream,
+    tx: &mpsc::Sender<io::Result<Incoming>>,
+) -> bool {
+    loop {
+        let message = match read_message(&mut recv).await {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => return true,
+            // A closed connection ends the socket without an error
+            Err(_) if closed_normally(&conn) => return false,
+            Err(err) => Err(err),
+        };
+        let failed = message.is_err();
+        if tx.send(message).await.is_err() || failed {
+            return false;
+        }
+    }
+}
+
+/// Whether the connection was closed by either end on purpose
+fn closed_normally(conn: &Connection) -> bool {
+    matches!(
+        conn.close_reason(),
+        Some(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed)
+    )
+}
+
+/// Accept the lanes of the peer and receive its datagrams
+async fn accept_lanes(conn: Connection, tx: mpsc::Sender<io::Result<Incoming>>) {
+    let datagrams = {
+        let conn = conn.clone();
+        let tx = tx.clone();
+        async move {
+            while let Ok(data) = conn.read_datagram().await {
+                if tx
+                    .send(Ok(Incoming::Message(Message::Binary(data))))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    };
+    let lanes = async move {
+        loop {
+            match conn.accept_uni().await {
+                Ok(recv) => {
+                    let conn = conn.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        if read_lane(conn, recv, &tx).await {
+                            tx.send(Ok(Incoming::LaneFinished)).await.ok();
+                        }
+                    });
+                }
+                Err(_) if closed_normally(&conn) => return,
+                Err(err) => {
+                    tx.send(Err(err.into())).await.ok();
+                    return;
+                }
+            }
+        }
+    };
+    tokio::join!(datagrams, lanes);
+}
+
+fn ws_error(err: io::Error) -> penguin_mux::Error {
+    penguin_mux::Error::WebSocket(Box::new(err))
+}
+
+/// A [`WebSocket`] over a QUIC connection
+#[derive(Debug)]
+pub struct QuicSocket {
+    conn: Connection,
+    /// Keeps the endpoint of a client open
+    _endpoint: Option<Endpoint>,
+    /// Sender of the control lane, `None` once closing
+    control: Option<mpsc::UnboundedSender<Message>>,
+    /// Senders of the flow lanes, opened on first use
+    lanes: Vec<Option<mpsc::UnboundedSender<Message>>>,
+    /// Whether to spread the flows over the lanes
+    split_flows: bool,
+    queued: Arc<Queued>,
+    /// Writer of the control lane
+    control_writer: JoinSet<()>,
+    /// Writers of the flow lanes
+    writers: JoinSet<()>,
+    /// Handed to [`accept_lanes`] by [`start_lanes`](Self::start_lanes)
+    incoming_tx: Option<mpsc::Sender<io::Result<Incoming>>>,
+    incoming: mpsc::Receiver<io::Result<Incoming>>,
+    /// Number of lanes of the peer read to the end
+    peer_lanes_finished: u32,
+    /// Number of lanes the peer finished before it sent `Close`
+    peer_closing: Option<u32>,
+}
+
+impl QuicSocket {
+    fn new(
+        conn: Connection,
+        endpoint: Option<Endpoint>,
+        send: SendStream,
+        recv: RecvStream,
+    ) -> Self {
+        let queued = Arc::new(Queued::default());
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let mut control_writer = JoinSet::new();
+        control_writer.spawn(write_lane(send, control_rx, queued.dupe()));
+        let (incoming_tx, incoming) = mpsc::channel(256);
+        let control_tx = incoming_tx.clone();
+        let control_conn = conn.clone();
+        tokio::spawn(async move { read_lane(control_conn, recv, &control_tx).await });
+        Self {
+            conn,
+            _endpoint: endpoint,
+            control: Some(control),
+            lanes: vec![None; LANES as usize],
+            split_flows: false,
+            queued,
+            control_writer,
+            writers: JoinSet::new(),
+            incoming_tx: Some(incoming_tx),
+            incoming,
+            peer_lanes_finished: 0,
+            peer_closing: None,
+        }
+    }
+
+    /// Start receiving on the lanes of the peer once the handshake on the
+    /// control lane is done, and spread our flows over our lanes if
+    /// `split_flows`. Until then, everything goes on the control lane so
+    /// that nothing overtakes the headers.
+    pub fn start_lanes(&mut self, split_flows: bool) {
+        self.split_flows = split_flows;
+        if let Some(tx) = self.incoming_tx.take() {
+            tokio::spawn(accept_lanes(self.conn.clone(), tx));
+        }
+    }
+
+    /// Queue `message` on a lane, opening it if needed
+    fn send_on(&mut self, route: Route, message: Message) -> Result<(), penguin_mux::Error> {
+        let len = queued_len(&message);
+        let sender = match route {
+            Route::Lane(index) => {
+                let lane = &mut self.lanes[index];
+                if lane.is_none() {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    let conn = self.conn.clone();
+                    let queued = self.queued.dupe();
+                    self.writers.spawn(async move {
+                        match conn.open_uni().await {
+                            Ok(send) => {
+                                queued.lanes.fetch_add(1, Ordering::AcqRel);
+                                write_lane(send, rx, queued).await;
+                            }
+                            Err(err) => {
+                                debug!("Failed to open a QUIC lane: {err}");
+                                discard_lane(rx, &queued);
+                            }
+                        }
+                    });
+                    *lane = Some(tx);
+                }
+                lane.as_ref()
+            }
+            Route::Control | Route::Datagram => self.control.as_ref(),
+        };
+        let sender = sender.ok_or(penguin_mux::Error::Closed)?;
+        self.queued.bytes.fetch_add(len, Ordering::AcqRel);
+        sender.send(message).map_err(|err| {
+            self.queued.done(&err.0);
+            ws_error(io::ErrorKind::BrokenPipe.into())
+        })
+    }
+}
+
+impl WebSocket for QuicSocket {
+    fn poll_ready_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), penguin_mux::Error>> {
+        if self.control.is_none() {
+            return Poll::Ready(Err(penguin_mux::Error::Closed));
+        }
+        self.queued.poll_below(MAX_QUEUED, cx).map(Ok)
+    }
+
+    fn start_send_unpin(&mut self, item: Message) -> Result<(), penguin_mux::Error> {
+        let route = route(&item, self.split_flows);
+        if route == Route::Datagram {
+            let Message::Binary(data) = &item else {
+                unreachable!("Only binary messages are datagrams");
+            };
+            match self.conn.send_datagram(data.dupe()) {
+                Ok(()) => return Ok(()),
+                Err(SendDatagramError::ConnectionLost(err)) => return Err(ws_error(err.into())),
+                // Too large or not supported; the control lane always works
+                Err(_) => {}
+            }
+        }
+        self.send_on(route, item)
+    }
+
+    fn poll_flush_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), penguin_mux::Error>> {
+        // Wait until the writers of all lanes have handed everything to QUIC
+        self.queued.poll_below(1, cx).map(Ok)
+    }
+
+    fn poll_close_unpin(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), penguin_mux::Error>> {
+        // Finish the flow lanes before sending `Close`, which tells the peer
+        // how many of them to read to the end first
+        self.lanes.fill(None);
+        while ready!(self.writers.poll_join_next(cx)).is_some() {}
+        if self.control.is_some() {
+            self.send_on(Route::Control, Message::Close).ok();
+            self.control = None;
+        }
+        while ready!(self.control_writer.poll_join_next(cx)).is_some() {}
+        self.conn.close(VarInt::from_u32(0), b"");
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_next_unpin(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Message, penguin_mux::Error>>> {
+        loop {
+            if self
+                .peer_closing
+                .is_some_and(|lanes| self.peer_lanes_finished >= lanes)
+            {
+                self.peer_closing = None;
+                return Poll::Ready(Some(Ok(Message::Close)));
+            }
+            match ready!(self.incoming.poll_recv(cx)) {
+                Some(Ok(Incoming::Message(Message::Ping))) => {
+                    // Answer like a `WebSocket` implementation would
+                    self.send_on(Route::Control, Message::Pong).ok();
+                    return Poll::Ready(Some(Ok(Message::Ping)));
+                }
+                Some(Ok(Incoming::Message(message))) => return Poll::Ready(Some(Ok(message))),
+                // Lanes of the peer are only read after `start_lanes`
+                Some(Ok(Incoming::Close(lanes))) if self.incoming_tx.is_none() => {
+                    self.peer_closing = Some(lanes);
+                }
+                Some(Ok(Incoming::Close(_))) => self.peer_closing = Some(0),
+                Some(Ok(Incoming::LaneFinished)) => self.peer_lanes_finished += 1,
+                Some(Err(err)) => return Poll::Ready(Some(Err(ws_error(err)))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Drop for QuicSocket {
+    fn drop(&mut self) {
+        // Nothing else owns the connection for long
+        self.conn.close(VarInt::from_u32(0), b"");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_transport::{recv_headers, send_headers};
+    use http::{HeaderMap, HeaderValue};
+    use std::future::poll_fn;
+
+    fn frame(opcode: u8, flow_id: u32) -> Message {
+        let mut data = BytesMut::new();
+        data.put_u8(opcode | 0x10);
+        data.put_u32(flow_id);
+        data.put_slice(b"data");
+        Message::Binary(data.freeze())
+    }
+
+    #[test]
+    fn test_route() {
+        crate::tests::setup_logging();
+        assert_eq!(route(&frame(4, 1), true), Route::Lane(1));
+        assert_eq!(route(&frame(0, 33), true), Route::Lane(1));
+        assert_eq!(route(&frame(6, 1), true), Route::Datagram);
+        // `Padded` and `Batch`
+        assert_eq!(route(&frame(9, 1), true), Route::Control);
+        assert_eq!(route(&frame(10, 1), true), Route::Control);
+        assert_eq!(route(&frame(4, 1), false), Route::Control);
+        assert_eq!(route(&Message::Ping, true), Route::Control);
+        assert_eq!(
+            route(&Message::Binary(Bytes::from_static(b"x")), true),
+            Route::Control
+        );
+    }
+
+    async fn send(ws: &mut QuicSocket, message: Message) {
+        poll_fn(|cx| ws.poll_ready_unpin(cx)).await.unwrap();
+        ws.start_send_unpin(message).unwrap();
+        poll_fn(|cx| ws.poll_flush_unpin(cx)).await.unwrap();
+    }
+
+    async fn recv(ws: &mut QuicSocket) -> Option<Message> {
+        poll_fn(|cx| ws.poll_next_unpin(cx))
+            .await
+            .map(Result::unwrap)
+    }
+
+    /// A server endpoint and the configuration of a client trusting it
+    fn make_endpoints() -> (Endpoint, quinn::ClientConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key =
+            rustls::pki_types::PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap();
+        let server_tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.cert.der().clone()], key)
+            .unwrap();
+        let server = Endpoint::server(
+            server_config(&server_tls).unwrap(),
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+        )
+        .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let client_tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (server, client_config(&client_tls).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_quic_socket() {
+        crate::tests::setup_logging();
+        let (server, client_config) = make_endpoints();
+        let addr = server.local_addr().unwrap();
+        let server_task = tokio::spawn(async move {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let mut ws = accept(conn).await.unwrap();
+            let headers = recv_headers(&mut ws).await.unwrap().unwrap();
+            send_headers(&mut ws, &headers).await.unwrap();
+            ws.start_lanes(true);
+            // Echo until the client closes
+            while let Some(message) = recv(&mut ws).await {
+                match message {
+                    Message::Close => break,
+                    Message::Ping => {}
+                    message => send(&mut ws, message).await,
+                }
+            }
+            poll_fn(|cx| ws.poll_close_unpin(cx)).await.unwrap();
+        });
+        let mut ws = connect(client_config, addr, "localhost", None)
+            .await
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-penguin-test", HeaderValue::from_static("1"));
+        send_headers(&mut ws, &headers).await.unwrap();
+        assert_eq!(recv_headers(&mut ws).await.unwrap().unwrap(), headers);
+        ws.start_lanes(true);
+        for flow_id in 0..64 {
+            send(&mut ws, frame(4, flow_id)).await;
+        }
+        let mut received = Vec::new();
+        for _ in 0..64 {
+            received.push(recv(&mut ws).await.unwrap());
+        }
+        for flow_id in 0..64 {
+            assert!(received.contains(&frame(4, flow_id)));
+        }
+        // Datagrams that fit go unreliably, but nothing is lost here
+        send(&mut ws, frame(6, 1)).await;
+        assert_eq!(recv(&mut ws).await.unwrap(), frame(6, 1));
+        send(&mut ws, Message::Ping).await;
+        assert_eq!(recv(&mut ws).await.unwrap(), Message::Pong);
+        poll_fn(|cx| ws.poll_close_unpin(cx)).await.unwrap();
+        server_task.await.unwrap();
+    }
+    #[tokio::test]
+    async fn test_close_after_large_payload() {
+        crate::tests::setup_logging();
+        let (server, client_config) = make_endpoints();
+        let addr = server.local_addr().unwrap();
+        // Larger than `MAX_QUEUED`, over all lanes
+        let payload = Bytes::from(vec![0x42; 256 << 10]);
+        let count = 64;
+        let server_task = tokio::spawn(async move {
+            let conn = server.accept().await.unwrap().await.unwrap();
+            let mut ws = accept(conn).await.unwrap();
+            ws.start_lanes(true);
+            let mut received = 0;
+            loop {
+                match recv(&mut ws).await.unwrap() {
+                    Message::Binary(data) => received += data.len(),
+                    Message::Close => break,
+                    message => panic!("unexpected message {message:?}"),
+                }
+            }
+            poll_fn(|cx| ws.poll_close_unpin(cx)).await.unwrap();
+            received
+        });
+        let mut ws = connect(client_config, addr, "localhost", None)
+            .await
+            .unwrap();
+        ws.start_lanes(true);
+        let mut sent = 0;
+        for flow_id in 0..count {
+            let mut data = BytesMut::new();
+            data.put_u8(0x14);
+            data.put_u32(flow_id);
+            data.put_slice(&payload);
+            sent += data.len();
+            poll_fn(|cx| ws.poll_ready_unpin(cx)).await.unwrap();
+            ws.start_send_unpin(Message::Binary(data.freeze())).unwrap();
+        }
+        poll_fn(|cx| ws.poll_close_unpin(cx)).await.unwrap();
+        assert_eq!(server_task.await.unwrap(), sent);
+    }
+}
@@ -0,0 +1,104 @@
+//! Handshake of the plain TLS/TCP transport.
+//!
+//! With `tls://` or `tcp://` server URLs, the multiplexor runs on a
+//! [`PlainStream`](penguin_mux::ws::PlainStream) instead of a `WebSocket`.
+//! The client first sends a binary message with the headers it would put in
+//! the upgrade request, one `name: value` per line. If the server accepts
+//! them, it replies with its headers in the same form; otherwise it closes
+//! the connection. The protocol versions are negotiated like in
+//! `sec-websocket-protocol`: the client lists the versions it supports in
+//! [`PROTOCOL_HEADER`], and the server replies with the one it picked.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{HeaderMap, HeaderName, HeaderValue};
+use penguin_mux::ws::{Message, WebSocket};
+use std::future::poll_fn;
+
+/// Header carrying the protocol versions the client supports in requests
+/// and the one the server picked in replies
+pub const PROTOCOL_HEADER: &str = "x-penguin-protocol";
+
+/// Encode headers into the payload of a message
+pub fn encode_headers(headers: &HeaderMap) -> Bytes {
+    let mut buf = BytesMut::new();
+    for (name, value) in headers {
+        buf.put_slice(name.as_str().as_bytes());
+        buf.put_slice(b": ");
+        buf.put_slice(value.as_bytes());
+        buf.put_u8(b'\n');
+    }
+    buf.freeze()
+}
+
+/// Decode the payload of a message into headers
+pub fn decode_headers(data: &[u8]) -> Option<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for line in data.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+        let colon = line.iter().position(|&b| b == b':')?;
+        let name = HeaderName::from_bytes(&line[..colon]).ok()?;
+        let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii()).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
+
+/// Send headers as a message
+pub async fn send_headers<S: WebSocket>(
+    ws: &mut S,
+    headers: &HeaderMap,
+) -> Result<(), penguin_mux::Error> {
+    poll_fn(|cx| ws.poll_ready_unpin(cx)).await?;
+    ws.start_send_unpin(Message::Binary(encode_headers(headers)))?;
+    poll_fn(|cx| ws.poll_flush_unpin(cx)).await
+}
+
+/// Receive headers sent with [`send_headers`].
+/// Returns `None` if the connection closes or the message is not headers.
+pub async fn recv_headers<S: WebSocket>(
+    ws: &mut S,
+) -> Result<Option<HeaderMap>, penguin_mux::Error> {
+    match poll_fn(|cx| ws.poll_next_unpin(cx)).await.transpose()? {
+        Some(Message::Binary(data)) => Ok(decode_headers(&data)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use penguin_mux::ws::PlainStream;
+
+    #[test]
+    fn test_encode_decode_headers() {
+        crate::tests::setup_logging();
+        let mut headers = HeaderMap::new();
+        headers.insert(PROTOCOL_HEADER, HeaderValue::from_static("penguin-v7"));
+        headers.insert(
+            "authorization",
+            HeaderValue::from_static("Basic Zm9vOmJhcg=="),
+        );
+        headers.append("x-custom", HeaderValue::from_static("a: b"));
+        headers.append("x-custom", HeaderValue::from_static("c"));
+        let decoded = decode_headers(&encode_headers(&headers)).unwrap();
+        assert_eq!(decoded, headers);
+        assert!(decode_headers(b"").unwrap().is_empty());
+        assert!(decode_headers(b"no colon\n").is_none());
+        assert!(decode_headers(b"bad name: value\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_recv_headers() {
+        crate::tests::setup_logging();
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = PlainStream::new(client);
+        let mut server = PlainStream::new(server);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-penguin-psk", HeaderValue::from_static("secret"));
+        send_headers(&mut client, &headers).await.unwrap();
+        assert_eq!(recv_headers(&mut server).await.unwrap(), Some(headers));
+        drop(client);
+        assert_eq!(recv_headers(&mut server).await.unwrap(), None);
+    }
+}
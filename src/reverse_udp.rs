@@ -0,0 +1,15 @@
+//! Reverse UDP remotes.
+//!
+//! For an `R:…/udp` remote, the client asks the server to bind a UDP socket
+//! with a `Bind` request of the datagram type. Each peer that sends to the
+//! socket gets a flow ID chosen by the server, and its datagrams reach the
+//! client with the host and port of the `Bind` request, so that the client
+//! can tell which reverse remote they belong to. The client forwards them to
+//! the target of the remote from a socket of its own for each flow, and sends
+//! the replies back with the same flow ID to the host `penguin-reverse-udp`,
+//! which the server relays to the peer.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+/// Host of the datagrams that answer a peer of a reverse UDP remote
+pub const REVERSE_UDP_HOST: &[u8] = b"penguin-reverse-udp";
@@ -0,0 +1,358 @@
+//! Access log of the HTTP requests the server answers, kept apart from the
+//! tracing output.
+//!
+//! Every request gets one line when its response starts: requests served by
+//! the backend, static files or the 404 handler, and WebSocket upgrades,
+//! including the refused ones with the reason.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::service::ResponseBody;
+use crate::arg::AccessLogFormat;
+use http::{HeaderValue, Method, Response, Version, header};
+use hyper::body::Body;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Why we refused to upgrade a request, attached to its response
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Rejected(pub &'static str);
+
+/// Name of the user a WebSocket was upgraded for, attached to the response
+#[derive(Clone, Debug)]
+pub(super) struct User(pub String);
+
+/// Where and how to write the access log
+pub(super) struct AccessLog {
+    format: AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AccessLog {
+    /// Append to the file at `path`, or write to stdout if it is `-`
+    pub fn open(path: &Path, format: AccessLogFormat) -> io::Result<Self> {
+        Ok(Self::new(open_log_file(path)?, format))
+    }
+
+    /// Write to `out`
+    pub fn new(out: Box<dyn Write + Send>, format: AccessLogFormat) -> Self {
+        Self {
+            format,
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Log the response to `request`
+    pub fn log(&self, request: &Request, resp: &Response<ResponseBody>) {
+        let size = resp.body().size_hint().exact().or_else(|| {
+            resp.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+        });
+        let entry = Entry {
+            time: request.time,
+            peer: request.peer,
+            method: request.method.as_str(),
+            target: &request.target,
+            version: version_str(request.version),
+            status: resp.status().as_u16(),
+            size,
+            referer: request
+                .referer
+                .as_ref()
+                .map(|value| String::from_utf8_lossy(value.as_bytes())),
+            user_agent: request
+                .user_agent
+                .as_ref()
+                .map(|value| String::from_utf8_lossy(value.as_bytes())),
+            user: resp.extensions().get::<User>().map(|user| user.0.as_str()),
+            rejected: resp
+                .extensions()
+                .get::<Rejected>()
+                .map(|rejected| rejected.0),
+        };
+        let mut line = match self.format {
+            AccessLogFormat::Combined => entry.combined(),
+            // `expect`: the entry has only strings and numbers
+            AccessLogFormat::Json => {
+                serde_json::to_string(&entry).expect("Cannot serialize access log (this is a bug)")
+            }
+        };
+        line.push('\n');
+        if let Err(err) = self.out.lock().write_all(line.as_bytes()) {
+            warn!("Cannot write the access log: {err}");
+        }
+    }
+}
+
+/// Open the file at `path` for appending, or stdout if it is `-`
+pub(super) fn open_log_file(path: &Path) -> io::Result<Box<dyn Write + Send>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdout()));
+    }
+    Ok(Box::new(
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?,
+    ))
+}
+
+/// What we need to log about a request once its response starts
+#[derive(Debug)]
+pub(super) struct Request {
+    time: SystemTime,
+    peer: Option<SocketAddr>,
+    method: Method,
+    target: String,
+    version: Version,
+    referer: Option<HeaderValue>,
+    user_agent: Option<HeaderValue>,
+}
+
+impl Request {
+    /// Remember `req` from `peer`
+    pub fn new<B>(req: &http::Request<B>, peer: Option<SocketAddr>) -> Self {
+        let headers = req.headers();
+        Self {
+            time: SystemTime::now(),
+            peer,
+            method: req.method().clone(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or("/", http::uri::PathAndQuery::as_str)
+                .to_string(),
+            version: req.version(),
+            referer: headers.get(header::REFERER).cloned(),
+            user_agent: headers.get(header::USER_AGENT).cloned(),
+        }
+    }
+}
+
+/// One line of the access log
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    #[serde(serialize_with = "serialize_time")]
+    time: SystemTime,
+    peer: Option<SocketAddr>,
+    method: &'a str,
+    target: &'a str,
+    version: &'static str,
+    status: u16,
+    size: Option<u64>,
+    referer: Option<Cow<'a, str>>,
+    user_agent: Option<Cow<'a, str>>,
+    user: Option<&'a str>,
+    rejected: Option<&'static str>,
+}
+
+impl Entry<'_> {
+    /// The entry in the Combined Log Format, with the reason of a refused
+    /// upgrade as an extra field at the end
+    fn combined(&self) -> String {
+        let (year, month, day, hour, minute, second) = civil_time(self.time);
+        let mut line = format!(
+            "{} - {} [{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            self.peer
+                .map_or_else(|| "-".to_string(), |peer| peer.ip().to_string()),
+            self.user
+                .map_or_else(|| "-".to_string(), |user| escape(user.as_bytes())),
+            MONTHS[month - 1],
+            self.method,
+            escape(self.target.as_bytes()),
+            self.version,
+            self.status,
+            self.size
+                .map_or_else(|| "-".to_string(), |size| size.to_string()),
+            self.referer
+                .as_deref()
+                .map_or_else(|| "-".to_string(), |v| escape(v.as_bytes())),
+            self.user_agent
+                .as_deref()
+                .map_or_else(|| "-".to_string(), |v| escape(v.as_bytes())),
+        );
+        if let Some(rejected) = self.rejected {
+            // `write!` to a `String` cannot fail
+            write!(line, " \"{rejected}\"").ok();
+        }
+        line
+    }
+}
+
+/// Write `time` like `2024-05-01T12:34:56Z`
+pub(super) fn serialize_time<S: serde::Serializer>(
+    time: &SystemTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let (year, month, day, hour, minute, second) = civil_time(*time);
+    serializer.collect_str(&format_args!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z"
+    ))
+}
+
+/// How HTTP versions are written in request lines
+const fn version_str(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "HTTP/0.9",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_2 => "HTTP/2.0",
+        Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+/// Escape quotes, backslashes and unprintable octets like Apache does so
+/// that clients cannot forge log fields
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &byte in value {
+        match byte {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7e => escaped.push(char::from(byte)),
+            // `write!` to a `String` cannot fail
+            _ => {
+                write!(escaped, "\\x{byte:02x}").ok();
+            }
+        }
+    }
+    escaped
+}
+
+/// `(year, month, day, hour, minute, second)` of `time` in UTC
+fn civil_time(time: SystemTime) -> (u64, usize, u64, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's `civil_from_days`, with March as the first month
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    // `expect`: `month` is between 1 and 12
+    let month = usize::try_from(month).expect("Invalid month (this is a bug)");
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// A `Write` whose output the test can look at
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn request() -> Request {
+        let req = http::Request::builder()
+            .uri("/index.html?q=x")
+            .header(header::USER_AGENT, "say \"hi\"")
+            .body(())
+            .unwrap();
+        let mut request = Request::new(&req, Some("192.0.2.1:1234".parse().unwrap()));
+        // 2000-10-10T13:55:36Z
+        request.time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        request
+    }
+
+    #[test]
+    fn test_civil_time() {
+        crate::tests::setup_logging();
+        assert_eq!(civil_time(UNIX_EPOCH), (1970, 1, 1, 0, 0, 0));
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_164_800 + 3661);
+        assert_eq!(civil_time(leap_day), (2024, 2, 29, 1, 1, 1));
+    }
+
+    #[test]
+    fn test_access_log_combined() {
+        crate::tests::setup_logging();
+        let buffer = Buffer::default();
+        let log = AccessLog::new(Box::new(buffer.clone()), AccessLogFormat::Combined);
+        let resp = Response::new(super::super::service::full_body(Bytes::from_static(
+            b"hello",
+        )));
+        log.log(&request(), &resp);
+        let mut resp = Response::new(super::super::service::full_body(Bytes::new()));
+        *resp.status_mut() = http::StatusCode::NOT_FOUND;
+        resp.extensions_mut().insert(Rejected("invalid PSK"));
+        log.log(&request(), &resp);
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        assert_eq!(
+            output,
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html?q=x HTTP/1.1\" 200 5 \"-\" \"say \\\"hi\\\"\"\n\
+             192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html?q=x HTTP/1.1\" 404 0 \"-\" \"say \\\"hi\\\"\" \"invalid PSK\"\n"
+        );
+    }
+
+    #[test]
+    fn test_access_log_json() {
+        crate::tests::setup_logging();
+        let buffer = Buffer::default();
+        let log = AccessLog::new(Box::new(buffer.clone()), AccessLogFormat::Json);
+        let mut resp = Response::new(super::super::service::full_body(Bytes::new()));
+        *resp.status_mut() = http::StatusCode::SWITCHING_PROTOCOLS;
+        resp.extensions_mut().insert(User("alice".to_string()));
+        log.log(&request(), &resp);
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            entry,
+            serde_json::json!({
+                "time": "2000-10-10T13:55:36Z",
+                "peer": "192.0.2.1:1234",
+                "method": "GET",
+                "target": "/index.html?q=x",
+                "version": "HTTP/1.1",
+                "status": 101,
+                "size": 0,
+                "referer": null,
+                "user_agent": "say \"hi\"",
+                "user": "alice",
+                "rejected": null,
+            })
+        );
+    }
+}
@@ -8,7 +8,7 @@ use challenge_helper::Action;
 use instant_acme::{Account, AuthorizationStatus, Identifier, NewAccount, NewOrder, OrderStatus};
 use penguin_mux::{Dupe, timing::Backoff};
 use rcgen::{CertificateParams, DistinguishedName, KeyPair};
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 use tracing::{debug, error, info};
 
 pub use challenge_helper::ChallengeHelper;
@@ -40,9 +40,8 @@ pub enum Error {
 /// Simple ACME Client
 pub struct Client {
     account: Account,
-    challenge_helper: &'static ChallengeHelper,
-    domain_names: &'static [String],
-    tls_ca: Option<&'static str>,
+    /// The domain names, challenge helper and TLS options to renew with
+    server_args: Arc<ServerArgs>,
     tls_config: TlsIdentity,
 }
 
@@ -53,13 +52,8 @@ impl std::fmt::Debug for Client {
 }
 
 impl Client {
-    pub async fn populate_or_get(server_args: &'static ServerArgs) -> Result<&'static Self, Error> {
-        // `expect`: challenge helper verified by `clap`
-        let helper = server_args
-            .tls_acme_challenge_helper
-            .as_ref()
-            .expect("Challenge helper missing (this is a bug)");
-
+    pub async fn populate_or_get(server_args: Arc<ServerArgs>) -> Result<&'static Self, Error> {
+        let helper = challenge_helper(&server_args);
         if let Some(client) = ACME_CLIENT.get() {
             return Ok(client);
         }
@@ -84,17 +78,17 @@ impl Client {
         .await?;
         let (keypair, cert) = issue(&account, helper, &server_args.tls_domain).await?;
 
+        let tls_config = make_tls_identity_from_rcgen_pem(
+            cert,
+            keypair,
+            server_args.client_ca(),
+            &server_args.tls,
+        )
+        .await?;
         let client = Self {
             account,
-            challenge_helper: helper,
-            domain_names: &server_args.tls_domain,
-            tls_ca: server_args.tls_ca.as_deref(),
-            tls_config: make_tls_identity_from_rcgen_pem(
-                cert,
-                keypair,
-                server_args.tls_ca.as_deref(), // Optional client CA path
-            )
-            .await?,
+            server_args,
+            tls_config,
         };
         ACME_CLIENT
             .set(client)
@@ -107,7 +101,7 @@ impl Client {
     pub fn get_tls_config_spawn_renewal(&'static self) -> TlsIdentity {
         tokio::spawn(async move {
             // Hard-coding a renewal interval of 30 days
-            let interval = std::time::Duration::from_secs(30 * 24 * 60 * 60); // 30 days
+            let interval = std::time::Duration::from_hours(720); // 30 days
             let mut interval = tokio::time::interval(interval);
             interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
             // Skip the first tick so that we don't immediately renew
@@ -115,14 +109,16 @@ impl Client {
             loop {
                 interval.tick().await;
                 info!("Renewing ACME certificate...");
-                match issue(&self.account, self.challenge_helper, self.domain_names).await {
+                let args = &self.server_args;
+                match issue(&self.account, challenge_helper(args), &args.tls_domain).await {
                     Ok((keypair, cert)) => {
                         info!("Certificate renewed successfully.");
                         reload_tls_identity_from_rcgen_pem(
                             &self.tls_config,
                             cert,
                             keypair,
-                            self.tls_ca,
+                            args.client_ca(),
+                            &args.tls,
                         )
                         .await
                         .unwrap_or_else(|e| {
@@ -139,6 +135,14 @@ impl Client {
     }
 }
 
+/// The `--tls-acme-challenge-helper` of `args`
+fn challenge_helper(args: &ServerArgs) -> &ChallengeHelper {
+    // `expect`: challenge helper verified by `clap`
+    args.tls_acme_challenge_helper
+        .as_ref()
+        .expect("Challenge helper missing (this is a bug)")
+}
+
 /// Issues a new certificate using the ACME protocol.
 /// Returns the private key and the certificate chain in PEM format.
 async fn issue(
@@ -161,7 +165,7 @@ async fn issue(
     // Back off until the order becomes ready or invalid
     let mut backoff = Backoff::new(
         std::time::Duration::from_secs(5),
-        std::time::Duration::from_secs(60),
+        std::time::Duration::from_mins(1),
         2,
         MAX_ORDER_RETRIES,
     );
@@ -236,10 +240,17 @@ mod tests_need_pebble {
     impl IgnoreTlsHttpClient {
         #[cfg(feature = "__rustls")]
         pub async fn new() -> Self {
-            let mut client_config =
-                make_client_config(None, None, None, true, Some(&crate::tls::TLS_ALPN))
-                    .await
-                    .expect("Failed to create client config");
+            let mut client_config = make_client_config(
+                None,
+                None,
+                None,
+                true,
+                Some(&crate::tls::TLS_ALPN),
+                &TlsArgs::default(),
+                &[],
+            )
+            .await
+            .expect("Failed to create client config");
             // Not supposed to predefine ALPN protocols for ACME
             client_config.alpn_protocols = vec![];
             let connector = hyper_rustls::HttpsConnectorBuilder::new()
@@ -251,10 +262,17 @@ mod tests_need_pebble {
         }
         #[cfg(feature = "nativetls")]
         pub async fn new() -> Self {
-            let client_config =
-                make_client_config(None, None, None, true, Some(&crate::tls::TLS_ALPN))
-                    .await
-                    .expect("Failed to create client config");
+            let client_config = make_client_config(
+                None,
+                None,
+                None,
+                true,
+                Some(&crate::tls::TLS_ALPN),
+                &TlsArgs::default(),
+                &[],
+            )
+            .await
+            .expect("Failed to create client config");
             let mut http_connector = hyper_util::client::legacy::connect::HttpConnector::new();
             http_connector.enforce_http(false);
             let connector = (http_connector, client_config.into()).into();
@@ -0,0 +1,412 @@
+//! Admin HTTP API of the server, enabled with `--admin-listen`.
+//!
+//! Every request needs the `--admin-token` as `Authorization: Bearer <token>`.
+//! The endpoints answer with JSON:
+//! - `GET /sessions` lists the connected sessions with their users and addresses,
+//! - `GET /sessions/<id>/streams` lists the open streams of a session,
+//! - `DELETE /sessions/<id>` closes a session, and
+//! - `GET /stats` sums up all sessions.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::service::{ResponseBody, full_body};
+use http::{HeaderValue, Method, Request, Response, StatusCode, header};
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use parking_lot::Mutex;
+use penguin_mux::{Dupe, Multiplexor};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tracing::{debug, error, info, warn};
+
+/// A session being served
+#[derive(Debug)]
+struct Entry {
+    /// Name of the authenticated user, if any
+    user: Option<String>,
+    /// Address of the client
+    peer: Option<SocketAddr>,
+    /// When the session started
+    connected_at: SystemTime,
+    mux: Arc<Multiplexor>,
+    /// Notified to close the session
+    kick: Arc<Notify>,
+}
+
+/// Sessions being served, by their IDs
+#[derive(Clone, Debug, Default)]
+pub(super) struct Registry {
+    sessions: Arc<Mutex<BTreeMap<u64, Entry>>>,
+    /// Number of sessions registered so far, which is also the next ID
+    served: Arc<AtomicU64>,
+}
+
+impl Dupe for Registry {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl Registry {
+    /// Add a session until the returned `Registration` is dropped
+    pub fn register(
+        &self,
+        user: Option<&str>,
+        peer: Option<SocketAddr>,
+        mux: Arc<Multiplexor>,
+    ) -> Registration {
+        let id = self.served.fetch_add(1, Ordering::Relaxed) + 1;
+        let kick = Arc::new(Notify::new());
+        let entry = Entry {
+            user: user.map(str::to_string),
+            peer,
+            connected_at: SystemTime::now(),
+            mux,
+            kick: kick.dupe(),
+        };
+        self.sessions.lock().insert(id, entry);
+        Registration {
+            id,
+            registry: self.dupe(),
+            kick,
+        }
+    }
+
+    /// Ask a session to close. Returns `false` if there is no such session.
+    fn kick(&self, id: u64) -> bool {
+        let sessions = self.sessions.lock();
+        let Some(entry) = sessions.get(&id) else {
+            return false;
+        };
+        entry.kick.notify_one();
+        true
+    }
+}
+
+/// Keeps a session in the [`Registry`]
+#[derive(Debug)]
+pub(super) struct Registration {
+    id: u64,
+    registry: Registry,
+    kick: Arc<Notify>,
+}
+
+impl Registration {
+    /// ID of the session in the admin API and the logs
+    pub const fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait until the session is closed through the admin API
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn kicked(&self) {
+        self.kick.notified().await;
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().remove(&self.id);
+    }
+}
+
+/// A session in `GET /sessions`
+#[derive(Debug, Serialize)]
+struct SessionInfo {
+    id: u64,
+    user: Option<String>,
+    peer: Option<SocketAddr>,
+    /// Seconds since the Unix epoch
+    connected_at: u64,
+    rtt_ms: Option<f64>,
+    streams: usize,
+    /// Octets of the open streams
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// A stream in `GET /sessions/<id>/streams`
+#[derive(Debug, Serialize)]
+struct StreamInfo {
+    flow_id: u32,
+    host: String,
+    port: u16,
+    bytes_sent: u64,
+    bytes_received: u64,
+    queued_frames: usize,
+    send_window: u32,
+    unread_frames: usize,
+}
+
+/// The answer to `GET /stats`
+#[derive(Debug, Default, Serialize)]
+struct Totals {
+    sessions: usize,
+    sessions_served: u64,
+    streams: usize,
+    pending_streams: usize,
+    queued_frames: usize,
+    /// Octets of the open streams
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+/// The admin API
+#[derive(Clone, Debug)]
+pub(super) struct Admin {
+    pub registry: Registry,
+    /// Expected value of the `Authorization` header
+    pub authorization: HeaderValue,
+}
+
+impl Admin {
+    /// Create the API, accepting requests with `token`
+    pub fn new(registry: Registry, token: &str) -> Option<Self> {
+        let authorization = HeaderValue::from_str(&format!("Bearer {token}")).ok()?;
+        Some(Self {
+            registry,
+            authorization,
+        })
+    }
+
+    /// Answer a request
+    pub fn respond<B>(&self, req: &Request<B>) -> Response<ResponseBody> {
+        // Do not reveal how much of the token matched
+        let authorized = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .is_some_and(|value| bool::from(value.as_bytes().ct_eq(self.authorization.as_bytes())));
+        if !authorized {
+            warn!("Admin API request without a valid token");
+            return status(StatusCode::UNAUTHORIZED);
+        }
+        let segments = req
+            .uri()
+            .path()
+            .trim_matches('/')
+            .split('/')
+            .collect::<Vec<_>>();
+        match (req.method(), segments.as_slice()) {
+            (&Method::GET, ["sessions"]) => json(&self.sessions()),
+            (&Method::GET, ["sessions", id, "streams"]) => {
+                match id.parse().ok().and_then(|id| self.streams(id)) {
+                    Some(streams) => json(&streams),
+                    None => status(StatusCode::NOT_FOUND),
+                }
+            }
+            (&Method::DELETE, ["sessions", id]) => match id.parse() {
+                Ok(id) if self.registry.kick(id) => {
+                    info!("Closing session {id} as asked through the admin API");
+                    status(StatusCode::NO_CONTENT)
+                }
+                _ => status(StatusCode::NOT_FOUND),
+            },
+            (&Method::GET, ["stats"]) => json(&self.totals()),
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self.registry.sessions.lock();
+        sessions
+            .iter()
+            .map(|(id, entry)| {
+                let stats = entry.mux.stats();
+                SessionInfo {
+                    id: *id,
+                    user: entry.user.clone(),
+                    peer: entry.peer,
+                    connected_at: entry
+                        .connected_at
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    rtt_ms: stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                    streams: stats.active_flows(),
+                    bytes_sent: stats.bytes_sent(),
+                    bytes_received: stats.bytes_received(),
+                }
+            })
+            .collect()
+    }
+
+    fn streams(&self, id: u64) -> Option<Vec<StreamInfo>> {
+        let stats = self.registry.sessions.lock().get(&id)?.mux.stats();
+        let streams = stats
+            .flows
+            .into_iter()
+            .map(|flow| StreamInfo {
+                flow_id: flow.flow_id,
+                host: String::from_utf8_lossy(&flow.dest_host).into_owned(),
+                port: flow.dest_port,
+                bytes_sent: flow.bytes_sent,
+                bytes_received: flow.bytes_received,
+                queued_frames: flow.queued_frames,
+                send_window: flow.send_window,
+                unread_frames: flow.unread_frames,
+            })
+            .collect();
+        Some(streams)
+    }
+
+    fn totals(&self) -> Totals {
+        let sessions = self.registry.sessions.lock();
+        let mut totals = Totals {
+            sessions: sessions.len(),
+            sessions_served: self.registry.served.load(Ordering::Relaxed),
+            ..Totals::default()
+        };
+        for entry in sessions.values() {
+            let stats = entry.mux.stats();
+            totals.streams += stats.active_flows();
+            totals.pending_streams += stats.pending_flows;
+            totals.queued_frames += stats.queued_frames();
+            totals.bytes_sent += stats.bytes_sent();
+            totals.bytes_received += stats.bytes_received();
+        }
+        totals
+    }
+}
+
+/// An empty response with `status`
+fn status(status: StatusCode) -> Response<ResponseBody> {
+    let mut resp = Response::new(full_body(bytes::Bytes::new()));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// A JSON response of `value`
+fn json<T: Serialize>(value: &T) -> Response<ResponseBody> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut resp = Response::new(full_body(body));
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+            resp
+        }
+        Err(err) => {
+            error!("Cannot serialize an admin API response: {err}");
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Serve the admin API on `listener`
+pub(super) async fn run_admin_listener(listener: TcpListener, admin: Admin) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("Admin API accept error: {err}");
+                continue;
+            }
+        };
+        debug!("accepted admin API connection from {peer}");
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let resp = admin.respond(&req);
+                async move { Ok::<_, std::convert::Infallible>(resp) }
+            });
+            let exec = auto::Builder::new(TokioExecutor::new());
+            if let Err(err) = exec.serve_connection(TokioIo::new(stream), service).await {
+                debug!("Admin API connection error: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use penguin_mux::ws::PlainStream;
+
+    type EmptyBody = http_body_util::Empty<bytes::Bytes>;
+
+    fn request(method: Method, path: &str, token: Option<&str>) -> Request<EmptyBody> {
+        let mut req = Request::builder().method(method).uri(path);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        req.body(EmptyBody::new()).unwrap()
+    }
+
+    async fn body_json(resp: Response<ResponseBody>) -> serde_json::Value {
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() {
+        crate::tests::setup_logging();
+        let (client, server) = tokio::io::duplex(2048);
+        let client_mux = Multiplexor::new(PlainStream::new(client), None, None);
+        let server_mux = Arc::new(Multiplexor::new(PlainStream::new(server), None, None));
+        let registry = Registry::default();
+        let admin = Admin::new(registry.dupe(), "secret").unwrap();
+        let peer = "192.0.2.1:4321".parse().unwrap();
+        let registration = registry.register(Some("alice"), Some(peer), server_mux.dupe());
+
+        let resp = admin.respond(&request(Method::GET, "/sessions", None));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = admin.respond(&request(Method::GET, "/sessions", Some("wrong")));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = admin.respond(&request(Method::GET, "/sessions", Some("secre")));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = admin.respond(&request(Method::GET, "/sessions", Some("secrets")));
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let accept = tokio::spawn(async move { server_mux.accept_stream_channel().await });
+        let _stream = client_mux
+            .new_stream_channel(b"example.com", 443)
+            .await
+            .unwrap();
+        let _accepted = accept.await.unwrap().unwrap();
+
+        let resp = admin.respond(&request(Method::GET, "/sessions", Some("secret")));
+        assert_eq!(resp.status(), StatusCode::OK);
+        let sessions = body_json(resp).await;
+        assert_eq!(sessions[0]["id"], 1);
+        assert_eq!(sessions[0]["user"], "alice");
+        assert_eq!(sessions[0]["peer"], "192.0.2.1:4321");
+        assert_eq!(sessions[0]["streams"], 1);
+
+        let resp = admin.respond(&request(Method::GET, "/sessions/1/streams", Some("secret")));
+        let streams = body_json(resp).await;
+        assert_eq!(streams[0]["host"], "example.com");
+        assert_eq!(streams[0]["port"], 443);
+        let resp = admin.respond(&request(Method::GET, "/sessions/2/streams", Some("secret")));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let resp = admin.respond(&request(Method::GET, "/stats", Some("secret")));
+        let totals = body_json(resp).await;
+        assert_eq!(totals["sessions"], 1);
+        assert_eq!(totals["sessions_served"], 1);
+        assert_eq!(totals["streams"], 1);
+
+        let resp = admin.respond(&request(Method::DELETE, "/sessions/1", Some("secret")));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        tokio::time::timeout(std::time::Duration::from_secs(1), registration.kicked())
+            .await
+            .unwrap();
+        drop(registration);
+        let resp = admin.respond(&request(Method::DELETE, "/sessions/1", Some("secret")));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = admin.respond(&request(Method::GET, "/sessions", Some("secret")));
+        assert_eq!(body_json(resp).await, serde_json::json!([]));
+    }
+}
@@ -0,0 +1,316 @@
+//! Audit log of the flows the server forwards, enabled with `--audit-log`.
+//!
+//! Every stream and UDP flow gets one JSON line when it closes, with the
+//! session and user it belongs to, the target the client asked for, when it
+//! started and ended, the octets each way and why it closed.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::access_log::{open_log_file, serialize_time};
+use parking_lot::Mutex;
+use penguin_mux::Dupe;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+/// Close reason of flows that were still open when their session ended
+const SESSION_CLOSED: &str = "session closed";
+
+/// Where to write the audit log
+pub(super) struct AuditLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Append to the file at `path`, or write to stdout if it is `-`
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(open_log_file(path)?))
+    }
+
+    /// Write to `out`
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+
+    fn write(&self, entry: &Entry<'_>) {
+        // `expect`: the entry has only strings and numbers
+        let mut line =
+            serde_json::to_string(entry).expect("Cannot serialize audit log (this is a bug)");
+        line.push('\n');
+        if let Err(err) = self.out.lock().write_all(line.as_bytes()) {
+            warn!("Cannot write the audit log: {err}");
+        }
+    }
+}
+
+/// The client of a session, to audit the flows it opens
+#[derive(Clone, Debug)]
+pub(super) struct SessionAudit {
+    log: Arc<AuditLog>,
+    session_id: u64,
+    user: Option<Arc<str>>,
+    peer: Option<SocketAddr>,
+}
+
+impl Dupe for SessionAudit {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl SessionAudit {
+    /// Audit the flows of the session `session_id`
+    pub fn new(
+        log: Arc<AuditLog>,
+        session_id: u64,
+        user: Option<&str>,
+        peer: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            log,
+            session_id,
+            user: user.map(Arc::from),
+            peer,
+        }
+    }
+
+    /// Start auditing a flow to `target_host` port `target_port`
+    pub fn flow(
+        &self,
+        protocol: &'static str,
+        flow_id: u32,
+        target_host: &[u8],
+        target_port: u16,
+    ) -> FlowAudit {
+        FlowAudit {
+            session: self.dupe(),
+            protocol,
+            flow_id,
+            target_host: String::from_utf8_lossy(target_host).into_owned(),
+            target_port,
+            started: SystemTime::now(),
+            bytes: Arc::default(),
+            close_reason: None,
+        }
+    }
+}
+
+/// Octets of a flow
+#[derive(Debug, Default)]
+struct Bytes {
+    /// To the client
+    sent: AtomicU64,
+    /// From the client
+    received: AtomicU64,
+}
+
+/// An open flow, logged when dropped
+#[derive(Debug)]
+pub(super) struct FlowAudit {
+    session: SessionAudit,
+    protocol: &'static str,
+    flow_id: u32,
+    target_host: String,
+    target_port: u16,
+    started: SystemTime,
+    bytes: Arc<Bytes>,
+    close_reason: Option<String>,
+}
+
+impl FlowAudit {
+    /// Count octets sent to the client
+    pub fn add_sent(&self, len: usize) {
+        self.bytes.sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count octets received from the client
+    pub fn add_received(&self, len: usize) {
+        self.bytes.received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Wrap the stream to the target so that what goes through it is
+    /// counted: what we read from it is sent to the client, and what we
+    /// write to it was received from the client.
+    pub fn count<S>(&self, stream: S) -> Counted<S> {
+        Counted {
+            inner: stream,
+            bytes: self.bytes.dupe(),
+        }
+    }
+
+    /// Log the flow as closed for `reason`
+    pub fn close(mut self, reason: impl Into<String>) {
+        self.close_reason = Some(reason.into());
+    }
+}
+
+impl Drop for FlowAudit {
+    fn drop(&mut self) {
+        let ended = SystemTime::now();
+        let duration = ended.duration_since(self.started).unwrap_or_default();
+        let entry = Entry {
+            session_id: self.session.session_id,
+            user: self.session.user.as_deref(),
+            peer: self.session.peer,
+            protocol: self.protocol,
+            flow_id: format!("{:08x}", self.flow_id),
+            target_host: &self.target_host,
+            target_port: self.target_port,
+            started: self.started,
+            ended,
+            duration_ms: u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+            bytes_sent: self.bytes.sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes.received.load(Ordering::Relaxed),
+            close_reason: self.close_reason.as_deref().unwrap_or(SESSION_CLOSED),
+        };
+        self.session.log.write(&entry);
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    session_id: u64,
+    user: Option<&'a str>,
+    peer: Option<SocketAddr>,
+    protocol: &'static str,
+    flow_id: String,
+    target_host: &'a str,
+    target_port: u16,
+    #[serde(serialize_with = "serialize_time")]
+    started: SystemTime,
+    #[serde(serialize_with = "serialize_time")]
+    ended: SystemTime,
+    duration_ms: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    close_reason: &'a str,
+}
+
+/// A stream to a target that counts the octets of a [`FlowAudit`]
+#[derive(Debug)]
+pub(super) struct Counted<S> {
+    inner: S,
+    bytes: Arc<Bytes>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let len = buf.filled().len() - before;
+        self.bytes.sent.fetch_add(len as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            self.bytes.received.fetch_add(len as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A `Write` whose output the test can look at
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flow_audit() {
+        crate::tests::setup_logging();
+        let buffer = Buffer::default();
+        let log = Arc::new(AuditLog::new(Box::new(buffer.clone())));
+        let session = SessionAudit::new(
+            log,
+            7,
+            Some("alice"),
+            Some("192.0.2.1:1234".parse().unwrap()),
+        );
+        let flow = session.flow("tcp", 0x1234, b"example.com", 443);
+        let (ours, mut theirs) = tokio::io::duplex(64);
+        let mut counted = flow.count(ours);
+        counted.write_all(b"hello").await.unwrap();
+        theirs.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        counted.read_exact(&mut buf).await.unwrap();
+        flow.close("closed");
+        let flow = session.flow("udp", 1, b"192.0.2.53", 53);
+        flow.add_received(30);
+        flow.add_sent(100);
+        drop(flow);
+
+        let output = String::from_utf8(buffer.0.lock().clone()).unwrap();
+        let entries: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["session_id"], 7);
+        assert_eq!(entries[0]["user"], "alice");
+        assert_eq!(entries[0]["peer"], "192.0.2.1:1234");
+        assert_eq!(entries[0]["protocol"], "tcp");
+        assert_eq!(entries[0]["flow_id"], "00001234");
+        assert_eq!(entries[0]["target_host"], "example.com");
+        assert_eq!(entries[0]["target_port"], 443);
+        assert_eq!(entries[0]["bytes_sent"], 2);
+        assert_eq!(entries[0]["bytes_received"], 5);
+        assert_eq!(entries[0]["close_reason"], "closed");
+        assert!(entries[0]["started"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(entries[1]["protocol"], "udp");
+        assert_eq!(entries[1]["bytes_sent"], 100);
+        assert_eq!(entries[1]["bytes_received"], 30);
+        assert_eq!(entries[1]["close_reason"], SESSION_CLOSED);
+    }
+}
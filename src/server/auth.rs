@@ -0,0 +1,392 @@
+//! Client authentication and per-user access rules.
+//!
+//! Clients authenticate with `Authorization: Basic` against the auth file,
+//! with `Authorization: Bearer` against the JWT settings (see
+//! [`super::jwt`]), or with their TLS certificate (see [`super::client_cert`]).
+//!
+//! The auth file is compatible with `chisel`: a JSON object mapping
+//! `"user:pass"` to a list of regular expressions. A user may forward to
+//! `host:port` if it matches one of the expressions, and may bind a reverse
+//! remote on `host:port` if `R:host:port` matches one of them. `""` and `*`
+//! match everything. Unlike `chisel`, the expressions must match the whole
+//! string. Unix sockets are matched as `PATH:0`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::client_cert::{ClientCert, ClientCertRules};
+use super::egress::Egress;
+use super::jwt::JwtVerifier;
+use super::resolver::Resolver;
+use super::rewrite::Rewrites;
+use crate::acl::Acl;
+use crate::arg::LockTarget;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use http::HeaderValue;
+use penguin_mux::Dupe;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Errors when loading the auth file
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read auth file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid auth file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid credentials in auth file: `{0}` is not `user:pass`")]
+    Credentials(String),
+    #[error("Invalid pattern for user `{0}`: {1}")]
+    Pattern(String, regex::Error),
+}
+
+/// A user and the remotes they may use
+#[derive(Debug)]
+pub struct User {
+    name: String,
+    remotes: Vec<Regex>,
+    /// When the credentials stop being valid
+    expires: Option<SystemTime>,
+}
+
+impl User {
+    /// Create a user from the patterns of the remotes they may use
+    pub fn new(name: &str, patterns: &[String]) -> Result<Self, Error> {
+        let remotes = patterns
+            .iter()
+            .map(|pattern| {
+                let pattern = match pattern.as_str() {
+                    "" | "*" => ".*",
+                    pattern => pattern,
+                };
+                Regex::new(&format!("^(?:{pattern})$"))
+                    .map_err(|err| Error::Pattern(name.to_string(), err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name: name.to_string(),
+            remotes,
+            expires: None,
+        })
+    }
+
+    /// Set when the credentials stop being valid
+    #[must_use]
+    pub const fn with_expiry(mut self, expires: SystemTime) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How long until the credentials expire, if they do
+    pub fn expires_in(&self) -> Option<Duration> {
+        let expires = self.expires?;
+        Some(
+            expires
+                .duration_since(SystemTime::now())
+                .unwrap_or(Duration::ZERO),
+        )
+    }
+
+    fn matches(&self, remote: &str) -> bool {
+        self.remotes.iter().any(|pattern| pattern.is_match(remote))
+    }
+
+    /// Whether the user may forward to `host:port`
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.matches(&format_remote("", host, port))
+    }
+
+    /// Whether the user may bind a reverse remote on `host:port`
+    pub fn allows_reverse(&self, host: &str, port: u16) -> bool {
+        self.matches(&format_remote("R:", host, port))
+    }
+}
+
+/// Format a remote the way `chisel` does for matching, with IPv6 addresses
+/// in brackets
+fn format_remote(prefix: &str, host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("{prefix}[{host}]:{port}")
+    } else {
+        format!("{prefix}{host}:{port}")
+    }
+}
+
+/// An entry of the auth file
+#[derive(Debug)]
+struct Entry {
+    password: String,
+    user: Arc<User>,
+}
+
+/// Users who may connect, by their names
+#[derive(Debug, Default)]
+pub struct Users(HashMap<String, Entry>);
+
+impl Users {
+    /// Parse the content of an auth file
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let entries: HashMap<String, Vec<String>> = serde_json::from_str(content)?;
+        let mut users = HashMap::with_capacity(entries.len());
+        for (credentials, patterns) in entries {
+            let (name, password) = credentials
+                .split_once(':')
+                .filter(|(name, _)| !name.is_empty())
+                .ok_or_else(|| Error::Credentials(credentials.clone()))?;
+            let entry = Entry {
+                password: password.to_string(),
+                user: Arc::new(User::new(name, &patterns)?),
+            };
+            users.insert(name.to_string(), entry);
+        }
+        Ok(Self(users))
+    }
+
+    /// Add a user who may use any remote
+    pub fn add(&mut self, name: &str, password: &str) {
+        let user =
+            User::new(name, &["*".to_string()]).expect("Invalid built-in pattern (this is a bug)");
+        let entry = Entry {
+            password: password.to_string(),
+            user: Arc::new(user),
+        };
+        self.0.insert(name.to_string(), entry);
+    }
+
+    /// Load an auth file
+    pub async fn load(path: &str) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    /// Find the user with the credentials in an `Authorization: Basic` header
+    pub fn authenticate(&self, authorization: Option<&HeaderValue>) -> Option<Arc<User>> {
+        let encoded = authorization?.to_str().ok()?.strip_prefix("Basic ")?;
+        let decoded = B64_STANDARD_ENGINE.decode(encoded.trim()).ok()?;
+        let decoded = std::str::from_utf8(&decoded).ok()?;
+        let (name, password) = decoded.split_once(':')?;
+        self.0
+            .get(name)
+            // Do not reveal how much of the password matched
+            .filter(|entry| bool::from(entry.password.as_bytes().ct_eq(password.as_bytes())))
+            .map(|entry| entry.user.dupe())
+    }
+}
+
+/// How clients authenticate
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    pub users: Option<Users>,
+    pub jwt: Option<JwtVerifier>,
+    pub client_certs: Option<ClientCertRules>,
+}
+
+impl Authenticator {
+    /// Whether clients must authenticate
+    pub const fn is_required(&self) -> bool {
+        self.users.is_some() || self.jwt.is_some() || self.client_certs.is_some()
+    }
+
+    /// Find the user with the credentials in an `Authorization` header or,
+    /// failing that, the user the client certificate maps to
+    pub fn authenticate(
+        &self,
+        authorization: Option<&HeaderValue>,
+        cert: Option<&ClientCert>,
+    ) -> Option<Arc<User>> {
+        self.users
+            .as_ref()
+            .and_then(|users| users.authenticate(authorization))
+            .or_else(|| {
+                self.jwt
+                    .as_ref()
+                    .and_then(|jwt| jwt.authenticate(authorization))
+            })
+            .or_else(|| {
+                self.client_certs
+                    .as_ref()
+                    .and_then(|rules| rules.authenticate(cert))
+            })
+    }
+}
+
+/// What the client of a session may access
+#[derive(Debug, Default)]
+pub struct Access {
+    /// Rules that apply to all clients
+    pub acl: Arc<Acl>,
+    /// The authenticated user, if any
+    pub user: Option<Arc<User>>,
+    /// How targets are looked up
+    pub resolver: Arc<Resolver>,
+    /// Where forwarded traffic leaves from
+    pub egress: Arc<Egress>,
+    /// How targets are rewritten before they are looked up
+    pub rewrites: Arc<Rewrites>,
+    /// The only target, if the server is locked to one
+    pub lock: Option<Arc<LockTarget>>,
+}
+
+impl Access {
+    /// The name of the user, if any
+    pub fn user_name(&self) -> Option<&str> {
+        self.user.as_deref().map(User::name)
+    }
+
+    /// Whether the user, if any, may forward to `host:port`.
+    /// This does not check `acl`, which needs the resolved addresses.
+    pub fn user_allows(&self, host: &str, port: u16) -> bool {
+        self.user
+            .as_ref()
+            .is_none_or(|user| user.allows(host, port))
+    }
+
+    /// Whether the user, if any, may bind a reverse remote on `host:port`
+    pub fn user_allows_reverse(&self, host: &str, port: u16) -> bool {
+        self.user
+            .as_ref()
+            .is_none_or(|user| user.allows_reverse(host, port))
+    }
+
+    /// Where to forward instead of `target` if the server is locked
+    pub fn locked<'a>(&'a self, target: (&'a str, u16)) -> (&'a str, u16) {
+        self.lock
+            .as_deref()
+            .map_or(target, |lock| (lock.host.as_str(), lock.port))
+    }
+
+    /// The local address to reach `target` from, if one is set
+    pub fn source_for(&self, target: SocketAddr) -> Option<IpAddr> {
+        self.egress.source(self.user_name(), target)
+    }
+}
+
+impl Dupe for Access {
+    fn dupe(&self) -> Self {
+        Self {
+            acl: self.acl.dupe(),
+            user: self.user.as_ref().map(Dupe::dupe),
+            resolver: self.resolver.dupe(),
+            egress: self.egress.dupe(),
+            rewrites: self.rewrites.dupe(),
+            lock: self.lock.as_ref().map(Dupe::dupe),
+        }
+    }
+}
+
+impl From<Acl> for Access {
+    fn from(acl: Acl) -> Self {
+        Self {
+            acl: Arc::new(acl),
+            user: None,
+            resolver: Arc::default(),
+            egress: Arc::default(),
+            rewrites: Arc::default(),
+            lock: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTH_FILE: &str = r#"{
+        "foo:bar": ["0.0.0.0:3000", "R:0.0.0.0:[45]000"],
+        "ping:pong:with:colons": ["*"],
+        "nobody:": []
+    }"#;
+
+    fn basic(credentials: &str) -> HeaderValue {
+        let encoded = B64_STANDARD_ENGINE.encode(credentials);
+        HeaderValue::from_str(&format!("Basic {encoded}")).unwrap()
+    }
+
+    #[test]
+    fn test_parse_auth_file() {
+        crate::tests::setup_logging();
+        let users = Users::parse(AUTH_FILE).unwrap();
+        assert_eq!(users.0.len(), 3);
+        assert!(matches!(
+            Users::parse(r#"{"nopass": []}"#),
+            Err(Error::Credentials(_))
+        ));
+        assert!(matches!(
+            Users::parse(r#"{":pass": []}"#),
+            Err(Error::Credentials(_))
+        ));
+        assert!(matches!(
+            Users::parse(r#"{"foo:bar": ["("]}"#),
+            Err(Error::Pattern(..))
+        ));
+        assert!(matches!(
+            Users::parse(r#"["foo:bar"]"#),
+            Err(Error::Json(_))
+        ));
+    }
+
+    #[test]
+    fn test_authenticate() {
+        crate::tests::setup_logging();
+        let users = Users::parse(AUTH_FILE).unwrap();
+        let foo = users.authenticate(Some(&basic("foo:bar"))).unwrap();
+        assert_eq!(foo.name(), "foo");
+        let ping = users.authenticate(Some(&basic("ping:pong:with:colons")));
+        assert_eq!(ping.unwrap().name(), "ping");
+        assert!(users.authenticate(Some(&basic("nobody:"))).is_some());
+        assert!(users.authenticate(Some(&basic("foo:baz"))).is_none());
+        assert!(users.authenticate(Some(&basic("foo:ba"))).is_none());
+        assert!(users.authenticate(Some(&basic("foo:barr"))).is_none());
+        assert!(users.authenticate(Some(&basic("bar:foo"))).is_none());
+        assert!(users.authenticate(Some(&basic("foo"))).is_none());
+        assert!(users.authenticate(None).is_none());
+        let mut users = users;
+        users.add("foo", "new");
+        assert!(users.authenticate(Some(&basic("foo:bar"))).is_none());
+        let foo = users.authenticate(Some(&basic("foo:new"))).unwrap();
+        assert!(foo.allows("localhost", 80));
+        let bearer = HeaderValue::from_static("Bearer Zm9vOmJhcg==");
+        assert!(users.authenticate(Some(&bearer)).is_none());
+    }
+
+    #[test]
+    fn test_user_remotes() {
+        crate::tests::setup_logging();
+        let users = Users::parse(AUTH_FILE).unwrap();
+        let foo = &users.0["foo"].user;
+        assert!(foo.allows("0.0.0.0", 3000));
+        // Patterns match the whole remote
+        assert!(!foo.allows("10.0.0.0", 3000));
+        assert!(!foo.allows("0.0.0.0", 30000));
+        assert!(!foo.allows_reverse("0.0.0.0", 3000));
+        assert!(foo.allows_reverse("0.0.0.0", 4000));
+        assert!(!foo.allows("0.0.0.0", 4000));
+        let ping = &users.0["ping"].user;
+        assert!(ping.allows("::1", 22));
+        assert!(ping.allows_reverse("localhost", 8080));
+        let nobody = &users.0["nobody"].user;
+        assert!(!nobody.allows("localhost", 80));
+        assert!(Access::default().user_allows("localhost", 80));
+        let access = Access {
+            acl: Arc::default(),
+            user: Some(foo.dupe()),
+            resolver: Arc::default(),
+            egress: Arc::default(),
+            rewrites: Arc::default(),
+            lock: None,
+        };
+        assert!(access.user_allows("0.0.0.0", 3000));
+        assert!(!access.user_allows("localhost", 80));
+        assert_eq!(format_remote("R:", "::1", 22), "R:[::1]:22");
+        assert_eq!(format_remote("", "[::1]", 22), "[::1]:22");
+    }
+}
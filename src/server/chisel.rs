@@ -0,0 +1,244 @@
+//! Server side of the `chisel` compatibility mode (`--chisel`).
+//!
+//! `chisel` clients are authenticated with their SSH user and password,
+//! their remotes are checked when they send the `config` request, and every
+//! channel they open is connected to the `host:port` in its extra data.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::auth::{Access, Authenticator};
+use super::client_cert::ClientCert;
+use super::forwarder::{connect_allowed, resolve_allowed};
+use crate::chisel::connection::{self, Channel, Connection, Incoming};
+use crate::chisel::transport::Transport;
+use crate::chisel::{Config, HostKey, RemoteConfig};
+use crate::config;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use bytes::Bytes;
+use http::HeaderValue;
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
+use penguin_mux::Dupe;
+use std::sync::Arc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tracing::{debug, info, warn};
+
+/// Split the extra data of a channel into the host and port to connect to
+fn parse_target(extra: &[u8]) -> Option<(&str, u16)> {
+    let (host, port) = std::str::from_utf8(extra).ok()?.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some((host, port.parse().ok()?))
+}
+
+/// Why a remote in the `config` request is rejected, if it is
+fn check_remote(remote: &RemoteConfig, access: &Access) -> Option<String> {
+    if remote.socks || remote.reverse || remote.stdio || remote.remote_proto == "udp" {
+        return Some(format!(
+            "Remote `{}:{}` is not supported by this server",
+            remote.remote_host, remote.remote_port
+        ));
+    }
+    let allowed = remote
+        .remote_port
+        .parse()
+        .is_ok_and(|port| access.user_allows(&remote.remote_host, port));
+    if allowed {
+        None
+    } else {
+        Some(format!(
+            "Access to `{}:{}` denied",
+            remote.remote_host, remote.remote_port
+        ))
+    }
+}
+
+/// Authenticate a client with its SSH user and password, or its client
+/// certificate if it offered no password.
+/// Returns what the client may access, which is what `anonymous` may access
+/// as the authenticated user, or `None` if it may not connect.
+fn check_credentials(
+    auth: &Authenticator,
+    cert: Option<&ClientCert>,
+    user: &str,
+    password: Option<&str>,
+    anonymous: &Access,
+) -> Option<Access> {
+    let authorization = password.and_then(|password| {
+        let encoded = B64_STANDARD_ENGINE.encode(format!("{user}:{password}"));
+        HeaderValue::from_str(&format!("Basic {encoded}")).ok()
+    });
+    let user = auth.authenticate(authorization.as_ref(), cert);
+    if auth.is_required() && user.is_none() {
+        return None;
+    }
+    Some(Access {
+        user,
+        ..anonymous.dupe()
+    })
+}
+
+/// Connect a channel to the destination in its extra data
+async fn forward_channel(channel: Channel, extra: Bytes, access: Access) {
+    let Some((host, port)) = parse_target(&extra) else {
+        warn!("Invalid chisel channel target {extra:?}");
+        return;
+    };
+    let connected = match resolve_allowed(&access, (host, port)).await {
+        Ok(addrs) => connect_allowed(&access, &addrs).await.map_err(Into::into),
+        Err(err) => Err(err),
+    };
+    match connected {
+        Ok(stream) => {
+            debug!("chisel forwarding to {host} port {port}");
+            if let Err(err) = channel.pipe(stream).await {
+                debug!("chisel channel to {host} port {port} failed: {err}");
+            }
+        }
+        Err(err) => warn!("Cannot forward a chisel channel: {err}"),
+    }
+}
+
+/// Answer a global request of the client
+fn handle_request(
+    connection: &Connection,
+    name: &str,
+    want_reply: bool,
+    data: &[u8],
+    access: &Access,
+) {
+    match name {
+        "config" => {
+            let rejected = match serde_json::from_slice::<Config>(data) {
+                Ok(config) => {
+                    debug!("chisel client version {}", config.version);
+                    config
+                        .remotes
+                        .iter()
+                        .find_map(|remote| check_remote(remote, access))
+                }
+                Err(err) => Some(format!("Invalid configuration: {err}")),
+            };
+            if let Some(reason) = rejected {
+                warn!("Rejected chisel configuration: {reason}");
+                connection.reply(false, reason.into());
+            } else {
+                connection.reply(true, Bytes::new());
+            }
+        }
+        "ping" => connection.reply(true, Bytes::new()),
+        _ if want_reply => connection.reply(false, Bytes::new()),
+        _ => {}
+    }
+}
+
+/// Serve a `chisel` client once the upgrade completes
+pub(super) async fn serve_chisel(
+    on_upgrade: OnUpgrade,
+    host_key: Arc<HostKey>,
+    auth: Arc<Authenticator>,
+    anonymous: Access,
+    cert: Option<Arc<ClientCert>>,
+) {
+    let ws = match on_upgrade.await {
+        Ok(upgraded) => {
+            WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await
+        }
+        Err(err) => {
+            warn!("Failed to upgrade to WebSocket: {err}");
+            return;
+        }
+    };
+    let accept = Transport::accept(ws, host_key, |user, password| {
+        check_credentials(&auth, cert.as_deref(), user, password, &anonymous)
+    });
+    let (transport, access) =
+        match tokio::time::timeout(config::CHISEL_HANDSHAKE_TIMEOUT, accept).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(err)) => {
+                warn!("chisel handshake failed: {err}");
+                return;
+            }
+            Err(_) => {
+                warn!("chisel handshake timed out");
+                return;
+            }
+        };
+    if let Some(user) = access.user_name() {
+        info!("chisel client connected as `{user}`");
+    } else {
+        info!("chisel client connected");
+    }
+    let (connection, mut incoming, task) = connection::start(transport);
+    let handle_incoming = async {
+        while let Some(incoming) = incoming.recv().await {
+            match incoming {
+                Incoming::Channel { extra, channel } => {
+                    tokio::spawn(forward_channel(channel, extra, access.dupe()));
+                }
+                Incoming::Request {
+                    name,
+                    want_reply,
+                    data,
+                } => handle_request(&connection, &name, want_reply, &data, &access),
+            }
+        }
+    };
+    tokio::select! {
+        result = task => {
+            if let Err(err) = result {
+                debug!("chisel connection closed: {err}");
+            }
+        }
+        () = handle_incoming => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::auth::Users;
+
+    #[test]
+    fn test_chisel_parse_target() {
+        crate::tests::setup_logging();
+        assert_eq!(parse_target(b"example.com:80"), Some(("example.com", 80)));
+        assert_eq!(parse_target(b"[::1]:22"), Some(("::1", 22)));
+        assert_eq!(parse_target(b"socks"), None);
+        assert_eq!(parse_target(b"example.com:http"), None);
+    }
+
+    #[test]
+    fn test_chisel_check_remote() {
+        crate::tests::setup_logging();
+        let users = Users::parse(r#"{"foo:bar": ["^example\\.com:80$"]}"#).unwrap();
+        let auth = Authenticator {
+            users: Some(users),
+            ..Default::default()
+        };
+        let anonymous = Access::default();
+        assert!(check_credentials(&auth, None, "foo", Some("baz"), &anonymous).is_none());
+        assert!(check_credentials(&auth, None, "foo", None, &anonymous).is_none());
+        let access = check_credentials(&auth, None, "foo", Some("bar"), &anonymous).unwrap();
+        assert_eq!(access.user_name(), Some("foo"));
+        let mut remote = RemoteConfig {
+            remote_host: "example.com".to_string(),
+            remote_port: "80".to_string(),
+            remote_proto: "tcp".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(check_remote(&remote, &access), None);
+        remote.remote_port = "443".to_string();
+        assert!(check_remote(&remote, &access).is_some());
+        remote.remote_port = "80".to_string();
+        remote.reverse = true;
+        assert!(check_remote(&remote, &access).is_some());
+        let anyone =
+            check_credentials(&Authenticator::default(), None, "", None, &anonymous).unwrap();
+        assert_eq!(anyone.user_name(), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! Authorization of clients by their TLS certificates.
+//!
+//! When `--tls-ca` makes clients present certificates, the rules file maps
+//! the names in a certificate to a label and the remotes the client may use.
+//! It is a JSON list of rules such as
+//! `{"name": "[a-z]+\\.ops\\.example\\.com", "label": "ops", "remotes": ["*"]}`,
+//! tried in order. `name` is a regular expression matched against the whole common
+//! name and each DNS, email and URI subject alternative name of the
+//! certificate. `remotes` has the same format as in the auth file and
+//! defaults to allowing everything. `label` names the client in the logs.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::auth::{self, User};
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::debug;
+use x509_parser::extensions::GeneralName;
+
+/// Errors when loading the rules file
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read client certificate rules: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid client certificate rules: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid name pattern for `{0}`: {1}")]
+    Name(String, regex::Error),
+    #[error(transparent)]
+    Remotes(#[from] auth::Error),
+}
+
+/// The names a client certificate identifies its holder with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCert {
+    /// The common names, then the subject alternative names
+    pub names: Vec<String>,
+}
+
+impl ClientCert {
+    /// Read the names in a DER-encoded certificate
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .inspect_err(|err| debug!("Cannot parse client certificate: {err}"))
+            .ok()?;
+        let mut names: Vec<String> = cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_string)
+            .collect();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => names.push((*name).to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(Self { names })
+    }
+}
+
+/// A rule as written in the rules file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRule {
+    name: String,
+    label: String,
+    remotes: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    name: Regex,
+    /// Named after the label
+    user: Arc<User>,
+}
+
+/// Rules mapping client certificates to users
+#[derive(Debug, Default)]
+pub struct ClientCertRules(Vec<Rule>);
+
+impl ClientCertRules {
+    /// Parse the content of a rules file
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let raw: Vec<RawRule> = serde_json::from_str(content)?;
+        let rules = raw
+            .into_iter()
+            .map(|rule| {
+                let name = Regex::new(&format!("^(?:{})$", rule.name))
+                    .map_err(|err| Error::Name(rule.label.clone(), err))?;
+                let remotes = rule.remotes.unwrap_or_else(|| vec!["*".to_string()]);
+                let user = User::new(&rule.label, &remotes)?;
+                Ok(Rule {
+                    name,
+                    user: Arc::new(user),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self(rules))
+    }
+
+    /// Load a rules file
+    pub async fn load(path: &str) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    /// Find the user of the first rule matching a name in `cert`
+    pub fn authenticate(&self, cert: Option<&ClientCert>) -> Option<Arc<User>> {
+        let cert = cert?;
+        let rule = self
+            .0
+            .iter()
+            .find(|rule| cert.names.iter().any(|name| rule.name.is_match(name)))?;
+        debug!(
+            "Client certificate {:?} matched `{}`",
+            cert.names,
+            rule.user.name()
+        );
+        Some(rule.user.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"[
+        {"name": "admin", "label": "admin"},
+        {"name": "[a-z]+\\.ops\\.example\\.com", "label": "ops", "remotes": ["localhost:22"]}
+    ]"#;
+
+    fn make_cert(cn: &str, sans: &[&str]) -> ClientCert {
+        let mut params = rcgen::CertificateParams::new(
+            sans.iter().map(|s| (*s).to_string()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, cn);
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        ClientCert::from_der(cert.der()).unwrap()
+    }
+
+    #[test]
+    fn test_client_cert_names() {
+        crate::tests::setup_logging();
+        let cert = make_cert("alice", &["alice.ops.example.com", "10.0.0.1"]);
+        assert_eq!(cert.names, ["alice", "alice.ops.example.com"]);
+        assert!(ClientCert::from_der(b"not a certificate").is_none());
+    }
+
+    #[test]
+    fn test_client_cert_rules() {
+        crate::tests::setup_logging();
+        let rules = ClientCertRules::parse(RULES).unwrap();
+        let admin = rules.authenticate(Some(&make_cert("admin", &[]))).unwrap();
+        assert_eq!(admin.name(), "admin");
+        assert!(admin.allows("example.com", 443));
+        let ops = rules
+            .authenticate(Some(&make_cert("alice", &["alice.ops.example.com"])))
+            .unwrap();
+        assert_eq!(ops.name(), "ops");
+        assert!(ops.allows("localhost", 22));
+        assert!(!ops.allows("localhost", 80));
+        // Patterns match whole names
+        assert!(
+            rules
+                .authenticate(Some(&make_cert("administrator", &[])))
+                .is_none()
+        );
+        assert!(
+            rules
+                .authenticate(Some(&make_cert("x", &["alice.ops.example.com.evil"])))
+                .is_none()
+        );
+        assert!(rules.authenticate(None).is_none());
+        assert!(matches!(
+            ClientCertRules::parse(r#"[{"name": "(", "label": "x"}]"#),
+            Err(Error::Name(..))
+        ));
+        assert!(matches!(
+            ClientCertRules::parse(r#"[{"name": "x", "label": "x", "remotes": ["("]}]"#),
+            Err(Error::Remotes(_))
+        ));
+        assert!(matches!(
+            ClientCertRules::parse(r#"[{"name": "x"}]"#),
+            Err(Error::Json(_))
+        ));
+    }
+}
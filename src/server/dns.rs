@@ -0,0 +1,226 @@
+//! Answering the queries of clients' `dns` remotes with our resolver.
+//!
+//! Streams to [`DNS_HOST`](crate::dns::DNS_HOST) are piped to the resolver
+//! over TCP. Each datagram to it is a query that we send to the resolver over
+//! UDP, and the answer goes back to the client in a datagram of the same
+//! flow. If that answer is truncated, the query is retried over TCP so that
+//! the client gets all of it.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::audit_log::FlowAudit;
+use super::forwarder::{Error, close_audit, copy_bidirectional};
+use crate::config;
+use crate::dns::DNS_PORT;
+use bytes::Bytes;
+use penguin_mux::{Datagram, MuxStream, ResetReason};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tracing::{debug, trace};
+
+/// Flag of a truncated answer in the third octet of the header
+const TRUNCATED: u8 = 0x02;
+
+/// The first nameserver in `/etc/resolv.conf`, if any
+pub(super) fn system_resolver() -> Option<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    parse_resolv_conf(&conf)
+}
+
+/// The first nameserver in the contents of a `resolv.conf`
+fn parse_resolv_conf(conf: &str) -> Option<SocketAddr> {
+    conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            return None;
+        }
+        // Skips addresses with a zone, which `IpAddr` cannot hold
+        let ip = words.next()?.parse().ok()?;
+        Some(SocketAddr::new(ip, DNS_PORT))
+    })
+}
+
+/// Pipe a stream of DNS queries over TCP to `resolver`. The stream is reset
+/// if we have no resolver or cannot reach it, and it is logged to `audit`
+/// when it closes.
+///
+/// # Errors
+/// It carries the errors from connecting to the resolver and from the
+/// underlying TCP or channel IO functions.
+#[tracing::instrument(skip_all, level = "debug", fields(flow_id = %format_args!("{:08x}", channel.flow_id())))]
+pub(super) async fn dns_forwarder_on_channel(
+    channel: MuxStream,
+    resolver: Option<SocketAddr>,
+    audit: Option<FlowAudit>,
+) -> Result<(), Error> {
+    let result = async {
+        let connected = match resolver {
+            Some(resolver) => TcpStream::connect(resolver).await.map_err(Error::from),
+            None => Err(Error::NoDnsResolver),
+        };
+        let mut rstream = match connected {
+            Ok(rstream) => rstream,
+            Err(e) => {
+                channel.reset(ResetReason::Unreachable);
+                return Err(e);
+            }
+        };
+        debug!("DNS forwarding to {}", rstream.peer_addr()?);
+        copy_bidirectional(channel, &mut rstream, audit.as_ref()).await?;
+        Ok(())
+    }
+    .await;
+    close_audit(audit, result.as_ref().map(|()| "closed"));
+    result
+}
+
+/// Answer the DNS query in a datagram with `resolver`, giving up after
+/// `timeout`. The answer is sent to the client in a datagram of the same
+/// flow, and the query is logged to `audit`.
+///
+/// # Errors
+/// Returns an error if we have no resolver or it does not answer.
+#[tracing::instrument(skip_all, level = "debug", fields(flow_id = %format_args!("{:08x}", query.flow_id)))]
+pub(super) async fn answer_dns_datagram(
+    query: Datagram,
+    resolver: Option<SocketAddr>,
+    datagram_tx: mpsc::Sender<Datagram>,
+    timeout: Duration,
+    audit: Option<FlowAudit>,
+) -> Result<(), Error> {
+    let result = async {
+        let resolver = resolver.ok_or(Error::NoDnsResolver)?;
+        let answer = tokio::time::timeout(timeout, resolve(&query.data, resolver))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        if let Some(audit) = &audit {
+            audit.add_received(query.data.len());
+            audit.add_sent(answer.len());
+        }
+        let frame = Datagram {
+            data: answer,
+            ..query
+        };
+        // The application retries its query if this is lost
+        datagram_tx.try_send(frame).ok();
+        Ok("answered")
+    }
+    .await;
+    close_audit(audit, result.as_ref().copied());
+    result.map(|_| ())
+}
+
+/// Send `query` to `resolver` over UDP, and over TCP if the answer is
+/// truncated
+pub(super) async fn resolve(query: &[u8], resolver: SocketAddr) -> io::Result<Bytes> {
+    let answer = query_udp(query, resolver).await?;
+    if answer.get(2).is_some_and(|flags| flags & TRUNCATED != 0) {
+        trace!("answer is truncated, retrying over TCP");
+        return query_tcp(query, resolver).await;
+    }
+    Ok(answer)
+}
+
+/// Send `query` to `resolver` over UDP and wait for its answer
+async fn query_udp(query: &[u8], resolver: SocketAddr) -> io::Result<Bytes> {
+    let bind_addr: SocketAddr = if resolver.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(resolver).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        // Skip stray datagrams that answer another ID
+        if len >= 2 && buf.get(..2) == query.get(..2) {
+            buf.truncate(len);
+            return Ok(Bytes::from(buf));
+        }
+    }
+}
+
+/// Send `query` to `resolver` over TCP and read its answer
+async fn query_tcp(query: &[u8], resolver: SocketAddr) -> io::Result<Bytes> {
+    let len = u16::try_from(query.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let mut stream = TcpStream::connect(resolver).await?;
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    let len = stream.read_u16().await?;
+    let mut answer = vec![0; usize::from(len)];
+    stream.read_exact(&mut answer).await?;
+    Ok(Bytes::from(answer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_resolv_conf() {
+        let conf = "# comment\nsearch example.com\nnameserver fe80::1%eth0\nnameserver 192.0.2.53\nnameserver 192.0.2.54\n";
+        assert_eq!(
+            parse_resolv_conf(conf),
+            Some("192.0.2.53:53".parse().unwrap())
+        );
+        assert_eq!(parse_resolv_conf("options edns0\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_answer_dns_datagram_truncated() {
+        crate::tests::setup_logging();
+        // A resolver that truncates over UDP and answers over TCP
+        let udp = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let resolver = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(resolver).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, addr) = udp.recv_from(&mut buf).await.unwrap();
+            let mut answer = buf[..len].to_vec();
+            answer[2] |= 0x80 | TRUNCATED;
+            udp.send_to(&answer, addr).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut query = vec![0; usize::from(len)];
+            stream.read_exact(&mut query).await.unwrap();
+            query[2] |= 0x80;
+            query.extend_from_slice(b"full answer");
+            stream
+                .write_all(&u16::try_from(query.len()).unwrap().to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&query).await.unwrap();
+        });
+        let (datagram_tx, mut datagram_rx) = mpsc::channel(1);
+        let query = Datagram {
+            flow_id: 7,
+            target_host: Bytes::from_static(crate::dns::DNS_HOST.as_bytes()),
+            target_port: DNS_PORT,
+            data: Bytes::from_static(b"\x12\x34\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00"),
+        };
+        answer_dns_datagram(
+            query,
+            Some(resolver),
+            datagram_tx,
+            Duration::from_secs(5),
+            None,
+        )
+        .await
+        .unwrap();
+        let answer = datagram_rx.recv().await.unwrap();
+        assert_eq!(answer.flow_id, 7);
+        assert_eq!(&answer.data[..3], b"\x12\x34\x81");
+        assert!(answer.data.ends_with(b"full answer"));
+    }
+}
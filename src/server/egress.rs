@@ -0,0 +1,149 @@
+//! Source addresses of forwarded traffic.
+//!
+//! `--egress-addr` sets the local address that forwarded TCP connections
+//! and UDP flows leave from, at most one per address family, and
+//! `--egress-user` overrides it for a user. Targets of a family with no
+//! address set are reached from the address the routing table picks.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::EgressUser;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+
+/// Errors in the egress options
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid --egress-addr: more than one {0} address")]
+    Duplicate(&'static str),
+    #[error("Invalid --egress-user: more than one {1} address for user `{0}`")]
+    DuplicateUser(String, &'static str),
+}
+
+/// Source addresses by family
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Sources {
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+}
+
+impl Sources {
+    /// Set the address of the family of `addr`.
+    /// Returns the name of the family if it already has one.
+    fn add(&mut self, addr: IpAddr) -> Result<(), &'static str> {
+        match addr {
+            IpAddr::V4(addr) if self.v4.is_none() => self.v4 = Some(addr),
+            IpAddr::V6(addr) if self.v6.is_none() => self.v6 = Some(addr),
+            IpAddr::V4(_) => return Err("IPv4"),
+            IpAddr::V6(_) => return Err("IPv6"),
+        }
+        Ok(())
+    }
+
+    /// The address to reach `target` from
+    fn for_target(self, target: SocketAddr) -> Option<IpAddr> {
+        match target {
+            SocketAddr::V4(_) => self.v4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.v6.map(IpAddr::V6),
+        }
+    }
+}
+
+/// Where forwarded traffic leaves from
+#[derive(Debug, Default)]
+pub struct Egress {
+    default: Sources,
+    users: HashMap<String, Sources>,
+}
+
+impl Egress {
+    /// Use `addrs` for everyone and `users` for some users
+    pub fn new(addrs: &[IpAddr], users: &[EgressUser]) -> Result<Self, Error> {
+        let mut default = Sources::default();
+        for addr in addrs {
+            default.add(*addr).map_err(Error::Duplicate)?;
+        }
+        let mut by_user: HashMap<String, Sources> = HashMap::with_capacity(users.len());
+        for user in users {
+            by_user
+                .entry(user.user.clone())
+                .or_default()
+                .add(user.addr)
+                .map_err(|family| Error::DuplicateUser(user.user.clone(), family))?;
+        }
+        Ok(Self {
+            default,
+            users: by_user,
+        })
+    }
+
+    /// The local address `user` reaches `target` from, if set
+    pub fn source(&self, user: Option<&str>, target: SocketAddr) -> Option<IpAddr> {
+        user.and_then(|user| self.users.get(user))
+            .and_then(|sources| sources.for_target(target))
+            .or_else(|| self.default.for_target(target))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn user(user: &str, addr: &str) -> EgressUser {
+        EgressUser {
+            user: user.to_string(),
+            addr: ip(addr),
+        }
+    }
+
+    #[test]
+    fn test_egress_source() {
+        crate::tests::setup_logging();
+        let v4_target = "192.0.2.80:80".parse().unwrap();
+        let v6_target = "[2001:db8::80]:80".parse().unwrap();
+        assert_eq!(Egress::default().source(Some("foo"), v4_target), None);
+        let egress = Egress::new(
+            &[ip("198.51.100.1")],
+            &[user("foo", "2001:db8::f00"), user("bar", "198.51.100.2")],
+        )
+        .unwrap();
+        assert_eq!(egress.source(None, v4_target), Some(ip("198.51.100.1")));
+        assert_eq!(egress.source(None, v6_target), None);
+        // A user without an address of the family gets the default
+        assert_eq!(
+            egress.source(Some("foo"), v4_target),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(
+            egress.source(Some("foo"), v6_target),
+            Some(ip("2001:db8::f00"))
+        );
+        assert_eq!(
+            egress.source(Some("bar"), v4_target),
+            Some(ip("198.51.100.2"))
+        );
+        assert_eq!(
+            egress.source(Some("baz"), v4_target),
+            Some(ip("198.51.100.1"))
+        );
+    }
+
+    #[test]
+    fn test_egress_duplicate() {
+        crate::tests::setup_logging();
+        assert!(Egress::new(&[ip("198.51.100.1"), ip("2001:db8::1")], &[]).is_ok());
+        assert!(matches!(
+            Egress::new(&[ip("198.51.100.1"), ip("198.51.100.2")], &[]),
+            Err(Error::Duplicate("IPv4"))
+        ));
+        assert!(matches!(
+            Egress::new(&[], &[user("foo", "::1"), user("foo", "::2")]),
+            Err(Error::DuplicateUser(name, "IPv6")) if name == "foo"
+        ));
+    }
+}
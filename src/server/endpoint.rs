@@ -0,0 +1,167 @@
+//! Additional `WebSocket` endpoints with their own policies.
+//!
+//! The endpoints file is a JSON list of endpoints such as
+//! `{"path": "/team-a", "label": "team-a", "psk": ["secret"], "allow": ["*:443"]}`.
+//! A client upgrading on `path` must present one of the PSKs in `psk`
+//! (none are needed if it is empty or missing), and may only forward to the
+//! destinations allowed by `allow`, `deny` and `unix_sockets`, which have
+//! the same format as `--allow`, `--deny` and `--allow-unix-socket`. The
+//! server-wide PSKs and rules do not apply to these endpoints, but
+//! `--auth` and the other authentication options still do. `label` names
+//! the endpoint in the logs.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::acl::{Acl, InvalidRule, Rule};
+use http::HeaderValue;
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Errors when loading the endpoints file
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read endpoints: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid endpoints: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid path for endpoint `{0}`: it must start with `/`")]
+    Path(String),
+    #[error("Duplicate path for endpoint `{0}`")]
+    Duplicate(String),
+    #[error("Invalid PSK for endpoint `{0}`")]
+    Psk(String),
+    #[error("Invalid rule for endpoint `{0}`: {1}")]
+    Rule(String, InvalidRule),
+}
+
+/// An endpoint as written in the endpoints file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEndpoint {
+    path: String,
+    label: String,
+    #[serde(default)]
+    psk: Vec<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    unix_sockets: Vec<String>,
+}
+
+/// A `WebSocket` endpoint
+#[derive(Debug)]
+pub struct Endpoint {
+    pub path: String,
+    /// Names the endpoint in the logs
+    pub label: String,
+    /// PSKs, any of which is accepted
+    pub ws_psk: Vec<HeaderValue>,
+    /// Which destinations clients may forward to
+    pub acl: Arc<Acl>,
+}
+
+impl Endpoint {
+    fn from_raw(raw: RawEndpoint) -> Result<Self, Error> {
+        if !raw.path.starts_with('/') {
+            return Err(Error::Path(raw.label));
+        }
+        let ws_psk = raw
+            .psk
+            .iter()
+            .map(|psk| HeaderValue::from_str(psk).map_err(|_| Error::Psk(raw.label.clone())))
+            .collect::<Result<_, _>>()?;
+        let parse_rules = |rules: &[String]| {
+            rules
+                .iter()
+                .map(|rule| {
+                    rule.parse::<Rule>()
+                        .map_err(|err| Error::Rule(raw.label.clone(), err))
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let acl = Acl::new(parse_rules(&raw.allow)?, parse_rules(&raw.deny)?)
+            .with_unix_sockets(raw.unix_sockets);
+        Ok(Self {
+            path: raw.path,
+            label: raw.label,
+            ws_psk,
+            acl: Arc::new(acl),
+        })
+    }
+}
+
+/// Additional endpoints, looked up by path
+#[derive(Debug, Default)]
+pub struct Endpoints(Vec<Arc<Endpoint>>);
+
+impl Endpoints {
+    /// Parse the content of an endpoints file
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let raw: Vec<RawEndpoint> = serde_json::from_str(content)?;
+        let mut endpoints: Vec<Arc<Endpoint>> = Vec::with_capacity(raw.len());
+        for raw in raw {
+            let endpoint = Endpoint::from_raw(raw)?;
+            if endpoints.iter().any(|other| other.path == endpoint.path) {
+                return Err(Error::Duplicate(endpoint.label));
+            }
+            endpoints.push(Arc::new(endpoint));
+        }
+        Ok(Self(endpoints))
+    }
+
+    /// Load an endpoints file
+    pub async fn load(path: &str) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    /// Find the endpoint at `path`
+    pub fn get(&self, path: &str) -> Option<&Arc<Endpoint>> {
+        self.0.iter().find(|endpoint| endpoint.path == path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINTS: &str = r#"[
+        {"path": "/team-a", "label": "team-a", "psk": ["a1", "a2"], "allow": ["*:443"]},
+        {"path": "/team-b", "label": "team-b", "deny": ["10.0.0.0/8"]}
+    ]"#;
+
+    #[test]
+    fn test_endpoints() {
+        crate::tests::setup_logging();
+        let endpoints = Endpoints::parse(ENDPOINTS).unwrap();
+        let a = endpoints.get("/team-a").unwrap();
+        assert_eq!(a.label, "team-a");
+        assert_eq!(a.ws_psk, ["a1", "a2"]);
+        assert!(a.acl.allows_unresolved("example.com", 443));
+        assert!(!a.acl.allows_unresolved("example.com", 80));
+        let b = endpoints.get("/team-b").unwrap();
+        assert!(b.ws_psk.is_empty());
+        assert!(b.acl.allows_unresolved("example.com", 80));
+        assert!(!b.acl.allows_unresolved("10.1.2.3", 80));
+        assert!(endpoints.get("/ws").is_none());
+        assert!(matches!(
+            Endpoints::parse(r#"[{"path": "team", "label": "x"}]"#),
+            Err(Error::Path(_))
+        ));
+        assert!(matches!(
+            Endpoints::parse(r#"[{"path": "/a", "label": "x"}, {"path": "/a", "label": "y"}]"#),
+            Err(Error::Duplicate(_))
+        ));
+        assert!(matches!(
+            Endpoints::parse(r#"[{"path": "/a", "label": "x", "allow": ["a:b:c"]}]"#),
+            Err(Error::Rule(..))
+        ));
+        assert!(matches!(
+            Endpoints::parse(r#"[{"path": "/a"}]"#),
+            Err(Error::Json(_))
+        ));
+    }
+}
@@ -3,16 +3,31 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+use super::audit_log::{FlowAudit, SessionAudit};
+use super::auth::Access;
+use super::proxy_protocol;
 use crate::config;
-use penguin_mux::{Datagram, Dupe, MuxStream};
-use std::net::SocketAddr;
+use crate::udp_error::UDP_ERROR_HOST;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use penguin_mux::{BufferPool, Datagram, Dupe, IntKey, Multiplexor, MuxStream, ResetReason};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tokio::net::TcpStream;
-use tokio::{
-    net::{UdpSocket, lookup_host},
-    sync::mpsc,
-};
-use tracing::{debug, trace};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Interest};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::task::JoinSet;
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::{Instrument, debug, debug_span, trace, warn};
+
+#[cfg(feature = "nohash")]
+use nohash_hasher::IntMap;
+#[cfg(not(feature = "nohash"))]
+use std::collections::HashMap as IntMap;
 
 /// Error type for the forwarder.
 #[derive(Error, Debug)]
@@ -21,21 +36,113 @@ pub(super) enum Error {
     Io(#[from] std::io::Error),
     #[error("Invalid host: {0}")]
     Host(#[from] std::str::Utf8Error),
+    #[error(transparent)]
+    Mux(#[from] penguin_mux::Error),
+    #[error("Destination not allowed: {0} port {1}")]
+    Forbidden(String, u16),
+    #[error("No DNS resolver to answer queries with")]
+    NoDnsResolver,
+}
+
+/// Rewrite a forwarding destination, resolve it and keep the addresses
+/// `access` allows. The user is checked against the destination as given,
+/// or the locked one if the server is locked.
+///
+/// # Errors
+/// Returns [`Error::Forbidden`] if the user may not use the destination or
+/// the ACL allows none of the addresses.
+pub(super) async fn resolve_allowed(
+    access: &Access,
+    target: (&str, u16),
+) -> Result<Vec<SocketAddr>, Error> {
+    let target = access.locked(target);
+    if !access.user_allows(target.0, target.1) {
+        return Err(Error::Forbidden(target.0.to_string(), target.1));
+    }
+    let rewritten = access.rewrites.apply(target.0, target.1);
+    if rewritten != target {
+        debug!(
+            "rewriting {} port {} to {} port {}",
+            target.0, target.1, rewritten.0, rewritten.1
+        );
+    }
+    let target = rewritten;
+    let resolved = access.resolver.lookup(target.0, target.1).await?;
+    let acl = &access.acl;
+    if acl.is_empty() {
+        return Ok(resolved);
+    }
+    let allowed: Vec<SocketAddr> = resolved
+        .into_iter()
+        .filter(|addr| {
+            let allowed = acl.allows(target.0, *addr);
+            if !allowed {
+                debug!("{} resolved to {addr}, which is not allowed", target.0);
+            }
+            allowed
+        })
+        .collect();
+    if allowed.is_empty() {
+        return Err(Error::Forbidden(target.0.to_string(), target.1));
+    }
+    Ok(allowed)
+}
+
+/// Connect to the first of `addrs` that accepts, from the local address
+/// `access` sets for its family if any
+pub(super) async fn connect_allowed(
+    access: &Access,
+    addrs: &[SocketAddr],
+) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in addrs {
+        match connect_from(access.source_for(*addr), *addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+/// Connect to `target` from `source`, or from the address the routing table
+/// picks if `None`
+async fn connect_from(source: Option<IpAddr>, target: SocketAddr) -> std::io::Result<TcpStream> {
+    let Some(source) = source else {
+        return TcpStream::connect(target).await;
+    };
+    let socket = if target.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.bind(SocketAddr::new(source, 0))?;
+    socket.connect(target).await
 }
 
 /// Bind a UDP socket with the same address family as the given target,
+/// from the local address `access` sets for the family if any,
 /// and return the bound socket and the matched target address.
+/// Only addresses allowed by `access` are considered.
 /// Note that we don't connect or send the socket here.
 #[inline]
-async fn bind_for_target(target: (&str, u16)) -> Result<(UdpSocket, SocketAddr), Error> {
-    let targets = lookup_host(target).await?;
+async fn bind_for_target(
+    target: (&str, u16),
+    access: &Access,
+) -> Result<(UdpSocket, SocketAddr), Error> {
+    let targets = resolve_allowed(access, target).await?;
     let mut last_err = None;
     for target in targets {
-        let socket = match if target.is_ipv4() {
-            UdpSocket::bind(("0.0.0.0", 0)).await
+        let source = access.source_for(target).unwrap_or(if target.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
         } else {
-            UdpSocket::bind(("::", 0)).await
-        } {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        });
+        let socket = match UdpSocket::bind((source, 0)).await {
             Ok(socket) => socket,
             Err(e) => {
                 last_err = Some(e);
@@ -59,14 +166,166 @@ async fn bind_for_target(target: (&str, u16)) -> Result<(UdpSocket, SocketAddr),
         .into())
 }
 
+/// How the server forwards UDP flows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct UdpOptions {
+    /// How long a flow lives without traffic
+    pub timeout: Duration,
+    /// Whether to relay datagrams from any peer instead of only from the
+    /// destinations the flow has sent to
+    pub full_cone: bool,
+    /// Whether the client asked to be told when a flow fails
+    pub report_errors: bool,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        Self {
+            timeout: config::UDP_PRUNE_TIMEOUT,
+            full_cone: false,
+            report_errors: false,
+        }
+    }
+}
+
 /// Sit on a random port, send a UDP datagram to the given target,
-/// and wait for a response in the following `UDP_PRUNE_TIMEOUT` seconds.
-#[tracing::instrument(skip_all, level = "debug", fields(flow_id = %format_args!("{:08x}", first_datagram_frame.flow_id)))]
+/// and wait for responses until no datagram is seen for `options.timeout`.
+/// Only responses from the destinations the flow has sent to are relayed,
+/// unless `options.full_cone` is set.
+/// With `options.report_errors`, the flow ends when the destination answers
+/// with an ICMP error or a datagram cannot be sent, and the client is told
+/// with a datagram to [`UDP_ERROR_HOST`].
+/// The flow is logged to `audit` when it closes.
+#[tracing::instrument(skip_all, level = "debug", fields(flow_id = %format_args!("{:08x}", first_datagram_frame.flow_id), user = access.user_name()))]
 pub(super) async fn udp_forward_on(
     first_datagram_frame: Datagram,
-    mut datagram_rx: mpsc::Receiver<Datagram>,
+    datagram_rx: mpsc::Receiver<Datagram>,
     datagram_tx: mpsc::Sender<Datagram>,
+    access: Access,
+    pool: BufferPool,
+    options: UdpOptions,
+    audit: Option<FlowAudit>,
 ) -> Result<(), Error> {
+    let flow_id = first_datagram_frame.flow_id;
+    let result = udp_forward(
+        first_datagram_frame,
+        datagram_rx,
+        datagram_tx.dupe(),
+        &access,
+        &pool,
+        options,
+        audit.as_ref(),
+    )
+    .await;
+    if options.report_errors
+        && let Err(Error::Io(err)) = &result
+    {
+        let frame = Datagram {
+            target_host: Bytes::from_static(UDP_ERROR_HOST),
+            target_port: 0,
+            flow_id,
+            data: Bytes::from(err.to_string()),
+        };
+        // The client retries anyway if this is lost
+        datagram_tx.try_send(frame).ok();
+    }
+    close_audit(audit, result.as_ref().copied());
+    result.map(|_| ())
+}
+
+/// Receive a datagram on `socket`. With `errors`, also wake up for ICMP
+/// errors, which readable interest misses.
+async fn recv_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    errors: bool,
+) -> std::io::Result<(usize, SocketAddr)> {
+    if !errors {
+        return socket.recv_from(buf).await;
+    }
+    loop {
+        let ready = socket.ready(Interest::READABLE | Interest::ERROR).await?;
+        if ready.is_error() {
+            // The error readiness stays set, so the flow cannot go on
+            return Err(socket
+                .take_error()?
+                .unwrap_or_else(|| std::io::Error::other("ICMP error")));
+        }
+        match socket.try_recv_from(buf) {
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+    }
+}
+
+/// Have ICMP errors, such as port unreachable, fail `recv_from` on `socket`.
+/// Linux does not report them for unconnected sockets otherwise.
+#[cfg(target_os = "linux")]
+fn enable_recv_err(socket: &UdpSocket, target: SocketAddr) {
+    use nix::sys::socket::{setsockopt, sockopt};
+    let result = if target.is_ipv4() {
+        setsockopt(socket, sockopt::Ipv4RecvErr, &true)
+    } else {
+        setsockopt(socket, sockopt::Ipv6RecvErr, &true)
+    };
+    if let Err(err) = result {
+        debug!("cannot receive ICMP errors: {err}");
+    }
+}
+
+/// Where a UDP flow has sent datagrams, by the host and port the client
+/// named, to label their responses with. Peers expire after the flow timeout.
+struct UdpPeers {
+    peers: HashMap<SocketAddr, (Bytes, u16, tokio::time::Instant)>,
+    timeout: Duration,
+    next_prune: tokio::time::Instant,
+}
+
+impl UdpPeers {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            timeout,
+            next_prune: tokio::time::Instant::now() + timeout,
+        }
+    }
+
+    /// Record that the flow has sent a datagram to `addr`
+    fn insert(&mut self, addr: SocketAddr, host: Bytes, port: u16) {
+        let now = tokio::time::Instant::now();
+        // Forget the idle peers once per timeout so that a flow sending to
+        // many destinations does not grow without bound
+        if now >= self.next_prune {
+            self.peers.retain(|_, (_, _, expires)| *expires > now);
+            self.next_prune = now + self.timeout;
+        }
+        self.peers.insert(addr, (host, port, now + self.timeout));
+    }
+
+    /// The host and port to label a response from `addr` with,
+    /// if the flow has recently sent to it
+    fn label(&mut self, addr: SocketAddr) -> Option<(Bytes, u16)> {
+        let now = tokio::time::Instant::now();
+        let (host, port, expires) = self
+            .peers
+            .get_mut(&addr)
+            .filter(|(_, _, expires)| *expires > now)?;
+        *expires = now + self.timeout;
+        Some((host.dupe(), *port))
+    }
+}
+
+/// Forward the UDP flow for [`udp_forward_on`].
+/// Returns why it ended if not because of an error.
+async fn udp_forward(
+    first_datagram_frame: Datagram,
+    mut datagram_rx: mpsc::Receiver<Datagram>,
+    datagram_tx: mpsc::Sender<Datagram>,
+    access: &Access,
+    pool: &BufferPool,
+    options: UdpOptions,
+    audit: Option<&FlowAudit>,
+) -> Result<&'static str, Error> {
     trace!("got datagram frame: {first_datagram_frame:?}");
     let Datagram {
         target_host: rhost,
@@ -75,30 +334,61 @@ pub(super) async fn udp_forward_on(
         data,
     } = first_datagram_frame;
     let rhost_str = std::str::from_utf8(&rhost)?;
-    let (socket, target) = bind_for_target((rhost_str, rport)).await?;
+    let (socket, target) = bind_for_target((rhost_str, rport), access).await?;
+    #[cfg(target_os = "linux")]
+    if options.report_errors {
+        enable_recv_err(&socket, target);
+    }
     socket.send_to(&data, target).await?;
     trace!("sent UDP packet to {target}");
-    loop {
+    let mut peers = UdpPeers::new(options.timeout);
+    peers.insert(target, rhost, rport);
+    if let Some(audit) = audit {
+        audit.add_received(data.len());
+    }
+    let mut bytes_sent = 0;
+    let mut bytes_received = data.len() as u64;
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    let reason = loop {
         // Reset this timeout each time we see traffic
-        let this_round_timeout = tokio::time::sleep(config::UDP_PRUNE_TIMEOUT);
-        let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+        let this_round_timeout = tokio::time::sleep(options.timeout);
         tokio::select! {
             // Check if the socket has received a datagram
-            Ok((len, addr)) = socket.recv_from(&mut buf) => {
-                buf.truncate(len);
+            result = recv_from(&socket, &mut buf, options.report_errors) => {
+                let (len, addr) = match result {
+                    Ok(received) => received,
+                    // Such as an ICMP error from one of the destinations
+                    Err(err) if options.report_errors => return Err(err.into()),
+                    Err(err) => {
+                        trace!("ignoring UDP receive error: {err}");
+                        continue;
+                    }
+                };
                 trace!("got UDP response from {addr}");
+                let (target_host, target_port) = if let Some(label) = peers.label(addr) {
+                    label
+                } else if options.full_cone && allows_peer(access, addr) {
+                    (Bytes::from(addr.ip().to_string()), addr.port())
+                } else {
+                    trace!("dropping UDP datagram from unknown peer {addr}");
+                    continue;
+                };
+                if let Some(audit) = audit {
+                    audit.add_sent(len);
+                }
+                bytes_sent += len as u64;
                 let frame = Datagram {
-                    target_host: rhost.dupe(),
-                    target_port: rport,
+                    target_host,
+                    target_port,
                     flow_id,
-                    data: buf.into(),
+                    data: pool.copy_from_slice(&buf[..len]),
                 };
                 if let Err(error) = datagram_tx.try_send(frame) {
                     match error {
                         mpsc::error::TrySendError::Closed(_) => {
                             // The mux loop has exited
                             trace!("UDP forwarder exiting due to closed mux");
-                            break;
+                            break "session closed";
                         }
                         mpsc::error::TrySendError::Full(_) => {
                             // The channel is full, so just discard the datagram
@@ -117,52 +407,373 @@ pub(super) async fn udp_forward_on(
                     datagram_frame.target_port,
                 );
                 trace!("got new datagram frame: {datagram_frame:?} for {target:?}");
-                socket.send_to(&datagram_frame.data, target).await?;
+                match resolve_allowed(access, target).await {
+                    Ok(addrs) => {
+                        socket.send_to(&datagram_frame.data, addrs.as_slice()).await?;
+                        // `send_to` only tries the first address
+                        if let Some(addr) = addrs.first() {
+                            peers.insert(*addr, datagram_frame.target_host.dupe(), datagram_frame.target_port);
+                        }
+                        if let Some(audit) = audit {
+                            audit.add_received(datagram_frame.data.len());
+                        }
+                        bytes_received += datagram_frame.data.len() as u64;
+                    }
+                    // Keep the flow for the destinations that are allowed
+                    Err(Error::Forbidden(..)) => debug!("dropping datagram to {target:?}"),
+                    Err(e) => return Err(e),
+                }
             }
             // Check if the timeout has expired
             () = this_round_timeout => {
                 trace!("UDP prune timeout expired");
-                break;
+                break "idle timeout";
             }
         }
-    }
-    debug!("UDP forwarding finished");
-    Ok(())
+    };
+    debug!(bytes_sent, bytes_received, "UDP forwarding finished");
+    #[cfg(feature = "otel")]
+    crate::otel::stream_finished("udp", bytes_sent, bytes_received);
+    Ok(reason)
+}
+
+/// Whether `access` allows a datagram from `addr` to reach the client of a
+/// full-cone flow, as it would allow the client to send one to `addr`
+fn allows_peer(access: &Access, addr: SocketAddr) -> bool {
+    let host = addr.ip().to_string();
+    access.user_allows(&host, addr.port()) && access.acl.allows(&host, addr)
 }
 
 /// Start a TCP forwarding server on the given listener.
 ///
 /// This forwarder is trivial: it just pipes the TCP stream to and from the
-/// channel.
+/// channel. If the destination is not allowed by `access` or cannot be reached,
+/// the channel is reset with the reason. With `proxy_source`, the stream
+/// starts with a PROXY protocol header from that address. The stream is
+/// logged to `audit` when it closes.
 ///
 /// # Errors
 /// It carries the errors from the underlying TCP or channel IO functions.
-#[tracing::instrument(skip_all, level = "debug")]
-pub(super) async fn tcp_forwarder_on_channel(channel: MuxStream) -> Result<(), Error> {
-    let rhost = std::str::from_utf8(&channel.dest_host)?;
+#[tracing::instrument(skip_all, level = "debug", fields(flow_id = %format_args!("{:08x}", channel.flow_id()), user = access.user_name()))]
+pub(super) async fn tcp_forwarder_on_channel(
+    channel: MuxStream,
+    access: Access,
+    proxy_source: Option<SocketAddr>,
+    audit: Option<FlowAudit>,
+) -> Result<(), Error> {
+    let result = tcp_forward(channel, &access, proxy_source, audit.as_ref()).await;
+    close_audit(audit, result.as_ref().map(|()| "closed"));
+    result
+}
+
+/// Forward the stream for [`tcp_forwarder_on_channel`]
+async fn tcp_forward(
+    channel: MuxStream,
+    access: &Access,
+    proxy_source: Option<SocketAddr>,
+    audit: Option<&FlowAudit>,
+) -> Result<(), Error> {
+    let rhost = channel.dest_host.dupe();
+    let rhost = std::str::from_utf8(&rhost)?;
     let rport = channel.dest_port;
+    #[cfg(unix)]
+    if rhost.starts_with('/') && access.lock.is_none() {
+        return unix_forwarder_on_channel(channel, rhost, access, audit).await;
+    }
     trace!("attempting TCP connect to {rhost} port={rport}");
-    let mut rstream = TcpStream::connect((rhost, rport)).await?;
+    let connected = async {
+        let addrs = resolve_allowed(access, (rhost, rport)).await?;
+        Ok(connect_allowed(access, &addrs).await?)
+    }
+    .instrument(debug_span!("connect", rhost, rport))
+    .await;
+    let mut rstream = match connected {
+        Ok(rstream) => rstream,
+        Err(e) => {
+            channel.reset(reset_reason(&e));
+            return Err(e);
+        }
+    };
     // Here `rstream` should be connected. Pass the error (unlikely) otherwise
-    debug!("TCP forwarding to {}", rstream.peer_addr()?);
-    channel.into_copy_bidirectional(&mut rstream).await?;
-    trace!("TCP forwarding finished");
+    let target = rstream.peer_addr()?;
+    debug!("TCP forwarding to {target}");
+    if let Some(source) = proxy_source {
+        let header = proxy_protocol::make_v2_header(source, target);
+        rstream.write_all(&header).await?;
+    }
+    let (bytes_received, bytes_sent) = copy_bidirectional(channel, &mut rstream, audit).await?;
+    debug!(bytes_sent, bytes_received, "TCP forwarding finished");
+    #[cfg(feature = "otel")]
+    crate::otel::stream_finished("tcp", bytes_sent, bytes_received);
+    Ok(())
+}
+
+/// Pipe a stream to the Unix socket at `path`, which the client sends as the
+/// host of the stream. The socket must be allowed by both the ACL and the user,
+/// to whom it is `PATH:0`.
+#[cfg(unix)]
+async fn unix_forwarder_on_channel(
+    channel: MuxStream,
+    path: &str,
+    access: &Access,
+    audit: Option<&FlowAudit>,
+) -> Result<(), Error> {
+    trace!("attempting Unix socket connect to {path}");
+    let connected = if access.acl.allows_unix(path) && access.user_allows(path, 0) {
+        UnixStream::connect(path).await.map_err(Error::from)
+    } else {
+        Err(Error::Forbidden(path.to_string(), 0))
+    };
+    let mut rstream = match connected {
+        Ok(rstream) => rstream,
+        Err(e) => {
+            channel.reset(reset_reason(&e));
+            return Err(e);
+        }
+    };
+    debug!("Unix socket forwarding to {path}");
+    let (bytes_received, bytes_sent) = copy_bidirectional(channel, &mut rstream, audit).await?;
+    debug!(
+        bytes_sent,
+        bytes_received, "Unix socket forwarding finished"
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::stream_finished("unix", bytes_sent, bytes_received);
     Ok(())
 }
 
+/// Pipe `channel` and `rstream` together, counting the octets for `audit`.
+/// Returns the octets received from and sent to the client.
+pub(super) async fn copy_bidirectional<S>(
+    channel: MuxStream,
+    rstream: S,
+    audit: Option<&FlowAudit>,
+) -> Result<(u64, u64), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    Ok(match audit {
+        Some(audit) => {
+            channel
+                .into_copy_bidirectional(audit.count(rstream))
+                .await?
+        }
+        None => channel.into_copy_bidirectional(rstream).await?,
+    })
+}
+
+/// Log the flow of `audit` as closed for the reason in `result`
+pub(super) fn close_audit(audit: Option<FlowAudit>, result: Result<&'static str, &Error>) {
+    let Some(audit) = audit else {
+        return;
+    };
+    match result {
+        Ok(reason) => audit.close(reason),
+        Err(Error::Forbidden(..)) => audit.close("forbidden"),
+        Err(err) => audit.close(format!("error: {err}")),
+    }
+}
+
+/// Why a stream is reset when its destination cannot be connected to
+const fn reset_reason(error: &Error) -> ResetReason {
+    match error {
+        Error::Forbidden(..) => ResetReason::Forbidden,
+        _ => ResetReason::Unreachable,
+    }
+}
+
+/// Accept TCP connections on a listener opened for a client's reverse remote
+/// and tunnel each of them back to the client.
+///
+/// Streams are requested with the host and port of the original `Bind` request
+/// so that the client can tell which reverse remote they belong to, and they
+/// are logged to `audit` with that target when they close.
+///
+/// # Errors
+/// It carries the errors from accepting on the listener.
+#[tracing::instrument(skip(listener, mux, audit), level = "debug")]
+pub(super) async fn tcp_reverse_forwarder_on_listener(
+    listener: TcpListener,
+    mux: Arc<Multiplexor>,
+    bind_host: Bytes,
+    bind_port: u16,
+    audit: Option<SessionAudit>,
+) -> Result<(), Error> {
+    // Dropping this `JoinSet` when the session ends aborts all forwarders
+    let mut forwarders = JoinSet::new();
+    loop {
+        tokio::select! {
+            Some(_) = forwarders.join_next() => {}
+            result = listener.accept() => {
+                let (mut tcp_stream, peer_addr) = result?;
+                debug!("reverse connection from {peer_addr}");
+                let mux = mux.dupe();
+                let bind_host = bind_host.dupe();
+                let audit = audit.as_ref().map(Dupe::dupe);
+                forwarders.spawn(async move {
+                    let result = async {
+                        let channel = mux.new_stream_channel(&bind_host, bind_port).await?;
+                        let flow = audit.map(|audit| {
+                            audit.flow("tcp-reverse", channel.flow_id(), &bind_host, bind_port)
+                        });
+                        let result = copy_bidirectional(channel, &mut tcp_stream, flow.as_ref()).await;
+                        close_audit(flow, result.as_ref().map(|_| "closed"));
+                        result.map(|_| ())
+                    }
+                    .await;
+                    // Transient errors in the forwarder don't matter.
+                    if let Err(error) = result {
+                        warn!("Reverse TCP forwarder failed: {error}");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Flows of the reverse UDP remotes of a session by the flow IDs we chose,
+/// to route the replies of the client to the socket of their remote
+pub(super) type ReverseUdpFlows = Arc<Mutex<IntMap<u32, mpsc::Sender<Datagram>>>>;
+
+/// A peer that sent to the socket of a reverse UDP remote
+#[derive(Debug)]
+struct ReverseUdpPeer {
+    addr: SocketAddr,
+    /// When the flow should be removed
+    expires: tokio::time::Instant,
+    audit: Option<FlowAudit>,
+}
+
+/// Relay datagrams between the peers of a socket opened for a client's
+/// reverse UDP remote and the client.
+///
+/// Each peer gets its own flow ID, registered in `flows` so that the replies
+/// of the client find their way back here. Datagrams are sent to the client
+/// with the host and port of the original `Bind` request, and each flow is
+/// logged to `audit` when it has seen no traffic for `timeout`.
+///
+/// # Errors
+/// Does not fail; errors of the peers are logged.
+#[tracing::instrument(skip(socket, flows, datagram_tx, pool, audit), level = "debug")]
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn udp_reverse_forwarder_on_socket(
+    socket: UdpSocket,
+    bind_host: Bytes,
+    bind_port: u16,
+    flows: ReverseUdpFlows,
+    datagram_tx: mpsc::Sender<Datagram>,
+    pool: BufferPool,
+    timeout: Duration,
+    audit: Option<SessionAudit>,
+) -> Result<(), Error> {
+    let (reply_tx, mut reply_rx) = mpsc::channel(config::INCOMING_DATAGRAM_BUFFER_SIZE);
+    let mut flow_ids: HashMap<SocketAddr, u32> = HashMap::new();
+    let mut peers: IntMap<u32, ReverseUdpPeer> = IntMap::default();
+    let mut prune = tokio::time::interval(timeout);
+    prune.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    loop {
+        tokio::select! {
+            result = socket.recv_from(&mut buf) => {
+                let (len, addr) = match result {
+                    Ok(received) => received,
+                    Err(err) => {
+                        trace!("ignoring UDP receive error: {err}");
+                        continue;
+                    }
+                };
+                let flow_id = *flow_ids.entry(addr).or_insert_with(|| {
+                    let flow_id = {
+                        let mut flows = flows.lock();
+                        let flow_id = u32::next_available_key(&flows);
+                        flows.insert(flow_id, reply_tx.dupe());
+                        flow_id
+                    };
+                    debug!("reverse UDP flow {flow_id:08x} from {addr}");
+                    let audit = audit
+                        .as_ref()
+                        .map(|audit| audit.flow("udp-reverse", flow_id, &bind_host, bind_port));
+                    peers.insert(flow_id, ReverseUdpPeer {
+                        addr,
+                        expires: tokio::time::Instant::now(),
+                        audit,
+                    });
+                    flow_id
+                });
+                // `expect`: both maps are updated together
+                let peer = peers
+                    .get_mut(&flow_id)
+                    .expect("`flow_ids` and `peers` are inconsistent (this is a bug)");
+                peer.expires = tokio::time::Instant::now() + timeout;
+                if let Some(audit) = &peer.audit {
+                    audit.add_sent(len);
+                }
+                let frame = Datagram {
+                    target_host: bind_host.dupe(),
+                    target_port: bind_port,
+                    flow_id,
+                    data: pool.copy_from_slice(&buf[..len]),
+                };
+                if let Err(error) = datagram_tx.try_send(frame) {
+                    match error {
+                        mpsc::error::TrySendError::Closed(_) => {
+                            trace!("reverse UDP forwarder exiting due to closed mux");
+                            return Ok(());
+                        }
+                        mpsc::error::TrySendError::Full(_) => {
+                            debug!("reverse UDP forwarder channel is full");
+                        }
+                    }
+                }
+            }
+            // Never `None` since we hold `reply_tx`
+            Some(datagram_frame) = reply_rx.recv() => {
+                let Some(peer) = peers.get_mut(&datagram_frame.flow_id) else {
+                    trace!("dropping reply to an expired reverse UDP flow");
+                    continue;
+                };
+                if let Err(err) = socket.send_to(&datagram_frame.data, peer.addr).await {
+                    debug!("cannot send to reverse UDP peer {}: {err}", peer.addr);
+                    continue;
+                }
+                peer.expires = tokio::time::Instant::now() + timeout;
+                if let Some(audit) = &peer.audit {
+                    audit.add_received(datagram_frame.data.len());
+                }
+            }
+            _ = prune.tick() => {
+                let now = tokio::time::Instant::now();
+                peers.retain(|flow_id, peer| {
+                    if peer.expires > now {
+                        return true;
+                    }
+                    trace!("reverse UDP flow {flow_id:08x} expired");
+                    flow_ids.remove(&peer.addr);
+                    flows.lock().remove(flow_id);
+                    if let Some(audit) = peer.audit.take() {
+                        audit.close("idle timeout");
+                    }
+                    false
+                });
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bytes::Bytes;
+    use crate::acl::Acl;
 
     #[tokio::test]
     async fn test_bind_and_send_v4() {
         crate::tests::setup_logging();
         let target_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
         let target_addr = target_sock.local_addr().unwrap();
-        let (socket, target) = bind_for_target(("127.0.0.1", target_addr.port()))
-            .await
-            .unwrap();
+        let (socket, target) =
+            bind_for_target(("127.0.0.1", target_addr.port()), &Access::default())
+                .await
+                .unwrap();
         assert_eq!(target, target_addr);
         socket.send_to(b"hello", target).await.unwrap();
         let mut buf = vec![0; 5];
@@ -175,12 +786,37 @@ mod tests {
         assert_eq!(buf, b"world");
     }
 
+    // Linux routes all of 127.0.0.0/8 to the loopback interface
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_egress_addr() {
+        crate::tests::setup_logging();
+        let source: IpAddr = "127.0.0.2".parse().unwrap();
+        let access = Access {
+            egress: Arc::new(super::super::egress::Egress::new(&[source], &[]).unwrap()),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let _stream = connect_allowed(&access, &[target]).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer.ip(), source);
+        let target_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let target_addr = target_sock.local_addr().unwrap();
+        let (socket, _) = bind_for_target(("127.0.0.1", target_addr.port()), &access)
+            .await
+            .unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), source);
+    }
+
     #[tokio::test]
     async fn test_bind_and_send_v6() {
         crate::tests::setup_logging();
         let target_sock = UdpSocket::bind(("::1", 0)).await.unwrap();
         let target_addr = target_sock.local_addr().unwrap();
-        let (socket, target) = bind_for_target(("::1", target_addr.port())).await.unwrap();
+        let (socket, target) = bind_for_target(("::1", target_addr.port()), &Access::default())
+            .await
+            .unwrap();
         assert_eq!(target, target_addr);
         socket.send_to(b"hello", target).await.unwrap();
         let mut buf = vec![0; 5];
@@ -207,7 +843,15 @@ mod tests {
             data: Bytes::from_static(b"hello"),
         };
         drop(send_tx);
-        let forwarder = tokio::spawn(udp_forward_on(datagram_frame, send_rx, recv_tx));
+        let forwarder = tokio::spawn(udp_forward_on(
+            datagram_frame,
+            send_rx,
+            recv_tx,
+            Access::default(),
+            BufferPool::new(),
+            UdpOptions::default(),
+            None,
+        ));
         let mut buf = vec![0; 5];
         let (len, addr) = target_sock.recv_from(&mut buf).await.unwrap();
         assert_eq!(len, 5);
@@ -238,7 +882,15 @@ mod tests {
             data: Bytes::from_static(b"hello"),
         };
         drop(send_tx);
-        let forwarder = tokio::spawn(udp_forward_on(datagram_frame, send_rx, recv_tx));
+        let forwarder = tokio::spawn(udp_forward_on(
+            datagram_frame,
+            send_rx,
+            recv_tx,
+            Access::default(),
+            BufferPool::new(),
+            UdpOptions::default(),
+            None,
+        ));
         let mut buf = vec![0; 5];
         let (len, addr) = target_sock.recv_from(&mut buf).await.unwrap();
         assert_eq!(len, 5);
@@ -254,4 +906,254 @@ mod tests {
         let datagram_frame = recv_rx.recv().await.unwrap();
         assert_eq!(*datagram_frame.data, *b"test 3");
     }
+
+    /// Send a datagram to a target through a flow with `options`, then
+    /// answer it from both the target and a stranger. Returns the frames
+    /// relayed to the client and the address of the stranger.
+    async fn udp_forward_with_stranger(options: UdpOptions) -> (Vec<Datagram>, SocketAddr) {
+        let target_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let target_addr = target_sock.local_addr().unwrap();
+        let stranger_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let (recv_tx, mut recv_rx) = tokio::sync::mpsc::channel(4);
+        let (send_tx, send_rx) = tokio::sync::mpsc::channel(4);
+        let datagram_frame = Datagram {
+            flow_id: 0,
+            target_host: Bytes::from_static(b"127.0.0.1"),
+            target_port: target_addr.port(),
+            data: Bytes::from_static(b"hello"),
+        };
+        drop(send_tx);
+        let forwarder = tokio::spawn(udp_forward_on(
+            datagram_frame,
+            send_rx,
+            recv_tx,
+            Access::default(),
+            BufferPool::new(),
+            options,
+            None,
+        ));
+        let mut buf = vec![0; 5];
+        let (_, addr) = target_sock.recv_from(&mut buf).await.unwrap();
+        stranger_sock.send_to(b"stranger", addr).await.unwrap();
+        target_sock.send_to(b"target", addr).await.unwrap();
+        forwarder.await.unwrap().unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = recv_rx.recv().await {
+            frames.push(frame);
+        }
+        (frames, stranger_sock.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_udp_forward_restricted() {
+        crate::tests::setup_logging();
+        let options = UdpOptions {
+            timeout: Duration::from_millis(500),
+            full_cone: false,
+            ..UdpOptions::default()
+        };
+        let (frames, _) = udp_forward_with_stranger(options).await;
+        assert_eq!(frames.len(), 1);
+        assert_eq!(*frames[0].data, *b"target");
+        assert_eq!(*frames[0].target_host, *b"127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_udp_forward_full_cone() {
+        crate::tests::setup_logging();
+        let options = UdpOptions {
+            timeout: Duration::from_millis(500),
+            full_cone: true,
+            ..UdpOptions::default()
+        };
+        let (frames, stranger_addr) = udp_forward_with_stranger(options).await;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(*frames[0].data, *b"stranger");
+        assert_eq!(*frames[0].target_host, *b"127.0.0.1");
+        assert_eq!(frames[0].target_port, stranger_addr.port());
+        assert_eq!(*frames[1].data, *b"target");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_udp_forward_report_errors() {
+        crate::tests::setup_logging();
+        // Nothing listens on this port once the socket is dropped
+        let closed_port = UdpSocket::bind(("127.0.0.1", 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (recv_tx, mut recv_rx) = tokio::sync::mpsc::channel(4);
+        let (_send_tx, send_rx) = tokio::sync::mpsc::channel(4);
+        let datagram_frame = Datagram {
+            flow_id: 42,
+            target_host: Bytes::from_static(b"127.0.0.1"),
+            target_port: closed_port,
+            data: Bytes::from_static(b"hello"),
+        };
+        let options = UdpOptions {
+            report_errors: true,
+            ..UdpOptions::default()
+        };
+        let result = udp_forward_on(
+            datagram_frame,
+            send_rx,
+            recv_tx,
+            Access::default(),
+            BufferPool::new(),
+            options,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Io(_))));
+        let frame = recv_rx.recv().await.unwrap();
+        assert_eq!(*frame.target_host, *UDP_ERROR_HOST);
+        assert_eq!(frame.flow_id, 42);
+    }
+
+    #[tokio::test]
+    async fn test_udp_reverse_forwarder() {
+        crate::tests::setup_logging();
+        let socket = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let bind_addr = socket.local_addr().unwrap();
+        let flows = ReverseUdpFlows::default();
+        let (datagram_tx, mut datagram_rx) = mpsc::channel(4);
+        let forwarder = tokio::spawn(udp_reverse_forwarder_on_socket(
+            socket,
+            Bytes::from_static(b"127.0.0.1"),
+            bind_addr.port(),
+            flows.dupe(),
+            datagram_tx,
+            BufferPool::new(),
+            Duration::from_millis(300),
+            None,
+        ));
+        let peer_1 = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let peer_2 = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        peer_1.send_to(b"hello 1", bind_addr).await.unwrap();
+        let frame_1 = datagram_rx.recv().await.unwrap();
+        peer_2.send_to(b"hello 2", bind_addr).await.unwrap();
+        let frame_2 = datagram_rx.recv().await.unwrap();
+        assert_eq!(*frame_1.data, *b"hello 1");
+        assert_eq!(*frame_1.target_host, *b"127.0.0.1");
+        assert_eq!(frame_1.target_port, bind_addr.port());
+        assert_ne!(frame_1.flow_id, frame_2.flow_id);
+        // Replies of the client go to the peer of their flow
+        let sender = flows.lock().get(&frame_2.flow_id).unwrap().dupe();
+        sender
+            .send(Datagram {
+                data: Bytes::from_static(b"reply 2"),
+                ..frame_2
+            })
+            .await
+            .unwrap();
+        let mut buf = [0; 16];
+        let (len, addr) = peer_2.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"reply 2");
+        assert_eq!(addr, bind_addr);
+        // Idle flows are forgotten
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(flows.lock().is_empty());
+        forwarder.abort();
+    }
+
+    #[tokio::test]
+    async fn test_udp_forward_denied() {
+        crate::tests::setup_logging();
+        let target_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let target_addr = target_sock.local_addr().unwrap();
+        let (recv_tx, _recv_rx) = tokio::sync::mpsc::channel(4);
+        let (_send_tx, send_rx) = tokio::sync::mpsc::channel(4);
+        let datagram_frame = Datagram {
+            flow_id: 0,
+            target_host: Bytes::from_static(b"127.0.0.1"),
+            target_port: target_addr.port(),
+            data: Bytes::from_static(b"hello"),
+        };
+        let acl = Acl::new(Vec::new(), vec!["127.0.0.0/8".parse().unwrap()]);
+        let result = udp_forward_on(
+            datagram_frame,
+            send_rx,
+            recv_tx,
+            acl.into(),
+            BufferPool::new(),
+            UdpOptions::default(),
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Forbidden(_, port)) if port == target_addr.port()));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_rewritten() {
+        crate::tests::setup_logging();
+        let rewrites = super::super::rewrite::Rewrites::parse(
+            r#"[{"match": "web.invalid", "host": "127.0.0.1", "port": 8080}]"#,
+        )
+        .unwrap();
+        let access = Access {
+            rewrites: Arc::new(rewrites),
+            ..Default::default()
+        };
+        let addrs = resolve_allowed(&access, ("web.invalid", 80)).await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:8080".parse().unwrap()]);
+        // The ACL applies to the rewritten target
+        let access = Access {
+            acl: Arc::new(Acl::new(Vec::new(), vec!["127.0.0.0/8".parse().unwrap()])),
+            ..access
+        };
+        assert!(matches!(
+            resolve_allowed(&access, ("web.invalid", 80)).await,
+            Err(Error::Forbidden(_, 8080))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_locked() {
+        crate::tests::setup_logging();
+        let access = Access {
+            lock: Some(Arc::new("127.0.0.1:2222".parse().unwrap())),
+            ..Default::default()
+        };
+        let addrs = resolve_allowed(&access, ("example.com", 80)).await.unwrap();
+        assert_eq!(addrs, ["127.0.0.1:2222".parse().unwrap()]);
+        // Users are checked against the locked target
+        let users = super::super::auth::Users::parse(r#"{"foo:bar": ["example.com:80"]}"#).unwrap();
+        let foo = users
+            .authenticate(Some(&http::HeaderValue::from_static("Basic Zm9vOmJhcg==")))
+            .unwrap();
+        let access = Access {
+            user: Some(foo),
+            ..access
+        };
+        assert!(matches!(
+            resolve_allowed(&access, ("example.com", 80)).await,
+            Err(Error::Forbidden(_, 2222))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_udp_peers_expire() {
+        crate::tests::setup_logging();
+        let timeout = Duration::from_secs(10);
+        let mut peers = UdpPeers::new(timeout);
+        let first: SocketAddr = "127.0.0.1:1000".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:2000".parse().unwrap();
+        peers.insert(first, Bytes::from_static(b"first"), 1000);
+        assert_eq!(
+            peers.label(first),
+            Some((Bytes::from_static(b"first"), 1000))
+        );
+        tokio::time::advance(timeout).await;
+        assert_eq!(peers.label(first), None);
+        // Idle peers are dropped when the next one is added
+        peers.insert(second, Bytes::from_static(b"second"), 2000);
+        assert_eq!(peers.peers.len(), 1);
+        assert_eq!(
+            peers.label(second),
+            Some((Bytes::from_static(b"second"), 2000))
+        );
+    }
 }
@@ -0,0 +1,325 @@
+//! Authentication with JSON Web Tokens.
+//!
+//! Clients send `Authorization: Bearer <jwt>`. Tokens are signed with a shared
+//! HMAC secret (`HS256`, `HS384` or `HS512`) or with a key from a JWKS URL
+//! (RSA, ECDSA or Ed25519), and must have the `exp` and `sub` claims. The
+//! optional `remotes` claim lists the remotes the client may use, in the same
+//! format as the auth file. Sessions are closed when their token expires.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::auth::User;
+use crate::config;
+use crate::tls::{HyperConnector, make_hyper_connector};
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http::{HeaderValue, StatusCode, Uri};
+use http_body_util::{BodyExt, Empty};
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+/// Errors when fetching the JWKS
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot create HTTP client: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Cannot fetch JWKS: {0}")]
+    Request(#[from] hyper_util::client::legacy::Error),
+    #[error("Cannot fetch JWKS: server returned {0}")]
+    Status(StatusCode),
+    #[error("Cannot fetch JWKS: {0}")]
+    Body(#[from] hyper::Error),
+    #[error("Invalid JWKS: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Algorithms accepted with a shared secret
+const HMAC_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+/// Claims we care about. `exp` is checked by `jsonwebtoken`.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+    remotes: Option<Vec<String>>,
+}
+
+/// Where the verification keys come from
+enum Keys {
+    Secret(DecodingKey),
+    /// Refreshed in the background
+    Jwks(Arc<ArcSwap<JwkSet>>),
+}
+
+/// Verifies the bearer tokens of clients
+pub struct JwtVerifier {
+    keys: Keys,
+    /// Accepted `aud` claims. Not checked if empty.
+    audience: Vec<String>,
+}
+
+impl std::fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Skip the keys
+        f.debug_struct("JwtVerifier")
+            .field("audience", &self.audience)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtVerifier {
+    /// Verify tokens signed with a shared secret
+    pub fn from_secret(secret: &str, audience: Vec<String>) -> Self {
+        Self {
+            keys: Keys::Secret(DecodingKey::from_secret(secret.as_bytes())),
+            audience,
+        }
+    }
+
+    /// Verify tokens signed with the keys at `url`, which are fetched now and
+    /// refreshed every [`config::JWKS_REFRESH_INTERVAL`].
+    pub async fn from_jwks_url(url: Uri, audience: Vec<String>) -> Result<Self, Error> {
+        let client = HyperClient::builder(TokioExecutor::new()).build(make_hyper_connector()?);
+        let jwks = Arc::new(ArcSwap::from_pointee(fetch_jwks(&client, &url).await?));
+        let weak = Arc::downgrade(&jwks);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(config::JWKS_REFRESH_INTERVAL).await;
+                let keys = fetch_jwks(&client, &url).await;
+                // Stop once the verifier is dropped
+                let Some(jwks) = weak.upgrade() else {
+                    break;
+                };
+                match keys {
+                    Ok(keys) => jwks.store(Arc::new(keys)),
+                    Err(err) => warn!("Keeping the old keys: {err}"),
+                }
+            }
+        });
+        Ok(Self {
+            keys: Keys::Jwks(jwks),
+            audience,
+        })
+    }
+
+    /// Find the user of the token in an `Authorization: Bearer` header
+    pub fn authenticate(&self, authorization: Option<&HeaderValue>) -> Option<Arc<User>> {
+        let token = authorization?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?
+            .trim();
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let (key, mut validation) = match &self.keys {
+            Keys::Secret(key) => {
+                let mut validation = Validation::new(header.alg);
+                validation.algorithms = HMAC_ALGORITHMS.to_vec();
+                (key.clone(), validation)
+            }
+            Keys::Jwks(jwks) => {
+                let jwks = jwks.load();
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid)?,
+                    None if jwks.keys.len() == 1 => &jwks.keys[0],
+                    None => return None,
+                };
+                // `jsonwebtoken` checks that the algorithm suits the type of
+                // the key, but the key may also be limited to one algorithm
+                let key_algorithm = jwk.common.key_algorithm.map(|alg| alg.to_string());
+                if key_algorithm.is_some_and(|alg| alg.parse().ok() != Some(header.alg)) {
+                    debug!("Rejecting token: algorithm does not match the key");
+                    return None;
+                }
+                (
+                    DecodingKey::from_jwk(jwk).ok()?,
+                    Validation::new(header.alg),
+                )
+            }
+        };
+        if self.audience.is_empty() {
+            validation.set_required_spec_claims(&["exp", "sub"]);
+            validation.validate_aud = false;
+        } else {
+            validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+            validation.set_audience(&self.audience);
+        }
+        let claims = match jsonwebtoken::decode::<Claims>(token, &key, &validation) {
+            Ok(data) => data.claims,
+            Err(err) => {
+                debug!("Rejecting token: {err}");
+                return None;
+            }
+        };
+        let patterns = claims.remotes.unwrap_or_else(|| vec!["*".to_string()]);
+        let user = User::new(&claims.sub, &patterns)
+            .inspect_err(|err| debug!("Rejecting token: {err}"))
+            .ok()?
+            .with_expiry(UNIX_EPOCH + Duration::from_secs(claims.exp));
+        Some(Arc::new(user))
+    }
+}
+
+/// Fetch the keys at `url`
+async fn fetch_jwks(
+    client: &HyperClient<HyperConnector, Empty<Bytes>>,
+    url: &Uri,
+) -> Result<JwkSet, Error> {
+    let resp = client.get(url.clone()).await?;
+    if !resp.status().is_success() {
+        return Err(Error::Status(resp.status()));
+    }
+    let body = resp.into_body().collect().await?.to_bytes();
+    debug!("fetched JWKS from {url}");
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::time::SystemTime;
+
+    const SECRET: &str = "some-secret";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn bearer(header: &Header, claims: &serde_json::Value, secret: &str) -> HeaderValue {
+        let key = EncodingKey::from_secret(secret.as_bytes());
+        let token = jsonwebtoken::encode(header, claims, &key).unwrap();
+        HeaderValue::from_str(&format!("Bearer {token}")).unwrap()
+    }
+
+    #[test]
+    fn test_jwt_secret() {
+        crate::tests::setup_logging();
+        let verifier = JwtVerifier::from_secret(SECRET, Vec::new());
+        let header = Header::default();
+        let claims = serde_json::json!({
+            "sub": "alice",
+            "exp": now() + 600,
+            "remotes": ["localhost:22"],
+        });
+        let user = verifier
+            .authenticate(Some(&bearer(&header, &claims, SECRET)))
+            .unwrap();
+        assert_eq!(user.name(), "alice");
+        assert!(user.allows("localhost", 22));
+        assert!(!user.allows("localhost", 80));
+        assert!(user.expires_in().unwrap() > Duration::from_secs(590));
+        // Without `remotes`, the user may use any remote
+        let claims = serde_json::json!({ "sub": "bob", "exp": now() + 600 });
+        let user = verifier
+            .authenticate(Some(&bearer(
+                &Header::new(Algorithm::HS512),
+                &claims,
+                SECRET,
+            )))
+            .unwrap();
+        assert!(user.allows("localhost", 80));
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, "wrong")))
+                .is_none()
+        );
+        let expired = serde_json::json!({ "sub": "bob", "exp": now() - 3600 });
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &expired, SECRET)))
+                .is_none()
+        );
+        let no_sub = serde_json::json!({ "exp": now() + 600 });
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &no_sub, SECRET)))
+                .is_none()
+        );
+        let basic = HeaderValue::from_static("Basic Zm9vOmJhcg==");
+        assert!(verifier.authenticate(Some(&basic)).is_none());
+        assert!(verifier.authenticate(None).is_none());
+    }
+
+    #[test]
+    fn test_jwt_audience() {
+        crate::tests::setup_logging();
+        let verifier = JwtVerifier::from_secret(SECRET, vec!["penguin".to_string()]);
+        let header = Header::default();
+        let claims = serde_json::json!({ "sub": "alice", "exp": now() + 600, "aud": "penguin" });
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, SECRET)))
+                .is_some()
+        );
+        let claims = serde_json::json!({ "sub": "alice", "exp": now() + 600, "aud": "other" });
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, SECRET)))
+                .is_none()
+        );
+        let claims = serde_json::json!({ "sub": "alice", "exp": now() + 600 });
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, SECRET)))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_jwt_jwks_key_selection() {
+        crate::tests::setup_logging();
+        // "some-secret" in URL-safe base64
+        let jwks: JwkSet = serde_json::from_value(serde_json::json!({
+            "keys": [
+                { "kty": "oct", "kid": "one", "alg": "HS256", "k": "c29tZS1zZWNyZXQ" },
+                { "kty": "oct", "kid": "two", "k": "b3RoZXI" },
+            ]
+        }))
+        .unwrap();
+        let verifier = JwtVerifier {
+            keys: Keys::Jwks(Arc::new(ArcSwap::from_pointee(jwks))),
+            audience: Vec::new(),
+        };
+        let claims = serde_json::json!({ "sub": "alice", "exp": now() + 600 });
+        let mut header = Header {
+            kid: Some("one".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, SECRET)))
+                .is_some()
+        );
+        // Signed with a different key than the one named
+        header.kid = Some("two".to_string());
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, SECRET)))
+                .is_none()
+        );
+        // The key is limited to another algorithm
+        let mut header = Header::new(Algorithm::HS384);
+        header.kid = Some("one".to_string());
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&header, &claims, SECRET)))
+                .is_none()
+        );
+        // Which key to use is ambiguous
+        assert!(
+            verifier
+                .authenticate(Some(&bearer(&Header::default(), &claims, SECRET)))
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Making the responses that are not for the tunnel look like they come from
+//! another web server.
+//!
+//! With `--masquerade`, every response gets the `Server` header (and any
+//! other header the server is known to send) of the chosen server, and
+//! the error responses penguin makes itself get its error pages instead of
+//! plain text, so that fingerprinting them does not reveal a hyper stack.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::Bytes;
+use http::{HeaderMap, HeaderValue, StatusCode, header};
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Error for unknown server names
+#[derive(Debug, Error)]
+#[error("unknown server to masquerade as: {0} (expected `nginx`, `iis` or `caddy`)")]
+pub struct MasqueradeError(String);
+
+/// A web server to look like
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Masquerade {
+    Nginx,
+    /// Microsoft IIS 10 serving ASP.NET
+    Iis,
+    Caddy,
+}
+
+impl FromStr for Masquerade {
+    type Err = MasqueradeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nginx" => Ok(Self::Nginx),
+            "iis" => Ok(Self::Iis),
+            "caddy" => Ok(Self::Caddy),
+            _ => Err(MasqueradeError(s.to_string())),
+        }
+    }
+}
+
+impl Masquerade {
+    const fn server(self) -> &'static str {
+        match self {
+            Self::Nginx => "nginx",
+            Self::Iis => "Microsoft-IIS/10.0",
+            Self::Caddy => "Caddy",
+        }
+    }
+
+    /// `Content-Type` of the error pages. Caddy sends empty ones without it.
+    const fn error_content_type(self) -> Option<&'static str> {
+        match self {
+            Self::Nginx | Self::Iis => Some("text/html"),
+            Self::Caddy => None,
+        }
+    }
+
+    /// Set the headers the server would send on a response with `status`
+    pub fn set_headers(self, status: StatusCode, headers: &mut HeaderMap) {
+        headers.insert(header::SERVER, HeaderValue::from_static(self.server()));
+        if self == Self::Iis {
+            headers.insert("x-powered-by", HeaderValue::from_static("ASP.NET"));
+        }
+        let is_error_page =
+            status.is_redirection() || status.is_client_error() || status.is_server_error();
+        if let Some(content_type) = self.error_content_type()
+            && is_error_page
+            && !headers.contains_key(header::CONTENT_TYPE)
+        {
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+    }
+
+    /// Body of the error page the server would send with `status`
+    pub fn error_body(self, status: StatusCode) -> Bytes {
+        let code = status.as_u16();
+        let reason = status.canonical_reason().unwrap_or_default();
+        match self {
+            Self::Nginx => format!(
+                "<html>\r\n<head><title>{code} {reason}</title></head>\r\n<body>\r\n\
+                 <center><h1>{code} {reason}</h1></center>\r\n\
+                 <hr><center>nginx</center>\r\n</body>\r\n</html>\r\n"
+            )
+            .into(),
+            Self::Iis => format!(
+                "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \
+                 \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">\r\n\
+                 <html xmlns=\"http://www.w3.org/1999/xhtml\">\r\n<head>\r\n\
+                 <meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\"/>\r\n\
+                 <title>{code} - {reason}</title>\r\n</head>\r\n<body>\r\n\
+                 <div id=\"header\"><h1>Server Error</h1></div>\r\n\
+                 <div id=\"content\">\r\n <div class=\"content-container\"><fieldset>\r\n  \
+                 <h2>{code} - {reason}.</h2>\r\n </fieldset></div>\r\n</div>\r\n\
+                 </body>\r\n</html>\r\n"
+            )
+            .into(),
+            Self::Caddy => Bytes::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masquerade() {
+        crate::tests::setup_logging();
+        assert_eq!("NGINX".parse::<Masquerade>().unwrap(), Masquerade::Nginx);
+        assert!("apache".parse::<Masquerade>().is_err());
+
+        let mut headers = HeaderMap::new();
+        Masquerade::Nginx.set_headers(StatusCode::NOT_FOUND, &mut headers);
+        assert_eq!(headers[header::SERVER], "nginx");
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+        let body = Masquerade::Nginx.error_body(StatusCode::NOT_FOUND);
+        assert!(body.starts_with(b"<html>\r\n<head><title>404 Not Found</title>"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        Masquerade::Iis.set_headers(StatusCode::OK, &mut headers);
+        assert_eq!(headers[header::SERVER], "Microsoft-IIS/10.0");
+        assert_eq!(headers["x-powered-by"], "ASP.NET");
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+
+        let mut headers = HeaderMap::new();
+        Masquerade::Caddy.set_headers(StatusCode::NOT_FOUND, &mut headers);
+        assert_eq!(headers[header::SERVER], "Caddy");
+        assert!(!headers.contains_key(header::CONTENT_TYPE));
+        assert!(
+            Masquerade::Caddy
+                .error_body(StatusCode::NOT_FOUND)
+                .is_empty()
+        );
+    }
+}
@@ -2,32 +2,72 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+mod access_log;
 #[cfg(feature = "acme")]
 pub mod acme;
+mod admin;
+mod audit_log;
+mod auth;
+#[cfg(feature = "chisel")]
+mod chisel;
+mod client_cert;
+mod dns;
+mod egress;
+mod endpoint;
 mod forwarder;
+mod jwt;
+pub mod masquerade;
+pub mod ocsp;
+#[cfg(unix)]
+mod privileges;
+mod proxy_protocol;
+#[cfg(feature = "quic")]
+mod quic;
+mod rate_limit;
+mod raw;
+mod resolver;
+mod rewrite;
+#[cfg(target_os = "linux")]
+mod sandbox;
 mod service;
+mod static_files;
+#[cfg(unix)]
+mod systemd;
 mod websocket;
 
+use self::access_log::AccessLog;
+use self::admin::{Admin, Registry};
+use self::audit_log::AuditLog;
+use self::auth::{Authenticator, Users};
+use self::client_cert::{ClientCert, ClientCertRules};
+use self::egress::Egress;
+use self::endpoint::Endpoints;
+use self::jwt::JwtVerifier;
+use self::rate_limit::RateLimiter;
+use self::resolver::Resolver;
+use self::rewrite::Rewrites;
 use self::service::State;
+use crate::acl::{Acl, IpNetwork};
 use crate::arg::ServerArgs;
-#[cfg(unix)]
-use crate::tls::reload_tls_identity;
-use crate::tls::{TlsIdentity, TlsIdentityInner, make_tls_identity};
-use hyper::upgrade::Upgraded;
+use crate::config;
+use crate::tls::{TlsIdentity, TlsIdentityInner, make_tls_identity, reload_tls_identity};
+use http::StatusCode;
 use hyper_util::rt::TokioIo;
 use hyper_util::rt::tokio::TokioExecutor;
 use hyper_util::server::conn::auto;
 use penguin_mux::Dupe;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::SystemTime;
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::task::JoinSet;
-use tokio_tungstenite::WebSocketStream;
-use tracing::{debug, error, info, trace};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, trace, warn};
 
-type WebSocket = WebSocketStream<TokioIo<Upgraded>>;
+/// A `WebSocket` or the plain transport
+type WebSocket = Box<dyn penguin_mux::ws::WebSocket>;
 
 /// Server Errors
 #[derive(Debug, Error)]
@@ -41,35 +81,92 @@ pub enum Error {
     Signal(std::io::Error),
     #[error("HTTP server I/O error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Cannot read the 404 response file: {0}")]
+    NotFoundResp(std::io::Error),
+    #[error("The admin token is not a valid header value")]
+    AdminToken,
+    #[error("Cannot open the access log: {0}")]
+    AccessLog(std::io::Error),
+    #[error("Cannot open the audit log: {0}")]
+    AuditLog(std::io::Error),
     #[error("TLS error: {0}")]
     #[cfg(feature = "nativetls")]
     NativeTls(#[from] tokio_native_tls::native_tls::Error),
     #[cfg(feature = "acme")]
     #[error(transparent)]
     Acme(#[from] acme::Error),
+    #[error(transparent)]
+    Auth(#[from] auth::Error),
+    #[error(transparent)]
+    Jwt(#[from] jwt::Error),
+    #[error("Cannot set up the DNS upstream: {0}")]
+    Resolver(#[from] resolver::Error),
+    #[error(transparent)]
+    ClientCert(#[from] client_cert::Error),
+    #[error(transparent)]
+    Endpoint(#[from] endpoint::Error),
+    #[error(transparent)]
+    Egress(#[from] egress::Error),
+    #[error(transparent)]
+    Rewrite(#[from] rewrite::Error),
+    #[error("Cannot load OCSP response: {0}")]
+    Ocsp(#[from] ocsp::Error),
+    #[cfg(unix)]
+    #[error(transparent)]
+    Privileges(#[from] privileges::Error),
+    #[cfg(not(unix))]
+    #[error("`--user` and `--group` are only supported on Unix")]
+    PrivilegesUnsupported,
+    #[cfg(target_os = "linux")]
+    #[error(transparent)]
+    Sandbox(#[from] sandbox::Error),
+    #[cfg(not(target_os = "linux"))]
+    #[error("`--sandbox` is only supported on Linux")]
+    SandboxUnsupported,
+    #[cfg(feature = "quic")]
+    #[error("`--quic` requires TLS")]
+    QuicWithoutTls,
 }
 
 /// Check if TLS is enabled.
 /// If so, create a `TlsIdentity` and start relevant tasks
-async fn check_start_tls(args: &'static ServerArgs) -> Result<Option<TlsIdentity>, Error> {
-    if let Some(tls_key) = &args.tls_key {
-        // `expect`: `clap` ensures that both `--tls-cert` and `--tls-key` are
-        // specified if either is specified.
-        let tls_cert = args
-            .tls_cert
-            .as_ref()
-            .expect("`tls_cert` is `None` (this is a bug)");
+async fn check_start_tls(
+    args: &Arc<ServerArgs>,
+    shutdown: &CancellationToken,
+) -> Result<Option<TlsIdentity>, Error> {
+    if let Some(tls_cert) = &args.tls_cert {
+        // Without `--tls-key`, `--tls-cert` must be a PKCS#12 bundle, which
+        // loading checks.
+        let tls_key = args.tls_key.as_deref();
         trace!("Enabling TLS");
-        let tls_config = make_tls_identity(tls_cert, tls_key, args.tls_ca.as_deref()).await?;
+        let ocsp = match &args.tls_ocsp {
+            Some(source) => Some(source.load(tls_cert).await?),
+            None => None,
+        };
+        let tls_config = make_tls_identity(
+            tls_cert,
+            tls_key,
+            args.client_ca(),
+            &args.tls_sni_cert,
+            ocsp,
+            &args.tls,
+        )
+        .await?;
         #[cfg(unix)]
-        register_signal_handler(tls_config.dupe(), tls_cert, tls_key, args.tls_ca.as_deref())?;
+        register_signal_handler(tls_config.dupe(), args.dupe(), shutdown)?;
+        if args.tls_ocsp.is_some() {
+            spawn_ocsp_refresh(tls_config.dupe(), args.dupe(), shutdown);
+        }
+        if args.tls_watch {
+            spawn_tls_watcher(tls_config.dupe(), args.dupe(), shutdown);
+        }
         return Ok(Some(tls_config));
     }
-    // `clap` ensures that tls-key or tls-domain are mutually exclusive.
+    // `clap` ensures that tls-cert or tls-domain are mutually exclusive.
     #[cfg(feature = "acme")]
     if !args.tls_domain.is_empty() {
         trace!("Enabling TLS using ACME");
-        let acme_client = acme::Client::populate_or_get(args).await?;
+        let acme_client = acme::Client::populate_or_get(args.dupe()).await?;
         let tls_config = acme_client.get_tls_config_spawn_renewal();
         return Ok(Some(tls_config));
     }
@@ -77,38 +174,213 @@ async fn check_start_tls(args: &'static ServerArgs) -> Result<Option<TlsIdentity
     Ok(None)
 }
 
-#[tracing::instrument(level = "trace")]
-pub async fn server_main(args: &'static ServerArgs) -> Result<(), Error> {
+/// Open the access log and the audit log, if any
+fn open_logs(args: &ServerArgs) -> Result<(Option<AccessLog>, Option<AuditLog>), Error> {
+    let access_log = match &args.access_log {
+        Some(path) => {
+            Some(AccessLog::open(path, args.access_log_format).map_err(Error::AccessLog)?)
+        }
+        None => None,
+    };
+    let audit_log = match &args.audit_log {
+        Some(path) => Some(AuditLog::open(path).map_err(Error::AuditLog)?),
+        None => None,
+    };
+    Ok((access_log, audit_log))
+}
+
+/// The resolver that answers the queries of `dns` remotes and the one that
+/// resolves the targets of the clients
+async fn make_resolvers(args: &ServerArgs) -> Result<(Option<SocketAddr>, Resolver), Error> {
+    let dns_resolver = args
+        .dns_resolver
+        .map(|resolver| resolver.0)
+        .or_else(dns::system_resolver);
+    if let Some(resolver) = dns_resolver {
+        debug!("answering DNS queries with {resolver}");
+    } else {
+        debug!("no DNS resolver, `dns` remotes will not work");
+    }
+    let resolver = match &args.dns_upstream {
+        Some(upstream) => Resolver::upstream(upstream).await?,
+        None => Resolver::nameservers(args.resolver_nameserver.iter().map(|ns| ns.0).collect()),
+    }
+    .with_prefer(args.resolver_prefer)
+    .with_timeout(args.resolver_timeout)
+    .with_cache_ttl(args.resolver_cache_ttl);
+    debug!("resolving targets with {resolver:?}");
+    Ok((dns_resolver, resolver))
+}
+
+/// Build the state shared by all connections from the options
+async fn make_state(
+    args: &ServerArgs,
+    registry: &Registry,
+) -> Result<State<hyper::body::Incoming>, Error> {
+    let auth = make_authenticator(args).await?;
+    let endpoints = match &args.endpoints {
+        Some(path) => Endpoints::load(path).await?,
+        None => Endpoints::default(),
+    };
+    let rewrites = match &args.rewrite {
+        Some(path) => Rewrites::load(path).await?,
+        None => Rewrites::default(),
+    };
+    let not_found_status = args.not_found_status.unwrap_or(StatusCode::NOT_FOUND);
+    let not_found_body = match (&args.not_found_resp_file, args.masquerade) {
+        (Some(path), _) => Some(
+            tokio::fs::read(path)
+                .await
+                .map_err(Error::NotFoundResp)?
+                .into(),
+        ),
+        (None, Some(masquerade)) => Some(masquerade.error_body(not_found_status)),
+        (None, None) => None,
+    };
+    let (access_log, audit_log) = open_logs(args)?;
+    let (dns_resolver, resolver) = make_resolvers(args).await?;
+    let egress = Egress::new(&args.egress_addr, &args.egress_user)?;
     let state = State::new(
         args.backend.as_ref(),
-        args.ws_psk.as_ref(),
+        &args.ws_psk,
         &args.not_found_resp,
-        args.obfs,
+        // Masquerading as another server is pointless if `/health` and
+        // `/version` give us away
+        args.obfs || args.masquerade.is_some(),
         args.reverse,
         args.timeout,
         args.timeout,
-    )?;
-    let sockaddrs = arg_to_sockaddrs(args)?;
+    )?
+    .with_ws_path(&args.ws_path.0)
+    .with_static_dir(args.static_dir.as_deref())
+    .with_masquerade(args.masquerade)
+    .with_not_found(not_found_status, &args.not_found_header, not_found_body)
+    .with_endpoints(endpoints)
+    .with_resume_timeout(args.resume_timeout)
+    .with_compression(&args.compression)
+    .with_limits(args.limits)
+    .with_mux(args.mux)
+    .with_rate_limiter(RateLimiter::new(
+        args.stream_rate_limit,
+        args.datagram_rate_limit,
+    ))
+    .with_acl(
+        Acl::new(args.allow.clone(), args.deny.clone())
+            .with_unix_sockets(args.allow_unix_socket.clone()),
+    )
+    .with_authenticator(auth)
+    .with_send_proxy_protocol(args.send_proxy_protocol)
+    .with_bench(args.bench)
+    .with_keepalive(args.keepalive)
+    .with_udp_timeout(args.udp_timeout.0)
+    .with_udp_full_cone(args.udp_full_cone)
+    .with_dns_resolver(dns_resolver)
+    .with_resolver(resolver)
+    .with_egress(egress)
+    .with_rewrites(rewrites)
+    .with_lock_target(args.lock_target.clone())
+    .with_registry(registry.dupe())
+    .with_backend_rewrite_host(args.backend_rewrite_host)
+    .with_access_log(access_log)
+    .with_audit_log(audit_log)
+    .with_ws_deflate(args.ws_deflate, args.ws_deflate_window_bits);
+    #[cfg(feature = "e2e")]
+    let state = state.with_e2e_key(args.e2e_key.as_ref());
+    #[cfg(feature = "chisel")]
+    let state = state.with_chisel(args.chisel.then(|| {
+        let host_key = args.key.as_deref().map_or_else(
+            crate::chisel::HostKey::generate,
+            crate::chisel::HostKey::from_seed,
+        );
+        info!("chisel host key fingerprint: {}", host_key.fingerprint());
+        host_key
+    }));
+    Ok(state)
+}
+
+#[tracing::instrument(level = "trace")]
+pub async fn server_main(args: Arc<ServerArgs>) -> Result<(), Error> {
+    let registry = Registry::default();
+    let state = make_state(&args, &registry).await?;
+    // Stop the tasks that outlive the listeners when this future is
+    // dropped, e.g. when the server is aborted from `embed`
+    let _shutdown = state.shutdown.clone().drop_guard();
     let mut listening_tasks = JoinSet::new();
-    if let Some(tls_config) = check_start_tls(args).await? {
-        for sockaddr in sockaddrs {
-            let listener = TcpListener::bind(sockaddr).await?;
-            let actual_addr = listener.local_addr()?;
-            info!("Listening on wss://{actual_addr}/ws");
-            listening_tasks.spawn(run_listener(
-                listener,
-                Some(tls_config.dupe()),
-                state.dupe(),
-            ));
+    let tls_config = check_start_tls(&args, &state.shutdown).await?;
+    // `(listener, use_tls, raw)`
+    let mut listeners = Vec::new();
+    let inherited = inherited_listeners()?;
+    if inherited.is_empty() {
+        for sockaddr in arg_to_sockaddrs(&args)? {
+            let bound = bind_listeners(sockaddr, args.reuse_port)?;
+            listeners.extend(bound.into_iter().map(|listener| (listener, true, false)));
         }
     } else {
-        for sockaddr in sockaddrs {
-            let listener = TcpListener::bind(sockaddr).await?;
-            let actual_addr = listener.local_addr()?;
-            info!("Listening on ws://{actual_addr}/ws");
-            listening_tasks.spawn(run_listener(listener, None, state.dupe()));
+        info!(
+            "Using {} socket(s) from systemd instead of `--host` and `--port`",
+            inherited.len()
+        );
+        for listener in inherited {
+            listeners.push((TcpListener::from_std(listener)?, true, false));
         }
     }
+    for sockaddr in &args.listen_plain {
+        let bound = bind_listeners(*sockaddr, args.reuse_port)?;
+        listeners.extend(bound.into_iter().map(|listener| (listener, false, false)));
+    }
+    for sockaddr in &args.listen_raw {
+        let bound = bind_listeners(*sockaddr, args.reuse_port)?;
+        listeners.extend(bound.into_iter().map(|listener| (listener, true, true)));
+    }
+    #[cfg(feature = "quic")]
+    let quic_endpoints = bind_quic(&args, tls_config.as_ref())?;
+    let admin_listener = match args.admin_listen {
+        Some(sockaddr) => Some(TcpListener::bind(sockaddr).await?),
+        None => None,
+    };
+    if args.user.is_some() || args.group.is_some() {
+        #[cfg(unix)]
+        privileges::drop_privileges(args.user.as_deref(), args.group.as_deref())?;
+        #[cfg(not(unix))]
+        return Err(Error::PrivilegesUnsupported);
+    }
+    if args.sandbox {
+        #[cfg(target_os = "linux")]
+        sandbox::apply()?;
+        #[cfg(not(target_os = "linux"))]
+        return Err(Error::SandboxUnsupported);
+    }
+    let proxy_protocol: Option<Arc<[IpNetwork]>> = args
+        .proxy_protocol
+        .then(|| args.proxy_protocol_trusted.as_slice().into());
+    for (listener, use_tls, raw) in listeners {
+        // Plaintext even if TLS is enabled, e.g. for a local reverse proxy
+        let tls_config = tls_config.as_ref().filter(|_| use_tls);
+        let actual_addr = listener.local_addr()?;
+        match (tls_config.is_some(), raw) {
+            (true, false) => info!("Listening on wss://{actual_addr}/ws"),
+            (false, false) => info!("Listening on ws://{actual_addr}/ws"),
+            (true, true) => info!("Listening on tls://{actual_addr}"),
+            (false, true) => info!("Listening on tcp://{actual_addr}"),
+        }
+        listening_tasks.spawn(run_listener(
+            listener,
+            tls_config.map(Dupe::dupe),
+            state.dupe().with_raw(raw),
+            proxy_protocol.as_ref().map(Dupe::dupe),
+        ));
+    }
+    #[cfg(feature = "quic")]
+    for (endpoint, tls_config) in quic_endpoints {
+        listening_tasks.spawn(quic::run_listener(endpoint, tls_config, state.dupe()));
+    }
+    if let (Some(listener), Some(token)) = (admin_listener, &args.admin_token) {
+        let admin = Admin::new(registry, token).ok_or(Error::AdminToken)?;
+        info!("Admin API listening on http://{}", listener.local_addr()?);
+        listening_tasks.spawn(admin::run_admin_listener(listener, admin));
+    }
+    #[cfg(unix)]
+    systemd::notify("READY=1");
     while let Some(res) = listening_tasks.join_next().await {
         if let Err(err) = res {
             assert!(!err.is_panic(), "Panic in a listener: {err}");
@@ -118,29 +390,186 @@ pub async fn server_main(args: &'static ServerArgs) -> Result<(), Error> {
     Ok(())
 }
 
+/// Load the credentials and the JWT settings, if any
+async fn make_authenticator(args: &ServerArgs) -> Result<Authenticator, Error> {
+    let mut users = match &args.authfile {
+        Some(path) => Some(Users::load(path).await?),
+        None => None,
+    };
+    if let Some(auth) = &args.auth {
+        users
+            .get_or_insert_default()
+            .add(&auth.username, &auth.password);
+    }
+    let audience = args.jwt_audience.clone();
+    let jwt = match (&args.jwt_secret, &args.jwt_jwks_url) {
+        (Some(secret), _) => Some(JwtVerifier::from_secret(secret, audience)),
+        (None, Some(url)) => Some(JwtVerifier::from_jwks_url(url.clone(), audience).await?),
+        (None, None) => None,
+    };
+    let client_certs = match &args.tls_client_rules {
+        Some(path) => Some(ClientCertRules::load(path).await?),
+        None => None,
+    };
+    Ok(Authenticator {
+        users,
+        jwt,
+        client_certs,
+    })
+}
+
+/// The paths of `--tls-cert` and `--tls-key`
+fn tls_cert_key(args: &ServerArgs) -> (&str, Option<&str>) {
+    let tls_cert = args
+        .tls_cert
+        .as_deref()
+        .expect("Reloading TLS without `--tls-cert` (this is a bug)");
+    (tls_cert, args.tls_key.as_deref())
+}
+
+/// Reload the TLS certificate and the OCSP response, if any.
+/// If the OCSP response cannot be loaded, the certificate is served without one.
+async fn reload_tls(tls_config: &TlsIdentity, args: &ServerArgs) {
+    let (tls_cert, tls_key) = tls_cert_key(args);
+    let ocsp = match &args.tls_ocsp {
+        Some(source) => source
+            .load(tls_cert)
+            .await
+            .inspect_err(|err| warn!("Not stapling an OCSP response: {err}"))
+            .ok(),
+        None => None,
+    };
+    let reloaded = reload_tls_identity(
+        tls_config,
+        tls_cert,
+        tls_key,
+        args.client_ca(),
+        &args.tls_sni_cert,
+        ocsp,
+        &args.tls,
+    )
+    .await;
+    if let Err(err) = reloaded {
+        error!("Cannot reload TLS certificate: {err}");
+    }
+}
+
 /// Run a signal handler task to reload the TLS certificate.
 #[cfg(unix)]
 #[inline]
 fn register_signal_handler(
-    tls_config: crate::tls::TlsIdentity,
-    tls_cert: &'static str,
-    tls_key: &'static str,
-    tls_ca: Option<&'static str>,
+    tls_config: TlsIdentity,
+    args: Arc<ServerArgs>,
+    shutdown: &CancellationToken,
 ) -> Result<(), Error> {
     let mut sigusr1 = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
         .map_err(Error::Signal)?;
-    // This `Future` does not fail, so we can ignore the `Result`.
-    tokio::spawn(async move {
+    let handler = async move {
         while sigusr1.recv().await.is_some() {
             info!("Reloading TLS certificate");
-            if let Err(err) = reload_tls_identity(&tls_config, tls_cert, tls_key, tls_ca).await {
-                error!("Cannot reload TLS certificate: {err}");
-            }
+            systemd::notify("RELOADING=1");
+            reload_tls(&tls_config, &args).await;
+            systemd::notify("READY=1");
         }
-    });
+    };
+    tokio::spawn(shutdown.clone().run_until_cancelled_owned(handler));
     Ok(())
 }
 
+/// Run a task to load the OCSP response again every
+/// [`config::OCSP_REFRESH_INTERVAL`].
+fn spawn_ocsp_refresh(
+    tls_config: TlsIdentity,
+    args: Arc<ServerArgs>,
+    shutdown: &CancellationToken,
+) {
+    let refresh = async move {
+        loop {
+            tokio::time::sleep(config::OCSP_REFRESH_INTERVAL).await;
+            debug!("Refreshing OCSP response");
+            reload_tls(&tls_config, &args).await;
+        }
+    };
+    tokio::spawn(shutdown.clone().run_until_cancelled_owned(refresh));
+}
+
+/// The files that make up the TLS configuration, watched with `--tls-watch`
+fn tls_files<'a>(
+    args: &'a ServerArgs,
+    tls_cert: &'a str,
+    tls_key: Option<&'a str>,
+) -> Vec<&'a str> {
+    let mut paths = vec![tls_cert];
+    paths.extend(tls_key);
+    for sni_cert in &args.tls_sni_cert {
+        paths.push(&sni_cert.cert_path);
+        paths.extend(sni_cert.key_path.as_deref());
+    }
+    paths.extend(args.tls_ca.as_deref());
+    paths.extend(args.tls_crl.as_deref());
+    if let Some(ocsp::OcspSource::File(path)) = &args.tls_ocsp {
+        paths.push(path);
+    }
+    paths
+}
+
+/// Get the modification times of `paths`, following symlinks so that
+/// swapping a symlink to a new file also counts as a change.
+/// Files that cannot be read are `None`.
+async fn modified_times(paths: &[&str]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(paths.len());
+    for path in paths {
+        let time = tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        times.push(time);
+    }
+    times
+}
+
+/// Run a task to reload the TLS certificate when its files change,
+/// checking every [`config::TLS_WATCH_INTERVAL`].
+/// Polling works everywhere and also notices files replaced through symlinks,
+/// like in Kubernetes secrets.
+fn spawn_tls_watcher(tls_config: TlsIdentity, args: Arc<ServerArgs>, shutdown: &CancellationToken) {
+    let watcher = async move {
+        let (tls_cert, tls_key) = tls_cert_key(&args);
+        let paths = tls_files(&args, tls_cert, tls_key);
+        let mut last = modified_times(&paths).await;
+        loop {
+            tokio::time::sleep(config::TLS_WATCH_INTERVAL).await;
+            let current = modified_times(&paths).await;
+            if current == last {
+                continue;
+            }
+            // A file may be missing while it is being replaced,
+            // so wait for all of them to come back.
+            if current.iter().any(Option::is_none) {
+                debug!("Some TLS files are missing, not reloading yet");
+                continue;
+            }
+            info!("TLS files changed, reloading TLS certificate");
+            reload_tls(&tls_config, &args).await;
+            last = current;
+        }
+    };
+    tokio::spawn(shutdown.clone().run_until_cancelled_owned(watcher));
+}
+
+/// The listening sockets passed by systemd, if any
+#[cfg(unix)]
+fn inherited_listeners() -> std::io::Result<Vec<std::net::TcpListener>> {
+    systemd::listen_fds()
+}
+
+/// The listening sockets passed by systemd, if any
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+fn inherited_listeners() -> std::io::Result<Vec<std::net::TcpListener>> {
+    Ok(Vec::new())
+}
+
 /// Create a list of `SocketAddr`s from the command-line arguments on which to listen.
 fn arg_to_sockaddrs(arg: &ServerArgs) -> Result<Vec<SocketAddr>, Error> {
     // `expect`: `clap` ensures that `--port` has at least one element.
@@ -160,15 +589,70 @@ fn arg_to_sockaddrs(arg: &ServerArgs) -> Result<Vec<SocketAddr>, Error> {
         .collect()
 }
 
+/// Bind the listeners for `sockaddr`. With `reuse_port`, there is one for each
+/// CPU, all bound with `SO_REUSEPORT` so that the kernel spreads the
+/// connections across them. This also lets a new `penguin` process bind the
+/// same port before the old one exits.
+fn bind_listeners(sockaddr: SocketAddr, reuse_port: bool) -> std::io::Result<Vec<TcpListener>> {
+    if !reuse_port {
+        let listener = std::net::TcpListener::bind(sockaddr)?;
+        listener.set_nonblocking(true)?;
+        return Ok(vec![TcpListener::from_std(listener)?]);
+    }
+    #[cfg(unix)]
+    {
+        let count = std::thread::available_parallelism().map_or(1, std::num::NonZero::get);
+        let mut sockaddr = sockaddr;
+        let mut listeners = Vec::with_capacity(count);
+        for _ in 0..count {
+            let socket = if sockaddr.is_ipv4() {
+                tokio::net::TcpSocket::new_v4()?
+            } else {
+                tokio::net::TcpSocket::new_v6()?
+            };
+            socket.set_reuseaddr(true)?;
+            socket.set_reuseport(true)?;
+            socket.bind(sockaddr)?;
+            // The same backlog as `TcpListener::bind`
+            let listener = socket.listen(1024)?;
+            // Bind the others to the same port if it was picked by the system
+            sockaddr = listener.local_addr()?;
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+    #[cfg(not(unix))]
+    {
+        warn!("SO_REUSEPORT is not supported on this platform");
+        bind_listeners(sockaddr, false)
+    }
+}
+
+/// Bind the QUIC endpoints of `--quic`, if any
+#[cfg(feature = "quic")]
+fn bind_quic(
+    args: &ServerArgs,
+    tls_config: Option<&TlsIdentity>,
+) -> Result<Vec<(quinn::Endpoint, TlsIdentity)>, Error> {
+    if !args.quic {
+        return Ok(Vec::new());
+    }
+    let tls_config = tls_config.ok_or(Error::QuicWithoutTls)?;
+    arg_to_sockaddrs(args)?
+        .into_iter()
+        .map(|sockaddr| Ok((quic::bind(sockaddr, tls_config)?, tls_config.dupe())))
+        .collect()
+}
+
 /// Runs a listener.
 #[tracing::instrument(skip_all, level = "debug", fields(tls = %tls_config.is_some()))]
 async fn run_listener(
     listener: TcpListener,
     tls_config: Option<crate::tls::TlsIdentity>,
-    state: State<'static, hyper::body::Incoming>,
+    state: State<hyper::body::Incoming>,
+    proxy_protocol: Option<Arc<[IpNetwork]>>,
 ) {
     loop {
-        let new_state = state.dupe();
         let (stream, peer) = match listener.accept().await {
             Ok((stream, peer)) => (stream, peer),
             Err(err) => {
@@ -176,23 +660,60 @@ async fn run_listener(
                 continue;
             }
         };
-        debug!("accepted connection from {peer}");
-        if let Some(tls_config) = &tls_config {
-            tokio::spawn(serve_connection_tls(
-                stream,
-                new_state,
-                tls_config.load_full(),
-            ));
-        } else {
-            tokio::spawn(serve_connection(stream, new_state));
+        let tls_config = tls_config.as_ref().map(|config| config.load_full());
+        let serve = serve_accepted(
+            stream,
+            peer,
+            tls_config,
+            state.dupe(),
+            proxy_protocol.as_ref().map(Dupe::dupe),
+        );
+        tokio::spawn(state.shutdown.clone().run_until_cancelled_owned(serve));
+    }
+}
+
+/// Serves an accepted connection, first reading the PROXY protocol header
+/// if `proxy_protocol` is set to the networks trusted to send it.
+async fn serve_accepted(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    tls_config: Option<Arc<TlsIdentityInner>>,
+    state: State<hyper::body::Incoming>,
+    proxy_protocol: Option<Arc<[IpNetwork]>>,
+) {
+    let peer = if let Some(trusted) = proxy_protocol {
+        if !proxy_protocol::is_trusted(&trusted, peer.ip()) {
+            warn!("Closing connection from {peer}: not trusted to send a PROXY protocol header");
+            return;
         }
+        let header = proxy_protocol::read_header(&mut stream);
+        match state.tls_timeout.timeout(header).await {
+            Ok(Ok(client)) => client.unwrap_or(peer),
+            Ok(Err(err)) => {
+                warn!("Closing connection from {peer}: {err}");
+                return;
+            }
+            Err(_) => {
+                warn!("Closing connection from {peer}: no PROXY protocol header");
+                return;
+            }
+        }
+    } else {
+        peer
+    };
+    let state = state.with_peer(SocketAddr::new(peer.ip().to_canonical(), peer.port()));
+    debug!("accepted connection from {peer}");
+    if let Some(tls_config) = tls_config {
+        serve_connection_tls(stream, state, tls_config).await;
+    } else {
+        serve_connection(stream, state).await;
     }
 }
 
 /// Serves a single connection from a client with TLS, ignoring errors.
 async fn serve_connection_tls<S>(
     stream: S,
-    state: State<'static, hyper::body::Incoming>,
+    state: State<hyper::body::Incoming>,
     tls_config: Arc<TlsIdentityInner>,
 ) where
     S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
@@ -207,6 +728,26 @@ async fn serve_connection_tls<S>(
 
     match stream {
         Ok(Ok(stream)) => {
+            #[cfg(feature = "__rustls")]
+            let peer_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(<[_]>::first)
+                .and_then(|cert| ClientCert::from_der(cert));
+            #[cfg(feature = "nativetls")]
+            let peer_cert = stream
+                .get_ref()
+                .peer_certificate()
+                .ok()
+                .flatten()
+                .and_then(|cert| cert.to_der().ok())
+                .and_then(|der| ClientCert::from_der(&der));
+            let state = match peer_cert {
+                Some(cert) => state.with_peer_cert(cert),
+                None => state,
+            }
+            .with_tls(true);
             serve_connection(stream, state).await;
         }
         Ok(Err(err)) => {
@@ -220,10 +761,14 @@ async fn serve_connection_tls<S>(
 
 /// Serves a single connection from a client, ignoring errors.
 #[tracing::instrument(skip_all, level = "debug")]
-async fn serve_connection<S>(stream: S, state: State<'static, hyper::body::Incoming>)
+async fn serve_connection<S>(stream: S, state: State<hyper::body::Incoming>)
 where
     S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
 {
+    if state.raw {
+        raw::serve_raw(stream, state).await;
+        return;
+    }
     let http_timeout = state.http_timeout;
     let hyper_io = TokioIo::new(stream);
     let exec = auto::Builder::new(TokioExecutor::new());
@@ -259,6 +804,54 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_tls_watch_files() {
+        crate::tests::setup_logging();
+        let args = ServerArgs {
+            tls_sni_cert: vec!["example.com,sni.p12".parse().unwrap()],
+            tls_ca: Some("ca.pem".to_string()),
+            tls_ocsp: Some(ocsp::OcspSource::Fetch),
+            ..Default::default()
+        };
+        assert_eq!(
+            tls_files(&args, "cert.pem", Some("key.pem")),
+            ["cert.pem", "key.pem", "sni.p12", "ca.pem"]
+        );
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("cert.pem");
+        let path = path.to_str().unwrap();
+        assert_eq!(modified_times(&[path]).await, [None]);
+        std::fs::write(path, "first").unwrap();
+        let first = modified_times(&[path]).await;
+        assert!(first[0].is_some());
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert_ne!(modified_times(&[path]).await, first);
+    }
+
+    #[tokio::test]
+    async fn test_bind_listeners() {
+        crate::tests::setup_logging();
+        let sockaddr = "127.0.0.1:0".parse().unwrap();
+        let listeners = bind_listeners(sockaddr, false).unwrap();
+        assert_eq!(listeners.len(), 1);
+        #[cfg(unix)]
+        {
+            let listeners = bind_listeners(sockaddr, true).unwrap();
+            let port = listeners[0].local_addr().unwrap().port();
+            assert!(
+                listeners
+                    .iter()
+                    .all(|listener| listener.local_addr().unwrap().port() == port)
+            );
+            // Another process could join them
+            let more = bind_listeners(listeners[0].local_addr().unwrap(), true).unwrap();
+            assert!(!more.is_empty());
+            // But not without `SO_REUSEPORT`
+            assert!(bind_listeners(listeners[0].local_addr().unwrap(), false).is_err());
+        }
+    }
+
     /// Test `arg_to_sockaddrs` with no hosts and no ports.
     #[test]
     #[should_panic(expected = "`port` is empty (this is a bug)")]
@@ -295,7 +888,7 @@ mod tests {
         assert_eq!(sockaddrs.len(), 1);
         assert_eq!(
             sockaddrs[0].ip(),
-            std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))
+            std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)
         );
         assert_eq!(sockaddrs[0].port(), 9999);
     }
@@ -309,7 +902,7 @@ mod tests {
         assert_eq!(sockaddrs.len(), 1);
         assert_eq!(
             sockaddrs[0].ip(),
-            std::net::IpAddr::V6(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))
+            std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)
         );
         assert_eq!(sockaddrs[0].port(), 1532);
     }
@@ -331,22 +924,22 @@ mod tests {
         assert_eq!(sockaddrs.len(), 4);
         assert_eq!(
             sockaddrs[0].ip(),
-            std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))
+            std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)
         );
         assert_eq!(sockaddrs[0].port(), 1233);
         assert_eq!(
             sockaddrs[1].ip(),
-            std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))
+            std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)
         );
         assert_eq!(sockaddrs[1].port(), 1233);
         assert_eq!(
             sockaddrs[2].ip(),
-            std::net::IpAddr::V6(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0))
+            std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED)
         );
         assert_eq!(sockaddrs[2].port(), 1233);
         assert_eq!(
             sockaddrs[3].ip(),
-            std::net::IpAddr::V6(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))
+            std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST)
         );
         assert_eq!(sockaddrs[3].port(), 1233);
     }
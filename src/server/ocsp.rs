@@ -0,0 +1,259 @@
+//! OCSP responses to staple to the server certificate.
+//!
+//! The response is either read from a file, e.g. one kept up to date by
+//! `openssl ocsp`, or fetched from the OCSP responder named in the
+//! certificate, which needs the issuer certificate after the leaf in
+//! `--tls-cert`. Either way, it is loaded again every
+//! [`config::OCSP_REFRESH_INTERVAL`](crate::config::OCSP_REFRESH_INTERVAL).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::tls::make_hyper_connector;
+use bytes::Bytes;
+use http::header::CONTENT_TYPE;
+use http::{Request, StatusCode, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use sha1::{Digest, Sha1};
+use std::convert::Infallible;
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::debug;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::der_parser::ber::BerObjectContent;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP;
+use x509_parser::pem::Pem;
+
+/// Errors when loading or fetching an OCSP response
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read OCSP response or certificate: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid certificate: {0}")]
+    Certificate(String),
+    #[error("The certificate file has no issuer certificate after the leaf")]
+    NoIssuer,
+    #[error("The certificate names no OCSP responder")]
+    NoResponder,
+    #[error("Invalid OCSP responder URL: {0}")]
+    Url(#[from] http::uri::InvalidUri),
+    #[error("Cannot fetch OCSP response: {0}")]
+    Http(#[from] http::Error),
+    #[error("Cannot fetch OCSP response: {0}")]
+    Request(#[from] hyper_util::client::legacy::Error),
+    #[error("Cannot fetch OCSP response: responder returned {0}")]
+    Status(StatusCode),
+    #[error("Cannot fetch OCSP response: {0}")]
+    Body(#[from] hyper::Error),
+    #[error("Invalid or unsuccessful OCSP response")]
+    Unsuccessful,
+}
+
+/// Where the OCSP response comes from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OcspSource {
+    /// A DER-encoded response in a file
+    File(String),
+    /// The responder named in the certificate
+    Fetch,
+}
+
+impl FromStr for OcspSource {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "fetch" => Self::Fetch,
+            path => Self::File(path.to_string()),
+        })
+    }
+}
+
+impl OcspSource {
+    /// Get a response for the certificate chain at `cert_path`
+    pub async fn load(&self, cert_path: &str) -> Result<Vec<u8>, Error> {
+        let response = match self {
+            Self::File(path) => tokio::fs::read(path).await?,
+            Self::Fetch => fetch(cert_path).await?,
+        };
+        check_response(&response)?;
+        Ok(response)
+    }
+}
+
+/// Fetch a response for the leaf certificate at `cert_path` from its responder
+async fn fetch(cert_path: &str) -> Result<Vec<u8>, Error> {
+    let content = tokio::fs::read(cert_path).await?;
+    let pems = Pem::iter_from_buffer(&content)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| Error::Certificate(err.to_string()))?;
+    let cert = parse(pems.first().ok_or(Error::NoIssuer)?)?;
+    let issuer = parse(pems.get(1).ok_or(Error::NoIssuer)?)?;
+    let url: Uri = responder_url(&cert).ok_or(Error::NoResponder)?.parse()?;
+    let client = HyperClient::builder(TokioExecutor::new()).build(make_hyper_connector()?);
+    let req = Request::post(url.clone())
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Full::new(Bytes::from(make_request(&cert, &issuer))))?;
+    let resp = client.request(req).await?;
+    if !resp.status().is_success() {
+        return Err(Error::Status(resp.status()));
+    }
+    let body = resp.into_body().collect().await?.to_bytes();
+    debug!("fetched OCSP response from {url}");
+    Ok(body.to_vec())
+}
+
+/// Parse a certificate in a PEM block
+fn parse(pem: &Pem) -> Result<X509Certificate<'_>, Error> {
+    pem.parse_x509()
+        .map_err(|err| Error::Certificate(err.to_string()))
+}
+
+/// The URL of the OCSP responder in the authority information access
+/// extension of `cert`
+fn responder_url<'a>(cert: &'a X509Certificate<'_>) -> Option<&'a str> {
+    cert.extensions()
+        .iter()
+        .find_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(aia) => Some(aia),
+            _ => None,
+        })?
+        .accessdescs
+        .iter()
+        .filter(|desc| desc.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+        .find_map(|desc| match desc.access_location {
+            GeneralName::URI(url) => Some(url),
+            _ => None,
+        })
+}
+
+/// DER-encode a value with a short tag
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match u8::try_from(content.len()) {
+        Ok(len) if len < 0x80 => out.push(len),
+        _ => {
+            let bytes = content.len().to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            // At most `size_of::<usize>()` octets
+            let octets = u8::try_from(bytes.len() - skip).expect("Length too long (this is a bug)");
+            out.push(0x80 | octets);
+            out.extend_from_slice(&bytes[skip..]);
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Build an unsigned `OCSPRequest` for `cert` (RFC 6960, section 4.1.1)
+fn make_request(cert: &X509Certificate<'_>, issuer: &X509Certificate<'_>) -> Vec<u8> {
+    // id-sha1 (1.3.14.3.2.26) with NULL parameters
+    let algorithm = der(
+        0x30,
+        &[der(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a]), der(0x05, &[])].concat(),
+    );
+    let name_hash = Sha1::digest(cert.issuer().as_raw());
+    let key_hash = Sha1::digest(&issuer.public_key().subject_public_key.data);
+    let cert_id = der(
+        0x30,
+        &[
+            algorithm,
+            der(0x04, &name_hash),
+            der(0x04, &key_hash),
+            der(0x02, cert.raw_serial()),
+        ]
+        .concat(),
+    );
+    // OCSPRequest { TBSRequest { requestList: SEQUENCE OF Request { CertID } } }
+    let request = der(0x30, &cert_id);
+    let request_list = der(0x30, &request);
+    let tbs_request = der(0x30, &request_list);
+    der(0x30, &tbs_request)
+}
+
+/// Check that `response` is a successful `OCSPResponse`.
+/// Clients check the signature and the status of the certificate.
+fn check_response(response: &[u8]) -> Result<(), Error> {
+    let (rest, response) =
+        x509_parser::der_parser::parse_der(response).map_err(|_| Error::Unsuccessful)?;
+    let fields = response.as_sequence().map_err(|_| Error::Unsuccessful)?;
+    // `responseStatus` is `successful` and `responseBytes` is present
+    match fields.first().map(|status| &status.content) {
+        Some(BerObjectContent::Enum(0)) if fields.len() == 2 && rest.is_empty() => Ok(()),
+        _ => Err(Error::Unsuccessful),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    #[test]
+    fn test_ocsp_request() {
+        crate::tests::setup_logging();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let leaf_key = KeyPair::generate().unwrap();
+        let leaf = CertificateParams::new(vec!["example.com".to_string()])
+            .unwrap()
+            .signed_by(&leaf_key, &ca, &ca_key)
+            .unwrap();
+        let (_, ca) = x509_parser::parse_x509_certificate(ca.der()).unwrap();
+        let (_, leaf) = x509_parser::parse_x509_certificate(leaf.der()).unwrap();
+        assert!(responder_url(&leaf).is_none());
+        let request = make_request(&leaf, &ca);
+        // Walk down to the `CertID`
+        let (rest, parsed) = x509_parser::der_parser::parse_der(&request).unwrap();
+        assert!(rest.is_empty());
+        let mut cert_id = &parsed;
+        for _ in 0..4 {
+            cert_id = &cert_id.as_sequence().unwrap()[0];
+        }
+        let fields = cert_id.as_sequence().unwrap();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(
+            fields[1].as_slice().unwrap(),
+            Sha1::digest(ca.subject().as_raw()).as_slice()
+        );
+        assert_eq!(
+            fields[2].as_slice().unwrap(),
+            Sha1::digest(&ca.public_key().subject_public_key.data).as_slice()
+        );
+        assert_eq!(fields[3].as_slice().unwrap(), leaf.raw_serial());
+    }
+
+    #[test]
+    fn test_der_length() {
+        crate::tests::setup_logging();
+        assert_eq!(der(0x04, &[1, 2]), [0x04, 0x02, 1, 2]);
+        let long = der(0x04, &[0; 300]);
+        assert_eq!(long[..4], [0x04, 0x82, 0x01, 0x2c]);
+        assert_eq!(long.len(), 304);
+    }
+
+    #[test]
+    fn test_check_response() {
+        crate::tests::setup_logging();
+        // Successful, with (truncated) `responseBytes`
+        let response_bytes = der(0xa0, &der(0x30, &der(0x06, &[0x2b, 0x06, 0x01])));
+        let ok = der(0x30, &[der(0x0a, &[0]), response_bytes].concat());
+        assert!(check_response(&ok).is_ok());
+        // `tryLater`
+        let try_later = der(0x30, &der(0x0a, &[3]));
+        assert!(matches!(
+            check_response(&try_later),
+            Err(Error::Unsuccessful)
+        ));
+        assert!(check_response(b"not DER").is_err());
+        assert_eq!("fetch".parse(), Ok(OcspSource::Fetch));
+        assert_eq!(
+            "ocsp.der".parse(),
+            Ok(OcspSource::File("ocsp.der".to_string()))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+//! Dropping root privileges after binding the listening sockets.
+//!
+//! Everything opened later, such as the TLS files on reload and Unix sockets
+//! to forward to, is opened as the new user.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use nix::unistd::{Gid, Group, Uid, User};
+use thiserror::Error;
+use tracing::info;
+
+/// Errors when dropping privileges
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot look up user or group `{0}`: {1}")]
+    Lookup(String, nix::Error),
+    #[error("No such user: `{0}`")]
+    NoUser(String),
+    #[error("No such group: `{0}`")]
+    NoGroup(String),
+    #[error("Cannot drop privileges: {0}")]
+    Set(nix::Error),
+}
+
+/// Find a user by name or numeric ID
+fn find_user(name: &str) -> Result<User, Error> {
+    let user = match name.parse() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+        Err(_) => User::from_name(name),
+    };
+    user.map_err(|err| Error::Lookup(name.to_string(), err))?
+        .ok_or_else(|| Error::NoUser(name.to_string()))
+}
+
+/// Find a group by name or numeric ID
+fn find_group(name: &str) -> Result<Gid, Error> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    let group = Group::from_name(name)
+        .map_err(|err| Error::Lookup(name.to_string(), err))?
+        .ok_or_else(|| Error::NoGroup(name.to_string()))?;
+    Ok(group.gid)
+}
+
+/// Switch to `user` and `group`. Without `group`, the primary group of
+/// `user` is used; without `user`, only the group is changed.
+/// Supplementary groups are dropped.
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> Result<(), Error> {
+    let user = user.map(find_user).transpose()?;
+    let gid = match (group, &user) {
+        (Some(group), _) => Some(find_group(group)?),
+        (None, Some(user)) => Some(user.gid),
+        (None, None) => None,
+    };
+    // The group first, as we cannot change it after giving up root
+    if let Some(gid) = gid {
+        #[cfg(not(any(target_vendor = "apple", target_os = "redox", target_os = "haiku")))]
+        nix::unistd::setgroups(&[gid]).map_err(Error::Set)?;
+        nix::unistd::setgid(gid).map_err(Error::Set)?;
+        info!("Switched to group {gid}");
+    }
+    if let Some(user) = user {
+        nix::unistd::setuid(user.uid).map_err(Error::Set)?;
+        info!("Switched to user `{}` ({})", user.name, user.uid);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_user_and_group() {
+        crate::tests::setup_logging();
+        let root = find_user("root").unwrap();
+        assert!(root.uid.is_root());
+        assert_eq!(find_user("0").unwrap().name, root.name);
+        assert!(matches!(
+            find_user("no-such-user-for-penguin"),
+            Err(Error::NoUser(_))
+        ));
+        assert_eq!(find_group("0").unwrap(), Gid::from_raw(0));
+        assert_eq!(find_group(&root.gid.to_string()).unwrap(), root.gid);
+        assert!(matches!(
+            find_group("no-such-group-for-penguin"),
+            Err(Error::NoGroup(_))
+        ));
+        // Nothing to do
+        drop_privileges(None, None).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+//! The PROXY protocol of load balancers such as `HAProxy`.
+//!
+//! With `--proxy-protocol`, every connection starts with a version 1 (text)
+//! or version 2 (binary) header carrying the address of the actual client,
+//! which is then used in the logs and for rate limits. With
+//! `--proxy-protocol-trusted`, only the listed peers may send it.
+//!
+//! With `--send-proxy-protocol`, the server sends a version 2 header with the
+//! address of the tunnel client to the destinations of TCP streams.
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::acl::IpNetwork;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Errors when reading a PROXY protocol header
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read PROXY protocol header: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Connection does not start with a PROXY protocol header")]
+    Missing,
+    #[error("Invalid PROXY protocol header")]
+    Invalid,
+}
+
+/// The signature that starts a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible version 1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Whether `peer` may send a PROXY protocol header, i.e. it is in one of
+/// the `trusted` networks or none are given
+pub fn is_trusted(trusted: &[IpNetwork], peer: IpAddr) -> bool {
+    trusted.is_empty() || trusted.iter().any(|network| network.contains(peer))
+}
+
+/// Read the PROXY protocol header at the start of `stream`, leaving the rest
+/// of the stream untouched.
+/// Returns the address of the client, or `None` if the header does not have one,
+/// e.g. for health checks of the load balancer.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, Error> {
+    // Both versions are longer than this
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]);
+        let mut addresses = vec![0; usize::from(len)];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(header[0], header[1], &addresses)
+    } else if start.starts_with(b"PROXY ") {
+        // Read byte by byte to not consume anything after the header
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(Error::Invalid);
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(Error::Missing)
+    }
+}
+
+/// Parse a version 1 header line such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::Invalid)?;
+    let fields: Vec<&str> = line.trim_end_matches("\r\n").split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _, src_port, _] => {
+            let ip: IpAddr = src.parse().map_err(|_| Error::Invalid)?;
+            let port = src_port.parse().map_err(|_| Error::Invalid)?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(Error::Invalid),
+    }
+}
+
+/// Parse the rest of a version 2 header
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, Error> {
+    if version_command >> 4 != 2 {
+        return Err(Error::Invalid);
+    }
+    match version_command & 0x0f {
+        // `LOCAL`: the connection is from the load balancer itself
+        0 => return Ok(None),
+        // `PROXY`
+        1 => {}
+        _ => return Err(Error::Invalid),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        // `AF_INET`: source and destination addresses, then ports
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[..4]
+                .try_into()
+                .expect("Wrong slice length (this is a bug)");
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        // `AF_INET6`
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[..16]
+                .try_into()
+                .expect("Wrong slice length (this is a bug)");
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        1 | 2 => Err(Error::Invalid),
+        // `AF_UNSPEC` or `AF_UNIX`: keep the address of the connection
+        _ => Ok(None),
+    }
+}
+
+/// Make a version 2 `PROXY` header for a TCP connection from `source` to
+/// `destination`. If only one of them is IPv6, the other is sent as an
+/// IPv4-mapped address.
+pub fn make_v2_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // Version 2, `PROXY`
+    header.push(0x21);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            // `AF_INET`, `STREAM`, and the length of the addresses
+            header.extend([0x11, 0, 12]);
+            header.extend(src.octets());
+            header.extend(dst.octets());
+        }
+        (src, dst) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.extend([0x21, 0, 36]);
+            header.extend(to_v6(src).octets());
+            header.extend(to_v6(dst).octets());
+        }
+    }
+    header.extend(source.port().to_be_bytes());
+    header.extend(destination.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        crate::tests::setup_logging();
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+        for invalid in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 443\r\n"[..],
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n",
+            b"PROXY TCP4 example.com 198.51.100.1 1 2\r\n",
+            &[b'P', b'R', b'O', b'X', b'Y', b' '].repeat(30),
+        ] {
+            let mut stream = invalid;
+            assert!(read_header(&mut stream).await.is_err());
+        }
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n";
+        assert!(matches!(
+            read_header(&mut stream).await,
+            Err(Error::Missing)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_v2() {
+        crate::tests::setup_logging();
+        let mut header = V2_SIGNATURE.to_vec();
+        // `PROXY` over TCP on IPv4 with a TLV after the addresses
+        header.extend([0x21, 0x11, 0, 15]);
+        header.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        header.extend([0x04, 0, 0]);
+        header.extend(b"rest");
+        let mut stream = &header[..];
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"rest");
+        let mut addresses = [0; 36];
+        addresses[15] = 1;
+        addresses[32..34].copy_from_slice(&4000_u16.to_be_bytes());
+        assert_eq!(
+            parse_v2(0x21, 0x21, &addresses).unwrap(),
+            Some("[::1]:4000".parse().unwrap())
+        );
+        // `LOCAL` and `AF_UNSPEC`
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x00, &[]).unwrap(), None);
+        // Wrong version, unknown command and truncated addresses
+        assert!(parse_v2(0x11, 0x11, &addresses).is_err());
+        assert!(parse_v2(0x22, 0x11, &addresses).is_err());
+        assert!(parse_v2(0x21, 0x21, &addresses[..12]).is_err());
+    }
+
+    #[test]
+    fn test_is_trusted() {
+        crate::tests::setup_logging();
+        let trusted = ["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+        assert!(is_trusted(&trusted, "10.0.0.1".parse().unwrap()));
+        assert!(is_trusted(&trusted, "fd00::1".parse().unwrap()));
+        assert!(!is_trusted(&trusted, "192.0.2.1".parse().unwrap()));
+        assert!(is_trusted(&[], "192.0.2.1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_make_v2_header() {
+        crate::tests::setup_logging();
+        let v4: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let header = make_v2_header(v4, "198.51.100.1:443".parse().unwrap());
+        assert_eq!(header.len(), 28);
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some(v4));
+        let header = make_v2_header(v6, v4);
+        assert_eq!(header.len(), 52);
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some(v6));
+        let header = make_v2_header(v4, v6);
+        let mapped = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().into(), 56324);
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), Some(mapped));
+    }
+}
@@ -0,0 +1,84 @@
+//! Experimental QUIC transport (`--quic`). See [`crate::quic`].
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::raw::handshake;
+use super::service::{State, serve_session};
+use crate::quic::{QuicSocket, server_config};
+use crate::tls::{TlsIdentity, TlsIdentityInner};
+use penguin_mux::Dupe;
+use quinn::{Endpoint, Incoming};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{debug, error, info};
+
+/// Bind a QUIC endpoint on the UDP port of `sockaddr`
+pub(super) fn bind(sockaddr: SocketAddr, tls_config: &TlsIdentity) -> std::io::Result<Endpoint> {
+    let endpoint = Endpoint::server(server_config(&tls_config.load())?, sockaddr)?;
+    info!("Listening on wss+quic://{}", endpoint.local_addr()?);
+    Ok(endpoint)
+}
+
+/// Accept QUIC connections on `endpoint`
+pub(super) async fn run_listener(
+    endpoint: Endpoint,
+    tls_config: TlsIdentity,
+    state: State<hyper::body::Incoming>,
+) {
+    let mut current: Arc<TlsIdentityInner> = tls_config.load_full();
+    while let Some(incoming) = endpoint.accept().await {
+        // Pick up reloaded certificates for the next connections
+        let latest = tls_config.load_full();
+        if !Arc::ptr_eq(&latest, &current) {
+            match server_config(&latest) {
+                Ok(config) => endpoint.set_server_config(Some(config)),
+                Err(err) => error!("Cannot use the reloaded TLS config for QUIC: {err}"),
+            }
+            current = latest;
+        }
+        let serve = serve_incoming(incoming, state.dupe());
+        tokio::spawn(state.shutdown.clone().run_until_cancelled_owned(serve));
+    }
+}
+
+/// Serves a single QUIC connection, ignoring errors.
+async fn serve_incoming(incoming: Incoming, state: State<hyper::body::Incoming>) {
+    let peer = incoming.remote_address();
+    let state = state.with_peer(SocketAddr::new(peer.ip().to_canonical(), peer.port()));
+    let http_timeout = state.http_timeout;
+    let accepted = async {
+        let conn = incoming.await?;
+        debug!("accepted QUIC connection from {peer}");
+        crate::quic::accept(conn).await
+    };
+    let mut ws: QuicSocket = match state.tls_timeout.timeout(accepted).await {
+        Ok(Ok(ws)) => ws,
+        Ok(Err(err)) => {
+            debug!("QUIC connection from {peer} failed: {err}");
+            return;
+        }
+        Err(_) => {
+            debug!("QUIC connection from {peer} timed out");
+            return;
+        }
+    };
+    match http_timeout.timeout(handshake(&mut ws, &state)).await {
+        Ok(Ok(Some((session, options)))) => {
+            #[cfg(feature = "otel")]
+            crate::otel::handshake("accepted");
+            #[cfg(feature = "e2e")]
+            let encrypted = options.e2e_key.is_some();
+            #[cfg(not(feature = "e2e"))]
+            let encrypted = false;
+            ws.start_lanes(!encrypted && !options.padding && !options.coalesce);
+            serve_session(Box::new(ws), session, options).await;
+        }
+        // Close the connection without telling the client why
+        Ok(Ok(None)) => {
+            #[cfg(feature = "otel")]
+            crate::otel::handshake("rejected");
+        }
+        Ok(Err(err)) => error!("QUIC handshake error: {err}"),
+        Err(_) => error!("QUIC handshake timed out after {http_timeout}"),
+    }
+}
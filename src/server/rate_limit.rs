@@ -0,0 +1,220 @@
+//! Per-client rate limiting of new streams and datagrams.
+//!
+//! Authenticated clients are counted by user name, so that a user cannot
+//! escape the limit by connecting from more addresses. Anonymous clients
+//! are counted by IP address.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often idle clients are forgotten
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A token bucket holding up to one second worth of events
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    const fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            tokens: rate as f64,
+            refilled: now,
+        }
+    }
+
+    /// Take a token if there is one
+    fn take(&mut self, rate: u32, now: Instant) -> bool {
+        let rate = f64::from(rate);
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = elapsed.mul_add(rate, self.tokens).min(rate);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Who the events of a client are counted for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    /// An authenticated user
+    User(Arc<str>),
+    /// An anonymous client
+    Ip(IpAddr),
+}
+
+impl ClientKey {
+    /// The key of a client connecting from `ip`, authenticated as `user` if any
+    pub fn new(user: Option<&str>, ip: IpAddr) -> Self {
+        user.map_or(Self::Ip(ip), |user| Self::User(user.into()))
+    }
+}
+
+#[derive(Debug)]
+struct ClientBuckets {
+    streams: Bucket,
+    datagrams: Bucket,
+}
+
+#[derive(Debug)]
+struct Clients {
+    buckets: HashMap<ClientKey, ClientBuckets>,
+    pruned: Instant,
+}
+
+/// Limits the rate at which each client may open streams and send datagrams.
+/// A rate of 0 means no limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    streams_per_sec: u32,
+    datagrams_per_sec: u32,
+    clients: Mutex<Clients>,
+}
+
+/// Which kind of event to count
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    Stream,
+    Datagram,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter, or `None` if neither rate is limited
+    pub fn new(streams_per_sec: u32, datagrams_per_sec: u32) -> Option<Arc<Self>> {
+        if streams_per_sec == 0 && datagrams_per_sec == 0 {
+            return None;
+        }
+        Some(Arc::new(Self {
+            streams_per_sec,
+            datagrams_per_sec,
+            clients: Mutex::new(Clients {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }))
+    }
+
+    fn allow(&self, client: &ClientKey, event: Event) -> bool {
+        let rate = match event {
+            Event::Stream => self.streams_per_sec,
+            Event::Datagram => self.datagrams_per_sec,
+        };
+        if rate == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        if now.saturating_duration_since(clients.pruned) >= PRUNE_INTERVAL {
+            // Buckets idle for a second are full, so forgetting them changes nothing
+            clients.buckets.retain(|_, buckets| {
+                now.saturating_duration_since(buckets.streams.refilled) < Duration::from_secs(1)
+                    || now.saturating_duration_since(buckets.datagrams.refilled)
+                        < Duration::from_secs(1)
+            });
+            clients.pruned = now;
+        }
+        let buckets = clients
+            .buckets
+            .entry(client.clone())
+            .or_insert_with(|| ClientBuckets {
+                streams: Bucket::new(self.streams_per_sec, now),
+                datagrams: Bucket::new(self.datagrams_per_sec, now),
+            });
+        match event {
+            Event::Stream => buckets.streams.take(rate, now),
+            Event::Datagram => buckets.datagrams.take(rate, now),
+        }
+    }
+
+    /// Check whether `client` may open another stream now
+    pub fn allow_stream(&self, client: &ClientKey) -> bool {
+        self.allow(client, Event::Stream)
+    }
+
+    /// Check whether `client` may send another datagram now
+    pub fn allow_datagram(&self, client: &ClientKey) -> bool {
+        self.allow(client, Event::Datagram)
+    }
+}
+
+/// The rate limiter applied to one client
+#[derive(Clone)]
+pub struct ClientRateLimit {
+    pub limiter: Arc<RateLimiter>,
+    pub client: ClientKey,
+}
+
+impl std::fmt::Debug for ClientRateLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Skip the buckets of all other clients
+        f.debug_struct("ClientRateLimit")
+            .field("client", &self.client)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientRateLimit {
+    pub fn allow_stream(&self) -> bool {
+        self.limiter.allow_stream(&self.client)
+    }
+
+    pub fn allow_datagram(&self) -> bool {
+        self.limiter.allow_datagram(&self.client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter() {
+        crate::tests::setup_logging();
+        assert!(RateLimiter::new(0, 0).is_none());
+        let limiter = RateLimiter::new(2, 0).unwrap();
+        let alice = ClientKey::new(None, IpAddr::from([192, 0, 2, 1]));
+        let bob = ClientKey::new(None, IpAddr::from([192, 0, 2, 2]));
+        assert!(limiter.allow_stream(&alice));
+        assert!(limiter.allow_stream(&alice));
+        assert!(!limiter.allow_stream(&alice));
+        // Clients do not share buckets
+        assert!(limiter.allow_stream(&bob));
+        // Datagrams are not limited
+        for _ in 0..100 {
+            assert!(limiter.allow_datagram(&alice));
+        }
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.allow_stream(&alice));
+        assert!(!limiter.allow_stream(&alice));
+        // Idle clients are forgotten
+        tokio::time::advance(PRUNE_INTERVAL).await;
+        assert!(limiter.allow_stream(&bob));
+        assert_eq!(limiter.clients.lock().buckets.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limiter_by_user() {
+        crate::tests::setup_logging();
+        let limiter = RateLimiter::new(1, 0).unwrap();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let other_ip = IpAddr::from([192, 0, 2, 2]);
+        assert!(limiter.allow_stream(&ClientKey::new(Some("alice"), ip)));
+        // The same user from another address shares the bucket
+        assert!(!limiter.allow_stream(&ClientKey::new(Some("alice"), other_ip)));
+        // Other users and anonymous clients from the same address do not
+        assert!(limiter.allow_stream(&ClientKey::new(Some("bob"), ip)));
+        assert!(limiter.allow_stream(&ClientKey::new(None, ip)));
+    }
+}
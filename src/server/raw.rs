@@ -0,0 +1,172 @@
+//! Plain TLS/TCP transport, which runs the multiplexor without the
+//! `WebSocket` layer. See [`crate::raw_transport`] for the handshake.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::service::{Session, State, serve_session, session_reply_headers};
+use super::websocket::SessionOptions;
+use crate::raw_transport::{PROTOCOL_HEADER, recv_headers, send_headers};
+use http::HeaderValue;
+use penguin_mux::negotiate_protocol_version;
+use penguin_mux::ws::{PlainStream, WebSocket};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, info, warn};
+
+/// Check the headers of the client and reply with ours.
+/// Returns `None` if the client may not connect.
+pub(super) async fn handshake<W: WebSocket>(
+    ws: &mut W,
+    state: &State<hyper::body::Incoming>,
+) -> Result<Option<(Session, SessionOptions)>, penguin_mux::Error> {
+    let Some(headers) = recv_headers(ws).await? else {
+        warn!("Invalid plain transport connection: no headers");
+        return Ok(None);
+    };
+    let offered = headers.get(PROTOCOL_HEADER);
+    let protocol = offered
+        .and_then(|offered| offered.to_str().ok())
+        .and_then(negotiate_protocol_version);
+    let Some(protocol) = protocol else {
+        warn!("Invalid plain transport connection: unsupported protocol {offered:?}");
+        return Ok(None);
+    };
+    let Ok(access) = state.authorize(&headers, None) else {
+        return Ok(None);
+    };
+    if let Some(user) = access.user_name() {
+        info!("Accepted plain transport connection for user `{user}`");
+    } else {
+        debug!("Accepted plain transport connection");
+    }
+    let (session, options) = state.start_session(&headers, access, None, state.reverse);
+    let mut reply = session_reply_headers(&session, &options);
+    reply.insert(PROTOCOL_HEADER, HeaderValue::from_static(protocol));
+    send_headers(ws, &reply).await?;
+    Ok(Some((session, options)))
+}
+
+/// Serves a single connection from a client of the plain transport,
+/// ignoring errors.
+pub async fn serve_raw<S>(stream: S, state: State<hyper::body::Incoming>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let http_timeout = state.http_timeout;
+    let mut ws = PlainStream::new(stream);
+    match http_timeout.timeout(handshake(&mut ws, &state)).await {
+        Ok(Ok(Some((session, options)))) => {
+            #[cfg(feature = "otel")]
+            crate::otel::handshake("accepted");
+            serve_session(Box::new(ws), session, options).await;
+        }
+        // Close the connection without telling the client why
+        Ok(Ok(None)) => {
+            #[cfg(feature = "otel")]
+            crate::otel::handshake("rejected");
+        }
+        Ok(Err(err)) => error!("Plain transport handshake error: {err}"),
+        Err(_) => error!("Plain transport handshake timed out after {http_timeout}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::auth::{Authenticator, Users};
+    use http::HeaderMap;
+    use penguin_mux::timing::OptionalDuration;
+    use penguin_mux::{Dupe, PROTOCOL_VERSION};
+
+    /// Connect to `serve_raw` with `headers` and return the reply
+    async fn connect(state: State<hyper::body::Incoming>, headers: HeaderMap) -> Option<HeaderMap> {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_raw(server, state));
+        let mut ws = PlainStream::new(client);
+        send_headers(&mut ws, &headers).await.unwrap();
+        recv_headers(&mut ws).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_raw_handshake() {
+        static PSK: [HeaderValue; 1] = [HeaderValue::from_static("secret")];
+        crate::tests::setup_logging();
+        let state = State::new(
+            None,
+            &PSK,
+            "",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_raw(true);
+        let mut headers = HeaderMap::new();
+        headers.insert(PROTOCOL_HEADER, HeaderValue::from_static(PROTOCOL_VERSION));
+        headers.insert("x-penguin-psk", HeaderValue::from_static("secret"));
+        let reply = connect(state.dupe(), headers.clone()).await.unwrap();
+        // No session and no compression to report
+        assert_eq!(reply.len(), 1);
+        assert_eq!(reply[PROTOCOL_HEADER], PROTOCOL_VERSION);
+        let mut padded = headers.clone();
+        padded.insert("x-penguin-padding", HeaderValue::from_static("1"));
+        let reply = connect(state.dupe(), padded).await.unwrap();
+        assert!(reply.contains_key("x-penguin-padding"));
+        let mut coalesced = headers.clone();
+        coalesced.insert("x-penguin-coalesce", HeaderValue::from_static("1"));
+        let reply = connect(state.dupe(), coalesced).await.unwrap();
+        assert!(reply.contains_key("x-penguin-coalesce"));
+        let mut wrong_psk = headers.clone();
+        wrong_psk.insert("x-penguin-psk", HeaderValue::from_static("wrong"));
+        assert!(connect(state.dupe(), wrong_psk).await.is_none());
+        let mut wrong_protocol = headers;
+        wrong_protocol.insert(PROTOCOL_HEADER, HeaderValue::from_static("penguin-v0"));
+        assert!(connect(state, wrong_protocol).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_raw_session_owner() {
+        crate::tests::setup_logging();
+        let users = Users::parse(r#"{"foo:bar": ["*"], "baz:qux": ["*"]}"#).unwrap();
+        let state = State::new(
+            None,
+            &[],
+            "",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_raw(true)
+        .with_resume_timeout(OptionalDuration::from_secs(60))
+        .with_authenticator(Authenticator {
+            users: Some(users),
+            ..Default::default()
+        });
+        let headers = |authorization: &'static str, session: &HeaderValue| {
+            let mut headers = HeaderMap::new();
+            headers.insert(PROTOCOL_HEADER, HeaderValue::from_static(PROTOCOL_VERSION));
+            headers.insert("authorization", HeaderValue::from_static(authorization));
+            headers.insert("x-penguin-session", session.dupe());
+            headers
+        };
+        // "foo:bar" and "baz:qux"
+        let (foo, baz) = ("Basic Zm9vOmJhcg==", "Basic YmF6OnF1eA==");
+        let new = HeaderValue::from_static("new");
+        // The server picks the token
+        let reply = connect(state.dupe(), headers(foo, &new)).await.unwrap();
+        let token = reply["x-penguin-session"].dupe();
+        assert_ne!(token, new);
+        assert_eq!(token.len(), 32);
+        // Wait for the session to be registered
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        // Another user cannot take over the session
+        let reply = connect(state.dupe(), headers(baz, &token)).await.unwrap();
+        assert_ne!(reply["x-penguin-session"], "resumed");
+        assert_ne!(reply["x-penguin-session"], token);
+        // Its owner can resume it
+        let reply = connect(state.dupe(), headers(foo, &token)).await.unwrap();
+        assert_eq!(reply["x-penguin-session"], "resumed");
+    }
+}
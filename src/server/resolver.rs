@@ -0,0 +1,720 @@
+//! Resolving the hostnames of forwarding targets.
+//!
+//! Targets are looked up with the system resolver, unless `--dns-upstream`
+//! names a DNS-over-HTTPS (RFC 8484) or DNS-over-TLS (RFC 7858) server to ask
+//! instead, for when the local DNS is broken or cannot be trusted, or
+//! `--resolver-nameserver` names plain DNS servers. We then ask for the A
+//! and AAAA records of the host at the same time, and try the IPv4
+//! addresses first. Addresses are never looked up, and `localhost` is always
+//! looked up with the system resolver.
+//!
+//! With `--resolver-prefer`, the addresses of one family are tried before
+//! the others. With `--resolver-cache-ttl`, the addresses of each host are
+//! kept for that long, or for as long as the records allow if we got them
+//! from a DNS server and they allow less.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::{AddressFamily, DnsUpstream, TlsArgs};
+use crate::config;
+use crate::tls::{HyperConnector, make_client_config, make_hyper_connector};
+use bytes::Bytes;
+use http::{Request, StatusCode, Uri, header};
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client as HyperClient;
+use hyper_util::rt::TokioExecutor;
+use parking_lot::Mutex;
+use penguin_mux::timing::OptionalDuration;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+#[cfg(feature = "nativetls")]
+type TlsConnector = tokio_native_tls::TlsConnector;
+#[cfg(feature = "__rustls")]
+type TlsConnector = tokio_rustls::TlsConnector;
+
+/// Media type of DNS messages over HTTPS
+const DNS_MESSAGE: &str = "application/dns-message";
+/// ALPN protocol of DNS-over-TLS
+const DOT_ALPN: [&str; 1] = ["dot"];
+/// Record type of IPv4 addresses
+const TYPE_A: u16 = 1;
+/// Record type of IPv6 addresses
+const TYPE_AAAA: u16 = 28;
+/// Internet class
+const CLASS_IN: u16 = 1;
+/// Response code of a name that does not exist
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Errors when setting up the DNS upstream
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot create HTTP client: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Tls(#[from] crate::tls::Error),
+}
+
+/// How the server looks up the targets of clients
+#[derive(Debug, Default)]
+pub struct Resolver {
+    upstream: Upstream,
+    /// Family of the addresses to try first
+    prefer: Option<AddressFamily>,
+    timeout: OptionalDuration,
+    cache: Option<Cache>,
+}
+
+/// Where the addresses of hosts come from
+#[derive(Default)]
+enum Upstream {
+    /// `getaddrinfo` or the like
+    #[default]
+    System,
+    /// Plain DNS servers, asked in order
+    Nameservers(Vec<SocketAddr>),
+    /// DNS-over-HTTPS server at a URL
+    Https {
+        client: Box<HyperClient<HyperConnector, Full<Bytes>>>,
+        uri: Uri,
+    },
+    /// DNS-over-TLS server
+    Tls {
+        host: String,
+        port: u16,
+        connector: TlsConnector,
+    },
+}
+
+impl std::fmt::Debug for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Skip the clients
+        match self {
+            Self::System => f.write_str("System"),
+            Self::Nameservers(nameservers) => {
+                f.debug_tuple("Nameservers").field(nameservers).finish()
+            }
+            Self::Https { uri, .. } => f.debug_struct("Https").field("uri", uri).finish(),
+            Self::Tls { host, port, .. } => f
+                .debug_struct("Tls")
+                .field("host", host)
+                .field("port", port)
+                .finish(),
+        }
+    }
+}
+
+impl Resolver {
+    /// Look up targets with `upstream`
+    pub async fn upstream(upstream: &DnsUpstream) -> Result<Self, Error> {
+        let upstream = match upstream {
+            DnsUpstream::Https(uri) => Upstream::Https {
+                client: Box::new(
+                    HyperClient::builder(TokioExecutor::new()).build(make_hyper_connector()?),
+                ),
+                uri: uri.clone(),
+            },
+            DnsUpstream::Tls(host, port) => {
+                let config = make_client_config(
+                    None,
+                    None,
+                    None,
+                    false,
+                    Some(&DOT_ALPN),
+                    &TlsArgs::default(),
+                    &[],
+                )
+                .await?;
+                Upstream::Tls {
+                    host: host.clone(),
+                    port: *port,
+                    #[cfg(feature = "__rustls")]
+                    connector: TlsConnector::from(std::sync::Arc::new(config)),
+                    #[cfg(feature = "nativetls")]
+                    connector: TlsConnector::from(config),
+                }
+            }
+        };
+        Ok(Self {
+            upstream,
+            ..Self::default()
+        })
+    }
+
+    /// Look up targets with the plain DNS servers `nameservers`, or the
+    /// system resolver if there are none
+    pub fn nameservers(nameservers: Vec<SocketAddr>) -> Self {
+        let upstream = if nameservers.is_empty() {
+            Upstream::System
+        } else {
+            Upstream::Nameservers(nameservers)
+        };
+        Self {
+            upstream,
+            ..Self::default()
+        }
+    }
+
+    /// Try the addresses of `family` first
+    pub const fn with_prefer(mut self, family: Option<AddressFamily>) -> Self {
+        self.prefer = family;
+        self
+    }
+
+    /// Give up on a lookup after `timeout`
+    pub const fn with_timeout(mut self, timeout: OptionalDuration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep the addresses of hosts for up to `ttl`
+    pub fn with_cache_ttl(mut self, ttl: OptionalDuration) -> Self {
+        self.cache = Option::<Duration>::from(ttl).map(Cache::new);
+        self
+    }
+
+    /// The addresses of `host` with `port`
+    ///
+    /// # Errors
+    /// Returns an error if `host` does not exist or has no addresses, or the
+    /// lookup does not finish in time.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let key = host.to_ascii_lowercase();
+        let cached = self.cache.as_ref().and_then(|cache| cache.get(&key));
+        let mut addrs = if let Some(addrs) = cached {
+            trace!("{host} is cached as {addrs:?}");
+            addrs
+        } else {
+            let (addrs, ttl) = self
+                .timeout
+                .timeout(self.query(host))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            trace!("{host} resolved to {addrs:?}");
+            if let Some(cache) = &self.cache {
+                cache.insert(key, &addrs, ttl);
+            }
+            addrs
+        };
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{host} has no addresses"),
+            ));
+        }
+        if let Some(family) = self.prefer {
+            // Stable, so each family keeps its order
+            addrs.sort_by_key(|ip| ip.is_ipv4() != (family == AddressFamily::Ipv4));
+        }
+        Ok(addrs
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Ask the upstream for the addresses of `host`, and how long they may
+    /// be cached if it says
+    async fn query(&self, host: &str) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        if matches!(self.upstream, Upstream::System) || host.eq_ignore_ascii_case("localhost") {
+            let addrs = lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect();
+            return Ok((addrs, None));
+        }
+        let id = rand::random::<u16>();
+        let queries = [
+            encode_query(id, host, TYPE_A)?,
+            encode_query(id.wrapping_add(1), host, TYPE_AAAA)?,
+        ];
+        let [answer_a, answer_aaaa] = match &self.upstream {
+            Upstream::System => unreachable!("handled above"),
+            Upstream::Nameservers(nameservers) => {
+                let (a, aaaa) = tokio::try_join!(
+                    query_nameservers(nameservers, &queries[0]),
+                    query_nameservers(nameservers, &queries[1])
+                )?;
+                [a, aaaa]
+            }
+            Upstream::Https { client, uri } => {
+                let [query_a, query_aaaa] = queries;
+                let (a, aaaa) = tokio::try_join!(
+                    query_https(client, uri, query_a),
+                    query_https(client, uri, query_aaaa)
+                )?;
+                [a, aaaa]
+            }
+            Upstream::Tls {
+                host: upstream,
+                port,
+                connector,
+            } => query_tls(upstream, *port, connector, &queries).await?,
+        };
+        let (mut addrs, ttl_a) = parse_answer(&answer_a, id)?;
+        let (addrs_aaaa, ttl_aaaa) = parse_answer(&answer_aaaa, id.wrapping_add(1))?;
+        addrs.extend(addrs_aaaa);
+        let ttl = ttl_a.into_iter().chain(ttl_aaaa).min();
+        Ok((addrs, ttl.map(|ttl| Duration::from_secs(ttl.into()))))
+    }
+}
+
+/// Addresses of the hosts we looked up recently
+#[derive(Debug)]
+struct Cache {
+    /// How long to keep them at most
+    ttl: Duration,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+impl Cache {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// The addresses of `host`, if they have not expired
+    fn get(&self, host: &str) -> Option<Vec<IpAddr>> {
+        let entries = self.entries.lock();
+        let entry = entries.get(host)?;
+        (entry.expires > Instant::now()).then(|| entry.addrs.clone())
+    }
+
+    /// Keep the addresses of `host` for our TTL, or `ttl` if it is shorter
+    fn insert(&self, host: String, addrs: &[IpAddr], ttl: Option<Duration>) {
+        let ttl = ttl.map_or(self.ttl, |ttl| ttl.min(self.ttl));
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if entries.len() >= config::RESOLVER_CACHE_SIZE {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        // Still full of live entries; start over rather than track their use
+        if entries.len() >= config::RESOLVER_CACHE_SIZE {
+            entries.clear();
+        }
+        entries.insert(
+            host,
+            CacheEntry {
+                addrs: addrs.to_vec(),
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+/// Send `query` to the first of `nameservers` that answers
+async fn query_nameservers(nameservers: &[SocketAddr], query: &[u8]) -> io::Result<Bytes> {
+    let mut last_err = None;
+    for nameserver in nameservers {
+        let result = tokio::time::timeout(
+            config::RESOLVER_NAMESERVER_TIMEOUT,
+            super::dns::resolve(query, *nameserver),
+        )
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        match result {
+            Ok(answer) => return Ok(answer),
+            Err(err) => {
+                debug!("nameserver {nameserver} failed: {err}");
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+}
+
+/// Send `query` to the DNS-over-HTTPS server at `uri`
+async fn query_https(
+    client: &HyperClient<HyperConnector, Full<Bytes>>,
+    uri: &Uri,
+    query: Vec<u8>,
+) -> io::Result<Bytes> {
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(header::ACCEPT, DNS_MESSAGE)
+        .body(Full::new(Bytes::from(query)))
+        .map_err(io::Error::other)?;
+    let response = client.request(request).await.map_err(io::Error::other)?;
+    if response.status() != StatusCode::OK {
+        return Err(io::Error::other(format!(
+            "DNS upstream returned {}",
+            response.status()
+        )));
+    }
+    Ok(response
+        .into_body()
+        .collect()
+        .await
+        .map_err(io::Error::other)?
+        .to_bytes())
+}
+
+/// Send `queries` to the DNS-over-TLS server at `host` port `port` over one
+/// connection and return the answers in the same order
+async fn query_tls(
+    host: &str,
+    port: u16,
+    connector: &TlsConnector,
+    queries: &[Vec<u8>; 2],
+) -> io::Result<[Bytes; 2]> {
+    let stream = TcpStream::connect((host, port)).await?;
+    #[cfg(feature = "__rustls")]
+    let mut stream = {
+        let name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        connector.connect(name, stream).await?
+    };
+    #[cfg(feature = "nativetls")]
+    let mut stream = connector
+        .connect(host, stream)
+        .await
+        .map_err(io::Error::other)?;
+    let mut message = Vec::new();
+    for query in queries {
+        let len = u16::try_from(query.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        message.extend_from_slice(&len.to_be_bytes());
+        message.extend_from_slice(query);
+    }
+    stream.write_all(&message).await?;
+    // The server may answer in any order
+    let mut answers = [Bytes::new(), Bytes::new()];
+    for _ in 0..queries.len() {
+        let len = stream.read_u16().await?;
+        let mut answer = vec![0; usize::from(len)];
+        stream.read_exact(&mut answer).await?;
+        let index = queries
+            .iter()
+            .position(|query| answer.get(..2) == query.get(..2))
+            .ok_or_else(malformed)?;
+        answers[index] = Bytes::from(answer);
+    }
+    Ok(answers)
+}
+
+/// A recursive query with `id` for the `qtype` records of `host`
+fn encode_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid hostname: {host}"),
+        )
+    };
+    let name = host.strip_suffix('.').unwrap_or(host);
+    if name.len() > 253 {
+        return Err(invalid());
+    }
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        let len = u8::try_from(label.len())
+            .ok()
+            .filter(|len| (1..=63).contains(len))
+            .ok_or_else(invalid)?;
+        query.push(len);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// The addresses in `answer` to the query with `id`, and the shortest TTL
+/// of their records
+fn parse_answer(answer: &[u8], id: u16) -> io::Result<(Vec<IpAddr>, Option<u32>)> {
+    let mut reader = Reader(answer);
+    let header = reader.take(12)?;
+    // Must be a response to our query
+    if header[..2] != id.to_be_bytes() || header[2] & 0x80 == 0 {
+        return Err(malformed());
+    }
+    match header[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(io::ErrorKind::NotFound.into()),
+        rcode => {
+            return Err(io::Error::other(format!(
+                "DNS upstream answered with RCODE {rcode}"
+            )));
+        }
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+    for _ in 0..questions {
+        reader.skip_name()?;
+        reader.take(4)?;
+    }
+    let mut addrs = Vec::new();
+    let mut min_ttl = None;
+    for _ in 0..answers {
+        reader.skip_name()?;
+        let rtype = reader.u16()?;
+        let class = reader.u16()?;
+        let ttl = reader.u32()?;
+        let len = reader.u16()?;
+        let data = reader.take(usize::from(len))?;
+        // CNAMEs are followed by the records of their targets
+        match (
+            rtype,
+            class,
+            <[u8; 4]>::try_from(data),
+            <[u8; 16]>::try_from(data),
+        ) {
+            (TYPE_A, CLASS_IN, Ok(octets), _) => addrs.push(Ipv4Addr::from(octets).into()),
+            (TYPE_AAAA, CLASS_IN, _, Ok(octets)) => addrs.push(Ipv6Addr::from(octets).into()),
+            _ => continue,
+        }
+        min_ttl = Some(min_ttl.map_or(ttl, |min: u32| min.min(ttl)));
+    }
+    Ok((addrs, min_ttl))
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed DNS answer")
+}
+
+/// Reads a DNS message from the start
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(malformed());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let octets = self.take(2)?;
+        Ok(u16::from_be_bytes([octets[0], octets[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let octets = self.take(4)?;
+        Ok(u32::from_be_bytes([
+            octets[0], octets[1], octets[2], octets[3],
+        ]))
+    }
+
+    /// Skip a name, which ends with an empty label or a compression pointer
+    fn skip_name(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.take(1)?[0];
+            match len {
+                0 => return Ok(()),
+                len if len & 0xc0 == 0xc0 => {
+                    self.take(1)?;
+                    return Ok(());
+                }
+                len => {
+                    self.take(usize::from(len))?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// The answer to `query` with `records` of the type it asks for
+    fn make_answer(query: &[u8], records: &[&[u8]]) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] |= 0x80;
+        answer[7] = u8::try_from(records.len()).unwrap();
+        let qtype = &query[query.len() - 4..query.len() - 2];
+        // A CNAME first, to be skipped
+        answer[7] += 1;
+        answer.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 30, 0, 2, 0xc0, 12]);
+        for record in records {
+            answer.extend_from_slice(&[0xc0, 12]);
+            answer.extend_from_slice(qtype);
+            answer.extend_from_slice(&[0, 1, 0, 0, 0, 60, 0, u8::try_from(record.len()).unwrap()]);
+            answer.extend_from_slice(record);
+        }
+        answer
+    }
+
+    #[test]
+    fn test_encode_query() {
+        assert_eq!(
+            encode_query(0x1234, "example.com.", TYPE_AAAA).unwrap(),
+            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x1c\x00\x01"
+        );
+        assert!(encode_query(1, "example..com", TYPE_A).is_err());
+        assert!(encode_query(1, &"a".repeat(64), TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_answer() {
+        let query = encode_query(7, "example.com", TYPE_A).unwrap();
+        let answer = make_answer(&query, &[&[192, 0, 2, 1], &[192, 0, 2, 2]]);
+        assert_eq!(
+            parse_answer(&answer, 7).unwrap(),
+            (
+                vec![IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])],
+                Some(60)
+            )
+        );
+        // Another ID
+        assert!(parse_answer(&answer, 8).is_err());
+        // Truncated
+        assert!(parse_answer(&answer[..answer.len() - 1], 7).is_err());
+        let mut nxdomain = query.clone();
+        nxdomain[2] |= 0x80;
+        nxdomain[3] |= RCODE_NXDOMAIN;
+        assert_eq!(
+            parse_answer(&nxdomain, 7).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_https() {
+        crate::tests::setup_logging();
+        // A DoH server that speaks just enough HTTP/1.1
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let uri: Uri = format!("http://{}/dns-query", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    // The query is small enough to come with the headers
+                    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                        let len = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..len]);
+                    }
+                    let headers_end = request
+                        .windows(4)
+                        .position(|window| window == b"\r\n\r\n")
+                        .unwrap()
+                        + 4;
+                    let headers = String::from_utf8_lossy(&request[..headers_end]).to_lowercase();
+                    assert!(headers.starts_with("post /dns-query "));
+                    assert!(headers.contains("content-type: application/dns-message"));
+                    let len: usize = headers
+                        .lines()
+                        .find_map(|line| line.strip_prefix("content-length: "))
+                        .unwrap()
+                        .trim()
+                        .parse()
+                        .unwrap();
+                    while request.len() < headers_end + len {
+                        let read = stream.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..read]);
+                    }
+                    let query = &request[headers_end..headers_end + len];
+                    let answer = if query.ends_with(&[0, 1, 0, 1]) {
+                        make_answer(query, &[&[192, 0, 2, 1]])
+                    } else {
+                        make_answer(query, &[&Ipv6Addr::LOCALHOST.octets()])
+                    };
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: {DNS_MESSAGE}\r\ncontent-length: {}\r\n\r\n",
+                        answer.len()
+                    );
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    stream.write_all(&answer).await.unwrap();
+                });
+            }
+        });
+        let resolver = Resolver {
+            upstream: Upstream::Https {
+                client: Box::new(
+                    HyperClient::builder(TokioExecutor::new())
+                        .build(make_hyper_connector().unwrap()),
+                ),
+                uri,
+            },
+            ..Resolver::default()
+        };
+        assert_eq!(
+            resolver.lookup("example.com", 443).await.unwrap(),
+            [
+                SocketAddr::from(([192, 0, 2, 1], 443)),
+                SocketAddr::from((Ipv6Addr::LOCALHOST, 443))
+            ]
+        );
+        // Not sent upstream
+        assert_eq!(
+            resolver.lookup("192.0.2.7", 80).await.unwrap(),
+            [SocketAddr::from(([192, 0, 2, 7], 80))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_nameservers() {
+        crate::tests::setup_logging();
+        // Nothing listens here anymore
+        let dead = tokio::net::UdpSocket::bind(("127.0.0.1", 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let socket = tokio::net::UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let alive = socket.local_addr().unwrap();
+        let queries = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let query = &buf[..len];
+                let answer = if query.ends_with(&[0, 1, 0, 1]) {
+                    make_answer(query, &[&[192, 0, 2, 1]])
+                } else {
+                    make_answer(query, &[&Ipv6Addr::LOCALHOST.octets()])
+                };
+                socket.send_to(&answer, addr).await.unwrap();
+            }
+        });
+        let resolver = Resolver::nameservers(vec![dead, alive])
+            .with_prefer(Some(AddressFamily::Ipv6))
+            .with_timeout(OptionalDuration::from_secs(10))
+            .with_cache_ttl(OptionalDuration::from_secs(300));
+        for _ in 0..2 {
+            assert_eq!(
+                resolver.lookup("Example.com", 443).await.unwrap(),
+                [
+                    SocketAddr::from((Ipv6Addr::LOCALHOST, 443)),
+                    SocketAddr::from(([192, 0, 2, 1], 443))
+                ]
+            );
+        }
+        // The second lookup is cached, for the TTL of the records
+        assert_eq!(queries.load(std::sync::atomic::Ordering::Relaxed), 2);
+        let cache = resolver.cache.as_ref().unwrap();
+        let expires = cache.entries.lock()["example.com"].expires;
+        assert!(expires <= Instant::now() + Duration::from_mins(1));
+    }
+}
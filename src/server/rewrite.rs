@@ -0,0 +1,132 @@
+//! Rewriting the targets of clients before connecting.
+//!
+//! The rewrite file is a JSON list of rules such as
+//! `{"match": "*.internal:443", "host": "10.0.0.5", "port": 8443}`.
+//! `match` has the same format as `--allow`. A target matching it is
+//! forwarded to `host` and `port` instead, each defaulting to the requested
+//! one if missing, so operators can present stable names to clients while
+//! moving the actual services around. The first matching rule applies.
+//! Clients are authorized for the target they requested, but `--allow` and
+//! `--deny` apply to the rewritten one.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::acl::{InvalidRule, Rule};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Errors when loading the rewrite file
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read rewrite rules: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid rewrite rules: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid rewrite rule: {0}")]
+    Rule(#[from] InvalidRule),
+    #[error("Invalid rewrite rule `{0}`: it must set `host` or `port`")]
+    Empty(String),
+}
+
+/// A rule as written in the rewrite file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRewrite {
+    #[serde(rename = "match")]
+    pattern: String,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+/// Where a matching target is sent instead
+#[derive(Debug)]
+struct Rewrite {
+    pattern: Rule,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+/// Rules to rewrite targets with, in order
+#[derive(Debug, Default)]
+pub struct Rewrites(Vec<Rewrite>);
+
+impl Rewrites {
+    /// Parse the content of a rewrite file
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let raw: Vec<RawRewrite> = serde_json::from_str(content)?;
+        raw.into_iter()
+            .map(|raw| {
+                if raw.host.is_none() && raw.port.is_none() {
+                    return Err(Error::Empty(raw.pattern));
+                }
+                Ok(Rewrite {
+                    pattern: raw.pattern.parse()?,
+                    host: raw.host,
+                    port: raw.port,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Load a rewrite file
+    pub async fn load(path: &str) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path).await?;
+        Self::parse(&content)
+    }
+
+    /// Where to connect to for `host:port`
+    pub fn apply<'a>(&'a self, host: &'a str, port: u16) -> (&'a str, u16) {
+        self.0
+            .iter()
+            .find(|rewrite| rewrite.pattern.matches_unresolved(host, port))
+            .map_or((host, port), |rewrite| {
+                (
+                    rewrite.host.as_deref().unwrap_or(host),
+                    rewrite.port.unwrap_or(port),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REWRITES: &str = r#"[
+        {"match": "*.internal:443", "host": "10.0.0.5", "port": 8443},
+        {"match": "db.internal", "host": "db-2.example.com"},
+        {"match": "legacy.example.com", "port": 8080}
+    ]"#;
+
+    #[test]
+    fn test_rewrites() {
+        crate::tests::setup_logging();
+        let rewrites = Rewrites::parse(REWRITES).unwrap();
+        assert_eq!(rewrites.apply("web.internal", 443), ("10.0.0.5", 8443));
+        // The first matching rule applies
+        assert_eq!(rewrites.apply("db.internal", 443), ("10.0.0.5", 8443));
+        assert_eq!(
+            rewrites.apply("DB.internal.", 5432),
+            ("db-2.example.com", 5432)
+        );
+        assert_eq!(
+            rewrites.apply("legacy.example.com", 80),
+            ("legacy.example.com", 8080)
+        );
+        assert_eq!(rewrites.apply("web.internal", 80), ("web.internal", 80));
+        assert_eq!(Rewrites::default().apply("::1", 22), ("::1", 22));
+        assert!(matches!(
+            Rewrites::parse(r#"[{"match": "a:b:c", "port": 1}]"#),
+            Err(Error::Rule(_))
+        ));
+        assert!(matches!(
+            Rewrites::parse(r#"[{"match": "example.com"}]"#),
+            Err(Error::Empty(_))
+        ));
+        assert!(matches!(
+            Rewrites::parse(r#"[{"host": "example.com"}]"#),
+            Err(Error::Json(_))
+        ));
+    }
+}
@@ -0,0 +1,420 @@
+//! Restricting the server with a seccomp filter once it has started.
+//!
+//! After the listeners are bound and the TLS files are loaded, the server
+//! still needs to read files (to reload the certificate and to resolve
+//! names) and to use the network, but nothing else. The filter only allows
+//! the syscalls needed for that in every thread, and everything else fails
+//! with `EPERM`. Files can only be opened for reading, and threads cannot
+//! be created in new namespaces. Landlock is not used because it only
+//! restricts the calling thread, and the runtime's worker threads are
+//! already running by then.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#![allow(unsafe_code)]
+#![cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "aarch64")),
+    allow(dead_code)
+)]
+
+use nix::libc;
+use thiserror::Error;
+use tracing::info;
+
+/// Errors when applying the filter
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot apply seccomp filter: {0}")]
+    Seccomp(std::io::Error),
+    #[error("Cannot apply seccomp filter to thread {0}")]
+    Thread(libc::c_long),
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    #[error("`--sandbox` is not supported on this architecture")]
+    UnsupportedArch,
+}
+
+/// `AUDIT_ARCH_X86_64`
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+/// `AUDIT_ARCH_AARCH64`
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// BPF opcodes
+const LD_W_ABS: u16 = 0x20;
+const JEQ_K: u16 = 0x15;
+#[cfg(target_arch = "x86_64")]
+const JGE_K: u16 = 0x35;
+const JSET_K: u16 = 0x45;
+const RET_K: u16 = 0x06;
+
+// Offsets in `struct seccomp_data`
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+const ARGS_OFFSET: u32 = 16;
+
+/// Flags of `open` that allow modifying the file
+const WRITE_FLAGS: libc::c_int = libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC;
+
+/// Flags of `clone` that create namespaces
+const NAMESPACE_FLAGS: libc::c_int = libc::CLONE_NEWNS
+    | libc::CLONE_NEWCGROUP
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET;
+
+/// Syscalls that the server uses once it has started. Everything else fails
+/// with `EPERM`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED: &[libc::c_long] = &[
+    // Reading and writing open files and sockets
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_close,
+    libc::SYS_close_range,
+    libc::SYS_lseek,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    // Looking at files, e.g. to reload the TLS certificate
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    // Waiting for events
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_futex,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_gettimeofday,
+    libc::SYS_sched_yield,
+    // The network
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    // Memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    // Threads and signals
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_set_tid_address,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_sigaltstack,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Information about the process and the system
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_getrandom,
+    libc::SYS_getrusage,
+    libc::SYS_prlimit64,
+    libc::SYS_sysinfo,
+    libc::SYS_uname,
+];
+
+/// Older syscalls that only exist on some architectures
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_getdents,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_wait,
+    libc::SYS_getrlimit,
+    libc::SYS_time,
+    libc::SYS_arch_prctl,
+];
+#[cfg(target_arch = "aarch64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+/// Syscalls that open files, with the index of their flags argument
+#[cfg(target_arch = "x86_64")]
+const OPENS: &[(libc::c_long, u32)] = &[(libc::SYS_openat, 2), (libc::SYS_open, 1)];
+#[cfg(target_arch = "aarch64")]
+const OPENS: &[(libc::c_long, u32)] = &[(libc::SYS_openat, 2)];
+
+const fn stmt(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// A syscall number as seen by the filter
+fn nr(syscall: libc::c_long) -> u32 {
+    u32::try_from(syscall).expect("Syscall number out of range (this is a bug)")
+}
+
+/// Build the filter program
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn make_filter() -> Vec<libc::sock_filter> {
+    let deny = libc::SECCOMP_RET_ERRNO | nr(libc::EPERM.into());
+    let allow = libc::SECCOMP_RET_ALLOW;
+    let mut filter = vec![
+        // Syscall numbers differ between architectures
+        stmt(LD_W_ABS, ARCH_OFFSET),
+        jump(JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+        stmt(LD_W_ABS, NR_OFFSET),
+    ];
+    // x32 syscalls have their own numbers
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([jump(JGE_K, 0x4000_0000, 0, 1), stmt(RET_K, deny)]);
+    for syscall in ALLOWED.iter().chain(ALLOWED_LEGACY) {
+        filter.extend([jump(JEQ_K, nr(*syscall), 0, 1), stmt(RET_K, allow)]);
+    }
+    // Flags are checked on their low half, as both architectures are
+    // little-endian
+    let write_flags = nr(WRITE_FLAGS.into());
+    for (syscall, flags_arg) in OPENS {
+        filter.extend([
+            jump(JEQ_K, nr(*syscall), 0, 4),
+            stmt(LD_W_ABS, ARGS_OFFSET + flags_arg * 8),
+            jump(JSET_K, write_flags, 0, 1),
+            stmt(RET_K, deny),
+            stmt(RET_K, allow),
+        ]);
+    }
+    // Threads are fine, new namespaces are not
+    filter.extend([
+        jump(JEQ_K, nr(libc::SYS_clone), 0, 4),
+        stmt(LD_W_ABS, ARGS_OFFSET),
+        jump(JSET_K, nr(NAMESPACE_FLAGS.into()), 0, 1),
+        stmt(RET_K, deny),
+        stmt(RET_K, allow),
+    ]);
+    // The flags of `clone3` are behind a pointer, so we cannot check them.
+    // `ENOSYS` makes the C library fall back to `clone`.
+    filter.extend([
+        jump(JEQ_K, nr(libc::SYS_clone3), 0, 1),
+        stmt(RET_K, libc::SECCOMP_RET_ERRNO | nr(libc::ENOSYS.into())),
+    ]);
+    filter.push(stmt(RET_K, deny));
+    filter
+}
+
+/// Apply the filter to all threads
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn apply() -> Result<(), Error> {
+    let filter = make_filter();
+    let prog = libc::sock_fprog {
+        len: u16::try_from(filter.len()).expect("Seccomp filter is too long (this is a bug)"),
+        filter: filter.as_ptr().cast_mut(),
+    };
+    // SAFETY: this takes no pointers
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(Error::Seccomp(std::io::Error::last_os_error()));
+    }
+    // SAFETY: `prog` points to `filter`, which outlives the call, and the
+    // kernel does not write to it
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            libc::SECCOMP_FILTER_FLAG_TSYNC,
+            &raw const prog,
+        )
+    };
+    match ret {
+        0 => {
+            info!("Applied seccomp filter");
+            Ok(())
+        }
+        // The ID of a thread that cannot take the filter
+        1.. => Err(Error::Thread(ret)),
+        _ => Err(Error::Seccomp(std::io::Error::last_os_error())),
+    }
+}
+
+/// Apply the filter to all threads
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub const fn apply() -> Result<(), Error> {
+    Err(Error::UnsupportedArch)
+}
+
+#[cfg(all(test, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    /// Run the filter on a syscall, like the kernel does
+    fn run(filter: &[libc::sock_filter], arch: u32, syscall: libc::c_long, args: [u32; 6]) -> u32 {
+        let mut acc = 0;
+        let mut pc = 0;
+        loop {
+            let insn = filter[pc];
+            pc += 1;
+            match insn.code {
+                LD_W_ABS => {
+                    acc = match insn.k {
+                        NR_OFFSET => nr(syscall),
+                        ARCH_OFFSET => arch,
+                        k => args[((k - ARGS_OFFSET) / 8) as usize],
+                    };
+                }
+                RET_K => return insn.k,
+                code => {
+                    let taken = match code {
+                        JEQ_K => acc == insn.k,
+                        JSET_K => acc & insn.k != 0,
+                        #[cfg(target_arch = "x86_64")]
+                        JGE_K => acc >= insn.k,
+                        _ => unreachable!("Unknown opcode {code:#x}"),
+                    };
+                    pc += usize::from(if taken { insn.jt } else { insn.jf });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_seccomp_filter() {
+        crate::tests::setup_logging();
+        let filter = make_filter();
+        let deny = libc::SECCOMP_RET_ERRNO | nr(libc::EPERM.into());
+        let allow = libc::SECCOMP_RET_ALLOW;
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_read, [0; 6]), allow);
+        assert_eq!(run(&filter, AUDIT_ARCH, libc::SYS_connect, [0; 6]), allow);
+        for syscall in [
+            libc::SYS_execve,
+            libc::SYS_setuid,
+            libc::SYS_unlinkat,
+            libc::SYS_io_uring_setup,
+            libc::SYS_io_uring_enter,
+            libc::SYS_io_uring_register,
+            libc::SYS_openat2,
+            libc::SYS_ftruncate,
+            libc::SYS_fallocate,
+            libc::SYS_utimensat,
+            libc::SYS_renameat2,
+            libc::SYS_unshare,
+        ] {
+            assert_eq!(
+                run(&filter, AUDIT_ARCH, syscall, [0; 6]),
+                deny,
+                "syscall {syscall}"
+            );
+        }
+        let read_only = nr((libc::O_RDONLY | libc::O_CLOEXEC).into());
+        let write = nr((libc::O_WRONLY | libc::O_CREAT).into());
+        assert_eq!(
+            run(
+                &filter,
+                AUDIT_ARCH,
+                libc::SYS_openat,
+                [0, 0, read_only, 0, 0, 0]
+            ),
+            allow
+        );
+        assert_eq!(
+            run(
+                &filter,
+                AUDIT_ARCH,
+                libc::SYS_openat,
+                [0, 0, write, 0, 0, 0]
+            ),
+            deny
+        );
+        // What the C library uses to create threads
+        let thread = nr((libc::CLONE_VM
+            | libc::CLONE_FS
+            | libc::CLONE_FILES
+            | libc::CLONE_SIGHAND
+            | libc::CLONE_THREAD
+            | libc::CLONE_SYSVSEM
+            | libc::CLONE_SETTLS
+            | libc::CLONE_PARENT_SETTID
+            | libc::CLONE_CHILD_CLEARTID)
+            .into());
+        assert_eq!(
+            run(
+                &filter,
+                AUDIT_ARCH,
+                libc::SYS_clone,
+                [thread, 0, 0, 0, 0, 0]
+            ),
+            allow
+        );
+        for namespace in [libc::CLONE_NEWUSER, libc::CLONE_NEWNET, libc::CLONE_NEWNS] {
+            let flags = nr(libc::SIGCHLD.into()) | nr(namespace.into());
+            assert_eq!(
+                run(&filter, AUDIT_ARCH, libc::SYS_clone, [flags, 0, 0, 0, 0, 0]),
+                deny
+            );
+        }
+        assert_eq!(
+            run(&filter, AUDIT_ARCH, libc::SYS_clone3, [0; 6]),
+            libc::SECCOMP_RET_ERRNO | nr(libc::ENOSYS.into())
+        );
+        assert_eq!(
+            run(&filter, 0, libc::SYS_read, [0; 6]),
+            libc::SECCOMP_RET_KILL_PROCESS
+        );
+    }
+}
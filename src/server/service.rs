@@ -2,31 +2,66 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use super::websocket::handle_websocket;
-use crate::arg::BackendUrl;
+use super::WebSocket;
+use super::access_log::{self, AccessLog, Rejected};
+use super::admin::Registry;
+use super::audit_log::AuditLog;
+use super::auth::{Access, Authenticator};
+use super::client_cert::ClientCert;
+use super::egress::Egress;
+use super::endpoint::{Endpoint, Endpoints};
+use super::forwarder::UdpOptions;
+use super::masquerade::Masquerade;
+use super::rate_limit::{ClientKey, ClientRateLimit, RateLimiter};
+use super::resolver::Resolver;
+use super::rewrite::Rewrites;
+use super::static_files;
+use super::websocket::{NewSession, SessionOptions, SessionOwner, Sessions};
+use super::websocket::{handle_websocket, new_session_token};
+use crate::acl::Acl;
+#[cfg(feature = "e2e")]
+use crate::arg::E2eKey;
+use crate::arg::{BackendUrl, Header, LimitArgs, LockTarget, MuxArgs, WsDeflateArgs};
+#[cfg(feature = "chisel")]
+use crate::chisel::HostKey;
 use crate::tls::HyperConnector;
+use crate::udp_error::UDP_ERRORS_HEADER;
+use crate::ws_deflate::{Agreed, DeflateStream};
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
 use bytes::Bytes;
-use http::{HeaderValue, Method, Request, Response, StatusCode, Uri, header};
+use http::uri::Authority;
+use http::{
+    HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri, header,
+};
+use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Full as FullBody};
 use hyper::body::Body;
 use hyper::service::Service;
 use hyper::upgrade::OnUpgrade;
 use hyper_util::client::legacy::{Client as HyperClient, Error as HyperClientError};
 use hyper_util::rt::{TokioExecutor, TokioIo};
-use penguin_mux::{Dupe, PROTOCOL_VERSION, timing::OptionalDuration};
+use penguin_mux::{
+    Compression, Dupe, Resumer, negotiate_protocol_version, timing::OptionalDuration,
+};
 use sha1::{Digest, Sha1};
+use std::net::SocketAddr;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::protocol::Role;
-use tracing::{debug, error, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, debug, debug_span, error, info, warn};
 
 static UPGRADE: HeaderValue = HeaderValue::from_static("upgrade");
 static WEBSOCKET: HeaderValue = HeaderValue::from_static("websocket");
-static WANTED_PROTOCOL: HeaderValue = HeaderValue::from_static(PROTOCOL_VERSION);
 static WEBSOCKET_VERSION: HeaderValue = HeaderValue::from_static("13");
+/// Sent by clients that want a new resumable session
+static SESSION_NEW: HeaderValue = HeaderValue::from_static("new");
+static SESSION_RESUMED: HeaderValue = HeaderValue::from_static("resumed");
 
 macro_rules! header_matches {
     ($given:expr, $wanted:expr) => {
@@ -48,6 +83,28 @@ fn make_sec_websocket_accept(key: &HeaderValue) -> HeaderValue {
     accept.parse().expect("Broken header value (this is a bug)")
 }
 
+/// Body of our responses, which may be streamed from the backend
+pub(super) type ResponseBody = BoxBody<Bytes, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Headers that only apply to a single connection and must not be
+/// forwarded, in addition to those listed in `Connection`
+static HOP_BY_HOP_HEADERS: [header::HeaderName; 7] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Make a response body from a buffer
+pub(super) fn full_body(data: impl Into<Bytes>) -> ResponseBody {
+    FullBody::new(data.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
+
 /// Possible errors when processing requests.
 /// Any of these actually should never happen.
 #[derive(Debug, Error)]
@@ -60,53 +117,179 @@ pub(super) enum Error {
     Body(#[from] hyper::Error),
 }
 
+/// What to do with the session of a `WebSocket` connection
+pub(super) enum Session {
+    /// The client did not ask for a resumable session or we do not allow it
+    None,
+    /// Start a new resumable session
+    New(NewSession),
+    /// Continue an existing session on the connection
+    Resume(Resumer<WebSocket>),
+}
+
 /// Required state for each request.
 #[derive(Clone, Debug)]
-pub(super) struct State<'a, B> {
+#[allow(clippy::struct_excessive_bools)]
+pub(super) struct State<B> {
     /// Backend URL
-    backend: Option<&'a BackendUrl>,
-    /// Websocket PSK
-    ws_psk: Option<&'a HeaderValue>,
-    /// 404 response
-    not_found_resp: &'a str,
+    backend: Option<Arc<BackendUrl>>,
+    /// Websocket PSKs, any of which is accepted
+    ws_psk: Arc<[HeaderValue]>,
+    /// Body of the 404 response
+    not_found_resp: Bytes,
+    /// Status code of the 404 response
+    not_found_status: StatusCode,
+    /// Additional headers of the 404 response
+    not_found_headers: Arc<[Header]>,
+    /// Path of the WebSocket endpoint
+    ws_path: Arc<str>,
+    /// Directory of files to serve when there is no backend
+    static_dir: Option<Arc<Path>>,
+    /// Web server to make the responses look like
+    masquerade: Option<Masquerade>,
+    /// Additional WebSocket endpoints with their own PSKs and rules
+    endpoints: Arc<Endpoints>,
     /// Whether to obfuscate
     obfs: bool,
     /// Whether we accept reverse binding
-    reverse: bool,
+    pub reverse: bool,
     /// Backend client
     client: HyperClient<HyperConnector, B>,
     /// TLS handshake timeout
     pub tls_timeout: OptionalDuration,
     /// HTTP timeout
     pub http_timeout: OptionalDuration,
+    /// How long to keep sessions that lost their connection
+    resume_timeout: OptionalDuration,
+    /// Resumable sessions
+    sessions: Sessions,
+    /// Compression algorithms clients may ask for
+    compression: Arc<[Compression]>,
+    /// Bandwidth limits of each session
+    limits: LimitArgs,
+    /// Flow control settings of each session
+    mux: MuxArgs,
+    /// Limits how fast each client may open streams and send datagrams
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Which destinations clients may forward to
+    acl: Arc<Acl>,
+    /// How clients authenticate
+    auth: Arc<Authenticator>,
+    /// Whether to send PROXY protocol headers to forwarding destinations
+    send_proxy_protocol: bool,
+    /// Whether to answer `penguin bench` clients
+    bench: bool,
+    /// How often to ping clients
+    keepalive: OptionalDuration,
+    /// How UDP flows are forwarded
+    udp: UdpOptions,
+    /// Resolver that answers the queries of `dns` remotes
+    dns_resolver: Option<SocketAddr>,
+    /// How the targets of clients are looked up
+    resolver: Arc<Resolver>,
+    /// Where forwarded traffic leaves from
+    egress: Arc<Egress>,
+    /// How the targets of clients are rewritten
+    rewrites: Arc<Rewrites>,
+    /// The only target, if the server is locked to one
+    lock: Option<Arc<LockTarget>>,
+    /// Sessions being served, for the admin API
+    registry: Registry,
+    /// Cancelled when the server stops, to close the connections and the
+    /// sessions that outlive their listeners
+    pub shutdown: CancellationToken,
+    /// Address of the client of this connection
+    peer: Option<SocketAddr>,
+    /// TLS certificate of the client of this connection
+    peer_cert: Option<Arc<ClientCert>>,
+    /// Whether connections use the plain transport instead of HTTP
+    pub raw: bool,
+    /// Secret for end-to-end encryption, if required
+    #[cfg(feature = "e2e")]
+    e2e_key: Option<Arc<E2eKey>>,
+    /// Whether and how to agree to `permessage-deflate`
+    ws_deflate: WsDeflateArgs,
+    /// Largest `permessage-deflate` window of clients, in bits
+    ws_deflate_window_bits: Option<u8>,
+    /// Whether the connection uses TLS
+    tls: bool,
+    /// Whether to send the authority of the backend URL as `Host` to the
+    /// backend instead of the one the client asked for
+    backend_rewrite_host: bool,
+    /// Where to log the requests, if anywhere
+    access_log: Option<Arc<AccessLog>>,
+    /// Where to log the flows of the sessions, if anywhere
+    audit_log: Option<Arc<AuditLog>>,
+    /// Host key to accept `chisel` clients with, if enabled
+    #[cfg(feature = "chisel")]
+    chisel: Option<Arc<HostKey>>,
 }
 
-impl<B> Dupe for State<'_, B> {
+impl<B> Dupe for State<B> {
     fn dupe(&self) -> Self {
         Self {
-            backend: self.backend,
-            ws_psk: self.ws_psk,
-            not_found_resp: self.not_found_resp,
+            backend: self.backend.as_ref().map(Dupe::dupe),
+            ws_psk: self.ws_psk.dupe(),
+            not_found_resp: self.not_found_resp.dupe(),
+            not_found_status: self.not_found_status,
+            not_found_headers: self.not_found_headers.dupe(),
+            ws_path: self.ws_path.dupe(),
+            static_dir: self.static_dir.as_ref().map(Dupe::dupe),
+            masquerade: self.masquerade,
+            endpoints: self.endpoints.dupe(),
             obfs: self.obfs,
             reverse: self.reverse,
             // `hyper` client is designed to be cheaply cloned.
             client: self.client.clone(),
             tls_timeout: self.tls_timeout,
             http_timeout: self.http_timeout,
+            resume_timeout: self.resume_timeout,
+            sessions: self.sessions.dupe(),
+            compression: self.compression.dupe(),
+            limits: self.limits,
+            mux: self.mux,
+            rate_limiter: self.rate_limiter.as_ref().map(Dupe::dupe),
+            acl: self.acl.dupe(),
+            auth: self.auth.dupe(),
+            send_proxy_protocol: self.send_proxy_protocol,
+            bench: self.bench,
+            keepalive: self.keepalive,
+            udp: self.udp,
+            dns_resolver: self.dns_resolver,
+            resolver: self.resolver.dupe(),
+            egress: self.egress.dupe(),
+            rewrites: self.rewrites.dupe(),
+            lock: self.lock.as_ref().map(Dupe::dupe),
+            registry: self.registry.dupe(),
+            // Cloning a `CancellationToken` only bumps a reference count
+            shutdown: self.shutdown.clone(),
+            peer: self.peer,
+            peer_cert: self.peer_cert.as_ref().map(Dupe::dupe),
+            raw: self.raw,
+            #[cfg(feature = "e2e")]
+            e2e_key: self.e2e_key.as_ref().map(Dupe::dupe),
+            ws_deflate: self.ws_deflate,
+            ws_deflate_window_bits: self.ws_deflate_window_bits,
+            tls: self.tls,
+            backend_rewrite_host: self.backend_rewrite_host,
+            access_log: self.access_log.as_ref().map(Dupe::dupe),
+            audit_log: self.audit_log.as_ref().map(Dupe::dupe),
+            #[cfg(feature = "chisel")]
+            chisel: self.chisel.as_ref().map(Dupe::dupe),
         }
     }
 }
 
-impl<'a, B> State<'a, B>
+impl<B> State<B>
 where
     B: Body + Send,
     <B as Body>::Data: Send,
 {
     /// Create a new `State`
     pub fn new(
-        backend: Option<&'a BackendUrl>,
-        ws_psk: Option<&'a HeaderValue>,
-        not_found_resp: &'a str,
+        backend: Option<&BackendUrl>,
+        ws_psk: &[HeaderValue],
+        not_found_resp: &str,
         obfs: bool,
         reverse: bool,
         tls_timeout: OptionalDuration,
@@ -115,44 +298,343 @@ where
         let client =
             HyperClient::builder(TokioExecutor::new()).build(crate::tls::make_hyper_connector()?);
         Ok(Self {
-            backend,
-            ws_psk,
-            not_found_resp,
+            backend: backend.cloned().map(Arc::new),
+            ws_psk: ws_psk.into(),
+            not_found_resp: Bytes::copy_from_slice(not_found_resp.as_bytes()),
+            not_found_status: StatusCode::NOT_FOUND,
+            not_found_headers: Arc::default(),
+            ws_path: Arc::from("/ws"),
+            static_dir: None,
+            masquerade: None,
+            endpoints: Arc::default(),
             obfs,
             reverse,
             client,
             tls_timeout,
             http_timeout,
+            resume_timeout: OptionalDuration::NONE,
+            sessions: Sessions::default(),
+            compression: Arc::default(),
+            limits: LimitArgs::default(),
+            mux: MuxArgs::default(),
+            rate_limiter: None,
+            acl: Arc::default(),
+            auth: Arc::default(),
+            send_proxy_protocol: false,
+            bench: false,
+            keepalive: OptionalDuration::NONE,
+            udp: UdpOptions::default(),
+            dns_resolver: None,
+            resolver: Arc::default(),
+            egress: Arc::default(),
+            rewrites: Arc::default(),
+            lock: None,
+            registry: Registry::default(),
+            shutdown: CancellationToken::new(),
+            peer: None,
+            peer_cert: None,
+            raw: false,
+            #[cfg(feature = "e2e")]
+            e2e_key: None,
+            ws_deflate: WsDeflateArgs::default(),
+            ws_deflate_window_bits: None,
+            tls: false,
+            backend_rewrite_host: false,
+            access_log: None,
+            audit_log: None,
+            #[cfg(feature = "chisel")]
+            chisel: None,
         })
     }
+
+    /// Upgrade to WebSocket on `ws_path` instead of `/ws`
+    pub fn with_ws_path(mut self, ws_path: &str) -> Self {
+        self.ws_path = Arc::from(ws_path);
+        self
+    }
+
+    /// Customize the 404 response. `body` replaces the one given to `new`.
+    pub fn with_not_found(
+        mut self,
+        status: StatusCode,
+        headers: &[Header],
+        body: Option<Bytes>,
+    ) -> Self {
+        self.not_found_status = status;
+        self.not_found_headers = headers.into();
+        if let Some(body) = body {
+            self.not_found_resp = body;
+        }
+        self
+    }
+
+    /// Make the responses look like they come from another web server
+    pub const fn with_masquerade(mut self, masquerade: Option<Masquerade>) -> Self {
+        self.masquerade = masquerade;
+        self
+    }
+
+    /// Serve the files in `static_dir` when there is no backend
+    pub fn with_static_dir(mut self, static_dir: Option<&Path>) -> Self {
+        self.static_dir = static_dir.map(Arc::from);
+        self
+    }
+
+    /// Also upgrade to WebSocket on the paths of `endpoints`
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = Arc::new(endpoints);
+        self
+    }
+
+    /// Allow clients to resume sessions that lost their connection
+    pub const fn with_resume_timeout(mut self, resume_timeout: OptionalDuration) -> Self {
+        self.resume_timeout = resume_timeout;
+        self
+    }
+
+    /// Allow clients to ask for these compression algorithms
+    pub fn with_compression(mut self, compression: &[Compression]) -> Self {
+        self.compression = compression.into();
+        self
+    }
+
+    /// Limit the bandwidth of each session
+    pub const fn with_limits(mut self, limits: LimitArgs) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Tune the flow control of each session
+    pub const fn with_mux(mut self, mux: MuxArgs) -> Self {
+        self.mux = mux;
+        self
+    }
+
+    /// Limit how fast each client may open streams and send datagrams
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Only let clients forward to the destinations allowed by `acl`
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Arc::new(acl);
+        self
+    }
+
+    /// Only accept clients that authenticate with `auth`, if it requires
+    /// authentication
+    pub fn with_authenticator(mut self, auth: Authenticator) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// The rate limit of the client of this connection, counted for its
+    /// user if it authenticated and for its address otherwise
+    fn client_rate_limit(&self, access: &Access) -> Option<ClientRateLimit> {
+        let limiter = self.rate_limiter.as_ref()?.dupe();
+        let client = ClientKey::new(access.user_name(), self.peer?.ip());
+        Some(ClientRateLimit { limiter, client })
+    }
+
+    /// Send a PROXY protocol header with the address of the client to
+    /// forwarding destinations
+    pub const fn with_send_proxy_protocol(mut self, send_proxy_protocol: bool) -> Self {
+        self.send_proxy_protocol = send_proxy_protocol;
+        self
+    }
+
+    /// Answer the streams and datagrams of `penguin bench` clients
+    pub const fn with_bench(mut self, bench: bool) -> Self {
+        self.bench = bench;
+        self
+    }
+
+    /// Ping clients every `keepalive` to measure the round-trip times
+    pub const fn with_keepalive(mut self, keepalive: OptionalDuration) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Close UDP flows after `udp_timeout` without traffic
+    pub const fn with_udp_timeout(mut self, udp_timeout: Duration) -> Self {
+        self.udp.timeout = udp_timeout;
+        self
+    }
+
+    /// Relay datagrams from any peer to the clients of UDP flows if
+    /// `full_cone` is set
+    pub const fn with_udp_full_cone(mut self, full_cone: bool) -> Self {
+        self.udp.full_cone = full_cone;
+        self
+    }
+
+    /// Answer the queries of clients' `dns` remotes with `resolver`
+    pub const fn with_dns_resolver(mut self, resolver: Option<SocketAddr>) -> Self {
+        self.dns_resolver = resolver;
+        self
+    }
+
+    /// Look up the targets of clients with `resolver`
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = Arc::new(resolver);
+        self
+    }
+
+    /// Send forwarded traffic from the addresses in `egress`
+    pub fn with_egress(mut self, egress: Egress) -> Self {
+        self.egress = Arc::new(egress);
+        self
+    }
+
+    /// Rewrite the targets of clients with `rewrites`
+    pub fn with_rewrites(mut self, rewrites: Rewrites) -> Self {
+        self.rewrites = Arc::new(rewrites);
+        self
+    }
+
+    /// Forward everything to `lock` if given, whatever clients ask for
+    pub fn with_lock_target(mut self, lock: Option<LockTarget>) -> Self {
+        self.lock = lock.map(Arc::new);
+        self
+    }
+
+    /// List the sessions in `registry`
+    pub fn with_registry(mut self, registry: Registry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Set the address of the client of this connection
+    pub const fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Set the TLS certificate of the client of this connection
+    pub fn with_peer_cert(mut self, cert: ClientCert) -> Self {
+        self.peer_cert = Some(Arc::new(cert));
+        self
+    }
+
+    /// Also accept `chisel` clients, identifying ourselves with `host_key`
+    #[cfg(feature = "chisel")]
+    pub fn with_chisel(mut self, host_key: Option<HostKey>) -> Self {
+        self.chisel = host_key.map(Arc::new);
+        self
+    }
+
+    /// Require clients to encrypt the traffic end to end with `key`
+    #[cfg(feature = "e2e")]
+    pub fn with_e2e_key(mut self, key: Option<&E2eKey>) -> Self {
+        self.e2e_key = key.cloned().map(Arc::new);
+        self
+    }
+
+    /// Agree to `permessage-deflate` with these settings, limiting the
+    /// window of clients to `window_bits`
+    pub const fn with_ws_deflate(
+        mut self,
+        ws_deflate: WsDeflateArgs,
+        window_bits: Option<u8>,
+    ) -> Self {
+        self.ws_deflate = ws_deflate;
+        self.ws_deflate_window_bits = window_bits;
+        self
+    }
+
+    /// Mark the connection as using TLS
+    pub const fn with_tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Send the authority of the backend URL as `Host` to the backend
+    pub const fn with_backend_rewrite_host(mut self, rewrite: bool) -> Self {
+        self.backend_rewrite_host = rewrite;
+        self
+    }
+
+    /// Log every request to `access_log`
+    pub fn with_access_log(mut self, access_log: Option<AccessLog>) -> Self {
+        self.access_log = access_log.map(Arc::new);
+        self
+    }
+
+    /// Log every stream and UDP flow to `audit_log` when it closes
+    pub fn with_audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log.map(Arc::new);
+        self
+    }
+
+    /// Serve connections with the plain transport instead of HTTP
+    pub const fn with_raw(mut self, raw: bool) -> Self {
+        self.raw = raw;
+        self
+    }
 }
 
-impl<B> State<'static, B>
+impl<B> State<B>
 where
     B: Body + Send + Unpin + 'static,
     <B as Body>::Data: Send,
     <B as Body>::Error: std::error::Error + Send + Sync,
 {
-    /// Helper for sending a request to the backend
-    async fn exec_request(&self, req: Request<B>) -> Result<Response<FullBody<Bytes>>, Error> {
-        let resp = self.client.request(req).await?;
-        let (parts, body) = resp.into_parts();
-        let body = body.collect().await?.to_bytes();
-        let collected = Response::from_parts(parts, FullBody::new(body));
-        Ok(collected)
+    /// Helper for sending a request to the backend.
+    /// Both bodies are streamed.
+    async fn exec_request(&self, mut req: Request<B>) -> Result<Response<ResponseBody>, Error> {
+        remove_hop_by_hop_headers(req.headers_mut());
+        let mut resp = self.client.request(req).await?;
+        remove_hop_by_hop_headers(resp.headers_mut());
+        Ok(resp.map(|body| body.map_err(Into::into).boxed()))
     }
 
-    /// Reverse proxy and 404
+    /// Send an upgrade request, such as a WebSocket handshake of the backend
+    /// app, to the backend. If the backend switches protocols, the upgraded
+    /// connections are joined together.
+    async fn upgrade_backend(&self, mut req: Request<B>) -> Result<Response<ResponseBody>, Error> {
+        let on_upgrade = req.extensions_mut().remove::<OnUpgrade>();
+        let mut resp = self.client.request(req).await?;
+        if resp.status() == StatusCode::SWITCHING_PROTOCOLS
+            && let Some(on_upgrade) = on_upgrade
+        {
+            let backend_upgrade = hyper::upgrade::on(&mut resp);
+            let joined = async move {
+                let (client, backend) = match tokio::try_join!(on_upgrade, backend_upgrade) {
+                    Ok(upgraded) => upgraded,
+                    Err(err) => {
+                        error!("Failed to upgrade backend connection: {err}");
+                        return;
+                    }
+                };
+                let mut client = TokioIo::new(client);
+                let mut backend = TokioIo::new(backend);
+                if let Err(err) = tokio::io::copy_bidirectional(&mut client, &mut backend).await {
+                    debug!("Upgraded backend connection closed: {err}");
+                }
+            };
+            tokio::spawn(self.shutdown.clone().run_until_cancelled_owned(joined));
+        }
+        Ok(resp.map(|body| body.map_err(Into::into).boxed()))
+    }
+
+    /// Reverse proxy, static files and 404
     async fn backend_or_404_handler(
         self,
         mut req: Request<B>,
-    ) -> Result<Response<FullBody<Bytes>>, Error> {
+    ) -> Result<Response<ResponseBody>, Error> {
         if let Some(BackendUrl {
             scheme,
             authority,
             path: backend_path,
-        }) = self.backend
+        }) = self.backend.as_deref()
         {
+            // HTTP/2 requests have no `Host` header
+            let original_host = req.headers().get(header::HOST).map(Dupe::dupe).or_else(|| {
+                req.uri()
+                    .authority()
+                    .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+            });
             let req_path = req.uri().path();
             let req_path_query = req
                 .uri()
@@ -174,28 +656,222 @@ where
                 .path_and_query(new_path)
                 .build()?;
             *req.uri_mut() = uri;
-            // This is no longer relevant since we're using reqwest instead of hyper.
-            // Kept here for reference.
-            // This may not be the best way to do this, but to avoid panicking if
-            // we have a HTTP/2 request, but `backend` does not support h2, let's
-            // downgrade to HTTP/1.1 and let them upgrade if they want to.
-            // *req.version_mut() = http::version::Version::default();
+            // To avoid failing if we have a HTTP/2 request, but `backend` does
+            // not support h2, let's downgrade to HTTP/1.1. The client uses
+            // HTTP/2 anyway if the backend negotiates it.
+            *req.version_mut() = http::Version::HTTP_11;
+            self.add_forwarded_headers(req.headers_mut(), original_host, authority);
+            if is_upgrade_request(&req) {
+                return self.upgrade_backend(req).await.or_else(|e| {
+                    error!("Failed to proxy upgrade request to backend: {e}");
+                    self.not_found_handler()
+                });
+            }
             self.exec_request(req).await.or_else(|e| {
                 error!("Failed to proxy request to backend: {e}");
                 self.not_found_handler()
             })
+        } else if let Some(static_dir) = self.static_dir.as_deref() {
+            let (parts, _) = req.into_parts();
+            match static_files::serve(static_dir, &parts).await? {
+                Some(mut resp) => {
+                    if let Some(masquerade) = self.masquerade
+                        && !resp.status().is_success()
+                    {
+                        *resp.body_mut() = full_body(masquerade.error_body(resp.status()));
+                        resp.headers_mut().remove(header::CONTENT_LENGTH);
+                    }
+                    Ok(resp)
+                }
+                None => self.not_found_handler(),
+            }
         } else {
             self.not_found_handler()
         }
     }
 
+    /// Tell the backend about the original request like a reverse proxy
+    /// does, and set `Host` to `original_host` or `backend_authority`
+    fn add_forwarded_headers(
+        &self,
+        headers: &mut HeaderMap,
+        original_host: Option<HeaderValue>,
+        backend_authority: &Authority,
+    ) {
+        if let Some(peer) = self.peer {
+            let peer = peer.ip().to_string();
+            let forwarded_for = match headers.get("x-forwarded-for").map(HeaderValue::to_str) {
+                Some(Ok(previous)) => format!("{previous}, {peer}"),
+                _ => peer,
+            };
+            // `expect`: addresses and existing header values are valid
+            let forwarded_for = HeaderValue::try_from(forwarded_for)
+                .expect("Invalid `X-Forwarded-For` (this is a bug)");
+            headers.insert("x-forwarded-for", forwarded_for);
+        }
+        let proto = if self.tls { "https" } else { "http" };
+        headers.insert("x-forwarded-proto", HeaderValue::from_static(proto));
+        if let Some(original_host) = &original_host {
+            headers.insert("x-forwarded-host", original_host.dupe());
+        }
+        let host = match original_host {
+            Some(original_host) if !self.backend_rewrite_host => Some(original_host),
+            _ => HeaderValue::from_str(backend_authority.as_str()).ok(),
+        };
+        if let Some(host) = host {
+            headers.insert(header::HOST, host);
+        }
+    }
+
     /// 404 handler
-    fn not_found_handler(self) -> Result<Response<FullBody<Bytes>>, Error> {
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(FullBody::new(Bytes::from_static(
-                self.not_found_resp.as_bytes(),
-            )))?)
+    fn not_found_handler(self) -> Result<Response<ResponseBody>, Error> {
+        let mut resp = Response::builder().status(self.not_found_status);
+        for header in self.not_found_headers.iter() {
+            resp = resp.header(&header.name, &header.value);
+        }
+        Ok(resp.body(full_body(self.not_found_resp))?)
+    }
+
+    /// Answer an upgrade request we refused like any other request, and
+    /// note `reason` for the access log
+    async fn reject_upgrade(
+        self,
+        req: Request<B>,
+        reason: &'static str,
+    ) -> Result<Response<ResponseBody>, Error> {
+        #[cfg(feature = "otel")]
+        crate::otel::handshake("rejected");
+        let mut resp = self.backend_or_404_handler(req).await?;
+        resp.extensions_mut().insert(Rejected(reason));
+        Ok(resp)
+    }
+
+    /// Span of the handshake of `req`, which continues the trace of the
+    /// client if it sent one
+    #[cfg_attr(not(feature = "otel"), expect(unused_variables))]
+    fn handshake_span(&self, req: &Request<B>) -> Span {
+        let span = debug_span!("handshake", peer = self.peer.map(tracing::field::display));
+        #[cfg(feature = "otel")]
+        crate::otel::set_parent(&span, req.headers());
+        span
+    }
+
+    /// Decide what to do with the session token of a `WebSocket` request
+    /// from `owner`. Sessions are only resumed by whoever started them, and
+    /// new sessions get a token of our choosing.
+    fn session_for(&self, token: Option<&HeaderValue>, owner: SessionOwner) -> Session {
+        let (Some(resume_timeout), Some(token)) =
+            (Option::<Duration>::from(self.resume_timeout), token)
+        else {
+            return Session::None;
+        };
+        if *token != SESSION_NEW {
+            let sessions = self.sessions.lock();
+            match sessions.get(token) {
+                Some(session) if session.owner != owner => {
+                    warn!("Refusing to resume a session started by another client");
+                }
+                Some(session) if !session.resumer.is_closed() => {
+                    return Session::Resume(session.resumer.dupe());
+                }
+                _ => debug!("No session to resume, starting a new one"),
+            }
+        }
+        Session::New(NewSession {
+            token: new_session_token(),
+            owner,
+            sessions: self.sessions.dupe(),
+            resume_timeout,
+        })
+    }
+
+    /// Check the PSK and the credentials of a client, using the PSKs and
+    /// rules of `endpoint` if it connected to one.
+    /// Returns what the client may access, or why it may not connect.
+    pub(super) fn authorize(
+        &self,
+        headers: &HeaderMap,
+        endpoint: Option<&Endpoint>,
+    ) -> Result<Access, &'static str> {
+        let x_penguin_psk = headers.get("x-penguin-psk");
+        let authorization = headers.get(header::AUTHORIZATION);
+        let ws_psk = endpoint.map_or(&*self.ws_psk, |endpoint| &endpoint.ws_psk);
+        if !ws_psk.is_empty() {
+            let matched = x_penguin_psk.and_then(|psk| ws_psk.iter().position(|k| k == psk));
+            let Some(idx) = matched else {
+                warn!("Invalid request: invalid PSK {x_penguin_psk:?}");
+                return Err("invalid PSK");
+            };
+            // Tell which key clients still use during a rotation without
+            // logging the key itself
+            if ws_psk.len() > 1 {
+                info!("Client presented PSK #{} of {}", idx + 1, ws_psk.len());
+            }
+        }
+        let user = self
+            .auth
+            .authenticate(authorization, self.peer_cert.as_deref());
+        if self.auth.is_required() && user.is_none() {
+            warn!("Invalid request: invalid credentials");
+            return Err("invalid credentials");
+        }
+        if let Some(endpoint) = endpoint {
+            info!("Client connected to endpoint `{}`", endpoint.label);
+        }
+        Ok(Access {
+            acl: endpoint.map_or_else(|| self.acl.dupe(), |endpoint| endpoint.acl.dupe()),
+            user,
+            resolver: self.resolver.dupe(),
+            egress: self.egress.dupe(),
+            rewrites: self.rewrites.dupe(),
+            lock: self.lock.as_ref().map(Dupe::dupe),
+        })
+    }
+
+    /// Decide how to serve the session of a client accepted on `endpoint`
+    pub(super) fn start_session(
+        &self,
+        headers: &HeaderMap,
+        access: Access,
+        endpoint: Option<&Endpoint>,
+        reverse: bool,
+    ) -> (Session, SessionOptions) {
+        let owner = SessionOwner {
+            user: access.user_name().map(ToString::to_string),
+            endpoint: endpoint.map(|endpoint| endpoint.path.clone()),
+        };
+        let session = self.session_for(headers.get("x-penguin-session"), owner);
+        // Resumed sessions keep the algorithm they started with
+        let compression = headers
+            .get("x-penguin-compression")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<Compression>().ok())
+            .filter(|compression| self.compression.contains(compression))
+            .unwrap_or_default();
+        let options = SessionOptions {
+            reverse,
+            compression,
+            padding: headers.contains_key("x-penguin-padding"),
+            coalesce: headers.contains_key("x-penguin-coalesce"),
+            limits: self.limits,
+            mux: self.mux,
+            rate_limit: self.client_rate_limit(&access),
+            proxy_source: self.peer.filter(|_| self.send_proxy_protocol),
+            bench: self.bench,
+            keepalive: self.keepalive,
+            udp: UdpOptions {
+                report_errors: headers.contains_key(UDP_ERRORS_HEADER),
+                ..self.udp
+            },
+            dns_resolver: self.dns_resolver,
+            registry: self.registry.dupe(),
+            peer: self.peer,
+            access,
+            #[cfg(feature = "e2e")]
+            e2e_key: self.e2e_key.as_ref().map(Dupe::dupe),
+            audit_log: self.audit_log.as_ref().map(Dupe::dupe),
+        };
+        (session, options)
     }
 
     /// Check the PSK and protocol version and upgrade to a WebSocket if the PSK matches (if required).
@@ -203,98 +879,353 @@ where
         self,
         mut req: Request<B>,
         reverse: bool,
-    ) -> Result<Response<FullBody<Bytes>>, Error> {
-        let on_upgrade = req.extensions_mut().remove::<OnUpgrade>();
+        endpoint: Option<Arc<Endpoint>>,
+    ) -> Result<Response<ResponseBody>, Error> {
         let headers = req.headers();
         let connection = headers.get(header::CONNECTION);
         let upgrade = headers.get(header::UPGRADE);
         let sec_websocket_key = headers.get(header::SEC_WEBSOCKET_KEY);
         let sec_websocket_protocol = headers.get(header::SEC_WEBSOCKET_PROTOCOL);
         let sec_websocket_version = headers.get(header::SEC_WEBSOCKET_VERSION);
-        let x_penguin_psk = headers.get("x-penguin-psk");
 
         if req.method() != Method::GET {
             warn!("Invalid WebSocket request: not a GET request");
-            return self.backend_or_404_handler(req).await;
-        }
-        if self.ws_psk.is_some() && x_penguin_psk != self.ws_psk {
-            warn!("Invalid WebSocket request: invalid PSK {x_penguin_psk:?}");
-            return self.backend_or_404_handler(req).await;
+            return self.reject_upgrade(req, "not a GET request").await;
         }
+        let access = match self.authorize(headers, endpoint.as_deref()) {
+            Ok(access) => access,
+            Err(reason) => return self.reject_upgrade(req, reason).await,
+        };
         let Some(sec_websocket_key) = sec_websocket_key else {
             warn!("Invalid WebSocket request: no `sec-websocket-key` header");
-            return self.backend_or_404_handler(req).await;
+            return self.reject_upgrade(req, "no sec-websocket-key").await;
         };
         if !header_matches!(connection, UPGRADE)
             || !header_matches!(upgrade, WEBSOCKET)
             || !header_matches!(sec_websocket_version, WEBSOCKET_VERSION)
-            || !header_matches!(sec_websocket_protocol, WANTED_PROTOCOL)
         {
-            return self.backend_or_404_handler(req).await;
+            return self.reject_upgrade(req, "not a WebSocket upgrade").await;
         }
-        let Some(on_upgrade) = on_upgrade else {
-            error!("Empty `on_upgrade`");
-            return self.backend_or_404_handler(req).await;
+        let protocol = sec_websocket_protocol
+            .and_then(|offered| offered.to_str().ok())
+            .and_then(negotiate_protocol_version);
+        let Some(protocol) = protocol else {
+            warn!("Invalid WebSocket request: no supported protocol in {sec_websocket_protocol:?}");
+            return self.reject_upgrade(req, "no supported protocol").await;
         };
+        if req.extensions().get::<OnUpgrade>().is_none() {
+            error!("Empty `on_upgrade`");
+            return self
+                .reject_upgrade(req, "connection cannot be upgraded")
+                .await;
+        }
 
         // Now we know it's a valid WebSocket request, so we can upgrade to a WebSocket.
-        debug!("Upgrading to WebSocket");
+        let user = access.user_name().map(ToString::to_string);
+        if let Some(user) = &user {
+            info!("Upgrading to WebSocket for user `{user}`");
+        } else {
+            debug!("Upgrading to WebSocket");
+        }
 
         let sec_websocket_accept = make_sec_websocket_accept(sec_websocket_key);
+        let (session, options) = self.start_session(headers, access, endpoint.as_deref(), reverse);
+        let reply_headers = session_reply_headers(&session, &options);
+        let deflate =
+            crate::ws_deflate::accept(self.ws_deflate, self.ws_deflate_window_bits, headers);
+        // Left in place until now in case we fall back to the backend
+        let on_upgrade = req
+            .extensions_mut()
+            .remove::<OnUpgrade>()
+            .expect("`on_upgrade` disappeared (this is a bug)");
 
-        tokio::spawn(async move {
-            match on_upgrade.await {
-                Ok(upgraded) => {
-                    let ws = WebSocketStream::from_raw_socket(
-                        TokioIo::new(upgraded),
-                        Role::Server,
-                        None,
-                    )
-                    .await;
-                    handle_websocket(ws, reverse).await;
-                }
-                Err(err) => {
-                    error!("Failed to upgrade to WebSocket: {err}");
-                }
-            }
-        });
+        let serve = serve_upgraded(
+            on_upgrade,
+            session,
+            options,
+            deflate.as_ref().map(|(agreed, _)| *agreed),
+        );
+        // The session continues the trace of its handshake
+        #[cfg(feature = "otel")]
+        let serve = serve.in_current_span();
+        tokio::spawn(self.shutdown.clone().run_until_cancelled_owned(serve));
+        #[cfg(feature = "otel")]
+        crate::otel::handshake("accepted");
 
+        let mut response = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, &UPGRADE)
+            .header(header::UPGRADE, &WEBSOCKET)
+            .header(header::SEC_WEBSOCKET_PROTOCOL, protocol)
+            .header(header::SEC_WEBSOCKET_ACCEPT, sec_websocket_accept);
+        for (name, value) in &reply_headers {
+            response = response.header(name, value);
+        }
+        if let Some((_, extensions)) = deflate {
+            response = response.header(header::SEC_WEBSOCKET_EXTENSIONS, extensions);
+        }
+        if let Some(user) = user {
+            response = response.extension(access_log::User(user));
+        }
+        Ok(response.body(full_body(Bytes::new()))?)
+    }
+
+    /// Upgrade the WebSocket of a `chisel` client, which authenticates
+    /// later in the SSH handshake. The PSK is still required if we have one.
+    #[cfg(feature = "chisel")]
+    async fn chisel_handler(self, mut req: Request<B>) -> Result<Response<ResponseBody>, Error> {
+        let headers = req.headers();
+        let psk_matches = self.ws_psk.is_empty()
+            || headers
+                .get("x-penguin-psk")
+                .is_some_and(|psk| self.ws_psk.contains(psk));
+        if !psk_matches {
+            warn!("Invalid chisel request: invalid PSK");
+            return self.reject_upgrade(req, "invalid PSK").await;
+        }
+        let Some(sec_websocket_key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+            warn!("Invalid chisel request: no `sec-websocket-key` header");
+            return self.reject_upgrade(req, "no sec-websocket-key").await;
+        };
+        let sec_websocket_accept = make_sec_websocket_accept(sec_websocket_key);
+        let Some(on_upgrade) = req.extensions_mut().remove::<OnUpgrade>() else {
+            error!("Empty `on_upgrade`");
+            return self
+                .reject_upgrade(req, "connection cannot be upgraded")
+                .await;
+        };
+        debug!("Upgrading to WebSocket for a chisel client");
+        let host_key = self
+            .chisel
+            .expect("chisel request without a host key (this is a bug)");
+        let shutdown = self.shutdown.clone();
+        let serve = super::chisel::serve_chisel(
+            on_upgrade,
+            host_key,
+            self.auth,
+            Access {
+                acl: self.acl,
+                user: None,
+                resolver: self.resolver,
+                egress: self.egress,
+                rewrites: self.rewrites,
+                lock: self.lock,
+            },
+            self.peer_cert,
+        );
+        tokio::spawn(shutdown.run_until_cancelled_owned(serve));
         Ok(Response::builder()
             .status(StatusCode::SWITCHING_PROTOCOLS)
             .header(header::CONNECTION, &UPGRADE)
             .header(header::UPGRADE, &WEBSOCKET)
-            .header(header::SEC_WEBSOCKET_PROTOCOL, &WANTED_PROTOCOL)
+            .header(header::SEC_WEBSOCKET_PROTOCOL, crate::chisel::WS_PROTOCOL)
             .header(header::SEC_WEBSOCKET_ACCEPT, sec_websocket_accept)
-            .body(FullBody::new(Bytes::new()))?)
+            .body(full_body(Bytes::new()))?)
+    }
+}
+
+/// Whether `req` is a WebSocket upgrade asking for the `chisel` protocol
+#[cfg(feature = "chisel")]
+fn is_chisel_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::GET
+        && is_upgrade_request(req)
+        && req
+            .headers()
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == crate::chisel::WS_PROTOCOL)
+}
+
+/// Remove the headers that only apply to one connection
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed.iter().chain(&HOP_BY_HOP_HEADERS) {
+        headers.remove(name);
     }
 }
 
-impl<B> Service<Request<B>> for State<'static, B>
+/// Whether `req` asks to switch protocols, e.g. to WebSocket
+fn is_upgrade_request<B>(req: &Request<B>) -> bool {
+    req.headers().contains_key(header::UPGRADE)
+        && req
+            .headers()
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Headers telling the client what we did with its session token and which
+/// compression algorithm, padding and coalescing we agreed to
+pub(super) fn session_reply_headers(session: &Session, options: &SessionOptions) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    match session {
+        Session::None => {}
+        Session::New(session) => {
+            headers.insert("x-penguin-session", session.token.dupe());
+        }
+        Session::Resume(_) => {
+            headers.insert("x-penguin-session", SESSION_RESUMED.dupe());
+        }
+    }
+    if options.compression != Compression::None {
+        headers.insert(
+            "x-penguin-compression",
+            HeaderValue::from_static(options.compression.name()),
+        );
+    }
+    if options.padding {
+        headers.insert("x-penguin-padding", HeaderValue::from_static("1"));
+    }
+    if options.coalesce {
+        headers.insert("x-penguin-coalesce", HeaderValue::from_static("1"));
+    }
+    headers
+}
+
+/// Serve the `WebSocket` connection once the upgrade completes, with
+/// `permessage-deflate` if `deflate` was agreed
+async fn serve_upgraded(
+    on_upgrade: OnUpgrade,
+    session: Session,
+    options: SessionOptions,
+    deflate: Option<Agreed>,
+) {
+    match on_upgrade.await {
+        Ok(upgraded) => {
+            let io = TokioIo::new(upgraded);
+            let ws: WebSocket = if let Some(agreed) = deflate {
+                let io = DeflateStream::server(io, agreed);
+                Box::new(WebSocketStream::from_raw_socket(io, Role::Server, None).await)
+            } else {
+                Box::new(WebSocketStream::from_raw_socket(io, Role::Server, None).await)
+            };
+            serve_session(ws, session, options).await;
+        }
+        Err(err) => {
+            error!("Failed to upgrade to WebSocket: {err}");
+        }
+    }
+}
+
+/// Start, continue or resume the session of a connection
+pub(super) async fn serve_session(ws: WebSocket, session: Session, options: SessionOptions) {
+    #[cfg(feature = "e2e")]
+    let ws: WebSocket = match &options.e2e_key {
+        Some(key) => {
+            let handshake = crate::e2e::accept(ws, key);
+            match tokio::time::timeout(crate::config::E2E_HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(ws)) => Box::new(ws),
+                Ok(Err(err)) => {
+                    warn!("{err}");
+                    return;
+                }
+                Err(_) => {
+                    warn!("E2E handshake timed out");
+                    return;
+                }
+            }
+        }
+        None => ws,
+    };
+    match session {
+        Session::None => handle_websocket(ws, options, None).await,
+        Session::New(session) => {
+            handle_websocket(ws, options, Some(session)).await;
+        }
+        Session::Resume(resumer) => {
+            debug!("Resuming session");
+            if let Err(err) = resumer.resume(ws).await {
+                warn!("Failed to resume session: {err}");
+            }
+        }
+    }
+}
+
+impl<B> Service<Request<B>> for State<B>
 where
     B: Body + Send + Unpin + 'static,
     <B as Body>::Data: Send,
     <B as Body>::Error: std::error::Error + Send + Sync,
 {
-    type Response = Response<FullBody<Bytes>>;
+    type Response = Response<ResponseBody>;
     type Error = Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     /// Hyper service handler
     fn call(&self, req: Request<B>) -> Self::Future {
+        let logged = self
+            .access_log
+            .as_ref()
+            .map(|log| (log.dupe(), access_log::Request::new(&req, self.peer)));
+        let resp = self.route(req);
+        let masquerade = self.masquerade;
+        if masquerade.is_none() && logged.is_none() {
+            return resp;
+        }
+        Box::pin(async move {
+            let mut resp = resp.await?;
+            if let Some(masquerade) = masquerade {
+                masquerade.set_headers(resp.status(), resp.headers_mut());
+            }
+            if let Some((log, request)) = logged {
+                log.log(&request, &resp);
+            }
+            Ok(resp)
+        })
+    }
+}
+
+impl<B> State<B>
+where
+    B: Body + Send + Unpin + 'static,
+    <B as Body>::Data: Send,
+    <B as Body>::Error: std::error::Error + Send + Sync,
+{
+    /// Pick the handler for a request
+    fn route(&self, req: Request<B>) -> <Self as Service<Request<B>>>::Future {
         // Only allow `/health` and `/version` if not obfuscating
         if req.uri().path() == "/health" && !self.obfs {
-            return Box::pin(async { Ok(Response::new(FullBody::new(Bytes::from_static(b"OK")))) });
+            return Box::pin(async { Ok(Response::new(full_body(Bytes::from_static(b"OK")))) });
         }
         if req.uri().path() == "/version" && !self.obfs {
             return Box::pin(async {
-                Ok(Response::new(FullBody::new(Bytes::from_static(
+                Ok(Response::new(full_body(Bytes::from_static(
                     env!("CARGO_PKG_VERSION").as_bytes(),
                 ))))
             });
         }
-        // If `/ws`, handle WebSocket
-        if req.uri().path() == "/ws" {
-            return Box::pin(self.dupe().ws_handler(req, self.reverse));
+        // `chisel` clients may use any path
+        #[cfg(feature = "chisel")]
+        if self.chisel.is_some() && is_chisel_request(&req) {
+            return Box::pin(self.dupe().chisel_handler(req));
+        }
+        // If the WebSocket path, handle WebSocket
+        if req.uri().path() == &*self.ws_path {
+            let span = self.handshake_span(&req);
+            return Box::pin(
+                self.dupe()
+                    .ws_handler(req, self.reverse, None)
+                    .instrument(span),
+            );
+        }
+        if let Some(endpoint) = self.endpoints.get(req.uri().path()) {
+            let endpoint = Some(endpoint.dupe());
+            let span = self.handshake_span(&req);
+            return Box::pin(
+                self.dupe()
+                    .ws_handler(req, self.reverse, endpoint)
+                    .instrument(span),
+            );
         }
         // Else, proxy to backend or return 404
         Box::pin(self.dupe().backend_or_404_handler(req))
@@ -304,11 +1235,16 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::auth::Users;
+    use crate::server::client_cert::ClientCertRules;
+    use penguin_mux::PROTOCOL_VERSION;
     use std::str::FromStr;
     use std::sync::LazyLock;
 
     type EmptyBody = http_body_util::Empty<Bytes>;
 
+    static WANTED_PROTOCOL: HeaderValue = HeaderValue::from_static(PROTOCOL_VERSION);
+
     #[test]
     fn test_make_sec_websocket_accept() {
         crate::tests::setup_logging();
@@ -328,7 +1264,7 @@ mod tests {
         // Test `/health` without obfuscation
         let state = State::new(
             None,
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -348,7 +1284,7 @@ mod tests {
         // Test `/health` with obfuscation
         let state = State::new(
             None,
-            None,
+            &[],
             "not found in the test",
             true,
             false,
@@ -368,7 +1304,7 @@ mod tests {
         // Test `/version` without obfuscation
         let state = State::new(
             None,
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -388,7 +1324,7 @@ mod tests {
         // Test `/version` with obfuscation
         let state = State::new(
             None,
-            None,
+            &[],
             "not found in the test",
             true,
             false,
@@ -416,7 +1352,7 @@ mod tests {
         // Test that the backend is actually working
         let state = State::new(
             Some(&BACKEND),
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -433,7 +1369,7 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let state = State::new(
             Some(&BACKEND),
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -460,7 +1396,7 @@ mod tests {
         // Test that the backend is actually working
         let state = State::new(
             Some(&BACKEND),
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -477,7 +1413,7 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         let state = State::new(
             Some(&BACKEND),
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -500,7 +1436,7 @@ mod tests {
         // Test non-GET request
         let state = State::new(
             None,
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -523,13 +1459,77 @@ mod tests {
         assert_eq!(body_bytes, "not found in the test");
     }
 
+    #[tokio::test]
+    async fn test_custom_not_found() {
+        static HEADERS: LazyLock<[Header; 2]> = LazyLock::new(|| {
+            [
+                "Content-Type: application/xml".parse().unwrap(),
+                "Server: AmazonS3".parse().unwrap(),
+            ]
+        });
+        crate::tests::setup_logging();
+        let state = State::new(
+            None,
+            &[],
+            "replaced",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_not_found(
+            StatusCode::FORBIDDEN,
+            &*HEADERS,
+            Some(Bytes::from_static(
+                b"<Error><Code>AccessDenied</Code></Error>",
+            )),
+        );
+        let req = Request::get("/bucket/key").body(EmptyBody::new()).unwrap();
+        let resp = state.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()["content-type"], "application/xml");
+        assert_eq!(resp.headers()["server"], "AmazonS3");
+        let body_bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body_bytes, "<Error><Code>AccessDenied</Code></Error>");
+    }
+
+    #[tokio::test]
+    async fn test_masquerade() {
+        crate::tests::setup_logging();
+        let masquerade = Masquerade::Nginx;
+        let state = State::new(
+            None,
+            &[],
+            "replaced",
+            true,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_not_found(
+            StatusCode::NOT_FOUND,
+            &[],
+            Some(masquerade.error_body(StatusCode::NOT_FOUND)),
+        )
+        .with_masquerade(Some(masquerade));
+        let req = Request::get("/index.php").body(EmptyBody::new()).unwrap();
+        let resp = state.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()["server"], "nginx");
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        let body_bytes = resp.into_body().collect().await.unwrap().to_bytes();
+        assert!(body_bytes.ends_with(b"<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n"));
+    }
+
     #[tokio::test]
     async fn test_stealth_websocket_upgrade_missing_key_header() {
         crate::tests::setup_logging();
         // Test missing upgrade header
         let state = State::new(
             None,
-            None,
+            &[],
             "not found in the test",
             false,
             false,
@@ -558,7 +1558,7 @@ mod tests {
         crate::tests::setup_logging();
         let state = State::new(
             None,
-            Some(&PSK),
+            std::slice::from_ref(&PSK),
             "not found in the test",
             false,
             false,
@@ -589,7 +1589,7 @@ mod tests {
         crate::tests::setup_logging();
         let state = State::new(
             None,
-            Some(&PSK),
+            std::slice::from_ref(&PSK),
             "not found in the test",
             false,
             false,
@@ -613,4 +1613,428 @@ mod tests {
         let result = state.call(req).await.unwrap();
         assert_eq!(result.status(), StatusCode::SWITCHING_PROTOCOLS);
     }
+
+    #[tokio::test]
+    async fn test_access_log() {
+        static PSK: HeaderValue = HeaderValue::from_static("correct PSK");
+        crate::tests::setup_logging();
+        let path = std::env::temp_dir().join(format!("penguin-access-{}.log", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let access_log = AccessLog::open(&path, crate::arg::AccessLogFormat::Combined).unwrap();
+        let state = State::new(
+            None,
+            std::slice::from_ref(&PSK),
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_peer("192.0.2.1:1234".parse().unwrap())
+        .with_access_log(Some(access_log));
+        let req = Request::builder()
+            .uri("/health")
+            .header("user-agent", "curl/8.0")
+            .body(EmptyBody::new())
+            .unwrap();
+        state.call(req).await.unwrap();
+        let req = Request::builder()
+            .uri("/ws")
+            .method(Method::GET)
+            .header("connection", "upgrade")
+            .header("upgrade", "websocket")
+            .header("sec-websocket-version", "13")
+            .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+            .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("x-penguin-psk", "wrong PSK")
+            .body(EmptyBody::new())
+            .unwrap();
+        state.call(req).await.unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("192.0.2.1 - - ["));
+        assert!(lines[0].ends_with("] \"GET /health HTTP/1.1\" 200 2 \"-\" \"curl/8.0\""));
+        assert!(lines[1].ends_with("] \"GET /ws HTTP/1.1\" 404 21 \"-\" \"-\" \"invalid PSK\""));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_protocol_negotiation() {
+        crate::tests::setup_logging();
+        let state = State::new(
+            None,
+            &[],
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap();
+        let upgrade = |protocols: &'static str| {
+            let on_upgrade = hyper::upgrade::on(http::Request::new(EmptyBody::new()));
+            Request::builder()
+                .uri("wss://example.com/ws")
+                .method(Method::GET)
+                .header("connection", "UpGrAdE")
+                .header("upgrade", "WEBSOCKET")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", protocols)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .extension(on_upgrade)
+                .body(EmptyBody::new())
+                .unwrap()
+        };
+        // A newer client offering a version we do not know yet
+        let resp = state
+            .call(upgrade("penguin-v99, penguin-v7"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(resp.headers()["sec-websocket-protocol"], PROTOCOL_VERSION);
+        let resp = state.call(upgrade("penguin-v6")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_authfile() {
+        crate::tests::setup_logging();
+        let users = Users::parse(r#"{"foo:bar": ["*"]}"#).unwrap();
+        let state = State::new(
+            None,
+            &[],
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_authenticator(Authenticator {
+            users: Some(users),
+            ..Default::default()
+        });
+        let request = |authorization: Option<&str>| {
+            let mut req = Request::builder()
+                .uri("wss://example.com/ws")
+                .method(Method::GET)
+                .header("connection", "UpGrAdE")
+                .header("upgrade", "WEBSOCKET")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .extension(hyper::upgrade::on(http::Request::new(EmptyBody::new())));
+            if let Some(authorization) = authorization {
+                req = req.header("authorization", authorization);
+            }
+            req.body(EmptyBody::new()).unwrap()
+        };
+        let result = state.call(request(None)).await.unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+        // "foo:baz"
+        let result = state
+            .call(request(Some("Basic Zm9vOmJheg==")))
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+        // "foo:bar"
+        let result = state
+            .call(request(Some("Basic Zm9vOmJhcg==")))
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_client_cert() {
+        crate::tests::setup_logging();
+        let rules = ClientCertRules::parse(r#"[{"name": "alice", "label": "ops"}]"#).unwrap();
+        let state = State::new(
+            None,
+            &[],
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_authenticator(Authenticator {
+            client_certs: Some(rules),
+            ..Default::default()
+        });
+        let request = || {
+            Request::builder()
+                .uri("wss://example.com/ws")
+                .method(Method::GET)
+                .header("connection", "UpGrAdE")
+                .header("upgrade", "WEBSOCKET")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .extension(hyper::upgrade::on(http::Request::new(EmptyBody::new())))
+                .body(EmptyBody::new())
+                .unwrap()
+        };
+        let result = state.dupe().call(request()).await.unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+        let bob = ClientCert {
+            names: vec!["bob".to_string()],
+        };
+        let result = state.dupe().with_peer_cert(bob).call(request()).await;
+        assert_eq!(result.unwrap().status(), StatusCode::NOT_FOUND);
+        let alice = ClientCert {
+            names: vec!["alice".to_string()],
+        };
+        let result = state.with_peer_cert(alice).call(request()).await;
+        assert_eq!(result.unwrap().status(), StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_multiple_psks() {
+        static PSKS: [HeaderValue; 2] = [
+            HeaderValue::from_static("old PSK"),
+            HeaderValue::from_static("new PSK"),
+        ];
+        crate::tests::setup_logging();
+        let state = State::new(
+            None,
+            &PSKS,
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap();
+        let request = |psk: &str| {
+            Request::builder()
+                .uri("wss://example.com/ws")
+                .method(Method::GET)
+                .header("connection", "UpGrAdE")
+                .header("upgrade", "WEBSOCKET")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .header("x-penguin-psk", psk)
+                .extension(hyper::upgrade::on(http::Request::new(EmptyBody::new())))
+                .body(EmptyBody::new())
+                .unwrap()
+        };
+        for psk in ["old PSK", "new PSK"] {
+            let result = state.call(request(psk)).await.unwrap();
+            assert_eq!(result.status(), StatusCode::SWITCHING_PROTOCOLS);
+        }
+        let result = state.call(request("wrong PSK")).await.unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_custom_path() {
+        crate::tests::setup_logging();
+        let state = State::new(
+            None,
+            &[],
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_ws_path("/assets/app.js");
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .method(Method::GET)
+                .header("connection", "UpGrAdE")
+                .header("upgrade", "WEBSOCKET")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .extension(hyper::upgrade::on(http::Request::new(EmptyBody::new())))
+                .body(EmptyBody::new())
+                .unwrap()
+        };
+        let result = state
+            .call(request("wss://example.com/assets/app.js"))
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let result = state.call(request("wss://example.com/ws")).await.unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_passthrough_to_backend() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::tests::setup_logging();
+        // A backend that switches protocols and then echoes
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_url = format!("http://{}", backend.local_addr().unwrap());
+        let backend_url = BackendUrl::from_str(&backend_url).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = backend.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            let request = String::from_utf8(request).unwrap().to_lowercase();
+            assert!(request.starts_with("get /app/socket "));
+            assert!(request.contains("upgrade: echo"));
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: echo\r\n\r\n")
+                .await
+                .unwrap();
+            let (mut rx, mut tx) = stream.split();
+            tokio::io::copy(&mut rx, &mut tx).await.unwrap();
+        });
+        let state = State::new(
+            Some(&backend_url),
+            &[],
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap();
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(crate::server::serve_connection(server, state));
+        client
+            .write_all(b"GET /app/socket HTTP/1.1\r\nhost: example.com\r\nconnection: upgrade\r\nupgrade: echo\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            response.push(client.read_u8().await.unwrap());
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101 "));
+        client.write_all(b"hello").await.unwrap();
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers_to_backend() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::tests::setup_logging();
+        // A backend that echoes the request head in a chunked response
+        let backend = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let backend_url = BackendUrl::from_str(&format!("http://{backend_addr}")).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                    request.len(),
+                    String::from_utf8(request).unwrap()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let request = || {
+            Request::builder()
+                .uri("http://example.com/page")
+                .header("host", "example.com")
+                .header("x-forwarded-for", "192.0.2.1")
+                .header("keep-alive", "timeout=5")
+                .body(EmptyBody::new())
+                .unwrap()
+        };
+        let state = State::new(
+            Some(&backend_url),
+            &[],
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_peer("198.51.100.7:1234".parse().unwrap())
+        .with_tls(true);
+        let resp = state.call(request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key("transfer-encoding"));
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let head = String::from_utf8(body.to_vec()).unwrap().to_lowercase();
+        assert!(head.starts_with("get /page http/1.1\r\n"));
+        assert!(head.contains("host: example.com\r\n"));
+        assert!(head.contains("x-forwarded-for: 192.0.2.1, 198.51.100.7\r\n"));
+        assert!(head.contains("x-forwarded-proto: https\r\n"));
+        assert!(head.contains("x-forwarded-host: example.com\r\n"));
+        assert!(!head.contains("keep-alive"));
+        let resp = state
+            .with_backend_rewrite_host(true)
+            .call(request())
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let head = String::from_utf8(body.to_vec()).unwrap().to_lowercase();
+        assert!(head.contains(&format!("host: {backend_addr}\r\n")));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_endpoints() {
+        static PSK: HeaderValue = HeaderValue::from_static("global PSK");
+        crate::tests::setup_logging();
+        let endpoints =
+            Endpoints::parse(r#"[{"path": "/team-a", "label": "team-a", "psk": ["team PSK"]}]"#)
+                .unwrap();
+        let state = State::new(
+            None,
+            std::slice::from_ref(&PSK),
+            "not found in the test",
+            false,
+            false,
+            OptionalDuration::NONE,
+            OptionalDuration::NONE,
+        )
+        .unwrap()
+        .with_endpoints(endpoints);
+        let request = |uri: &str, psk: &str| {
+            Request::builder()
+                .uri(uri)
+                .method(Method::GET)
+                .header("connection", "UpGrAdE")
+                .header("upgrade", "WEBSOCKET")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .header("x-penguin-psk", psk)
+                .extension(hyper::upgrade::on(http::Request::new(EmptyBody::new())))
+                .body(EmptyBody::new())
+                .unwrap()
+        };
+        for (uri, psk, status) in [
+            (
+                "wss://example.com/team-a",
+                "team PSK",
+                StatusCode::SWITCHING_PROTOCOLS,
+            ),
+            (
+                "wss://example.com/team-a",
+                "global PSK",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                "wss://example.com/ws",
+                "global PSK",
+                StatusCode::SWITCHING_PROTOCOLS,
+            ),
+            ("wss://example.com/ws", "team PSK", StatusCode::NOT_FOUND),
+        ] {
+            let result = state.call(request(uri, psk)).await.unwrap();
+            assert_eq!(result.status(), status, "{uri} with {psk}");
+        }
+    }
 }
@@ -0,0 +1,332 @@
+//! Serving a directory of static files as the fallback handler.
+//!
+//! With `--static-dir`, requests that are not for the `WebSocket` endpoint
+//! are answered from files in the directory, like a plain web server would:
+//! directories are served by their `index.html`, the `Content-Type` is
+//! guessed from the file extension, and single-part `Range` requests are
+//! answered with `206 Partial Content`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::service::{ResponseBody, full_body};
+use bytes::{Bytes, BytesMut};
+use http::{HeaderValue, Method, Response, StatusCode, header, request};
+use http_body_util::{BodyExt, StreamBody};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{debug, warn};
+
+/// File served for a directory
+const INDEX_FILE: &str = "index.html";
+/// Largest chunk read from a file at once
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Serve `req` from the files in `dir`.
+/// Returns `None` if there is no such file.
+pub async fn serve(
+    dir: &Path,
+    req: &request::Parts,
+) -> Result<Option<Response<ResponseBody>>, http::Error> {
+    if req.method != Method::GET && req.method != Method::HEAD {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(full_body(Bytes::new()))
+            .map(Some);
+    }
+    let uri_path = req.uri.path();
+    let Some(mut path) = resolve(dir, uri_path) else {
+        debug!("Invalid static file path {uri_path:?}");
+        return Ok(None);
+    };
+    let Ok(metadata) = tokio::fs::metadata(&path).await else {
+        return Ok(None);
+    };
+    if metadata.is_dir() {
+        if !uri_path.ends_with('/') {
+            // Relative links in the index must resolve inside the directory
+            let location = match req.uri.query() {
+                Some(query) => format!("{uri_path}/?{query}"),
+                None => format!("{uri_path}/"),
+            };
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(full_body(Bytes::new()))
+                .map(Some);
+        }
+        path.push(INDEX_FILE);
+    }
+    let file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            debug!("Cannot open static file {}: {err}", path.display());
+            return Ok(None);
+        }
+    };
+    let len = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        Ok(_) => return Ok(None),
+        Err(err) => {
+            warn!("Cannot read static file {}: {err}", path.display());
+            return Ok(None);
+        }
+    };
+    let range = match req
+        .headers
+        .get(header::RANGE)
+        .map(|range| parse_range(range, len))
+    {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+                .body(full_body(Bytes::new()))
+                .map(Some);
+        }
+        None => None,
+    };
+    let mut resp = Response::builder()
+        .header(header::CONTENT_TYPE, content_type(&path))
+        .header(header::ACCEPT_RANGES, "bytes");
+    let (start, count) = if let Some((start, end)) = range {
+        resp = resp
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"));
+        (start, end - start + 1)
+    } else {
+        (0, len)
+    };
+    resp = resp.header(header::CONTENT_LENGTH, count);
+    if req.method == Method::HEAD {
+        return resp.body(full_body(Bytes::new())).map(Some);
+    }
+    resp.body(file_body(file, start, count)).map(Some)
+}
+
+/// Map the path of a request to a path under `dir`.
+/// Returns `None` if it could escape `dir`.
+fn resolve(dir: &Path, uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(uri_path)?;
+    let mut path = dir.to_path_buf();
+    for component in decoded.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            _ if component.contains(['\\', '\0']) => return None,
+            _ if cfg!(windows) && component.contains(':') => return None,
+            _ => path.push(component),
+        }
+    }
+    Some(path)
+}
+
+/// Decode `%XX` escapes. Returns `None` if they are invalid or do not
+/// decode to UTF-8.
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = input.bytes();
+    let mut decoded = Vec::with_capacity(input.len());
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = char::from(bytes.next()?).to_digit(16)?;
+            let low = char::from(bytes.next()?).to_digit(16)?;
+            // `expect`: two hexadecimal digits fit in a byte
+            decoded.push(u8::try_from(high * 16 + low).expect("Invalid escape (this is a bug)"));
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Parse a `Range` header into the first and last octet to send.
+/// Returns `Ok(None)` if the whole file should be sent, either because the
+/// header is malformed or because it asks for several ranges, and `Err` if
+/// the range is not satisfiable.
+fn parse_range(range: &HeaderValue, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.to_str().ok().and_then(|r| r.strip_prefix("bytes=")) else {
+        return Ok(None);
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    if end.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // `bytes=-N`: the last N octets
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        // `bytes=N-`: from N to the end
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        _ => return Ok(None),
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Guess the `Content-Type` of a file from its extension
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Stream `count` octets of `file` from `start`
+fn file_body(file: File, start: u64, count: u64) -> ResponseBody {
+    let chunks = futures_util::stream::try_unfold(
+        (file, start, count),
+        |(mut file, start, remaining)| async move {
+            if remaining == 0 {
+                return Ok(None);
+            }
+            if start != 0 {
+                file.seek(SeekFrom::Start(start)).await?;
+            }
+            let mut buf = BytesMut::new();
+            (&mut file)
+                .take(remaining.min(CHUNK_SIZE))
+                .read_buf(&mut buf)
+                .await?;
+            if buf.is_empty() {
+                // The file was truncated while we were sending it
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let sent = buf.len() as u64;
+            let frame = hyper::body::Frame::data(buf.freeze());
+            Ok::<_, std::io::Error>(Some((frame, (file, 0, remaining - sent))))
+        },
+    );
+    StreamBody::new(chunks).map_err(Into::into).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        crate::tests::setup_logging();
+        let dir = Path::new("/srv/www");
+        assert_eq!(resolve(dir, "/"), Some(PathBuf::from("/srv/www")));
+        assert_eq!(
+            resolve(dir, "/a/./b%20c.html"),
+            Some(PathBuf::from("/srv/www/a/b c.html"))
+        );
+        assert_eq!(resolve(dir, "/a/../../etc/passwd"), None);
+        assert_eq!(resolve(dir, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve(dir, "/a%5c..%5cb"), None);
+        assert_eq!(resolve(dir, "/a%2"), None);
+        assert_eq!(resolve(dir, "/%ff"), None);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        crate::tests::setup_logging();
+        let parse = |range| parse_range(&HeaderValue::from_static(range), 100);
+        assert_eq!(parse("bytes=0-9"), Ok(Some((0, 9))));
+        assert_eq!(parse("bytes=90-"), Ok(Some((90, 99))));
+        assert_eq!(parse("bytes=-10"), Ok(Some((90, 99))));
+        assert_eq!(parse("bytes=-1000"), Ok(Some((0, 99))));
+        assert_eq!(parse("bytes=50-1000"), Ok(Some((50, 99))));
+        assert_eq!(parse("bytes=100-"), Err(()));
+        assert_eq!(parse("bytes=-0"), Err(()));
+        assert_eq!(parse("bytes=0-1,5-6"), Ok(None));
+        assert_eq!(parse("bytes=9-0"), Ok(None));
+        assert_eq!(parse("items=0-9"), Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        crate::tests::setup_logging();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/index.html"), "<p>hello</p>").unwrap();
+        std::fs::write(dir.path().join("data.bin"), b"0123456789").unwrap();
+        let get = |uri: &str| http::Request::get(uri).body(()).unwrap().into_parts().0;
+        let read = |resp: Response<ResponseBody>| async move {
+            resp.into_body().collect().await.unwrap().to_bytes()
+        };
+
+        let resp = serve(dir.path(), &get("/docs/")).await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(read(resp).await, "<p>hello</p>");
+
+        let resp = serve(dir.path(), &get("/docs?x=1")).await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()[header::LOCATION], "/docs/?x=1");
+
+        let mut req = get("/data.bin");
+        req.headers
+            .insert(header::RANGE, HeaderValue::from_static("bytes=2-5"));
+        let resp = serve(dir.path(), &req).await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "4");
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "application/octet-stream"
+        );
+        assert_eq!(read(resp).await, "2345");
+
+        req.headers
+            .insert(header::RANGE, HeaderValue::from_static("bytes=10-"));
+        let resp = serve(dir.path(), &req).await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        let resp = serve(dir.path(), &get("/data.bin")).await.unwrap().unwrap();
+        assert_eq!(read(resp).await, "0123456789");
+
+        assert!(serve(dir.path(), &get("/missing")).await.unwrap().is_none());
+        // A directory without an index
+        assert!(serve(dir.path(), &get("/")).await.unwrap().is_none());
+        let post = http::Request::post("/data.bin")
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0;
+        let resp = serve(dir.path(), &post).await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -0,0 +1,152 @@
+//! Integration with systemd.
+//!
+//! With `Type=notify`, systemd waits for `READY=1` on `$NOTIFY_SOCKET` before
+//! considering the server started. With socket activation, systemd opens the
+//! listening sockets itself and passes them from file descriptor 3 on, with
+//! their count in `$LISTEN_FDS` and our PID in `$LISTEN_PID`. Neither the
+//! variables nor the sockets are passed on to child processes.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use std::ffi::OsStr;
+use std::ops::Range;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, warn};
+
+/// The first file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Whether the passed file descriptors have been taken
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Tell systemd about a state change, e.g. `READY=1`.
+/// Does nothing if we are not run by systemd with `Type=notify`.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    match notify_to(&path, state) {
+        Ok(()) => debug!("Notified systemd: {state}"),
+        Err(err) => warn!("Cannot notify systemd: {err}"),
+    }
+}
+
+/// Send `state` to the notification socket at `path`.
+/// A leading `@` means an abstract socket.
+fn notify_to(path: &OsStr, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// The file descriptors passed to the process `own_pid` according to
+/// `$LISTEN_PID` and `$LISTEN_FDS`
+fn listen_fds_range(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Range<RawFd> {
+    let count = fds
+        .and_then(|fds| fds.parse::<RawFd>().ok())
+        .filter(|_| pid.and_then(|pid| pid.parse().ok()) == Some(own_pid))
+        .unwrap_or(0);
+    LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)
+}
+
+/// Take the listening TCP sockets passed by systemd, if any.
+/// Only the first call gets them.
+#[allow(unsafe_code)]
+pub fn listen_fds() -> std::io::Result<Vec<std::net::TcpListener>> {
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    // SAFETY: this runs once while the server starts, before it spawns
+    // anything that reads the environment.
+    unsafe {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+    listen_fds_range(pid.as_deref(), fds.as_deref(), std::process::id())
+        .map(|fd| {
+            // SAFETY: systemd opened these descriptors for us and nothing
+            // else in the process owns them, because `TAKEN` makes sure
+            // that we only take them once.
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            debug!("Got socket {fd} from systemd");
+            prepare_listener(listener)
+        })
+        .collect()
+}
+
+/// Check that `listener` is a TCP socket and make it non-blocking and
+/// close-on-exec, which systemd does not set
+fn prepare_listener(listener: std::net::TcpListener) -> std::io::Result<std::net::TcpListener> {
+    // Fails if it is not a TCP socket
+    let addr = listener.local_addr()?;
+    debug!("Listening on {addr} from systemd");
+    listener.set_nonblocking(true)?;
+    fcntl(&listener, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds_range() {
+        crate::tests::setup_logging();
+        assert_eq!(listen_fds_range(Some("42"), Some("2"), 42), 3..5);
+        assert!(listen_fds_range(Some("41"), Some("2"), 42).is_empty());
+        assert!(listen_fds_range(None, Some("2"), 42).is_empty());
+        assert!(listen_fds_range(Some("42"), None, 42).is_empty());
+        assert!(listen_fds_range(Some("42"), Some("x"), 42).is_empty());
+        assert!(listen_fds_range(Some("42"), Some("-1"), 42).is_empty());
+    }
+
+    #[test]
+    fn test_prepare_listener() {
+        crate::tests::setup_logging();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // Like a socket passed by systemd
+        fcntl(&listener, FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+        let listener = prepare_listener(listener).unwrap();
+        let flags = fcntl(&listener, FcntlArg::F_GETFD).unwrap();
+        assert!(FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC));
+        let unix = UnixDatagram::unbound().unwrap();
+        let not_tcp = std::net::TcpListener::from(std::os::fd::OwnedFd::from(unix));
+        assert!(prepare_listener(not_tcp).is_err());
+    }
+
+    #[test]
+    fn test_notify() {
+        crate::tests::setup_logging();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let name = format!("penguin-test-{}", std::process::id());
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+            let socket = UnixDatagram::bind_addr(&addr).unwrap();
+            notify_to(OsStr::new(&format!("@{name}")), "RELOADING=1").unwrap();
+            let len = socket.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"RELOADING=1");
+        }
+    }
+}
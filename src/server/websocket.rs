@@ -3,38 +3,409 @@
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
 use super::WebSocket;
-use super::forwarder::tcp_forwarder_on_channel;
-use super::forwarder::udp_forward_on;
+use super::admin::Registry;
+use super::audit_log::{AuditLog, SessionAudit};
+use super::auth::Access;
+use super::dns::{answer_dns_datagram, dns_forwarder_on_channel};
+use super::forwarder::tcp_reverse_forwarder_on_listener;
+use super::forwarder::{self, tcp_forwarder_on_channel};
+use super::forwarder::{ReverseUdpFlows, udp_reverse_forwarder_on_socket};
+use super::forwarder::{UdpOptions, udp_forward_on};
+use super::rate_limit::ClientRateLimit;
+#[cfg(feature = "e2e")]
+use crate::arg::E2eKey;
+use crate::arg::{LimitArgs, MuxArgs};
+use crate::bench::{self, BENCH_HOST};
 use crate::config;
-use penguin_mux::{Datagram, Dupe, Multiplexor};
+use crate::dns::DNS_HOST;
+use crate::parse_remote::remove_brackets;
+use crate::reverse_udp::REVERSE_UDP_HOST;
+use bytes::Bytes;
+use http::HeaderValue;
+use parking_lot::Mutex;
+use penguin_mux::{
+    BindRequest, Compression, Datagram, Dupe, Multiplexor, MuxStream, ResetReason, Resumer,
+    frame::BindType, timing::OptionalDuration,
+};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
 use tokio::{sync::mpsc, task::JoinSet};
-use tracing::{debug, error, trace, warn};
+use tracing::{Instrument, debug, error, info, trace, warn};
 
 #[cfg(feature = "nohash")]
 use nohash_hasher::IntMap;
 #[cfg(not(feature = "nohash"))]
 use std::collections::HashMap as IntMap;
 
-/// Multiplex the `WebSocket` connection and handle the forwarding requests.
-#[tracing::instrument(skip(ws_stream), level = "debug")]
-pub async fn handle_websocket(ws_stream: WebSocket, reverse: bool) {
-    let options = penguin_mux::config::Options::new().bind_buffer_size(if reverse {
-        config::BIND_BUFFER_SIZE
+/// Who started a resumable session. Only the same user on the same
+/// endpoint may resume it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct SessionOwner {
+    pub user: Option<String>,
+    pub endpoint: Option<String>,
+}
+
+/// A session that can be resumed on a new connection
+#[derive(Debug)]
+pub(super) struct Resumable {
+    pub owner: SessionOwner,
+    pub resumer: Resumer<WebSocket>,
+}
+
+/// Resumable sessions by their tokens
+pub(super) type Sessions = Arc<Mutex<HashMap<HeaderValue, Resumable>>>;
+
+/// A resumable session to be started on a `WebSocket` connection
+#[derive(Debug)]
+pub(super) struct NewSession {
+    /// Issued to the client in the handshake reply
+    pub token: HeaderValue,
+    pub owner: SessionOwner,
+    pub sessions: Sessions,
+    pub resume_timeout: Duration,
+}
+
+/// Generate a random token that identifies a new session to its client
+pub(super) fn new_session_token() -> HeaderValue {
+    let token = format!("{:032x}", rand::random::<u128>());
+    // `expect`: hexadecimal digits are valid in a header value
+    HeaderValue::from_str(&token).expect("Invalid session token (this is a bug)")
+}
+
+/// How to serve a `WebSocket` connection
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub(super) struct SessionOptions {
+    /// Whether we accept reverse binding
+    pub reverse: bool,
+    /// Compression algorithm agreed with the client
+    pub compression: Compression,
+    /// Whether the client asked for traffic padding
+    pub padding: bool,
+    /// Whether the client can receive `Batch` frames
+    pub coalesce: bool,
+    /// Bandwidth limits of the session
+    pub limits: LimitArgs,
+    /// Flow control settings of the session
+    pub mux: MuxArgs,
+    /// Limits how fast the client may open streams and send datagrams
+    pub rate_limit: Option<ClientRateLimit>,
+    /// Which destinations the client may forward to
+    pub access: Access,
+    /// Source address of the PROXY protocol header sent to forwarding
+    /// destinations, if any
+    pub proxy_source: Option<SocketAddr>,
+    /// Whether to answer `penguin bench` clients
+    pub bench: bool,
+    /// How often to ping the client
+    pub keepalive: OptionalDuration,
+    /// How UDP flows are forwarded
+    pub udp: UdpOptions,
+    /// Resolver that answers the queries of `dns` remotes
+    pub dns_resolver: Option<SocketAddr>,
+    /// Where to list the session
+    pub registry: Registry,
+    /// Address of the client
+    pub peer: Option<SocketAddr>,
+    /// Secret for end-to-end encryption, if required
+    #[cfg(feature = "e2e")]
+    pub e2e_key: Option<Arc<E2eKey>>,
+    /// Where to log the flows of the session, if anywhere
+    pub audit_log: Option<Arc<AuditLog>>,
+}
+
+impl SessionOptions {
+    /// Settings of the multiplexor of the session
+    fn mux_options(&self) -> penguin_mux::config::Options {
+        self.mux.apply(
+            self.limits.apply(
+                penguin_mux::config::Options::new()
+                    .bind_buffer_size(if self.reverse {
+                        config::BIND_BUFFER_SIZE
+                    } else {
+                        0
+                    })
+                    .compression(self.compression)
+                    .padding(self.padding)
+                    .coalesce(self.coalesce)
+                    .keepalive_interval(self.keepalive),
+            ),
+        )
+    }
+}
+
+/// Forwarders and reverse listeners of a session, and what they need.
+/// They are all aborted when the session ends.
+#[derive(Debug)]
+struct Forwarders {
+    mux: Arc<Multiplexor>,
+    jobs: JoinSet<Result<(), forwarder::Error>>,
+    access: Access,
+    audit: Option<SessionAudit>,
+    rate_limit: Option<ClientRateLimit>,
+    proxy_source: Option<SocketAddr>,
+    bench: bool,
+    udp: UdpOptions,
+    dns_resolver: Option<SocketAddr>,
+    udp_clients: IntMap<u32, mpsc::Sender<Datagram>>,
+    reverse_udp_flows: ReverseUdpFlows,
+    /// For listeners to send UDP datagrams to the main loop
+    datagram_send_tx: mpsc::Sender<Datagram>,
+}
+
+impl Forwarders {
+    /// Forward a new stream from the client
+    fn on_stream(&mut self, stream: MuxStream) {
+        let is_bench = self.bench && stream.dest_host == BENCH_HOST;
+        let flow =
+            self.audit.as_ref().filter(|_| !is_bench).map(|audit| {
+                audit.flow("tcp", stream.flow_id(), &stream.dest_host, stream.dest_port)
+            });
+        if !self
+            .rate_limit
+            .as_ref()
+            .is_none_or(ClientRateLimit::allow_stream)
+        {
+            debug!("client is opening streams too fast, resetting a stream");
+            stream.reset(ResetReason::RateLimited);
+            if let Some(flow) = flow {
+                flow.close("rate limited");
+            }
+        } else if is_bench {
+            self.jobs
+                .spawn(async move { Ok(bench::serve_stream(stream).await?) });
+        } else if stream.dest_host == DNS_HOST.as_bytes() && self.access.lock.is_none() {
+            self.jobs
+                .spawn(dns_forwarder_on_channel(stream, self.dns_resolver, flow).in_current_span());
+        } else {
+            // Forwarders log with the `session_id` of the session
+            self.jobs.spawn(
+                tcp_forwarder_on_channel(stream, self.access.dupe(), self.proxy_source, flow)
+                    .in_current_span(),
+            );
+        }
+    }
+
+    /// Forward a UDP datagram from the client
+    async fn on_datagram(&mut self, datagram_frame: Datagram) {
+        if !self
+            .rate_limit
+            .as_ref()
+            .is_none_or(ClientRateLimit::allow_datagram)
+        {
+            trace!("client is sending datagrams too fast, dropping a datagram");
+            return;
+        }
+        if self.bench && datagram_frame.target_host == BENCH_HOST {
+            self.mux
+                .send_datagram(datagram_frame)
+                .await
+                .unwrap_or_else(|err| error!("Failed to send datagram: {err}"));
+            return;
+        }
+        let flow_id = datagram_frame.flow_id;
+        if datagram_frame.target_host == DNS_HOST.as_bytes() && self.access.lock.is_none() {
+            let flow = self.audit.as_ref().map(|audit| {
+                audit.flow(
+                    "dns",
+                    flow_id,
+                    &datagram_frame.target_host,
+                    datagram_frame.target_port,
+                )
+            });
+            self.jobs.spawn(
+                answer_dns_datagram(
+                    datagram_frame,
+                    self.dns_resolver,
+                    self.datagram_send_tx.dupe(),
+                    self.udp.timeout,
+                    flow,
+                )
+                .in_current_span(),
+            );
+        } else if datagram_frame.target_host == REVERSE_UDP_HOST {
+            // A reply to a peer of a reverse UDP remote
+            let sender = self.reverse_udp_flows.lock().get(&flow_id).map(Dupe::dupe);
+            if let Some(sender) = sender {
+                sender.try_send(datagram_frame).ok();
+            } else {
+                trace!("dropping reply to unknown reverse UDP flow {flow_id:08x}");
+            }
+        } else if let Some(sender) = self.udp_clients.get_mut(&flow_id) {
+            sender
+                .try_send(datagram_frame)
+                .unwrap_or_else(|err| match err {
+                    mpsc::error::TrySendError::Closed(_) => {
+                        // This client has been pruned, so we should
+                        // remove it from the map and hopefully
+                        // the client will try again.
+                        trace!("UDP client {flow_id} has been pruned");
+                        self.udp_clients.remove(&flow_id);
+                    }
+                    mpsc::error::TrySendError::Full(_) => {
+                        // The channel is full, so just discard the datagram
+                        trace!("UDP client {flow_id} has a full channel");
+                    }
+                });
+        } else {
+            let (sender, receiver) =
+                mpsc::channel::<Datagram>(config::INCOMING_DATAGRAM_BUFFER_SIZE);
+            self.udp_clients.insert(flow_id, sender);
+            let flow = self.audit.as_ref().map(|audit| {
+                audit.flow(
+                    "udp",
+                    flow_id,
+                    &datagram_frame.target_host,
+                    datagram_frame.target_port,
+                )
+            });
+            self.jobs.spawn(
+                udp_forward_on(
+                    datagram_frame,
+                    receiver,
+                    self.datagram_send_tx.dupe(),
+                    self.access.dupe(),
+                    self.mux.buffer_pool().dupe(),
+                    self.udp,
+                    flow,
+                )
+                .in_current_span(),
+            );
+        }
+    }
+
+    /// Open a reverse remote the client asked for.
+    /// Dropping `bind_request` without replying rejects it.
+    async fn on_bind_request(&mut self, bind_request: BindRequest<'static>) {
+        let Some(listener) = open_reverse_listener(&bind_request, &self.access).await else {
+            return;
+        };
+        if bind_request.reply(true).is_err() {
+            return;
+        }
+        let bind_host = Bytes::copy_from_slice(bind_request.host());
+        let audit = self.audit.as_ref().map(Dupe::dupe);
+        match listener {
+            ReverseListener::Tcp(listener) => self.jobs.spawn(
+                tcp_reverse_forwarder_on_listener(
+                    listener,
+                    self.mux.dupe(),
+                    bind_host,
+                    bind_request.port(),
+                    audit,
+                )
+                .in_current_span(),
+            ),
+            ReverseListener::Udp(socket) => self.jobs.spawn(
+                udp_reverse_forwarder_on_socket(
+                    socket,
+                    bind_host,
+                    bind_request.port(),
+                    self.reverse_udp_flows.dupe(),
+                    self.datagram_send_tx.dupe(),
+                    self.mux.buffer_pool().dupe(),
+                    self.udp.timeout,
+                    audit,
+                )
+                .in_current_span(),
+            ),
+        };
+    }
+}
+
+/// Start the multiplexor of a session, registering it for resumption if
+/// `session` is set
+fn start_multiplexor(
+    ws_stream: WebSocket,
+    options: penguin_mux::config::Options,
+    session: Option<&NewSession>,
+) -> Multiplexor {
+    if let Some(session) = session {
+        let options = options.resume_timeout(session.resume_timeout);
+        let (mux, resumer) = Multiplexor::new_resumable(ws_stream, Some(options), None);
+        let resumable = Resumable {
+            owner: session.owner.clone(),
+            resumer,
+        };
+        session
+            .sessions
+            .lock()
+            .insert(session.token.dupe(), resumable);
+        debug!("registered resumable session");
+        mux
     } else {
-        0
-    });
-    let mux = Multiplexor::new(ws_stream, Some(options), None);
-    let mut udp_clients: IntMap<u32, mpsc::Sender<Datagram>> = IntMap::default();
+        Multiplexor::new(ws_stream, Some(options), None)
+    }
+}
+
+/// Wait until the credentials of `access` expire, if ever
+async fn credentials_expired(access: &Access) {
+    match access.user.as_ref().and_then(|user| user.expires_in()) {
+        Some(expires_in) => tokio::time::sleep(expires_in).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Multiplex the `WebSocket` connection and handle the forwarding requests.
+#[tracing::instrument(
+    name = "session",
+    skip_all,
+    fields(session_id = tracing::field::Empty, peer = options.peer.map(tracing::field::display))
+)]
+pub async fn handle_websocket(
+    ws_stream: WebSocket,
+    options: SessionOptions,
+    session: Option<NewSession>,
+) {
+    let mux_options = options.mux_options();
+    let SessionOptions {
+        reverse,
+        rate_limit,
+        access,
+        proxy_source,
+        bench,
+        udp,
+        dns_resolver,
+        registry,
+        peer,
+        audit_log,
+        // The rest is used by `mux_options` or by the caller
+        ..
+    } = options;
+    // Shared with the listeners of reverse remotes so that they can open streams
+    let mux = Arc::new(start_multiplexor(ws_stream, mux_options, session.as_ref()));
+    let registration = registry.register(access.user_name(), peer, mux.dupe());
+    tracing::Span::current().record("session_id", registration.id());
+    let audit =
+        audit_log.map(|log| SessionAudit::new(log, registration.id(), access.user_name(), peer));
     debug!("WebSocket connection established");
-    let mut jobs = JoinSet::new();
-    // Channel for listeners to send UDP datagrams to the main loop
     let (datagram_send_tx, mut datagram_send_rx) =
         mpsc::channel::<Datagram>(config::INCOMING_DATAGRAM_BUFFER_SIZE);
+    // Close the session when the credentials of the client expire
+    let expired = credentials_expired(&access);
+    tokio::pin!(expired);
+    let mut forwarders = Forwarders {
+        mux: mux.dupe(),
+        jobs: JoinSet::new(),
+        access: access.dupe(),
+        audit,
+        rate_limit,
+        proxy_source,
+        bench,
+        udp,
+        dns_resolver,
+        udp_clients: IntMap::default(),
+        reverse_udp_flows: ReverseUdpFlows::default(),
+        datagram_send_tx,
+    };
     loop {
         trace!("server WebSocket loop");
         tokio::select! {
             // Check if any of the jobs have finished
-            Some(result) = jobs.join_next() => {
+            Some(result) = forwarders.jobs.join_next() => {
                 match result {
                     Ok(Ok(())) => {}
                     Ok(Err(err)) => {
@@ -46,33 +417,12 @@ pub async fn handle_websocket(ws_stream: WebSocket, reverse: bool) {
                 }
             }
             // Check if the multiplexor has received a new stream request
-            Ok(result) = mux.accept_stream_channel() => {
-                jobs.spawn(tcp_forwarder_on_channel(result));
-            }
+            Ok(stream) = mux.accept_stream_channel() => forwarders.on_stream(stream),
             // Check if the multiplexor has received a UDP datagram
-            Ok(datagram_frame) = mux.get_datagram() => {
-                let flow_id = datagram_frame.flow_id;
-                if let Some(sender) = udp_clients.get_mut(&flow_id) {
-                    sender.try_send(datagram_frame).unwrap_or_else(|err| {
-                        match err {
-                            mpsc::error::TrySendError::Closed(_) => {
-                                // This client has been pruned, so we should
-                                // remove it from the map and hopefully
-                                // the client will try again.
-                                trace!("UDP client {flow_id} has been pruned");
-                                udp_clients.remove(&flow_id);
-                            }
-                            mpsc::error::TrySendError::Full(_) => {
-                                // The channel is full, so just discard the datagram
-                                trace!("UDP client {flow_id} has a full channel");
-                            }
-                        }
-                    });
-                } else {
-                    let (sender, receiver) = mpsc::channel::<Datagram>(config::INCOMING_DATAGRAM_BUFFER_SIZE);
-                    udp_clients.insert(flow_id, sender);
-                    jobs.spawn(udp_forward_on(datagram_frame, receiver, datagram_send_tx.dupe()));
-                }
+            Ok(datagram_frame) = mux.get_datagram() => forwarders.on_datagram(datagram_frame).await,
+            // Check if the client has requested a reverse remote
+            Ok(bind_request) = mux.next_bind_request(), if reverse => {
+                forwarders.on_bind_request(bind_request).await;
             }
             // Check if any of the listeners have sent a UDP datagram
             Some(datagram_frame) = datagram_send_rx.recv() => {
@@ -80,12 +430,73 @@ pub async fn handle_websocket(ws_stream: WebSocket, reverse: bool) {
                     |err| error!("Failed to send datagram: {err}"),
                 );
             }
+            () = &mut expired => {
+                info!("credentials of the client expired, closing the session");
+                break;
+            }
+            () = registration.kicked() => {
+                info!("closing the session as asked through the admin API");
+                break;
+            }
             else => {
                 // The multiplexor has closed for some reason
                 break;
             }
         }
     }
-    debug!("WebSocket connection closed");
-    jobs.shutdown().await;
+    if let Some(rtt) = mux.rtt() {
+        info!("WebSocket connection closed, round-trip time {rtt:?}");
+    } else {
+        debug!("WebSocket connection closed");
+    }
+    if let Some(session) = session {
+        session.sessions.lock().remove(&session.token);
+    }
+    forwarders.jobs.shutdown().await;
+}
+
+/// What we listen on for a reverse remote
+#[derive(Debug)]
+enum ReverseListener {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+/// Try to open a listener for a `Bind` request from the client, or a UDP
+/// socket for a datagram `Bind` request.
+/// Returns `None` if the request should be rejected.
+async fn open_reverse_listener(
+    bind_request: &BindRequest<'_>,
+    access: &Access,
+) -> Option<ReverseListener> {
+    let Ok(host) = std::str::from_utf8(bind_request.host()) else {
+        warn!("Rejecting bind request with invalid host");
+        return None;
+    };
+    let port = bind_request.port();
+    if !access.user_allows_reverse(host, port) {
+        info!("Rejecting bind request on {host}:{port}: not allowed for the user");
+        return None;
+    }
+    let addr = (remove_brackets(host), port);
+    let result = match bind_request.bind_type() {
+        BindType::Stream => TcpListener::bind(addr).await.map(ReverseListener::Tcp),
+        BindType::Datagram => UdpSocket::bind(addr).await.map(ReverseListener::Udp),
+    };
+    match result {
+        Ok(listener) => {
+            // `expect`: at this point `listener` should be bound. Otherwise, it's a bug.
+            let local_addr = match &listener {
+                ReverseListener::Tcp(listener) => listener.local_addr(),
+                ReverseListener::Udp(socket) => socket.local_addr(),
+            }
+            .expect("Failed to get local address of reverse listener (this is a bug)");
+            info!("Reverse remote listening on {local_addr}");
+            Some(listener)
+        }
+        Err(err) => {
+            warn!("Failed to bind reverse remote on {host}:{port}: {err}");
+            None
+        }
+    }
 }
@@ -0,0 +1,385 @@
+//! Running as a Windows service.
+//!
+//! `--service install` registers the current command line with the service
+//! control manager, which then starts it automatically with `--service run`.
+//! `--service uninstall` removes it again. The service is named
+//! `penguin-client` or `penguin-server` after the subcommand. Paths on the
+//! command line should be absolute because services start in the system
+//! directory.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#![allow(unsafe_code)]
+
+use crate::arg::{Commands, PenguinCli};
+use std::ffi::{OsStr, c_void};
+use std::os::windows::ffi::OsStrExt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tracing::{error, info};
+use windows_sys::Win32::Foundation::{
+    ERROR_CALL_NOT_IMPLEMENTED, ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR,
+};
+use windows_sys::Win32::Storage::FileSystem::DELETE;
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW,
+    RegisterServiceCtrlHandlerExW, SC_HANDLE, SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE,
+    SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_ALL_ACCESS, SERVICE_AUTO_START,
+    SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP,
+    SERVICE_ERROR_NORMAL, SERVICE_RUNNING, SERVICE_STATUS, SERVICE_STATUS_CURRENT_STATE,
+    SERVICE_STOP_PENDING, SERVICE_STOPPED, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+    SetServiceStatus, StartServiceCtrlDispatcherW,
+};
+
+/// Errors when managing or running the service
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot {0}: {1}")]
+    Os(&'static str, std::io::Error),
+    #[error("Cannot find the executable: {0}")]
+    CurrentExe(std::io::Error),
+    #[error("Service dispatcher panicked: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// What `--service` does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceAction {
+    Install,
+    Uninstall,
+    /// Used by the service control manager
+    Run,
+}
+
+/// Service action parsing errors
+#[derive(Debug, Error)]
+#[error("expected `install`, `uninstall` or `run`")]
+pub struct ServiceActionError;
+
+impl FromStr for ServiceAction {
+    type Err = ServiceActionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "install" => Ok(Self::Install),
+            "uninstall" => Ok(Self::Uninstall),
+            "run" => Ok(Self::Run),
+            _ => Err(ServiceActionError),
+        }
+    }
+}
+
+/// Runtime to run the subcommand on from the service thread
+static RUNTIME: OnceLock<tokio::runtime::Handle> = OnceLock::new();
+/// Notified when the service control manager asks us to stop
+static STOP: Notify = Notify::const_new();
+/// Returned by `RegisterServiceCtrlHandlerExW`
+static STATUS_HANDLE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+/// A handle to the service control manager or a service
+struct ScHandle(SC_HANDLE);
+
+impl ScHandle {
+    fn new(handle: SC_HANDLE, what: &'static str) -> Result<Self, Error> {
+        if handle.is_null() {
+            Err(Error::Os(what, std::io::Error::last_os_error()))
+        } else {
+            Ok(Self(handle))
+        }
+    }
+
+    fn open_manager(access: u32) -> Result<Self, Error> {
+        // SAFETY: null names mean the local machine and the active database
+        let handle = unsafe { OpenSCManagerW(std::ptr::null(), std::ptr::null(), access) };
+        Self::new(handle, "open the service control manager")
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        // SAFETY: the handle is valid and not used after this
+        unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/// Encode a string as a null-terminated UTF-16 string
+fn wide(s: impl AsRef<OsStr>) -> Vec<u16> {
+    s.as_ref().encode_wide().chain(std::iter::once(0)).collect()
+}
+
+/// The name of the service running `cli`
+fn service_name(cli: &PenguinCli) -> &'static str {
+    match cli.subcommand {
+        #[cfg(feature = "client")]
+        Commands::Client(_) => "penguin-client",
+        #[cfg(feature = "client")]
+        Commands::Bench(_) => "penguin-bench",
+        #[cfg(feature = "client")]
+        Commands::Ping(_) => "penguin-ping",
+        #[cfg(feature = "server")]
+        Commands::Server(_) => "penguin-server",
+    }
+}
+
+/// Quote an argument so that `CommandLineToArgvW` reads it back unchanged
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+        // Backslashes are only special before a quote
+        let escapes = if c == '"' {
+            backslashes * 2 + 1
+        } else {
+            backslashes
+        };
+        quoted.extend(std::iter::repeat_n('\\', escapes));
+        quoted.push(c);
+        backslashes = 0;
+    }
+    quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// The command line of the service: `args` with `--service run` instead
+/// of the `--service` option given
+fn service_command_line(exe: &str, args: impl IntoIterator<Item = String>) -> String {
+    let mut parts = vec![quote_arg(exe)];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--service" {
+            args.next();
+        } else if !arg.starts_with("--service=") {
+            parts.push(quote_arg(&arg));
+        }
+    }
+    parts.push("--service run".to_string());
+    parts.join(" ")
+}
+
+/// Carry out `--service`
+pub async fn service_main(action: ServiceAction, cli: &'static PenguinCli) -> Result<(), Error> {
+    let name = service_name(cli);
+    match action {
+        ServiceAction::Install => {
+            let exe = std::env::current_exe().map_err(Error::CurrentExe)?;
+            let command_line =
+                service_command_line(&exe.to_string_lossy(), std::env::args().skip(1));
+            install(name, &command_line)?;
+            info!("Installed service `{name}`: {command_line}");
+        }
+        ServiceAction::Uninstall => {
+            uninstall(name)?;
+            info!("Uninstalled service `{name}`");
+        }
+        ServiceAction::Run => {
+            RUNTIME
+                .set(tokio::runtime::Handle::current())
+                .expect("`service_main` should not be called twice (this is a bug)");
+            tokio::task::spawn_blocking(move || dispatch(name)).await??;
+        }
+    }
+    Ok(())
+}
+
+fn install(name: &str, command_line: &str) -> Result<(), Error> {
+    let manager = ScHandle::open_manager(SC_MANAGER_CREATE_SERVICE)?;
+    let name = wide(name);
+    let command_line = wide(command_line);
+    // SAFETY: the strings are null-terminated and outlive the call
+    let service = unsafe {
+        CreateServiceW(
+            manager.0,
+            name.as_ptr(),
+            name.as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            command_line.as_ptr(),
+            std::ptr::null(),
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    ScHandle::new(service, "create the service")?;
+    Ok(())
+}
+
+/// Mark the service for deletion. It goes away once it stops.
+fn uninstall(name: &str) -> Result<(), Error> {
+    let manager = ScHandle::open_manager(SC_MANAGER_CONNECT)?;
+    let name = wide(name);
+    // SAFETY: the name is null-terminated and outlives the call
+    let service = unsafe { OpenServiceW(manager.0, name.as_ptr(), DELETE) };
+    let service = ScHandle::new(service, "open the service")?;
+    // SAFETY: the handle is valid and was opened with `DELETE`
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(Error::Os(
+            "delete the service",
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Hand the thread over to the service control manager until the service
+/// stops
+fn dispatch(name: &str) -> Result<(), Error> {
+    let mut name = wide(name);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(run_service),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: std::ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    // SAFETY: the table ends with a null entry and outlives the call
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(Error::Os(
+            "connect to the service control manager",
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Report the state of the service
+fn set_status(state: SERVICE_STATUS_CURRENT_STATE, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: if exit_code == 0 {
+            NO_ERROR
+        } else {
+            ERROR_SERVICE_SPECIFIC_ERROR
+        },
+        dwServiceSpecificExitCode: exit_code,
+        dwCheckPoint: 0,
+        dwWaitHint: if state == SERVICE_STOP_PENDING {
+            5000
+        } else {
+            0
+        },
+    };
+    // SAFETY: the handle stays valid until the process exits
+    if unsafe { SetServiceStatus(STATUS_HANDLE.load(Ordering::SeqCst), &raw const status) } == 0 {
+        error!(
+            "Cannot report service status: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Called by the service control manager on its own thread
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, 0);
+            STOP.notify_one();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+/// The entry point of the service, called by the service control manager
+/// on a new thread
+unsafe extern "system" fn run_service(_argc: u32, _argv: *mut windows_sys::core::PWSTR) {
+    let cli = PenguinCli::get_global();
+    let name = wide(service_name(cli));
+    // SAFETY: the name is null-terminated and `control_handler` does not
+    // use the context
+    let status_handle = unsafe {
+        RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), std::ptr::null())
+    };
+    if status_handle.is_null() {
+        error!(
+            "Cannot register service control handler: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    STATUS_HANDLE.store(status_handle, Ordering::SeqCst);
+    set_status(SERVICE_RUNNING, 0);
+    let runtime = RUNTIME
+        .get()
+        .expect("Service started without a runtime (this is a bug)");
+    let result = runtime.block_on(async {
+        tokio::select! {
+            result = crate::run(cli) => result,
+            () = STOP.notified() => Ok(()),
+        }
+    });
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(err) => {
+            error!("Service failed: {err}");
+            1
+        }
+    };
+    set_status(SERVICE_STOPPED, exit_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_arg() {
+        crate::tests::setup_logging();
+        assert_eq!(quote_arg("server"), "server");
+        assert_eq!(quote_arg(""), "\"\"");
+        assert_eq!(
+            quote_arg(r"C:\Program Files\penguin.exe"),
+            r#""C:\Program Files\penguin.exe""#
+        );
+        assert_eq!(quote_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quote_arg(r"C:\dir with space\"), r#""C:\dir with space\\""#);
+    }
+
+    #[test]
+    fn test_service_command_line() {
+        crate::tests::setup_logging();
+        let args = ["server", "--service", "install", "--port", "443"].map(String::from);
+        assert_eq!(
+            service_command_line(r"C:\penguin.exe", args),
+            r"C:\penguin.exe server --port 443 --service run"
+        );
+        let args = ["--service=install", "client", "ws://example.com", "8080"].map(String::from);
+        assert_eq!(
+            service_command_line(r"C:\penguin.exe", args),
+            r"C:\penguin.exe client ws://example.com 8080 --service run"
+        );
+        assert_eq!(
+            "install".parse::<ServiceAction>().unwrap(),
+            ServiceAction::Install
+        );
+        assert!("start".parse::<ServiceAction>().is_err());
+    }
+}
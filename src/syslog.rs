@@ -0,0 +1,147 @@
+//! Writing log messages to the local syslog daemon with `--log-target syslog`.
+//!
+//! Each event is sent as one datagram in the format the daemon expects on
+//! its local socket, `<PRI>penguin[PID]: MESSAGE`, and the daemon adds the
+//! timestamp and hostname. The priority comes from the level of the event
+//! under the `daemon` facility.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Local sockets of syslog daemons on Linux and macOS
+const SOCKET_PATHS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+/// The `daemon` facility
+const FACILITY: u8 = 3;
+
+/// Connection to the syslog daemon
+#[derive(Debug)]
+pub struct Syslog {
+    socket: UnixDatagram,
+    /// `penguin[PID]`
+    tag: String,
+}
+
+impl Syslog {
+    /// Connect to the syslog daemon on its usual socket
+    pub fn connect() -> io::Result<Self> {
+        let mut last_err = None;
+        for path in SOCKET_PATHS {
+            match Self::connect_to(path) {
+                Ok(syslog) => return Ok(syslog),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        // `expect`: `SOCKET_PATHS` is not empty
+        Err(last_err.expect("No syslog socket paths (this is a bug)"))
+    }
+
+    /// Connect to a syslog daemon listening on `path`
+    pub fn connect_to(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket,
+            tag: format!("penguin[{}]", std::process::id()),
+        })
+    }
+
+    /// A writer for one message at `level`
+    const fn writer(&self, level: Level) -> SyslogWriter<'_> {
+        SyslogWriter {
+            syslog: self,
+            level,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = SyslogWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.writer(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.writer(*meta.level())
+    }
+}
+
+/// Collects one formatted event and sends it when dropped
+#[derive(Debug)]
+pub struct SyslogWriter<'a> {
+    syslog: &'a Syslog,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl io::Write for SyslogWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogWriter<'_> {
+    fn drop(&mut self) {
+        let message = self.buf.trim_ascii_end();
+        if message.is_empty() {
+            return;
+        }
+        let severity = match self.level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        let mut datagram =
+            format!("<{}>{}: ", FACILITY * 8 + severity, self.syslog.tag).into_bytes();
+        datagram.extend_from_slice(message);
+        // Nowhere to report the error, and the daemon may just be restarting
+        self.syslog.socket.send(&datagram).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_syslog_writer() {
+        crate::tests::setup_logging();
+        let dir = std::env::temp_dir().join(format!("penguin-syslog-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log");
+        std::fs::remove_file(&path).ok();
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::connect_to(&path).unwrap();
+        let mut writer = syslog.writer(Level::WARN);
+        writer.write_all(b"Connection failed\n").unwrap();
+        drop(writer);
+        // Empty messages are not sent
+        drop(syslog.writer(Level::INFO));
+        let mut writer = syslog.writer(Level::DEBUG);
+        writer.write_all(b"done").unwrap();
+        drop(writer);
+        let mut buf = [0; 256];
+        let len = daemon.recv(&mut buf).unwrap();
+        let tag = format!("penguin[{}]", std::process::id());
+        assert_eq!(
+            buf[..len],
+            *format!("<28>{tag}: Connection failed").as_bytes()
+        );
+        let len = daemon.recv(&mut buf).unwrap();
+        assert_eq!(buf[..len], *format!("<31>{tag}: done").as_bytes());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
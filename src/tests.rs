@@ -1,8 +1,9 @@
 use super::*;
 use crate::{arg::ServerUrl, parse_remote::Remote};
+use penguin_mux::limit::Bandwidth;
 use penguin_mux::timing::OptionalDuration;
 #[allow(unused_imports)]
-use std::sync::{LazyLock, OnceLock};
+use std::sync::{Arc, LazyLock, OnceLock};
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     str::FromStr,
@@ -29,6 +30,7 @@ fn make_server_args(host: &str, port: u16) -> arg::ServerArgs {
         host: vec![host.to_string()],
         port: vec![port],
         not_found_resp: "404".to_string(),
+        ws_path: arg::WsPath::default(),
         // Very short timeout for testing purposes.
         timeout: OptionalDuration::from_secs(2),
         ..Default::default()
@@ -38,13 +40,14 @@ fn make_server_args(host: &str, port: u16) -> arg::ServerArgs {
 fn make_client_args(servhost: &str, servport: u16, remotes: Vec<Remote>) -> arg::ClientArgs {
     arg::ClientArgs {
         server: ServerUrl::from_str(&format!("ws://{servhost}:{servport}/ws")).unwrap(),
-        remote: remotes,
+        remote: remotes.into_iter().map(Into::into).collect(),
         keepalive: OptionalDuration::NONE,
         max_retry_count: 10,
         max_retry_interval: 10,
         tls_skip_verify: false,
         hostname: Some(http::HeaderValue::from_static("localhost")),
         channel_timeout: OptionalDuration::from_secs(10),
+        connections: 1,
         ..Default::default()
     }
 }
@@ -70,13 +73,12 @@ async fn make_server_cert_ecdsa() -> TempDir {
 #[tokio::test]
 async fn test_client_handshake_timeout() {
     static CLIENT_ARGS: OnceLock<arg::ClientArgs> = OnceLock::new();
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
     let blackhole = TcpListener::bind("[::1]:0").await.unwrap();
     let addr = blackhole.local_addr().unwrap();
     let client_args = arg::ClientArgs {
         server: ServerUrl::from_str(&format!("ws://{addr}/ws")).unwrap(),
-        remote: vec![Remote::from_str("[::1]:0:socks").unwrap()],
+        remote: vec![Remote::from_str("[::1]:0:socks").unwrap().into()],
         keepalive: OptionalDuration::NONE,
         max_retry_count: 1,
         handshake_timeout: OptionalDuration::from_secs(1),
@@ -85,10 +87,9 @@ async fn test_client_handshake_timeout() {
     CLIENT_ARGS.set(client_args).unwrap();
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let r = crate::client::client_main_inner(
         CLIENT_ARGS.get().unwrap(),
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     )
@@ -106,13 +107,12 @@ async fn test_client_handshake_timeout() {
 #[tokio::test]
 async fn test_client_handshake_timeout_will_retry() {
     static CLIENT_ARGS: OnceLock<arg::ClientArgs> = OnceLock::new();
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
     let blackhole = TcpListener::bind("[::1]:0").await.unwrap();
     let addr = blackhole.local_addr().unwrap();
     let client_args = arg::ClientArgs {
         server: ServerUrl::from_str(&format!("ws://{addr}/ws")).unwrap(),
-        remote: vec![Remote::from_str("[::1]:0:socks").unwrap()],
+        remote: vec![Remote::from_str("[::1]:0:socks").unwrap().into()],
         keepalive: OptionalDuration::NONE,
         max_retry_count: 3,
         handshake_timeout: OptionalDuration::from_secs(1),
@@ -121,11 +121,10 @@ async fn test_client_handshake_timeout_will_retry() {
     CLIENT_ARGS.set(client_args).unwrap();
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let initial_time = std::time::Instant::now();
     let r = crate::client::client_main_inner(
         CLIENT_ARGS.get().unwrap(),
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     )
@@ -153,7 +152,6 @@ async fn test_it_works() {
             vec![Remote::from_str("127.0.0.1:21628:127.0.0.1:10807").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
@@ -167,14 +165,13 @@ async fn test_it_works() {
     });
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
     tokio::time::sleep(Duration::from_secs(2)).await;
     let mut sock = TcpStream::connect("127.0.0.1:21628").await.unwrap();
     sock.write_all(&input_bytes).await.unwrap();
@@ -185,12 +182,250 @@ async fn test_it_works() {
     client_task.abort();
 }
 
+#[tokio::test]
+async fn test_reverse_it_works() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> = LazyLock::new(|| arg::ServerArgs {
+        reverse: true,
+        auth: Some("user:pass".parse().unwrap()),
+        ..make_server_args("127.0.0.1", 24172)
+    });
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| arg::ClientArgs {
+        auth: Some("user:pass".parse().unwrap()),
+        ..make_client_args(
+            "127.0.0.1",
+            24172,
+            vec![Remote::from_str("R:127.0.0.1:21629:127.0.0.1:10808").unwrap()],
+        )
+    });
+    setup_logging();
+
+    let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let input_len = input_bytes.len();
+    let second_task = tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:10808").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut output_bytes = vec![0u8; input_len];
+        stream.read_exact(&mut output_bytes).await.unwrap();
+        output_bytes
+    });
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    // This time, the server is listening
+    let mut sock = TcpStream::connect("127.0.0.1:21629").await.unwrap();
+    sock.write_all(&input_bytes).await.unwrap();
+    sock.shutdown().await.unwrap();
+    let output_bytes = second_task.await.unwrap();
+    assert_eq!(input_bytes, output_bytes);
+    server_task.abort();
+    client_task.abort();
+}
+
+#[cfg(feature = "tests-udp")]
+#[tokio::test]
+async fn test_reverse_udp_works() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> = LazyLock::new(|| arg::ServerArgs {
+        reverse: true,
+        ..make_server_args("127.0.0.1", 24174)
+    });
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            24174,
+            vec![Remote::from_str("R:127.0.0.1:21632:127.0.0.1:10809/udp").unwrap()],
+        )
+    });
+    setup_logging();
+
+    // Echo server behind the client
+    let target_task = tokio::spawn(async move {
+        let socket = UdpSocket::bind("127.0.0.1:10809").await.unwrap();
+        let mut buf = [0u8; 1024];
+        loop {
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            socket.send_to(&buf[..len], addr).await.unwrap();
+        }
+    });
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    // Two peers of the server get their own flows
+    for peer in 0..2 {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for i in 0..3 {
+            let message = format!("peer {peer} message {i}");
+            sock.send_to(message.as_bytes(), "127.0.0.1:21632")
+                .await
+                .unwrap();
+            let mut buf = [0u8; 32];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(5), sock.recv_from(&mut buf))
+                .await
+                .expect("Timed out waiting for the echo")
+                .unwrap();
+            assert_eq!(&buf[..len], message.as_bytes());
+        }
+    }
+    target_task.abort();
+    server_task.abort();
+    client_task.abort();
+}
+
+#[cfg(feature = "tests-udp")]
+#[tokio::test]
+async fn test_dns_remote_works() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> = LazyLock::new(|| arg::ServerArgs {
+        dns_resolver: Some("127.0.0.1:10810".parse().unwrap()),
+        ..make_server_args("127.0.0.1", 24175)
+    });
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            24175,
+            vec![Remote::from_str("127.0.0.1:21633:dns").unwrap()],
+        )
+    });
+    setup_logging();
+
+    // A resolver that answers every query with itself and the QR bit set
+    let udp_resolver_task = tokio::spawn(async move {
+        let socket = UdpSocket::bind("127.0.0.1:10810").await.unwrap();
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, addr) = socket.recv_from(&mut buf).await.unwrap();
+            buf[2] |= 0x80;
+            socket.send_to(&buf[..len], addr).await.unwrap();
+        }
+    });
+    let tcp_resolver_task = tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:10810").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let len = stream.read_u16().await.unwrap();
+        let mut query = vec![0u8; usize::from(len)];
+        stream.read_exact(&mut query).await.unwrap();
+        query[2] |= 0x80;
+        stream.write_u16(len).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+    });
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let query =
+        b"\x37\x0a\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01";
+    // Over UDP
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sock.send_to(query, "127.0.0.1:21633").await.unwrap();
+    let mut buf = [0u8; 512];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), sock.recv_from(&mut buf))
+        .await
+        .expect("Timed out waiting for DNS response")
+        .unwrap();
+    assert_eq!(&buf[..3], b"\x37\x0a\x81");
+    assert_eq!(&buf[3..len], &query[3..]);
+    // Over TCP
+    let mut stream = TcpStream::connect("127.0.0.1:21633").await.unwrap();
+    stream
+        .write_u16(u16::try_from(query.len()).unwrap())
+        .await
+        .unwrap();
+    stream.write_all(query).await.unwrap();
+    let len = stream.read_u16().await.unwrap();
+    let mut answer = vec![0u8; usize::from(len)];
+    stream.read_exact(&mut answer).await.unwrap();
+    assert_eq!(&answer[..3], b"\x37\x0a\x81");
+    tcp_resolver_task.await.unwrap();
+    udp_resolver_task.abort();
+    server_task.abort();
+    client_task.abort();
+}
+
+#[tokio::test]
+async fn test_reverse_socks5_works() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> = LazyLock::new(|| arg::ServerArgs {
+        reverse: true,
+        ..make_server_args("127.0.0.1", 24173)
+    });
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            24173,
+            vec![Remote::from_str("R:127.0.0.1:21631:socks").unwrap()],
+        )
+    });
+    setup_logging();
+
+    let input_bytes: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
+    let input_len = input_bytes.len();
+    let target_server_task = tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:26308").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut output_bytes = vec![0u8; input_len];
+        stream.read_exact(&mut output_bytes).await.unwrap();
+        stream.write_all(&output_bytes).await.unwrap();
+    });
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // The SOCKS server is on the server side, but the connection is made by the client
+    let mut sock = TcpStream::connect("127.0.0.1:21631").await.unwrap();
+    sock.write_all(b"\x05\x01\x00").await.unwrap();
+    let mut buf = vec![0u8; 32];
+    let n = sock.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"\x05\x00");
+    sock.write_all(b"\x05\x01\x00\x01\x7f\x00\x00\x01\x66\xc4")
+        .await
+        .unwrap();
+    let n = sock.read(&mut buf).await.unwrap();
+    assert!(n > 3);
+    assert_eq!(&buf[..3], b"\x05\x00\x00");
+    sock.write_all(&input_bytes).await.unwrap();
+    let mut output_bytes = vec![0u8; input_len];
+    sock.read_exact(&mut output_bytes).await.unwrap();
+    assert_eq!(input_bytes, output_bytes);
+
+    target_server_task.await.unwrap();
+    server_task.abort();
+    client_task.abort();
+}
+
 #[tokio::test]
 async fn test_server_timeout() {
     static SERVER_ARGS: LazyLock<arg::ServerArgs> =
         LazyLock::new(|| make_server_args("::1", 22183));
     setup_logging();
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
     tokio::time::sleep(Duration::from_secs(2)).await;
     // Connect to the socket and do nothing. Make sure the socket is closed
     let mut sock = TcpStream::connect("[::1]:22183").await.unwrap();
@@ -218,7 +453,6 @@ async fn test_server_timeout_does_not_interrupt_ws() {
             vec![Remote::from_str("[::1]:27848:[::1]:17787").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
@@ -233,14 +467,13 @@ async fn test_server_timeout_does_not_interrupt_ws() {
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
     // Wait much more than the timeout to ensure that any timeout would pop up.
     tokio::time::sleep(Duration::from_secs(8)).await;
     let mut sock = TcpStream::connect("[::1]:27848").await.unwrap();
@@ -263,7 +496,6 @@ async fn test_it_works_v6() {
             vec![Remote::from_str("[::1]:20246:[::1]:30389").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
@@ -278,14 +510,13 @@ async fn test_it_works_v6() {
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
     tokio::time::sleep(Duration::from_secs(2)).await;
     let mut sock = TcpStream::connect("[::1]:20246").await.unwrap();
     sock.write_all(&input_bytes).await.unwrap();
@@ -303,34 +534,64 @@ async fn test_it_works_tls_simple() {
     static SERVER_ARGS: OnceLock<arg::ServerArgs> = OnceLock::new();
     static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| arg::ClientArgs {
         server: ServerUrl::from_str("wss://127.0.0.1:20353/ws").unwrap(),
-        remote: vec![Remote::from_str("127.0.0.1:24368:127.0.0.1:12034").unwrap()],
-        ws_psk: None,
-        keepalive: OptionalDuration::NONE,
+        remote: vec![
+            Remote::from_str("127.0.0.1:24368:127.0.0.1:12034")
+                .unwrap()
+                .into(),
+        ],
+        #[cfg(feature = "e2e")]
+        e2e_key: Some(arg::E2eKey("e2e-secret".to_string())),
         max_retry_count: 10,
         max_retry_interval: 10,
-        handshake_timeout: OptionalDuration::NONE,
-        proxy: None,
-        header: vec![],
-        tls_ca: None,
-        tls_cert: None,
-        tls_key: None,
+        connections: 2,
+        compression: penguin_mux::Compression::Deflate,
+        compression_threshold: 256,
+        coalesce: true,
+        limits: arg::LimitArgs {
+            stream_limit_up: Bandwidth::from_bytes_per_sec(2_000_000),
+            ..Default::default()
+        },
+        mux: arg::MuxArgs {
+            rwnd: Some(64),
+            ..Default::default()
+        },
+        ws_deflate: arg::WsDeflateArgs {
+            enabled: true,
+            no_context_takeover: true,
+        },
         tls_skip_verify: true,
+        tls: arg::TlsArgs {
+            min_version: Some(arg::TlsVersion::Tls12),
+            max_version: Some(arg::TlsVersion::Tls13),
+            ..Default::default()
+        },
         hostname: Some(http::HeaderValue::from_static("localhost")),
         channel_timeout: OptionalDuration::from_secs(10),
-        _pid: false,
-        _fingerprint: None,
-        _auth: None,
+        ..Default::default()
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let mut serv_cfg = make_server_args("127.0.0.1", 20353);
     let cert_dir = make_server_cert_ecdsa().await;
     serv_cfg.tls_cert = Some(format!("{}/cert.pem", cert_dir.path().display()));
     serv_cfg.tls_key = Some(format!("{}/privkey.pem", cert_dir.path().display()));
+    serv_cfg.compression = penguin_mux::Compression::SUPPORTED.to_vec();
+    serv_cfg.limits.limit_down = Bandwidth::from_bytes_per_sec(4_000_000);
+    #[cfg(feature = "e2e")]
+    {
+        serv_cfg.e2e_key = Some(arg::E2eKey("e2e-secret".to_string()));
+    }
+    serv_cfg.ws_deflate.enabled = true;
+    #[cfg(feature = "__rustls")]
+    {
+        serv_cfg.tls.cipher_suites = vec!["TLS13_AES_256_GCM_SHA384".to_string()];
+        serv_cfg.tls.kx_groups = vec!["secp384r1".to_string(), "X25519".to_string()];
+    }
     SERVER_ARGS.set(serv_cfg).unwrap();
 
-    let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
+    // Half random and half compressible
+    let mut input_bytes: Vec<u8> = (0..(512 * 1024)).map(|_| rand::random::<u8>()).collect();
+    input_bytes.extend(b"penguin ".repeat(64 * 1024));
     let input_len = input_bytes.len();
     let second_task = tokio::spawn(async move {
         let listener = TcpListener::bind("127.0.0.1:12034").await.unwrap();
@@ -342,14 +603,15 @@ async fn test_it_works_tls_simple() {
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(SERVER_ARGS.get().unwrap()));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(
+        SERVER_ARGS.get().unwrap().clone(),
+    )));
     tokio::time::sleep(Duration::from_secs(2)).await;
     let mut sock = TcpStream::connect("127.0.0.1:24368").await.unwrap();
     sock.write_all(&input_bytes).await.unwrap();
@@ -360,6 +622,67 @@ async fn test_it_works_tls_simple() {
     client_task.abort();
 }
 
+#[cfg(feature = "quic")]
+#[tokio::test]
+async fn test_it_works_quic() {
+    static SERVER_ARGS: OnceLock<arg::ServerArgs> = OnceLock::new();
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| arg::ClientArgs {
+        server: ServerUrl::from_str("wss+quic://127.0.0.1:20363/ws").unwrap(),
+        tls_skip_verify: true,
+        ..make_client_args(
+            "127.0.0.1",
+            20363,
+            vec![
+                Remote::from_str("127.0.0.1:24378:127.0.0.1:12044").unwrap(),
+                Remote::from_str("127.0.0.1:24379:127.0.0.1:12045").unwrap(),
+            ],
+        )
+    });
+    setup_logging();
+
+    let mut serv_cfg = make_server_args("127.0.0.1", 20363);
+    let cert_dir = make_server_cert_ecdsa().await;
+    serv_cfg.tls_cert = Some(format!("{}/cert.pem", cert_dir.path().display()));
+    serv_cfg.tls_key = Some(format!("{}/privkey.pem", cert_dir.path().display()));
+    serv_cfg.quic = true;
+    SERVER_ARGS.set(serv_cfg).unwrap();
+
+    let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let input_len = input_bytes.len();
+    let receive = |port: u16| async move {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut output_bytes = vec![0u8; input_len];
+        stream.read_exact(&mut output_bytes).await.unwrap();
+        output_bytes
+    };
+    let first_task = tokio::spawn(receive(12044));
+    let second_task = tokio::spawn(receive(12045));
+
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(
+        SERVER_ARGS.get().unwrap().clone(),
+    )));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    // Two flows, which go on different lanes
+    for port in [24378, 24379] {
+        let mut sock = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        sock.write_all(&input_bytes).await.unwrap();
+        sock.shutdown().await.unwrap();
+    }
+    assert_eq!(input_bytes, first_task.await.unwrap());
+    assert_eq!(input_bytes, second_task.await.unwrap());
+    server_task.abort();
+    client_task.abort();
+}
+
 #[tokio::test]
 async fn test_socks5_connect_reliability_v4() {
     static SERVER_ARGS: LazyLock<arg::ServerArgs> =
@@ -371,19 +694,17 @@ async fn test_socks5_connect_reliability_v4() {
             vec![Remote::from_str("127.0.0.1:21330:socks").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
 
     // Use a small buffer to simulate a HTTP request: if `flush` or `shutdown` is not called, the
     // server will not receive the data.
@@ -438,19 +759,17 @@ async fn test_socks5_connect_reliability_v6() {
             vec![Remote::from_str("127.0.0.1:13261:socks").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
 
     // Use a small buffer to simulate a HTTP request: if `flush` or `shutdown` is not called, the
     // server will not receive the data.
@@ -504,19 +823,17 @@ async fn test_socks5_udp_v4v4() {
             vec![Remote::from_str("127.0.0.1:30711:socks").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
 
     let input_bytes: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
     let input_len = input_bytes.len();
@@ -601,19 +918,17 @@ async fn test_socks5_udp_v4v6() {
             vec![Remote::from_str("127.0.0.1:26396:socks").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
 
     let input_bytes: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
     let input_len = input_bytes.len();
@@ -702,19 +1017,17 @@ async fn test_socks5_udp_v6v6() {
             vec![Remote::from_str("[::1]:12654:socks").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
 
     let input_bytes: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
     let input_len = input_bytes.len();
@@ -800,19 +1113,17 @@ async fn test_socks4_works() {
             vec![Remote::from_str("127.0.0.1:23213:socks").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
 
     let input_bytes: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
     let input_len = input_bytes.len();
@@ -847,6 +1158,77 @@ async fn test_socks4_works() {
     client_task.abort();
 }
 
+#[tokio::test]
+async fn test_http_proxy_works() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> =
+        LazyLock::new(|| make_server_args("127.0.0.1", 10797));
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            10797,
+            vec![Remote::from_str("127.0.0.1:23214:socks").unwrap()],
+        )
+    });
+    setup_logging();
+
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+
+    let target_server_task = tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:20592").await.unwrap();
+        // Absolute-form request
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let expected = b"GET /hello HTTP/1.1\r\nHost: 127.0.0.1:20592\r\nConnection: close\r\n\r\n";
+        let mut request = vec![0u8; expected.len()];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request, expected);
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        drop(stream);
+        // CONNECT request
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut sock = TcpStream::connect("127.0.0.1:23214").await.unwrap();
+    sock.write_all(b"GET http://127.0.0.1:20592/hello HTTP/1.1\r\nHost: 127.0.0.1:20592\r\nProxy-Connection: keep-alive\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    sock.read_to_end(&mut response).await.unwrap();
+    assert_eq!(response, b"HTTP/1.1 204 No Content\r\n\r\n");
+
+    let mut sock = TcpStream::connect("127.0.0.1:23214").await.unwrap();
+    sock.write_all(b"CONNECT 127.0.0.1:20592 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let expected = b"HTTP/1.1 200 Connection Established\r\nContent-Length: 0\r\n\r\n";
+    let mut response = vec![0u8; expected.len()];
+    sock.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, expected);
+    sock.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    sock.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    target_server_task.await.unwrap();
+    server_task.abort();
+    client_task.abort();
+}
+
 #[cfg(all(feature = "tests-real-internet4", feature = "tests-udp"))]
 #[tokio::test]
 async fn test_it_works_dns_v4() {
@@ -859,19 +1241,17 @@ async fn test_it_works_dns_v4() {
             vec![Remote::from_str("127.0.0.1:20326:1.1.1.1:53/udp").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
     tokio::time::sleep(Duration::from_secs(2)).await;
     let sock = UdpSocket::bind("0.0.0.0:0").await.unwrap();
     // Just for fun, let's query AAAA here
@@ -900,19 +1280,17 @@ async fn test_it_works_dns_v6() {
             vec![Remote::from_str("[::1]:20326:[2606:4700:4700::1111]:53/udp").unwrap()],
         )
     });
-    static HANDLER_RESOURCES: OnceLock<crate::client::HandlerResources> = OnceLock::new();
     setup_logging();
 
     let (handler_resources, stream_command_rx, datagram_rx) =
         crate::client::HandlerResources::create();
-    HANDLER_RESOURCES.set(handler_resources).unwrap();
     let client_task = tokio::spawn(crate::client::client_main_inner(
         &CLIENT_ARGS,
-        HANDLER_RESOURCES.get().unwrap(),
+        Arc::new(handler_resources),
         stream_command_rx,
         datagram_rx,
     ));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
     tokio::time::sleep(Duration::from_secs(2)).await;
     let sock = UdpSocket::bind("[::]:0").await.unwrap();
     let request = b"\x39\x36\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03www\x06google\x03com\x00\x00\x01\x00\x01";
@@ -927,3 +1305,121 @@ async fn test_it_works_dns_v6() {
     server_task.abort();
     client_task.abort();
 }
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_works() {
+    static DIR: LazyLock<tempfile::TempDir> = LazyLock::new(|| tempfile::tempdir().unwrap());
+    static TARGET: LazyLock<String> =
+        LazyLock::new(|| DIR.path().join("target.sock").to_str().unwrap().to_string());
+    static LISTEN: LazyLock<String> =
+        LazyLock::new(|| DIR.path().join("listen.sock").to_str().unwrap().to_string());
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> = LazyLock::new(|| arg::ServerArgs {
+        allow_unix_socket: vec![TARGET.clone()],
+        ..make_server_args("127.0.0.1", 28711)
+    });
+    static CLIENT_ARGS: LazyLock<arg::ClientArgs> = LazyLock::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            28711,
+            vec![Remote::from_str(&format!("{}:{}", *LISTEN, *TARGET)).unwrap()],
+        )
+    });
+    setup_logging();
+
+    let target = tokio::net::UnixListener::bind(&*TARGET).unwrap();
+    let second_task = tokio::spawn(async move {
+        let (mut stream, _) = target.accept().await.unwrap();
+        let mut output_bytes = Vec::new();
+        stream.read_to_end(&mut output_bytes).await.unwrap();
+        output_bytes
+    });
+    let (handler_resources, stream_command_rx, datagram_rx) =
+        crate::client::HandlerResources::create();
+    let client_task = tokio::spawn(crate::client::client_main_inner(
+        &CLIENT_ARGS,
+        Arc::new(handler_resources),
+        stream_command_rx,
+        datagram_rx,
+    ));
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let mut sock = tokio::net::UnixStream::connect(&*LISTEN).await.unwrap();
+    sock.write_all(b"GET /_ping HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    sock.shutdown().await.unwrap();
+    let output_bytes = second_task.await.unwrap();
+    assert_eq!(output_bytes, b"GET /_ping HTTP/1.0\r\n\r\n");
+    server_task.abort();
+    client_task.abort();
+}
+
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn test_bench() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> = LazyLock::new(|| arg::ServerArgs {
+        bench: true,
+        ..make_server_args("127.0.0.1", 27318)
+    });
+    static PLAIN_SERVER_ARGS: LazyLock<arg::ServerArgs> =
+        LazyLock::new(|| make_server_args("127.0.0.1", 27319));
+    setup_logging();
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    let plain_server_task = tokio::spawn(crate::server::server_main(Arc::new(
+        PLAIN_SERVER_ARGS.clone(),
+    )));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let args = arg::BenchArgs {
+        client: make_client_args("127.0.0.1", 27318, Vec::new()),
+        size: 2,
+        datagrams: 50,
+        datagram_size: 64,
+    };
+    let report = crate::client::bench::bench(&args).await.unwrap();
+    assert_eq!(report.len, 2 << 20);
+    assert_eq!(report.datagrams_sent, 50);
+    assert_eq!(report.datagrams_echoed, 50);
+    // Servers without --bench forward to the host "penguin-bench", which
+    // does not exist
+    let args = arg::BenchArgs {
+        client: make_client_args("127.0.0.1", 27319, Vec::new()),
+        ..args
+    };
+    let err = crate::client::bench::bench(&args).await.unwrap_err();
+    assert!(
+        matches!(err, crate::client::Error::Bench(_)),
+        "Expected Bench, got {err:?}"
+    );
+    server_task.abort();
+    plain_server_task.abort();
+}
+
+#[cfg(feature = "client")]
+#[tokio::test]
+async fn test_ping() {
+    static SERVER_ARGS: LazyLock<arg::ServerArgs> =
+        LazyLock::new(|| make_server_args("127.0.0.1", 27320));
+    setup_logging();
+    let server_task = tokio::spawn(crate::server::server_main(Arc::new(SERVER_ARGS.clone())));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let args = arg::PingArgs {
+        client: make_client_args("127.0.0.1", 27320, Vec::new()),
+        count: 3,
+        interval: 10,
+        timeout: 2000,
+    };
+    let mut seqs = Vec::new();
+    let report = crate::client::ping::ping(&args, |seq, rtt| {
+        assert!(rtt.is_some(), "Probe {seq} timed out");
+        seqs.push(seq);
+    })
+    .await
+    .unwrap();
+    assert_eq!(seqs, [1, 2, 3]);
+    assert_eq!(report.sent, 3);
+    assert_eq!(report.rtts.len(), 3);
+    assert!(report.min() <= report.avg() && report.avg() <= report.max());
+    assert!(report.jitter().is_some());
+    server_task.abort();
+}
@@ -4,6 +4,8 @@
 
 #[cfg(feature = "nativetls")]
 mod native;
+#[cfg(feature = "encrypted-keys")]
+mod passphrase;
 #[cfg(feature = "__rustls")]
 mod rustls;
 
@@ -11,7 +13,11 @@ mod rustls;
 use self::native::make_server_config_from_rcgen_pem;
 #[cfg(all(feature = "__rustls", feature = "acme"))]
 use self::rustls::make_server_config_from_rcgen_pem;
+use crate::arg::TlsArgs;
 use arc_swap::ArcSwap;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use std::str::FromStr;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio_tungstenite::Connector;
@@ -25,6 +31,8 @@ pub use self::rustls::{TlsIdentityInner, make_client_config, make_server_config}
 pub use native::{HyperConnector, make_hyper_connector};
 #[cfg(feature = "nativetls")]
 pub use native::{TlsIdentityInner, make_client_config, make_server_config};
+#[cfg(feature = "encrypted-keys")]
+pub use passphrase::Passphrase;
 
 /// A hot-swappable container for a TLS key and certificate.
 #[allow(clippy::module_name_repetitions)]
@@ -52,6 +60,170 @@ pub enum Error {
     #[error("Unsupported private key type")]
     #[cfg(feature = "__rustls")]
     PrivateKeyNotSupported,
+    #[error("The minimum TLS version is newer than the maximum")]
+    InvalidVersionRange,
+    #[error("Unknown or unsupported cipher suite: {0}")]
+    #[cfg(feature = "__rustls")]
+    UnknownCipherSuite(String),
+    #[error("Unknown or unsupported key exchange group: {0}")]
+    #[cfg(feature = "__rustls")]
+    UnknownKxGroup(String),
+    #[error("Post-quantum key exchange needs the `aws-lc-rs` feature")]
+    #[cfg(feature = "__rustls")]
+    PostQuantumNotSupported,
+    #[error("{0} is not supported with native-tls")]
+    #[cfg(feature = "nativetls")]
+    NotSupported(&'static str),
+    #[error("Cannot decrypt private key: {0}")]
+    #[cfg(feature = "encrypted-keys")]
+    DecryptKey(#[from] openssl::error::ErrorStack),
+    #[error("Cannot read the key passphrase: `{0}` is not set")]
+    #[cfg(feature = "encrypted-keys")]
+    PassphraseEnv(String),
+    #[error("A private key is needed unless the certificate is a PKCS#12 bundle")]
+    MissingKey,
+    #[error("PKCS#12 bundles need the `encrypted-keys` feature")]
+    #[cfg(not(feature = "encrypted-keys"))]
+    Pkcs12NotSupported,
+    #[error("The passphrase of a PKCS#12 bundle cannot be asked on the terminal")]
+    #[cfg(feature = "encrypted-keys")]
+    Pkcs12Prompt,
+    #[error("The PKCS#12 bundle has no certificate or no private key")]
+    #[cfg(feature = "encrypted-keys")]
+    Pkcs12Incomplete,
+}
+
+/// Error returned when parsing an invalid SPKI pin
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Invalid pin `{0}`: expected `sha256/BASE64`")]
+pub struct InvalidSpkiPin(String);
+
+/// The SHA-256 hash of the `SubjectPublicKeyInfo` of a certificate, written
+/// as `sha256/BASE64` like in HPKP
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpkiPin(pub [u8; 32]);
+
+impl FromStr for SpkiPin {
+    type Err = InvalidSpkiPin;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hash = s
+            .strip_prefix("sha256/")
+            .and_then(|encoded| B64_STANDARD_ENGINE.decode(encoded).ok())
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or_else(|| InvalidSpkiPin(s.to_string()))?;
+        Ok(Self(hash))
+    }
+}
+
+impl std::fmt::Display for SpkiPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sha256/{}", B64_STANDARD_ENGINE.encode(self.0))
+    }
+}
+
+/// Error returned when parsing an invalid SNI certificate
+#[derive(Clone, Debug, Error, PartialEq, Eq)]
+#[error("Invalid SNI certificate `{0}`: expected `HOSTNAME,CERT,KEY` or `HOSTNAME,BUNDLE.p12`")]
+pub struct InvalidSniCert(String);
+
+/// A certificate served to clients asking for a hostname, in the form
+/// `HOSTNAME,CERT,KEY` or `HOSTNAME,BUNDLE` for a PKCS#12 bundle.
+/// `HOSTNAME` may be a wildcard such as `*.example.com`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SniCert {
+    /// Lowercase, without the trailing dot
+    pub hostname: String,
+    pub cert_path: String,
+    /// `None` if `cert_path` is a PKCS#12 bundle
+    pub key_path: Option<String>,
+}
+
+impl FromStr for SniCert {
+    type Err = InvalidSniCert;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidSniCert(s.to_string());
+        let mut parts = s.splitn(3, ',');
+        let (Some(hostname), Some(cert_path), key_path) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+        let name = hostname.strip_prefix("*.").unwrap_or(hostname);
+        let key_valid = match key_path {
+            Some(key_path) => !key_path.is_empty(),
+            None => is_pkcs12(cert_path),
+        };
+        if name.is_empty() || name.contains('*') || cert_path.is_empty() || !key_valid {
+            return Err(invalid());
+        }
+        Ok(Self {
+            hostname: hostname.to_ascii_lowercase(),
+            cert_path: cert_path.to_string(),
+            key_path: key_path.map(str::to_string),
+        })
+    }
+}
+
+/// Where the server finds the CAs of client certificates
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientCa<'a> {
+    /// PEM-encoded CA certificates. Clients are not asked for certificates
+    /// if this is `None`.
+    pub ca_path: Option<&'a str>,
+    /// Certificate revocation lists from those CAs
+    pub crl_path: Option<&'a str>,
+}
+
+/// Read a PEM-encoded private key, decrypting it if `options` has a passphrase
+async fn read_private_key(path: &str, options: &TlsArgs) -> Result<Vec<u8>, Error> {
+    let key = tokio::fs::read(path).await?;
+    #[cfg(feature = "encrypted-keys")]
+    if let Some(passphrase) = options.key_pass.clone() {
+        // Asking for the passphrase blocks
+        return tokio::task::spawn_blocking(move || passphrase.decrypt(&key))
+            .await
+            .expect("Decrypting the key panicked (this is a bug)");
+    }
+    #[cfg(not(feature = "encrypted-keys"))]
+    let _ = options;
+    Ok(key)
+}
+
+/// Whether `path` names a PKCS#12 bundle rather than a PEM certificate chain
+fn is_pkcs12(path: &str) -> bool {
+    std::path::Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx"))
+}
+
+/// Read a PEM certificate chain and a PEM private key from `cert_path` and
+/// `key_path`, or both from a PKCS#12 bundle at `cert_path`
+async fn read_cert_and_key(
+    cert_path: &str,
+    key_path: Option<&str>,
+    options: &TlsArgs,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    if is_pkcs12(cert_path) {
+        #[cfg(feature = "encrypted-keys")]
+        {
+            let bundle = tokio::fs::read(cert_path).await?;
+            let passphrase = options.key_pass.clone();
+            return tokio::task::spawn_blocking(move || {
+                passphrase::read_pkcs12(&bundle, passphrase.as_ref())
+            })
+            .await
+            .expect("Reading the bundle panicked (this is a bug)");
+        }
+        #[cfg(not(feature = "encrypted-keys"))]
+        return Err(Error::Pkcs12NotSupported);
+    }
+    let key_path = key_path.ok_or(Error::MissingKey)?;
+    let certs = tokio::fs::read(cert_path).await?;
+    let key = read_private_key(key_path, options).await?;
+    Ok((certs, key))
 }
 
 /// Make a `Connector`.
@@ -60,9 +232,19 @@ pub async fn make_tls_connector(
     tls_key: Option<&str>,
     tls_ca: Option<&str>,
     tls_insecure: bool,
+    options: &TlsArgs,
+    pins: &[SpkiPin],
 ) -> Result<Connector, Error> {
-    let tls_config =
-        make_client_config(tls_cert, tls_key, tls_ca, tls_insecure, Some(&TLS_ALPN)).await?;
+    let tls_config = make_client_config(
+        tls_cert,
+        tls_key,
+        tls_ca,
+        tls_insecure,
+        Some(&TLS_ALPN),
+        options,
+        pins,
+    )
+    .await?;
     #[cfg(feature = "__rustls")]
     let result = Ok(Connector::Rustls(tls_config.into()));
     #[cfg(feature = "nativetls")]
@@ -72,10 +254,14 @@ pub async fn make_tls_connector(
 
 pub async fn make_tls_identity(
     cert_path: &str,
-    key_path: &str,
-    client_ca_path: Option<&str>,
+    key_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    sni_certs: &[SniCert],
+    ocsp: Option<Vec<u8>>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentity, Error> {
-    let identity = make_server_config(cert_path, key_path, client_ca_path).await?;
+    let identity =
+        make_server_config(cert_path, key_path, client_ca, sni_certs, ocsp, options).await?;
     Ok(Arc::new(ArcSwap::from_pointee(identity)))
 }
 
@@ -83,20 +269,23 @@ pub async fn make_tls_identity(
 pub async fn make_tls_identity_from_rcgen_pem(
     certs: String,
     keypair: rcgen::KeyPair,
-    client_ca_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentity, Error> {
-    let identity = make_server_config_from_rcgen_pem(certs, keypair, client_ca_path).await?;
+    let identity = make_server_config_from_rcgen_pem(certs, keypair, client_ca, options).await?;
     Ok(Arc::new(ArcSwap::from_pointee(identity)))
 }
 
-#[cfg(unix)]
 pub async fn reload_tls_identity(
     identity: &TlsIdentity,
     cert_path: &str,
-    key_path: &str,
-    client_ca_path: Option<&str>,
+    key_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    sni_certs: &[SniCert],
+    ocsp: Option<Vec<u8>>,
+    options: &TlsArgs,
 ) -> Result<(), Error> {
-    let new = make_server_config(cert_path, key_path, client_ca_path).await?;
+    let new = make_server_config(cert_path, key_path, client_ca, sni_certs, ocsp, options).await?;
     identity.store(Arc::new(new));
     Ok(())
 }
@@ -106,9 +295,10 @@ pub async fn reload_tls_identity_from_rcgen_pem(
     identity: &TlsIdentity,
     certs: String,
     keypair: rcgen::KeyPair,
-    client_ca_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    options: &TlsArgs,
 ) -> Result<(), Error> {
-    let new = make_server_config_from_rcgen_pem(certs, keypair, client_ca_path).await?;
+    let new = make_server_config_from_rcgen_pem(certs, keypair, client_ca, options).await?;
     identity.store(Arc::new(new));
     Ok(())
 }
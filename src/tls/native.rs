@@ -2,8 +2,9 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use super::Error;
-use tokio_native_tls::native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector};
+use super::{ClientCa, Error, SniCert, SpkiPin};
+use crate::arg::{TlsArgs, TlsVersion};
+use tokio_native_tls::native_tls::{Certificate, Identity, Protocol, TlsAcceptor, TlsConnector};
 
 /// Type alias for the inner TLS identity type.
 pub type TlsIdentityInner = tokio_native_tls::TlsAcceptor;
@@ -15,11 +16,20 @@ pub type HyperConnector =
 
 pub async fn make_server_config(
     cert_path: &str,
-    key_path: &str,
-    client_ca_path: Option<&str>,
+    key_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    sni_certs: &[SniCert],
+    ocsp: Option<Vec<u8>>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentityInner, Error> {
-    let identity = read_key_cert(key_path, cert_path).await?;
-    make_server_config_from_mem(identity, client_ca_path)
+    if !sni_certs.is_empty() {
+        return Err(Error::NotSupported("Selecting certificates by SNI"));
+    }
+    if ocsp.is_some() {
+        return Err(Error::NotSupported("OCSP stapling"));
+    }
+    let identity = read_key_cert(key_path, cert_path, options).await?;
+    make_server_config_from_mem(identity, client_ca, options)
 }
 
 #[cfg(feature = "acme")]
@@ -27,30 +37,79 @@ pub async fn make_server_config(
 pub async fn make_server_config_from_rcgen_pem(
     certs: String,
     keypair: rcgen::KeyPair,
-    client_ca_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentityInner, Error> {
     let identity = Identity::from_pkcs8(certs.as_bytes(), keypair.serialize_pem().as_bytes())?;
-    make_server_config_from_mem(identity, client_ca_path)
+    make_server_config_from_mem(identity, client_ca, options)
 }
 
 fn make_server_config_from_mem(
     identity: Identity,
-    _client_ca_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentityInner, Error> {
     // TODO: support client CA (sfackler/rust-native-tls#161)
-    let raw_acceptor = TlsAcceptor::builder(identity).build()?;
+    if client_ca.ca_path.is_some() {
+        tracing::warn!("Client certificates are not verified with native-tls");
+    }
+    if client_ca.crl_path.is_some() {
+        return Err(Error::NotSupported(
+            "Checking client certificates against CRLs",
+        ));
+    }
+    let (min, max) = protocol_versions(options)?;
+    let raw_acceptor = TlsAcceptor::builder(identity)
+        .min_protocol_version(min)
+        .max_protocol_version(max)
+        .build()?;
     Ok(raw_acceptor.into())
 }
 
+/// The minimum and maximum protocol versions in `options`
+fn protocol_versions(options: &TlsArgs) -> Result<(Option<Protocol>, Option<Protocol>), Error> {
+    if !options.cipher_suites.is_empty() {
+        return Err(Error::NotSupported("Selecting cipher suites"));
+    }
+    if !options.kx_groups.is_empty() {
+        return Err(Error::NotSupported("Selecting key exchange groups"));
+    }
+    if options.post_quantum {
+        return Err(Error::NotSupported("Post-quantum key exchange"));
+    }
+    if options.min_version > options.max_version && options.max_version.is_some() {
+        return Err(Error::InvalidVersionRange);
+    }
+    let min = match options.min_version {
+        // The default of `native-tls`
+        None => Some(Protocol::Tlsv10),
+        Some(TlsVersion::Tls12) => Some(Protocol::Tlsv12),
+        Some(TlsVersion::Tls13) => return Err(Error::NotSupported("Requiring TLS 1.3")),
+    };
+    let max = match options.max_version {
+        Some(TlsVersion::Tls12) => Some(Protocol::Tlsv12),
+        None | Some(TlsVersion::Tls13) => None,
+    };
+    Ok((min, max))
+}
+
 pub async fn make_client_config(
     cert_path: Option<&str>,
     key_path: Option<&str>,
     ca_path: Option<&str>,
     tls_skip_verify: bool,
     tls_alpn: Option<&[&str]>,
+    options: &TlsArgs,
+    pins: &[SpkiPin],
 ) -> Result<TlsConnector, Error> {
+    if !pins.is_empty() {
+        return Err(Error::NotSupported("Pinning certificates"));
+    }
+    let (min, max) = protocol_versions(options)?;
     let mut tls_config_builder = TlsConnector::builder();
     tls_config_builder
+        .min_protocol_version(min)
+        .max_protocol_version(max)
         .danger_accept_invalid_certs(tls_skip_verify)
         .danger_accept_invalid_hostnames(tls_skip_verify);
     if let Some(tls_alpn) = tls_alpn {
@@ -61,15 +120,19 @@ pub async fn make_client_config(
         tls_config_builder.add_root_certificate(Certificate::from_pem(&ca)?);
     }
     if let Some(cert_path) = cert_path {
-        let identity = read_key_cert(key_path.unwrap_or(cert_path), cert_path).await?;
+        let identity =
+            read_key_cert(Some(key_path.unwrap_or(cert_path)), cert_path, options).await?;
         tls_config_builder.identity(identity);
     }
     Ok(tls_config_builder.build()?)
 }
 
-async fn read_key_cert(key_path: &str, cert_path: &str) -> Result<Identity, Error> {
-    let key = tokio::fs::read(key_path).await?;
-    let cert = tokio::fs::read(cert_path).await?;
+async fn read_key_cert(
+    key_path: Option<&str>,
+    cert_path: &str,
+    options: &TlsArgs,
+) -> Result<Identity, Error> {
+    let (cert, key) = super::read_cert_and_key(cert_path, key_path, options).await?;
     Ok(Identity::from_pkcs8(&cert, &key)?)
 }
 
@@ -99,9 +162,13 @@ mod tests {
         let crt_key = keypair.serialize_pem();
         tokio::fs::write(&cert_path, crt).await.unwrap();
         tokio::fs::write(&key_path, crt_key).await.unwrap();
-        read_key_cert(key_path.to_str().unwrap(), cert_path.to_str().unwrap())
-            .await
-            .unwrap();
+        read_key_cert(
+            key_path.to_str(),
+            cert_path.to_str().unwrap(),
+            &TlsArgs::default(),
+        )
+        .await
+        .unwrap();
     }
     #[tokio::test]
     #[cfg(feature = "acme")]
@@ -112,7 +179,13 @@ mod tests {
         let custom_crt = cert_params.self_signed(&keypair).unwrap();
         let crt = custom_crt.pem();
 
-        let result = make_server_config_from_rcgen_pem(crt, keypair, None).await;
+        let result = make_server_config_from_rcgen_pem(
+            crt,
+            keypair,
+            ClientCa::default(),
+            &TlsArgs::default(),
+        )
+        .await;
 
         assert!(result.is_ok());
     }
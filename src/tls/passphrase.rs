@@ -0,0 +1,193 @@
+//! Decrypting passphrase-protected private keys and PKCS#12 bundles with
+//! OpenSSL.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use std::str::FromStr;
+
+/// Error returned when parsing an invalid passphrase source
+#[derive(Clone, Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Invalid passphrase source: expected `pass:PASSWORD`, `env:VAR`, `file:PATH` or `prompt`")]
+pub struct InvalidPassphrase;
+
+/// Where the passphrase of a private key or a PKCS#12 bundle comes from,
+/// written like the `-passin` argument of `openssl`
+#[derive(Clone, PartialEq, Eq)]
+pub enum Passphrase {
+    Pass(String),
+    /// The name of an environment variable
+    Env(String),
+    /// The first line of a file
+    File(String),
+    /// Ask on the terminal
+    Prompt,
+}
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            // Skip the password
+            Self::Pass(_) => f.write_str("Pass"),
+            Self::Env(var) => f.debug_tuple("Env").field(var).finish(),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Prompt => f.write_str("Prompt"),
+        }
+    }
+}
+
+impl FromStr for Passphrase {
+    type Err = InvalidPassphrase;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "prompt" {
+            return Ok(Self::Prompt);
+        }
+        match s.split_once(':') {
+            Some(("pass", pass)) => Ok(Self::Pass(pass.to_string())),
+            Some(("env", var)) if !var.is_empty() => Ok(Self::Env(var.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(Self::File(path.to_string())),
+            _ => Err(InvalidPassphrase),
+        }
+    }
+}
+
+impl Passphrase {
+    fn read(&self) -> Result<Option<String>, Error> {
+        Ok(match self {
+            Self::Pass(pass) => Some(pass.clone()),
+            Self::Env(var) => {
+                Some(std::env::var(var).map_err(|_| Error::PassphraseEnv(var.clone()))?)
+            }
+            Self::File(path) => {
+                let content = std::fs::read_to_string(path)?;
+                Some(content.lines().next().unwrap_or_default().to_string())
+            }
+            Self::Prompt => None,
+        })
+    }
+
+    /// Decrypt a PEM-encoded private key into an unencrypted PKCS#8 PEM key.
+    /// Keys that are not encrypted are only converted.
+    /// This may block to ask for the passphrase.
+    pub fn decrypt(&self, key: &[u8]) -> Result<Vec<u8>, Error> {
+        let key = match self.read()? {
+            Some(pass) => PKey::private_key_from_pem_passphrase(key, pass.as_bytes())?,
+            // OpenSSL asks on the terminal without a passphrase
+            None => PKey::private_key_from_pem(key)?,
+        };
+        Ok(key.private_key_to_pem_pkcs8()?)
+    }
+}
+
+/// Read the certificate chain and the private key in a PKCS#12 bundle as
+/// PEM, with an empty password if there is no passphrase
+pub fn read_pkcs12(
+    bundle: &[u8],
+    passphrase: Option<&Passphrase>,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let pass = match passphrase {
+        Some(passphrase) => passphrase.read()?.ok_or(Error::Pkcs12Prompt)?,
+        None => String::new(),
+    };
+    let parsed = Pkcs12::from_der(bundle)?.parse2(&pass)?;
+    let (Some(key), Some(cert)) = (parsed.pkey, parsed.cert) else {
+        return Err(Error::Pkcs12Incomplete);
+    };
+    let mut certs = cert.to_pem()?;
+    for ca in parsed.ca.iter().flatten() {
+        certs.extend(ca.to_pem()?);
+    }
+    Ok((certs, key.private_key_to_pem_pkcs8()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::symm::Cipher;
+
+    #[test]
+    fn test_decrypt_key() {
+        crate::tests::setup_logging();
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let encrypted = key
+            .private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"hunter2")
+            .unwrap();
+        let expected = key.private_key_to_pem_pkcs8().unwrap();
+        let pass = Passphrase::Pass("hunter2".to_string());
+        assert_eq!(pass.decrypt(&encrypted).unwrap(), expected);
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("pass.txt");
+        std::fs::write(&path, "hunter2\nignored\n").unwrap();
+        let file = Passphrase::File(path.to_str().unwrap().to_string());
+        assert_eq!(file.decrypt(&encrypted).unwrap(), expected);
+        assert!(
+            Passphrase::Pass("wrong".to_string())
+                .decrypt(&encrypted)
+                .is_err()
+        );
+        // Unencrypted keys are accepted too
+        assert_eq!(pass.decrypt(&expected).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_read_pkcs12() {
+        crate::tests::setup_logging();
+        let generated = rcgen::generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+        let cert = openssl::x509::X509::from_pem(generated.cert.pem().as_bytes()).unwrap();
+        let key =
+            PKey::private_key_from_pem(generated.key_pair.serialize_pem().as_bytes()).unwrap();
+        let bundle = Pkcs12::builder()
+            .cert(&cert)
+            .pkey(&key)
+            .build2("hunter2")
+            .unwrap()
+            .to_der()
+            .unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("id.p12");
+        std::fs::write(&path, &bundle).unwrap();
+        let options = crate::arg::TlsArgs {
+            key_pass: Some(Passphrase::Pass("hunter2".to_string())),
+            ..Default::default()
+        };
+        let (certs, key_pem) =
+            crate::tls::read_cert_and_key(path.to_str().unwrap(), None, &options)
+                .await
+                .unwrap();
+        assert_eq!(certs, cert.to_pem().unwrap());
+        assert_eq!(key_pem, key.private_key_to_pem_pkcs8().unwrap());
+        assert!(read_pkcs12(&bundle, None).is_err());
+        assert!(matches!(
+            read_pkcs12(&bundle, Some(&Passphrase::Prompt)),
+            Err(Error::Pkcs12Prompt)
+        ));
+    }
+
+    #[test]
+    fn test_parse_passphrase() {
+        crate::tests::setup_logging();
+        assert_eq!("pass:a:b".parse(), Ok(Passphrase::Pass("a:b".to_string())));
+        assert_eq!(
+            "env:KEY_PASS".parse(),
+            Ok(Passphrase::Env("KEY_PASS".to_string()))
+        );
+        assert_eq!(
+            "file:/run/pass".parse(),
+            Ok(Passphrase::File("/run/pass".to_string()))
+        );
+        assert_eq!("prompt".parse(), Ok(Passphrase::Prompt));
+        for invalid in ["hunter2", "env:", "file:", "stdin"] {
+            assert!(invalid.parse::<Passphrase>().is_err(), "{invalid}");
+        }
+        assert_eq!(
+            format!("{:?}", Passphrase::Pass("secret".to_string())),
+            "Pass"
+        );
+    }
+}
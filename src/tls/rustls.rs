@@ -2,17 +2,26 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use super::Error;
+use super::{ClientCa, Error, SniCert, SpkiPin};
+use crate::arg::{TlsArgs, TlsVersion};
 use rustls::{
-    ClientConfig, RootCertStore, ServerConfig,
+    CertificateError, ClientConfig, NamedGroup, RootCertStore, ServerConfig, SupportedCipherSuite,
+    SupportedProtocolVersion,
+    client::WebPkiServerVerifier,
     client::danger::{ServerCertVerified, ServerCertVerifier},
     crypto::CryptoProvider,
-    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
-    server::WebPkiClientVerifier,
+    crypto::hash::{Hash, HashAlgorithm},
+    pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer, ServerName},
+    server::{ClientHello, ParsedCertificate, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
 };
+use std::collections::HashMap;
 use std::sync::Arc;
 use tracing::debug;
 
+/// A certificate chain and its private key
+type CertAndKey = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
 /// Type alias for the inner TLS identity type.
 pub type TlsIdentityInner = ServerConfig;
 
@@ -23,47 +32,92 @@ pub type HyperConnector =
 
 pub async fn make_server_config(
     cert_path: &str,
-    key_path: &str,
-    client_ca_path: Option<&str>,
+    key_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    sni_certs: &[SniCert],
+    ocsp: Option<Vec<u8>>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentityInner, Error> {
     // Load certificate
-    // `expect`: we only get `None` if `key_path` and `cert_path` are `None`,
-    // which is not the case here.
-    let (certs, key) = try_load_certificate(Some(key_path), Some(cert_path))
+    // `expect`: we only get `None` if `cert_path` is `None`, which is not the
+    // case here.
+    let default = try_load_certificate(key_path, Some(cert_path), options)
+        .await?
+        .expect("`try_load_certificate` returned `None` (this is a bug)");
+    let mut by_name = Vec::with_capacity(sni_certs.len());
+    for sni_cert in sni_certs {
+        let loaded = try_load_certificate(
+            sni_cert.key_path.as_deref(),
+            Some(sni_cert.cert_path.as_str()),
+            options,
+        )
         .await?
         .expect("`try_load_certificate` returned `None` (this is a bug)");
-    make_server_config_from_mem(certs, key, client_ca_path).await
+        by_name.push((sni_cert.hostname.clone(), loaded));
+    }
+    make_server_config_from_mem(default, ocsp, by_name, client_ca, options).await
 }
 
 #[cfg(feature = "acme")]
 pub async fn make_server_config_from_rcgen_pem(
     certs: String,
     keypair: rcgen::KeyPair,
-    client_ca_path: Option<&str>,
+    client_ca: ClientCa<'_>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentityInner, Error> {
     let certs: std::io::Result<Vec<CertificateDer<'_>>> =
         rustls_pemfile::certs(&mut certs.as_bytes()).collect();
     let crt_key = keypair.serialize_pem();
     let key = rustls_pemfile::private_key(&mut crt_key.as_bytes())?
         .ok_or(Error::PrivateKeyNotSupported)?;
-    make_server_config_from_mem(certs?, key, client_ca_path).await
+    make_server_config_from_mem((certs?, key), None, Vec::new(), client_ca, options).await
 }
 
+/// Build a server config serving `default`, with `ocsp` stapled, unless the
+/// client asks for one of the hostnames in `by_name`
 async fn make_server_config_from_mem(
-    certs: Vec<CertificateDer<'static>>,
-    key: PrivateKeyDer<'static>,
-    client_ca_path: Option<&str>,
+    default: CertAndKey,
+    ocsp: Option<Vec<u8>>,
+    by_name: Vec<(String, CertAndKey)>,
+    client_ca: ClientCa<'_>,
+    options: &TlsArgs,
 ) -> Result<TlsIdentityInner, Error> {
     // Build config
-    let config = ServerConfig::builder();
-    let mut config = if let Some(client_ca_path) = client_ca_path {
+    let (provider, versions) = make_crypto_config(options)?;
+    let config =
+        ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(&versions)?;
+    let certified_key =
+        |(certs, key): CertAndKey| CertifiedKey::from_der(certs, key, &provider).map(Arc::new);
+    let mut default = certified_key(default)?;
+    if ocsp.is_some() {
+        // `expect`: nothing else holds the `Arc` yet
+        Arc::get_mut(&mut default)
+            .expect("`CertifiedKey` is shared (this is a bug)")
+            .ocsp = ocsp;
+    }
+    let resolver = SniResolver {
+        default,
+        by_name: by_name
+            .into_iter()
+            .map(|(name, cert)| Ok((name, certified_key(cert)?)))
+            .collect::<Result<_, Error>>()?,
+    };
+    let mut config = if let Some(client_ca_path) = client_ca.ca_path {
         let store = load_ca_store(client_ca_path).await?;
-        let verifier = WebPkiClientVerifier::builder(Arc::new(store)).build()?;
+        let mut verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(store), provider.clone());
+        if let Some(client_crl_path) = client_ca.crl_path {
+            // Only reject certificates the lists revoke
+            verifier = verifier
+                .with_crls(load_crls(client_crl_path).await?)
+                .allow_unknown_revocation_status();
+        }
+        let verifier = verifier.build()?;
         config.with_client_cert_verifier(verifier)
     } else {
         config.with_no_client_auth()
     }
-    .with_single_cert(certs, key)?;
+    .with_cert_resolver(Arc::new(resolver));
     config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
     #[cfg(feature = "rustls-keylog")]
     {
@@ -72,37 +126,148 @@ async fn make_server_config_from_mem(
     Ok(config)
 }
 
+/// The process-wide crypto provider
+fn default_crypto_provider() -> Arc<CryptoProvider> {
+    // `builder` installs the default provider if there is none yet
+    ClientConfig::builder().crypto_provider().clone()
+}
+
+/// The crypto provider and protocol versions limited to those in `options`
+fn make_crypto_config(
+    options: &TlsArgs,
+) -> Result<(Arc<CryptoProvider>, Vec<&'static SupportedProtocolVersion>), Error> {
+    let min = options.min_version.unwrap_or(TlsVersion::Tls12);
+    let max = options.max_version.unwrap_or(TlsVersion::Tls13);
+    if min > max {
+        return Err(Error::InvalidVersionRange);
+    }
+    let versions = [
+        (TlsVersion::Tls12, &rustls::version::TLS12),
+        (TlsVersion::Tls13, &rustls::version::TLS13),
+    ]
+    .into_iter()
+    .filter(|(version, _)| (min..=max).contains(version))
+    .map(|(_, version)| version)
+    .collect();
+    let mut provider = CryptoProvider::clone(&default_crypto_provider());
+    let named =
+        |name: Option<&str>, wanted: &str| name.is_some_and(|n| n.eq_ignore_ascii_case(wanted));
+    if !options.cipher_suites.is_empty() {
+        let suites = options
+            .cipher_suites
+            .iter()
+            .map(|wanted| {
+                provider
+                    .cipher_suites
+                    .iter()
+                    .find(|suite| named(suite.suite().as_str(), wanted))
+                    .copied()
+                    .ok_or_else(|| Error::UnknownCipherSuite(wanted.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        provider.cipher_suites = suites;
+    }
+    if !options.kx_groups.is_empty() {
+        let groups = options
+            .kx_groups
+            .iter()
+            .map(|wanted| {
+                provider
+                    .kx_groups
+                    .iter()
+                    .find(|group| named(group.name().as_str(), wanted))
+                    .copied()
+                    .ok_or_else(|| Error::UnknownKxGroup(wanted.clone()))
+            })
+            .collect::<Result<_, _>>()?;
+        provider.kx_groups = groups;
+    }
+    if options.post_quantum {
+        // Offered first so that clients send a key share for it
+        let hybrid = default_crypto_provider()
+            .kx_groups
+            .iter()
+            .find(|group| group.name() == NamedGroup::X25519MLKEM768)
+            .copied()
+            .ok_or(Error::PostQuantumNotSupported)?;
+        provider
+            .kx_groups
+            .retain(|group| group.name() != NamedGroup::X25519MLKEM768);
+        provider.kx_groups.insert(0, hybrid);
+    }
+    Ok((Arc::new(provider), versions))
+}
+
+/// Selects the certificate by the server name the client asks for
+#[derive(Debug)]
+struct SniResolver {
+    /// By lowercase hostname, which may be a wildcard such as `*.example.com`
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    /// For clients asking for another name or none
+    default: Arc<CertifiedKey>,
+}
+
+impl SniResolver {
+    fn resolve_name(&self, name: Option<&str>) -> Arc<CertifiedKey> {
+        let Some(name) = name.map(str::to_ascii_lowercase) else {
+            return self.default.clone();
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{parent}"));
+        self.by_name
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| self.by_name.get(&wildcard)))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.resolve_name(client_hello.server_name()))
+    }
+}
+
 pub async fn make_client_config(
     cert_path: Option<&str>,
     key_path: Option<&str>,
     ca_path: Option<&str>,
     tls_skip_verify: bool,
     tls_alpn: Option<&[&str]>,
+    options: &TlsArgs,
+    pins: &[SpkiPin],
 ) -> Result<ClientConfig, Error> {
-    let config = ClientConfig::builder();
-    // Whether there is a custom CA store
-    let roots = generate_rustls_rootcertstore(ca_path).await?;
-    let client_certificate = try_load_certificate(key_path, cert_path).await?;
-    // Whether to skip TLS verification and whether there is a client certificate
-    let mut config = match (tls_skip_verify, client_certificate) {
-        (true, Some((cert_chain, key_der))) => config
-            .dangerous()
-            .with_custom_certificate_verifier(Arc::new(EmptyVerifier(
-                CryptoProvider::get_default()
-                    .expect("no process-level CryptoProvider available (this is a bug)"),
-            )))
-            .with_client_auth_cert(cert_chain, key_der)?,
-        (true, None) => config
-            .dangerous()
-            .with_custom_certificate_verifier(Arc::new(EmptyVerifier(
-                CryptoProvider::get_default()
-                    .expect("no process-level CryptoProvider available (this is a bug)"),
-            )))
-            .with_no_client_auth(),
-        (false, Some((cert_chain, key_der))) => config
-            .with_root_certificates(roots)
-            .with_client_auth_cert(cert_chain, key_der)?,
-        (false, None) => config.with_root_certificates(roots).with_no_client_auth(),
+    let (provider, versions) = make_crypto_config(options)?;
+    let config =
+        ClientConfig::builder_with_provider(provider.clone()).with_protocol_versions(&versions)?;
+    let client_certificate = try_load_certificate(key_path, cert_path, options).await?;
+    // Whether to skip TLS verification
+    let verifier: Arc<dyn ServerCertVerifier> = if tls_skip_verify {
+        Arc::new(EmptyVerifier(CryptoProvider::get_default().expect(
+            "no process-level CryptoProvider available (this is a bug)",
+        )))
+    } else {
+        // Whether there is a custom CA store
+        let roots = generate_rustls_rootcertstore(ca_path).await?;
+        WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?
+    };
+    let verifier: Arc<dyn ServerCertVerifier> = if pins.is_empty() {
+        verifier
+    } else {
+        Arc::new(PinnedVerifier {
+            inner: verifier,
+            pins: pins.to_vec(),
+            provider: default_crypto_provider(),
+        })
+    };
+    let config = config
+        .dangerous()
+        .with_custom_certificate_verifier(verifier);
+    // Whether there is a client certificate
+    let mut config = match client_certificate {
+        Some((cert_chain, key_der)) => config.with_client_auth_cert(cert_chain, key_der)?,
+        None => config.with_no_client_auth(),
     };
     if let Some(tls_alpn) = tls_alpn {
         config.alpn_protocols = tls_alpn.iter().map(|&x| x.as_bytes().to_vec()).collect();
@@ -139,7 +304,7 @@ async fn load_ca_store(ca_path: &str) -> Result<RootCertStore, Error> {
     let client_ca = tokio::fs::read(ca_path).await?;
     let client_ca: std::io::Result<Vec<CertificateDer<'_>>> =
         rustls_pemfile::certs(&mut client_ca.as_ref()).collect();
-    let (new, ignored) = store.add_parsable_certificates(client_ca?.into_iter());
+    let (new, ignored) = store.add_parsable_certificates(client_ca?);
     debug!("ignored {ignored} certificates from {ca_path}");
     if new == 0 {
         Err(Error::EmptyClientCertStore)
@@ -148,6 +313,18 @@ async fn load_ca_store(ca_path: &str) -> Result<RootCertStore, Error> {
     }
 }
 
+/// Load the certificate revocation lists in a PEM or DER file
+async fn load_crls(crl_path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>, Error> {
+    let content = tokio::fs::read(crl_path).await?;
+    let crls = rustls_pemfile::crls(&mut content.as_ref()).collect::<Result<Vec<_>, _>>()?;
+    if crls.is_empty() {
+        // Not PEM, so it may be a single DER-encoded CRL
+        Ok(vec![CertificateRevocationListDer::from(content)])
+    } else {
+        Ok(crls)
+    }
+}
+
 /// Load system certificates or a custom CA store.
 async fn generate_rustls_rootcertstore(
     custom_ca_path: Option<&str>,
@@ -164,14 +341,14 @@ async fn generate_rustls_rootcertstore(
 async fn try_load_certificate(
     tls_key: Option<&str>,
     tls_cert: Option<&str>,
+    options: &TlsArgs,
 ) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>, Error> {
-    if let (Some(key), Some(cert)) = (tls_key, tls_cert) {
+    if let Some(cert) = tls_cert {
+        let (certs, key) = super::read_cert_and_key(cert, tls_key, options).await?;
         // Load certificate chain
-        let certs = tokio::fs::read(cert).await?;
         let certs: std::io::Result<Vec<CertificateDer<'_>>> =
             rustls_pemfile::certs(&mut certs.as_ref()).collect();
         // Load private key
-        let key = tokio::fs::read(key).await?;
         let Some(key) = rustls_pemfile::private_key(&mut key.as_ref())? else {
             return Err(Error::PrivateKeyNotSupported);
         };
@@ -230,6 +407,92 @@ impl ServerCertVerifier for EmptyVerifier {
     }
 }
 
+/// SHA-256 as implemented by `provider`
+fn sha256(provider: &CryptoProvider) -> Option<&'static dyn Hash> {
+    provider
+        .cipher_suites
+        .iter()
+        .filter_map(SupportedCipherSuite::tls13)
+        .map(|suite| suite.common.hash_provider)
+        .find(|hash| hash.algorithm() == HashAlgorithm::SHA256)
+}
+
+/// The pin of the public key of a certificate
+fn spki_pin(
+    cert: &CertificateDer<'_>,
+    provider: &CryptoProvider,
+) -> Result<SpkiPin, rustls::Error> {
+    let spki = ParsedCertificate::try_from(cert)?.subject_public_key_info();
+    let hash = sha256(provider)
+        .expect("No SHA-256 in the crypto provider (this is a bug)")
+        .hash(spki.as_ref());
+    let hash = hash
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digest is not 32 bytes (this is a bug)");
+    Ok(SpkiPin(hash))
+}
+
+/// Only accepts server certificates whose public key is pinned, after
+/// the usual verification (if any)
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<SpkiPin>,
+    /// For hashing, unaffected by `--tls-cipher-suites`
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let pin = spki_pin(end_entity, &self.provider)?;
+        if self.pins.contains(&pin) {
+            Ok(verified)
+        } else {
+            debug!("Server certificate does not match any pin: {pin}");
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
 /// For backend requests
 #[cfg(all(feature = "rustls-native-roots", feature = "server"))]
 pub fn make_hyper_connector() -> std::io::Result<HyperConnector> {
@@ -282,7 +545,9 @@ mod tests {
     async fn test_try_load_certificate() {
         crate::tests::setup_logging();
         // No certificate and key
-        let no_cert = try_load_certificate(None, None).await.unwrap();
+        let no_cert = try_load_certificate(None, None, &TlsArgs::default())
+            .await
+            .unwrap();
         assert!(no_cert.is_none());
         // Certificate and key
         let tmpdir = tempdir().unwrap();
@@ -296,6 +561,7 @@ mod tests {
         let loaded_cert = try_load_certificate(
             Some(key_path.to_str().unwrap()),
             Some(cert_path.to_str().unwrap()),
+            &TlsArgs::default(),
         )
         .await
         .unwrap()
@@ -313,6 +579,7 @@ mod tests {
         let loaded_cert = try_load_certificate(
             Some(key_path.to_str().unwrap()),
             Some(cert_path.to_str().unwrap()),
+            &TlsArgs::default(),
         )
         .await
         .unwrap()
@@ -335,8 +602,11 @@ mod tests {
         tokio::fs::write(&key_path, crt_key).await.unwrap();
         let config = make_server_config(
             cert_path.to_str().unwrap(),
-            key_path.to_str().unwrap(),
+            key_path.to_str(),
+            ClientCa::default(),
+            &[],
             None,
+            &TlsArgs::default(),
         )
         .await
         .unwrap();
@@ -346,6 +616,255 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_sni_resolver() {
+        crate::tests::setup_logging();
+        let tmpdir = tempdir().unwrap();
+        let mut paths = Vec::new();
+        for name in ["default.example", "example.org", "*.example.net"] {
+            let crt = generate_simple_self_signed(vec![name.into()]).unwrap();
+            let cert_path = tmpdir.path().join(format!("{name}.pem"));
+            let key_path = tmpdir.path().join(format!("{name}.key"));
+            tokio::fs::write(&cert_path, crt.cert.pem()).await.unwrap();
+            tokio::fs::write(&key_path, crt.key_pair.serialize_pem())
+                .await
+                .unwrap();
+            let cert_path = cert_path.to_str().unwrap().to_string();
+            let key_path = key_path.to_str().unwrap().to_string();
+            paths.push((name, cert_path, key_path));
+        }
+        let sni_certs: Vec<SniCert> = paths[1..]
+            .iter()
+            .map(|(name, cert, key)| format!("{name},{cert},{key}").parse().unwrap())
+            .collect();
+        // Loading all certificates works
+        make_server_config(
+            &paths[0].1,
+            Some(&paths[0].2),
+            ClientCa::default(),
+            &sni_certs,
+            None,
+            &TlsArgs::default(),
+        )
+        .await
+        .unwrap();
+        let load = |(_, cert, key): &(&str, String, String)| {
+            let certs = std::fs::read(cert).unwrap();
+            let certs = rustls_pemfile::certs(&mut certs.as_ref())
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let key = std::fs::read(key).unwrap();
+            let key = rustls_pemfile::private_key(&mut key.as_ref())
+                .unwrap()
+                .unwrap();
+            let provider = CryptoProvider::get_default().unwrap();
+            Arc::new(CertifiedKey::from_der(certs, key, provider).unwrap())
+        };
+        let resolver = SniResolver {
+            default: load(&paths[0]),
+            by_name: [
+                ("example.org".to_string(), load(&paths[1])),
+                ("*.example.net".to_string(), load(&paths[2])),
+            ]
+            .into(),
+        };
+        let served = |name| resolver.resolve_name(name).cert[0].clone();
+        assert_eq!(served(None), resolver.default.cert[0]);
+        assert_eq!(
+            served(Some("example.org")),
+            resolver.by_name["example.org"].cert[0]
+        );
+        assert_eq!(
+            served(Some("EXAMPLE.org")),
+            resolver.by_name["example.org"].cert[0]
+        );
+        assert_eq!(served(Some("www.example.org")), resolver.default.cert[0]);
+        assert_eq!(
+            served(Some("www.example.net")),
+            resolver.by_name["*.example.net"].cert[0]
+        );
+        // Wildcards only cover one label
+        assert_eq!(served(Some("a.b.example.net")), resolver.default.cert[0]);
+        assert_eq!(served(Some("example.net")), resolver.default.cert[0]);
+    }
+
+    #[test]
+    fn test_make_crypto_config() {
+        crate::tests::setup_logging();
+        let (provider, versions) = make_crypto_config(&TlsArgs::default()).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(provider.cipher_suites.len() > 1);
+        let options = TlsArgs {
+            min_version: Some(TlsVersion::Tls13),
+            cipher_suites: vec![
+                "tls13_aes_256_gcm_sha384".to_string(),
+                "TLS13_AES_128_GCM_SHA256".to_string(),
+            ],
+            kx_groups: vec!["X25519".to_string()],
+            ..Default::default()
+        };
+        let (provider, versions) = make_crypto_config(&options).unwrap();
+        assert_eq!(versions, [&rustls::version::TLS13]);
+        let suites: Vec<_> = provider
+            .cipher_suites
+            .iter()
+            .map(|suite| suite.suite().as_str().unwrap())
+            .collect();
+        assert_eq!(
+            suites,
+            ["TLS13_AES_256_GCM_SHA384", "TLS13_AES_128_GCM_SHA256"]
+        );
+        assert_eq!(provider.kx_groups.len(), 1);
+        let options = TlsArgs {
+            min_version: Some(TlsVersion::Tls13),
+            max_version: Some(TlsVersion::Tls12),
+            ..Default::default()
+        };
+        assert!(matches!(
+            make_crypto_config(&options),
+            Err(Error::InvalidVersionRange)
+        ));
+        let options = TlsArgs {
+            kx_groups: vec!["X25519".to_string()],
+            post_quantum: true,
+            ..Default::default()
+        };
+        // Only some crypto providers implement it
+        match make_crypto_config(&options) {
+            Ok((provider, _)) => {
+                let groups: Vec<_> = provider.kx_groups.iter().map(|g| g.name()).collect();
+                assert_eq!(groups, [NamedGroup::X25519MLKEM768, NamedGroup::X25519]);
+            }
+            Err(err) => assert!(matches!(err, Error::PostQuantumNotSupported)),
+        }
+        let options = TlsArgs {
+            cipher_suites: vec!["TLS_NULL_WITH_NULL_NULL".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            make_crypto_config(&options),
+            Err(Error::UnknownCipherSuite(_))
+        ));
+        let options = TlsArgs {
+            kx_groups: vec!["ffdhe1024".to_string()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            make_crypto_config(&options),
+            Err(Error::UnknownKxGroup(_))
+        ));
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        crate::tests::setup_logging();
+        let provider = default_crypto_provider();
+        let crt = generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+        let other = generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+        let pin = spki_pin(crt.cert.der(), &provider).unwrap();
+        let expected = sha256(&provider)
+            .unwrap()
+            .hash(&crt.key_pair.public_key_der());
+        assert_eq!(pin.0.as_slice(), expected.as_ref());
+        assert_eq!(pin.to_string().parse::<SpkiPin>().unwrap(), pin);
+        let verifier = PinnedVerifier {
+            inner: Arc::new(EmptyVerifier(CryptoProvider::get_default().unwrap())),
+            pins: vec![SpkiPin([0; 32]), pin],
+            provider,
+        };
+        let verify = |cert: &CertificateDer<'_>| {
+            verifier.verify_server_cert(
+                cert,
+                &[],
+                &ServerName::try_from("example.com").unwrap(),
+                &[],
+                rustls::pki_types::UnixTime::now(),
+            )
+        };
+        assert!(verify(crt.cert.der()).is_ok());
+        assert!(verify(other.cert.der()).is_err());
+        for invalid in ["sha256/", "sha1/AAAA", "sha256/AAAA", "sha256/!"] {
+            assert!(invalid.parse::<SpkiPin>().is_err(), "{invalid}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_crl() {
+        crate::tests::setup_logging();
+        let tmpdir = tempdir().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params.key_usages = vec![
+            rcgen::KeyUsagePurpose::KeyCertSign,
+            rcgen::KeyUsagePurpose::CrlSign,
+        ];
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let issue = |serial: u64| {
+            let mut params = CertificateParams::new(vec!["client".into()]).unwrap();
+            params.serial_number = Some(serial.into());
+            let key = rcgen::KeyPair::generate().unwrap();
+            params.signed_by(&key, &ca, &ca_key).unwrap()
+        };
+        let (good, revoked) = (issue(1), issue(2));
+        let crl = rcgen::CertificateRevocationListParams {
+            this_update: rcgen::date_time_ymd(2025, 1, 1),
+            next_update: rcgen::date_time_ymd(2099, 1, 1),
+            crl_number: 1.into(),
+            issuing_distribution_point: None,
+            revoked_certs: vec![rcgen::RevokedCertParams {
+                serial_number: 2.into(),
+                revocation_time: rcgen::date_time_ymd(2025, 1, 1),
+                reason_code: None,
+                invalidity_date: None,
+            }],
+            key_identifier_method: rcgen::KeyIdMethod::Sha256,
+        }
+        .signed_by(&ca, &ca_key)
+        .unwrap();
+        let server = generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+        let path = |name: &str| tmpdir.path().join(name).to_str().unwrap().to_string();
+        tokio::fs::write(path("ca.pem"), ca.pem()).await.unwrap();
+        tokio::fs::write(path("crl.pem"), crl.pem().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(path("crl.der"), crl.der()).await.unwrap();
+        tokio::fs::write(path("cert.pem"), server.cert.pem())
+            .await
+            .unwrap();
+        tokio::fs::write(path("key.pem"), server.key_pair.serialize_pem())
+            .await
+            .unwrap();
+        make_server_config(
+            &path("cert.pem"),
+            Some(&path("key.pem")),
+            ClientCa {
+                ca_path: Some(&path("ca.pem")),
+                crl_path: Some(&path("crl.pem")),
+            },
+            &[],
+            None,
+            &TlsArgs::default(),
+        )
+        .await
+        .unwrap();
+        // Both encodings load
+        assert_eq!(load_crls(&path("crl.der")).await.unwrap().len(), 1);
+        let store = load_ca_store(&path("ca.pem")).await.unwrap();
+        let verifier = WebPkiClientVerifier::builder(Arc::new(store))
+            .with_crls(load_crls(&path("crl.pem")).await.unwrap())
+            .allow_unknown_revocation_status()
+            .build()
+            .unwrap();
+        let now = rustls::pki_types::UnixTime::now();
+        assert!(verifier.verify_client_cert(good.der(), &[], now).is_ok());
+        assert!(
+            verifier
+                .verify_client_cert(revoked.der(), &[], now)
+                .is_err()
+        );
+    }
+
     #[tokio::test]
     async fn test_client_config() {
         crate::tests::setup_logging();
@@ -361,6 +880,8 @@ mod tests {
             Some(ca_path.to_str().unwrap()),
             true,
             Some(&crate::tls::TLS_ALPN),
+            &TlsArgs::default(),
+            &[],
         )
         .await
         .unwrap();
@@ -379,7 +900,13 @@ mod tests {
         let custom_crt = cert_params.self_signed(&keypair).unwrap();
         let crt = custom_crt.pem();
 
-        let result = make_server_config_from_rcgen_pem(crt, keypair, None).await;
+        let result = make_server_config_from_rcgen_pem(
+            crt,
+            keypair,
+            ClientCa::default(),
+            &TlsArgs::default(),
+        )
+        .await;
 
         assert!(result.is_ok());
     }
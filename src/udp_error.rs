@@ -0,0 +1,18 @@
+//! Reporting failed UDP flows to the client.
+//!
+//! A client that sends the `x-penguin-udp-errors` header in its handshake
+//! asks the server to tell it when a UDP flow fails, because the destination
+//! answered with an ICMP error such as port unreachable or a datagram could
+//! not be sent. Instead of dropping the datagrams of the flow silently, the
+//! server then closes the flow and sends one datagram with its flow ID to
+//! the host `penguin-udp-error`, with the reason as data. The client drops
+//! its mapping of the flow, so that the next datagram of its application
+//! starts a new one.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+/// Header asking the server to report failed UDP flows
+pub const UDP_ERRORS_HEADER: &str = "x-penguin-udp-errors";
+
+/// Host of the datagrams that report a failed UDP flow
+pub const UDP_ERROR_HOST: &[u8] = b"penguin-udp-error";
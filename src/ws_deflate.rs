@@ -0,0 +1,698 @@
+//! `permessage-deflate` (RFC 7692) for the `WebSocket` transport.
+//!
+//! `tungstenite` neither negotiates extensions nor accepts frames with the
+//! RSV1 bit, so [`DeflateStream`] sits below it: it inflates the compressed
+//! messages we receive before `tungstenite` parses them, and deflates the
+//! data frames `tungstenite` writes before they reach the connection.
+//! Control frames and fragmented messages we send are left uncompressed,
+//! which the extension allows.
+//!
+//! Our compressor always uses a 32 KiB window, so the client never asks the
+//! server for a smaller one, and the server only agrees to offers that do
+//! not limit it. The server can limit the window of clients that allow it
+//! with `--ws-deflate-window-bits`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::WsDeflateArgs;
+use bytes::{Buf, BufMut, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use http::{HeaderMap, HeaderValue, header};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Name of the extension in `sec-websocket-extensions`
+const EXTENSION: &str = "permessage-deflate";
+
+/// The tail of a sync flush, which the extension removes from messages
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Messages shorter than this are sent uncompressed
+const MIN_COMPRESS_SIZE: usize = 128;
+
+/// Largest message we inflate, the default limit of `tungstenite`
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Largest HTTP response head we look through for the agreed parameters
+const MAX_RESPONSE_HEAD: usize = 16384;
+
+/// Bytes we buffer for the connection before waiting for it to drain
+const WRITE_HIGH_WATER: usize = 65536;
+
+/// Errors in the negotiation of the extension
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The server replied with parameters we did not offer
+    #[error("invalid `permessage-deflate` parameter from the server: {0}")]
+    Parameter(String),
+    /// The response head never ended
+    #[error("WebSocket response head is too long")]
+    ResponseHead,
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        Self::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Parameters agreed with the peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Agreed {
+    /// Whether we must compress every message with an empty window
+    pub reset_compressor: bool,
+}
+
+/// Parameters of one offer or reply in `sec-websocket-extensions`
+type Params<'a> = Vec<(&'a str, Option<&'a str>)>;
+
+/// The `permessage-deflate` entries in `sec-websocket-extensions` headers
+fn extension_params(headers: &HeaderMap) -> impl Iterator<Item = Params<'_>> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_EXTENSIONS)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            if parts.next() != Some(EXTENSION) {
+                return None;
+            }
+            Some(
+                parts
+                    .filter(|param| !param.is_empty())
+                    .map(|param| match param.split_once('=') {
+                        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                        None => (param, None),
+                    })
+                    .collect(),
+            )
+        })
+}
+
+/// Parse a window size parameter
+fn window_bits(value: Option<&str>) -> Option<u8> {
+    value?.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+/// The `sec-websocket-extensions` value the client offers
+pub fn offer(args: WsDeflateArgs) -> HeaderValue {
+    let mut offer = EXTENSION.to_owned();
+    if args.no_context_takeover {
+        offer.push_str("; client_no_context_takeover; server_no_context_takeover");
+    }
+    HeaderValue::from_str(&offer)
+        .expect("Extension offer is not a valid header value (this is a bug)")
+}
+
+/// Pick the first offer of the client we can agree to.
+/// Returns the agreed parameters and the `sec-websocket-extensions` value
+/// of the response, or `None` if the extension is disabled or not offered.
+/// The window of the client is limited to `client_window_bits` if it allows it.
+pub fn accept(
+    args: WsDeflateArgs,
+    client_window_bits: Option<u8>,
+    headers: &HeaderMap,
+) -> Option<(Agreed, HeaderValue)> {
+    if !args.enabled {
+        return None;
+    }
+    extension_params(headers).find_map(|params| {
+        let mut reply = EXTENSION.to_owned();
+        let mut reset_compressor = args.no_context_takeover;
+        let mut client_window = None;
+        for (name, value) in params {
+            match name {
+                "server_no_context_takeover" if value.is_none() => reset_compressor = true,
+                "client_no_context_takeover" if value.is_none() => {}
+                // We cannot shrink our window
+                "server_max_window_bits" if window_bits(value) == Some(15) => {}
+                "client_max_window_bits" => {
+                    let offered = if value.is_none() {
+                        Some(15)
+                    } else {
+                        window_bits(value)
+                    };
+                    client_window = Some(offered?);
+                }
+                _ => return None,
+            }
+        }
+        if reset_compressor {
+            reply.push_str("; server_no_context_takeover");
+        }
+        if args.no_context_takeover {
+            reply.push_str("; client_no_context_takeover");
+        }
+        if let (Some(offered), Some(bits)) = (client_window, client_window_bits) {
+            reply.push_str("; client_max_window_bits=");
+            reply.push_str(&bits.min(offered).to_string());
+        }
+        let reply = HeaderValue::from_str(&reply).ok()?;
+        Some((Agreed { reset_compressor }, reply))
+    })
+}
+
+/// Check the reply of the server to our [`offer`].
+/// Returns `None` if the server did not agree to the extension.
+fn agreed_by_server(headers: &HeaderMap) -> Result<Option<Agreed>, Error> {
+    let Some(params) = extension_params(headers).next() else {
+        return Ok(None);
+    };
+    let mut agreed = Agreed::default();
+    for (name, value) in params {
+        match name {
+            "client_no_context_takeover" if value.is_none() => agreed.reset_compressor = true,
+            "server_no_context_takeover" if value.is_none() => {}
+            "server_max_window_bits" if window_bits(value).is_some() => {}
+            _ => return Err(Error::Parameter(name.to_owned())),
+        }
+    }
+    Ok(Some(agreed))
+}
+
+/// The headers of a `101 Switching Protocols` response head.
+/// `tungstenite` reports other or malformed responses itself.
+fn parse_upgrade_response(head: &[u8]) -> Option<HeaderMap> {
+    let mut lines = head.split(|&b| b == b'\n').map(<[u8]>::trim_ascii);
+    let mut status = lines.next()?.split(|&b| b == b' ');
+    if !status.next()?.starts_with(b"HTTP/") || status.next()? != b"101" {
+        return None;
+    }
+    let mut headers = HeaderMap::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let colon = line.iter().position(|&b| b == b':')?;
+        let name = http::HeaderName::from_bytes(&line[..colon]).ok()?;
+        let value = HeaderValue::from_bytes(line[colon + 1..].trim_ascii()).ok()?;
+        headers.append(name, value);
+    }
+    Some(headers)
+}
+
+/// Header of a `WebSocket` frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FrameHeader {
+    /// First byte: FIN, RSV and the opcode
+    flags: u8,
+    mask: Option<[u8; 4]>,
+    /// Length of the header on the wire
+    len: usize,
+    payload_len: usize,
+}
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const OPCODE: u8 = 0x0f;
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+
+impl FrameHeader {
+    /// Parse the header at the start of `buf`.
+    /// Returns `None` if `buf` does not hold the whole header yet.
+    fn parse(buf: &[u8]) -> io::Result<Option<Self>> {
+        let [flags, second, ..] = *buf else {
+            return Ok(None);
+        };
+        let (len, payload_len) = match second & 0x7f {
+            126 if buf.len() >= 4 => (4, u64::from(u16::from_be_bytes([buf[2], buf[3]]))),
+            127 if buf.len() >= 10 => (
+                10,
+                u64::from_be_bytes(buf[2..10].try_into().expect("Slice of 8 bytes")),
+            ),
+            126 | 127 => return Ok(None),
+            short => (2, u64::from(short)),
+        };
+        let payload_len = usize::try_from(payload_len)
+            .ok()
+            .filter(|&len| len <= MAX_MESSAGE_SIZE)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "WebSocket frame too long")
+            })?;
+        let (mask, len) = if second & 0x80 == 0 {
+            (None, len)
+        } else if buf.len() >= len + 4 {
+            let mask = buf[len..len + 4].try_into().expect("Slice of 4 bytes");
+            (Some(mask), len + 4)
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            flags,
+            mask,
+            len,
+            payload_len,
+        }))
+    }
+
+    const fn opcode(&self) -> u8 {
+        self.flags & OPCODE
+    }
+
+    const fn is_final(&self) -> bool {
+        self.flags & FIN != 0
+    }
+
+    const fn is_compressed(&self) -> bool {
+        self.flags & RSV1 != 0
+    }
+
+    const fn is_data(&self) -> bool {
+        matches!(self.opcode(), OPCODE_TEXT | OPCODE_BINARY)
+    }
+
+    /// Write a frame with `flags` and `payload`, masked with our mask if any
+    fn write_frame(&self, flags: u8, mut payload: Vec<u8>, out: &mut BytesMut) {
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        out.put_u8(flags);
+        match payload.len() {
+            len @ 0..=125 => out.put_u8(mask_bit | u8::try_from(len).expect("Checked length")),
+            len @ 126..=0xffff => {
+                out.put_u8(mask_bit | 0x7e);
+                out.put_u16(u16::try_from(len).expect("Checked length"));
+            }
+            len => {
+                out.put_u8(mask_bit | 0x7f);
+                out.put_u64(len as u64);
+            }
+        }
+        if let Some(mask) = self.mask {
+            out.put_slice(&mask);
+            apply_mask(&mut payload, mask);
+        }
+        out.put_slice(&payload);
+    }
+}
+
+/// Mask or unmask a payload
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (byte, mask) in payload.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= mask;
+    }
+}
+
+/// Compressed message whose final frame we are waiting for
+#[derive(Debug)]
+struct Fragmented {
+    header: FrameHeader,
+    payload: Vec<u8>,
+}
+
+/// Compression state of an agreed connection
+struct Codec {
+    agreed: Agreed,
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed message we are receiving
+    inflating: Option<Fragmented>,
+}
+
+impl std::fmt::Debug for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Codec")
+            .field("agreed", &self.agreed)
+            .field("inflating", &self.inflating)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Codec {
+    fn new(agreed: Agreed) -> Self {
+        Self {
+            agreed,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            inflating: None,
+        }
+    }
+
+    /// Compress a message
+    fn deflate(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if self.agreed.reset_compressor {
+            self.compress.reset();
+        }
+        let mut out = Vec::with_capacity(data.len() / 2 + 64);
+        let mut consumed = 0;
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(out.capacity());
+            }
+            let before = self.compress.total_in();
+            self.compress
+                .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            consumed +=
+                usize::try_from(self.compress.total_in() - before).expect("Consumed input fits");
+            if consumed == data.len() && out.len() < out.capacity() {
+                break;
+            }
+        }
+        if out.ends_with(&TRAILER) {
+            out.truncate(out.len() - TRAILER.len());
+        }
+        Ok(out)
+    }
+
+    /// Decompress a message
+    fn inflate(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let input = [data, &TRAILER].concat();
+        let mut out = Vec::with_capacity(input.len() * 2);
+        let mut consumed = 0;
+        loop {
+            if out.len() == out.capacity() {
+                if out.len() >= MAX_MESSAGE_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "inflated WebSocket message too long",
+                    ));
+                }
+                out.reserve(out.capacity());
+            }
+            let (before_in, before_out) = (self.decompress.total_in(), out.len());
+            let before_consumed = consumed;
+            self.decompress
+                .decompress_vec(&input[consumed..], &mut out, FlushDecompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            consumed += usize::try_from(self.decompress.total_in() - before_in)
+                .expect("Consumed input fits");
+            if out.len() < out.capacity() {
+                if consumed == input.len() {
+                    break;
+                }
+                if consumed == before_consumed && out.len() == before_out {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid compressed WebSocket message",
+                    ));
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Turn a frame we received into what `tungstenite` expects
+    fn decode(&mut self, header: FrameHeader, frame: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        let mut payload = frame[header.len..].to_vec();
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+        if header.is_data() && header.is_compressed() {
+            if header.is_final() {
+                let inflated = self.inflate(&payload)?;
+                header.write_frame(header.flags & !RSV1, inflated, out);
+            } else {
+                self.inflating = Some(Fragmented { header, payload });
+            }
+        } else if header.opcode() == OPCODE_CONTINUATION
+            && let Some(fragmented) = &mut self.inflating
+        {
+            if fragmented.payload.len() + payload.len() > MAX_MESSAGE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "WebSocket message too long",
+                ));
+            }
+            fragmented.payload.extend_from_slice(&payload);
+            if header.is_final() {
+                let Fragmented {
+                    header: first,
+                    payload,
+                } = self.inflating.take().expect("Checked above");
+                let inflated = self.inflate(&payload)?;
+                first.write_frame((first.flags & !RSV1) | FIN, inflated, out);
+            }
+        } else {
+            // Control frames and uncompressed messages
+            out.put_slice(frame);
+        }
+        Ok(())
+    }
+
+    /// Turn a frame `tungstenite` wrote into what we send
+    fn encode(&mut self, header: FrameHeader, frame: &[u8], out: &mut BytesMut) -> io::Result<()> {
+        // The frames of fragmented messages go as they are
+        if !header.is_data()
+            || !header.is_final()
+            || header.is_compressed()
+            || header.payload_len < MIN_COMPRESS_SIZE
+        {
+            out.put_slice(frame);
+            return Ok(());
+        }
+        let mut payload = frame[header.len..].to_vec();
+        if let Some(mask) = header.mask {
+            apply_mask(&mut payload, mask);
+        }
+        let deflated = self.deflate(&payload)?;
+        header.write_frame(header.flags | RSV1, deflated, out);
+        Ok(())
+    }
+}
+
+/// A connection that compresses `WebSocket` messages with
+/// `permessage-deflate` once it has been agreed
+#[derive(Debug)]
+pub struct DeflateStream<S> {
+    inner: S,
+    /// Whether we still wait for the end of the HTTP response head (client)
+    in_handshake: bool,
+    /// `None` if the extension was not agreed
+    codec: Option<Codec>,
+    /// Bytes from the connection not yet decoded
+    read_raw: BytesMut,
+    /// Decoded bytes for `tungstenite`
+    read_out: BytesMut,
+    /// Bytes from `tungstenite` not yet encoded
+    write_in: BytesMut,
+    /// Encoded bytes for the connection
+    write_out: BytesMut,
+}
+
+impl<S> DeflateStream<S> {
+    fn with_state(inner: S, in_handshake: bool, codec: Option<Codec>) -> Self {
+        Self {
+            inner,
+            in_handshake,
+            codec,
+            read_raw: BytesMut::new(),
+            read_out: BytesMut::new(),
+            write_in: BytesMut::new(),
+            write_out: BytesMut::new(),
+        }
+    }
+
+    /// Wrap the connection of a client before the handshake. The extension
+    /// is used if the response of the server agrees to it.
+    pub fn client(inner: S) -> Self {
+        Self::with_state(inner, true, None)
+    }
+
+    /// Wrap the upgraded connection of a server with the agreed parameters
+    pub fn server(inner: S, agreed: Agreed) -> Self {
+        Self::with_state(inner, false, Some(Codec::new(agreed)))
+    }
+
+    /// Decode whatever complete frames `read_raw` holds
+    fn decode_read(&mut self) -> io::Result<()> {
+        if self.in_handshake {
+            let Some(end) = self.read_raw.windows(4).position(|w| w == b"\r\n\r\n") else {
+                if self.read_raw.len() > MAX_RESPONSE_HEAD {
+                    return Err(Error::ResponseHead.into());
+                }
+                return Ok(());
+            };
+            let head = self.read_raw.split_to(end + 4);
+            if let Some(headers) = parse_upgrade_response(&head) {
+                self.codec = agreed_by_server(&headers)?.map(Codec::new);
+            }
+            self.read_out.put_slice(&head);
+            self.in_handshake = false;
+        }
+        let Some(codec) = &mut self.codec else {
+            self.read_out.unsplit(self.read_raw.split());
+            return Ok(());
+        };
+        while let Some(header) = FrameHeader::parse(&self.read_raw)? {
+            if self.read_raw.len() < header.len + header.payload_len {
+                break;
+            }
+            let frame = self.read_raw.split_to(header.len + header.payload_len);
+            codec.decode(header, &frame, &mut self.read_out)?;
+        }
+        Ok(())
+    }
+
+    /// Encode whatever complete frames `write_in` holds
+    fn encode_write(&mut self) -> io::Result<()> {
+        let Some(codec) = self.codec.as_mut().filter(|_| !self.in_handshake) else {
+            self.write_out.unsplit(self.write_in.split());
+            return Ok(());
+        };
+        while let Some(header) = FrameHeader::parse(&self.write_in)? {
+            if self.write_in.len() < header.len + header.payload_len {
+                break;
+            }
+            let frame = self.write_in.split_to(header.len + header.payload_len);
+            codec.encode(header, &frame, &mut self.write_out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    /// Write `write_out` to the connection
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_out.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_out))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_out.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read_out.is_empty() {
+            let mut chunk = [0; 16384];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // EOF; a partial frame is `tungstenite`'s problem
+                this.read_out.unsplit(this.read_raw.split());
+                break;
+            }
+            this.read_raw.put_slice(chunk.filled());
+            this.decode_read()?;
+        }
+        let len = buf.remaining().min(this.read_out.len());
+        buf.put_slice(&this.read_out[..len]);
+        this.read_out.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_out.len() >= WRITE_HIGH_WATER {
+            ready!(this.poll_drain(cx))?;
+        }
+        this.write_in.put_slice(buf);
+        this.encode_write()?;
+        // Make progress without waiting, the rest goes on flush
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::WebSocketStream;
+    use tokio_tungstenite::tungstenite::{Message, protocol::Role};
+
+    fn args(no_context_takeover: bool) -> WsDeflateArgs {
+        WsDeflateArgs {
+            enabled: true,
+            no_context_takeover,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        crate::tests::setup_logging();
+        let mut request = HeaderMap::new();
+        // The server cannot shrink its window to 10 bits
+        request.insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static("permessage-deflate; server_max_window_bits=10"),
+        );
+        assert!(accept(args(false), None, &request).is_none());
+        request.insert(header::SEC_WEBSOCKET_EXTENSIONS, offer(args(true)));
+        // Our own offer is accepted even if the server limits client windows
+        assert!(accept(args(false), Some(9), &request).is_some());
+        let (agreed, reply) = accept(args(false), None, &request).unwrap();
+        assert!(agreed.reset_compressor);
+        assert_eq!(reply, "permessage-deflate; server_no_context_takeover");
+        let mut response = HeaderMap::new();
+        response.insert(header::SEC_WEBSOCKET_EXTENSIONS, reply);
+        assert_eq!(
+            agreed_by_server(&response).unwrap(),
+            Some(Agreed {
+                reset_compressor: false
+            })
+        );
+        // Disabled on the server
+        assert!(accept(WsDeflateArgs::default(), None, &request).is_none());
+        // Limit the window of a client that allows it
+        request.insert(
+            header::SEC_WEBSOCKET_EXTENSIONS,
+            HeaderValue::from_static("x-foo, permessage-deflate; client_max_window_bits"),
+        );
+        let (agreed, reply) = accept(args(true), Some(9), &request).unwrap();
+        assert!(agreed.reset_compressor);
+        assert_eq!(
+            reply,
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover; client_max_window_bits=9"
+        );
+        response.insert(header::SEC_WEBSOCKET_EXTENSIONS, reply);
+        // We never offer `client_max_window_bits`
+        assert!(agreed_by_server(&response).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deflate_stream() {
+        crate::tests::setup_logging();
+        for reset_compressor in [false, true] {
+            let (client, server) = tokio::io::duplex(1 << 20);
+            let agreed = Agreed { reset_compressor };
+            let client = DeflateStream::with_state(client, false, Some(Codec::new(agreed)));
+            let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+            let server = DeflateStream::server(server, agreed);
+            let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+            let long = "penguin ".repeat(10000);
+            for message in [
+                Message::text(long.clone()),
+                Message::binary(b"short".to_vec()),
+                Message::text(long.clone()),
+            ] {
+                client.send(message.clone()).await.unwrap();
+                let received = server.next().await.unwrap().unwrap();
+                assert_eq!(received, message);
+                server.send(message.clone()).await.unwrap();
+                let received = client.next().await.unwrap().unwrap();
+                assert_eq!(received, message);
+            }
+        }
+    }
+}